@@ -0,0 +1,89 @@
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+
+/// Forward MDCT of `2 * len` windowed samples to `len` coefficients, scaled as AAC
+/// decoders expect: four times the plain transform
+pub(super) struct Mdct {
+    len: usize,
+    fft: Arc<dyn Fft<f32>>,
+    /// Twiddles applied before and after the FFT
+    pre: Vec<Complex<f32>>,
+    post: Vec<Complex<f32>>,
+    buffer: Vec<Complex<f32>>,
+}
+
+impl Mdct {
+    pub fn new(len: usize) -> Mdct {
+        let half = len / 2;
+        let n = len as f64;
+        let twiddle = |angle: f64| Complex::new(angle.cos() as f32, angle.sin() as f32);
+        Mdct {
+            len,
+            fft: FftPlanner::new().plan_fft_forward(half),
+            pre: (0..half)
+                .map(|i| twiddle(-PI * (4 * i + 1) as f64 / (4.0 * n)) * 4.0)
+                .collect(),
+            post: (0..half).map(|i| twiddle(-PI * i as f64 / n)).collect(),
+            buffer: vec![Complex::default(); half],
+        }
+    }
+
+    /// Transform `input`, `2 * len` samples already windowed, into `out`
+    pub fn transform(&mut self, input: &[f32], out: &mut [f32]) {
+        let (len, half) = (self.len, self.len / 2);
+        // Folded into a DCT-IV of (-c' - d, a - b'), for the quarters a, b, c, d of the
+        // input and ' reversing them, which is computed with a complex FFT of half size.
+        let folded = |i: usize| match i < half {
+            true => -input[3 * half - 1 - i] - input[3 * half + i],
+            false => input[i - half] - input[3 * half - 1 - i],
+        };
+        for (i, value) in self.buffer.iter_mut().enumerate() {
+            *value = Complex::new(folded(2 * i), folded(len - 1 - 2 * i)) * self.pre[i];
+        }
+        self.fft.process(&mut self.buffer);
+        for (i, (value, post)) in self.buffer.iter().zip(&self.post).enumerate() {
+            let value = value * post;
+            out[2 * i] = value.re;
+            out[len - 1 - 2 * i] = -value.im;
+        }
+    }
+}
+
+/// The rising half of the sine window for transforms of `len` coefficients
+pub(super) fn sine_window(len: usize) -> Vec<f32> {
+    (0..len)
+        .map(|i| ((i as f64 + 0.5) * PI / (2 * len) as f64).sin() as f32)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_the_plain_transform() {
+        let len = 64;
+        let input: Vec<f32> = (0..2 * len)
+            .map(|i| ((i * 37 % 101) as f32 / 50.0 - 1.0) * (i as f32 / 9.0).sin())
+            .collect();
+        let mut out = vec![0.0; len];
+        Mdct::new(len).transform(&input, &mut out);
+        for (k, &value) in out.iter().enumerate() {
+            let expected: f64 = input
+                .iter()
+                .enumerate()
+                .map(|(i, &x)| {
+                    let phase = PI / len as f64 * (i as f64 + 0.5 + len as f64 / 2.0);
+                    4.0 * x as f64 * (phase * (k as f64 + 0.5)).cos()
+                })
+                .sum();
+            assert!(
+                (value as f64 - expected).abs() < 2e-3,
+                "{k}: {value} {expected}"
+            );
+        }
+    }
+}
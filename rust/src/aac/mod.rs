@@ -0,0 +1,945 @@
+mod huffman;
+mod mdct;
+mod mp4;
+
+use std::path::Path;
+
+use self::huffman::ZERO_BOOK;
+use self::mdct::{sine_window, Mdct};
+use self::mp4::{Mp4Writer, Track};
+use crate::flac::BitWriter;
+use crate::TranscodeOptions;
+
+/// Frames each AAC frame codes per channel
+const FRAME: usize = 1024;
+
+/// Frames of each of the eight transforms of a frame coded in short blocks
+const SHORT: usize = 128;
+const SHORT_WINDOWS: usize = 8;
+
+/// Where the first short window starts in a frame's window, which is 2048 frames long
+const SHORT_START: usize = 448;
+
+/// Input frames held per channel: a frame's window and the next frame's short windows,
+/// which are looked at for attacks
+const BUFFERED: usize = FRAME + SHORT_START + (SHORT_WINDOWS + 1) * SHORT;
+
+/// Bits of each frame a decoder buffers per channel, which no frame may go over
+const MAX_CHANNEL_BITS: usize = 6144;
+
+const DEFAULT_KBPS_PER_CHANNEL: u32 = 128;
+const MIN_KBPS_PER_CHANNEL: u32 = 16;
+
+/// Scalefactor of a quantizer step of one, for samples from -1 to 1
+const UNIT_SCALEFACTOR: f32 = 156.0;
+
+/// Rounding of the quantizer, a little under a half to favour smaller values
+const ROUNDING: f32 = 0.4054;
+
+/// Noise of the quantizer per coefficient, relative to `step^1.5 * sqrt|x|`
+const QUANTIZER_NOISE: f32 = 0.148;
+
+/// Noise allowed relative to what masking hides, at most and at least; at 1 all of
+/// every band is masked and left out
+const MIN_NOISE_RATIO: f32 = 1e-6;
+const MAX_NOISE_RATIO: f32 = 1.0;
+
+/// Bisections of the noise ratio when fitting a frame to its bits
+const RATE_STEPS: usize = 10;
+
+/// How much of a band's energy masks noise in the band above and below it
+const SPREAD_UP: f32 = 0.15;
+const SPREAD_DOWN: f32 = 0.05;
+
+/// Where the threshold of hearing's 0 dB is put, in dB below a long block's
+/// coefficient power of one
+const QUIET_DB: f32 = 40.0;
+
+/// How much louder than the blocks before it a block has to be to be coded short
+const ATTACK_RATIO: f32 = 10.0;
+
+/// Share of the bits saved up a frame may spend, coded in long and short blocks
+const LONG_RESERVOIR_SHARE: f64 = 0.25;
+const SHORT_RESERVOIR_SHARE: f64 = 0.6;
+
+// Scalefactor band offsets, from ISO/IEC 14496-3 tables 4.129 to 4.131
+#[rustfmt::skip]
+const LONG_BANDS_48K: [usize; 50] = [
+    0, 4, 8, 12, 16, 20, 24, 28, 32, 36, 40, 48, 56, 64, 72, 80, 88, 96, 108, 120, 132, 144,
+    160, 176, 196, 216, 240, 264, 292, 320, 352, 384, 416, 448, 480, 512, 544, 576, 608, 640,
+    672, 704, 736, 768, 800, 832, 864, 896, 928, 1024,
+];
+#[rustfmt::skip]
+const LONG_BANDS_32K: [usize; 52] = [
+    0, 4, 8, 12, 16, 20, 24, 28, 32, 36, 40, 48, 56, 64, 72, 80, 88, 96, 108, 120, 132, 144,
+    160, 176, 196, 216, 240, 264, 292, 320, 352, 384, 416, 448, 480, 512, 544, 576, 608, 640,
+    672, 704, 736, 768, 800, 832, 864, 896, 928, 960, 992, 1024,
+];
+const SHORT_BANDS: [usize; 15] = [0, 4, 8, 12, 16, 20, 28, 36, 44, 56, 68, 80, 96, 112, 128];
+
+/// A sample rate AAC is written at, with its index in the AudioSpecificConfig
+struct Rate {
+    hz: u32,
+    index: u8,
+    long_bands: &'static [usize],
+}
+
+const RATES: [Rate; 3] = [
+    Rate {
+        hz: 48_000,
+        index: 3,
+        long_bands: &LONG_BANDS_48K,
+    },
+    Rate {
+        hz: 44_100,
+        index: 4,
+        long_bands: &LONG_BANDS_48K,
+    },
+    Rate {
+        hz: 32_000,
+        index: 5,
+        long_bands: &LONG_BANDS_32K,
+    },
+];
+
+/// Rate sources at other rates are resampled to
+pub(crate) const RESAMPLE_RATE: u32 = 48_000;
+
+/// Whether AAC is written at `sample_rate` without resampling
+pub(crate) fn codes_rate(sample_rate: u32) -> bool {
+    RATES.iter().any(|rate| rate.hz == sample_rate)
+}
+
+/// How a frame's 2048 frame window is shaped and transformed
+#[derive(Clone, Copy, PartialEq)]
+enum Sequence {
+    Long = 0,
+    /// Long, leading into short blocks
+    Start = 1,
+    Short = 2,
+    /// Long, following short blocks
+    Stop = 3,
+}
+
+impl Sequence {
+    /// Whether the window ends in a short block's slope
+    fn ends_short(self) -> bool {
+        matches!(self, Sequence::Start | Sequence::Short)
+    }
+}
+
+/// An AAC-LC encoder for one or two channels, writing an MPEG-4 audio file
+///
+/// Switches to short blocks on attacks, codes stereo bands as mid and side where that
+/// takes fewer bits, and places the noise of each band under what louder bands near it
+/// mask. Frames get an even share of the bitrate, topped up from what quieter frames
+/// left over.
+pub(crate) struct AacWriter {
+    mp4: Mp4Writer,
+    rate: &'static Rate,
+    channels: usize,
+    long: Mdct,
+    short: Mdct,
+    long_window: Vec<f32>,
+    short_window: Vec<f32>,
+    /// Each channel's input from the start of the next frame's window on; the first
+    /// window starts a frame of silence before the input
+    input: Vec<Vec<f32>>,
+    /// Noise too quiet to hear in each long and short band, per coefficient
+    long_quiet: Vec<f32>,
+    short_quiet: Vec<f32>,
+    /// Long and short bands below the low-pass, which the rest are left out above
+    long_max_band: usize,
+    short_max_band: usize,
+    frame_bits: f64,
+    max_frame_bits: f64,
+    reservoir: f64,
+    previous: Sequence,
+    /// Whether the next frame found an attack in its short windows
+    next_short: bool,
+    frames_in: u64,
+    frames_out: u64,
+    coefficients: Vec<Vec<f32>>,
+    windowed: Vec<f32>,
+}
+
+impl AacWriter {
+    pub fn create(
+        path: &Path,
+        channels: u16,
+        sample_rate: u32,
+        options: &TranscodeOptions,
+    ) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            (1..=2).contains(&channels),
+            "AAC is written with one or two channels, not {channels}"
+        );
+        let Some(rate) = RATES.iter().find(|rate| rate.hz == sample_rate) else {
+            anyhow::bail!("AAC isn't written at {sample_rate} Hz");
+        };
+        let channels = channels as usize;
+        let max_frame_bits = (MAX_CHANNEL_BITS * channels) as f64;
+        let frame_seconds = FRAME as f64 / sample_rate as f64;
+        let kbps = options
+            .bitrate_kbps
+            .unwrap_or(DEFAULT_KBPS_PER_CHANNEL * channels as u32)
+            .max(MIN_KBPS_PER_CHANNEL * channels as u32);
+        // Leave room for frames spending saved up bits.
+        let frame_bits = (kbps as f64 * 1000.0 * frame_seconds).min(max_frame_bits * 0.75);
+
+        let kbps_per_channel = frame_bits / frame_seconds / 1000.0 / channels as f64;
+        let cutoff_hz = (5_000.0 + 150.0 * kbps_per_channel).min(20_000.0);
+        let cutoff = |bands: &[usize], len: usize| {
+            let bin = cutoff_hz * 2.0 * len as f64 / sample_rate as f64;
+            bands
+                .iter()
+                .skip(1)
+                .take_while(|&&end| (end as f64) <= bin)
+                .count()
+        };
+        let quiet = |bands: &[usize], len: usize| -> Vec<f32> {
+            bands
+                .windows(2)
+                .map(|band| {
+                    let center = (band[0] + band[1]) as f32 / 2.0;
+                    let hz = center * sample_rate as f32 / (2 * len) as f32;
+                    let db = threshold_of_hearing(hz)
+                        - QUIET_DB
+                        - 20.0 * (FRAME as f32 / len as f32).log10();
+                    10f32.powf(db / 10.0)
+                })
+                .collect()
+        };
+        Ok(AacWriter {
+            mp4: Mp4Writer::create(path)?,
+            rate,
+            channels,
+            long: Mdct::new(FRAME),
+            short: Mdct::new(SHORT),
+            long_window: sine_window(FRAME),
+            short_window: sine_window(SHORT),
+            input: vec![vec![0.0; FRAME]; channels],
+            long_quiet: quiet(rate.long_bands, FRAME),
+            short_quiet: quiet(&SHORT_BANDS, SHORT),
+            long_max_band: cutoff(rate.long_bands, FRAME).max(1),
+            short_max_band: cutoff(&SHORT_BANDS, SHORT).max(1),
+            frame_bits,
+            max_frame_bits,
+            reservoir: 0.0,
+            previous: Sequence::Long,
+            next_short: false,
+            frames_in: 0,
+            frames_out: 0,
+            coefficients: vec![vec![0.0; FRAME]; channels],
+            windowed: vec![0.0; 2 * FRAME],
+        })
+    }
+
+    /// Encode interleaved samples
+    pub fn write(&mut self, samples: &[f32]) -> anyhow::Result<()> {
+        for frame in samples.chunks_exact(self.channels) {
+            for (input, &sample) in self.input.iter_mut().zip(frame) {
+                input.push(sample);
+            }
+            if self.input[0].len() == BUFFERED {
+                self.encode_frame()?;
+            }
+        }
+        self.frames_in += (samples.len() / self.channels) as u64;
+        Ok(())
+    }
+
+    /// Encode what's left and write the index of the frames
+    pub fn finish(mut self) -> anyhow::Result<()> {
+        // Frames until the last input frame is decoded, the first being the silence
+        // before the input.
+        let frames = self.frames_in.div_ceil(FRAME as u64) + 1;
+        while self.frames_out < frames {
+            for input in &mut self.input {
+                input.resize(BUFFERED, 0.0);
+            }
+            self.encode_frame()?;
+        }
+        let config = [
+            // AAC-LC, then the rate's index and the channel configuration
+            (2 << 3) | (self.rate.index >> 1),
+            (self.rate.index << 7) | ((self.channels as u8) << 3),
+        ];
+        self.mp4.finish(&Track {
+            sample_rate: self.rate.hz,
+            channels: self.channels as u16,
+            config: config.to_vec(),
+            frames_per_sample: FRAME as u32,
+            priming: FRAME as u32,
+            frames: self.frames_in,
+        })
+    }
+
+    fn encode_frame(&mut self) -> anyhow::Result<()> {
+        let short = self.next_short;
+        self.next_short = self.input.iter().any(|input| has_attack(input));
+        let sequence = match (short, self.previous.ends_short(), self.next_short) {
+            (true, _, _) => Sequence::Short,
+            // A long window can't end and start short blocks both.
+            (false, true, true) => Sequence::Short,
+            (false, true, false) => Sequence::Stop,
+            (false, false, true) => Sequence::Start,
+            (false, false, false) => Sequence::Long,
+        };
+        for channel in 0..self.channels {
+            self.transform(channel, sequence);
+        }
+        let groups = match sequence {
+            Sequence::Short => self.groups(),
+            _ => vec![1],
+        };
+        let mut spectra: Vec<Spectrum> = self
+            .coefficients
+            .iter()
+            .map(|coefficients| self.spectrum(coefficients, sequence, &groups))
+            .collect();
+        let mid_side = match &mut spectra[..] {
+            [left, right] => mid_side(left, right),
+            _ => Vec::new(),
+        };
+
+        let share = match sequence {
+            Sequence::Short => SHORT_RESERVOIR_SHARE,
+            _ => LONG_RESERVOIR_SHARE,
+        };
+        let budget = (self.frame_bits + self.reservoir * share).min(self.max_frame_bits);
+        let frame = Frame {
+            sequence,
+            groups: &groups,
+            spectra: &spectra,
+            mid_side: &mid_side,
+        };
+        let coded = frame.fit(budget);
+        let mut bits = BitWriter::default();
+        frame.write(&coded, &mut bits);
+        let used = bits.bytes.len() as f64 * 8.0;
+        debug_assert_eq!(bits.bytes.len() * 8, frame.bits(&coded));
+        debug_assert!(used <= self.max_frame_bits);
+        self.reservoir = (self.reservoir + self.frame_bits - used)
+            .clamp(0.0, self.max_frame_bits - self.frame_bits);
+        self.mp4.write_sample(&bits.bytes)?;
+
+        self.previous = sequence;
+        self.frames_out += 1;
+        for input in &mut self.input {
+            input.drain(..FRAME);
+        }
+        Ok(())
+    }
+
+    /// Fill `coefficients` of `channel` with its input's transform for `sequence`
+    fn transform(&mut self, channel: usize, sequence: Sequence) {
+        let input = &self.input[channel];
+        let out = &mut self.coefficients[channel];
+        let (long, short) = (&self.long_window, &self.short_window);
+        if sequence == Sequence::Short {
+            for (window, out) in out.chunks_exact_mut(SHORT).enumerate() {
+                let start = SHORT_START + window * SHORT;
+                for (i, windowed) in self.windowed[..2 * SHORT].iter_mut().enumerate() {
+                    let shape = match i < SHORT {
+                        true => short[i],
+                        false => short[2 * SHORT - 1 - i],
+                    };
+                    *windowed = input[start + i] * shape;
+                }
+                self.short.transform(&self.windowed[..2 * SHORT], out);
+            }
+            return;
+        }
+        let short_end = SHORT_START + SHORT;
+        for (i, windowed) in self.windowed.iter_mut().enumerate() {
+            let shape = match (sequence, i < FRAME) {
+                (Sequence::Stop, true) if i < SHORT_START => 0.0,
+                (Sequence::Stop, true) if i < short_end => short[i - SHORT_START],
+                (Sequence::Stop, true) => 1.0,
+                (Sequence::Start, false) if i < FRAME + SHORT_START => 1.0,
+                (Sequence::Start, false) if i < FRAME + short_end => {
+                    short[FRAME + short_end - 1 - i]
+                }
+                (Sequence::Start, false) => 0.0,
+                (_, true) => long[i],
+                (_, false) => long[2 * FRAME - 1 - i],
+            };
+            *windowed = input[i] * shape;
+        }
+        self.long.transform(&self.windowed, out);
+    }
+
+    /// Windows of a short frame in each group sharing scalefactors: runs without a
+    /// jump in loudness
+    fn groups(&self) -> Vec<usize> {
+        let energies: Vec<f32> = (0..SHORT_WINDOWS)
+            .map(|window| {
+                let range = window * SHORT..(window + 1) * SHORT;
+                self.coefficients
+                    .iter()
+                    .flat_map(|coefficients| &coefficients[range.clone()])
+                    .map(|x| x * x)
+                    .sum::<f32>()
+                    + 1e-9
+            })
+            .collect();
+        let mut groups = vec![1];
+        for pair in energies.windows(2) {
+            let ratio = pair[1] / pair[0];
+            match (0.25..4.0).contains(&ratio) {
+                true => *groups.last_mut().unwrap() += 1,
+                false => groups.push(1),
+            }
+        }
+        groups
+    }
+
+    /// `coefficients` cut into bands, group after group
+    fn spectrum(&self, coefficients: &[f32], sequence: Sequence, groups: &[usize]) -> Spectrum {
+        let (bands, quiet, max_band) = match sequence {
+            Sequence::Short => (&SHORT_BANDS[..], &self.short_quiet, self.short_max_band),
+            _ => (self.rate.long_bands, &self.long_quiet, self.long_max_band),
+        };
+        let mut spectrum = Vec::with_capacity(groups.len() * max_band);
+        let mut first_window = 0;
+        for &windows in groups {
+            let start = spectrum.len();
+            for band in 0..max_band {
+                let values = (first_window..first_window + windows)
+                    .flat_map(|window| {
+                        let offset = window * SHORT;
+                        &coefficients[offset + bands[band]..offset + bands[band + 1]]
+                    })
+                    .copied()
+                    .collect();
+                spectrum.push(Band::new(values, quiet[band]));
+            }
+            spread_masking(&mut spectrum[start..]);
+            first_window += windows;
+        }
+        Spectrum { bands: spectrum }
+    }
+}
+
+/// Whether `input`'s next frame has an attack in its short windows, judged by the
+/// energy of its changes block by block
+fn has_attack(input: &[f32]) -> bool {
+    let first = FRAME + SHORT_START;
+    let energy = |block: usize| -> f32 {
+        let start = first + block * SHORT - SHORT;
+        (start..start + SHORT)
+            .map(|i| {
+                let change = input[i] - input[i - 1];
+                change * change
+            })
+            .sum()
+    };
+    let mut before = energy(0);
+    for block in 1..=SHORT_WINDOWS + 1 {
+        let energy = energy(block);
+        if energy > ATTACK_RATIO * before && energy > 1e-5 {
+            return true;
+        }
+        before = (before * 0.5).max(energy);
+    }
+    false
+}
+
+/// Threshold of hearing at `hz`, in dB SPL
+fn threshold_of_hearing(hz: f32) -> f32 {
+    let khz = hz.max(20.0) / 1000.0;
+    let db = 3.64 * khz.powf(-0.8) - 6.5 * (-0.6 * (khz - 3.3).powi(2)).exp() + 1e-3 * khz.powi(4);
+    db.min(90.0)
+}
+
+/// Let each band's masking reach into the bands around it
+fn spread_masking(bands: &mut [Band]) {
+    for i in 1..bands.len() {
+        bands[i].mask = bands[i].mask.max(bands[i - 1].mask * SPREAD_UP);
+    }
+    for i in (0..bands.len().saturating_sub(1)).rev() {
+        bands[i].mask = bands[i].mask.max(bands[i + 1].mask * SPREAD_DOWN);
+    }
+}
+
+/// Bands of the two channels to code as mid and side, which become their spectra
+fn mid_side(left: &mut Spectrum, right: &mut Spectrum) -> Vec<bool> {
+    let mut used = Vec::with_capacity(left.bands.len());
+    for (left, right) in left.bands.iter_mut().zip(&mut right.bands) {
+        let mask = left.mask.min(right.mask);
+        let (mid, side): (Vec<f32>, Vec<f32>) = left
+            .values
+            .iter()
+            .zip(&right.values)
+            .map(|(l, r)| ((l + r) / 2.0, (l - r) / 2.0))
+            .unzip();
+        let mid = Band::new(mid, left.quiet);
+        let side = Band::new(side, left.quiet);
+        // Rough bits each way: those of the ratio of each band's energy to its masking.
+        let cost = |band: &Band, mask: f32| (band.energy / mask).max(1.0).log2();
+        let stereo = cost(left, left.mask) + cost(right, right.mask);
+        let joint = cost(&mid, mask) + cost(&side, mask);
+        let use_mid_side = joint < stereo;
+        if use_mid_side {
+            *left = Band { mask, ..mid };
+            *right = Band { mask, ..side };
+        }
+        used.push(use_mid_side);
+    }
+    used
+}
+
+/// One channel's coefficients in bands, group after group
+struct Spectrum {
+    bands: Vec<Band>,
+}
+
+struct Band {
+    /// Window after window of the group
+    values: Vec<f32>,
+    /// `|x|^0.75` of each, which the quantizer steps through evenly
+    scaled: Vec<f32>,
+    peak: f32,
+    /// Mean of the squares, and of the square roots of the magnitudes
+    energy: f32,
+    roughness: f32,
+    /// Noise masked per coefficient, before scaling by the frame's noise ratio
+    mask: f32,
+    /// Noise too quiet to hear per coefficient
+    quiet: f32,
+}
+
+impl Band {
+    fn new(values: Vec<f32>, quiet: f32) -> Band {
+        let len = values.len() as f32;
+        let scaled = values.iter().map(|x| x.abs().powf(0.75)).collect();
+        let energy = values.iter().map(|x| x * x).sum::<f32>() / len;
+        Band {
+            scaled,
+            peak: values.iter().fold(0.0, |peak, x| x.abs().max(peak)),
+            energy,
+            roughness: values.iter().map(|x| x.abs().sqrt()).sum::<f32>() / len,
+            mask: energy,
+            quiet,
+            values,
+        }
+    }
+
+    /// Scalefactor of a quantizer whose noise is about `noise` per coefficient, or
+    /// `None` if leaving the values out is quiet enough
+    fn scalefactor(&self, noise: f32) -> Option<i32> {
+        if noise >= self.energy {
+            return None;
+        }
+        let step = (noise / (QUANTIZER_NOISE * self.roughness)).powf(2.0 / 3.0);
+        let scalefactor = UNIT_SCALEFACTOR + 4.0 * step.log2();
+        // Fine enough a step would take values past what escape codes hold.
+        let max_step = (huffman::MAX_VALUE as f32 - ROUNDING).powf(4.0 / 3.0);
+        let min = UNIT_SCALEFACTOR + 4.0 * (self.peak / max_step).log2();
+        Some(scalefactor.max(min.ceil()).round() as i32)
+    }
+
+    /// The band quantized at `scalefactor`, then whether any value is left
+    fn quantize(&self, scalefactor: i32, out: &mut Vec<i32>) -> bool {
+        let gain = 2f32.powf(-0.1875 * (scalefactor as f32 - UNIT_SCALEFACTOR));
+        out.clear();
+        out.extend(self.scaled.iter().zip(&self.values).map(|(scaled, value)| {
+            let q = ((scaled * gain + ROUNDING) as i32).min(huffman::MAX_VALUE as i32);
+            match *value < 0.0 {
+                true => -q,
+                false => q,
+            }
+        }));
+        out.iter().any(|&q| q != 0)
+    }
+}
+
+/// A channel quantized for one noise ratio
+struct Coded {
+    /// Per band
+    values: Vec<Vec<i32>>,
+    books: Vec<u8>,
+    scalefactors: Vec<i32>,
+    global_gain: i32,
+    bits: usize,
+}
+
+/// A frame ready to be quantized
+struct Frame<'a> {
+    sequence: Sequence,
+    groups: &'a [usize],
+    spectra: &'a [Spectrum],
+    /// Per band of a stereo frame: whether it's coded as mid and side
+    mid_side: &'a [bool],
+}
+
+impl Frame<'_> {
+    fn bands_per_group(&self) -> usize {
+        self.spectra[0].bands.len() / self.groups.len()
+    }
+
+    /// The channels quantized as finely as fits in `budget` bits
+    fn fit(&self, budget: f64) -> Vec<Coded> {
+        let finest = self.code(MIN_NOISE_RATIO);
+        if self.bits(&finest) as f64 <= budget {
+            return finest;
+        }
+        let (mut fine, mut coarse) = (MIN_NOISE_RATIO.ln(), MAX_NOISE_RATIO.ln());
+        let mut best = self.code(MAX_NOISE_RATIO);
+        for _ in 0..RATE_STEPS {
+            let middle = (fine + coarse) / 2.0;
+            let coded = self.code(middle.exp());
+            match self.bits(&coded) as f64 <= budget {
+                true => {
+                    coarse = middle;
+                    best = coded;
+                }
+                false => fine = middle,
+            }
+        }
+        best
+    }
+
+    fn code(&self, noise_ratio: f32) -> Vec<Coded> {
+        self.spectra
+            .iter()
+            .map(|spectrum| self.code_channel(spectrum, noise_ratio))
+            .collect()
+    }
+
+    fn code_channel(&self, spectrum: &Spectrum, noise_ratio: f32) -> Coded {
+        let count = spectrum.bands.len();
+        let mut coded = Coded {
+            values: Vec::with_capacity(count),
+            books: Vec::with_capacity(count),
+            scalefactors: Vec::with_capacity(count),
+            global_gain: 0,
+            bits: 0,
+        };
+        let mut last = None;
+        for band in &spectrum.bands {
+            let mut values = Vec::with_capacity(band.values.len());
+            let noise = (band.mask * noise_ratio).max(band.quiet);
+            let scalefactor = band.scalefactor(noise).map(|scalefactor| {
+                // Scalefactors are coded as differences of at most 60 from the last.
+                let scalefactor = match last {
+                    Some(last) => scalefactor.clamp(last - 60, last + 60),
+                    None => scalefactor,
+                };
+                scalefactor.clamp(0, 255)
+            });
+            let (book, bits) = match scalefactor {
+                Some(scalefactor) if band.quantize(scalefactor, &mut values) => {
+                    if let Some(last) = last {
+                        coded.bits += huffman::scalefactor(scalefactor - last).1 as usize;
+                    } else {
+                        coded.global_gain = scalefactor;
+                        // The first scalefactor is coded as a difference of zero.
+                        coded.bits += huffman::scalefactor(0).1 as usize;
+                    }
+                    last = Some(scalefactor);
+                    huffman::choose(&values)
+                }
+                _ => {
+                    values.clear();
+                    values.resize(band.values.len(), 0);
+                    (ZERO_BOOK, 0)
+                }
+            };
+            coded.bits += bits as usize;
+            coded.values.push(values);
+            coded.books.push(book);
+            coded.scalefactors.push(scalefactor.unwrap_or(0));
+        }
+        coded.bits += self.section_bits(&coded.books);
+        coded
+    }
+
+    /// Bits of the section data coding `books`
+    fn section_bits(&self, books: &[u8]) -> usize {
+        let len_bits = self.section_len_bits();
+        let escape = (1 << len_bits) - 1;
+        books
+            .chunks(self.bands_per_group())
+            .flat_map(sections)
+            .map(|(_, len)| 4 + len_bits * (len / escape + 1))
+            .sum()
+    }
+
+    fn section_len_bits(&self) -> usize {
+        match self.sequence {
+            Sequence::Short => 3,
+            _ => 5,
+        }
+    }
+
+    /// Bits the frame takes coded as `coded`, once byte aligned
+    fn bits(&self, coded: &[Coded]) -> usize {
+        // Each channel's global gain and the tool flags after its scalefactors
+        let channels: usize = coded.iter().map(|coded| coded.bits + 8 + 3).sum();
+        let bits = 3 + 4 + self.info_bits() + channels + 3;
+        let bits = match coded.len() {
+            2 => bits + 1 + 2 + self.mid_side_bits(),
+            _ => bits,
+        };
+        bits.div_ceil(8) * 8
+    }
+
+    fn info_bits(&self) -> usize {
+        match self.sequence {
+            Sequence::Short => 1 + 2 + 1 + 4 + 7,
+            _ => 1 + 2 + 1 + 6 + 1,
+        }
+    }
+
+    fn mid_side_bits(&self) -> usize {
+        match self.mid_side.contains(&true) {
+            true => self.mid_side.len(),
+            false => 0,
+        }
+    }
+
+    /// The raw data block of the frame, coded as `coded`
+    fn write(&self, coded: &[Coded], bits: &mut BitWriter) {
+        match coded {
+            [channel] => {
+                // A single channel element
+                bits.put(0, 3);
+                bits.put(0, 4);
+                bits.put(channel.global_gain as u64, 8);
+                self.write_info(bits);
+                self.write_channel(channel, bits);
+            }
+            [left, right] => {
+                // A channel pair element, with a common window
+                bits.put(1, 3);
+                bits.put(0, 4);
+                bits.put(1, 1);
+                self.write_info(bits);
+                if self.mid_side.contains(&true) {
+                    bits.put(1, 2);
+                    for &used in self.mid_side {
+                        bits.put(used as u64, 1);
+                    }
+                } else {
+                    bits.put(0, 2);
+                }
+                for channel in [left, right] {
+                    bits.put(channel.global_gain as u64, 8);
+                    self.write_channel(channel, bits);
+                }
+            }
+            _ => unreachable!("frames have one or two channels"),
+        }
+        // The end of the block
+        bits.put(7, 3);
+        bits.align();
+    }
+
+    fn write_info(&self, bits: &mut BitWriter) {
+        bits.put(0, 1);
+        bits.put(self.sequence as u64, 2);
+        // Sine windows throughout
+        bits.put(0, 1);
+        let max_band = self.bands_per_group() as u64;
+        match self.sequence {
+            Sequence::Short => {
+                bits.put(max_band, 4);
+                // Whether each window after the first is in the same group as the one
+                // before it
+                let mut grouping = 0;
+                for &windows in self.groups {
+                    grouping = grouping << windows | ((1 << windows) - 1) >> 1;
+                }
+                bits.put(grouping, 7);
+            }
+            _ => {
+                bits.put(max_band, 6);
+                // No prediction
+                bits.put(0, 1);
+            }
+        }
+    }
+
+    fn write_channel(&self, coded: &Coded, bits: &mut BitWriter) {
+        let len_bits = self.section_len_bits() as u32;
+        let escape = (1 << len_bits) - 1;
+        for books in coded.books.chunks(self.bands_per_group()) {
+            for (book, mut len) in sections(books) {
+                bits.put(book as u64, 4);
+                while len >= escape {
+                    bits.put(escape as u64, len_bits);
+                    len -= escape;
+                }
+                bits.put(len as u64, len_bits);
+            }
+        }
+        let mut last = coded.global_gain;
+        for (&book, &scalefactor) in coded.books.iter().zip(&coded.scalefactors) {
+            if book != ZERO_BOOK {
+                let (code, len) = huffman::scalefactor(scalefactor - last);
+                bits.put(code as u64, len);
+                last = scalefactor;
+            }
+        }
+        // No pulses, noise shaping or gain control
+        bits.put(0, 3);
+        for (&book, values) in coded.books.iter().zip(&coded.values) {
+            if book != ZERO_BOOK {
+                huffman::write(book, values, bits);
+            }
+        }
+    }
+}
+
+/// Runs of the same codebook in `books`, with their lengths
+fn sections(books: &[u8]) -> Vec<(u8, usize)> {
+    let mut sections: Vec<(u8, usize)> = Vec::new();
+    for &book in books {
+        match sections.last_mut() {
+            Some((last, len)) if *last == book => *len += 1,
+            _ => sections.push((book, 1)),
+        }
+    }
+    sections
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::TAU;
+    use std::fs;
+
+    use symphonia::core::audio::SampleBuffer;
+    use symphonia::core::codecs::{DecoderOptions, CODEC_TYPE_AAC};
+    use symphonia::core::formats::FormatOptions;
+    use symphonia::core::io::MediaSourceStream;
+    use symphonia::core::meta::MetadataOptions;
+    use symphonia::core::probe::Hint;
+
+    use super::*;
+
+    /// Interleaved tones, a different one per channel, with a burst of noise a second
+    /// in for the encoder to switch to short blocks on
+    fn music(channels: usize, frames: usize, rate: u32) -> Vec<f32> {
+        let mut noise = 0x1234_5678_u32;
+        let mut samples = Vec::with_capacity(channels * frames);
+        for frame in 0..frames {
+            let t = frame as f32 / rate as f32;
+            let burst = frame as i64 - rate as i64;
+            for channel in 0..channels {
+                let pitch = 440.0 * (channel + 2) as f32 / 2.0;
+                let mut sample =
+                    0.3 * (TAU * pitch * t).sin() + 0.1 * (TAU * 3.0 * pitch * t).sin();
+                if (0..2000).contains(&burst) {
+                    noise = noise.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                    let decay = (-(burst as f32) / 400.0).exp();
+                    sample += 0.5 * decay * (noise as i32 as f32 / i32::MAX as f32);
+                }
+                samples.push(sample);
+            }
+        }
+        samples
+    }
+
+    /// Encode `samples` and decode them back with symphonia, which keeps the frame of
+    /// priming the edit list cuts off
+    fn round_trip(samples: &[f32], channels: u16, rate: u32, kbps: u32) -> (u64, Vec<f32>) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.m4a");
+        let options = TranscodeOptions {
+            bitrate_kbps: Some(kbps),
+            ..Default::default()
+        };
+        let mut writer = AacWriter::create(&path, channels, rate, &options).unwrap();
+        // Uneven writes, so frames straddle them
+        for chunk in samples.chunks(1000 * channels as usize + channels as usize) {
+            writer.write(chunk).unwrap();
+        }
+        writer.finish().unwrap();
+        let size = fs::metadata(&path).unwrap().len();
+
+        let stream =
+            MediaSourceStream::new(Box::new(fs::File::open(&path).unwrap()), Default::default());
+        let mut hint = Hint::new();
+        hint.with_extension("m4a");
+        let mut format = symphonia::default::get_probe()
+            .format(
+                &hint,
+                stream,
+                &FormatOptions::default(),
+                &MetadataOptions::default(),
+            )
+            .unwrap()
+            .format;
+        let track = format.default_track().unwrap();
+        assert_eq!(track.codec_params.codec, CODEC_TYPE_AAC);
+        assert_eq!(track.codec_params.sample_rate, Some(rate));
+        let mut decoder = symphonia::default::get_codecs()
+            .make(&track.codec_params, &DecoderOptions::default())
+            .unwrap();
+        let mut decoded = Vec::new();
+        while let Ok(packet) = format.next_packet() {
+            let audio = decoder.decode(&packet).unwrap();
+            assert_eq!(audio.spec().channels.count(), channels as usize);
+            let mut buffer = SampleBuffer::<f32>::new(audio.capacity() as u64, *audio.spec());
+            buffer.copy_interleaved_ref(audio);
+            decoded.extend_from_slice(buffer.samples());
+        }
+        (size, decoded)
+    }
+
+    /// Signal to noise ratio of `decoded` against `samples`, in dB
+    fn snr(samples: &[f32], decoded: &[f32]) -> f32 {
+        let signal: f32 = samples.iter().map(|s| s * s).sum();
+        let noise: f32 = samples
+            .iter()
+            .zip(decoded)
+            .map(|(s, d)| (s - d) * (s - d))
+            .sum();
+        10.0 * (signal / noise).log10()
+    }
+
+    #[test]
+    fn decodes_back_close_to_the_input() {
+        for (channels, rate) in [(2, 44_100), (1, 48_000), (2, 32_000)] {
+            let frames = 2 * rate as usize + 321;
+            let samples = music(channels, frames, rate);
+            let (_, decoded) = round_trip(&samples, channels as u16, rate, 96 * channels as u32);
+            let priming = FRAME * channels;
+            assert!(decoded.len() >= priming + samples.len());
+            assert!(decoded.len() < priming + samples.len() + FRAME * channels);
+            let snr = snr(&samples, &decoded[priming..]);
+            assert!(snr > 22.0, "{channels} channels at {rate} Hz: {snr} dB");
+        }
+    }
+
+    #[test]
+    fn keeps_to_the_bitrate() {
+        let rate = 44_100;
+        let samples = music(2, 5 * rate as usize, rate);
+        for kbps in [64, 128, 256] {
+            let (size, _) = round_trip(&samples, 2, rate, kbps);
+            let kbps_out = size as f32 * 8.0 / 5.0 / 1000.0;
+            assert!(kbps_out < kbps as f32 * 1.1, "{kbps} kbps: {kbps_out}");
+        }
+    }
+
+    #[test]
+    fn silence_decodes_to_silence() {
+        let samples = vec![0.0; 2 * 5000];
+        let (_, decoded) = round_trip(&samples, 2, 44_100, 128);
+        assert!(decoded.iter().all(|&s| s == 0.0));
+    }
+
+    #[test]
+    fn unsupported_formats_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.m4a");
+        let options = TranscodeOptions::default();
+        assert!(AacWriter::create(&path, 0, 44_100, &options).is_err());
+        assert!(AacWriter::create(&path, 3, 44_100, &options).is_err());
+        assert!(AacWriter::create(&path, 2, 22_050, &options).is_err());
+        assert!(codes_rate(48_000) && !codes_rate(96_000));
+    }
+}
@@ -0,0 +1,269 @@
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Length of `mdat`'s header and the `free` atom before it, which `finish` turns
+/// into a 64-bit size if the samples need one
+///
+/// A 32-bit size is kept otherwise, as some readers (lofty among them) skip 64-bit
+/// ones wrongly.
+const MDAT_HEADER: u64 = 16;
+
+/// The identity transform `mvhd` and `tkhd` carry
+const MATRIX: [u32; 9] = [0x1_0000, 0, 0, 0, 0x1_0000, 0, 0, 0, 0x4000_0000];
+
+/// What `moov` says about the track, once all of it is written
+pub(super) struct Track {
+    pub sample_rate: u32,
+    pub channels: u16,
+    /// AudioSpecificConfig
+    pub config: Vec<u8>,
+    /// Frames decoded by each sample, the same for all of them
+    pub frames_per_sample: u32,
+    /// Frames of the decoded stream before the audio, and of the audio
+    pub priming: u32,
+    pub frames: u64,
+}
+
+/// An MPEG-4 audio file of one track, written one sample (a coded frame) at a time
+///
+/// Samples go straight into `mdat`; `finish` appends a `moov` indexing them, so none
+/// are buffered and tags written later can grow `moov` without moving them.
+pub(super) struct Mp4Writer {
+    file: BufWriter<File>,
+    mdat_start: u64,
+    sizes: Vec<u32>,
+}
+
+impl Mp4Writer {
+    pub fn create(path: &Path) -> anyhow::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(&atom(b"ftyp", &[b"M4A ", &[0; 4], b"M4A mp42isom"]))?;
+        let mdat_start = file.stream_position()?;
+        file.write_all(&atom(b"free", &[]))?;
+        file.write_all(&atom(b"mdat", &[]))?;
+        Ok(Mp4Writer {
+            file,
+            mdat_start,
+            sizes: Vec::new(),
+        })
+    }
+
+    pub fn write_sample(&mut self, sample: &[u8]) -> anyhow::Result<()> {
+        self.file.write_all(sample)?;
+        self.sizes.push(sample.len() as u32);
+        Ok(())
+    }
+
+    /// Fill in `mdat`'s size and write `moov`
+    pub fn finish(mut self, track: &Track) -> anyhow::Result<()> {
+        let end = self.file.stream_position()?;
+        match u32::try_from(end - self.mdat_start - 8) {
+            Ok(size) => {
+                self.file.seek(SeekFrom::Start(self.mdat_start + 8))?;
+                self.file.write_all(&size.to_be_bytes())?;
+            }
+            Err(_) => {
+                self.file.seek(SeekFrom::Start(self.mdat_start))?;
+                self.file.write_all(&1u32.to_be_bytes())?;
+                self.file.write_all(b"mdat")?;
+                self.file
+                    .write_all(&(end - self.mdat_start).to_be_bytes())?;
+            }
+        }
+        self.file.seek(SeekFrom::Start(end))?;
+        let moov = self.moov(track)?;
+        self.file.write_all(&moov)?;
+        self.file.flush()?;
+        Ok(())
+    }
+
+    fn moov(&self, track: &Track) -> anyhow::Result<Vec<u8>> {
+        let too_long = || anyhow::anyhow!("too long for an MPEG-4 file");
+        let duration = u32::try_from(track.frames).map_err(|_| too_long())?;
+        let media_duration =
+            u32::try_from(self.sizes.len() as u64 * track.frames_per_sample as u64)
+                .map_err(|_| too_long())?;
+        let rate = track.sample_rate;
+        let matrix: Vec<u8> = MATRIX
+            .iter()
+            .flat_map(|value| value.to_be_bytes())
+            .collect();
+
+        let mvhd = full_atom(
+            b"mvhd",
+            0,
+            &[
+                &[0; 8],
+                &rate.to_be_bytes(),
+                &duration.to_be_bytes(),
+                &0x1_0000u32.to_be_bytes(),
+                &0x100u16.to_be_bytes(),
+                &[0; 10],
+                &matrix,
+                &[0; 24],
+                &2u32.to_be_bytes(),
+            ],
+        );
+        let tkhd = full_atom(
+            b"tkhd",
+            // Enabled, in the movie and in its preview
+            0x7,
+            &[
+                &[0; 8],
+                &1u32.to_be_bytes(),
+                &[0; 4],
+                &duration.to_be_bytes(),
+                &[0; 12],
+                &0x100u16.to_be_bytes(),
+                &[0; 2],
+                &matrix,
+                &[0; 8],
+            ],
+        );
+        // The priming frames are cut off by an edit list.
+        let elst = full_atom(
+            b"elst",
+            0,
+            &[
+                &1u32.to_be_bytes(),
+                &duration.to_be_bytes(),
+                &track.priming.to_be_bytes(),
+                &0x1_0000u32.to_be_bytes(),
+            ],
+        );
+        let mdhd = full_atom(
+            b"mdhd",
+            0,
+            &[
+                &[0; 8],
+                &rate.to_be_bytes(),
+                &media_duration.to_be_bytes(),
+                // "und", in three five-bit letters
+                &0x55c4u16.to_be_bytes(),
+                &[0; 2],
+            ],
+        );
+        let hdlr = full_atom(b"hdlr", 0, &[&[0; 4], b"soun", &[0; 12], b"SoundHandler\0"]);
+        let dinf = atom(
+            b"dinf",
+            &[&full_atom(
+                b"dref",
+                0,
+                &[&1u32.to_be_bytes(), &full_atom(b"url ", 1, &[])],
+            )],
+        );
+        let minf = atom(
+            b"minf",
+            &[&full_atom(b"smhd", 0, &[&[0; 4]]), &dinf, &self.stbl(track)],
+        );
+        let mdia = atom(b"mdia", &[&mdhd, &hdlr, &minf]);
+        let trak = atom(b"trak", &[&tkhd, &atom(b"edts", &[&elst]), &mdia]);
+        Ok(atom(b"moov", &[&mvhd, &trak]))
+    }
+
+    fn stbl(&self, track: &Track) -> Vec<u8> {
+        let count = self.sizes.len() as u32;
+        let total: u64 = self.sizes.iter().map(|&size| size as u64).sum();
+        let largest = self.sizes.iter().copied().max().unwrap_or(0);
+        let seconds =
+            (count as u64 * track.frames_per_sample as u64) as f64 / track.sample_rate as f64;
+        let average_bitrate = match seconds > 0.0 {
+            true => (total as f64 * 8.0 / seconds) as u32,
+            false => 0,
+        };
+        let peak_bitrate =
+            (largest as u64 * 8 * track.sample_rate as u64 / track.frames_per_sample as u64) as u32;
+
+        let decoder_config = descriptor(
+            0x04,
+            &[
+                // MPEG-4 audio, in an audio stream
+                &[0x40, 0x15],
+                &largest.to_be_bytes()[1..],
+                &peak_bitrate.to_be_bytes(),
+                &average_bitrate.to_be_bytes(),
+                &descriptor(0x05, &[&track.config]),
+            ],
+        );
+        let es = descriptor(
+            0x03,
+            &[&[0; 3], &decoder_config, &descriptor(0x06, &[&[0x02]])],
+        );
+        let mp4a = atom(
+            b"mp4a",
+            &[
+                &[0; 6],
+                &1u16.to_be_bytes(),
+                &[0; 8],
+                &track.channels.to_be_bytes(),
+                &16u16.to_be_bytes(),
+                &[0; 4],
+                &(track.sample_rate << 16).to_be_bytes(),
+                &full_atom(b"esds", 0, &[&es]),
+            ],
+        );
+        let stsd = full_atom(b"stsd", 0, &[&1u32.to_be_bytes(), &mp4a]);
+        let stts = full_atom(
+            b"stts",
+            0,
+            &[
+                &1u32.to_be_bytes(),
+                &count.to_be_bytes(),
+                &track.frames_per_sample.to_be_bytes(),
+            ],
+        );
+        // Every sample in one chunk, right after `mdat`'s header
+        let stsc = full_atom(
+            b"stsc",
+            0,
+            &[
+                &1u32.to_be_bytes(),
+                &1u32.to_be_bytes(),
+                &count.to_be_bytes(),
+                &1u32.to_be_bytes(),
+            ],
+        );
+        let sizes: Vec<u8> = self
+            .sizes
+            .iter()
+            .flat_map(|size| size.to_be_bytes())
+            .collect();
+        let stsz = full_atom(b"stsz", 0, &[&[0; 4], &count.to_be_bytes(), &sizes]);
+        let offset = self.mdat_start + MDAT_HEADER;
+        let stco = match u32::try_from(offset) {
+            Ok(offset) => full_atom(b"stco", 0, &[&1u32.to_be_bytes(), &offset.to_be_bytes()]),
+            Err(_) => full_atom(b"co64", 0, &[&1u32.to_be_bytes(), &offset.to_be_bytes()]),
+        };
+        atom(b"stbl", &[&stsd, &stts, &stsc, &stsz, &stco])
+    }
+}
+
+fn atom(kind: &[u8; 4], parts: &[&[u8]]) -> Vec<u8> {
+    let len: usize = parts.iter().map(|part| part.len()).sum();
+    let mut atom = Vec::with_capacity(8 + len);
+    atom.extend_from_slice(&(8 + len as u32).to_be_bytes());
+    atom.extend_from_slice(kind);
+    for part in parts {
+        atom.extend_from_slice(part);
+    }
+    atom
+}
+
+/// An atom starting with a version, always 0 here, and flags
+fn full_atom(kind: &[u8; 4], flags: u32, parts: &[&[u8]]) -> Vec<u8> {
+    let header = flags.to_be_bytes();
+    let mut all = vec![&header[..]];
+    all.extend_from_slice(parts);
+    atom(kind, &all)
+}
+
+/// An MPEG-4 descriptor inside `esds`, all of which are shorter than 128 bytes
+fn descriptor(tag: u8, parts: &[&[u8]]) -> Vec<u8> {
+    let len: usize = parts.iter().map(|part| part.len()).sum();
+    let mut descriptor = vec![tag, len as u8];
+    for part in parts {
+        descriptor.extend_from_slice(part);
+    }
+    descriptor
+}
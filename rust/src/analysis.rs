@@ -0,0 +1,191 @@
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use rodio::Source;
+
+use crate::decoder::SymphoniaSource;
+use crate::features::FeatureMeter;
+use crate::jobs::{self, Job};
+use crate::key::{Chromagram, Key};
+use crate::loudness::LoudnessMeter;
+use crate::tempo::{self, OnsetDetector};
+use crate::{cue, library, AnalysisEvent, JobKind, Song, StreamSink, TrackFeatures};
+
+/// How often, in frames, analysis checks whether it was cancelled
+const CANCEL_CHECK_FRAMES: u64 = 1 << 16;
+
+/// What decoding a song tells about it
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Analysis {
+    /// Tempo; `None` without a steady beat
+    pub bpm: Option<f32>,
+    /// `None` for silence
+    pub key: Option<Key>,
+    /// `None` for silence
+    pub features: Option<TrackFeatures>,
+    /// Integrated loudness in LUFS; `None` unless asked for
+    pub loudness: Option<f32>,
+}
+
+/// Decode `song` and estimate its tempo, key and audio features, and measure its
+/// loudness in the same pass if `loudness`
+///
+/// Stops early, finding nothing, once `cancelled` returns true.
+pub(crate) fn analyze(
+    song: &Song,
+    loudness: bool,
+    cancelled: impl Fn() -> bool,
+) -> anyhow::Result<Analysis> {
+    let mut source = SymphoniaSource::open(song.file_path.as_ref())?;
+    let channels = source.channels().max(1) as usize;
+    let sample_rate = source.sample_rate();
+    if cue::is_track(song) {
+        source
+            .try_seek(Duration::from_secs_f64(song.start_offset))
+            .map_err(|e| anyhow::anyhow!("cannot seek to the start of {}: {e}", song.title))?;
+    }
+    let length = song
+        .end_offset
+        .map(|end| ((end - song.start_offset).max(0.0) * sample_rate as f64) as u64);
+
+    let mut onsets = OnsetDetector::new(sample_rate);
+    let mut envelope = Vec::new();
+    let mut chroma = Chromagram::new(sample_rate);
+    let mut meter = FeatureMeter::new(sample_rate);
+    let mut loudness = match loudness {
+        true => Some(LoudnessMeter::new(channels as u16, sample_rate)?),
+        false => None,
+    };
+    let mut frames = 0u64;
+    loop {
+        if length.is_some_and(|length| frames >= length) {
+            break;
+        }
+        let mut sum = 0.0;
+        let mut read = 0;
+        for sample in source.by_ref().take(channels) {
+            sum += sample;
+            read += 1;
+            if let Some(loudness) = &mut loudness {
+                loudness.push(sample)?;
+            }
+        }
+        if read < channels {
+            break;
+        }
+        let mono = sum / channels as f32;
+        if let Some(strength) = onsets.push(mono) {
+            envelope.push(strength);
+        }
+        chroma.push(mono);
+        meter.push(mono);
+        frames += 1;
+        if frames.is_multiple_of(CANCEL_CHECK_FRAMES) && cancelled() {
+            return Ok(Analysis::default());
+        }
+    }
+    let bpm = tempo::estimate(&envelope);
+    Ok(Analysis {
+        bpm,
+        key: chroma.key(),
+        features: meter.features(song.bpm.or(bpm), &envelope),
+        loudness: loudness.map(LoudnessMeter::loudness).transpose()?,
+    })
+}
+
+/// Fill in the tempo and key of `song` where its tags had none, and its track loudness
+/// too if `loudness`, from one decode; returns the audio features measured on the way
+/// for storing once the song is
+pub(crate) fn complete(song: &mut Song, loudness: bool) -> Option<TrackFeatures> {
+    let loudness = loudness && song.track_loudness.is_none();
+    if song.bpm.is_some() && song.key.is_some() && !loudness {
+        return None;
+    }
+    match analyze(song, loudness, || false) {
+        Ok(analysis) => {
+            song.bpm = song.bpm.or(analysis.bpm);
+            song.key = song.key.take().or(analysis.key.map(Key::name));
+            song.track_loudness = song.track_loudness.or(analysis.loudness);
+            analysis.features
+        }
+        Err(e) => {
+            log::debug!("nothing measured for {}: {e}", song.file_path);
+            None
+        }
+    }
+}
+
+/// Tempo and key of the library song `song`, measured and stored unless they already were
+pub(crate) fn analysis_of(song: &Song) -> anyhow::Result<Analysis> {
+    let stored = Analysis {
+        bpm: song.bpm,
+        key: song.key.as_deref().and_then(Key::parse),
+        features: None,
+        loudness: None,
+    };
+    if stored.bpm.is_some() && stored.key.is_some() {
+        return Ok(stored);
+    }
+    let measured = analyze(song, false, || false)?;
+    let analysis = Analysis {
+        bpm: stored.bpm.or(measured.bpm),
+        key: stored.key.or(measured.key),
+        features: measured.features,
+        loudness: None,
+    };
+    library::with_library(|lib| lib.set_analysis(&song.id, analysis))?;
+    Ok(analysis)
+}
+
+/// Audio features of the library song `song`, measured and stored unless they
+/// already were; `None` for silence
+pub(crate) fn features_of(song: &Song) -> anyhow::Result<Option<TrackFeatures>> {
+    if let Some(features) = library::with_library(|lib| lib.get_features(&song.id))? {
+        return Ok(Some(features));
+    }
+    let analysis = analyze(song, false, || false)?;
+    library::with_library(|lib| lib.set_analysis(&song.id, analysis))?;
+    Ok(analysis.features)
+}
+
+/// Queue measuring the tempo, key and audio features of every library song missing
+/// any of them as a background job, returning its id for `jobs::cancel`
+pub(crate) fn start(sink: StreamSink<AnalysisEvent>) -> u32 {
+    jobs::submit(JobKind::Analysis, move |job| {
+        let (event, result) = match analyze_library(&sink, job) {
+            _ if job.is_cancelled() => (AnalysisEvent::Cancelled, Ok(())),
+            Ok(analyzed) => (AnalysisEvent::Finished { analyzed }, Ok(())),
+            Err(e) => {
+                let message = format!("{e:#}");
+                (AnalysisEvent::Failed { message }, Err(e))
+            }
+        };
+        let _ = sink.add(event);
+        result
+    })
+}
+
+fn analyze_library(sink: &StreamSink<AnalysisEvent>, job: &Job) -> anyhow::Result<u32> {
+    let songs = library::with_library(|lib| lib.get_songs_without_analysis())?;
+    let total = songs.len() as u32;
+    let mut analyzed = 0;
+    for (i, song) in songs.iter().enumerate() {
+        match analyze(song, false, || job.is_cancelled()) {
+            _ if job.is_cancelled() => break,
+            Ok(analysis) => {
+                library::with_library(|lib| lib.set_analysis(&song.id, analysis))?;
+                analyzed += 1;
+            }
+            Err(e) => log::debug!("nothing measured for {}: {e}", song.file_path),
+        }
+        let checked = i as u32 + 1;
+        if sink
+            .add(AnalysisEvent::Progress { checked, total })
+            .is_err()
+        {
+            job.cancelled().store(true, Ordering::Relaxed);
+        }
+        job.progress(checked as u64, Some(total as u64));
+    }
+    Ok(analyzed)
+}
@@ -0,0 +1,65 @@
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::ensure;
+
+use crate::library::{self, Library};
+use crate::{
+    collation, drop_folder, jobs, logging, sources, storage, watcher, EngineConfig, TunesError,
+};
+
+/// How long cancelled jobs get to wind down before their library is let go
+const JOB_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Set up logging, sorting, storage and the library as `config` asks, in that order,
+/// stopping at the first that fails
+pub(crate) fn init(config: EngineConfig) -> anyhow::Result<()> {
+    logging::init();
+    if config.sample_rate == Some(0) {
+        return Err(TunesError::invalid_state("the sample rate can't be zero").into());
+    }
+    if let Some(level) = config.log_level {
+        logging::set_level(level);
+    }
+    if let Some(locale) = &config.locale {
+        collation::set_locale(locale);
+    }
+    if let Some(storage) = config.storage.clone() {
+        storage::init(storage)?;
+    }
+    match &config.library_path {
+        Some(path) => open_library(Path::new(path))?,
+        // Starting over without a library closes the one opened before.
+        None => {
+            stop_library_work()?;
+            library::close();
+        }
+    }
+    Ok(())
+}
+
+/// Open (creating if needed) the library at `path`, stopping what works on the open one
+/// first if there is one
+pub(crate) fn open_library(path: &Path) -> anyhow::Result<()> {
+    let opened = Library::open(path)?;
+    if library::is_open() {
+        stop_library_work()?;
+    }
+    library::install(opened);
+    Ok(())
+}
+
+/// Stop everything working on the open library, before it's closed or replaced: its
+/// jobs, the watcher, browsed sources and the drop folder
+///
+/// Fails, having stopped nothing but cancelled jobs, if they don't end in time.
+pub(crate) fn stop_library_work() -> anyhow::Result<()> {
+    ensure!(
+        jobs::cancel_library_jobs(JOB_TIMEOUT),
+        "background jobs are still winding down; try again shortly"
+    );
+    watcher::stop();
+    sources::forget_all();
+    drop_folder::stop();
+    Ok(())
+}
@@ -0,0 +1,129 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+use image::ImageFormat;
+use lofty::picture::PictureType;
+use lofty::prelude::*;
+
+use crate::{metadata, sources};
+
+/// Longest edge of cached thumbnails, in pixels
+const THUMBNAIL_SIZE: u32 = 512;
+
+/// Image files checked for in a song's directory when it has no embedded art
+const FOLDER_ART_NAMES: &[&str] = &[
+    "cover.jpg",
+    "cover.jpeg",
+    "cover.png",
+    "folder.jpg",
+    "folder.jpeg",
+    "folder.png",
+    "front.jpg",
+    "front.png",
+];
+
+/// Thumbnail of the cover art for the song at `song_path`, cached under `cache_dir`
+///
+/// Embedded front covers win over other embedded pictures, which win over a cover
+/// image next to the file. A cached thumbnail is reused until the song file changes.
+pub(crate) fn album_art(song_path: &Path, cache_dir: &Path) -> anyhow::Result<Option<Vec<u8>>> {
+    let cached = cache_path(cache_dir, song_path);
+    if is_fresh(&cached, song_path) {
+        if let Ok(bytes) = fs::read(&cached) {
+            return Ok(Some(bytes));
+        }
+    }
+
+    let Some(original) = embedded_art(song_path)?.or_else(|| folder_art(song_path)) else {
+        return Ok(None);
+    };
+    let thumbnail = match thumbnail(&original) {
+        Ok(thumbnail) => thumbnail,
+        Err(e) => {
+            log::warn!("failed to resize art for {}: {e}", song_path.display());
+            return Ok(Some(original));
+        }
+    };
+    if let Err(e) = fs::create_dir_all(cache_dir).and_then(|_| fs::write(&cached, &thumbnail)) {
+        log::warn!("failed to cache art at {}: {e}", cached.display());
+    }
+    Ok(Some(thumbnail))
+}
+
+/// Where the thumbnail for `song_path` is cached, making it first if needed, for
+/// consumers that take art by file; `None` if the song has no art
+pub(crate) fn album_art_file(
+    song_path: &Path,
+    cache_dir: &Path,
+) -> anyhow::Result<Option<PathBuf>> {
+    let cached = cache_path(cache_dir, song_path);
+    if is_fresh(&cached, song_path) {
+        return Ok(Some(cached));
+    }
+    let art = album_art(song_path, cache_dir)?;
+    Ok(art.filter(|_| cached.exists()).map(|_| cached))
+}
+
+/// Where the thumbnail for `song_path` is cached, whether or not it has been made
+pub(crate) fn cache_path(cache_dir: &Path, song_path: &Path) -> PathBuf {
+    let mut hasher = DefaultHasher::new();
+    song_path.hash(&mut hasher);
+    cache_dir.join(format!("{:016x}.jpg", hasher.finish()))
+}
+
+/// Whether the thumbnail at `cached` is newer than the song it was made from
+fn is_fresh(cached: &Path, song_path: &Path) -> bool {
+    let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+    match (modified(cached), modified(song_path)) {
+        (Some(cached), Some(song)) => cached >= song,
+        // Remote files have no local time to compare against.
+        (Some(_), None) => sources::is_remote(song_path),
+        _ => false,
+    }
+}
+
+fn embedded_art(song_path: &Path) -> anyhow::Result<Option<Vec<u8>>> {
+    let tagged_file = metadata::read_tagged(song_path)?;
+    let Some(tag) = tagged_file
+        .primary_tag()
+        .or_else(|| tagged_file.first_tag())
+    else {
+        return Ok(None);
+    };
+    let picture = tag
+        .get_picture_type(PictureType::CoverFront)
+        .or_else(|| tag.pictures().first());
+    Ok(picture.map(|p| p.data().to_vec()))
+}
+
+fn folder_art(song_path: &Path) -> Option<Vec<u8>> {
+    let entries = fs::read_dir(song_path.parent()?).ok()?;
+    let mut candidates: Vec<(usize, PathBuf)> = entries
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            let name = path.file_name()?.to_str()?.to_ascii_lowercase();
+            let rank = FOLDER_ART_NAMES.iter().position(|&n| n == name)?;
+            Some((rank, path))
+        })
+        .collect();
+    candidates.sort();
+    candidates
+        .into_iter()
+        .find_map(|(_, path)| fs::read(path).ok())
+}
+
+/// Scale an encoded image down to `THUMBNAIL_SIZE` and re-encode it as JPEG
+fn thumbnail(original: &[u8]) -> image::ImageResult<Vec<u8>> {
+    let image = image::load_from_memory(original)?;
+    let image = if image.width().max(image.height()) > THUMBNAIL_SIZE {
+        image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)
+    } else {
+        image
+    };
+    let mut out = Cursor::new(Vec::new());
+    image.into_rgb8().write_to(&mut out, ImageFormat::Jpeg)?;
+    Ok(out.into_inner())
+}
@@ -0,0 +1,234 @@
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::sync::mpsc::Receiver;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use walkdir::WalkDir;
+
+use tunes4r::{AudioEngine, AudioEvent, EngineConfig, LogLevel, PlaybackState, Song, StreamSink};
+
+const USAGE: &str = "\
+usage: tunes4r-cli [--library DB] [--verbose] [PATH]...
+
+Plays the files and folders given, then takes one command per line on stdin, either
+as words (`seek 30`) or as JSON (`{\"command\": \"seek\", \"args\": [\"30\"]}`). Each
+command is answered, and each engine event reported, as one JSON object per line on
+stdout. At the end of stdin it waits for playback to finish.
+
+commands:
+  play PATH       play a file, or every file below a folder, instead of the queue
+  queue PATH      add a file, or every file below a folder, to the queue
+  queue           list the queue
+  pause | resume | stop | next | previous
+  seek SECS       jump to a position in the current song
+  volume LEVEL    set the volume, from 0 to 1
+  status          playback state, song, position and queue
+  devices         list output devices
+  device [ID]     play through the device with ID, or the default without one
+  help            show this
+  quit            stop and exit";
+
+/// How often the end of stdin checks whether playback has finished
+const FINISH_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How long the end of stdin waits for playback to start before giving up on it, as
+/// when the output or the first file fails
+const START_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A command sent as JSON
+#[derive(Deserialize)]
+struct Request {
+    command: String,
+    #[serde(default)]
+    args: Vec<String>,
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("tunes4r-cli: {e:#}");
+        std::process::exit(1);
+    }
+}
+
+fn run() -> anyhow::Result<()> {
+    let mut library = None;
+    let mut paths = Vec::new();
+    let mut level = LogLevel::Warn;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--library" => library = Some(args.next().context("--library needs a path")?),
+            "--verbose" => level = LogLevel::Debug,
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return Ok(());
+            }
+            flag if flag.starts_with("--") => bail!("unknown option {flag}\n\n{USAGE}"),
+            _ => paths.push(arg),
+        }
+    }
+
+    let config = EngineConfig {
+        library_path: library,
+        log_level: Some(level),
+        ..EngineConfig::default()
+    };
+    tunes4r::init_app(config.clone())?;
+    let engine = tunes4r::create_audio_engine(config)?;
+    let (sink, events) = StreamSink::channel();
+    engine.audio_event_stream(sink);
+    thread::Builder::new()
+        .name("tunes4r-cli-events".into())
+        .spawn(move || report_events(events))?;
+
+    if !paths.is_empty() {
+        for path in &paths {
+            queue_path(&engine, path)?;
+        }
+        engine.play_queue_index(0);
+    }
+
+    for line in io::stdin().lock().lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (command, args) = match parse(line) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                print(json!({ "ok": false, "error": format!("{e:#}") }));
+                continue;
+            }
+        };
+        if command == "quit" {
+            engine.stop();
+            return Ok(());
+        }
+        match execute(&engine, &command, &args) {
+            Ok(result) => print(json!({ "ok": true, "command": command, "result": result })),
+            Err(e) => print(json!({ "ok": false, "command": command, "error": format!("{e:#}") })),
+        }
+    }
+
+    // Scripted runs like `tunes4r-cli album/ < /dev/null` play to the end, though
+    // the engine may not have started on the queue yet.
+    if engine.get_queue().is_empty() {
+        return Ok(());
+    }
+    let waiting = Instant::now();
+    let mut started = false;
+    loop {
+        let stopped = matches!(engine.get_engine_state().state, PlaybackState::Stopped);
+        if stopped && (started || waiting.elapsed() >= START_TIMEOUT) {
+            return Ok(());
+        }
+        started |= !stopped;
+        thread::sleep(FINISH_POLL_INTERVAL);
+    }
+}
+
+/// Command name and arguments of a line of words or a JSON request
+fn parse(line: &str) -> anyhow::Result<(String, Vec<String>)> {
+    if line.starts_with('{') {
+        let request: Request = serde_json::from_str(line).context("invalid JSON command")?;
+        return Ok((request.command, request.args));
+    }
+    let (command, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let rest = rest.trim();
+    // Paths keep their spaces, so everything after the command is one argument.
+    let args = match rest.is_empty() {
+        true => Vec::new(),
+        false => vec![rest.to_string()],
+    };
+    Ok((command.to_string(), args))
+}
+
+fn execute(engine: &AudioEngine, command: &str, args: &[String]) -> anyhow::Result<Value> {
+    let arg = |name: &str| {
+        args.first()
+            .map(String::as_str)
+            .with_context(|| format!("{command} needs {name}"))
+    };
+    match command {
+        "play" => {
+            engine.queue_clear();
+            let added = queue_path(engine, arg("a path")?)?;
+            engine.play_queue_index(0);
+            Ok(json!(added))
+        }
+        "queue" if args.is_empty() => Ok(json!(engine.get_queue())),
+        "queue" => Ok(json!(queue_path(engine, arg("a path")?)?)),
+        "pause" => Ok(json!(engine.pause())),
+        "resume" => Ok(json!(engine.resume())),
+        "stop" => Ok(json!(engine.stop())),
+        "next" => Ok(json!(engine.skip_next())),
+        "previous" => Ok(json!(engine.skip_previous())),
+        "seek" => {
+            let secs: f64 = arg("a position in seconds")?
+                .parse()
+                .context("invalid position")?;
+            Ok(json!(engine.seek_to(secs)))
+        }
+        "volume" => {
+            let volume: f32 = arg("a level")?.parse().context("invalid volume")?;
+            engine.set_volume(volume.clamp(0.0, 1.0));
+            Ok(json!(engine.get_volume()))
+        }
+        "status" => Ok(json!(engine.get_engine_state())),
+        "devices" => Ok(json!(tunes4r::list_output_devices()?)),
+        "device" => Ok(json!(engine.set_output_device(args.first().cloned())?)),
+        "help" => Ok(json!(USAGE)),
+        _ => bail!("unknown command {command:?}; try help"),
+    }
+}
+
+/// Add the file at `path`, or every readable file below it, to the queue in file name
+/// order; returns how many were added
+fn queue_path(engine: &AudioEngine, path: &str) -> anyhow::Result<usize> {
+    if !Path::new(path).is_dir() {
+        engine.queue_add(tunes4r::read_song_metadata(path.to_string())?);
+        return Ok(1);
+    }
+    let songs: Vec<Song> = WalkDir::new(path)
+        .follow_links(true)
+        .sort_by_file_name()
+        .into_iter()
+        .flatten()
+        .filter(|entry| entry.file_type().is_file())
+        // Covers, playlists and the like don't read as songs.
+        .filter_map(|entry| {
+            tunes4r::read_song_metadata(entry.path().to_string_lossy().into_owned()).ok()
+        })
+        .collect();
+    anyhow::ensure!(!songs.is_empty(), "no playable files in {path}");
+    let added = songs.len();
+    songs.into_iter().for_each(|song| engine.queue_add(song));
+    Ok(added)
+}
+
+/// Print engine events as they come, leaving out the visualizer's many per second
+fn report_events(events: Receiver<AudioEvent>) {
+    for event in events {
+        if matches!(
+            event,
+            AudioEvent::SpectrumDataUpdated { .. }
+                | AudioEvent::BeatDetected { .. }
+                | AudioEvent::WaveformFrame { .. }
+                | AudioEvent::ProgressUpdated { .. }
+        ) {
+            continue;
+        }
+        print(json!({ "event": event }));
+    }
+}
+
+fn print(value: Value) {
+    let mut stdout = io::stdout().lock();
+    let _ = writeln!(stdout, "{value}");
+    let _ = stdout.flush();
+}
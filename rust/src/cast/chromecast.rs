@@ -0,0 +1,586 @@
+use std::io::{ErrorKind, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{self, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, ClientConnection, DigitallySignedStruct, SignatureScheme, StreamOwned};
+use serde_json::{json, Value};
+
+use super::{mdns, CastTarget, Position, TransportState};
+use crate::{CastDevice, CastProtocol, Song};
+
+/// DNS-SD service Cast devices announce themselves under
+const SERVICE: &str = "_googlecast._tcp.local";
+
+/// Google's receiver app for plain media URLs
+const DEFAULT_MEDIA_RECEIVER: &str = "CC1AD845";
+
+const NAMESPACE_CONNECTION: &str = "urn:x-cast:com.google.cast.tp.connection";
+const NAMESPACE_HEARTBEAT: &str = "urn:x-cast:com.google.cast.tp.heartbeat";
+const NAMESPACE_RECEIVER: &str = "urn:x-cast:com.google.cast.receiver";
+const NAMESPACE_MEDIA: &str = "urn:x-cast:com.google.cast.media";
+
+const SENDER: &str = "sender-0";
+const RECEIVER: &str = "receiver-0";
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a request may take, launching the receiver app included
+const REPLY_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Devices drop connections that have been quiet for longer than a few of these
+const PING_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long a socket read waits, so heartbeats and deadlines are kept while idle
+const READ_SLICE: Duration = Duration::from_millis(200);
+
+/// Largest message accepted, well above any status a device sends
+const MAX_MESSAGE_BYTES: usize = 64 * 1024;
+
+/// Cast devices announcing themselves over mDNS within `timeout`
+pub(super) fn discover(timeout: Duration) -> anyhow::Result<Vec<CastDevice>> {
+    let mut devices: Vec<CastDevice> = Vec::new();
+    for service in mdns::browse(SERVICE, timeout)? {
+        let location = SocketAddr::new(service.ip, service.port).to_string();
+        let txt = |key: &str| service.txt.get(key).filter(|v| !v.is_empty()).cloned();
+        let id = txt("id").unwrap_or_else(|| location.clone());
+        if devices.iter().any(|device| device.id == id) {
+            continue;
+        }
+        devices.push(CastDevice {
+            name: txt("fn").unwrap_or_else(|| location.clone()),
+            id,
+            model: txt("md"),
+            protocol: CastProtocol::Chromecast,
+            location,
+        });
+    }
+    Ok(devices)
+}
+
+/// Accepts whatever certificate a device presents
+///
+/// Cast devices sign theirs with Google's own device CA rather than a web one; the
+/// connection only carries playback commands on the local network.
+#[derive(Debug)]
+struct AnyCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// A `CastMessage` of the CASTV2 protocol, with a string payload
+struct Message {
+    source: String,
+    namespace: String,
+    payload: String,
+}
+
+fn put_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn put_string(out: &mut Vec<u8>, field: u64, text: &str) {
+    put_varint(out, field << 3 | 2);
+    put_varint(out, text.len() as u64);
+    out.extend_from_slice(text.as_bytes());
+}
+
+fn read_varint(bytes: &mut &[u8]) -> Option<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = bytes.split_first()?;
+        *bytes = rest;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+impl Message {
+    /// The protobuf encoding, after its big-endian length as the wire wants it
+    fn encode(namespace: &str, destination: &str, payload: &str) -> Vec<u8> {
+        let mut body = Vec::new();
+        // protocol_version CASTV2_1_0
+        put_varint(&mut body, 1 << 3);
+        put_varint(&mut body, 0);
+        put_string(&mut body, 2, SENDER);
+        put_string(&mut body, 3, destination);
+        put_string(&mut body, 4, namespace);
+        // payload_type STRING
+        put_varint(&mut body, 5 << 3);
+        put_varint(&mut body, 0);
+        put_string(&mut body, 6, payload);
+        let mut framed = (body.len() as u32).to_be_bytes().to_vec();
+        framed.extend(body);
+        framed
+    }
+
+    fn decode(mut bytes: &[u8]) -> Option<Self> {
+        let mut message = Message {
+            source: String::new(),
+            namespace: String::new(),
+            payload: String::new(),
+        };
+        while !bytes.is_empty() {
+            let key = read_varint(&mut bytes)?;
+            match key & 7 {
+                0 => {
+                    read_varint(&mut bytes)?;
+                }
+                2 => {
+                    let len = read_varint(&mut bytes)? as usize;
+                    let value = bytes.get(..len)?;
+                    bytes = &bytes[len..];
+                    let text = || String::from_utf8_lossy(value).into_owned();
+                    match key >> 3 {
+                        2 => message.source = text(),
+                        4 => message.namespace = text(),
+                        6 => message.payload = text(),
+                        _ => {}
+                    }
+                }
+                1 => bytes = bytes.get(8..)?,
+                5 => bytes = bytes.get(4..)?,
+                _ => return None,
+            }
+        }
+        Some(message)
+    }
+}
+
+/// What the media receiver last said it was doing
+#[derive(Default)]
+struct MediaStatus {
+    session: Option<u64>,
+    state: Option<TransportState>,
+    position: f64,
+    duration: Option<f64>,
+    /// When `position` was reported, as the device doesn't report it continuously
+    at: Option<Instant>,
+}
+
+/// A Chromecast or other Google Cast device, playing through the default media receiver
+pub(super) struct Chromecast {
+    device: CastDevice,
+    stream: StreamOwned<ClientConnection, TcpStream>,
+    /// Bytes received that don't make up a whole message yet
+    inbox: Vec<u8>,
+    next_request: u64,
+    last_ping: Instant,
+    /// Session and transport ids of the media receiver, once running
+    app: Option<(String, String)>,
+    /// Transport of the media receiver this sender is connected to
+    connected: Option<String>,
+    media: MediaStatus,
+    volume: Option<(f32, bool)>,
+}
+
+impl Chromecast {
+    pub fn connect(device: &CastDevice) -> anyhow::Result<Self> {
+        let addr = device
+            .location
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+            .with_context(|| format!("invalid Cast device address {}", device.location))?;
+        let tcp = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)
+            .with_context(|| format!("failed to connect to {}", device.name))?;
+        tcp.set_read_timeout(Some(READ_SLICE))?;
+        tcp.set_write_timeout(Some(CONNECT_TIMEOUT))?;
+        tcp.set_nodelay(true)?;
+
+        let provider = Arc::new(crypto::ring::default_provider());
+        let config = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(AnyCertificate(provider)))
+            .with_no_client_auth();
+        let connection = ClientConnection::new(Arc::new(config), ServerName::from(addr.ip()))?;
+        let mut chromecast = Chromecast {
+            device: device.clone(),
+            stream: StreamOwned::new(connection, tcp),
+            inbox: Vec::new(),
+            next_request: 1,
+            last_ping: Instant::now(),
+            app: None,
+            connected: None,
+            media: MediaStatus::default(),
+            volume: None,
+        };
+        chromecast.send(
+            NAMESPACE_CONNECTION,
+            RECEIVER,
+            &json!({ "type": "CONNECT" }),
+        )?;
+        let status = chromecast.request(
+            NAMESPACE_RECEIVER,
+            RECEIVER,
+            json!({ "type": "GET_STATUS" }),
+        )?;
+        chromecast.read_receiver_status(&status);
+        Ok(chromecast)
+    }
+
+    fn send(&mut self, namespace: &str, destination: &str, payload: &Value) -> anyhow::Result<()> {
+        let message = Message::encode(namespace, destination, &payload.to_string());
+        self.stream
+            .write_all(&message)
+            .and_then(|()| self.stream.flush())
+            .with_context(|| format!("lost the connection to {}", self.device.name))
+    }
+
+    /// Send a request and wait for the reply carrying its id
+    fn request(
+        &mut self,
+        namespace: &str,
+        destination: &str,
+        mut payload: Value,
+    ) -> anyhow::Result<Value> {
+        let id = self.next_request;
+        self.next_request += 1;
+        payload["requestId"] = id.into();
+        self.send(namespace, destination, &payload)?;
+        let deadline = Instant::now() + REPLY_TIMEOUT;
+        loop {
+            if Instant::now() >= deadline {
+                bail!("{} didn't answer {}", self.device.name, payload["type"]);
+            }
+            let Some(reply) = self.receive()? else {
+                continue;
+            };
+            if reply["requestId"].as_u64() != Some(id) {
+                continue;
+            }
+            match reply["type"].as_str().unwrap_or("") {
+                "LOAD_FAILED"
+                | "LOAD_CANCELLED"
+                | "INVALID_REQUEST"
+                | "INVALID_PLAYER_STATE"
+                | "LAUNCH_ERROR" => {
+                    let reason = match reply["reason"].as_str() {
+                        Some(reason) => format!("{}: {reason}", reply["type"]),
+                        None => reply["type"].to_string(),
+                    };
+                    bail!("{} refused {}: {reason}", self.device.name, payload["type"]);
+                }
+                _ => return Ok(reply),
+            }
+        }
+    }
+
+    /// The next message, if one arrives within a read slice, with heartbeats and
+    /// status updates seen to
+    fn receive(&mut self) -> anyhow::Result<Option<Value>> {
+        if self.last_ping.elapsed() >= PING_INTERVAL {
+            self.last_ping = Instant::now();
+            self.send(NAMESPACE_HEARTBEAT, RECEIVER, &json!({ "type": "PING" }))?;
+        }
+        let message = loop {
+            if let Some(message) = self.take_message()? {
+                break message;
+            }
+            let mut buf = [0; 4096];
+            match self.stream.read(&mut buf) {
+                Ok(0) => bail!("{} closed the connection", self.device.name),
+                Ok(len) => self.inbox.extend_from_slice(&buf[..len]),
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    return Ok(None)
+                }
+                Err(e) => {
+                    return Err(e)
+                        .with_context(|| format!("lost the connection to {}", self.device.name))
+                }
+            }
+        };
+        let Ok(payload) = serde_json::from_str::<Value>(&message.payload) else {
+            return Ok(None);
+        };
+        match (message.namespace.as_str(), payload["type"].as_str()) {
+            (NAMESPACE_HEARTBEAT, Some("PING")) => {
+                self.send(
+                    NAMESPACE_HEARTBEAT,
+                    &message.source,
+                    &json!({ "type": "PONG" }),
+                )?;
+            }
+            (NAMESPACE_CONNECTION, Some("CLOSE")) => {
+                if message.source == RECEIVER {
+                    bail!("{} closed the connection", self.device.name);
+                }
+                // Another sender took over the device, or the receiver app quit.
+                self.connected = None;
+                self.media = MediaStatus::default();
+            }
+            (NAMESPACE_RECEIVER, Some("RECEIVER_STATUS")) => self.read_receiver_status(&payload),
+            (NAMESPACE_MEDIA, Some("MEDIA_STATUS")) => self.read_media_status(&payload),
+            _ => {}
+        }
+        Ok(Some(payload))
+    }
+
+    /// A whole message from the start of `inbox`, if one has arrived
+    fn take_message(&mut self) -> anyhow::Result<Option<Message>> {
+        let Some(len) = self.inbox.get(..4) else {
+            return Ok(None);
+        };
+        let len = u32::from_be_bytes(len.try_into()?) as usize;
+        anyhow::ensure!(
+            len <= MAX_MESSAGE_BYTES,
+            "{} sent an oversized message",
+            self.device.name
+        );
+        if self.inbox.len() < 4 + len {
+            return Ok(None);
+        }
+        let message = Message::decode(&self.inbox[4..4 + len]);
+        self.inbox.drain(..4 + len);
+        Ok(message)
+    }
+
+    fn read_receiver_status(&mut self, payload: &Value) {
+        let status = &payload["status"];
+        if let Some(level) = status["volume"]["level"].as_f64() {
+            let muted = status["volume"]["muted"].as_bool().unwrap_or(false);
+            self.volume = Some((level as f32, muted));
+        }
+        let app = status["applications"].as_array().and_then(|apps| {
+            apps.iter()
+                .find(|app| app["appId"] == DEFAULT_MEDIA_RECEIVER)
+        });
+        let app = app.and_then(|app| {
+            Some((
+                app["sessionId"].as_str()?.to_string(),
+                app["transportId"].as_str()?.to_string(),
+            ))
+        });
+        if !status["applications"].is_array() {
+            return;
+        }
+        if app.is_none() {
+            self.connected = None;
+            self.media = MediaStatus::default();
+        }
+        self.app = app;
+    }
+
+    fn read_media_status(&mut self, payload: &Value) {
+        let Some(status) = payload["status"].as_array() else {
+            return;
+        };
+        let Some(status) = status.first() else {
+            // The media session is over.
+            self.media.session = None;
+            self.media.state = Some(TransportState::Stopped);
+            return;
+        };
+        self.media.session = status["mediaSessionId"].as_u64().or(self.media.session);
+        self.media.state = match status["playerState"].as_str() {
+            Some("PLAYING") => Some(TransportState::Playing),
+            Some("PAUSED") => Some(TransportState::Paused),
+            Some("BUFFERING") => Some(TransportState::Buffering),
+            Some("IDLE") => Some(TransportState::Stopped),
+            _ => self.media.state,
+        };
+        if let Some(position) = status["currentTime"].as_f64() {
+            self.media.position = position;
+            self.media.at = Some(Instant::now());
+        }
+        if let Some(duration) = status["media"]["duration"].as_f64() {
+            self.media.duration = Some(duration).filter(|d| *d > 0.0);
+        }
+    }
+
+    /// Transport id of the media receiver, launching and connecting to it first if needed
+    fn media_receiver(&mut self) -> anyhow::Result<String> {
+        if self.app.is_none() {
+            let launch = json!({ "type": "LAUNCH", "appId": DEFAULT_MEDIA_RECEIVER });
+            let status = self.request(NAMESPACE_RECEIVER, RECEIVER, launch)?;
+            self.read_receiver_status(&status);
+        }
+        let (_, transport) = self
+            .app
+            .clone()
+            .with_context(|| format!("{} didn't start its media receiver", self.device.name))?;
+        if self.connected.as_ref() != Some(&transport) {
+            self.send(
+                NAMESPACE_CONNECTION,
+                &transport,
+                &json!({ "type": "CONNECT" }),
+            )?;
+            self.connected = Some(transport.clone());
+        }
+        Ok(transport)
+    }
+
+    /// Send a command about the loaded media; nothing happens without any
+    fn media_command(&mut self, kind: &str, extra: Value) -> anyhow::Result<()> {
+        let (Some(transport), Some(session)) = (self.connected.clone(), self.media.session) else {
+            return Ok(());
+        };
+        let mut payload = json!({ "type": kind, "mediaSessionId": session });
+        if let (Value::Object(payload), Value::Object(extra)) = (&mut payload, extra) {
+            payload.extend(extra);
+        }
+        let reply = self.request(NAMESPACE_MEDIA, &transport, payload)?;
+        self.read_media_status(&reply);
+        Ok(())
+    }
+}
+
+impl CastTarget for Chromecast {
+    fn device(&self) -> &CastDevice {
+        &self.device
+    }
+
+    fn local_ip(&self) -> anyhow::Result<IpAddr> {
+        Ok(self.stream.sock.local_addr()?.ip())
+    }
+
+    fn load(
+        &mut self,
+        url: &str,
+        mime: &str,
+        song: &Song,
+        start_at: f64,
+        paused: bool,
+    ) -> anyhow::Result<()> {
+        let transport = self.media_receiver()?;
+        // Streams without a known length can't be sought in.
+        let stream_type = match song.duration {
+            0 => "LIVE",
+            _ => "BUFFERED",
+        };
+        let load = json!({
+            "type": "LOAD",
+            "media": {
+                "contentId": url,
+                "contentType": mime,
+                "streamType": stream_type,
+                "metadata": {
+                    "metadataType": 3,
+                    "title": song.title,
+                    "artist": song.artist,
+                    "albumName": song.album,
+                },
+            },
+            "autoplay": !paused,
+            "currentTime": start_at,
+        });
+        self.media = MediaStatus::default();
+        let reply = self.request(NAMESPACE_MEDIA, &transport, load)?;
+        self.read_media_status(&reply);
+        Ok(())
+    }
+
+    fn play(&mut self) -> anyhow::Result<()> {
+        self.media_command("PLAY", json!({}))
+    }
+
+    fn pause(&mut self) -> anyhow::Result<()> {
+        self.media_command("PAUSE", json!({}))
+    }
+
+    fn stop(&mut self) -> anyhow::Result<()> {
+        self.media_command("STOP", json!({}))
+    }
+
+    fn seek(&mut self, position_secs: f64) -> anyhow::Result<()> {
+        self.media_command("SEEK", json!({ "currentTime": position_secs.max(0.0) }))
+    }
+
+    fn set_volume(&mut self, volume: f32) -> anyhow::Result<()> {
+        let payload =
+            json!({ "type": "SET_VOLUME", "volume": { "level": volume.clamp(0.0, 1.0) } });
+        let status = self.request(NAMESPACE_RECEIVER, RECEIVER, payload)?;
+        self.read_receiver_status(&status);
+        Ok(())
+    }
+
+    fn position(&mut self) -> anyhow::Result<Position> {
+        if let Some(transport) = self.connected.clone() {
+            let reply =
+                self.request(NAMESPACE_MEDIA, &transport, json!({ "type": "GET_STATUS" }))?;
+            self.read_media_status(&reply);
+        }
+        let media = &self.media;
+        let state = media.state.unwrap_or(TransportState::Buffering);
+        let elapsed = match (state, media.at) {
+            (TransportState::Playing, Some(at)) => at.elapsed().as_secs_f64(),
+            _ => 0.0,
+        };
+        Ok(Position {
+            state,
+            position: media.position + elapsed,
+            duration: media.duration,
+            volume: self.volume,
+        })
+    }
+
+    fn keep_alive(&mut self) -> anyhow::Result<()> {
+        while self.receive()?.is_some() {}
+        Ok(())
+    }
+}
+
+impl Drop for Chromecast {
+    fn drop(&mut self) {
+        // Quit the receiver app, so the device goes back to its idle screen.
+        if let Some((session, _)) = self.app.take() {
+            let stop = json!({ "type": "STOP", "sessionId": session, "requestId": 0 });
+            let _ = self.send(NAMESPACE_RECEIVER, RECEIVER, &stop);
+        }
+        let _ = self.send(NAMESPACE_CONNECTION, RECEIVER, &json!({ "type": "CLOSE" }));
+    }
+}
@@ -0,0 +1,391 @@
+use std::collections::HashSet;
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context};
+use quick_xml::escape::escape;
+use url::Url;
+
+use super::{CastTarget, Position, TransportState};
+use crate::{sources, CastDevice, CastProtocol, Song};
+
+/// Where SSDP searches are sent
+const SSDP_ADDRESS: &str = "239.255.255.250:1900";
+
+/// Devices answering for this type can play what they're sent
+const MEDIA_RENDERER: &str = "urn:schemas-upnp-org:device:MediaRenderer:1";
+
+const AV_TRANSPORT: &str = "urn:schemas-upnp-org:service:AVTransport:1";
+const RENDERING_CONTROL: &str = "urn:schemas-upnp-org:service:RenderingControl:1";
+
+/// Searches sent per discovery, since UDP multicast drops packets
+const SEARCHES: u32 = 2;
+
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn agent() -> ureq::Agent {
+    ureq::AgentBuilder::new()
+        .timeout_connect(HTTP_TIMEOUT)
+        .timeout_read(HTTP_TIMEOUT)
+        .build()
+}
+
+/// DLNA renderers answering an SSDP search on the local network within `timeout`
+pub(super) fn discover(timeout: Duration) -> anyhow::Result<Vec<CastDevice>> {
+    let socket = UdpSocket::bind("0.0.0.0:0").context("failed to open an SSDP socket")?;
+    let search = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {SSDP_ADDRESS}\r\nMAN: \"ssdp:discover\"\r\nMX: {}\r\nST: {MEDIA_RENDERER}\r\n\r\n",
+        timeout.as_secs().clamp(1, 5)
+    );
+    for _ in 0..SEARCHES {
+        socket
+            .send_to(search.as_bytes(), SSDP_ADDRESS)
+            .context("failed to send an SSDP search")?;
+    }
+
+    let deadline = Instant::now() + timeout;
+    let mut locations = Vec::new();
+    let mut buf = [0; 2048];
+    while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+        socket.set_read_timeout(Some(left.max(Duration::from_millis(1))))?;
+        let len = match socket.recv_from(&mut buf) {
+            Ok((len, _)) => len,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => break,
+            Err(e) => return Err(e).context("failed to read SSDP answers"),
+        };
+        let answer = String::from_utf8_lossy(&buf[..len]);
+        let location = answer.lines().find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.trim()
+                .eq_ignore_ascii_case("location")
+                .then(|| value.trim().to_string())
+        });
+        if let Some(location) = location.filter(|l| !locations.contains(l)) {
+            locations.push(location);
+        }
+    }
+
+    let agent = agent();
+    let mut seen = HashSet::new();
+    let mut devices = Vec::new();
+    for location in locations {
+        match Description::fetch(&agent, &location) {
+            Ok(description) if seen.insert(description.device.id.clone()) => {
+                devices.push(description.device)
+            }
+            Ok(_) => {}
+            Err(e) => log::warn!("skipping renderer at {location}: {e:#}"),
+        }
+    }
+    Ok(devices)
+}
+
+/// What a renderer's device description says about it
+struct Description {
+    device: CastDevice,
+    av_transport: Option<Url>,
+    rendering_control: Option<Url>,
+}
+
+impl Description {
+    fn fetch(agent: &ureq::Agent, location: &str) -> anyhow::Result<Self> {
+        let xml = agent
+            .get(location)
+            .call()
+            .with_context(|| format!("failed to fetch {location}"))?
+            .into_string()?;
+        let mut base = Url::parse(location).with_context(|| format!("invalid URL {location}"))?;
+        let (mut id, mut name, mut model) = (None, None, None);
+        let mut services = Vec::new();
+        let (mut service_type, mut control_url) = (None, None);
+        sources::parse_xml(&xml, |open, text| match open {
+            [.., tag] if tag == "URLBase" => {
+                if let Ok(url) = Url::parse(text) {
+                    base = url;
+                }
+            }
+            // Embedded devices come after the root device's own fields.
+            [.., parent, tag] if parent == "device" && tag == "UDN" => {
+                id.get_or_insert_with(|| text.to_string());
+            }
+            [.., parent, tag] if parent == "device" && tag == "friendlyName" => {
+                name.get_or_insert_with(|| text.to_string());
+            }
+            [.., parent, tag] if parent == "device" && tag == "modelName" => {
+                model.get_or_insert_with(|| text.to_string());
+            }
+            [.., parent, tag] if parent == "service" && tag == "serviceType" => {
+                service_type = Some(text.to_string())
+            }
+            [.., parent, tag] if parent == "service" && tag == "controlURL" => {
+                control_url = Some(text.to_string())
+            }
+            [.., tag] if tag == "service" => {
+                if let (Some(kind), Some(url)) = (service_type.take(), control_url.take()) {
+                    services.push((kind, url));
+                }
+            }
+            _ => {}
+        })?;
+        // Any version of a service will do for the actions used.
+        let control = |kind: &str| {
+            let unversioned = |k: &str| k.rsplit_once(':').map_or(k, |(k, _)| k).to_string();
+            let (_, url) = services
+                .iter()
+                .find(|(k, _)| unversioned(k) == unversioned(kind))?;
+            base.join(url).ok()
+        };
+        let id = id.with_context(|| format!("{location} describes no device"))?;
+        Ok(Description {
+            av_transport: control(AV_TRANSPORT),
+            rendering_control: control(RENDERING_CONTROL),
+            device: CastDevice {
+                name: name.unwrap_or_else(|| id.clone()),
+                id,
+                model: model.filter(|m| !m.is_empty()),
+                protocol: CastProtocol::Dlna,
+                location: location.to_string(),
+            },
+        })
+    }
+}
+
+/// A renderer being controlled over UPnP AVTransport and RenderingControl
+pub(super) struct Renderer {
+    agent: ureq::Agent,
+    device: CastDevice,
+    av_transport: Url,
+    rendering_control: Option<Url>,
+}
+
+impl Renderer {
+    pub fn connect(device: &CastDevice) -> anyhow::Result<Self> {
+        let agent = agent();
+        let description = Description::fetch(&agent, &device.location)?;
+        let av_transport = description
+            .av_transport
+            .with_context(|| format!("{} can't be sent media to", device.name))?;
+        Ok(Renderer {
+            agent,
+            device: description.device,
+            av_transport,
+            rendering_control: description.rendering_control,
+        })
+    }
+
+    /// Invoke `action` of `service` with `args`, returning the response's elements
+    fn call(
+        &self,
+        service: &str,
+        action: &str,
+        args: &[(&str, &str)],
+    ) -> anyhow::Result<Vec<(String, String)>> {
+        let url = match service {
+            AV_TRANSPORT => &self.av_transport,
+            _ => self
+                .rendering_control
+                .as_ref()
+                .with_context(|| format!("{} has no volume control", self.device.name))?,
+        };
+        let mut body = format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+             <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+             <s:Body><u:{action} xmlns:u=\"{service}\"><InstanceID>0</InstanceID>"
+        );
+        for (name, value) in args {
+            body.push_str(&format!("<{name}>{}</{name}>", escape(*value)));
+        }
+        body.push_str(&format!("</u:{action}></s:Body></s:Envelope>"));
+
+        let result = self
+            .agent
+            .post(url.as_str())
+            .set("Content-Type", "text/xml; charset=\"utf-8\"")
+            .set("SOAPACTION", &format!("\"{service}#{action}\""))
+            .send_string(&body);
+        let xml = match result {
+            Ok(response) => response.into_string()?,
+            Err(ureq::Error::Status(status, response)) => {
+                let xml = response.into_string().unwrap_or_default();
+                let mut fault = (None, None);
+                let _ = sources::parse_xml(&xml, |open, text| match open {
+                    [.., tag] if tag == "errorCode" => fault.0 = Some(text.to_string()),
+                    [.., tag] if tag == "errorDescription" => fault.1 = Some(text.to_string()),
+                    _ => {}
+                });
+                match fault {
+                    (Some(code), Some(description)) => {
+                        bail!(
+                            "{} refused {action}: {description} ({code})",
+                            self.device.name
+                        )
+                    }
+                    (Some(code), None) => {
+                        bail!("{} refused {action}: error {code}", self.device.name)
+                    }
+                    _ => bail!("{} refused {action}: HTTP {status}", self.device.name),
+                }
+            }
+            Err(e) => {
+                return Err(e).with_context(|| format!("failed to reach {}", self.device.name))
+            }
+        };
+        let mut values = Vec::new();
+        sources::parse_xml(&xml, |open, text| {
+            if let [.., parent, tag] = open {
+                if parent.as_str() == format!("{action}Response") {
+                    values.push((tag.clone(), text.to_string()));
+                }
+            }
+        })?;
+        Ok(values)
+    }
+}
+
+impl CastTarget for Renderer {
+    fn device(&self) -> &CastDevice {
+        &self.device
+    }
+
+    fn local_ip(&self) -> anyhow::Result<IpAddr> {
+        let host = self
+            .av_transport
+            .host_str()
+            .context("renderer has no host")?;
+        let port = self.av_transport.port_or_known_default().unwrap_or(80);
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        let addr = (host, port)
+            .to_socket_addrs()?
+            .find(SocketAddr::is_ipv4)
+            .with_context(|| format!("cannot resolve {host}"))?;
+        socket.connect(addr)?;
+        Ok(socket.local_addr()?.ip())
+    }
+
+    fn load(
+        &mut self,
+        url: &str,
+        mime: &str,
+        song: &Song,
+        start_at: f64,
+        paused: bool,
+    ) -> anyhow::Result<()> {
+        // Some renderers only take a new URI once stopped.
+        let _ = self.stop();
+        let metadata = didl_lite(url, mime, song);
+        self.call(
+            AV_TRANSPORT,
+            "SetAVTransportURI",
+            &[("CurrentURI", url), ("CurrentURIMetaData", &metadata)],
+        )?;
+        if !paused {
+            self.play()?;
+        }
+        if start_at > 0.0 {
+            if let Err(e) = self.seek(start_at) {
+                log::warn!("failed to start {} at {start_at}s: {e:#}", song.file_path);
+            }
+        }
+        Ok(())
+    }
+
+    fn play(&mut self) -> anyhow::Result<()> {
+        self.call(AV_TRANSPORT, "Play", &[("Speed", "1")])?;
+        Ok(())
+    }
+
+    fn pause(&mut self) -> anyhow::Result<()> {
+        self.call(AV_TRANSPORT, "Pause", &[])?;
+        Ok(())
+    }
+
+    fn stop(&mut self) -> anyhow::Result<()> {
+        self.call(AV_TRANSPORT, "Stop", &[])?;
+        Ok(())
+    }
+
+    fn seek(&mut self, position_secs: f64) -> anyhow::Result<()> {
+        let target = format_time(position_secs);
+        self.call(
+            AV_TRANSPORT,
+            "Seek",
+            &[("Unit", "REL_TIME"), ("Target", &target)],
+        )?;
+        Ok(())
+    }
+
+    fn set_volume(&mut self, volume: f32) -> anyhow::Result<()> {
+        let volume = ((volume.clamp(0.0, 1.0) * 100.0).round() as u32).to_string();
+        self.call(
+            RENDERING_CONTROL,
+            "SetVolume",
+            &[("Channel", "Master"), ("DesiredVolume", &volume)],
+        )?;
+        Ok(())
+    }
+
+    fn position(&mut self) -> anyhow::Result<Position> {
+        let transport = self.call(AV_TRANSPORT, "GetTransportInfo", &[])?;
+        let info = self.call(AV_TRANSPORT, "GetPositionInfo", &[])?;
+        let value = |values: &[(String, String)], name: &str| {
+            values
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| v.clone())
+        };
+        let state = match value(&transport, "CurrentTransportState").as_deref() {
+            Some("PLAYING") => TransportState::Playing,
+            Some("PAUSED_PLAYBACK") => TransportState::Paused,
+            Some("STOPPED" | "NO_MEDIA_PRESENT") => TransportState::Stopped,
+            _ => TransportState::Buffering,
+        };
+        Ok(Position {
+            state,
+            position: value(&info, "RelTime")
+                .and_then(|t| parse_time(&t))
+                .unwrap_or(0.0),
+            duration: value(&info, "TrackDuration")
+                .and_then(|t| parse_time(&t))
+                .filter(|d| *d > 0.0),
+            volume: None,
+        })
+    }
+}
+
+/// `H:MM:SS`, as AVTransport times are written
+fn format_time(secs: f64) -> String {
+    let secs = secs.max(0.0).round() as u64;
+    format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// Seconds of an AVTransport time, `H+:MM:SS[.F+]`; `None` for `NOT_IMPLEMENTED` and
+/// the like
+fn parse_time(time: &str) -> Option<f64> {
+    let mut secs = 0.0;
+    for part in time.trim().split(':') {
+        secs = secs * 60.0 + part.parse::<f64>().ok()?;
+    }
+    Some(secs)
+}
+
+/// DIDL-Lite metadata renderers show while they play `url`
+fn didl_lite(url: &str, mime: &str, song: &Song) -> String {
+    let duration = match song.duration {
+        0 => String::new(),
+        secs => format!(" duration=\"{}.000\"", format_time(secs as f64)),
+    };
+    format!(
+        "<DIDL-Lite xmlns=\"urn:schemas-upnp-org:metadata-1-0/DIDL-Lite/\" \
+         xmlns:dc=\"http://purl.org/dc/elements/1.1/\" \
+         xmlns:upnp=\"urn:schemas-upnp-org:metadata-1-0/upnp/\">\
+         <item id=\"0\" parentID=\"-1\" restricted=\"1\">\
+         <dc:title>{}</dc:title><upnp:artist>{}</upnp:artist><upnp:album>{}</upnp:album>\
+         <upnp:class>object.item.audioItem.musicTrack</upnp:class>\
+         <res protocolInfo=\"http-get:*:{mime}:*\"{duration}>{}</res></item></DIDL-Lite>",
+        escape(song.title.as_str()),
+        escape(song.artist.as_str()),
+        escape(song.album.as_str()),
+        escape(url),
+    )
+}
@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+
+/// Where mDNS queries are sent
+const MDNS_ADDRESS: &str = "224.0.0.251:5353";
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+
+/// Class IN with the bit asking for answers straight back to the querier
+const CLASS_IN_UNICAST: u16 = 0x8001;
+
+/// Queries sent per browse, since UDP multicast drops packets
+const QUERIES: u32 = 2;
+
+/// An instance of a DNS-SD service
+pub(super) struct Service {
+    pub ip: IpAddr,
+    pub port: u16,
+    /// `key=value` pairs of its TXT record
+    pub txt: HashMap<String, String>,
+}
+
+/// What the answers so far say about each instance
+#[derive(Default)]
+struct Answers {
+    instances: Vec<String>,
+    /// Instance -> (host, port)
+    srv: HashMap<String, (String, u16)>,
+    txt: HashMap<String, HashMap<String, String>>,
+    a: HashMap<String, Ipv4Addr>,
+}
+
+/// Instances of `service` (like `_googlecast._tcp.local`) answering within `timeout`
+///
+/// Queries come from a port of their own, which RFC 6762 responders answer directly
+/// rather than to the whole network.
+pub(super) fn browse(service: &str, timeout: Duration) -> anyhow::Result<Vec<Service>> {
+    let socket = UdpSocket::bind("0.0.0.0:0").context("failed to open an mDNS socket")?;
+    let query = query(service, TYPE_PTR);
+    for _ in 0..QUERIES {
+        socket
+            .send_to(&query, MDNS_ADDRESS)
+            .context("failed to send an mDNS query")?;
+    }
+
+    let deadline = Instant::now() + timeout;
+    let mut answers = Answers::default();
+    let mut buf = [0; 9000];
+    while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+        socket.set_read_timeout(Some(left.max(Duration::from_millis(1))))?;
+        let len = match socket.recv_from(&mut buf) {
+            Ok((len, _)) => len,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => break,
+            Err(e) => return Err(e).context("failed to read mDNS answers"),
+        };
+        if parse(&buf[..len], service, &mut answers).is_none() {
+            log::debug!("ignoring a malformed mDNS answer");
+        }
+    }
+
+    let services = answers
+        .instances
+        .iter()
+        .filter_map(|instance| {
+            let (host, port) = answers.srv.get(instance)?;
+            Some(Service {
+                ip: IpAddr::V4(*answers.a.get(host)?),
+                port: *port,
+                txt: answers.txt.get(instance).cloned().unwrap_or_default(),
+            })
+        })
+        .collect();
+    Ok(services)
+}
+
+/// A query for records of `kind` about `name`
+fn query(name: &str, kind: u16) -> Vec<u8> {
+    // Id, flags, one question and no other records
+    let mut packet = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    for label in name.split('.').filter(|l| !l.is_empty()) {
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+    packet.extend_from_slice(&kind.to_be_bytes());
+    packet.extend_from_slice(&CLASS_IN_UNICAST.to_be_bytes());
+    packet
+}
+
+fn read_u16(packet: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(
+        packet.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+/// The possibly compressed name at `offset`, lowercased, and the offset after it
+fn read_name(packet: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // Bounds the pointers followed, so a looping packet can't hang discovery.
+    for _ in 0..64 {
+        let len = *packet.get(offset)? as usize;
+        match len {
+            0 => {
+                let name = labels.join(".").to_ascii_lowercase();
+                return Some((name, end.unwrap_or(offset + 1)));
+            }
+            len if len & 0xc0 == 0xc0 => {
+                let pointer = read_u16(packet, offset)? as usize & 0x3fff;
+                end.get_or_insert(offset + 2);
+                offset = pointer;
+            }
+            len => {
+                let label = packet.get(offset + 1..offset + 1 + len)?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                offset += 1 + len;
+            }
+        }
+    }
+    None
+}
+
+/// Add the records of `packet` to `answers`
+fn parse(packet: &[u8], service: &str, answers: &mut Answers) -> Option<()> {
+    let questions = read_u16(packet, 4)?;
+    let records = [6, 8, 10]
+        .iter()
+        .map(|&offset| read_u16(packet, offset).map(usize::from))
+        .sum::<Option<usize>>()?;
+    let mut offset = 12;
+    for _ in 0..questions {
+        offset = read_name(packet, offset)?.1 + 4;
+    }
+    for _ in 0..records {
+        let (name, next) = read_name(packet, offset)?;
+        let kind = read_u16(packet, next)?;
+        let len = read_u16(packet, next + 8)? as usize;
+        let data = next + 10;
+        let rdata = packet.get(data..data + len)?;
+        offset = data + len;
+        match kind {
+            TYPE_PTR if name == service.to_ascii_lowercase() => {
+                let (instance, _) = read_name(packet, data)?;
+                if !answers.instances.contains(&instance) {
+                    answers.instances.push(instance);
+                }
+            }
+            TYPE_SRV => {
+                let port = read_u16(rdata, 4)?;
+                let (host, _) = read_name(packet, data + 6)?;
+                answers.srv.insert(name, (host, port));
+            }
+            TYPE_TXT => {
+                let mut entries = HashMap::new();
+                let mut rest = rdata;
+                while let Some((&len, tail)) = rest.split_first() {
+                    let entry = tail.get(..len as usize)?;
+                    let entry = String::from_utf8_lossy(entry);
+                    if let Some((key, value)) = entry.split_once('=') {
+                        entries.insert(key.to_ascii_lowercase(), value.to_string());
+                    }
+                    rest = &tail[len as usize..];
+                }
+                answers.txt.insert(name, entries);
+            }
+            TYPE_A if len == 4 => {
+                answers
+                    .a
+                    .insert(name, Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]));
+            }
+            _ => {}
+        }
+    }
+    Some(())
+}
@@ -0,0 +1,97 @@
+mod chromecast;
+mod dlna;
+mod mdns;
+mod server;
+
+pub(crate) use server::{read_request, reply, send_file, MediaServer, Request};
+
+use std::net::IpAddr;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Context;
+
+use self::chromecast::Chromecast;
+use self::dlna::Renderer;
+use crate::{CastDevice, CastProtocol, Song};
+
+/// What a device reports it's doing
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum TransportState {
+    Playing,
+    Paused,
+    /// Loading or waiting for data
+    Buffering,
+    /// Nothing loaded, or played to the end
+    Stopped,
+}
+
+/// Transport state and position a device reports
+pub(crate) struct Position {
+    pub state: TransportState,
+    pub position: f64,
+    pub duration: Option<f64>,
+    /// Volume and mute, for devices whose volume can be changed on them too
+    pub volume: Option<(f32, bool)>,
+}
+
+/// A device playback can be cast to
+///
+/// Positions are in seconds from the start of the loaded file.
+pub(crate) trait CastTarget: Send {
+    fn device(&self) -> &CastDevice;
+
+    /// Address of this machine the device can reach it at
+    fn local_ip(&self) -> anyhow::Result<IpAddr>;
+
+    /// Hand the device `url` to play from `start_at`, described by `song`
+    fn load(
+        &mut self,
+        url: &str,
+        mime: &str,
+        song: &Song,
+        start_at: f64,
+        paused: bool,
+    ) -> anyhow::Result<()>;
+
+    fn play(&mut self) -> anyhow::Result<()>;
+
+    fn pause(&mut self) -> anyhow::Result<()>;
+
+    fn stop(&mut self) -> anyhow::Result<()>;
+
+    fn seek(&mut self, position_secs: f64) -> anyhow::Result<()>;
+
+    /// Set the device's own volume, 0 to 1
+    fn set_volume(&mut self, volume: f32) -> anyhow::Result<()>;
+
+    fn position(&mut self) -> anyhow::Result<Position>;
+
+    /// Answer whatever the device sent while nothing is loaded
+    fn keep_alive(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Cast devices answering on the local network within `timeout`, DLNA renderers first
+pub(crate) fn discover(timeout: Duration) -> anyhow::Result<Vec<CastDevice>> {
+    let chromecasts = thread::Builder::new()
+        .name("tunes4r-mdns".into())
+        .spawn(move || chromecast::discover(timeout))
+        .context("failed to spawn discovery thread")?;
+    let mut devices = dlna::discover(timeout)?;
+    match chromecasts.join() {
+        Ok(Ok(found)) => devices.extend(found),
+        Ok(Err(e)) => log::warn!("Chromecast discovery failed: {e:#}"),
+        Err(_) => log::error!("Chromecast discovery panicked"),
+    }
+    Ok(devices)
+}
+
+/// Open a connection to control `device`
+pub(crate) fn connect(device: &CastDevice) -> anyhow::Result<Box<dyn CastTarget>> {
+    Ok(match device.protocol {
+        CastProtocol::Dlna => Box::new(Renderer::connect(device)?),
+        CastProtocol::Chromecast => Box::new(Chromecast::connect(device)?),
+    })
+}
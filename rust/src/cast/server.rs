@@ -0,0 +1,249 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::net::{IpAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Context;
+
+use crate::sources::{self, RemoteFile};
+use crate::{offline, Song};
+
+/// Files kept reachable, so a renderer can still fetch the previous track while it
+/// switches to the next
+const SERVED_FILES: usize = 4;
+
+/// Longest request head read
+const MAX_HEAD_BYTES: u64 = 8 * 1024;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Lets renderers seek by byte range and play while they fetch
+const DLNA_FEATURES: &str = "DLNA.ORG_OP=01;DLNA.ORG_FLAGS=01700000000000000000000000000000";
+
+trait ReadSeek: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> ReadSeek for T {}
+
+/// Serves the files of songs being cast over HTTP, for renderers to fetch
+///
+/// Each song gets an unguessable path of its own; nothing else on the device is
+/// reachable.
+pub(crate) struct MediaServer {
+    port: u16,
+    files: Arc<Mutex<VecDeque<(String, PathBuf)>>>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl MediaServer {
+    pub fn start() -> anyhow::Result<Self> {
+        let listener = TcpListener::bind("0.0.0.0:0").context("failed to open a media server")?;
+        let port = listener.local_addr()?.port();
+        let files: Arc<Mutex<VecDeque<(String, PathBuf)>>> = Arc::default();
+        let stopped = Arc::new(AtomicBool::new(false));
+        let (thread_files, thread_stopped) = (files.clone(), stopped.clone());
+        thread::Builder::new()
+            .name("tunes4r-cast-server".into())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    if thread_stopped.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    let Ok(stream) = stream else {
+                        continue;
+                    };
+                    let files = thread_files.clone();
+                    let spawned = thread::Builder::new()
+                        .name("tunes4r-cast-request".into())
+                        .spawn(move || {
+                            if let Err(e) = respond(stream, &files) {
+                                log::debug!("cast request failed: {e:#}");
+                            }
+                        });
+                    if let Err(e) = spawned {
+                        log::warn!("failed to spawn a cast request thread: {e}");
+                    }
+                }
+            })
+            .context("failed to spawn media server thread")?;
+        Ok(MediaServer {
+            port,
+            files,
+            stopped,
+        })
+    }
+
+    /// URL `song`'s file can be fetched from at `ip`, with its MIME type
+    pub fn url(&self, ip: IpAddr, song: &Song) -> (String, &'static str) {
+        let path = Path::new(&song.file_path);
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+        let token = format!("{:016x}", rand::random::<u64>());
+        let mut files = self.files.lock().unwrap();
+        files.push_back((token.clone(), path.to_path_buf()));
+        if files.len() > SERVED_FILES {
+            files.pop_front();
+        }
+        let url = match extension {
+            "" => format!("http://{ip}:{}/{token}", self.port),
+            extension => format!("http://{ip}:{}/{token}.{extension}", self.port),
+        };
+        (url, mime_type(extension))
+    }
+}
+
+impl Drop for MediaServer {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        // Wake the accept loop so it sees the flag.
+        let _ = TcpStream::connect(("127.0.0.1", self.port));
+    }
+}
+
+/// MIME type players expect for files ending with `extension`
+fn mime_type(extension: &str) -> &'static str {
+    match extension.to_ascii_lowercase().as_str() {
+        "mp3" => "audio/mpeg",
+        "flac" => "audio/flac",
+        "ogg" | "oga" | "opus" => "audio/ogg",
+        "m4a" | "m4b" | "mp4" => "audio/mp4",
+        "aac" => "audio/aac",
+        "wav" => "audio/wav",
+        "aif" | "aiff" => "audio/aiff",
+        _ => "application/octet-stream",
+    }
+}
+
+/// The file at `path`: its offline copy for remote songs that have one
+fn open(path: &Path) -> anyhow::Result<Box<dyn ReadSeek>> {
+    let local = offline::local_copy(path);
+    let path = local.as_deref().unwrap_or(path);
+    Ok(match sources::is_remote(path) {
+        true => Box::new(RemoteFile::open(path)?),
+        false => Box::new(
+            File::open(path).with_context(|| format!("failed to open {}", path.display()))?,
+        ),
+    })
+}
+
+/// Method, target and range of an HTTP request
+pub(crate) struct Request {
+    pub method: String,
+    /// Path and query
+    pub target: String,
+    /// Value of its `Range` header
+    pub range: Option<String>,
+    /// Value of its `Authorization` header
+    pub authorization: Option<String>,
+}
+
+/// Read the head of the request on `stream`, within its timeouts
+pub(crate) fn read_request(stream: &TcpStream) -> anyhow::Result<Request> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?.take(MAX_HEAD_BYTES));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let (mut range, mut authorization) = (None, None);
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            let name = name.trim();
+            if name.eq_ignore_ascii_case("range") {
+                range = Some(value.trim().to_string());
+            } else if name.eq_ignore_ascii_case("authorization") {
+                authorization = Some(value.trim().to_string());
+            }
+        }
+    }
+    let mut parts = request_line.split_whitespace();
+    Ok(Request {
+        method: parts.next().unwrap_or("").to_string(),
+        target: parts.next().unwrap_or("").to_string(),
+        range,
+        authorization,
+    })
+}
+
+fn respond(
+    mut stream: TcpStream,
+    files: &Mutex<VecDeque<(String, PathBuf)>>,
+) -> anyhow::Result<()> {
+    let request = read_request(&stream)?;
+    if !matches!(request.method.as_str(), "GET" | "HEAD") {
+        return reply(&mut stream, "405 Method Not Allowed");
+    }
+    let token = request.target.trim_start_matches('/');
+    let token = token.split_once('.').map_or(token, |(token, _)| token);
+    let path = files
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(t, _)| t == token)
+        .map(|(_, path)| path.clone());
+    match path {
+        Some(path) => send_file(&mut stream, &request, &path),
+        None => reply(&mut stream, "404 Not Found"),
+    }
+}
+
+/// Answer `request` with the file at `path`, or the part of it its range asks for
+pub(crate) fn send_file(
+    stream: &mut TcpStream,
+    request: &Request,
+    path: &Path,
+) -> anyhow::Result<()> {
+    let mut file = open(path)?;
+    let len = file.seek(SeekFrom::End(0))?;
+    let range = request.range.as_deref();
+    let (status, start, end) = match range.and_then(|r| parse_range(r, len)) {
+        Some((start, end)) => ("206 Partial Content", start, end),
+        None if range.is_some() => return reply(stream, "416 Range Not Satisfiable"),
+        None => ("200 OK", 0, len),
+    };
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+    let mut head = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {}\r\nContent-Length: {}\r\nAccept-Ranges: bytes\r\n\
+         transferMode.dlna.org: Streaming\r\ncontentFeatures.dlna.org: {DLNA_FEATURES}\r\n\
+         Connection: close\r\n",
+        mime_type(extension),
+        end - start
+    );
+    if status.starts_with("206") {
+        head.push_str(&format!(
+            "Content-Range: bytes {start}-{}/{len}\r\n",
+            end.saturating_sub(1)
+        ));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes())?;
+    if request.method == "GET" {
+        file.seek(SeekFrom::Start(start))?;
+        io::copy(&mut file.take(end - start), stream)?;
+    }
+    Ok(stream.flush()?)
+}
+
+/// A response with no body
+pub(crate) fn reply(stream: &mut TcpStream, status: &str) -> anyhow::Result<()> {
+    let head = format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+    Ok(stream.write_all(head.as_bytes())?)
+}
+
+/// Start and end, exclusive, of the single `bytes=` range `header` asks for in a file
+/// of `len` bytes
+fn parse_range(header: &str, len: u64) -> Option<(u64, u64)> {
+    let (start, end) = header.strip_prefix("bytes=")?.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => (len.saturating_sub(suffix.parse().ok()?), len),
+        (start, "") => (start.parse().ok()?, len),
+        (start, end) => (start.parse().ok()?, (end.parse::<u64>().ok()? + 1).min(len)),
+    };
+    (start < end).then_some((start, end))
+}
@@ -0,0 +1,172 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use anyhow::Context;
+
+use crate::Chapter;
+
+/// Nero `chpl` start times count in units of 100ns
+const CHPL_UNITS_PER_SEC: f64 = 10_000_000.0;
+
+/// Largest `moov` box read into memory while looking for chapters
+const MAX_MOOV_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Chapter markers of the audio file at `path`, in order; empty if it has none
+///
+/// MP4 files are read for Nero `chpl` chapters, anything else for ID3 `CHAP` frames.
+pub(crate) fn read(path: &Path) -> anyhow::Result<Vec<Chapter>> {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_ascii_lowercase);
+    let mut chapters = match extension.as_deref() {
+        Some("m4a" | "m4b" | "mp4" | "aac") => mp4_chapters(path)?,
+        _ => id3_chapters(path),
+    };
+    chapters.sort_by(|a, b| a.start.total_cmp(&b.start));
+    Ok(chapters)
+}
+
+fn id3_chapters(path: &Path) -> Vec<Chapter> {
+    let Ok(tag) = id3::Tag::read_from_path(path) else {
+        return Vec::new();
+    };
+    tag.chapters()
+        .enumerate()
+        .map(|(i, chapter)| Chapter {
+            title: chapter
+                .frames
+                .iter()
+                .find(|frame| frame.id() == "TIT2")
+                .and_then(|frame| frame.content().text())
+                .map(|title| title.trim().to_string())
+                .unwrap_or_else(|| format!("Chapter {}", i + 1)),
+            start: chapter.start_time as f64 / 1000.0,
+            end: chapter.end_time as f64 / 1000.0,
+        })
+        .collect()
+}
+
+/// Child boxes of an MP4 container's payload, as `(type, payload)`
+fn boxes(mut data: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    std::iter::from_fn(move || {
+        if data.len() < 8 {
+            return None;
+        }
+        let size = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+        let (header, size) = match size {
+            0 => (8, data.len()),
+            1 if data.len() >= 16 => (
+                16,
+                u64::from_be_bytes(data[8..16].try_into().unwrap()) as usize,
+            ),
+            _ => (8, size),
+        };
+        if size < header || size > data.len() {
+            return None;
+        }
+        let (kind, payload) = (&data[4..8], &data[header..size]);
+        data = &data[size..];
+        Some((kind, payload))
+    })
+}
+
+fn child<'a>(data: &'a [u8], kind: &[u8]) -> Option<&'a [u8]> {
+    boxes(data)
+        .find(|(k, _)| *k == kind)
+        .map(|(_, payload)| payload)
+}
+
+/// Read the top-level `moov` box of the MP4 file at `path`
+fn read_moov(path: &Path) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut file =
+        File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let len = file.metadata()?.len();
+    let mut position = 0;
+    while position + 8 <= len {
+        let mut header = [0; 16];
+        file.seek(SeekFrom::Start(position))?;
+        file.read_exact(&mut header[..8])?;
+        let mut size = u32::from_be_bytes(header[..4].try_into().unwrap()) as u64;
+        let mut header_len = 8;
+        if size == 1 {
+            file.read_exact(&mut header[8..])?;
+            size = u64::from_be_bytes(header[8..].try_into().unwrap());
+            header_len = 16;
+        } else if size == 0 {
+            size = len - position;
+        }
+        if size < header_len {
+            break;
+        }
+        if &header[4..8] == b"moov" {
+            let payload_len = size - header_len;
+            anyhow::ensure!(payload_len <= MAX_MOOV_BYTES, "moov box is too large");
+            let mut moov = vec![0; payload_len as usize];
+            file.read_exact(&mut moov)?;
+            return Ok(Some(moov));
+        }
+        position += size;
+    }
+    Ok(None)
+}
+
+/// Movie duration in seconds from `mvhd`
+fn movie_duration(moov: &[u8]) -> Option<f64> {
+    let mvhd = child(moov, b"mvhd")?;
+    let (timescale, duration) = match *mvhd.first()? {
+        0 => (
+            u32::from_be_bytes(mvhd.get(12..16)?.try_into().ok()?),
+            u32::from_be_bytes(mvhd.get(16..20)?.try_into().ok()?) as u64,
+        ),
+        _ => (
+            u32::from_be_bytes(mvhd.get(20..24)?.try_into().ok()?),
+            u64::from_be_bytes(mvhd.get(24..32)?.try_into().ok()?),
+        ),
+    };
+    (timescale > 0).then(|| duration as f64 / timescale as f64)
+}
+
+/// Nero chapters: a count, then a 64-bit start and a length-prefixed title for each
+fn parse_chpl(chpl: &[u8], end: f64) -> Vec<Chapter> {
+    let Some(&version) = chpl.first() else {
+        return Vec::new();
+    };
+    let mut rest = chpl
+        .get(if version == 0 { 4 } else { 8 }..)
+        .unwrap_or_default();
+    let Some((&count, tail)) = rest.split_first() else {
+        return Vec::new();
+    };
+    rest = tail;
+    let mut starts = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let Some(start) = rest.get(..8) else { break };
+        let Some(&len) = rest.get(8) else { break };
+        let Some(title) = rest.get(9..9 + len as usize) else {
+            break;
+        };
+        let start = u64::from_be_bytes(start.try_into().unwrap()) as f64 / CHPL_UNITS_PER_SEC;
+        starts.push((start, String::from_utf8_lossy(title).trim().to_string()));
+        rest = &rest[9 + len as usize..];
+    }
+    (0..starts.len())
+        .map(|i| Chapter {
+            title: starts[i].1.clone(),
+            start: starts[i].0,
+            end: starts.get(i + 1).map_or(end, |next| next.0),
+        })
+        .collect()
+}
+
+fn mp4_chapters(path: &Path) -> anyhow::Result<Vec<Chapter>> {
+    let Some(moov) = read_moov(path)? else {
+        anyhow::bail!("{} has no moov box", path.display());
+    };
+    let Some(chpl) = child(&moov, b"udta").and_then(|udta| child(udta, b"chpl")) else {
+        return Ok(Vec::new());
+    };
+    let end = movie_duration(&moov).unwrap_or_default();
+    Ok(parse_chpl(chpl, end))
+}
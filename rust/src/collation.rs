@@ -0,0 +1,77 @@
+use std::cmp::Ordering;
+use std::sync::Mutex;
+
+use icu_normalizer::DecomposingNormalizerBorrowed;
+
+/// Name library queries sort text with, as in `ORDER BY ar.sort_name COLLATE unicode`
+pub(crate) const NAME: &str = "unicode";
+
+/// Letters the sort language orders as letters of their own, each with the letter it
+/// comes right after; set by `set_locale`
+static TAILORING: Mutex<&'static [(char, char)]> = Mutex::new(&[]);
+
+const NFKD: DecomposingNormalizerBorrowed<'static> = DecomposingNormalizerBorrowed::new_nfkd();
+
+/// Sort the way speakers of `locale`, a BCP 47 tag like "sv-SE", expect
+///
+/// Letters some alphabets have of their own, like the Swedish "å" or the Spanish "ñ",
+/// go where those alphabets put them; other languages sort them with the plain letter.
+pub(crate) fn set_locale(locale: &str) {
+    let language = locale
+        .split(['-', '_'])
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    let tailoring: &'static [(char, char)] = match language.as_str() {
+        "sv" | "fi" => &[('å', 'z'), ('ä', 'z'), ('æ', 'z'), ('ö', 'z'), ('ø', 'z')],
+        "da" | "nb" | "nn" | "no" => &[('æ', 'z'), ('ø', 'z'), ('å', 'z')],
+        "es" => &[('ñ', 'n')],
+        _ => &[],
+    };
+    *TAILORING.lock().unwrap() = tailoring;
+}
+
+/// Characters combining with the one before them, such as accents once decomposed and
+/// the kana voicing marks
+fn is_combining(c: char) -> bool {
+    matches!(
+        c,
+        '\u{0300}'..='\u{036f}'
+            | '\u{1ab0}'..='\u{1aff}'
+            | '\u{1dc0}'..='\u{1dff}'
+            | '\u{20d0}'..='\u{20ff}'
+            | '\u{3099}'..='\u{309a}'
+            | '\u{fe20}'..='\u{fe2f}'
+    )
+}
+
+/// What `text` sorts by: lower case, without accents or width differences, and with
+/// hiragana as katakana, so "Éric" sorts with "eric" and "ｶﾞ" with "が"
+pub(crate) fn sort_key(text: &str) -> String {
+    let tailoring = *TAILORING.lock().unwrap();
+    let mut key = String::with_capacity(text.len());
+    for c in text.chars().flat_map(char::to_lowercase) {
+        if let Some(index) = tailoring.iter().position(|&(letter, _)| letter == c) {
+            // Past every string of its base letter, before the next one
+            key.push(tailoring[index].1);
+            key.push(char::MAX);
+            key.push(char::from(b'a' + index as u8));
+            continue;
+        }
+        for c in NFKD.normalize_iter(std::iter::once(c)) {
+            match c {
+                c if is_combining(c) => {}
+                '\u{3041}'..='\u{3096}' => {
+                    key.push(char::from_u32(c as u32 + 0x60).unwrap_or(c));
+                }
+                c => key.push(c),
+            }
+        }
+    }
+    key
+}
+
+/// Order of `a` and `b` by `sort_key`, then as written so only equal text ties
+pub(crate) fn compare(a: &str, b: &str) -> Ordering {
+    sort_key(a).cmp(&sort_key(b)).then_with(|| a.cmp(b))
+}
@@ -0,0 +1,323 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+use crate::{metadata, scanner, Song, SongId};
+
+/// CUE sheet time is `mm:ss:ff` with 75 frames per second
+const FRAMES_PER_SEC: f64 = 75.0;
+
+/// One audio file of a CUE sheet and the tracks cut from it
+pub(crate) struct CueFile {
+    pub path: PathBuf,
+    pub tracks: Vec<CueTrack>,
+}
+
+pub(crate) struct CueTrack {
+    pub number: u32,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    /// Start of the track's `INDEX 01` in seconds
+    pub start: f64,
+}
+
+/// Whether `song` is a span of a larger file rather than a whole file
+pub(crate) fn is_track(song: &Song) -> bool {
+    song.start_offset > 0.0 || song.end_offset.is_some()
+}
+
+/// A parsed CUE sheet
+pub(crate) struct CueSheet {
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub files: Vec<CueFile>,
+}
+
+fn parse_time(text: &str) -> Option<f64> {
+    let mut parts = text.split(':').map(|p| p.parse::<u32>().ok());
+    let (minutes, seconds, frames) = (parts.next()??, parts.next()??, parts.next()??);
+    Some(minutes as f64 * 60.0 + seconds as f64 + frames as f64 / FRAMES_PER_SEC)
+}
+
+/// A command's argument, with the quotes around it removed
+fn argument(rest: &str) -> String {
+    let rest = rest.trim();
+    match rest.strip_prefix('"') {
+        Some(quoted) => quoted.split('"').next().unwrap_or_default().to_string(),
+        None => rest.to_string(),
+    }
+}
+
+/// The audio file a `FILE` line names, relative to the sheet
+///
+/// Sheets often outlive a conversion of their image, so when the named file is gone
+/// a supported file with the same stem is used instead.
+fn resolve_file(dir: &Path, name: &str) -> PathBuf {
+    let path = dir.join(name.replace('\\', "/"));
+    if path.is_file() {
+        return path;
+    }
+    scanner::SUPPORTED_EXTENSIONS
+        .iter()
+        .map(|extension| path.with_extension(extension))
+        .find(|candidate| candidate.is_file())
+        .unwrap_or(path)
+}
+
+fn parse(text: &str, dir: &Path) -> CueSheet {
+    let mut sheet = CueSheet {
+        title: None,
+        performer: None,
+        files: Vec::new(),
+    };
+    for line in text.lines() {
+        let line = line.trim();
+        let (command, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let track = sheet.files.last_mut().and_then(|f| f.tracks.last_mut());
+        match command.to_ascii_uppercase().as_str() {
+            "FILE" => {
+                // The file type comes last and may follow a quoted name with spaces.
+                let name = match rest.trim().strip_prefix('"') {
+                    Some(_) => argument(rest),
+                    None => rest
+                        .split_whitespace()
+                        .next()
+                        .unwrap_or_default()
+                        .to_string(),
+                };
+                sheet.files.push(CueFile {
+                    path: resolve_file(dir, &name),
+                    tracks: Vec::new(),
+                });
+            }
+            "TRACK" => {
+                let number = rest.split_whitespace().next().and_then(|n| n.parse().ok());
+                if let (Some(file), Some(number)) = (sheet.files.last_mut(), number) {
+                    file.tracks.push(CueTrack {
+                        number,
+                        title: None,
+                        performer: None,
+                        start: 0.0,
+                    });
+                }
+            }
+            "TITLE" => match track {
+                Some(track) => track.title = Some(argument(rest)),
+                None => sheet.title = Some(argument(rest)),
+            },
+            "PERFORMER" => match track {
+                Some(track) => track.performer = Some(argument(rest)),
+                None => sheet.performer = Some(argument(rest)),
+            },
+            "INDEX" => {
+                let mut parts = rest.split_whitespace();
+                if let (Some(track), Some("01"), Some(time)) =
+                    (track, parts.next(), parts.next().and_then(parse_time))
+                {
+                    track.start = time;
+                }
+            }
+            _ => {}
+        }
+    }
+    for file in &mut sheet.files {
+        file.tracks.sort_by(|a, b| a.start.total_cmp(&b.start));
+    }
+    sheet
+}
+
+/// Parse the CUE sheet at `path`, which may be UTF-8 or Latin-1
+pub(crate) fn read(path: &Path) -> anyhow::Result<CueSheet> {
+    let bytes = fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let text = match String::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(e) => e.into_bytes().iter().map(|&b| b as char).collect(),
+    };
+    let text = text.trim_start_matches('\u{feff}');
+    Ok(parse(text, path.parent().unwrap_or(Path::new(""))))
+}
+
+/// One `Song` per track of `file`, each playing its span of the file
+///
+/// Tags of the file fill in what the sheet leaves out; its loudness becomes the
+/// tracks' album loudness.
+pub(crate) fn songs(sheet: &CueSheet, file: &CueFile) -> anyhow::Result<Vec<Song>> {
+    let image = metadata::read_song(&file.path)?;
+    let file_end = image.duration as f64;
+    let artist = sheet.performer.clone().unwrap_or(image.artist);
+    let album = sheet.title.clone().unwrap_or(image.album);
+    let album_loudness = image.album_loudness.or(image.track_loudness);
+
+    let songs = file
+        .tracks
+        .iter()
+        .enumerate()
+        .map(|(i, track)| {
+            let end = file.tracks.get(i + 1).map(|next| next.start);
+            Song {
+                id: SongId::for_file(&file.path, Some(track.number)),
+                title: track
+                    .title
+                    .clone()
+                    .unwrap_or_else(|| format!("Track {:02}", track.number)),
+                artist: track.performer.clone().unwrap_or_else(|| artist.clone()),
+                album: album.clone(),
+                album_artist: sheet.performer.clone().or(image.album_artist.clone()),
+                compilation: image.compilation,
+                artist_sort: None,
+                album_sort: image.album_sort.clone(),
+                disc_number: image.disc_number,
+                track_number: Some(track.number),
+                genre: image.genre.clone(),
+                year: image.year,
+                rating: None,
+                favorite: false,
+                duration: (end.unwrap_or(file_end) - track.start).max(0.0).round() as u64,
+                file_path: image.file_path.clone(),
+                start_offset: track.start,
+                end_offset: end,
+                track_loudness: None,
+                album_loudness,
+                bpm: None,
+                key: None,
+                explicit: image.explicit,
+            }
+        })
+        .collect();
+    Ok(songs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn starts(file: &CueFile) -> Vec<f64> {
+        file.tracks.iter().map(|track| track.start).collect()
+    }
+
+    #[test]
+    fn parses_a_sheet_with_album_and_track_details() {
+        let sheet = parse(
+            r#"REM GENRE Rock
+PERFORMER "The Band"
+TITLE "Live, Somewhere"
+FILE "Live Somewhere.flac" WAVE
+  TRACK 01 AUDIO
+    TITLE "Intro"
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    TITLE "Song: Two"
+    PERFORMER "Guest"
+    INDEX 00 03:58:10
+    INDEX 01 04:00:37
+"#,
+            Path::new("/music"),
+        );
+        assert_eq!(sheet.title.as_deref(), Some("Live, Somewhere"));
+        assert_eq!(sheet.performer.as_deref(), Some("The Band"));
+        assert_eq!(sheet.files.len(), 1);
+        let file = &sheet.files[0];
+        assert_eq!(file.path, Path::new("/music/Live Somewhere.flac"));
+        let titles: Vec<_> = file.tracks.iter().map(|t| t.title.as_deref()).collect();
+        assert_eq!(titles, [Some("Intro"), Some("Song: Two")]);
+        assert_eq!(file.tracks[0].performer, None);
+        assert_eq!(file.tracks[1].performer.as_deref(), Some("Guest"));
+        // INDEX 00 is the pregap; the track starts at INDEX 01.
+        assert_eq!(starts(file), [0.0, 240.0 + 37.0 / 75.0]);
+    }
+
+    #[test]
+    fn commands_are_case_insensitive_and_lines_may_end_in_crlf() {
+        let sheet = parse(
+            "file image.wav wave\r\n\ttrack 1 audio\r\n\t\ttitle One\r\n\t\tindex 01 00:01:00\r\n",
+            Path::new("dir"),
+        );
+        let file = &sheet.files[0];
+        assert_eq!(file.path, Path::new("dir/image.wav"));
+        assert_eq!(file.tracks[0].number, 1);
+        assert_eq!(file.tracks[0].title.as_deref(), Some("One"));
+        assert_eq!(starts(file), [1.0]);
+    }
+
+    #[test]
+    fn tracks_belong_to_the_file_before_them_and_sort_by_start() {
+        let sheet = parse(
+            "TRACK 09 AUDIO
+FILE \"a.wav\" WAVE
+TRACK 02 AUDIO
+INDEX 01 02:00:00
+TRACK 01 AUDIO
+INDEX 01 00:00:00
+FILE b.wav WAVE
+TRACK 03 AUDIO
+INDEX 01 00:00:00",
+            Path::new(""),
+        );
+        assert_eq!(sheet.files.len(), 2);
+        let numbers: Vec<_> = sheet.files[0].tracks.iter().map(|t| t.number).collect();
+        assert_eq!(numbers, [1, 2], "the track before any FILE is dropped");
+        assert_eq!(sheet.files[1].tracks[0].number, 3);
+    }
+
+    #[test]
+    fn malformed_lines_are_ignored() {
+        let sheet = parse(
+            "FILE \"a.wav\" WAVE
+TRACK xx AUDIO
+TRACK 01 AUDIO
+INDEX 01 1:2
+INDEX 01 aa:bb:cc
+TITLE \"Unterminated
+INDEX
+",
+            Path::new(""),
+        );
+        let track = &sheet.files[0].tracks[..];
+        assert_eq!(track.len(), 1);
+        assert_eq!(track[0].start, 0.0);
+        assert_eq!(track[0].title.as_deref(), Some("Unterminated"));
+    }
+
+    #[test]
+    fn arguments_lose_their_quotes_and_what_follows_them() {
+        assert_eq!(argument(r#" "Quoted title" trailing"#), "Quoted title");
+        assert_eq!(argument("  bare words  "), "bare words");
+        assert_eq!(argument(r#""""#), "");
+    }
+
+    #[test]
+    fn times_count_75_frames_a_second() {
+        assert_eq!(parse_time("00:00:00"), Some(0.0));
+        assert_eq!(parse_time("01:02:15"), Some(62.2));
+        assert_eq!(parse_time("99:59:74"), Some(5999.0 + 74.0 / 75.0));
+        assert_eq!(parse_time("00:00"), None);
+        assert_eq!(parse_time("-1:00:00"), None);
+    }
+
+    #[test]
+    fn a_missing_image_is_found_under_another_extension() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("album.flac"), b"").unwrap();
+        assert_eq!(
+            resolve_file(dir.path(), "album.wav"),
+            dir.path().join("album.flac")
+        );
+        assert_eq!(
+            resolve_file(dir.path(), "sub\\gone.wav"),
+            dir.path().join("sub/gone.wav")
+        );
+    }
+
+    #[test]
+    fn latin_1_sheets_and_byte_order_marks_are_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let latin = dir.path().join("latin.cue");
+        fs::write(&latin, b"TITLE \"Caf\xe9\"\nFILE a.wav WAVE\n").unwrap();
+        assert_eq!(read(&latin).unwrap().title.as_deref(), Some("Café"));
+
+        let bom = dir.path().join("bom.cue");
+        fs::write(&bom, "\u{feff}TITLE \"Album\"\n").unwrap();
+        assert_eq!(read(&bom).unwrap().title.as_deref(), Some("Album"));
+    }
+}
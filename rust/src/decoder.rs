@@ -0,0 +1,272 @@
+use std::fs::File;
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use anyhow::Context;
+use rodio::source::SeekError;
+use rodio::Source;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{self, CodecRegistry, CodecType, DecoderOptions};
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::{FormatOptions, FormatReader, SeekMode, SeekTo, Track};
+use symphonia::core::io::{MediaSource, MediaSourceStream};
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+use symphonia::core::units::Time;
+
+use crate::sources::{self, RemoteFile};
+use crate::{offline, AudioFormatInfo};
+
+/// Open `path` and pick its default audio track
+///
+/// Remote files are read from their offline copy if they have one. Otherwise they're
+/// fetched ahead of the reader when they'll be decoded, and only as far as needed
+/// otherwise.
+fn open_format(path: &Path, decode: bool) -> anyhow::Result<Box<dyn FormatReader>> {
+    let local = offline::local_copy(path);
+    let path = local.as_deref().unwrap_or(path);
+    let file: Box<dyn MediaSource> = match sources::is_remote(path) {
+        true if decode => Box::new(RemoteFile::open(path)?),
+        true => Box::new(RemoteFile::open_for_tags(path)?),
+        false => Box::new(
+            File::open(path).with_context(|| format!("failed to open {}", path.display()))?,
+        ),
+    };
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(extension);
+    }
+    probe_format(file, &hint)
+        .with_context(|| format!("unrecognized audio format: {}", path.display()))
+}
+
+fn probe_format(
+    source: Box<dyn MediaSource>,
+    hint: &Hint,
+) -> symphonia::core::errors::Result<Box<dyn FormatReader>> {
+    let stream = MediaSourceStream::new(source, Default::default());
+    let probed = symphonia::default::get_probe().format(
+        hint,
+        stream,
+        &FormatOptions {
+            enable_gapless: true,
+            ..Default::default()
+        },
+        &MetadataOptions::default(),
+    )?;
+    Ok(probed.format)
+}
+
+fn audio_track(format: &dyn FormatReader) -> anyhow::Result<&Track> {
+    format
+        .default_track()
+        .filter(|t| t.codec_params.codec != codecs::CODEC_TYPE_NULL)
+        .context("file has no audio track")
+}
+
+/// symphonia's decoders, with Opus's through libopus when built with the `opus` feature
+pub(crate) fn codecs() -> &'static CodecRegistry {
+    static CODECS: OnceLock<CodecRegistry> = OnceLock::new();
+    CODECS.get_or_init(|| {
+        let mut registry = CodecRegistry::new();
+        symphonia::default::register_enabled_codecs(&mut registry);
+        #[cfg(feature = "opus")]
+        registry.register_all::<crate::opus::OpusDecoder>();
+        registry
+    })
+}
+
+fn codec_name(codec: CodecType) -> String {
+    match codecs().get_codec(codec) {
+        Some(descriptor) => descriptor.short_name.to_string(),
+        // Known to symphonia but without a decoder in this build.
+        None if codec == codecs::CODEC_TYPE_OPUS => "opus".to_string(),
+        None => format!("unknown ({codec})"),
+    }
+}
+
+fn track_duration(track: &Track) -> Option<f64> {
+    let params = &track.codec_params;
+    let frames = params.n_frames?;
+    match params.time_base {
+        Some(time_base) => {
+            let time = time_base.calc_time(frames);
+            Some(time.seconds as f64 + time.frac)
+        }
+        None => Some(frames as f64 / params.sample_rate? as f64),
+    }
+}
+
+/// Codec and stream properties of an audio file, read without decoding it
+pub(crate) fn probe(path: &Path) -> anyhow::Result<AudioFormatInfo> {
+    let format = open_format(path, false)?;
+    let track = audio_track(format.as_ref())?;
+    let params = &track.codec_params;
+    Ok(AudioFormatInfo {
+        codec: codec_name(params.codec),
+        sample_rate: params.sample_rate.unwrap_or(0),
+        channels: params.channels.map_or(0, |c| c.count() as u16),
+        bit_depth: params.bits_per_sample.or(params.bits_per_coded_sample),
+        duration_secs: track_duration(track),
+    })
+}
+
+/// Decodes any format symphonia supports into interleaved `f32` samples
+pub(crate) struct SymphoniaSource {
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn codecs::Decoder>,
+    track_id: u32,
+    channels: u16,
+    sample_rate: u32,
+    duration: Option<Duration>,
+    /// Scratch space for converting decoded packets, reused while it is big enough
+    samples: Option<SampleBuffer<f32>>,
+    buffer: Vec<f32>,
+    cursor: usize,
+    /// After an accurate seek, frames before this timestamp are dropped
+    skip_until: u64,
+}
+
+impl SymphoniaSource {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        Self::from_format(open_format(path, true)?)
+    }
+
+    /// Decode a stream that can't seek, such as a network response
+    pub fn from_stream(source: Box<dyn MediaSource>, hint: &Hint) -> anyhow::Result<Self> {
+        Self::from_format(probe_format(source, hint).context("unrecognized stream format")?)
+    }
+
+    fn from_format(format: Box<dyn FormatReader>) -> anyhow::Result<Self> {
+        let track = audio_track(format.as_ref())?;
+        let params = track.codec_params.clone();
+        let track_id = track.id;
+        let duration = track_duration(track).map(Duration::from_secs_f64);
+        let decoder = codecs()
+            .make(&params, &DecoderOptions::default())
+            .with_context(|| format!("unsupported codec: {}", codec_name(params.codec)))?;
+        let mut source = SymphoniaSource {
+            format,
+            decoder,
+            track_id,
+            channels: params.channels.map_or(2, |c| c.count() as u16),
+            sample_rate: params.sample_rate.unwrap_or(44100),
+            duration,
+            samples: None,
+            buffer: Vec::new(),
+            cursor: 0,
+            skip_until: 0,
+        };
+        // Decode the first packet now so the stream spec is known before playback.
+        source.refill();
+        Ok(source)
+    }
+
+    /// Decode the next packet of our track into `buffer`, leaving it empty at the end
+    fn refill(&mut self) {
+        self.buffer.clear();
+        self.cursor = 0;
+        loop {
+            let packet = match self.format.next_packet() {
+                Ok(packet) => packet,
+                Err(SymphoniaError::ResetRequired) => {
+                    self.decoder.reset();
+                    continue;
+                }
+                Err(SymphoniaError::IoError(e))
+                    if e.kind() == std::io::ErrorKind::UnexpectedEof =>
+                {
+                    return;
+                }
+                Err(e) => {
+                    log::warn!("stopping decode: {e}");
+                    return;
+                }
+            };
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+            let end = packet.ts() + packet.dur();
+            if end <= self.skip_until {
+                continue;
+            }
+            let skip_frames = self.skip_until.saturating_sub(packet.ts()) as usize;
+            let decoded = match self.decoder.decode(&packet) {
+                Ok(decoded) => decoded,
+                Err(SymphoniaError::DecodeError(e)) => {
+                    log::debug!("skipping corrupt packet: {e}");
+                    continue;
+                }
+                Err(e) => {
+                    log::warn!("stopping decode: {e}");
+                    return;
+                }
+            };
+            let spec = *decoded.spec();
+            let capacity = decoded.capacity();
+            let samples = match &mut self.samples {
+                Some(samples) if samples.capacity() >= capacity * spec.channels.count() => samples,
+                slot => slot.insert(SampleBuffer::new(capacity as u64, spec)),
+            };
+            samples.copy_interleaved_ref(decoded);
+            self.channels = spec.channels.count() as u16;
+            self.sample_rate = spec.rate;
+            let skip = (skip_frames * self.channels as usize).min(samples.samples().len());
+            self.buffer.extend_from_slice(&samples.samples()[skip..]);
+            if !self.buffer.is_empty() {
+                return;
+            }
+        }
+    }
+}
+
+impl Iterator for SymphoniaSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let sample = *self.buffer.get(self.cursor)?;
+        self.cursor += 1;
+        // Refill eagerly so `current_frame_len` always describes the upcoming samples.
+        if self.cursor == self.buffer.len() {
+            self.refill();
+        }
+        Some(sample)
+    }
+}
+
+impl Source for SymphoniaSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        // The spec can change between packets, so converters re-check it after each one.
+        Some(self.buffer.len() - self.cursor)
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.duration
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        let seeked = self
+            .format
+            .seek(
+                SeekMode::Accurate,
+                SeekTo::Time {
+                    time: Time::from(pos.as_secs_f64()),
+                    track_id: Some(self.track_id),
+                },
+            )
+            .map_err(|e| SeekError::Other(Box::new(e)))?;
+        self.decoder.reset();
+        self.skip_until = seeked.required_ts;
+        self.refill();
+        Ok(())
+    }
+}
@@ -0,0 +1,293 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use anyhow::Context;
+use lofty::prelude::*;
+use notify::event::{AccessKind, AccessMode, ModifyKind};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use walkdir::WalkDir;
+
+use crate::jobs::{self, Job};
+use crate::{
+    export, identify, library, metadata, scanner, transcode, watcher, DropFolderEvent,
+    DropFolderOptions, JobKind, Song, StreamSink,
+};
+
+/// AcoustID matches at least this likely are tagged without asking
+const AUTO_TAG_CONFIDENCE: f32 = 0.9;
+
+/// The running drop folder watcher and its id; dropping it ends its worker thread
+static WATCHER: Mutex<Option<(u32, RecommendedWatcher)>> = Mutex::new(None);
+
+/// Files a job is importing, so one started meanwhile leaves them alone
+static CLAIMED: Mutex<Option<HashSet<PathBuf>>> = Mutex::new(None);
+
+/// Watch `options.folder` and move every audio file that settles there into the
+/// library, replacing any earlier drop folder
+///
+/// Files already in the folder are imported straight away. Each import runs as a
+/// background job.
+pub(crate) fn start(
+    options: DropFolderOptions,
+    sink: StreamSink<DropFolderEvent>,
+) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        library::is_open(),
+        "library database is not open; call open_library first"
+    );
+    let folder = PathBuf::from(&options.folder);
+    anyhow::ensure!(folder.is_dir(), "{} is not a folder", folder.display());
+    let root = PathBuf::from(&options.library_root);
+    anyhow::ensure!(
+        !options.library_root.is_empty() && !root.starts_with(&folder),
+        "the library folder has to be outside the drop folder"
+    );
+    fs::create_dir_all(&root).with_context(|| format!("failed to create {}", root.display()))?;
+
+    static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+    let watch_id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    watcher.watch(&folder, RecursiveMode::Recursive)?;
+    let options = Arc::new(options);
+    submit(options.clone(), sink.clone());
+    thread::Builder::new()
+        .name("tunes4r-drop-folder".into())
+        .spawn(move || {
+            run(rx, options, sink);
+            // Nobody is listening any more, so stop watching, unless already replaced.
+            let mut watcher = WATCHER.lock().unwrap();
+            if watcher.as_ref().is_some_and(|(id, _)| *id == watch_id) {
+                watcher.take();
+            }
+        })?;
+    *WATCHER.lock().unwrap() = Some((watch_id, watcher));
+    log::info!("watching drop folder {}", folder.display());
+    Ok(())
+}
+
+pub(crate) fn stop() {
+    WATCHER.lock().unwrap().take();
+}
+
+/// Import new files once the folder has been quiet for a while, until the watcher is
+/// dropped or the sink closes
+fn run(
+    rx: Receiver<notify::Result<Event>>,
+    options: Arc<DropFolderOptions>,
+    sink: StreamSink<DropFolderEvent>,
+) {
+    let mut pending = false;
+    loop {
+        let received = match pending {
+            false => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            true => rx.recv_timeout(watcher::SETTLE_TIME),
+        };
+        match received {
+            // Files leaving, like those just imported, don't need another look.
+            Ok(Ok(event)) => {
+                pending |= matches!(
+                    event.kind,
+                    EventKind::Create(_)
+                        | EventKind::Modify(
+                            ModifyKind::Data(_) | ModifyKind::Name(_) | ModifyKind::Any
+                        )
+                        | EventKind::Access(AccessKind::Close(AccessMode::Write))
+                )
+            }
+            Ok(Err(e)) => log::warn!("drop folder watch failed: {e}"),
+            Err(RecvTimeoutError::Timeout) => {
+                pending = false;
+                submit(options.clone(), sink.clone());
+            }
+            // The watcher was stopped or replaced.
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
+
+/// Queue importing what's in the drop folder as a background job
+fn submit(options: Arc<DropFolderOptions>, sink: StreamSink<DropFolderEvent>) -> u32 {
+    jobs::submit(JobKind::Organize, move |job| {
+        organize(&options, &sink, job);
+        Ok(())
+    })
+}
+
+fn organize(options: &DropFolderOptions, sink: &StreamSink<DropFolderEvent>, job: &Job) {
+    let folder = Path::new(&options.folder);
+    let files: Vec<PathBuf> = WalkDir::new(folder)
+        .follow_links(true)
+        .into_iter()
+        .flatten()
+        .filter(|entry| entry.file_type().is_file() && scanner::is_supported(entry.path()))
+        .map(|entry| entry.into_path())
+        .filter(|path| claim(path))
+        .collect();
+    let total = files.len();
+    for (done, path) in files.iter().enumerate() {
+        job.progress(done as u64, Some(total as u64));
+        let event = match job.is_cancelled() {
+            true => None,
+            false => Some(match import(path, options) {
+                Ok((song, retagged)) => DropFolderEvent::Imported {
+                    source: path.to_string_lossy().into_owned(),
+                    song,
+                    retagged,
+                },
+                Err(e) => {
+                    let message = format!("{e:#}");
+                    log::warn!("couldn't import {}: {message}", path.display());
+                    DropFolderEvent::Failed {
+                        path: path.to_string_lossy().into_owned(),
+                        message,
+                    }
+                }
+            }),
+        };
+        release(path);
+        if event.is_some_and(|event| sink.add(event).is_err()) {
+            job.cancelled().store(true, Ordering::Relaxed);
+        }
+    }
+    remove_empty_folders(folder);
+    if total > 0 {
+        log::info!("imported {total} files from {}", folder.display());
+    }
+}
+
+/// Mark `path` as being imported; false if another job already is
+fn claim(path: &Path) -> bool {
+    let mut claimed = CLAIMED.lock().unwrap();
+    claimed
+        .get_or_insert_with(HashSet::new)
+        .insert(path.to_path_buf())
+}
+
+fn release(path: &Path) {
+    if let Some(claimed) = CLAIMED.lock().unwrap().as_mut() {
+        claimed.remove(path);
+    }
+}
+
+/// Tag `path` if it needs it and asked for, move it into the library folder and add it
+/// to the library; returns the song as stored and whether it was retagged
+fn import(path: &Path, options: &DropFolderOptions) -> anyhow::Result<(Song, bool)> {
+    let mut retagged = false;
+    if options.identify_untagged && lacks_tags(path)? {
+        retagged = retag(path);
+    }
+    let tagged = metadata::read_song(path)?;
+    let target = vacant_path(Path::new(&options.library_root).join(relative_path(
+        &options.pattern,
+        &tagged,
+        path,
+    )));
+    move_file(path, &target)?;
+    let read = scanner::read_file(&target)?;
+    let mut stored = scanner::store_file_songs(&target, std::slice::from_ref(&read))?;
+    log::debug!("imported {} as {}", path.display(), target.display());
+    Ok((stored.remove(0), retagged))
+}
+
+/// Whether the file's tags leave out its title or artist
+fn lacks_tags(path: &Path) -> anyhow::Result<bool> {
+    let tagged = metadata::read_tagged(path)?;
+    let Some(tag) = tagged.primary_tag().or_else(|| tagged.first_tag()) else {
+        return Ok(true);
+    };
+    let blank =
+        |value: Option<std::borrow::Cow<'_, str>>| value.is_none_or(|v| v.trim().is_empty());
+    Ok(blank(tag.title()) || blank(tag.artist()))
+}
+
+/// Tag the file with its AcoustID match, if there is a confident one; whether it was
+fn retag(path: &Path) -> bool {
+    let best = match identify::identify(path) {
+        Ok(candidates) => candidates.into_iter().next(),
+        Err(e) => {
+            log::info!("couldn't identify {}: {e:#}", path.display());
+            return false;
+        }
+    };
+    let Some(best) = best.filter(|c| c.confidence >= AUTO_TAG_CONFIDENCE) else {
+        return false;
+    };
+    match identify::apply(path, &best) {
+        Ok(_) => true,
+        Err(e) => {
+            log::warn!("couldn't tag {}: {e:#}", path.display());
+            false
+        }
+    }
+}
+
+/// Where `song`, read from `path`, goes below the library folder, following `pattern`
+fn relative_path(pattern: &str, song: &Song, path: &Path) -> PathBuf {
+    let pattern = match pattern.trim() {
+        "" => export::DEFAULT_PATTERN,
+        pattern => pattern,
+    };
+    let track = export::track_number(path).map_or(String::new(), |n| format!("{n:02}"));
+    let fields = [
+        ("{artist}", song.artist.as_str()),
+        ("{album}", song.album.as_str()),
+        ("{title}", song.title.as_str()),
+        ("{track}", track.as_str()),
+    ];
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path.extension().unwrap_or_default().to_string_lossy();
+    export::fill_pattern(pattern, &fields, &stem, &extension.to_ascii_lowercase())
+}
+
+/// `path`, or "<path> (2)" and so on if a file is already there
+fn vacant_path(path: PathBuf) -> PathBuf {
+    let mut candidate = path.clone();
+    let mut n = 2;
+    while candidate.exists() {
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let name = match path.extension() {
+            Some(extension) => format!("{stem} ({n}).{}", extension.to_string_lossy()),
+            None => format!("{stem} ({n})"),
+        };
+        candidate = path.with_file_name(name);
+        n += 1;
+    }
+    candidate
+}
+
+/// Move `from` to `to`, copying it across when they're on different file systems
+fn move_file(from: &Path, to: &Path) -> anyhow::Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create {}", parent.display()))?;
+    }
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    let partial = transcode::partial_path(to);
+    if let Err(e) = fs::copy(from, &partial).and_then(|_| fs::rename(&partial, to)) {
+        let _ = fs::remove_file(&partial);
+        return Err(e).with_context(|| format!("failed to move {}", from.display()));
+    }
+    fs::remove_file(from).with_context(|| format!("failed to remove {}", from.display()))
+}
+
+/// Remove folders below `folder` that importing left empty
+fn remove_empty_folders(folder: &Path) {
+    let folders = WalkDir::new(folder)
+        .min_depth(1)
+        .contents_first(true)
+        .into_iter()
+        .flatten()
+        .filter(|entry| entry.file_type().is_dir());
+    for entry in folders {
+        // Fails for the ones that aren't empty.
+        let _ = fs::remove_dir(entry.path());
+    }
+}
@@ -0,0 +1,266 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+
+use crate::fingerprint::Fingerprint;
+use crate::jobs::{self, Job};
+use crate::metadata::UNKNOWN_ARTIST;
+use crate::{
+    cue, library, waveform, DuplicateEvent, DuplicateGroup, DuplicateStrategy, JobKind, Song,
+    StreamSink,
+};
+
+/// Songs with matching tags are only duplicates if their lengths are this close
+const TAG_DURATION_TOLERANCE_SECS: u64 = 3;
+
+/// Songs further apart in length than this aren't compared by fingerprint
+const FINGERPRINT_DURATION_TOLERANCE_SECS: u64 = 10;
+
+/// Fingerprints sharing at least this share of bits are the same recording; unrelated
+/// audio lands around half
+const MIN_FINGERPRINT_SIMILARITY: f32 = 0.8;
+
+/// How far, in sub-fingerprints of about an eighth of a second, two prints are slid
+/// against each other to make up for differing leading silence
+const MAX_FINGERPRINT_OFFSET: usize = 40;
+
+/// Fingerprints have to overlap by at least this many sub-fingerprints to be compared
+const MIN_FINGERPRINT_OVERLAP: usize = 80;
+
+/// Queue a search for duplicates as a background job, returning its id for
+/// `jobs::cancel`
+pub(crate) fn start(strategy: DuplicateStrategy, sink: StreamSink<DuplicateEvent>) -> u32 {
+    jobs::submit(JobKind::Duplicates, move |job| {
+        let (event, result) = match find(strategy, &sink, job) {
+            _ if job.is_cancelled() => (DuplicateEvent::Cancelled, Ok(())),
+            Ok(groups) => (DuplicateEvent::Finished { groups }, Ok(())),
+            Err(e) => {
+                let message = format!("{e:#}");
+                (DuplicateEvent::Failed { message }, Err(e))
+            }
+        };
+        let _ = sink.add(event);
+        result
+    })
+}
+
+/// Reports progress through the sink, stopping the search if it's cancelled or the
+/// listener went away
+struct Progress<'a> {
+    sink: &'a StreamSink<DuplicateEvent>,
+    job: &'a Job,
+    total: u32,
+}
+
+impl Progress<'_> {
+    /// Report `checked` songs done; false once the search should stop
+    fn report(&self, checked: u32) -> bool {
+        let event = DuplicateEvent::Progress {
+            checked,
+            total: self.total,
+        };
+        if self.sink.add(event).is_err() {
+            self.job.cancelled().store(true, Ordering::Relaxed);
+        }
+        self.job.progress(checked as u64, Some(self.total as u64));
+        !self.job.is_cancelled()
+    }
+}
+
+fn find(
+    strategy: DuplicateStrategy,
+    sink: &StreamSink<DuplicateEvent>,
+    job: &Job,
+) -> anyhow::Result<Vec<DuplicateGroup>> {
+    let songs = library::with_library(|lib| lib.get_all_songs())?;
+    let progress = Progress {
+        sink,
+        job,
+        total: songs.len() as u32,
+    };
+    let groups = match strategy {
+        DuplicateStrategy::ByTags => by_tags(&songs, &progress),
+        DuplicateStrategy::ByAudioFingerprint => by_fingerprint(&songs, &progress),
+        DuplicateStrategy::ByFileHash => by_file_hash(&songs, &progress),
+    };
+    let mut groups: Vec<DuplicateGroup> = groups
+        .into_iter()
+        .filter(|group| group.len() > 1)
+        .map(|group| DuplicateGroup {
+            songs: group.into_iter().map(|i| songs[i].clone()).collect(),
+        })
+        .collect();
+    groups.sort_by_key(|group| group.songs[0].title.to_lowercase());
+    Ok(groups)
+}
+
+/// Lowercased letters and digits, so "Don't Stop" and "dont stop" match
+fn normalize(text: &str) -> String {
+    text.split_whitespace()
+        .map(|word| {
+            word.chars()
+                .filter(|c| c.is_alphanumeric())
+                .flat_map(char::to_lowercase)
+                .collect::<String>()
+        })
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Songs with the same title and artist and about the same length
+fn by_tags(songs: &[Song], progress: &Progress) -> Vec<Vec<usize>> {
+    let mut by_name: HashMap<(String, String), Vec<usize>> = HashMap::new();
+    for (i, song) in songs.iter().enumerate() {
+        // Untagged songs are titled after their file and would mostly match by accident.
+        if song.artist == UNKNOWN_ARTIST {
+            continue;
+        }
+        by_name
+            .entry((normalize(&song.title), normalize(&song.artist)))
+            .or_default()
+            .push(i);
+    }
+    progress.report(songs.len() as u32);
+
+    let mut groups = Vec::new();
+    for (_, mut indices) in by_name {
+        indices.sort_by_key(|&i| songs[i].duration);
+        let mut group: Vec<usize> = Vec::new();
+        for i in indices {
+            let last = group.last().map(|&last| songs[last].duration);
+            if last.is_some_and(|last| songs[i].duration - last > TAG_DURATION_TOLERANCE_SECS) {
+                groups.push(std::mem::take(&mut group));
+            }
+            group.push(i);
+        }
+        groups.push(group);
+    }
+    groups
+}
+
+/// Songs whose files have exactly the same contents
+fn by_file_hash(songs: &[Song], progress: &Progress) -> Vec<Vec<usize>> {
+    // Only files of equal size can match, so most never need hashing.
+    let mut by_size: HashMap<u64, Vec<usize>> = HashMap::new();
+    for (i, song) in songs.iter().enumerate() {
+        // CUE tracks share their image; comparing those would only find the sheet.
+        if cue::is_track(song) {
+            continue;
+        }
+        match fs::metadata(&song.file_path) {
+            Ok(metadata) => by_size.entry(metadata.len()).or_default().push(i),
+            Err(e) => log::debug!("skipping {} for duplicates: {e}", song.file_path),
+        }
+    }
+
+    let mut by_hash: HashMap<(u64, u64), Vec<usize>> = HashMap::new();
+    let mut checked = songs.len() - by_size.values().filter(|v| v.len() > 1).flatten().count();
+    for (size, indices) in by_size {
+        if indices.len() < 2 {
+            continue;
+        }
+        for i in indices {
+            match waveform::content_hash(Path::new(&songs[i].file_path)) {
+                Ok(hash) => by_hash.entry((size, hash)).or_default().push(i),
+                Err(e) => log::debug!("skipping {} for duplicates: {e}", songs[i].file_path),
+            }
+            checked += 1;
+            if !progress.report(checked as u32) {
+                return Vec::new();
+            }
+        }
+    }
+    by_hash.into_values().collect()
+}
+
+/// Songs that sound the same, however they were encoded or tagged
+fn by_fingerprint(songs: &[Song], progress: &Progress) -> Vec<Vec<usize>> {
+    let mut prints = Vec::new();
+    for (i, song) in songs.iter().enumerate() {
+        if !cue::is_track(song) {
+            match Fingerprint::compute(Path::new(&song.file_path)) {
+                Ok(print) => prints.push((i, print.raw)),
+                Err(e) => log::debug!("no fingerprint for {}: {e}", song.file_path),
+            }
+        }
+        if !progress.report(i as u32 + 1) {
+            return Vec::new();
+        }
+    }
+
+    prints.sort_by_key(|(i, _)| songs[*i].duration);
+    let mut groups = Groups::new(songs.len());
+    for (a, (i, print)) in prints.iter().enumerate() {
+        for (j, other) in &prints[a + 1..] {
+            if songs[*j].duration - songs[*i].duration > FINGERPRINT_DURATION_TOLERANCE_SECS {
+                break;
+            }
+            if similarity(print, other) >= MIN_FINGERPRINT_SIMILARITY {
+                groups.join(*i, *j);
+            }
+        }
+    }
+    groups.into_groups()
+}
+
+/// Share of bits two fingerprints have in common at their best alignment
+fn similarity(a: &[u32], b: &[u32]) -> f32 {
+    let mut best = 0.0f32;
+    for offset in 0..=MAX_FINGERPRINT_OFFSET {
+        for (a, b) in [(a.get(offset..), Some(b)), (Some(a), b.get(offset..))] {
+            let (Some(a), Some(b)) = (a, b) else {
+                continue;
+            };
+            let len = a.len().min(b.len());
+            if len < MIN_FINGERPRINT_OVERLAP {
+                continue;
+            }
+            let differing: u32 = a.iter().zip(b).map(|(x, y)| (x ^ y).count_ones()).sum();
+            best = best.max(1.0 - differing as f32 / (len * 32) as f32);
+        }
+    }
+    best
+}
+
+/// Union-find over song indices
+struct Groups {
+    parents: Vec<usize>,
+}
+
+impl Groups {
+    fn new(len: usize) -> Self {
+        Groups {
+            parents: (0..len).collect(),
+        }
+    }
+
+    fn root(&mut self, mut i: usize) -> usize {
+        while self.parents[i] != i {
+            self.parents[i] = self.parents[self.parents[i]];
+            i = self.parents[i];
+        }
+        i
+    }
+
+    fn join(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.root(a), self.root(b));
+        self.parents[b] = a;
+    }
+
+    fn into_groups(mut self) -> Vec<Vec<usize>> {
+        let mut groups: HashMap<usize, Vec<usize>> = HashMap::new();
+        for i in 0..self.parents.len() {
+            let root = self.root(i);
+            groups.entry(root).or_default().push(i);
+        }
+        groups
+            .into_values()
+            .map(|mut group| {
+                group.sort_unstable();
+                group
+            })
+            .collect()
+    }
+}
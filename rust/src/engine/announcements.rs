@@ -0,0 +1,197 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::SyncSender;
+
+use rodio::buffer::SamplesBuffer;
+
+use super::pipeline::{self, PipelineEvent, CHANNELS};
+use super::volume::VolumeRamp;
+use super::{AudioEngine, Command, EngineThread};
+use crate::{AnnouncementClip, AudioEvent, ClipTiming, Song, TunesError};
+
+/// Longest clip kept; announcements are meant to be short, and clips are held in memory
+const MAX_CLIP_SECS: u32 = 60;
+
+/// How far the music is lowered while a clip plays
+const DUCK_DB: f32 = 12.0;
+
+/// How long the music takes to duck before a clip and to come back after the last one
+const DUCK_FADE_MS: u32 = 250;
+
+/// A decoded clip waiting for its turn or playing
+pub(crate) struct Clip {
+    id: u32,
+    /// Interleaved stereo at the pipeline rate
+    samples: Vec<f32>,
+    cursor: usize,
+    started: bool,
+}
+
+impl Clip {
+    /// Decode `clip` at `sample_rate`, up to `MAX_CLIP_SECS`
+    fn decode(clip: AnnouncementClip, sample_rate: u32) -> anyhow::Result<Self> {
+        let source = match clip {
+            AnnouncementClip::File { path } => {
+                let song = Song {
+                    title: path.clone(),
+                    file_path: path,
+                    ..Song::default()
+                };
+                pipeline::open_source(&song, sample_rate)?
+            }
+            AnnouncementClip::Pcm {
+                samples,
+                sample_rate: rate,
+                channels,
+            } => {
+                anyhow::ensure!(
+                    rate > 0 && (1..=8).contains(&channels),
+                    TunesError::invalid_state(format!(
+                        "clips are one to eight channels at a non-zero rate, not {channels} at {rate} Hz"
+                    ))
+                );
+                pipeline::uniform(SamplesBuffer::new(channels, rate, samples), sample_rate)
+            }
+        };
+        let limit = (MAX_CLIP_SECS * sample_rate) as usize * CHANNELS as usize;
+        static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+        Ok(Clip {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            samples: source.take(limit).collect(),
+            cursor: 0,
+            started: false,
+        })
+    }
+}
+
+/// Clips mixed over the music, which is ducked while they play
+pub(crate) struct Announcer {
+    /// Clips to play now, in order; the front one is playing once the music is ducked
+    queue: VecDeque<Clip>,
+    /// Clips waiting for the next track to start
+    next_track: Vec<Clip>,
+    duck: VolumeRamp,
+    duck_gain: f32,
+    fade_frames: f32,
+}
+
+impl Announcer {
+    pub fn new(sample_rate: u32) -> Self {
+        Announcer {
+            queue: VecDeque::new(),
+            next_track: Vec::new(),
+            duck: VolumeRamp::new(sample_rate),
+            duck_gain: 10f32.powf(-DUCK_DB / 20.0),
+            fade_frames: (sample_rate * DUCK_FADE_MS / 1000) as f32,
+        }
+    }
+
+    fn push(&mut self, clip: Clip, timing: ClipTiming) {
+        match timing {
+            ClipTiming::Now => self.queue.push_back(clip),
+            ClipTiming::NextTrack => self.next_track.push(clip),
+        }
+        if !self.queue.is_empty() {
+            self.duck.ramp_to(self.duck_gain, self.fade_frames);
+        }
+    }
+
+    /// A new track started; clips held back for it start too
+    pub fn track_changed(&mut self) {
+        if self.next_track.is_empty() {
+            return;
+        }
+        self.queue.extend(self.next_track.drain(..));
+        self.duck.ramp_to(self.duck_gain, self.fade_frames);
+    }
+
+    /// Drop every clip, returning the id of the one playing, if any
+    fn clear(&mut self) -> Option<u32> {
+        self.next_track.clear();
+        let playing = self
+            .queue
+            .front()
+            .filter(|clip| clip.started)
+            .map(|clip| clip.id);
+        self.queue.clear();
+        self.duck.ramp_to(1.0, self.fade_frames);
+        playing
+    }
+
+    /// Duck the music in an interleaved stereo block and mix the playing clip in at
+    /// `volume`, reporting clips starting and ending on `events`
+    pub fn process(&mut self, block: &mut [f32], volume: f32, events: &SyncSender<PipelineEvent>) {
+        if self.queue.is_empty() && self.duck.gain() == 1.0 {
+            return;
+        }
+        self.duck.process(block, CHANNELS as usize);
+        // Clips start once the music is out of the way.
+        if self.duck.gain() > self.duck_gain {
+            return;
+        }
+        let mut out = block.iter_mut();
+        while let Some(clip) = self.queue.front_mut() {
+            if !clip.started {
+                clip.started = true;
+                let _ = events.try_send(PipelineEvent::ClipStarted(clip.id));
+            }
+            let rest = &clip.samples[clip.cursor..];
+            let mut mixed = 0;
+            for (out, &sample) in out.by_ref().zip(rest) {
+                *out += sample * volume;
+                mixed += 1;
+            }
+            clip.cursor += mixed;
+            if clip.cursor < clip.samples.len() {
+                break;
+            }
+            let _ = events.try_send(PipelineEvent::ClipFinished(clip.id));
+            self.queue.pop_front();
+            if self.queue.is_empty() {
+                self.duck.ramp_to(1.0, self.fade_frames);
+            }
+        }
+    }
+}
+
+impl AudioEngine {
+    /// Duck the music and play the audio file at `path` over it, returning an id that
+    /// `ClipStarted` and `ClipFinished` report it by
+    ///
+    /// Like `play_clip` with `ClipTiming::Now`.
+    pub fn duck_and_play_clip(&self, path: String) -> Result<u32, TunesError> {
+        self.play_clip(AnnouncementClip::File { path }, ClipTiming::Now)
+    }
+
+    /// Mix a short announcement, like a spoken "now playing", over the music, which is
+    /// lowered while it plays and restored after; returns an id that `ClipStarted` and
+    /// `ClipFinished` report it by
+    ///
+    /// Clips play one after the other at the playback volume, also while paused, and
+    /// are cut off after a minute. `NextTrack` holds a clip back until the next track
+    /// starts. Only heard on the device's own outputs, not on a cast device.
+    pub fn play_clip(&self, clip: AnnouncementClip, timing: ClipTiming) -> Result<u32, TunesError> {
+        let clip = Clip::decode(clip, self.sample_rate)?;
+        let id = clip.id;
+        self.send(Command::PlayClip { clip, timing });
+        Ok(id)
+    }
+
+    /// Drop the clip playing and any waiting, bringing the music back up
+    pub fn stop_clips(&self) {
+        self.send(Command::StopClips);
+    }
+}
+
+impl EngineThread {
+    pub(super) fn play_clip(&mut self, clip: Clip, timing: ClipTiming) {
+        self.player.lock().unwrap().announcer.push(clip, timing);
+    }
+
+    pub(super) fn stop_clips(&mut self) {
+        let playing = self.player.lock().unwrap().announcer.clear();
+        if let Some(id) = playing {
+            self.shared.events.emit(AudioEvent::ClipFinished { id });
+        }
+    }
+}
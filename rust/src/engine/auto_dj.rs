@@ -0,0 +1,184 @@
+use std::collections::HashSet;
+
+use rand::Rng;
+
+use super::{AudioEngine, Command, EngineThread};
+use crate::key::Key;
+use crate::library::{self, now_secs, Candidate};
+use crate::{AudioEvent, AutoDjParams, Song, SongId, TrackFeatures};
+
+/// Best matches each pick is drawn from, so the same song doesn't always lead on to
+/// the same next one
+const CHOICES: usize = 8;
+
+/// Tempo difference, in BPM, around which tempos stop sounding alike when no largest
+/// difference is set
+const TEMPO_TOLERANCE: f32 = 8.0;
+
+impl AudioEngine {
+    /// Keep the queue going with library songs like the ones before once fewer than
+    /// `params.min_upcoming` are left to play
+    ///
+    /// Songs are picked by tempo, key, energy and genre, favouring often played ones;
+    /// each batch added is announced with `AutoDjAdded`. A repeating queue never runs
+    /// low, and nothing is added while the library is closed.
+    pub fn set_auto_dj(&self, enabled: bool, params: AutoDjParams) {
+        self.send(Command::SetAutoDj(enabled.then_some(params)));
+    }
+}
+
+impl EngineThread {
+    pub(super) fn set_auto_dj(&mut self, params: Option<AutoDjParams>) {
+        self.auto_dj = params;
+        self.sync_next();
+    }
+
+    /// Add auto-DJ picks to the queue if it's running low while a song plays
+    pub(super) fn top_up_queue(&mut self) {
+        let Some(params) = self.auto_dj else {
+            return;
+        };
+        let Some(playing) = self.song() else {
+            return;
+        };
+        if !library::is_open() {
+            return;
+        }
+        let (seed, queued) = {
+            let queue = self.shared.queue.lock().unwrap();
+            let seed = match queue.remaining() {
+                Some(0) => playing.clone(),
+                Some(remaining) if remaining < params.min_upcoming as usize => {
+                    queue.last_in_order().cloned().unwrap_or(playing.clone())
+                }
+                _ => return,
+            };
+            let queued: HashSet<SongId> = queue
+                .songs()
+                .iter()
+                .chain([&playing])
+                .map(|song| song.id.clone())
+                .collect();
+            (seed, queued)
+        };
+        let picks = match pick(&seed, &queued, &params) {
+            Ok(picks) if !picks.is_empty() => picks,
+            Ok(_) => return,
+            Err(e) => {
+                log::warn!("auto-DJ couldn't pick songs: {e:#}");
+                return;
+            }
+        };
+        {
+            let mut queue = self.shared.queue.lock().unwrap();
+            for song in &picks {
+                queue.add(song.clone());
+            }
+        }
+        self.shared.emit_queue_changed();
+        self.shared
+            .events
+            .emit(AudioEvent::AutoDjAdded { songs: picks });
+    }
+}
+
+/// Up to `params.batch_size` library songs to follow `seed`, each chosen to follow
+/// the one before
+fn pick(seed: &Song, queued: &HashSet<SongId>, params: &AutoDjParams) -> anyhow::Result<Vec<Song>> {
+    let played_before = now_secs() - params.skip_played_within_hours as i64 * 3600;
+    let (mut candidates, seed_features) = library::with_library(|lib| {
+        Ok((
+            lib.get_auto_dj_candidates(played_before)?,
+            lib.get_features(&seed.id)?,
+        ))
+    })?;
+    candidates.retain(|candidate| !queued.contains(&candidate.song.id));
+    let most_played = candidates.iter().map(|c| c.play_count).max().unwrap_or(0);
+
+    let mut rng = rand::thread_rng();
+    let mut previous = (seed.clone(), seed_features);
+    let mut picks = Vec::new();
+    for _ in 0..params.batch_size {
+        let mut scored: Vec<(usize, f32)> = candidates
+            .iter()
+            .enumerate()
+            .filter_map(|(i, candidate)| {
+                let score = score(&previous.0, previous.1, candidate, most_played, params)?;
+                Some((i, score))
+            })
+            .collect();
+        scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+        scored.truncate(CHOICES);
+        let Some(&(best, _)) = scored.first() else {
+            break;
+        };
+        let mut roll = rng.gen::<f32>() * scored.iter().map(|&(_, score)| score).sum::<f32>();
+        let index = scored
+            .iter()
+            .find(|&&(_, score)| {
+                roll -= score;
+                roll <= 0.0
+            })
+            .map_or(best, |&(i, _)| i);
+        let chosen = candidates.swap_remove(index);
+        previous = (chosen.song.clone(), chosen.features);
+        picks.push(chosen.song);
+    }
+    Ok(picks)
+}
+
+/// How well `candidate` follows `previous`, from 0 to 1; `None` if `params` rule it
+/// out
+///
+/// Anything unknown about either song scores halfway.
+fn score(
+    previous: &Song,
+    features: Option<TrackFeatures>,
+    candidate: &Candidate,
+    most_played: u32,
+    params: &AutoDjParams,
+) -> Option<f32> {
+    let song = &candidate.song;
+    let tempo = match (previous.bpm, song.bpm) {
+        (Some(before), Some(bpm)) => {
+            // Half and double time mix as well as the same tempo.
+            let difference = [bpm, bpm * 2.0, bpm / 2.0]
+                .into_iter()
+                .map(|bpm| (before - bpm).abs())
+                .fold(f32::INFINITY, f32::min);
+            let limited = params.max_bpm_difference > 0.0;
+            if limited && difference > params.max_bpm_difference {
+                return None;
+            }
+            let tolerance = match limited {
+                true => params.max_bpm_difference,
+                false => TEMPO_TOLERANCE,
+            };
+            (-0.5 * (difference / tolerance).powi(2)).exp()
+        }
+        _ => 0.5,
+    };
+    let parse = |key: &Option<String>| key.as_deref().and_then(Key::parse);
+    let key = match (parse(&previous.key), parse(&song.key)) {
+        (Some(before), Some(key)) if before == key => 1.0,
+        (Some(before), Some(key)) if before.compatible().contains(&key) => 0.8,
+        (Some(_), Some(_)) if params.harmonic_only => return None,
+        (Some(_), Some(_)) => 0.0,
+        _ => 0.5,
+    };
+    let energy = match (features, candidate.features) {
+        (Some(before), Some(features)) => 1.0 - (before.energy - features.energy).abs(),
+        _ => 0.5,
+    };
+    let genre = match (&previous.genre, &song.genre) {
+        (Some(before), Some(genre)) if before.eq_ignore_ascii_case(genre) => 1.0,
+        (Some(_), Some(_)) => 0.0,
+        _ => 0.5,
+    };
+    let liked = match most_played {
+        0 => 0.0,
+        most => (candidate.play_count as f32).ln_1p() / (most as f32).ln_1p(),
+    };
+    let favorite = if song.favorite { 1.0 } else { 0.0 };
+    Some(0.25 * tempo + 0.2 * key + 0.2 * energy + 0.2 * genre + 0.1 * liked + 0.05 * favorite)
+}
@@ -0,0 +1,145 @@
+use std::collections::VecDeque;
+use std::time::SystemTime;
+
+use super::pipeline::CHANNELS;
+use super::{AudioEngine, Command, EngineThread};
+use crate::tempo::{self, OnsetDetector, ENVELOPE_RATE};
+use crate::AudioEvent;
+
+/// Seconds of onsets the tempo is estimated over
+const TEMPO_WINDOW_SECS: usize = 8;
+
+/// Onset values between tempo estimates, a second's worth
+const TEMPO_UPDATE_HOPS: usize = ENVELOPE_RATE as usize;
+
+/// Onset values the beat threshold is taken over, a second's worth
+const THRESHOLD_HOPS: usize = ENVELOPE_RATE as usize;
+
+/// Onsets must rise this many standard deviations above the recent mean to be beats
+const THRESHOLD_DEVIATIONS: f32 = 1.5;
+
+/// A beat heard in the output
+pub(crate) struct Beat {
+    /// How far the onset rose above the threshold, from 0 to 1
+    pub confidence: f32,
+    pub bpm: f32,
+    /// Track position of the block the beat is in
+    pub position_secs: f64,
+    /// When the end of that block is handed to the output device
+    pub output_at: SystemTime,
+}
+
+/// Beat tracker over the rendered output, estimating the tempo from the last few
+/// seconds and picking out onsets that stand out from their surroundings
+pub(crate) struct BeatTracker {
+    enabled: bool,
+    onsets: OnsetDetector,
+    envelope: VecDeque<f32>,
+    hops_since_estimate: usize,
+    bpm: Option<f32>,
+    hops_since_beat: usize,
+}
+
+impl BeatTracker {
+    pub fn new(sample_rate: u32) -> Self {
+        BeatTracker {
+            enabled: true,
+            onsets: OnsetDetector::new(sample_rate),
+            envelope: VecDeque::with_capacity(TEMPO_WINDOW_SECS * ENVELOPE_RATE as usize),
+            hops_since_estimate: 0,
+            bpm: None,
+            hops_since_beat: 0,
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.envelope.clear();
+            self.bpm = None;
+        }
+    }
+
+    /// Feed an interleaved block, returning the confidence and tempo of the strongest
+    /// beat in it, if any
+    pub fn push(&mut self, block: &[f32]) -> Option<(f32, f32)> {
+        if !self.enabled {
+            return None;
+        }
+        let mut beat: Option<(f32, f32)> = None;
+        for frame in block.chunks_exact(CHANNELS as usize) {
+            let mono = frame.iter().sum::<f32>() / CHANNELS as f32;
+            let Some(strength) = self.onsets.push(mono) else {
+                continue;
+            };
+            if let Some(found) = self.hop(strength) {
+                if beat.is_none_or(|(confidence, _)| found.0 > confidence) {
+                    beat = Some(found);
+                }
+            }
+        }
+        beat
+    }
+
+    fn hop(&mut self, strength: f32) -> Option<(f32, f32)> {
+        if self.envelope.len() == TEMPO_WINDOW_SECS * ENVELOPE_RATE as usize {
+            self.envelope.pop_front();
+        }
+        self.envelope.push_back(strength);
+        self.hops_since_beat += 1;
+        self.hops_since_estimate += 1;
+        if self.hops_since_estimate >= TEMPO_UPDATE_HOPS {
+            self.hops_since_estimate = 0;
+            self.bpm = tempo::estimate(self.envelope.make_contiguous());
+        }
+        let bpm = self.bpm?;
+
+        // The value before the latest one is a beat if it's a peak well above the others.
+        let count = self.envelope.len();
+        if count < THRESHOLD_HOPS + 2 {
+            return None;
+        }
+        let (before, peak, after) = (
+            self.envelope[count - 3],
+            self.envelope[count - 2],
+            self.envelope[count - 1],
+        );
+        if peak <= before || peak < after {
+            return None;
+        }
+        let recent = self.envelope.range(count - THRESHOLD_HOPS..);
+        let mean = recent.clone().sum::<f32>() / THRESHOLD_HOPS as f32;
+        let variance = recent.map(|v| (v - mean) * (v - mean)).sum::<f32>() / THRESHOLD_HOPS as f32;
+        let deviation = variance.sqrt();
+        let threshold = mean + THRESHOLD_DEVIATIONS * deviation;
+        if deviation == 0.0 || peak <= threshold {
+            return None;
+        }
+        // No two beats within half a beat of each other
+        let beat_hops = 60.0 * ENVELOPE_RATE as f32 / bpm;
+        if (self.hops_since_beat as f32) < beat_hops / 2.0 {
+            return None;
+        }
+        self.hops_since_beat = 1;
+        let confidence = ((peak - threshold) / (THRESHOLD_DEVIATIONS * deviation)).min(1.0);
+        Some((confidence, bpm))
+    }
+}
+
+impl AudioEngine {
+    /// Turn `BeatDetected` events on or off; on by default
+    pub fn set_beat_detection(&self, enabled: bool) {
+        self.send(Command::SetBeatDetection(enabled));
+    }
+}
+
+impl EngineThread {
+    pub(super) fn emit_beat(&self, beat: Beat) {
+        self.shared.events.emit(AudioEvent::BeatDetected {
+            confidence: beat.confidence,
+            bpm: beat.bpm,
+            position_secs: beat.position_secs,
+            presentation_time_ms: self.presentation_time_ms(beat.output_at),
+        });
+    }
+}
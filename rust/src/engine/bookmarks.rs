@@ -0,0 +1,93 @@
+use std::time::{Duration, Instant};
+
+use super::{AudioEngine, Command, EngineThread};
+use crate::{library, runtime, Song, SongId};
+
+/// Songs at least this long get their position remembered unless configured otherwise
+pub(super) const DEFAULT_MIN_DURATION_SECS: u64 = 20 * 60;
+
+/// How often the position of a playing song is saved
+const SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Positions this close to the start or end aren't worth resuming from
+const START_MARGIN_SECS: f64 = 5.0;
+const END_MARGIN_SECS: f64 = 30.0;
+
+impl AudioEngine {
+    /// Start `song` from the beginning even if it has a saved position, forgetting it
+    pub fn play_from_start(&self, song: Song) {
+        self.send(Command::Play {
+            song,
+            resume: false,
+        });
+    }
+
+    /// Remember playback positions of songs at least `secs` long (20 minutes by default)
+    ///
+    /// Positions are kept in the library database, so bookmarks need it to be open.
+    pub fn set_bookmark_min_duration(&self, secs: u64) {
+        self.send(Command::SetBookmarkMinDuration(secs));
+    }
+}
+
+impl EngineThread {
+    fn keeps_bookmark(&self, song: &Song) -> bool {
+        song.duration > 0 && song.duration >= self.bookmark_min_duration && library::is_open()
+    }
+
+    /// Position to resume `song` from, if one was saved
+    pub(super) fn bookmark_for(&self, song: &Song) -> Option<f64> {
+        if !self.keeps_bookmark(song) {
+            return None;
+        }
+        // The playing song's latest position may not have reached the database yet.
+        let position = if self.song().is_some_and(|current| current.id == song.id) {
+            self.shared.position_secs()
+        } else {
+            library::with_library(|lib| lib.get_bookmark(&song.id))
+                .inspect_err(|e| log::warn!("failed to read bookmark of {}: {e}", song.id))
+                .ok()??
+        };
+        let resumable = START_MARGIN_SECS..song.duration as f64 - END_MARGIN_SECS;
+        resumable.contains(&position).then_some(position)
+    }
+
+    /// Save where the current song is, or forget it once it is nearly done
+    pub(super) fn save_bookmark(&mut self) {
+        self.last_bookmark_save = Instant::now();
+        let Some(song) = self.song().filter(|song| self.keeps_bookmark(song)) else {
+            return;
+        };
+        let position = self.shared.position_secs();
+        if position >= song.duration as f64 - END_MARGIN_SECS {
+            self.clear_bookmark(&song);
+        } else if position >= START_MARGIN_SECS {
+            write_bookmark(song.id, Some(position));
+        }
+    }
+
+    pub(super) fn clear_bookmark(&self, song: &Song) {
+        if self.keeps_bookmark(song) {
+            write_bookmark(song.id.clone(), None);
+        }
+    }
+
+    pub(super) fn check_bookmark(&mut self) {
+        if self.is_playing() && self.last_bookmark_save.elapsed() >= SAVE_INTERVAL {
+            self.save_bookmark();
+        }
+    }
+}
+
+/// Store a bookmark off the audio thread
+fn write_bookmark(song_id: SongId, position: Option<f64>) {
+    runtime::spawn_blocking(move || {
+        let result = library::with_library(|lib| match position {
+            Some(position) => lib.set_bookmark(&song_id, position),
+            None => lib.clear_bookmark(&song_id),
+        });
+        if let Err(e) = result {
+            log::warn!("failed to save bookmark of {song_id}: {e}");
+        }
+    });
+}
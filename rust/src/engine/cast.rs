@@ -0,0 +1,525 @@
+use std::net::IpAddr;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+
+use super::{pipeline, AudioEngine, Command, EngineThread, Shared};
+use crate::cast::{self, CastTarget, MediaServer, TransportState};
+use crate::{cue, AudioEvent, CastDevice, PlaybackState, Song, TunesError};
+
+/// How often the device is asked where it is
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Polls in a row that may fail before the device counts as gone
+const MAX_FAILED_POLLS: u32 = 3;
+
+/// How long after a command the device's state may still lag behind it
+const SETTLE_TIME: Duration = Duration::from_secs(2);
+
+/// Volume steps devices round to, which aren't changes made on them
+const VOLUME_TOLERANCE: f32 = 0.01;
+
+/// Where the device was at its last report
+pub(super) struct CastStatus {
+    pub device: CastDevice,
+    pub position: f64,
+    pub duration: Option<f64>,
+    pub playing: bool,
+    pub updated: Instant,
+}
+
+impl CastStatus {
+    /// Position now, counting on from the last report while playing
+    fn position(&self) -> f64 {
+        let elapsed = match self.playing {
+            true => self.updated.elapsed().as_secs_f64(),
+            false => 0.0,
+        };
+        let position = self.position + elapsed;
+        self.duration
+            .map_or(position, |duration| position.min(duration))
+    }
+}
+
+enum CastCommand {
+    /// Positions count from the start of the song, CUE sheet tracks included
+    Load {
+        song: Box<Song>,
+        start_at: Option<f64>,
+        paused: bool,
+    },
+    Play,
+    Pause,
+    Stop,
+    Seek(f64),
+    SetVolume(f32),
+}
+
+enum CastEvent {
+    Progress {
+        position: f64,
+        duration: Option<f64>,
+        state: TransportState,
+        volume: Option<(f32, bool)>,
+        /// No command is still on its way, so the state is the device's own
+        settled: bool,
+    },
+    /// The device reached the end of the song
+    Ended,
+    Failed(String),
+}
+
+/// The device the engine is casting to, driven from a thread of its own so that slow
+/// devices don't hold up the engine
+pub(super) struct CastSession {
+    commands: Sender<CastCommand>,
+    events: Receiver<CastEvent>,
+}
+
+impl CastSession {
+    fn start(target: Box<dyn CastTarget>) -> anyhow::Result<Self> {
+        let (commands, commands_rx) = mpsc::channel();
+        let (events_tx, events) = mpsc::channel();
+        let ip = target.local_ip()?;
+        thread::Builder::new()
+            .name("tunes4r-cast".into())
+            .spawn(move || CastWorker::new(target, ip, events_tx).run(commands_rx))
+            .context("failed to spawn cast thread")?;
+        Ok(CastSession { commands, events })
+    }
+
+    fn send(&self, command: CastCommand) {
+        // A worker that has quit reports so through its events.
+        let _ = self.commands.send(command);
+    }
+}
+
+/// The song the device was given
+struct Loaded {
+    /// Where the song starts and ends in the file, for CUE sheet tracks
+    start: f64,
+    end: Option<f64>,
+    /// Stale states from the song before come until it first plays
+    started: bool,
+}
+
+struct CastWorker {
+    target: Box<dyn CastTarget>,
+    ip: IpAddr,
+    /// Started once a local file is cast
+    server: Option<MediaServer>,
+    events: Sender<CastEvent>,
+    loaded: Option<Loaded>,
+    failed_polls: u32,
+    last_command: Instant,
+}
+
+impl CastWorker {
+    fn new(target: Box<dyn CastTarget>, ip: IpAddr, events: Sender<CastEvent>) -> Self {
+        CastWorker {
+            target,
+            ip,
+            server: None,
+            events,
+            loaded: None,
+            failed_polls: 0,
+            last_command: Instant::now(),
+        }
+    }
+
+    fn run(mut self, commands: Receiver<CastCommand>) {
+        let mut last_poll = Instant::now();
+        loop {
+            let wait = POLL_INTERVAL.saturating_sub(last_poll.elapsed());
+            let result = match commands.recv_timeout(wait) {
+                Ok(command) => {
+                    self.last_command = Instant::now();
+                    self.handle(command)
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    last_poll = Instant::now();
+                    self.poll()
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    if let Err(e) = self.target.stop() {
+                        log::debug!("failed to stop {}: {e:#}", self.target.device().name);
+                    }
+                    return;
+                }
+            };
+            if let Err(e) = result {
+                let _ = self.events.send(CastEvent::Failed(format!("{e:#}")));
+                return;
+            }
+        }
+    }
+
+    fn handle(&mut self, command: CastCommand) -> anyhow::Result<()> {
+        let start = self.loaded.as_ref().map_or(0.0, |loaded| loaded.start);
+        match command {
+            CastCommand::Load {
+                song,
+                start_at,
+                paused,
+            } => self.load(&song, start_at, paused)?,
+            CastCommand::Play => self.target.play()?,
+            CastCommand::Pause => self.target.pause()?,
+            CastCommand::Stop => {
+                self.loaded = None;
+                self.target.stop()?;
+            }
+            CastCommand::Seek(position) => self.target.seek(start + position)?,
+            CastCommand::SetVolume(volume) => {
+                // Not every device has a volume of its own.
+                if let Err(e) = self.target.set_volume(volume) {
+                    log::warn!("{e:#}");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn load(&mut self, song: &Song, start_at: Option<f64>, paused: bool) -> anyhow::Result<()> {
+        // Stations and podcast episodes the device can fetch itself.
+        let (url, mime) =
+            match song.file_path.starts_with("http://") || song.file_path.starts_with("https://") {
+                true => (song.file_path.clone(), "audio/mpeg"),
+                false => {
+                    let server = match &mut self.server {
+                        Some(server) => server,
+                        server => server.insert(MediaServer::start()?),
+                    };
+                    server.url(self.ip, song)
+                }
+            };
+        let (start, end) = match cue::is_track(song) {
+            true => (song.start_offset, song.end_offset),
+            false => (0.0, None),
+        };
+        self.loaded = None;
+        let start_at = start + start_at.unwrap_or(0.0);
+        self.target.load(&url, mime, song, start_at, paused)?;
+        self.loaded = Some(Loaded {
+            start,
+            end,
+            started: false,
+        });
+        log::info!(
+            "casting {} to {}",
+            song.file_path,
+            self.target.device().name
+        );
+        Ok(())
+    }
+
+    /// Report where the device is, and when it got to the end of the song
+    fn poll(&mut self) -> anyhow::Result<()> {
+        let Some(loaded) = &mut self.loaded else {
+            return self.target.keep_alive();
+        };
+        let position = match self.target.position() {
+            Ok(position) => position,
+            Err(e) => {
+                self.failed_polls += 1;
+                return match self.failed_polls < MAX_FAILED_POLLS {
+                    true => Ok(()),
+                    false => Err(e.context(format!("lost {}", self.target.device().name))),
+                };
+            }
+        };
+        self.failed_polls = 0;
+        loaded.started |= matches!(
+            position.state,
+            TransportState::Playing | TransportState::Paused
+        );
+        // A CUE sheet track ends before its file does.
+        let past_end = loaded.end.is_some_and(|end| position.position >= end);
+        if loaded.started && (position.state == TransportState::Stopped || past_end) {
+            self.loaded = None;
+            if past_end {
+                self.target.stop()?;
+            }
+            let _ = self.events.send(CastEvent::Ended);
+            return Ok(());
+        }
+        let end = loaded.end.or(position.duration);
+        let _ = self.events.send(CastEvent::Progress {
+            position: (position.position - loaded.start).max(0.0),
+            duration: end.map(|end| (end - loaded.start).max(0.0)),
+            state: position.state,
+            volume: position.volume,
+            settled: self.last_command.elapsed() >= SETTLE_TIME,
+        });
+        Ok(())
+    }
+}
+
+impl AudioEngine {
+    /// Play through a DLNA renderer or Chromecast found by `discover_cast_devices`
+    /// instead of this device, carrying on from where playback is
+    ///
+    /// The queue plays on the cast device, and progress keeps coming as
+    /// `ProgressUpdated`; pauses and volume changes made on the device itself show up as
+    /// `PlaybackStateChanged` and `VolumeChanged`. Files are served to it from this
+    /// device; the equalizer and other effects, the playback rate and crossfades don't
+    /// apply while casting. Should the device stop answering, playback continues here,
+    /// paused.
+    pub fn cast_to(&self, device: CastDevice) -> Result<(), TunesError> {
+        let target = cast::connect(&device)?;
+        self.send(Command::CastTo(target));
+        Ok(())
+    }
+
+    /// Go back to playing on this device, from where the cast device was
+    pub fn stop_casting(&self) {
+        self.send(Command::StopCasting);
+    }
+
+    /// The device being cast to, if any
+    pub fn get_cast_device(&self) -> Option<CastDevice> {
+        let cast = self.shared.cast.lock().unwrap();
+        cast.as_ref().map(|cast| cast.device.clone())
+    }
+}
+
+impl Shared {
+    /// Position in the current track, on the cast device while casting
+    pub(super) fn position_secs(&self) -> f64 {
+        match &*self.cast.lock().unwrap() {
+            Some(cast) => cast.position(),
+            None => self.player.lock().unwrap().position_secs(),
+        }
+    }
+
+    /// Duration of the current track, if the decoder or cast device knows it
+    pub(super) fn duration_secs(&self) -> Option<f64> {
+        match &*self.cast.lock().unwrap() {
+            Some(cast) => cast.duration,
+            None => self.player.lock().unwrap().duration_secs(),
+        }
+    }
+
+    fn update_cast(&self, update: impl FnOnce(&mut CastStatus)) {
+        if let Some(cast) = &mut *self.cast.lock().unwrap() {
+            cast.position = cast.position();
+            cast.updated = Instant::now();
+            update(cast);
+        }
+    }
+}
+
+impl EngineThread {
+    pub(super) fn is_casting(&self) -> bool {
+        self.cast.is_some()
+    }
+
+    pub(super) fn start_cast(&mut self, target: Box<dyn CastTarget>) {
+        let device = target.device().clone();
+        let session = match CastSession::start(target) {
+            Ok(session) => session,
+            Err(e) => {
+                log::error!("failed to cast to {}: {e:#}", device.name);
+                return;
+            }
+        };
+        let position = self.shared.position_secs();
+        let duration = self.shared.duration_secs();
+        let volume = self.shared.volume.lock().unwrap().gain();
+        session.send(CastCommand::SetVolume(volume));
+        log::info!("casting to {}", device.name);
+        // A session with another device stops that one as it's dropped.
+        self.cast = Some(session);
+        *self.shared.cast.lock().unwrap() = Some(CastStatus {
+            device: device.clone(),
+            position,
+            duration,
+            playing: false,
+            updated: Instant::now(),
+        });
+        self.player.lock().unwrap().unload();
+        self.continue_playback(position, false);
+        self.shared.events.emit(AudioEvent::CastChanged {
+            device: Some(device),
+        });
+    }
+
+    /// Stop casting and carry on here, `paused` or in the state playback was in
+    pub(super) fn stop_cast(&mut self, paused: bool) {
+        if self.cast.is_none() {
+            return;
+        }
+        let position = self.shared.position_secs();
+        self.cast = None;
+        *self.shared.cast.lock().unwrap() = None;
+        self.continue_playback(position, paused);
+        self.shared
+            .events
+            .emit(AudioEvent::CastChanged { device: None });
+    }
+
+    /// Load the current song again at `position`, wherever output now goes
+    fn continue_playback(&mut self, position: f64, paused: bool) {
+        let state = self.state();
+        let Some(song) = self.song() else {
+            return;
+        };
+        let paused = match state {
+            PlaybackState::Paused => true,
+            PlaybackState::Playing | PlaybackState::Buffering { .. } => paused,
+            _ => return,
+        };
+        if song.file_path.starts_with("http://") || song.file_path.starts_with("https://") {
+            // Live streams can't be resumed where they were.
+            match paused {
+                true => self.stop(),
+                false => self.play_url(song.file_path),
+            }
+            return;
+        }
+        // The song is still the same listen.
+        let tracker = self.play_tracker.take();
+        let sample_rate = self.sample_rate;
+        self.start_playback(song, Some(position), paused, |song| {
+            pipeline::open_source(song, sample_rate)
+        });
+        if self.song().is_some() {
+            self.play_tracker = tracker;
+        }
+        self.sync_next();
+    }
+
+    /// Hand `song` to the cast device instead of the local pipeline
+    pub(super) fn cast_song(&mut self, song: Song, start_at: Option<f64>, paused: bool) {
+        let Some(session) = &self.cast else {
+            return;
+        };
+        session.send(CastCommand::Load {
+            song: Box::new(song.clone()),
+            start_at,
+            paused,
+        });
+        let duration = Some(song.duration as f64).filter(|&d| d > 0.0);
+        self.shared.update_cast(|cast| {
+            cast.position = start_at.unwrap_or(0.0);
+            cast.duration = duration;
+            cast.playing = false;
+        });
+    }
+
+    pub(super) fn cast_pause(&self, paused: bool) {
+        if let Some(session) = &self.cast {
+            session.send(match paused {
+                true => CastCommand::Pause,
+                false => CastCommand::Play,
+            });
+            self.shared.update_cast(|cast| cast.playing = !paused);
+        }
+    }
+
+    pub(super) fn cast_stop(&self) {
+        if let Some(session) = &self.cast {
+            session.send(CastCommand::Stop);
+            self.shared.update_cast(|cast| {
+                cast.position = 0.0;
+                cast.playing = false;
+            });
+        }
+    }
+
+    pub(super) fn cast_seek(&self, position_secs: f64) {
+        if let Some(session) = &self.cast {
+            session.send(CastCommand::Seek(position_secs));
+            self.shared.update_cast(|cast| {
+                cast.position = position_secs.max(0.0);
+            });
+        }
+    }
+
+    pub(super) fn cast_volume(&self, gain: f32) {
+        if let Some(session) = &self.cast {
+            session.send(CastCommand::SetVolume(gain));
+        }
+    }
+
+    /// Follow the device's reports: its position, when a song ended and whether it's
+    /// still there
+    pub(super) fn check_cast(&mut self) {
+        let Some(session) = &self.cast else {
+            return;
+        };
+        let mut events = Vec::new();
+        let lost = loop {
+            match session.events.try_recv() {
+                Ok(event) => events.push(event),
+                Err(TryRecvError::Empty) => break false,
+                Err(TryRecvError::Disconnected) => break true,
+            }
+        };
+        for event in events {
+            match event {
+                CastEvent::Progress {
+                    position,
+                    duration,
+                    state,
+                    volume,
+                    settled,
+                } => {
+                    self.shared.update_cast(|cast| {
+                        cast.position = position;
+                        cast.duration = duration.or(cast.duration);
+                        cast.playing = state == TransportState::Playing;
+                    });
+                    if settled {
+                        self.follow_device(state, volume);
+                    }
+                }
+                CastEvent::Ended => self.finish_track(),
+                CastEvent::Failed(message) => {
+                    log::error!("casting stopped: {message}");
+                    self.stop_cast(true);
+                    return;
+                }
+            }
+        }
+        if lost {
+            log::error!("casting stopped: cast thread quit");
+            self.stop_cast(true);
+        }
+    }
+
+    /// Take on pauses and volume changes made on the device itself, from its remote
+    /// or another app
+    fn follow_device(&mut self, state: TransportState, volume: Option<(f32, bool)>) {
+        match (self.state(), state) {
+            (PlaybackState::Playing, TransportState::Paused) => {
+                self.save_bookmark();
+                self.set_state(PlaybackState::Paused, self.song());
+            }
+            (PlaybackState::Paused, TransportState::Playing) => {
+                self.set_state(PlaybackState::Playing, self.song());
+            }
+            _ => {}
+        }
+        let Some((level, muted)) = volume else {
+            return;
+        };
+        let (gain, event) = {
+            let mut settings = self.shared.volume.lock().unwrap();
+            let gain = if muted { 0.0 } else { level };
+            if (settings.gain() - gain).abs() < VOLUME_TOLERANCE {
+                return;
+            }
+            settings.level = level.clamp(0.0, 1.0);
+            settings.muted = muted;
+            let event = AudioEvent::VolumeChanged {
+                volume: settings.level,
+                muted,
+            };
+            (settings.gain(), event)
+        };
+        self.player.lock().unwrap().volume.set_target(gain);
+        self.shared.events.emit(event);
+    }
+}
@@ -0,0 +1,50 @@
+use super::dsp_chain::Effect;
+use super::AudioEngine;
+use crate::{AudioEvent, ChannelMode};
+
+/// Maps the stereo channels onto the outputs as `ChannelMode` says
+pub(crate) struct ChannelMapper {
+    mode: ChannelMode,
+}
+
+impl ChannelMapper {
+    pub fn new() -> Self {
+        ChannelMapper {
+            mode: ChannelMode::Stereo,
+        }
+    }
+
+    pub fn set(&mut self, mode: ChannelMode) {
+        self.mode = mode;
+    }
+}
+
+impl Effect for ChannelMapper {
+    fn process(&mut self, block: &mut [f32], channels: usize) {
+        let frames = block.chunks_exact_mut(channels);
+        match self.mode {
+            ChannelMode::Stereo => {}
+            ChannelMode::Mono => {
+                for frame in frames {
+                    let mid = (frame[0] + frame[1]) * 0.5;
+                    frame[0] = mid;
+                    frame[1] = mid;
+                }
+            }
+            ChannelMode::SwapLR => frames.for_each(|frame| frame.swap(0, 1)),
+        }
+    }
+}
+
+impl AudioEngine {
+    /// Play in stereo, mix both channels to mono for listening with one ear, or swap
+    /// left and right
+    ///
+    /// Emits `ChannelModeChanged`.
+    pub fn set_channel_mode(&self, mode: ChannelMode) {
+        self.update_dsp(|settings| settings.channel_mode = mode);
+        self.shared
+            .events
+            .emit(AudioEvent::ChannelModeChanged { mode });
+    }
+}
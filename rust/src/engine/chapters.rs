@@ -0,0 +1,72 @@
+use std::path::Path;
+
+use super::{AudioEngine, Command, EngineThread};
+use crate::{chapters, cue, AudioEvent, Chapter, Song};
+
+/// Chapters of the current song and the one last announced
+pub(super) struct ChapterTracker {
+    chapters: Vec<Chapter>,
+    current: Option<usize>,
+}
+
+impl AudioEngine {
+    /// Jump to the start of chapter `index` of the current song
+    pub fn seek_to_chapter(&self, index: u32) {
+        self.send(Command::SeekToChapter(index));
+    }
+}
+
+impl EngineThread {
+    /// Pick up the chapter markers of a song that just started, if it has any
+    pub(super) fn load_chapters(&mut self, song: &Song) {
+        self.chapters = None;
+        let path = Path::new(&song.file_path);
+        if !path.is_file() || cue::is_track(song) {
+            return;
+        }
+        match chapters::read(path) {
+            Ok(chapters) if !chapters.is_empty() => {
+                self.chapters = Some(ChapterTracker {
+                    chapters,
+                    current: None,
+                });
+            }
+            Ok(_) => {}
+            Err(e) => log::debug!("no chapters for {}: {e}", song.file_path),
+        }
+    }
+
+    pub(super) fn seek_to_chapter(&mut self, index: u32) {
+        let start = self
+            .chapters
+            .as_ref()
+            .and_then(|tracker| tracker.chapters.get(index as usize))
+            .map(|chapter| chapter.start);
+        match start {
+            Some(start) => self.seek(start),
+            None => log::warn!("ignoring seek to missing chapter {index}"),
+        }
+    }
+
+    /// Emit `ChapterChanged` when the playback position crosses into another chapter
+    pub(super) fn check_chapters(&mut self) {
+        let Some(tracker) = &mut self.chapters else {
+            return;
+        };
+        let position = self.shared.position_secs();
+        let index = tracker
+            .chapters
+            .partition_point(|chapter| chapter.start <= position)
+            .checked_sub(1);
+        if index == tracker.current {
+            return;
+        }
+        tracker.current = index;
+        if let Some(index) = index {
+            self.shared.events.emit(AudioEvent::ChapterChanged {
+                index: index as u32,
+                title: tracker.chapters[index].title.clone(),
+            });
+        }
+    }
+}
@@ -0,0 +1,49 @@
+use std::f32::consts::FRAC_PI_2;
+
+use crate::FadeCurve;
+
+impl FadeCurve {
+    /// Gains for the (incoming, outgoing) tracks at fade progress `t` in 0.0..=1.0
+    pub(crate) fn gains(self, t: f32) -> (f32, f32) {
+        let t = t.clamp(0.0, 1.0);
+        match self {
+            FadeCurve::Linear => (t, 1.0 - t),
+            FadeCurve::EqualPower => ((t * FRAC_PI_2).sin(), (t * FRAC_PI_2).cos()),
+            FadeCurve::SCurve => {
+                let incoming = 0.5 - 0.5 * (t * std::f32::consts::PI).cos();
+                (incoming, 1.0 - incoming)
+            }
+        }
+    }
+}
+
+/// The outgoing side of a crossfade in progress
+pub(super) struct Fade<T> {
+    pub outgoing: T,
+    pub position: usize,
+    pub length: usize,
+}
+
+impl<T> Fade<T> {
+    /// Mix `outgoing` (same layout as `out`) into `out`, advancing the fade.
+    /// Returns false once the fade has completed.
+    pub fn mix(
+        &mut self,
+        curve: FadeCurve,
+        channels: usize,
+        out: &mut [f32],
+        outgoing: &[f32],
+    ) -> bool {
+        for (frame, old) in out
+            .chunks_exact_mut(channels)
+            .zip(outgoing.chunks_exact(channels))
+        {
+            let (gain_in, gain_out) = curve.gains(self.position as f32 / self.length as f32);
+            for (sample, old) in frame.iter_mut().zip(old) {
+                *sample = *sample * gain_in + old * gain_out;
+            }
+            self.position += 1;
+        }
+        self.position < self.length && outgoing.len() == out.len()
+    }
+}
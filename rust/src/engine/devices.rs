@@ -0,0 +1,297 @@
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use rodio::cpal::traits::HostTrait;
+use rodio::cpal::{SampleFormat, SampleRate, SupportedStreamConfig};
+use rodio::{cpal, DeviceTrait};
+
+use super::output::Output;
+use super::pipeline::{PipelineSource, CHANNELS};
+use super::{AudioEngine, Command, EngineThread};
+use crate::{AudioDevice, AudioEvent, OutputFormat, TunesError};
+
+/// How often the engine checks that an explicitly selected device is still present
+const DEVICE_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Rate the pipeline runs at when no device reports its own
+const FALLBACK_SAMPLE_RATE: u32 = 44100;
+
+/// Device sample formats in order of preference
+const SAMPLE_FORMATS: [SampleFormat; 3] = [SampleFormat::F32, SampleFormat::I32, SampleFormat::I16];
+
+/// Between the host and the device name in the ids of devices on other hosts than the
+/// default, like "JACK:system"
+const HOST_SEPARATOR: char = ':';
+
+/// Host whose devices are always held exclusively, bypassing any system mixer
+const EXCLUSIVE_HOST: &str = "ASIO";
+
+/// Rate the pipeline should render at: the default device's own, so that output
+/// doesn't have to convert it again
+pub(crate) fn preferred_sample_rate() -> u32 {
+    cpal::default_host()
+        .default_output_device()
+        .and_then(|device| device.default_output_config().ok())
+        .map_or(FALLBACK_SAMPLE_RATE, |config| config.sample_rate().0)
+}
+
+/// A stereo configuration of `device` running at `sample_rate`, if it has one
+pub(super) fn negotiate(device: &cpal::Device, sample_rate: u32) -> Option<SupportedStreamConfig> {
+    let configs: Vec<_> = device.supported_output_configs().ok()?.collect();
+    SAMPLE_FORMATS.iter().find_map(|&format| {
+        configs
+            .iter()
+            .filter(|c| c.channels() == CHANNELS && c.sample_format() == format)
+            .find_map(|c| c.try_with_sample_rate(SampleRate(sample_rate)))
+    })
+}
+
+fn default_device_name() -> Option<String> {
+    cpal::default_host()
+        .default_output_device()
+        .and_then(|d| d.name().ok())
+}
+
+/// Id of the device called `name` on `host`: just its name on the default host, so ids
+/// saved before other hosts were listed keep working
+fn device_id(host: cpal::HostId, name: &str) -> String {
+    match host == cpal::default_host().id() {
+        true => name.to_string(),
+        false => format!("{}{HOST_SEPARATOR}{name}", host.name()),
+    }
+}
+
+/// Host and device name a device id stands for
+fn split_id(id: &str) -> (cpal::HostId, &str) {
+    let default = cpal::default_host().id();
+    cpal::available_hosts()
+        .into_iter()
+        .filter(|&host| host != default)
+        .find_map(|host| {
+            let name = id.strip_prefix(host.name())?.strip_prefix(HOST_SEPARATOR)?;
+            Some((host, name))
+        })
+        .unwrap_or((default, id))
+}
+
+pub(super) fn find_device(id: &str) -> Option<cpal::Device> {
+    let (host, name) = split_id(id);
+    cpal::host_from_id(host)
+        .ok()?
+        .output_devices()
+        .ok()?
+        .find(|d| d.name().is_ok_and(|n| n == name))
+}
+
+/// Whether the device with `id` is on a host that holds it exclusively
+fn is_exclusive(id: &str) -> bool {
+    split_id(id).0.name() == EXCLUSIVE_HOST
+}
+
+/// Output devices of every audio host cpal was built with, the default host's first
+///
+/// Devices are identified by name, the only identifier cpal keeps stable across runs,
+/// prefixed by their host's unless it's the default. ASIO and JACK are only there when
+/// built with the `asio` or `jack` feature; a host that can't be reached, like JACK
+/// without its server running, is left out.
+pub(crate) fn list_output_devices() -> anyhow::Result<Vec<AudioDevice>> {
+    let default_host = cpal::default_host();
+    let default = default_device_name();
+    let mut devices: Vec<_> = default_host
+        .output_devices()?
+        .filter_map(|device| device.name().ok())
+        .map(|name| AudioDevice {
+            is_default: default.as_ref() == Some(&name),
+            id: name.clone(),
+            name,
+            host: default_host.id().name().to_string(),
+        })
+        .collect();
+    for host in cpal::available_hosts() {
+        if host == default_host.id() {
+            continue;
+        }
+        let found = cpal::host_from_id(host)
+            .map_err(anyhow::Error::from)
+            .and_then(|h| Ok(h.output_devices()?));
+        match found {
+            Ok(found) => {
+                devices.extend(found.filter_map(|device| device.name().ok()).map(|name| {
+                    AudioDevice {
+                        id: device_id(host, &name),
+                        name,
+                        is_default: false,
+                        host: host.name().to_string(),
+                    }
+                }))
+            }
+            Err(e) => log::debug!("{} output unavailable: {e:#}", host.name()),
+        }
+    }
+    Ok(devices)
+}
+
+fn describe(id: String) -> AudioDevice {
+    let (host, name) = split_id(&id);
+    AudioDevice {
+        is_default: host == cpal::default_host().id()
+            && default_device_name().as_deref() == Some(name),
+        name: name.to_string(),
+        host: host.name().to_string(),
+        id,
+    }
+}
+
+impl AudioEngine {
+    /// Rate, channels and sample format the output device is running at, or `None`
+    /// without any output
+    pub fn get_output_format(&self) -> Option<OutputFormat> {
+        self.shared.output_format.lock().unwrap().clone()
+    }
+
+    /// Route output to the device with `id`, or back to the system default for `None`
+    ///
+    /// Devices on ASIO are held exclusively, so `OutputFormat::exclusive` is set while
+    /// playing to one.
+    ///
+    /// If the device later disappears, output fails over to the default device and
+    /// `DeviceDisconnected` is emitted.
+    pub fn set_output_device(&self, id: Option<String>) -> Result<(), TunesError> {
+        if let Some(id) = &id {
+            if find_device(id).is_none() {
+                return Err(TunesError::device(format!("no output device named {id:?}")));
+            }
+        }
+        self.send(Command::SetOutputDevice(id.clone()));
+        self.shared.record_settings(|s| s.output_device = id);
+        Ok(())
+    }
+}
+
+impl EngineThread {
+    /// (Re)start the output stream on the device with id `device`, or the default device
+    /// for `None`
+    ///
+    /// The pipeline keeps its state, so playback continues where it was.
+    pub(super) fn open_output(&mut self, device: Option<&str>) {
+        // Release the old device before opening the new one; some backends allow only one.
+        self.output = None;
+        let device = device.and_then(|id| {
+            let found = find_device(id);
+            if found.is_none() {
+                log::warn!("output device {id:?} not found, using the default");
+            }
+            found.map(|found| (id, found))
+        });
+        let Some(target) = device
+            .as_ref()
+            .map(|(_, found)| found.clone())
+            .or_else(|| cpal::default_host().default_output_device())
+        else {
+            log::error!("no audio output available");
+            self.close_output();
+            return;
+        };
+        // Ask for the pipeline's own rate first; otherwise the output converts it again.
+        let negotiated = negotiate(&target, self.sample_rate).and_then(|config| {
+            match self.start_output(&target, &config) {
+                Ok(output) => Some((output, config)),
+                Err(e) => {
+                    log::warn!("failed to open output at {} Hz: {e:#}", self.sample_rate);
+                    None
+                }
+            }
+        });
+        let opened = match negotiated {
+            Some(opened) => Ok(opened),
+            None => target
+                .default_output_config()
+                .map_err(anyhow::Error::from)
+                .and_then(|config| Ok((self.start_output(&target, &config)?, config))),
+        };
+        let (output, config) = match opened {
+            Ok(opened) => opened,
+            Err(e) => {
+                log::error!("no audio output available: {e:#}");
+                self.close_output();
+                return;
+            }
+        };
+        let format = OutputFormat {
+            sample_rate: config.sample_rate().0,
+            channels: config.channels(),
+            sample_format: config.sample_format().to_string(),
+            engine_sample_rate: self.sample_rate,
+            exclusive: device.as_ref().is_some_and(|(id, _)| is_exclusive(id)),
+            bit_perfect: self.exclusive_mode && config.sample_rate().0 == self.sample_rate,
+            buffer_frames: (self.buffer_frames > 0).then_some(self.buffer_frames),
+        };
+        log::info!(
+            "output running at {} Hz, {} channels, {}",
+            format.sample_rate,
+            format.channels,
+            format.sample_format
+        );
+        *self.shared.output_format.lock().unwrap() = Some(format.clone());
+        self.shared
+            .events
+            .emit(AudioEvent::OutputFormatChanged { format });
+        self.output = Some(output);
+        self.output_device = device.map(|(id, _)| id.to_string());
+    }
+
+    fn start_output(
+        &self,
+        device: &cpal::Device,
+        config: &SupportedStreamConfig,
+    ) -> anyhow::Result<Output> {
+        let source = PipelineSource::new(self.player.clone(), self.sample_rate);
+        let latency = self.shared.output_latency.clone();
+        let meters = self.shared.meters.clone();
+        Output::open(device, config, self.buffer_frames, source, latency, meters)
+    }
+
+    fn close_output(&mut self) {
+        self.output_device = None;
+        *self.shared.output_format.lock().unwrap() = None;
+        self.shared.output_latency.store(0, Ordering::Relaxed);
+    }
+
+    pub(super) fn set_output_device(&mut self, id: Option<String>) {
+        self.open_output(id.as_deref());
+        let id = self.output_device.clone().or_else(default_device_name);
+        if let Some(id) = id {
+            self.shared.events.emit(AudioEvent::DeviceChanged {
+                device: describe(id),
+            });
+        }
+    }
+
+    /// Fail over to the default device when the selected one has gone away
+    pub(super) fn check_output_device(&mut self) {
+        if self.last_device_check.elapsed() < DEVICE_CHECK_INTERVAL {
+            return;
+        }
+        self.last_device_check = Instant::now();
+        self.fail_over_if_gone();
+    }
+
+    /// Move to the default device if the selected one has gone away; true if it had
+    pub(super) fn fail_over_if_gone(&mut self) -> bool {
+        let Some(id) = self.output_device.clone() else {
+            return false;
+        };
+        if find_device(&id).is_some() {
+            return false;
+        }
+        log::warn!("output device {id:?} disconnected");
+        self.shared.events.emit(AudioEvent::DeviceDisconnected {
+            device: AudioDevice {
+                is_default: false,
+                ..describe(id)
+            },
+        });
+        self.set_output_device(None);
+        true
+    }
+}
@@ -0,0 +1,50 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::Context;
+use serde_json::json;
+
+use super::{devices, AudioEngine};
+use crate::transcode::partial_path;
+use crate::{jobs, library, logging, LogLevel, TunesError};
+
+impl AudioEngine {
+    /// Write what a bug report needs to `path` as JSON: versions, output devices, the
+    /// engine's state and settings, background jobs and the recent log
+    ///
+    /// The queue is given by its length only, though the log may name songs.
+    pub fn export_diagnostics(&self, path: String) -> Result<(), TunesError> {
+        Ok(self.write_diagnostics(Path::new(&path))?)
+    }
+
+    fn write_diagnostics(&self, path: &Path) -> anyhow::Result<()> {
+        let mut state = serde_json::to_value(self.shared.snapshot())?;
+        if let Some(queue) = state.get_mut("queue") {
+            *queue = json!(queue.as_array().map_or(0, Vec::len));
+        }
+        let output_devices = match devices::list_output_devices() {
+            Ok(devices) => json!(devices),
+            Err(e) => json!(format!("{e:#}")),
+        };
+        let report = json!({
+            "app_version": env!("CARGO_PKG_VERSION"),
+            "os": std::env::consts::OS,
+            "arch": std::env::consts::ARCH,
+            "created_at": library::now_secs(),
+            "output_devices": output_devices,
+            "output_format": self.get_output_format(),
+            "output_latency_ms": self.get_output_latency_ms(),
+            "metrics": self.get_metrics(),
+            "settings": self.get_settings(),
+            "state": state,
+            "library_open": library::is_open(),
+            "jobs": jobs::list(),
+            "log_level": logging::level(),
+            "logs": logging::recent(LogLevel::Trace, usize::MAX),
+        });
+        let partial = partial_path(path);
+        fs::write(&partial, serde_json::to_vec_pretty(&report)?)
+            .and_then(|()| fs::rename(&partial, path))
+            .with_context(|| format!("failed to write {}", path.display()))
+    }
+}
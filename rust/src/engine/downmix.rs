@@ -0,0 +1,146 @@
+use std::f32::consts::FRAC_1_SQRT_2;
+use std::time::Duration;
+
+use rodio::source::SeekError;
+use rodio::Source;
+
+/// Weight of the LFE channel in each side of the downmix
+const LFE_WEIGHT: f32 = 0.5;
+
+/// Left and right weights of each speaker position
+const FRONT_LEFT: (f32, f32) = (1.0, 0.0);
+const FRONT_RIGHT: (f32, f32) = (0.0, 1.0);
+const CENTER: (f32, f32) = (FRAC_1_SQRT_2, FRAC_1_SQRT_2);
+const LFE: (f32, f32) = (LFE_WEIGHT, LFE_WEIGHT);
+const REAR_LEFT: (f32, f32) = (FRAC_1_SQRT_2, 0.0);
+const REAR_RIGHT: (f32, f32) = (0.0, FRAC_1_SQRT_2);
+const REAR_CENTER: (f32, f32) = (0.5, 0.5);
+
+/// Speaker positions for each channel count, in the WAVE/FLAC channel order
+fn layout(channels: usize) -> Vec<(f32, f32)> {
+    match channels {
+        3 => vec![FRONT_LEFT, FRONT_RIGHT, CENTER],
+        4 => vec![FRONT_LEFT, FRONT_RIGHT, REAR_LEFT, REAR_RIGHT],
+        5 => vec![FRONT_LEFT, FRONT_RIGHT, CENTER, REAR_LEFT, REAR_RIGHT],
+        6 => vec![FRONT_LEFT, FRONT_RIGHT, CENTER, LFE, REAR_LEFT, REAR_RIGHT],
+        7 => vec![
+            FRONT_LEFT,
+            FRONT_RIGHT,
+            CENTER,
+            LFE,
+            REAR_CENTER,
+            REAR_LEFT,
+            REAR_RIGHT,
+        ],
+        8 => vec![
+            FRONT_LEFT,
+            FRONT_RIGHT,
+            CENTER,
+            LFE,
+            REAR_LEFT,
+            REAR_RIGHT,
+            REAR_LEFT,
+            REAR_RIGHT,
+        ],
+        // Unknown layouts: keep the front pair and spread the rest over both sides.
+        _ => (0..channels)
+            .map(|channel| match channel {
+                0 => FRONT_LEFT,
+                1 => FRONT_RIGHT,
+                _ => REAR_CENTER,
+            })
+            .collect(),
+    }
+}
+
+/// Weights of `layout(channels)`, scaled so neither side can exceed full scale
+fn weights(channels: usize) -> Vec<(f32, f32)> {
+    let layout = layout(channels);
+    let left: f32 = layout.iter().map(|w| w.0).sum();
+    let right: f32 = layout.iter().map(|w| w.1).sum();
+    layout
+        .into_iter()
+        .map(|(l, r)| (l / left, r / right))
+        .collect()
+}
+
+/// Mixes sources with more than two channels down to stereo, weighting the center,
+/// LFE and surround channels instead of dropping them
+///
+/// Mono and stereo pass through untouched.
+pub(crate) struct Downmix<S> {
+    source: S,
+    /// Weights for the channel count they were made for
+    weights: Vec<(f32, f32)>,
+    /// Right sample of the last mixed frame, still to be returned
+    right: Option<f32>,
+}
+
+impl<S: Source<Item = f32>> Downmix<S> {
+    pub fn new(source: S) -> Self {
+        Downmix {
+            source,
+            weights: Vec::new(),
+            right: None,
+        }
+    }
+}
+
+impl<S: Source<Item = f32>> Iterator for Downmix<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if let Some(right) = self.right.take() {
+            return Some(right);
+        }
+        let channels = self.source.channels() as usize;
+        if channels <= 2 {
+            return self.source.next();
+        }
+        if self.weights.len() != channels {
+            self.weights = weights(channels);
+        }
+        let (mut left, mut right) = (0.0, 0.0);
+        for &(l, r) in &self.weights {
+            let sample = self.source.next()?;
+            left += sample * l;
+            right += sample * r;
+        }
+        self.right = Some(right);
+        Some(left)
+    }
+}
+
+impl<S: Source<Item = f32>> Source for Downmix<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        let channels = self.source.channels() as usize;
+        let pending = self.right.is_some() as usize;
+        match channels {
+            0..=2 => self.source.current_frame_len().map(|len| len + pending),
+            _ => self
+                .source
+                .current_frame_len()
+                .map(|len| len / channels * 2 + pending),
+        }
+    }
+
+    fn channels(&self) -> u16 {
+        match self.right {
+            Some(_) => 2,
+            None => self.source.channels().min(2),
+        }
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.source.total_duration()
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.right = None;
+        self.source.try_seek(pos)
+    }
+}
@@ -0,0 +1,120 @@
+use super::eq::Equalizer;
+use super::preamp::GainStage;
+use super::{AudioEngine, Command};
+use crate::{AudioEvent, DspStage, DspStageInfo, TunesError};
+
+/// Stages in the order audio passes through them
+pub(super) const ORDER: [DspStage; 11] = [
+    DspStage::Resampler,
+    DspStage::PauseSkipping,
+    DspStage::Equalizer,
+    DspStage::TimeStretch,
+    DspStage::Normalization,
+    DspStage::Reverb,
+    DspStage::StereoWidener,
+    DspStage::ChannelMode,
+    DspStage::Compressor,
+    DspStage::Preamp,
+    DspStage::Limiter,
+];
+
+/// Samples below this level pass the limiter untouched
+const LIMIT_THRESHOLD: f32 = 0.9;
+
+/// Which stages are bypassed, mirrored on the FFI side like the volume
+#[derive(Clone, Copy, Default, PartialEq)]
+pub(crate) struct DspChain {
+    bypassed: [bool; ORDER.len()],
+}
+
+impl DspChain {
+    pub fn is_enabled(&self, stage: DspStage) -> bool {
+        !self.bypassed[stage as usize]
+    }
+
+    fn set_enabled(&mut self, stage: DspStage, enabled: bool) {
+        self.bypassed[stage as usize] = !enabled;
+    }
+
+    /// Stages before and after the time-stretcher, which runs on its own
+    pub fn around_stretch() -> (&'static [DspStage], &'static [DspStage]) {
+        let stretch = DspStage::TimeStretch as usize;
+        (&ORDER[..stretch], &ORDER[stretch + 1..])
+    }
+
+    pub fn stages(&self) -> Vec<DspStageInfo> {
+        ORDER
+            .iter()
+            .map(|&stage| DspStageInfo {
+                stage,
+                enabled: self.is_enabled(stage),
+                per_track: matches!(
+                    stage,
+                    DspStage::Resampler | DspStage::PauseSkipping | DspStage::Normalization
+                ),
+            })
+            .collect()
+    }
+}
+
+/// A stage that processes interleaved blocks in place
+pub(super) trait Effect: Send {
+    fn process(&mut self, block: &mut [f32], channels: usize);
+}
+
+impl Effect for Equalizer {
+    fn process(&mut self, block: &mut [f32], _channels: usize) {
+        Equalizer::process(self, block);
+    }
+}
+
+impl Effect for GainStage {
+    fn process(&mut self, block: &mut [f32], channels: usize) {
+        GainStage::process(self, block, channels);
+    }
+}
+
+/// Soft clipper at the end of the chain, bending peaks smoothly towards full scale
+/// instead of letting them clip hard
+pub(super) struct Limiter;
+
+impl Effect for Limiter {
+    fn process(&mut self, block: &mut [f32], _channels: usize) {
+        let headroom = 1.0 - LIMIT_THRESHOLD;
+        for sample in block.iter_mut() {
+            let level = sample.abs();
+            if level > LIMIT_THRESHOLD {
+                let bent =
+                    LIMIT_THRESHOLD + headroom * ((level - LIMIT_THRESHOLD) / headroom).tanh();
+                *sample = bent.copysign(*sample);
+            }
+        }
+    }
+}
+
+impl AudioEngine {
+    /// Playback stages in the order audio passes through them, and whether each is on
+    pub fn get_dsp_chain(&self) -> Vec<DspStageInfo> {
+        self.shared.dsp_chain.lock().unwrap().stages()
+    }
+
+    /// Bypass a stage or bring it back, keeping its settings either way
+    ///
+    /// The resampler can't be bypassed, since the output runs at a fixed rate. Emits
+    /// `DspChainChanged`.
+    pub fn set_stage_enabled(&self, stage: DspStage, enabled: bool) -> Result<(), TunesError> {
+        if stage == DspStage::Resampler && !enabled {
+            return Err(TunesError::invalid_state("the resampler can't be bypassed"));
+        }
+        let chain = {
+            let mut chain = self.shared.dsp_chain.lock().unwrap();
+            chain.set_enabled(stage, enabled);
+            *chain
+        };
+        self.send(Command::SetDspChain(chain));
+        self.shared.events.emit(AudioEvent::DspChainChanged {
+            stages: chain.stages(),
+        });
+        Ok(())
+    }
+}
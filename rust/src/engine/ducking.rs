@@ -0,0 +1,40 @@
+use std::time::Duration;
+
+use super::{AudioEngine, Command, EngineThread};
+
+/// How long ducking takes to lower or restore the volume unless `set_ducking_fade` says otherwise
+pub(super) const DEFAULT_FADE: Duration = Duration::from_millis(300);
+
+/// Most ducking can lower the volume by; beyond this it might as well pause
+const MAX_ATTENUATION_DB: f32 = 60.0;
+
+impl AudioEngine {
+    /// Lower the output by `attenuation_db` while `active`, for when the OS reports a
+    /// transient loss of audio focus (navigation prompts, notifications), and restore it after
+    ///
+    /// Both directions fade over the time set by `set_ducking_fade`. Separate from the
+    /// volume, so volume changes while ducked neither undo it nor get lost.
+    pub fn set_ducking(&self, active: bool, attenuation_db: f32) {
+        let gain = if active {
+            let attenuation_db = attenuation_db.abs().min(MAX_ATTENUATION_DB);
+            10f32.powf(-attenuation_db / 20.0)
+        } else {
+            1.0
+        };
+        self.send(Command::SetDucking(gain));
+    }
+
+    /// How long ducking takes to fade the volume down and back up; 300 ms by default
+    pub fn set_ducking_fade(&self, fade_ms: u32) {
+        self.send(Command::SetDuckingFade(Duration::from_millis(
+            fade_ms.into(),
+        )));
+    }
+}
+
+impl EngineThread {
+    pub(super) fn set_ducking(&mut self, gain: f32) {
+        let frames = self.ducking_fade.as_secs_f32() * self.sample_rate as f32;
+        self.player.lock().unwrap().duck.ramp_to(gain, frames);
+    }
+}
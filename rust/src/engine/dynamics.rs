@@ -0,0 +1,181 @@
+use super::dsp_chain::Effect;
+use super::AudioEngine;
+use crate::{AudioEvent, Dynamics, DynamicsSettings};
+
+/// Width of the soft knee around the threshold, in dB
+const KNEE_DB: f32 = 6.0;
+
+/// How quickly the limiter lets go again after holding down a peak
+const LIMITER_RELEASE_MS: f32 = 50.0;
+
+/// Length of the ramp applied when the settings change, so makeup gain doesn't jump
+const RAMP_MS: f32 = 20.0;
+
+/// Detector floor, well below anything audible
+const SILENCE_DB: f32 = -120.0;
+
+impl Dynamics {
+    /// The compressor settings this stands for; `None` when off
+    pub(crate) fn settings(self) -> Option<DynamicsSettings> {
+        let preset = |threshold_db, ratio, attack_ms, release_ms, makeup_db, ceiling_db| {
+            Some(DynamicsSettings {
+                threshold_db,
+                ratio,
+                attack_ms,
+                release_ms,
+                makeup_db,
+                ceiling_db,
+            })
+        };
+        match self {
+            Dynamics::Off => None,
+            Dynamics::NightMode => preset(-30.0, 3.0, 10.0, 300.0, 8.0, -1.0),
+            Dynamics::VoiceBoost => preset(-32.0, 4.0, 5.0, 150.0, 12.0, -1.0),
+            Dynamics::Car => preset(-24.0, 6.0, 5.0, 120.0, 10.0, -0.5),
+            Dynamics::Custom { settings } => Some(settings),
+        }
+    }
+
+    /// With custom settings brought into range
+    fn clamped(self) -> Self {
+        match self {
+            Dynamics::Custom { settings } => Dynamics::Custom {
+                settings: DynamicsSettings {
+                    threshold_db: settings.threshold_db.clamp(-60.0, 0.0),
+                    ratio: settings.ratio.clamp(1.0, 20.0),
+                    attack_ms: settings.attack_ms.clamp(0.1, 200.0),
+                    release_ms: settings.release_ms.clamp(10.0, 2000.0),
+                    makeup_db: settings.makeup_db.clamp(0.0, 24.0),
+                    ceiling_db: settings.ceiling_db.clamp(-12.0, 0.0),
+                },
+            },
+            preset => preset,
+        }
+    }
+}
+
+/// `DynamicsSettings` turned into what the compressor works with per frame
+#[derive(Clone, Copy)]
+struct Params {
+    threshold_db: f32,
+    /// Gain change per dB over the threshold, from 0 (none) to nearly -1
+    slope: f32,
+    attack: f32,
+    release: f32,
+    makeup_db: f32,
+    ceiling: f32,
+}
+
+/// Factor of a one-pole smoother that covers most of the way in `ms`
+fn coefficient(ms: f32, sample_rate: u32) -> f32 {
+    (-1000.0 / (ms * sample_rate as f32)).exp()
+}
+
+/// A stereo-linked feed-forward compressor followed by a peak limiter with no
+/// lookahead, which never lets a sample past the ceiling
+pub(crate) struct Compressor {
+    sample_rate: u32,
+    params: Option<Params>,
+    /// Level the compressor is reacting to, in dBFS
+    envelope_db: f32,
+    /// Compressor and makeup gain applied to the last frame, in dB
+    gain_db: f32,
+    limiter_gain: f32,
+    ramp: f32,
+    limiter_release: f32,
+}
+
+impl Compressor {
+    pub fn new(sample_rate: u32) -> Self {
+        Compressor {
+            sample_rate,
+            params: None,
+            envelope_db: SILENCE_DB,
+            gain_db: 0.0,
+            limiter_gain: 1.0,
+            ramp: coefficient(RAMP_MS, sample_rate),
+            limiter_release: coefficient(LIMITER_RELEASE_MS, sample_rate),
+        }
+    }
+
+    pub fn set(&mut self, dynamics: Dynamics) {
+        self.params = dynamics.settings().map(|settings| Params {
+            threshold_db: settings.threshold_db,
+            slope: 1.0 / settings.ratio - 1.0,
+            attack: coefficient(settings.attack_ms, self.sample_rate),
+            release: coefficient(settings.release_ms, self.sample_rate),
+            makeup_db: settings.makeup_db,
+            ceiling: 10f32.powf(settings.ceiling_db / 20.0),
+        });
+    }
+
+    /// Gain change for a level of `level_db`, bending into the ratio over the knee
+    fn reduction_db(params: &Params, level_db: f32) -> f32 {
+        let over = level_db - params.threshold_db;
+        if 2.0 * over <= -KNEE_DB {
+            0.0
+        } else if 2.0 * over < KNEE_DB {
+            params.slope * (over + KNEE_DB / 2.0).powi(2) / (2.0 * KNEE_DB)
+        } else {
+            params.slope * over
+        }
+    }
+}
+
+impl Effect for Compressor {
+    /// Compress an interleaved block in place; once off and settled it is left bit
+    /// for bit
+    fn process(&mut self, block: &mut [f32], channels: usize) {
+        if self.params.is_none() && self.gain_db.abs() < 0.01 && self.limiter_gain == 1.0 {
+            self.gain_db = 0.0;
+            self.envelope_db = SILENCE_DB;
+            return;
+        }
+        for frame in block.chunks_exact_mut(channels) {
+            let peak = frame.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+            let (target_db, ceiling) = match &self.params {
+                Some(params) => {
+                    let level_db = (20.0 * peak.log10()).max(SILENCE_DB);
+                    let factor = match level_db > self.envelope_db {
+                        true => params.attack,
+                        false => params.release,
+                    };
+                    self.envelope_db = level_db + (self.envelope_db - level_db) * factor;
+                    let gain_db = Self::reduction_db(params, self.envelope_db) + params.makeup_db;
+                    (gain_db, params.ceiling)
+                }
+                None => (0.0, f32::INFINITY),
+            };
+            self.gain_db = target_db + (self.gain_db - target_db) * self.ramp;
+            let gain = 10f32.powf(self.gain_db / 20.0);
+
+            // Recover from the last peak, but never so far that this one gets past.
+            let allowed = match peak * gain > ceiling {
+                true => ceiling / (peak * gain),
+                false => 1.0,
+            };
+            let recovered = 1.0 + (self.limiter_gain - 1.0) * self.limiter_release;
+            self.limiter_gain = recovered.min(allowed);
+            if self.limiter_gain > 0.9999 {
+                self.limiter_gain = 1.0;
+            }
+            let gain = gain * self.limiter_gain;
+            frame.iter_mut().for_each(|sample| *sample *= gain);
+        }
+    }
+}
+
+impl AudioEngine {
+    /// Even out loud and quiet passages with one of the presets or custom settings, so
+    /// quiet dialogue is heard at low volume, ending in a limiter so the boost doesn't
+    /// clip small speakers
+    ///
+    /// `Off` turns it off. Emits `DynamicsChanged`.
+    pub fn set_dynamics(&self, dynamics: Dynamics) {
+        let dynamics = dynamics.clamped();
+        self.update_dsp(|settings| settings.dynamics = dynamics);
+        self.shared
+            .events
+            .emit(AudioEvent::DynamicsChanged { dynamics });
+    }
+}
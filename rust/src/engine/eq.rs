@@ -0,0 +1,219 @@
+use std::f32::consts::PI;
+
+use crate::TunesError;
+
+/// Center frequencies of the graphic bands, in Hz
+pub(crate) const BAND_FREQUENCIES: [f32; 10] = [
+    31.0, 62.0, 125.0, 250.0, 500.0, 1000.0, 2000.0, 4000.0, 8000.0, 16000.0,
+];
+
+/// Gains are clamped to +/- this many dB
+pub(crate) const MAX_GAIN_DB: f32 = 12.0;
+
+/// Bandwidth of each peaking filter
+const BAND_Q: f32 = 1.41;
+
+/// Largest gain change applied per rendered block, so edits ramp instead of stepping
+const MAX_STEP_DB: f32 = 0.5;
+
+/// Built-in presets, as gains for each of `BAND_FREQUENCIES`
+pub(crate) const PRESETS: &[(&str, [f32; 10])] = &[
+    ("Flat", [0.0; 10]),
+    (
+        "Bass Boost",
+        [6.0, 5.0, 4.0, 2.0, 0.5, 0.0, 0.0, 0.0, 0.0, 0.0],
+    ),
+    (
+        "Treble Boost",
+        [0.0, 0.0, 0.0, 0.0, 0.0, 0.5, 2.0, 4.0, 5.0, 6.0],
+    ),
+    (
+        "Rock",
+        [4.5, 3.5, 2.0, -0.5, -1.5, -1.0, 1.0, 2.5, 3.5, 4.0],
+    ),
+    (
+        "Pop",
+        [-1.0, 0.5, 2.0, 3.5, 4.0, 3.0, 1.0, -0.5, -1.0, -1.0],
+    ),
+    ("Jazz", [3.0, 2.0, 1.0, 1.5, -1.5, -1.5, 0.0, 1.0, 2.0, 3.0]),
+    (
+        "Classical",
+        [4.0, 3.0, 2.0, 1.0, -1.0, -1.0, 0.0, 2.0, 3.0, 3.5],
+    ),
+    (
+        "Electronic",
+        [4.5, 4.0, 1.5, 0.0, -2.0, 1.5, 0.5, 1.5, 4.0, 5.0],
+    ),
+    (
+        "Vocal",
+        [-2.0, -2.5, -2.0, 1.0, 3.5, 3.5, 3.0, 1.5, 0.0, -1.5],
+    ),
+    (
+        "Loudness",
+        [5.0, 3.5, 0.0, 0.0, -1.5, 0.0, -0.5, -3.0, 3.5, 1.0],
+    ),
+];
+
+/// Second-order IIR section (transposed direct form II)
+#[derive(Clone, Copy, Default)]
+struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    z1: f32,
+    z2: f32,
+}
+
+impl Biquad {
+    /// RBJ cookbook peaking filter; keeps the filter state so updates don't click
+    fn set_peaking(&mut self, sample_rate: u32, frequency: f32, q: f32, gain_db: f32) {
+        let a = 10f32.powf(gain_db / 40.0);
+        let w0 = 2.0 * PI * frequency.min(sample_rate as f32 * 0.45) / sample_rate as f32;
+        let alpha = w0.sin() / (2.0 * q);
+        let cos_w0 = w0.cos();
+        let a0 = 1.0 + alpha / a;
+        self.b0 = (1.0 + alpha * a) / a0;
+        self.b1 = -2.0 * cos_w0 / a0;
+        self.b2 = (1.0 - alpha * a) / a0;
+        self.a1 = -2.0 * cos_w0 / a0;
+        self.a2 = (1.0 - alpha / a) / a0;
+    }
+
+    #[inline]
+    fn process(&mut self, x: f32) -> f32 {
+        let y = self.b0 * x + self.z1;
+        self.z1 = self.b1 * x - self.a1 * y + self.z2;
+        self.z2 = self.b2 * x - self.a2 * y;
+        y
+    }
+}
+
+/// Graphic equalizer built from one peaking filter per band and channel
+pub(crate) struct Equalizer {
+    sample_rate: u32,
+    channels: usize,
+    enabled: bool,
+    target_db: [f32; 10],
+    current_db: [f32; 10],
+    filters: Vec<Biquad>,
+}
+
+impl Equalizer {
+    pub fn new(sample_rate: u32, channels: usize) -> Self {
+        let mut eq = Equalizer {
+            sample_rate,
+            channels,
+            enabled: false,
+            target_db: [0.0; 10],
+            current_db: [0.0; 10],
+            filters: vec![Biquad::default(); BAND_FREQUENCIES.len() * channels],
+        };
+        for band in 0..BAND_FREQUENCIES.len() {
+            eq.update_band(band);
+        }
+        eq
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Gain each band is set to, in dB, even while still ramping towards it
+    pub fn gains(&self) -> [f32; 10] {
+        self.target_db
+    }
+
+    pub fn set_band(&mut self, index: usize, gain_db: f32) {
+        self.target_db[index] = gain_db.clamp(-MAX_GAIN_DB, MAX_GAIN_DB);
+    }
+
+    pub fn set_all(&mut self, gains_db: [f32; 10]) {
+        for (index, gain_db) in gains_db.into_iter().enumerate() {
+            self.set_band(index, gain_db);
+        }
+    }
+
+    fn update_band(&mut self, band: usize) {
+        for channel in 0..self.channels {
+            self.filters[band * self.channels + channel].set_peaking(
+                self.sample_rate,
+                BAND_FREQUENCIES[band],
+                BAND_Q,
+                self.current_db[band],
+            );
+        }
+    }
+
+    /// Filter an interleaved block in place
+    pub fn process(&mut self, block: &mut [f32]) {
+        if !self.enabled {
+            return;
+        }
+        for band in 0..BAND_FREQUENCIES.len() {
+            let delta = self.target_db[band] - self.current_db[band];
+            if delta != 0.0 {
+                self.current_db[band] += delta.clamp(-MAX_STEP_DB, MAX_STEP_DB);
+                self.update_band(band);
+            }
+        }
+        for frame in block.chunks_exact_mut(self.channels) {
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                for band in 0..BAND_FREQUENCIES.len() {
+                    *sample = self.filters[band * self.channels + channel].process(*sample);
+                }
+            }
+        }
+    }
+}
+
+impl super::AudioEngine {
+    /// Set one band of the equalizer, in dB (clamped to +/-12)
+    pub fn set_eq_band(&self, index: u32, gain_db: f32) -> Result<(), TunesError> {
+        if index as usize >= BAND_FREQUENCIES.len() {
+            return Err(TunesError::invalid_state(format!(
+                "equalizer band {index} out of range"
+            )));
+        }
+        self.send(super::Command::SetEqBand {
+            index: index as usize,
+            gain_db,
+        });
+        self.shared.record_settings(|s| {
+            s.eq_gains[index as usize] = gain_db.clamp(-MAX_GAIN_DB, MAX_GAIN_DB);
+        });
+        Ok(())
+    }
+
+    pub fn set_eq_enabled(&self, enabled: bool) {
+        self.send(super::Command::SetEqEnabled(enabled));
+        self.shared.record_settings(|s| s.eq_enabled = enabled);
+    }
+
+    /// Center frequencies of the equalizer bands, in Hz
+    pub fn get_eq_bands(&self) -> Vec<f32> {
+        BAND_FREQUENCIES.to_vec()
+    }
+
+    pub fn get_eq_preset_list(&self) -> Vec<String> {
+        PRESETS.iter().map(|(name, _)| name.to_string()).collect()
+    }
+
+    pub fn apply_eq_preset(&self, name: String) -> Result<(), TunesError> {
+        let Some((_, gains)) = PRESETS.iter().find(|(preset, _)| *preset == name) else {
+            return Err(TunesError::invalid_state(format!(
+                "unknown equalizer preset {name:?}"
+            )));
+        };
+        self.send(super::Command::SetEqGains(*gains));
+        self.shared.record_settings(|s| {
+            s.eq_gains = gains.map(|g| g.clamp(-MAX_GAIN_DB, MAX_GAIN_DB)).to_vec()
+        });
+        Ok(())
+    }
+}
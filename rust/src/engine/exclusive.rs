@@ -0,0 +1,34 @@
+use super::{AudioEngine, Command, EngineThread};
+use crate::AudioEvent;
+
+impl AudioEngine {
+    /// Send samples to the device exactly as decoded, for listeners who prefer that
+    ///
+    /// Every DSP stage is bypassed while this is on, though their settings are kept.
+    /// Files at another rate than the output are still resampled. Exclusive access to
+    /// the device, bypassing the system mixer, is only had on ASIO devices (built in
+    /// with the `asio` feature), which `set_output_device` picks; elsewhere
+    /// `OutputFormat::exclusive` stays false. Emits `OutputFormatChanged`.
+    pub fn set_exclusive_mode(&self, enabled: bool) {
+        self.send(Command::SetExclusiveMode(enabled));
+    }
+}
+
+impl EngineThread {
+    pub(super) fn set_exclusive_mode(&mut self, enabled: bool) {
+        self.exclusive_mode = enabled;
+        self.player.lock().unwrap().set_bit_perfect(enabled);
+        let format = {
+            let mut format = self.shared.output_format.lock().unwrap();
+            if let Some(format) = format.as_mut() {
+                format.bit_perfect = enabled && format.sample_rate == format.engine_sample_rate;
+            }
+            format.clone()
+        };
+        if let Some(format) = format {
+            self.shared
+                .events
+                .emit(AudioEvent::OutputFormatChanged { format });
+        }
+    }
+}
@@ -0,0 +1,85 @@
+use std::time::Duration;
+
+use super::{AudioEngine, Command, EngineThread};
+use crate::library::{self, now_secs};
+use crate::{runtime, scrobble, Song};
+
+/// Share of a song that has to be heard before it counts as played
+pub(super) const DEFAULT_PLAY_THRESHOLD: f32 = 0.5;
+
+/// Position advances larger than this between two checks are seeks, not listening
+const MAX_LISTEN_STEP: Duration = Duration::from_secs(1);
+
+/// How much of the current song has actually been heard
+pub(super) struct PlayTracker {
+    song: Song,
+    started_at: i64,
+    last_position: f64,
+    listened: f64,
+    counted: bool,
+    /// Listening time after which the song is scrobbled; `None` once it has been
+    scrobble_after: Option<f64>,
+}
+
+impl AudioEngine {
+    /// Count a play once `fraction` of a song has been heard (0.5 by default)
+    ///
+    /// Seeking ahead doesn't count as listening. Plays are recorded in the library
+    /// database for `get_recently_played` and friends.
+    pub fn set_play_count_threshold(&self, fraction: f32) {
+        self.send(Command::SetPlayCountThreshold(fraction));
+    }
+}
+
+impl EngineThread {
+    pub(super) fn set_play_count_threshold(&mut self, fraction: f32) {
+        self.play_threshold = fraction.clamp(0.05, 1.0);
+    }
+
+    /// Start counting listening time for a song that just started
+    pub(super) fn track_play(&mut self, song: &Song) {
+        scrobble::now_playing(song);
+        self.play_tracker = (song.duration > 0).then(|| PlayTracker {
+            song: song.clone(),
+            started_at: now_secs(),
+            last_position: 0.0,
+            listened: 0.0,
+            counted: false,
+            scrobble_after: scrobble::listen_threshold(song),
+        });
+    }
+
+    pub(super) fn check_play_count(&mut self) {
+        let Some(tracker) = &mut self.play_tracker else {
+            return;
+        };
+        let position = self.shared.position_secs();
+        let step = position - tracker.last_position;
+        tracker.last_position = position;
+        let done = tracker.counted && tracker.scrobble_after.is_none();
+        if done || !(0.0..MAX_LISTEN_STEP.as_secs_f64()).contains(&step) {
+            return;
+        }
+        tracker.listened += step;
+        if tracker
+            .scrobble_after
+            .is_some_and(|after| tracker.listened >= after)
+        {
+            tracker.scrobble_after = None;
+            scrobble::scrobble(&tracker.song, tracker.started_at);
+        }
+        if !tracker.counted
+            && tracker.listened >= tracker.song.duration as f64 * self.play_threshold as f64
+        {
+            tracker.counted = true;
+            let song_id = tracker.song.id.clone();
+            if library::is_open() {
+                runtime::spawn_blocking(move || {
+                    if let Err(e) = library::with_library(|lib| lib.record_play(&song_id)) {
+                        log::warn!("failed to record play of {song_id}: {e}");
+                    }
+                });
+            }
+        }
+    }
+}
@@ -0,0 +1,28 @@
+use super::{AudioEngine, Command, EngineThread};
+use crate::AudioEvent;
+
+impl AudioEngine {
+    /// Report that the OS took (`begin`) or returned audio focus, e.g. for a phone call
+    ///
+    /// Playback pauses when an interruption begins and resumes when it ends, unless
+    /// the user started, paused or stopped playback in between.
+    pub fn notify_interruption(&self, begin: bool) {
+        self.send(Command::Interruption(begin));
+    }
+}
+
+impl EngineThread {
+    pub(super) fn handle_interruption(&mut self, begin: bool) {
+        if begin {
+            self.resume_after_interruption = self.is_playing();
+            if self.resume_after_interruption {
+                self.pause();
+            }
+        } else if std::mem::take(&mut self.resume_after_interruption) {
+            self.resume();
+        }
+        self.shared
+            .events
+            .emit(AudioEvent::PlaybackInterrupted { active: begin });
+    }
+}
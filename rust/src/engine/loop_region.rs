@@ -0,0 +1,89 @@
+use std::time::Duration;
+
+use super::{pipeline, AudioEngine, Command, EngineThread};
+use crate::{AudioEvent, LoopRegion, TunesError};
+
+/// Shortest region that can be looped
+const MIN_LOOP_SECS: f64 = 0.05;
+
+impl AudioEngine {
+    /// Play the current song between `start_secs` and `end_secs` over and over
+    ///
+    /// The jump back to the start is sample-accurate, without a gap. Playback moves to
+    /// the start if it is outside the region. Loading another song clears the region.
+    /// Emits `LoopRegionChanged`.
+    pub fn set_loop_region(&self, start_secs: f64, end_secs: f64) -> Result<(), TunesError> {
+        if start_secs < 0.0 || end_secs - start_secs < MIN_LOOP_SECS {
+            return Err(TunesError::invalid_state(format!(
+                "invalid loop region {start_secs}s to {end_secs}s"
+            )));
+        }
+        let song = self.shared.status.lock().unwrap().song.clone();
+        match song {
+            None => Err(TunesError::invalid_state("no song is loaded")),
+            Some(song) if song.duration == 0 => {
+                Err(TunesError::invalid_state("streams can't be looped"))
+            }
+            Some(_) => {
+                self.send(Command::SetLoopRegion(Some((start_secs, end_secs))));
+                Ok(())
+            }
+        }
+    }
+
+    pub fn clear_loop_region(&self) {
+        self.send(Command::SetLoopRegion(None));
+    }
+}
+
+impl EngineThread {
+    pub(super) fn set_loop_region(&mut self, region: Option<(f64, f64)>) {
+        let region = match region {
+            Some((start, end)) => {
+                let duration = self.player.lock().unwrap().duration_secs();
+                let end = duration.map_or(end, |duration| end.min(duration));
+                (end > start).then_some((start, end))
+            }
+            None => None,
+        };
+        self.player.lock().unwrap().set_loop_region(region);
+        if let Some((start, end)) = region {
+            let position = self.player.lock().unwrap().position_secs();
+            if !(start..end).contains(&position) {
+                self.seek(start);
+            }
+            self.prepare_loop_restart();
+        }
+        self.emit_loop_region();
+    }
+
+    /// Open the song again at the loop start, ready for the next time round
+    pub(super) fn prepare_loop_restart(&mut self) {
+        let Some((start, _)) = self.player.lock().unwrap().loop_region() else {
+            return;
+        };
+        let Some(song) = self.song() else {
+            return;
+        };
+        let source = pipeline::open_source(&song, self.sample_rate).and_then(|mut source| {
+            source
+                .try_seek(Duration::from_secs_f64(start))
+                .map_err(|e| anyhow::anyhow!("{e}"))?;
+            Ok(pipeline::prime(source))
+        });
+        match source {
+            Ok(source) => self.player.lock().unwrap().load_loop_restart(source),
+            Err(e) => log::warn!("failed to prepare loop of {}: {e}", song.file_path),
+        }
+    }
+
+    pub(super) fn emit_loop_region(&self) {
+        let region = self.player.lock().unwrap().loop_region();
+        self.shared.events.emit(AudioEvent::LoopRegionChanged {
+            region: region.map(|(start_secs, end_secs)| LoopRegion {
+                start_secs,
+                end_secs,
+            }),
+        });
+    }
+}
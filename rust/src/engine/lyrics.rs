@@ -0,0 +1,54 @@
+use std::path::Path;
+
+use super::EngineThread;
+use crate::{cue, lyrics, AudioEvent, LyricLine, Song};
+
+/// Synced lyrics of the current song and the line last announced
+pub(super) struct LyricsTracker {
+    lines: Vec<LyricLine>,
+    current: Option<usize>,
+}
+
+impl EngineThread {
+    /// Pick up the synced lyrics of a song that just started, if it has any
+    pub(super) fn load_lyrics(&mut self, song: &Song) {
+        self.lyrics = None;
+        let path = Path::new(&song.file_path);
+        // Lyrics next to or inside a CUE image belong to the whole file, not this track.
+        if !path.is_file() || cue::is_track(song) {
+            return;
+        }
+        match lyrics::read(path) {
+            Ok(lyrics) if lyrics.synced => {
+                self.lyrics = Some(LyricsTracker {
+                    lines: lyrics.lines,
+                    current: None,
+                });
+            }
+            Ok(_) => {}
+            Err(e) => log::debug!("no lyrics for {}: {e}", song.file_path),
+        }
+    }
+
+    /// Emit `LyricLineChanged` when the playback position crosses into another line
+    pub(super) fn check_lyrics(&mut self) {
+        let Some(tracker) = &mut self.lyrics else {
+            return;
+        };
+        let position = self.shared.position_secs();
+        let index = tracker
+            .lines
+            .partition_point(|line| line.time.unwrap_or_default() <= position)
+            .checked_sub(1);
+        if index == tracker.current {
+            return;
+        }
+        tracker.current = index;
+        if let Some(index) = index {
+            self.shared.events.emit(AudioEvent::LyricLineChanged {
+                index: index as u32,
+                text: tracker.lines[index].text.clone(),
+            });
+        }
+    }
+}
@@ -0,0 +1,112 @@
+use std::time::{Duration, Instant};
+
+use super::{AudioEngine, Command, EngineThread};
+use crate::{MediaCommand, PlaybackState};
+
+/// Repeats of a command closer together than this are one press delivered twice, as
+/// happens when both a media session and a key event report it
+const DEBOUNCE: Duration = Duration::from_millis(80);
+
+/// Longest gap between presses of `PlayPause` that still counts them together
+const MULTI_PRESS_WINDOW: Duration = Duration::from_millis(400);
+
+/// How far `FastForward` and `Rewind` move
+const SKIP_SECS: f64 = 10.0;
+
+/// Presses seen so far, to debounce them and count `PlayPause` presses
+#[derive(Default)]
+pub(super) struct MediaButtons {
+    last: Option<(MediaCommand, Instant)>,
+    /// `PlayPause` presses waiting for the window to close
+    presses: u32,
+    last_press: Option<Instant>,
+}
+
+impl AudioEngine {
+    /// Act on a headset button, media key or lock screen control
+    ///
+    /// Every platform's buttons can go through here so they behave the same: repeats
+    /// delivered twice are dropped, and `PlayPause` waits briefly to tell a single press
+    /// from a double (next song) or triple one (previous song).
+    pub fn handle_media_command(&self, command: MediaCommand) {
+        self.send(Command::Media(command));
+    }
+}
+
+impl EngineThread {
+    pub(super) fn handle_media_command(&mut self, command: MediaCommand) {
+        let now = Instant::now();
+        let buttons = &mut self.media_buttons;
+        let repeated = buttons
+            .last
+            .is_some_and(|(last, at)| last == command && now - at < DEBOUNCE);
+        buttons.last = Some((command, now));
+        if repeated {
+            return;
+        }
+        match command {
+            MediaCommand::PlayPause => {
+                buttons.presses += 1;
+                buttons.last_press = Some(now);
+                // Nothing counts beyond three presses, so there is no need to wait.
+                if buttons.presses >= 3 {
+                    self.act_on_presses();
+                }
+            }
+            MediaCommand::Next => self.skip_next(),
+            MediaCommand::Previous => self.skip_previous(),
+            MediaCommand::Stop => self.stop(),
+            MediaCommand::SeekTo { position_secs } => self.seek(position_secs.max(0.0)),
+            MediaCommand::FastForward => self.skip_by(SKIP_SECS),
+            MediaCommand::Rewind => self.skip_by(-SKIP_SECS),
+        }
+    }
+
+    /// Act on `PlayPause` presses once no more are coming
+    pub(super) fn check_media_buttons(&mut self) {
+        let done = self
+            .media_buttons
+            .last_press
+            .is_some_and(|at| at.elapsed() >= MULTI_PRESS_WINDOW);
+        if done {
+            self.act_on_presses();
+        }
+    }
+
+    fn act_on_presses(&mut self) {
+        let presses = std::mem::take(&mut self.media_buttons.presses);
+        self.media_buttons.last_press = None;
+        match presses {
+            0 => {}
+            1 => self.toggle_playback(),
+            2 => self.skip_next(),
+            _ => self.skip_previous(),
+        }
+    }
+
+    /// Pause, resume, or start the queue from its current entry when stopped
+    fn toggle_playback(&mut self) {
+        match self.state() {
+            PlaybackState::Paused => self.resume(),
+            PlaybackState::Stopped => {
+                let index = {
+                    let queue = self.shared.queue.lock().unwrap();
+                    let has_songs = !queue.songs().is_empty();
+                    queue.current_index().or(has_songs.then_some(0))
+                };
+                if index.is_some() {
+                    self.play_queue_index(index);
+                }
+            }
+            _ => self.pause(),
+        }
+    }
+
+    fn skip_by(&mut self, delta_secs: f64) {
+        {
+            let mut pending = self.shared.pending_skip.lock().unwrap();
+            *pending = Some(pending.unwrap_or(0.0) + delta_secs);
+        }
+        self.seek_relative();
+    }
+}
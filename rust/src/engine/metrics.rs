@@ -0,0 +1,115 @@
+use std::fs;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use super::{AudioEngine, Shared};
+use crate::http_stream::BufferLevel;
+use crate::{EngineMetrics, StreamSink};
+
+/// Weight each new block gets in the smoothed loads
+const SMOOTHING: f32 = 0.05;
+
+/// Shortest interval `metrics_stream` sends at
+const MIN_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Timings the output callback and the pipeline keep as they run
+#[derive(Default)]
+pub(super) struct Meters {
+    /// Blocks the output callback was too slow to produce in time
+    underruns: AtomicU64,
+    /// Bits of the smoothed `f32` share of real time spent in the DSP stages
+    dsp_load: AtomicU32,
+    /// Bits of the smoothed `f32` share of its deadline the output callback takes
+    callback_load: AtomicU32,
+}
+
+impl Meters {
+    /// Note that `busy` went on the DSP stages while rendering `audio` worth of samples
+    pub fn record_dsp(&self, busy: Duration, audio: Duration) {
+        smooth(&self.dsp_load, busy, audio);
+    }
+
+    /// Note that the output callback took `busy` to produce `audio` worth of samples;
+    /// the device ran dry waiting if that took longer than the audio lasts
+    pub fn record_callback(&self, busy: Duration, audio: Duration) {
+        if busy > audio {
+            self.underruns.fetch_add(1, Ordering::Relaxed);
+        }
+        smooth(&self.callback_load, busy, audio);
+    }
+}
+
+/// Move the load in `cell` towards `busy` out of `audio`
+///
+/// Only the output callback's thread writes, so loading and storing apart is safe.
+fn smooth(cell: &AtomicU32, busy: Duration, audio: Duration) {
+    if audio.is_zero() {
+        return;
+    }
+    let load = busy.as_secs_f32() / audio.as_secs_f32();
+    let old = f32::from_bits(cell.load(Ordering::Relaxed));
+    cell.store(
+        (old + (load - old) * SMOOTHING).to_bits(),
+        Ordering::Relaxed,
+    );
+}
+
+/// Resident memory of the process, as the kernel accounts for it
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn resident_bytes() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let kib = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?;
+    let kib: u64 = kib.trim().strip_suffix("kB")?.trim().parse().ok()?;
+    Some(kib * 1024)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn resident_bytes() -> Option<u64> {
+    None
+}
+
+impl AudioEngine {
+    /// How full the stream buffer is, glitches so far, DSP load and memory use
+    pub fn get_metrics(&self) -> EngineMetrics {
+        self.shared.metrics()
+    }
+
+    /// Send `get_metrics` every `interval_ms`, at least 100, until the stream closes
+    pub fn metrics_stream(&self, interval_ms: u32, sink: StreamSink<EngineMetrics>) {
+        let shared = Arc::downgrade(&self.shared);
+        let interval = Duration::from_millis(interval_ms.into()).max(MIN_INTERVAL);
+        let spawned = thread::Builder::new()
+            .name("tunes4r-metrics".into())
+            .spawn(move || {
+                // Stops with the engine too, rather than keeping it alive.
+                while let Some(metrics) = shared.upgrade().map(|shared| shared.metrics()) {
+                    if sink.add(metrics).is_err() {
+                        break;
+                    }
+                    thread::sleep(interval);
+                }
+            });
+        if let Err(e) = spawned {
+            log::error!("failed to spawn metrics thread: {e}");
+        }
+    }
+}
+
+impl Shared {
+    fn metrics(&self) -> EngineMetrics {
+        let load = |cell: &AtomicU32| f32::from_bits(cell.load(Ordering::Relaxed));
+        let stream = self.stream_buffer.lock().unwrap();
+        EngineMetrics {
+            buffer_fill: stream.as_ref().map(BufferLevel::fill),
+            buffer_ahead_secs: stream.as_ref().map(BufferLevel::ahead_secs),
+            underruns: self.meters.underruns.load(Ordering::Relaxed),
+            dsp_load: load(&self.meters.dsp_load),
+            callback_load: load(&self.meters.callback_load),
+            memory_bytes: resident_bytes(),
+        }
+    }
+}
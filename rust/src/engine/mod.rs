@@ -0,0 +1,917 @@
+mod announcements;
+mod auto_dj;
+mod beats;
+mod bookmarks;
+mod cast;
+mod channel_mode;
+mod chapters;
+mod crossfade;
+mod devices;
+mod diagnostics;
+mod downmix;
+mod dsp_chain;
+mod ducking;
+mod dynamics;
+mod eq;
+mod exclusive;
+mod history;
+mod interruption;
+mod loop_region;
+mod lyrics;
+mod media_buttons;
+mod metrics;
+mod mpd;
+#[cfg(target_os = "linux")]
+mod mpris;
+mod normalization;
+mod now_playing;
+mod output;
+mod pauses;
+mod persistence;
+mod pipeline;
+mod preamp;
+mod prebuffer;
+mod preview;
+mod queue;
+mod radio;
+mod recording;
+mod resampler;
+mod reverb;
+mod settings;
+mod skip;
+mod sleep_timer;
+mod snapshot;
+mod spectrum;
+mod supervisor;
+mod suspend;
+mod timestretch;
+mod trim;
+mod volume;
+mod widener;
+mod zones;
+
+use std::sync::atomic::AtomicU32;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+
+use crate::cast::CastTarget;
+use crate::events::EventBus;
+use crate::http_stream::BufferLevel;
+use crate::{
+    AudioEvent, AutoDjParams, ClipTiming, EngineConfig, FadeCurve, MediaCommand, NormalizationMode,
+    OutputFormat, PlaybackState, SleepTimerMode, Song, StreamSink, ZoneConfig,
+};
+
+use self::announcements::Clip;
+use self::cast::{CastSession, CastStatus};
+use self::chapters::ChapterTracker;
+use self::dsp_chain::DspChain;
+use self::history::PlayTracker;
+use self::lyrics::LyricsTracker;
+use self::media_buttons::MediaButtons;
+use self::metrics::Meters;
+use self::output::Output;
+use self::persistence::StateFile;
+use self::pipeline::{BoxedSource, PipelineEvent, Player};
+use self::preamp::DspSettings;
+use self::prebuffer::Prebuffer;
+use self::preview::Preview;
+use self::queue::Queue;
+use self::recording::Recording;
+use self::settings::SettingsFile;
+use self::sleep_timer::SleepTimer;
+use self::suspend::SuspendWatch;
+use self::volume::VolumeSettings;
+use self::zones::Zone;
+
+pub(crate) use self::devices::{list_output_devices, preferred_sample_rate};
+pub(crate) use self::downmix::Downmix;
+#[cfg(feature = "test-fixtures")]
+pub(crate) use self::pipeline::render;
+pub(crate) use self::resampler::Resampler;
+pub(crate) use self::supervisor::panic_message;
+
+/// How often the engine thread wakes up to forward pipeline events
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Interval between `ProgressUpdated` events while playing
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Capacity of the audio callback -> engine thread channel
+const PIPELINE_EVENT_CAPACITY: usize = 64;
+
+/// Core audio engine for Tunes4R
+///
+/// Commands are forwarded to a dedicated audio thread which owns the output stream;
+/// state changes and progress come back through `audio_event_stream`.
+pub struct AudioEngine {
+    pub sample_rate: u32,
+    commands: Sender<Command>,
+    shared: Arc<Shared>,
+}
+
+enum Command {
+    Play {
+        song: Song,
+        /// Continue from the song's bookmark, if it has one
+        resume: bool,
+    },
+    Pause,
+    Resume,
+    Stop,
+    SetPauseFade(u32),
+    Seek(f64),
+    /// Seek by `Shared::pending_skip`
+    SeekRelative,
+    SetLoopRegion(Option<(f64, f64)>),
+    SeekToChapter(u32),
+    SetVisualizerConfig {
+        bands: u32,
+        fps: u32,
+        window: u32,
+    },
+    SetBeatDetection(bool),
+    SyncQueue,
+    /// `None` turns auto-DJ off
+    SetAutoDj(Option<AutoDjParams>),
+    PlayQueueIndex(usize),
+    SkipNext,
+    SkipPrevious,
+    SetGapless(bool),
+    SetCrossfade {
+        duration_ms: u32,
+        curve: FadeCurve,
+    },
+    SetEqEnabled(bool),
+    SetEqBand {
+        index: usize,
+        gain_db: f32,
+    },
+    SetEqGains([f32; 10]),
+    SetNormalization {
+        mode: NormalizationMode,
+        target_lufs: f32,
+    },
+    SetVolume(f32),
+    SetDsp(DspSettings),
+    SetDspChain(DspChain),
+    SetOutputDevice(Option<String>),
+    SetBufferSize(u32),
+    SetExclusiveMode(bool),
+    Interruption(bool),
+    /// Gain the output is ducked to, 1 when not ducked
+    SetDucking(f32),
+    SetDuckingFade(Duration),
+    SetPlaybackRate(f32),
+    SetPitchShift(f32),
+    SetSleepTimer {
+        duration: Duration,
+        mode: SleepTimerMode,
+    },
+    CancelSleepTimer,
+    SetBookmarkMinDuration(u64),
+    SetSkipSilence(bool),
+    SetPauseSkipping {
+        enabled: bool,
+        min_pause_ms: u32,
+    },
+    SetPlayCountThreshold(f32),
+    SetInstantPlay {
+        count: u32,
+        memory_budget_mb: u32,
+    },
+    PlayUrl(String),
+    PreviewPlay {
+        song: Song,
+        device_id: Option<String>,
+    },
+    PreviewStop,
+    PlayClip {
+        clip: Clip,
+        timing: ClipTiming,
+    },
+    StopClips,
+    SetZones(Vec<ZoneConfig>),
+    Restore {
+        song: Song,
+        position: f64,
+    },
+    CastTo(Box<dyn CastTarget>),
+    StopCasting,
+    Media(MediaCommand),
+    #[cfg(target_os = "linux")]
+    SetMpris(Option<mpris::Mpris>),
+    StartMpd {
+        port: u16,
+        reply: Sender<anyhow::Result<u16>>,
+    },
+    StopMpd,
+}
+
+/// Engine state visible from both the FFI side and the audio thread
+struct Shared {
+    events: EventBus,
+    status: Mutex<Status>,
+    queue: Mutex<Queue>,
+    volume: Mutex<VolumeSettings>,
+    dsp: Mutex<DspSettings>,
+    dsp_chain: Mutex<DspChain>,
+    /// Sum of `seek_relative` calls the engine thread hasn't acted on yet
+    pending_skip: Mutex<Option<f64>>,
+    /// Also locked by the output stream, so only briefly from the FFI side
+    player: Arc<Mutex<Player>>,
+    state_file: Mutex<StateFile>,
+    settings: Mutex<SettingsFile>,
+    output_format: Mutex<Option<OutputFormat>>,
+    /// Microseconds from a sample leaving the pipeline to it being heard
+    output_latency: Arc<AtomicU32>,
+    /// Timings of the main output and the pipeline feeding it
+    meters: Arc<Meters>,
+    /// Buffer of the network stream being played, if any
+    stream_buffer: Mutex<Option<BufferLevel>>,
+    /// Bits of the `f32` gain previews play at
+    preview_volume: Arc<AtomicU32>,
+    zones: Mutex<Vec<ZoneConfig>>,
+    recording: Mutex<Option<Recording>>,
+    /// Set while playback goes to a cast device rather than `player`
+    cast: Mutex<Option<CastStatus>>,
+}
+
+struct Status {
+    state: PlaybackState,
+    song: Option<Song>,
+}
+
+impl AudioEngine {
+    /// An engine at the sample rate `config` asks for, keeping its settings and state in
+    /// the config's files
+    pub(crate) fn with_config(config: &EngineConfig) -> anyhow::Result<Self> {
+        let sample_rate = config.sample_rate.unwrap_or_else(preferred_sample_rate);
+        let engine = AudioEngine::new(sample_rate)?;
+        if let Some(path) = &config.settings_path {
+            engine.set_settings_file(path.clone())?;
+        }
+        engine.set_state_file(config.state_path.clone());
+        Ok(engine)
+    }
+
+    pub(crate) fn new(sample_rate: u32) -> anyhow::Result<Self> {
+        let (commands, rx) = mpsc::channel();
+        let (events_tx, pipeline_events) = mpsc::sync_channel(PIPELINE_EVENT_CAPACITY);
+        let meters = Arc::new(Meters::default());
+        let shared = Arc::new(Shared {
+            events: EventBus::default(),
+            status: Mutex::new(Status {
+                state: PlaybackState::Stopped,
+                song: None,
+            }),
+            queue: Mutex::new(Queue::default()),
+            volume: Mutex::new(VolumeSettings::default()),
+            dsp: Mutex::new(DspSettings::default()),
+            dsp_chain: Mutex::new(DspChain::default()),
+            pending_skip: Mutex::new(None),
+            player: Arc::new(Mutex::new(Player::new(
+                sample_rate,
+                events_tx,
+                meters.clone(),
+            ))),
+            state_file: Mutex::new(StateFile::default()),
+            settings: Mutex::new(SettingsFile::default()),
+            output_format: Mutex::new(None),
+            output_latency: Arc::new(AtomicU32::new(0)),
+            meters,
+            stream_buffer: Mutex::new(None),
+            preview_volume: Arc::new(AtomicU32::new(1f32.to_bits())),
+            zones: Mutex::new(Vec::new()),
+            recording: Mutex::new(None),
+            cast: Mutex::new(None),
+        });
+        let thread_shared = shared.clone();
+        thread::Builder::new()
+            .name("tunes4r-audio".into())
+            .spawn(move || {
+                EngineThread::new(sample_rate, thread_shared, pipeline_events).supervise(rx)
+            })
+            .context("failed to spawn audio thread")?;
+        Ok(AudioEngine {
+            sample_rate,
+            commands,
+            shared,
+        })
+    }
+
+    /// Start playing `song`, replacing whatever is loaded
+    ///
+    /// Long songs continue from where they were left off; see `set_bookmark_min_duration`.
+    /// The queue is left untouched, so playback continues with its next entry afterwards.
+    pub fn play(&self, song: Song) {
+        self.send(Command::Play { song, resume: true });
+    }
+
+    pub fn pause(&self) {
+        self.send(Command::Pause);
+    }
+
+    pub fn resume(&self) {
+        self.send(Command::Resume);
+    }
+
+    pub fn stop(&self) {
+        self.send(Command::Stop);
+    }
+
+    /// Fade out over `duration_ms` before pausing or stopping, and back in on resume
+    ///
+    /// Zero, the default, pauses and stops at once.
+    pub fn set_pause_fade(&self, duration_ms: u32) {
+        self.send(Command::SetPauseFade(duration_ms));
+    }
+
+    /// Jump to `position_secs` in the current track
+    ///
+    /// The state is `Seeking` until the decoder has repositioned, then `Seeked` reports
+    /// where playback actually resumed.
+    pub fn seek_to(&self, position_secs: f64) {
+        self.send(Command::Seek(position_secs));
+    }
+
+    /// Toggle sample-accurate switching into the next track (on by default)
+    pub fn set_gapless(&self, enabled: bool) {
+        self.send(Command::SetGapless(enabled));
+        self.shared.record_settings(|s| s.gapless = enabled);
+    }
+
+    /// Mix the end of each track into the start of the next over `duration_ms`
+    ///
+    /// Zero disables crossfading. Only applies when a next track is queued.
+    pub fn set_crossfade(&self, duration_ms: u32, curve: FadeCurve) {
+        self.send(Command::SetCrossfade { duration_ms, curve });
+        self.shared.record_settings(|s| {
+            s.crossfade_ms = duration_ms;
+            s.crossfade_curve = curve;
+        });
+    }
+
+    /// Configure `SpectrumDataUpdated` output: number of log-spaced bands and frames per
+    /// second. Passing zero for either turns the analyzer off.
+    pub fn set_spectrum_config(&self, bands: u32, fps: u32) {
+        self.set_visualizer_config(bands, fps, 0);
+    }
+
+    /// Like `set_spectrum_config`, also choosing the samples per FFT window, which
+    /// `WaveformFrame` carries too; zero for the default 2048
+    ///
+    /// Longer windows resolve low frequencies better but react more slowly. Rounded up
+    /// to a power of two between 256 and 16384.
+    pub fn set_visualizer_config(&self, bands: u32, fps: u32, window: u32) {
+        self.send(Command::SetVisualizerConfig { bands, fps, window });
+        self.shared.record_settings(|s| {
+            s.visualizer_bands = bands;
+            s.visualizer_fps = fps;
+            s.visualizer_window = window;
+        });
+    }
+
+    /// Subscribe to engine events; the stream stays open for the engine's lifetime
+    ///
+    /// Any number of streams may be open at once, each receiving every event.
+    pub fn audio_event_stream(&self, sink: StreamSink<AudioEvent>) {
+        self.shared.events.subscribe(sink);
+    }
+
+    fn send(&self, command: Command) {
+        if self.commands.send(command).is_err() {
+            log::error!("audio thread is no longer running");
+        }
+    }
+}
+
+/// Audio thread side of the engine
+struct EngineThread {
+    sample_rate: u32,
+    shared: Arc<Shared>,
+    player: Arc<Mutex<Player>>,
+    pipeline_events: Receiver<PipelineEvent>,
+    next_song: Option<Song>,
+    /// Queue entries after the up-next one, ready to play at once
+    prebuffer: Prebuffer,
+    /// Set by `set_auto_dj`; `None` while it's off
+    auto_dj: Option<AutoDjParams>,
+    gapless: bool,
+    crossfade_ms: u32,
+    normalization: NormalizationMode,
+    target_lufs: f32,
+    // Must stay alive for as long as audio should be heard.
+    output: Option<Output>,
+    /// Song playing on an output of its own, from `preview_play`
+    preview: Option<Preview>,
+    /// Devices the output is mirrored to, from `set_zones`
+    zones: Vec<Zone>,
+    last_zone_check: Instant,
+    /// Explicitly selected output device; `None` follows the system default
+    output_device: Option<String>,
+    /// Set by `set_buffer_size`; zero for the device's default
+    buffer_frames: u32,
+    last_device_check: Instant,
+    /// Set by `set_exclusive_mode`
+    exclusive_mode: bool,
+    /// Set by `set_skip_silence`
+    skip_silence: bool,
+    /// Playback was paused by an interruption and should resume when it ends
+    resume_after_interruption: bool,
+    /// Set by `set_ducking_fade`
+    ducking_fade: Duration,
+    sleep_timer: Option<SleepTimer>,
+    lyrics: Option<LyricsTracker>,
+    chapters: Option<ChapterTracker>,
+    /// Shortest song, in seconds, whose playback position is saved
+    bookmark_min_duration: u64,
+    last_bookmark_save: Instant,
+    play_tracker: Option<PlayTracker>,
+    /// Share of a song that has to be heard for a play to count
+    play_threshold: f32,
+    last_progress: Instant,
+    last_state_save: Instant,
+    suspend_watch: SuspendWatch,
+    /// The device playback is cast to, if any
+    cast: Option<CastSession>,
+    media_buttons: MediaButtons,
+    #[cfg(target_os = "linux")]
+    mpris: Option<mpris::Mpris>,
+    mpd: Option<mpd::MpdServer>,
+}
+
+impl EngineThread {
+    fn new(
+        sample_rate: u32,
+        shared: Arc<Shared>,
+        pipeline_events: Receiver<PipelineEvent>,
+    ) -> Self {
+        let player = shared.player.clone();
+        let mut thread = EngineThread {
+            sample_rate,
+            shared,
+            player,
+            pipeline_events,
+            next_song: None,
+            prebuffer: Prebuffer::new(sample_rate),
+            auto_dj: None,
+            gapless: true,
+            crossfade_ms: 0,
+            normalization: NormalizationMode::Off,
+            target_lufs: normalization::DEFAULT_TARGET_LUFS,
+            output: None,
+            preview: None,
+            zones: Vec::new(),
+            last_zone_check: Instant::now(),
+            buffer_frames: 0,
+            output_device: None,
+            last_device_check: Instant::now(),
+            exclusive_mode: false,
+            skip_silence: false,
+            resume_after_interruption: false,
+            ducking_fade: ducking::DEFAULT_FADE,
+            sleep_timer: None,
+            lyrics: None,
+            chapters: None,
+            bookmark_min_duration: bookmarks::DEFAULT_MIN_DURATION_SECS,
+            last_bookmark_save: Instant::now(),
+            play_tracker: None,
+            play_threshold: history::DEFAULT_PLAY_THRESHOLD,
+            last_progress: Instant::now(),
+            last_state_save: Instant::now(),
+            suspend_watch: SuspendWatch::new(),
+            cast: None,
+            media_buttons: MediaButtons::default(),
+            #[cfg(target_os = "linux")]
+            mpris: None,
+            mpd: None,
+        };
+        thread.open_output(None);
+        thread
+    }
+
+    /// Handle the next command, if one comes within `POLL_INTERVAL`, and run the
+    /// periodic checks; false once the engine has been dropped
+    fn step(&mut self, commands: &Receiver<Command>) -> bool {
+        match commands.recv_timeout(POLL_INTERVAL) {
+            Ok(command) => self.handle(command),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return false,
+        }
+        while let Ok(event) = self.pipeline_events.try_recv() {
+            self.handle_pipeline_event(event);
+        }
+        self.check_suspend();
+        if self.is_playing() && self.last_progress.elapsed() >= PROGRESS_INTERVAL {
+            self.emit_progress();
+        }
+        self.check_output_device();
+        self.check_cast();
+        self.check_preview();
+        self.check_zones();
+        self.check_media_buttons();
+        #[cfg(target_os = "linux")]
+        self.check_mpris();
+        self.check_mpd();
+        self.check_sleep_timer();
+        self.check_stream_buffer();
+        self.check_lyrics();
+        self.check_chapters();
+        self.check_bookmark();
+        self.check_play_count();
+        self.check_state_save();
+        true
+    }
+
+    fn handle_pipeline_event(&mut self, event: PipelineEvent) {
+        match event {
+            PipelineEvent::TrackFinished => self.finish_track(),
+            PipelineEvent::TrackTransition => {
+                let Some(song) = self.next_song.take() else {
+                    return;
+                };
+                let previous = self.song();
+                if let Some(previous) = &previous {
+                    self.clear_bookmark(previous);
+                }
+                *self.shared.stream_buffer.lock().unwrap() = None;
+                self.load_lyrics(&song);
+                self.load_chapters(&song);
+                self.track_play(&song);
+                self.set_state(PlaybackState::Playing, Some(song.clone()));
+                // The trailing silence of a song measured after it was preloaded
+                self.trim_silence(&song, false);
+                self.shared.events.emit(AudioEvent::TrackTransition {
+                    previous,
+                    song: song.clone(),
+                });
+                self.emit_progress();
+                self.advance_queue_to(&song);
+            }
+            PipelineEvent::LoopRestarted => self.prepare_loop_restart(),
+            PipelineEvent::PauseSkipped(saved) => self.emit_pause_skipped(saved),
+            PipelineEvent::Spectrum(frame) => self.emit_spectrum(frame),
+            PipelineEvent::Beat(beat) => self.emit_beat(beat),
+            PipelineEvent::ClipStarted(id) => {
+                self.shared.events.emit(AudioEvent::ClipStarted { id })
+            }
+            PipelineEvent::ClipFinished(id) => {
+                self.shared.events.emit(AudioEvent::ClipFinished { id })
+            }
+            PipelineEvent::Panicked(message) => self.skip_after_panic("playback", message),
+        }
+    }
+
+    /// Move on to the next song once the current one has played to its end
+    fn finish_track(&mut self) {
+        self.emit_progress();
+        if let Some(song) = self.song() {
+            self.clear_bookmark(&song);
+        }
+        if self.sleep_timer_at_track_end() {
+            self.set_state(PlaybackState::Stopped, None);
+            return;
+        }
+        match self.next_song.take() {
+            Some(next) => {
+                self.play(next.clone(), true);
+                self.advance_queue_to(&next);
+            }
+            None => self.set_state(PlaybackState::Stopped, None),
+        }
+    }
+
+    fn emit_progress(&mut self) {
+        let fallback = self.song().map_or(0.0, |s| s.duration as f64);
+        let current_time = self.shared.position_secs();
+        let total_time = self.shared.duration_secs().unwrap_or(fallback);
+        self.last_progress = Instant::now();
+        self.shared.events.emit(AudioEvent::ProgressUpdated {
+            current_time,
+            total_time,
+        });
+    }
+
+    fn handle(&mut self, command: Command) {
+        if matches!(
+            command,
+            Command::Play { .. }
+                | Command::PlayUrl(_)
+                | Command::Pause
+                | Command::Resume
+                | Command::Stop
+        ) {
+            // The user took over; don't undo their choice when an interruption ends.
+            self.resume_after_interruption = false;
+            self.interrupt_sleep_fade();
+        }
+        match command {
+            Command::Play { song, resume } => self.play(song, resume),
+            Command::PlayUrl(url) => self.play_url(url),
+            Command::PreviewPlay { song, device_id } => self.preview_play(song, device_id),
+            Command::PreviewStop => self.preview_stop(),
+            Command::PlayClip { clip, timing } => self.play_clip(clip, timing),
+            Command::StopClips => self.stop_clips(),
+            Command::SetZones(zones) => self.set_zones(zones),
+            Command::Restore { song, position } => self.restore(song, position),
+            Command::CastTo(renderer) => self.start_cast(renderer),
+            Command::StopCasting => self.stop_cast(false),
+            Command::Media(command) => self.handle_media_command(command),
+            #[cfg(target_os = "linux")]
+            Command::SetMpris(mpris) => self.set_mpris(mpris),
+            Command::StartMpd { port, reply } => self.start_mpd(port, reply),
+            Command::StopMpd => self.stop_mpd(),
+            Command::Pause => self.pause(),
+            Command::Resume => self.resume(),
+            Command::Stop => self.stop(),
+            Command::SetPauseFade(duration_ms) => {
+                self.player.lock().unwrap().set_pause_fade(duration_ms);
+            }
+            Command::Seek(position_secs) => self.seek(position_secs),
+            Command::SeekRelative => self.seek_relative(),
+            Command::SetLoopRegion(region) => self.set_loop_region(region),
+            Command::SeekToChapter(index) => self.seek_to_chapter(index),
+            Command::SetVisualizerConfig { bands, fps, window } => {
+                let mut player = self.player.lock().unwrap();
+                player.set_spectrum_config(bands, fps, window);
+            }
+            Command::SetBeatDetection(enabled) => {
+                self.player.lock().unwrap().beats.set_enabled(enabled);
+            }
+            Command::SyncQueue => self.sync_next(),
+            Command::SetAutoDj(params) => self.set_auto_dj(params),
+            Command::PlayQueueIndex(index) => self.play_queue_index(Some(index)),
+            Command::SkipNext => self.skip_next(),
+            Command::SkipPrevious => self.skip_previous(),
+            Command::SetGapless(enabled) => {
+                self.gapless = enabled;
+                self.player.lock().unwrap().set_gapless(enabled);
+                self.preload_next();
+            }
+            Command::SetCrossfade { duration_ms, curve } => {
+                self.crossfade_ms = duration_ms;
+                self.player
+                    .lock()
+                    .unwrap()
+                    .set_crossfade(duration_ms, curve);
+                self.preload_next();
+            }
+            Command::SetEqEnabled(enabled) => self.player.lock().unwrap().eq.set_enabled(enabled),
+            Command::SetEqBand { index, gain_db } => {
+                self.player.lock().unwrap().eq.set_band(index, gain_db)
+            }
+            Command::SetEqGains(gains) => self.player.lock().unwrap().eq.set_all(gains),
+            Command::SetNormalization { mode, target_lufs } => {
+                self.set_normalization(mode, target_lufs)
+            }
+            Command::SetVolume(gain) => {
+                self.player.lock().unwrap().volume.set_target(gain);
+                self.cast_volume(gain);
+            }
+            Command::SetDsp(settings) => {
+                let mut player = self.player.lock().unwrap();
+                player.gain.set(settings);
+                player.reverb.set(settings.reverb);
+                player.widener.set(settings.stereo_width);
+                player.channel_map.set(settings.channel_mode);
+                player.compressor.set(settings.dynamics);
+            }
+            Command::SetDspChain(chain) => self.player.lock().unwrap().set_chain(chain),
+            Command::SetOutputDevice(id) => self.set_output_device(id),
+            Command::SetBufferSize(frames) => self.set_buffer_size(frames),
+            Command::SetExclusiveMode(enabled) => self.set_exclusive_mode(enabled),
+            Command::Interruption(begin) => self.handle_interruption(begin),
+            Command::SetDucking(gain) => self.set_ducking(gain),
+            Command::SetDuckingFade(fade) => self.ducking_fade = fade,
+            Command::SetPlaybackRate(rate) => self.player.lock().unwrap().set_playback_rate(rate),
+            Command::SetPitchShift(semitones) => {
+                self.player.lock().unwrap().set_pitch_shift(semitones)
+            }
+            Command::SetSleepTimer { duration, mode } => self.set_sleep_timer(duration, mode),
+            Command::CancelSleepTimer => self.cancel_sleep_timer(),
+            Command::SetBookmarkMinDuration(secs) => self.bookmark_min_duration = secs,
+            Command::SetSkipSilence(enabled) => self.set_skip_silence(enabled),
+            Command::SetPauseSkipping {
+                enabled,
+                min_pause_ms,
+            } => self
+                .player
+                .lock()
+                .unwrap()
+                .pauses
+                .set(enabled, min_pause_ms),
+            Command::SetPlayCountThreshold(fraction) => self.set_play_count_threshold(fraction),
+            Command::SetInstantPlay {
+                count,
+                memory_budget_mb,
+            } => self.set_instant_play(count, memory_budget_mb),
+        }
+    }
+
+    fn play(&mut self, song: Song, resume: bool) {
+        // Saving a song that is about to be cleared could land after the clear.
+        if resume || self.song().is_none_or(|current| current.id != song.id) {
+            self.save_bookmark();
+        }
+        let start_at = if resume {
+            self.bookmark_for(&song)
+        } else {
+            self.clear_bookmark(&song);
+            None
+        };
+        let sample_rate = self.sample_rate;
+        let prebuffered = self.prebuffer.take(&song);
+        self.start_playback(song, start_at, false, |song| match prebuffered {
+            Some(source) => Ok(source),
+            None => pipeline::open_source(song, sample_rate),
+        });
+    }
+
+    /// Load the source `open` returns for `song` and play it, or hold it `paused`, from
+    /// `start_at` seconds; `open` may fill in `song`
+    fn start_playback(
+        &mut self,
+        mut song: Song,
+        start_at: Option<f64>,
+        paused: bool,
+        open: impl FnOnce(&mut Song) -> anyhow::Result<BoxedSource>,
+    ) {
+        *self.shared.stream_buffer.lock().unwrap() = None;
+        self.lyrics = None;
+        self.chapters = None;
+        self.play_tracker = None;
+        self.set_state(PlaybackState::Loading, Some(song.clone()));
+        if self.is_casting() {
+            self.cast_song(song.clone(), start_at, paused);
+            self.load_lyrics(&song);
+            self.load_chapters(&song);
+            self.track_play(&song);
+            let state = match paused {
+                true => PlaybackState::Paused,
+                false => PlaybackState::Playing,
+            };
+            self.set_state(state, Some(song));
+            return;
+        }
+        if self.output.is_none() {
+            log::error!("cannot play {}: no audio output", song.file_path);
+            self.set_state(PlaybackState::Stopped, None);
+            return;
+        }
+        match open(&mut song) {
+            Ok(source) => {
+                let gain = self.normalization_gain(&song);
+                let mut player = self.player.lock().unwrap();
+                let looped = player.loop_region().is_some();
+                player.load(source, gain);
+                player.set_paused(paused);
+                if let Some(position) = start_at {
+                    if let Err(e) = player.seek(position) {
+                        log::warn!("failed to resume {} at {position}s: {e}", song.file_path);
+                    }
+                }
+                drop(player);
+                if looped {
+                    self.emit_loop_region();
+                }
+                self.load_lyrics(&song);
+                self.load_chapters(&song);
+                self.track_play(&song);
+                self.trim_silence(&song, start_at.is_none());
+                let state = match paused {
+                    true => PlaybackState::Paused,
+                    false => PlaybackState::Playing,
+                };
+                self.set_state(state, Some(song));
+            }
+            Err(e) => {
+                log::error!("failed to open {}: {e}", song.file_path);
+                self.player.lock().unwrap().unload();
+                self.set_state(PlaybackState::Stopped, None);
+            }
+        }
+    }
+
+    fn pause(&mut self) {
+        if self.is_playing() {
+            self.save_bookmark();
+            self.player.lock().unwrap().pause();
+            self.cast_pause(true);
+            self.set_state(PlaybackState::Paused, self.song());
+        }
+    }
+
+    fn resume(&mut self) {
+        if self.state() == PlaybackState::Paused {
+            self.player.lock().unwrap().resume();
+            self.cast_pause(false);
+            self.set_state(PlaybackState::Playing, self.song());
+        }
+    }
+
+    fn stop(&mut self) {
+        self.save_bookmark();
+        self.player.lock().unwrap().stop();
+        self.cast_stop();
+        self.lyrics = None;
+        self.chapters = None;
+        self.play_tracker = None;
+        self.set_state(PlaybackState::Stopped, None);
+    }
+
+    fn seek(&mut self, position_secs: f64) {
+        let state = self.state();
+        if !matches!(state, PlaybackState::Playing | PlaybackState::Paused) {
+            log::warn!("ignoring seek to {position_secs}s while {state:?}");
+            return;
+        }
+        self.set_state(PlaybackState::Seeking, self.song());
+        let result = match self.is_casting() {
+            true => {
+                self.cast_seek(position_secs);
+                Ok(())
+            }
+            false => self.player.lock().unwrap().seek(position_secs),
+        };
+        self.set_state(state, self.song());
+        match result {
+            Ok(()) => {
+                let position = self.shared.position_secs();
+                self.shared.events.emit(AudioEvent::Seeked { position });
+                self.emit_progress();
+            }
+            Err(e) => log::warn!("seek to {position_secs}s failed: {e}"),
+        }
+    }
+
+    /// Open and prime the up-next track so the pipeline can switch to it without a gap
+    fn preload_next(&mut self) {
+        // A cast device is handed each song as the one before ends.
+        let seamless = (self.gapless || self.crossfade_ms > 0)
+            && !self.stops_at_track_end()
+            && !self.is_casting();
+        let gain = self
+            .next_song
+            .as_ref()
+            .map_or(1.0, |s| self.normalization_gain(s));
+        let mut trim = None;
+        let source = match &self.next_song {
+            // A bookmarked next song starts where it was left off, which needs a fresh load.
+            Some(song) if seamless && self.bookmark_for(song).is_none() => {
+                match pipeline::open_source(song, self.sample_rate) {
+                    Ok(mut source) => {
+                        trim = self.trim_next_source(song, &mut source);
+                        Some(pipeline::prime(source))
+                    }
+                    Err(e) => {
+                        log::warn!("failed to preload {}: {e}", song.file_path);
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+        let mut player = self.player.lock().unwrap();
+        player.load_next(source, gain);
+        if let Some((start, silence)) = trim {
+            player.trim_next(start, Some(silence.trailing_secs));
+        }
+    }
+
+    fn state(&self) -> PlaybackState {
+        self.shared.status.lock().unwrap().state.clone()
+    }
+
+    /// Playing, including while a network stream waits for its buffer
+    fn is_playing(&self) -> bool {
+        matches!(
+            self.state(),
+            PlaybackState::Playing | PlaybackState::Buffering { .. }
+        )
+    }
+
+    fn song(&self) -> Option<Song> {
+        self.shared.status.lock().unwrap().song.clone()
+    }
+
+    fn set_state(&self, state: PlaybackState, song: Option<Song>) {
+        {
+            let mut status = self.shared.status.lock().unwrap();
+            if status.state == state
+                && status.song.as_ref().map(|s| &s.id) == song.as_ref().map(|s| &s.id)
+            {
+                return;
+            }
+            status.state = state.clone();
+            status.song = song.clone();
+        }
+        self.shared
+            .events
+            .emit(AudioEvent::PlaybackStateChanged { state, song });
+    }
+}
@@ -0,0 +1,1074 @@
+use std::fmt::Write;
+use std::path::{Path, PathBuf, MAIN_SEPARATOR};
+
+use super::{Ack, Client, Subsystem};
+use crate::{cue, library, metadata, PlaybackState, RepeatMode, ShuffleMode, Song, SongId};
+
+/// What `commands` answers with: every command understood
+const COMMANDS: &[&str] = &[
+    "add",
+    "addid",
+    "clear",
+    "clearerror",
+    "close",
+    "command_list_begin",
+    "command_list_end",
+    "command_list_ok_begin",
+    "commands",
+    "consume",
+    "count",
+    "crossfade",
+    "currentsong",
+    "delete",
+    "deleteid",
+    "find",
+    "getvol",
+    "idle",
+    "list",
+    "listall",
+    "listallinfo",
+    "listplaylist",
+    "listplaylistinfo",
+    "listplaylists",
+    "load",
+    "lsinfo",
+    "move",
+    "moveid",
+    "next",
+    "noidle",
+    "notcommands",
+    "outputs",
+    "password",
+    "pause",
+    "ping",
+    "play",
+    "playid",
+    "playlist",
+    "playlistadd",
+    "playlistclear",
+    "playlistdelete",
+    "playlistid",
+    "playlistinfo",
+    "playlistmove",
+    "plchanges",
+    "plchangesposid",
+    "previous",
+    "random",
+    "rename",
+    "repeat",
+    "rm",
+    "save",
+    "search",
+    "seek",
+    "seekcur",
+    "seekid",
+    "setvol",
+    "single",
+    "stats",
+    "status",
+    "stop",
+    "tagtypes",
+    "urlhandlers",
+    "volume",
+];
+
+/// Tags songs are described with, as `tagtypes` lists them
+const TAG_TYPES: &[&str] = &[
+    "Artist",
+    "ArtistSort",
+    "Album",
+    "AlbumSort",
+    "AlbumArtist",
+    "Title",
+    "Track",
+    "Genre",
+    "Date",
+    "Disc",
+];
+
+/// Where the MPD client's paths start: the folder every local song is below
+struct Root(Option<PathBuf>);
+
+impl Root {
+    fn get() -> Result<Root, Ack> {
+        if !library::is_open() {
+            return Ok(Root(None));
+        }
+        let root = library::with_library(|lib| lib.get_music_root())?;
+        Ok(Root(root.map(PathBuf::from)))
+    }
+
+    /// The path `uri` names; absolute paths and `file://` URLs stand for themselves
+    fn path(&self, uri: &str) -> PathBuf {
+        if let Some(path) = url::Url::parse(uri)
+            .ok()
+            .filter(|url| url.scheme() == "file")
+            .and_then(|url| url.to_file_path().ok())
+        {
+            return path;
+        }
+        let path = Path::new(uri);
+        match (&self.0, path.is_absolute()) {
+            (Some(root), false) => root.join(uri.trim_matches('/')),
+            _ => path.to_path_buf(),
+        }
+    }
+
+    /// How the MPD client is shown `path`: relative to the root, with `/` between names
+    fn uri(&self, path: &str) -> String {
+        let relative = self
+            .0
+            .as_deref()
+            .and_then(|root| Path::new(path).strip_prefix(root).ok());
+        match relative {
+            Some(relative) => relative.to_string_lossy().replace(MAIN_SEPARATOR, "/"),
+            None => path.to_string(),
+        }
+    }
+
+    /// How `song` is shown: its file's URI, with the track for one cut by a CUE sheet
+    fn song_uri(&self, song: &Song) -> String {
+        let uri = self.uri(&song.file_path);
+        match (cue::is_track(song), song.track_number) {
+            (true, Some(track)) => format!("{uri}/track{track:04}"),
+            _ => uri,
+        }
+    }
+}
+
+fn number<T: std::str::FromStr>(arg: Option<&String>, what: &str) -> Result<T, Ack> {
+    let arg = arg.ok_or_else(|| Ack::arg(format!("missing {what}")))?;
+    arg.parse()
+        .map_err(|_| Ack::arg(format!("Integer or number expected: {arg}")))
+}
+
+fn flag(arg: Option<&String>) -> Result<bool, Ack> {
+    match arg.map(String::as_str) {
+        Some("0") => Ok(false),
+        Some("1") => Ok(true),
+        _ => Err(Ack::arg("Boolean (0/1) expected")),
+    }
+}
+
+/// Positions a `POS` or `START:END` argument covers, given `len` entries
+fn range(arg: &str, len: usize) -> Result<std::ops::Range<usize>, Ack> {
+    let bad = || Ack::arg(format!("Bad song index: {arg}"));
+    let range = match arg.split_once(':') {
+        Some((start, "")) => start.parse().map_err(|_| bad())?..len,
+        Some((start, end)) => start.parse().map_err(|_| bad())?..end.parse().map_err(|_| bad())?,
+        None => {
+            let position: usize = arg.parse().map_err(|_| bad())?;
+            position..position + 1
+        }
+    };
+    match range.start < range.end && range.end <= len {
+        true => Ok(range),
+        false => Err(bad()),
+    }
+}
+
+/// Whether `song` has `value` for the MPD tag `tag`, exactly or as a case-insensitive
+/// substring
+fn matches(root: &Root, song: &Song, tag: &str, value: &str, exact: bool) -> bool {
+    let fields = match tag.to_ascii_lowercase().as_str() {
+        "any" => vec![
+            song.title.clone(),
+            song.artist.clone(),
+            song.album.clone(),
+            song.album_artist.clone().unwrap_or_default(),
+            song.genre.clone().unwrap_or_default(),
+        ],
+        "file" | "base" => vec![root.song_uri(song)],
+        "artist" => vec![song.artist.clone()],
+        "albumartist" => vec![song.album_artist.clone().unwrap_or_default()],
+        "album" => vec![song.album.clone()],
+        "title" => vec![song.title.clone()],
+        "genre" => vec![song.genre.clone().unwrap_or_default()],
+        "date" => vec![song.year.map(|y| y.to_string()).unwrap_or_default()],
+        "track" => vec![song.track_number.map(|t| t.to_string()).unwrap_or_default()],
+        _ => return false,
+    };
+    let value_lower = value.to_lowercase();
+    fields.iter().any(|field| match exact {
+        true => field == value,
+        false => field.to_lowercase().contains(&value_lower),
+    })
+}
+
+/// The value of the MPD tag `tag` `list` groups `song` under
+fn tag_value(song: &Song, tag: &str) -> Option<String> {
+    match tag {
+        "artist" => Some(song.artist.clone()),
+        "albumartist" => Some(
+            song.album_artist
+                .clone()
+                .unwrap_or_else(|| song.artist.clone()),
+        ),
+        "album" => Some(song.album.clone()),
+        "title" => Some(song.title.clone()),
+        "genre" => song.genre.clone(),
+        "date" => song.year.map(|y| y.to_string()),
+        _ => None,
+    }
+}
+
+/// MPD's name for the tag `tag`, as `list` answers with it
+fn tag_name(tag: &str) -> Option<&'static str> {
+    TAG_TYPES
+        .iter()
+        .copied()
+        .find(|name| name.eq_ignore_ascii_case(tag))
+}
+
+/// A song for the radio stream or other URL `url`
+fn url_song(url: &str) -> Song {
+    Song {
+        id: SongId(url.to_string()),
+        title: url.to_string(),
+        file_path: url.to_string(),
+        ..Default::default()
+    }
+}
+
+impl Client {
+    /// Carry out `command`, returning its answer without the final `OK`
+    pub(super) fn run(&mut self, command: &str, args: &[String]) -> Result<String, Ack> {
+        let arg = |index: usize| args.get(index);
+        let text = |index: usize, what: &str| {
+            arg(index)
+                .map(String::as_str)
+                .ok_or_else(|| Ack::arg(format!("missing {what}")))
+        };
+        let engine = &self.engine;
+        match command {
+            "ping" | "clearerror" | "password" | "binarylimit" => Ok(String::new()),
+            "commands" => Ok(COMMANDS.iter().map(|c| format!("command: {c}\n")).collect()),
+            "notcommands" => Ok(String::new()),
+            "tagtypes" if args.is_empty() => Ok(TAG_TYPES
+                .iter()
+                .map(|t| format!("tagtype: {t}\n"))
+                .collect()),
+            // Which tags are sent can't be narrowed, but clients needn't know.
+            "tagtypes" => Ok(String::new()),
+            "urlhandlers" => Ok("handler: http://\nhandler: https://\n".into()),
+            "outputs" => {
+                let name = engine
+                    .get_output_format()
+                    .map_or("tunes4r (no output)", |_| "tunes4r");
+                Ok(format!(
+                    "outputid: 0\noutputname: {name}\nplugin: tunes4r\noutputenabled: 1\n"
+                ))
+            }
+            "status" => Ok(self.status()),
+            "currentsong" => {
+                let Some(song) = engine.shared.status.lock().unwrap().song.clone() else {
+                    return Ok(String::new());
+                };
+                let position = engine.shared.queue.lock().unwrap().current_index();
+                Ok(self.describe(&Root::get()?, &song, position))
+            }
+            "stats" => self.stats(),
+
+            "play" => {
+                match arg(0) {
+                    Some(_) => {
+                        let position: usize = number(arg(0), "song position")?;
+                        self.queue_position(position)?;
+                        engine.play_queue_index(position as u32);
+                    }
+                    None => self.play(),
+                }
+                Ok(String::new())
+            }
+            "playid" => {
+                match arg(0) {
+                    Some(_) => {
+                        let position = self.position_of_id(number(arg(0), "song id")?)?;
+                        engine.play_queue_index(position as u32);
+                    }
+                    None => self.play(),
+                }
+                Ok(String::new())
+            }
+            "pause" => {
+                let pause = match arg(0) {
+                    Some(_) => flag(arg(0))?,
+                    None => matches!(
+                        engine.shared.status.lock().unwrap().state,
+                        PlaybackState::Playing | PlaybackState::Buffering { .. }
+                    ),
+                };
+                match pause {
+                    true => engine.pause(),
+                    false => engine.resume(),
+                }
+                Ok(String::new())
+            }
+            "stop" | "next" | "previous" => {
+                match command {
+                    "stop" => engine.stop(),
+                    "next" => engine.skip_next(),
+                    _ => engine.skip_previous(),
+                }
+                Ok(String::new())
+            }
+            "seek" | "seekid" => {
+                let position = match command {
+                    "seek" => number(arg(0), "song position")?,
+                    _ => self.position_of_id(number(arg(0), "song id")?)?,
+                };
+                self.queue_position(position)?;
+                let secs: f64 = number(arg(1), "time")?;
+                let current = engine.shared.queue.lock().unwrap().current_index();
+                if current != Some(position) {
+                    engine.play_queue_index(position as u32);
+                }
+                engine.seek_to(secs.max(0.0));
+                Ok(String::new())
+            }
+            "seekcur" => {
+                let secs: f64 = number(arg(0), "time")?;
+                let secs = match text(0, "time")?.starts_with(['+', '-']) {
+                    true => engine.shared.position_secs() + secs,
+                    false => secs,
+                };
+                engine.seek_to(secs.max(0.0));
+                Ok(String::new())
+            }
+            "setvol" => {
+                let volume: u32 = number(arg(0), "volume")?;
+                engine.set_volume(volume.min(100) as f32 / 100.0);
+                Ok(String::new())
+            }
+            "volume" => {
+                let change: i32 = number(arg(0), "volume change")?;
+                let volume = engine.get_volume() + change as f32 / 100.0;
+                engine.set_volume(volume.clamp(0.0, 1.0));
+                Ok(String::new())
+            }
+            "getvol" => Ok(format!(
+                "volume: {}\n",
+                (engine.get_volume() * 100.0).round()
+            )),
+            "random" => {
+                let mode = match flag(arg(0))? {
+                    true => ShuffleMode::On,
+                    false => ShuffleMode::Off,
+                };
+                engine.set_shuffle(mode);
+                Ok(String::new())
+            }
+            "repeat" | "single" => {
+                let on = flag(arg(0))?;
+                let current = engine.shared.queue.lock().unwrap().repeat();
+                let mode = match (command, on, current) {
+                    ("repeat", false, _) => RepeatMode::Off,
+                    ("repeat", true, RepeatMode::One) => RepeatMode::One,
+                    ("repeat", true, _) => RepeatMode::All,
+                    (_, true, _) => RepeatMode::One,
+                    (_, false, RepeatMode::One) => RepeatMode::All,
+                    (_, false, mode) => mode,
+                };
+                engine.set_repeat(mode);
+                Ok(String::new())
+            }
+            "consume" => match flag(arg(0))? {
+                false => Ok(String::new()),
+                true => Err(Ack::arg("consume mode isn't supported")),
+            },
+            "crossfade" => {
+                let secs: u32 = number(arg(0), "seconds")?;
+                let curve = engine.get_settings().crossfade_curve;
+                engine.set_crossfade(secs * 1000, curve);
+                Ok(String::new())
+            }
+
+            "add" | "addid" => {
+                let root = Root::get()?;
+                let songs = self.resolve(&root, text(0, "URI")?)?;
+                if command == "add" {
+                    songs.into_iter().for_each(|song| engine.queue_add(song));
+                    return Ok(String::new());
+                }
+                let [song] = <[Song; 1]>::try_from(songs)
+                    .map_err(|_| Ack::no_exist("addid adds a single song"))?;
+                let id = self.state.id_of(&song.id);
+                match arg(1) {
+                    Some(_) => engine.queue_insert(number(arg(1), "position")?, song)?,
+                    None => engine.queue_add(song),
+                }
+                Ok(format!("Id: {id}\n"))
+            }
+            "clear" => {
+                engine.stop();
+                engine.queue_clear();
+                Ok(String::new())
+            }
+            "delete" | "deleteid" => {
+                let positions = match command {
+                    "delete" => range(text(0, "song position")?, engine.get_queue().len())?,
+                    _ => {
+                        let position = self.position_of_id(number(arg(0), "song id")?)?;
+                        position..position + 1
+                    }
+                };
+                for position in positions.rev() {
+                    engine.queue_remove(position as u32)?;
+                }
+                Ok(String::new())
+            }
+            "move" | "moveid" => {
+                let len = engine.get_queue().len();
+                let from = match command {
+                    "move" => range(text(0, "song position")?, len)?,
+                    _ => {
+                        let position = self.position_of_id(number(arg(0), "song id")?)?;
+                        position..position + 1
+                    }
+                };
+                let to: usize = number(arg(1), "song position")?;
+                if to + from.len() > len {
+                    return Err(Ack::arg(format!("Bad song index: {to}")));
+                }
+                // Entry by entry, so the block keeps its order and starts at `to`.
+                let last = to + from.len() - 1;
+                for (i, position) in from.clone().enumerate() {
+                    match to > from.start {
+                        true => engine.queue_move(from.start as u32, last as u32)?,
+                        false => engine.queue_move(position as u32, (to + i) as u32)?,
+                    }
+                }
+                Ok(String::new())
+            }
+            "playlist" => {
+                let root = Root::get()?;
+                let queue = engine.get_queue();
+                Ok(queue
+                    .iter()
+                    .enumerate()
+                    .map(|(i, song)| format!("{i}:file: {}\n", root.song_uri(song)))
+                    .collect())
+            }
+            "playlistinfo" | "playlistid" | "plchanges" | "plchangesposid" => {
+                let queue = engine.get_queue();
+                let positions = match command {
+                    "playlistinfo" => match arg(0) {
+                        Some(arg) => range(arg, queue.len())?,
+                        None => 0..queue.len(),
+                    },
+                    "playlistid" => match arg(0) {
+                        Some(_) => {
+                            let position = self.position_of_id(number(arg(0), "song id")?)?;
+                            position..position + 1
+                        }
+                        None => 0..queue.len(),
+                    },
+                    // Changes aren't tracked entry by entry, so all of an older queue
+                    // counts as changed.
+                    _ => {
+                        let version: u64 = number(arg(0), "version")?;
+                        match version < self.state.change_count(Subsystem::Playlist) {
+                            true => 0..queue.len(),
+                            false => 0..0,
+                        }
+                    }
+                };
+                let root = Root::get()?;
+                let mut answer = String::new();
+                for position in positions {
+                    let song = &queue[position];
+                    match command {
+                        "plchangesposid" => {
+                            let id = self.state.id_of(&song.id);
+                            let _ = write!(answer, "cpos: {position}\nId: {id}\n");
+                        }
+                        _ => answer.push_str(&self.describe(&root, song, Some(position))),
+                    }
+                }
+                Ok(answer)
+            }
+
+            "listplaylists" => {
+                let playlists = library::with_library(|lib| lib.get_playlists())?;
+                Ok(playlists
+                    .iter()
+                    .map(|p| {
+                        let modified = chrono::DateTime::from_timestamp(p.created_at, 0)
+                            .unwrap_or_default()
+                            .format("%Y-%m-%dT%H:%M:%SZ");
+                        format!("playlist: {}\nLast-Modified: {modified}\n", p.name)
+                    })
+                    .collect())
+            }
+            "listplaylist" | "listplaylistinfo" | "load" => {
+                let id = self.playlist_id(text(0, "playlist name")?)?;
+                let songs = library::with_library(|lib| lib.get_playlist_songs(id))?;
+                let root = Root::get()?;
+                Ok(match command {
+                    "listplaylist" => songs
+                        .iter()
+                        .map(|song| format!("file: {}\n", root.song_uri(song)))
+                        .collect(),
+                    "listplaylistinfo" => songs
+                        .iter()
+                        .map(|song| self.describe(&root, song, None))
+                        .collect(),
+                    _ => {
+                        songs.into_iter().for_each(|song| engine.queue_add(song));
+                        String::new()
+                    }
+                })
+            }
+            "save" => {
+                let name = text(0, "playlist name")?;
+                if self.playlist_id(name).is_ok() {
+                    return Err(Ack::new(Ack::EXIST, "Playlist already exists"));
+                }
+                let queue = engine.get_queue();
+                library::with_library(|lib| {
+                    let id = lib.create_playlist(name)?;
+                    let mut stored = Vec::new();
+                    for song in queue {
+                        // Radio streams and files outside the library can't be kept.
+                        if lib.get_song(&song.id)?.is_some() {
+                            stored.push(song.id);
+                        }
+                    }
+                    lib.playlist_add_songs(id, stored)
+                })?;
+                self.state.changed(Subsystem::StoredPlaylist);
+                Ok(String::new())
+            }
+            "rm" | "playlistclear" => {
+                let id = self.playlist_id(text(0, "playlist name")?)?;
+                library::with_library(|lib| match command {
+                    "rm" => lib.delete_playlist(id),
+                    _ => lib.playlist_clear(id),
+                })?;
+                self.state.changed(Subsystem::StoredPlaylist);
+                Ok(String::new())
+            }
+            "rename" => {
+                let id = self.playlist_id(text(0, "playlist name")?)?;
+                let name = text(1, "new playlist name")?;
+                if self.playlist_id(name).is_ok() {
+                    return Err(Ack::new(Ack::EXIST, "Playlist already exists"));
+                }
+                library::with_library(|lib| lib.rename_playlist(id, name))?;
+                self.state.changed(Subsystem::StoredPlaylist);
+                Ok(String::new())
+            }
+            "playlistadd" => {
+                let name = text(0, "playlist name")?;
+                let songs = self.resolve(&Root::get()?, text(1, "URI")?)?;
+                let id = match self.playlist_id(name) {
+                    Ok(id) => id,
+                    Err(_) => library::with_library(|lib| lib.create_playlist(name))?,
+                };
+                library::with_library(|lib| {
+                    let mut stored = Vec::new();
+                    for song in songs {
+                        if lib.get_song(&song.id)?.is_some() {
+                            stored.push(song.id);
+                        }
+                    }
+                    lib.playlist_add_songs(id, stored)
+                })?;
+                self.state.changed(Subsystem::StoredPlaylist);
+                Ok(String::new())
+            }
+            "playlistdelete" | "playlistmove" => {
+                let id = self.playlist_id(text(0, "playlist name")?)?;
+                let position: usize = number(arg(1), "song position")?;
+                let to = match command {
+                    "playlistmove" => Some(number(arg(2), "song position")?),
+                    _ => None,
+                };
+                library::with_library(|lib| match to {
+                    Some(to) => lib.playlist_move_song(id, position, to),
+                    None => lib.playlist_remove_song(id, position),
+                })
+                .map_err(|e| Ack::arg(format!("{e:#}")))?;
+                self.state.changed(Subsystem::StoredPlaylist);
+                Ok(String::new())
+            }
+
+            "lsinfo" => self.lsinfo(arg(0).map_or("", String::as_str)),
+            "listall" | "listallinfo" => {
+                let root = Root::get()?;
+                let dir = root.path(arg(0).map_or("", String::as_str));
+                let songs =
+                    library::with_library(|lib| lib.get_songs_below(&dir.to_string_lossy()))?;
+                let mut answer = String::new();
+                let mut listed_dir = String::new();
+                for song in &songs {
+                    let uri = root.song_uri(song);
+                    let dir = uri.rsplit_once('/').map_or("", |(dir, _)| dir);
+                    // Folders come before their files, songs being in path order.
+                    if dir != listed_dir {
+                        let mut parent = String::new();
+                        for name in dir.split('/') {
+                            parent = match parent.is_empty() {
+                                true => name.to_string(),
+                                false => format!("{parent}/{name}"),
+                            };
+                            if !listed_dir.starts_with(&format!("{parent}/"))
+                                && listed_dir != parent
+                            {
+                                let _ = writeln!(answer, "directory: {parent}");
+                            }
+                        }
+                        listed_dir = dir.to_string();
+                    }
+                    match command {
+                        "listall" => {
+                            let _ = writeln!(answer, "file: {uri}");
+                        }
+                        _ => answer.push_str(&self.describe(&root, song, None)),
+                    }
+                }
+                Ok(answer)
+            }
+            "find" | "search" | "count" => {
+                let root = Root::get()?;
+                let songs = self.filter(&root, args, command == "find")?;
+                match command {
+                    "count" => {
+                        let playtime: u64 = songs.iter().map(|song| song.duration).sum();
+                        Ok(format!("songs: {}\nplaytime: {playtime}\n", songs.len()))
+                    }
+                    _ => Ok(songs
+                        .iter()
+                        .map(|song| self.describe(&root, song, None))
+                        .collect()),
+                }
+            }
+            "list" => {
+                let tag = text(0, "tag type")?.to_ascii_lowercase();
+                let name =
+                    tag_name(&tag).ok_or_else(|| Ack::arg(format!("Unknown tag type: {tag}")))?;
+                let root = Root::get()?;
+                // The old form `list album ARTIST` filters by artist.
+                let filters = match args.len() {
+                    2 => vec!["artist".to_string(), args[1].clone()],
+                    _ => args[1..]
+                        .iter()
+                        .take_while(|arg| *arg != "group")
+                        .cloned()
+                        .collect(),
+                };
+                let songs = self.filter(&root, &filters, true)?;
+                let mut values: Vec<String> = songs
+                    .iter()
+                    .filter_map(|song| tag_value(song, &tag))
+                    .collect();
+                values.sort();
+                values.dedup();
+                Ok(values
+                    .iter()
+                    .map(|value| format!("{name}: {value}\n"))
+                    .collect())
+            }
+            "update" | "rescan" => Err(Ack::new(
+                Ack::PERMISSION,
+                "the library is scanned from the app",
+            )),
+            _ => Err(Ack::new(
+                Ack::UNKNOWN,
+                format!("unknown command \"{command}\""),
+            )),
+        }
+    }
+
+    fn status(&self) -> String {
+        let engine = &self.engine;
+        let (state, has_song) = {
+            let status = engine.shared.status.lock().unwrap();
+            (status.state.clone(), status.song.is_some())
+        };
+        let (length, current, next, shuffle, repeat, current_song, next_song) = {
+            let queue = engine.shared.queue.lock().unwrap();
+            let current = queue.current_index();
+            let next = queue.upcoming_index();
+            let songs = queue.songs();
+            (
+                songs.len(),
+                current,
+                next,
+                queue.shuffle(),
+                queue.repeat(),
+                current.and_then(|i| songs.get(i)).map(|s| s.id.clone()),
+                next.and_then(|i| songs.get(i)).map(|s| s.id.clone()),
+            )
+        };
+        let state = match state {
+            PlaybackState::Stopped => "stop",
+            PlaybackState::Paused => "pause",
+            _ => "play",
+        };
+        let mut answer = format!(
+            "volume: {}\nrepeat: {}\nrandom: {}\nsingle: {}\nconsume: 0\nplaylist: {}\n\
+             playlistlength: {length}\nstate: {state}\n",
+            (engine.get_volume() * 100.0).round(),
+            (repeat != RepeatMode::Off) as u8,
+            (shuffle == ShuffleMode::On) as u8,
+            (repeat == RepeatMode::One) as u8,
+            self.state.change_count(Subsystem::Playlist),
+        );
+        if let (Some(position), Some(id)) = (current, current_song) {
+            let id = self.state.id_of(&id);
+            let _ = write!(answer, "song: {position}\nsongid: {id}\n");
+        }
+        if let (Some(position), Some(id)) = (next, next_song) {
+            let id = self.state.id_of(&id);
+            let _ = write!(answer, "nextsong: {position}\nnextsongid: {id}\n");
+        }
+        if has_song && state != "stop" {
+            let elapsed = engine.shared.position_secs();
+            let duration = engine.shared.duration_secs().unwrap_or(0.0);
+            let _ = write!(
+                answer,
+                "time: {}:{}\nelapsed: {elapsed:.3}\nduration: {duration:.3}\n",
+                elapsed as u64,
+                duration.round() as u64
+            );
+        }
+        let crossfade_ms = engine.get_settings().crossfade_ms;
+        if crossfade_ms > 0 {
+            let _ = writeln!(answer, "xfade: {}", crossfade_ms / 1000);
+        }
+        if let Some(format) = engine.get_output_format() {
+            let bits = format.sample_format.strip_prefix(['i', 'u']).unwrap_or("f");
+            let _ = writeln!(
+                answer,
+                "audio: {}:{bits}:{}",
+                format.sample_rate, format.channels
+            );
+        }
+        answer
+    }
+
+    fn stats(&self) -> Result<String, Ack> {
+        let uptime = self.state.started.elapsed().as_secs();
+        let mut answer = format!("uptime: {uptime}\n");
+        if library::is_open() {
+            let (songs, artists, albums, playtime) = library::with_library(|lib| lib.get_totals())?;
+            let _ = write!(
+                answer,
+                "artists: {artists}\nalbums: {albums}\nsongs: {songs}\ndb_playtime: {playtime}\n"
+            );
+        }
+        Ok(answer)
+    }
+
+    /// Resume, or start the queue from its current entry when stopped
+    fn play(&self) {
+        let engine = &self.engine;
+        let state = engine.shared.status.lock().unwrap().state.clone();
+        match state {
+            PlaybackState::Stopped => {
+                let index = {
+                    let queue = engine.shared.queue.lock().unwrap();
+                    let has_songs = !queue.songs().is_empty();
+                    queue.current_index().or(has_songs.then_some(0))
+                };
+                if let Some(index) = index {
+                    engine.play_queue_index(index as u32);
+                }
+            }
+            _ => engine.resume(),
+        }
+    }
+
+    fn queue_position(&self, position: usize) -> Result<(), Ack> {
+        match position < self.engine.shared.queue.lock().unwrap().songs().len() {
+            true => Ok(()),
+            false => Err(Ack::arg(format!("Bad song index: {position}"))),
+        }
+    }
+
+    /// Queue position of the entry the client knows by `id`
+    fn position_of_id(&self, id: u32) -> Result<usize, Ack> {
+        let no_such_song = || Ack::no_exist(format!("No such song: {id}"));
+        let song = self.state.song_with_id(id).ok_or_else(no_such_song)?;
+        let queue = self.engine.shared.queue.lock().unwrap();
+        queue.position_of(&song).ok_or_else(no_such_song)
+    }
+
+    /// Library id of the stored playlist called `name`
+    fn playlist_id(&self, name: &str) -> Result<i64, Ack> {
+        let playlists = library::with_library(|lib| lib.get_playlists())?;
+        playlists
+            .iter()
+            .find(|p| p.name == name)
+            .map(|p| p.id)
+            .ok_or_else(|| Ack::no_exist("No such playlist"))
+    }
+
+    /// `song` as MPD describes one, at `position` of the queue if it's in it
+    fn describe(&self, root: &Root, song: &Song, position: Option<usize>) -> String {
+        let mut answer = format!("file: {}\n", root.song_uri(song));
+        let mut tag = |name: &str, value: Option<&str>| {
+            if let Some(value) = value.filter(|v| !v.is_empty()) {
+                let _ = writeln!(answer, "{name}: {value}");
+            }
+        };
+        tag("Title", Some(&song.title));
+        tag("Artist", Some(&song.artist));
+        tag("ArtistSort", song.artist_sort.as_deref());
+        tag("Album", Some(&song.album));
+        tag("AlbumSort", song.album_sort.as_deref());
+        tag("AlbumArtist", song.album_artist.as_deref());
+        tag("Genre", song.genre.as_deref());
+        tag("Date", song.year.map(|y| y.to_string()).as_deref());
+        tag("Track", song.track_number.map(|t| t.to_string()).as_deref());
+        tag("Disc", song.disc_number.map(|d| d.to_string()).as_deref());
+        if song.duration > 0 {
+            let _ = write!(
+                answer,
+                "Time: {}\nduration: {}.000\n",
+                song.duration, song.duration
+            );
+        }
+        if let Some(position) = position {
+            let id = self.state.id_of(&song.id);
+            let _ = write!(answer, "Pos: {position}\nId: {id}\n");
+        }
+        answer
+    }
+
+    /// Songs `uri` names: a radio stream, a folder's files, a file or a CUE track
+    fn resolve(&self, root: &Root, uri: &str) -> Result<Vec<Song>, Ack> {
+        if uri.starts_with("http://") || uri.starts_with("https://") {
+            return Ok(vec![url_song(uri)]);
+        }
+        let path = root.path(uri);
+        let file_path = path.to_string_lossy();
+        if library::is_open() {
+            let songs = library::with_library(|lib| {
+                let songs = lib.get_file_songs(&file_path)?;
+                match songs.is_empty() {
+                    true => lib.get_songs_below(&file_path),
+                    false => Ok(songs),
+                }
+            })?;
+            if !songs.is_empty() {
+                return Ok(songs);
+            }
+            // A CUE track, as "<image>/track0003"
+            let track = path
+                .file_name()
+                .and_then(|name| name.to_str()?.strip_prefix("track")?.parse::<u32>().ok());
+            if let (Some(track), Some(image)) = (track, path.parent()) {
+                let songs =
+                    library::with_library(|lib| lib.get_file_songs(&image.to_string_lossy()))?;
+                if let Some(song) = songs.into_iter().find(|s| s.track_number == Some(track)) {
+                    return Ok(vec![song]);
+                }
+            }
+        }
+        if !path.is_file() {
+            return Err(Ack::no_exist(format!("No such song: {uri}")));
+        }
+        let mut song = metadata::read_song(&path)?;
+        if let Err(e) = library::adopt_stored_id(&mut song) {
+            log::warn!("failed to look up {} in the library: {e:#}", path.display());
+        }
+        Ok(vec![song])
+    }
+
+    /// The folders and songs directly in the folder `uri`, or the file it names; the
+    /// top lists stored playlists too
+    fn lsinfo(&self, uri: &str) -> Result<String, Ack> {
+        if !library::is_open() {
+            return Ok(String::new());
+        }
+        let root = Root::get()?;
+        let Some(top) = &root.0 else {
+            return Ok(String::new());
+        };
+        let dir = match uri.trim_matches('/') {
+            "" => top.clone(),
+            uri => root.path(uri),
+        };
+        let dir_path = dir.to_string_lossy();
+        let (folders, songs) = library::with_library(|lib| lib.get_folder(&dir_path))?;
+        if folders.is_empty() && songs.is_empty() {
+            let songs = self.resolve(&root, uri)?;
+            return Ok(songs
+                .iter()
+                .map(|song| self.describe(&root, song, None))
+                .collect());
+        }
+        let mut answer = String::new();
+        for folder in folders {
+            let path = dir.join(folder);
+            let _ = writeln!(answer, "directory: {}", root.uri(&path.to_string_lossy()));
+        }
+        for song in &songs {
+            answer.push_str(&self.describe(&root, song, None));
+        }
+        if uri.trim_matches('/').is_empty() {
+            let playlists = library::with_library(|lib| lib.get_playlists())?;
+            for playlist in playlists {
+                let _ = writeln!(answer, "playlist: {}", playlist.name);
+            }
+        }
+        Ok(answer)
+    }
+
+    /// Library songs matching every TAG VALUE pair of `args`, exactly for `find`
+    fn filter(&self, root: &Root, args: &[String], exact: bool) -> Result<Vec<Song>, Ack> {
+        if args.first().is_some_and(|arg| arg.starts_with('(')) {
+            return Err(Ack::arg(
+                "filter expressions aren't supported; use TAG VALUE pairs",
+            ));
+        }
+        if !args.len().is_multiple_of(2) {
+            return Err(Ack::arg("incorrect arguments"));
+        }
+        if !library::is_open() {
+            return Ok(Vec::new());
+        }
+        let songs = library::with_library(|lib| lib.get_all_songs())?;
+        Ok(songs
+            .into_iter()
+            .filter(|song| {
+                args.chunks(2)
+                    .all(|pair| matches(root, song, &pair[0], &pair[1], exact))
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tokenize;
+    use super::*;
+
+    fn words(line: &str) -> Vec<String> {
+        tokenize(line).unwrap_or_else(|ack| panic!("{line}: {}", ack.message))
+    }
+
+    fn song() -> Song {
+        Song {
+            title: "Hey Jude".into(),
+            artist: "The Beatles".into(),
+            album: "Past Masters".into(),
+            genre: Some("Rock".into()),
+            year: Some(1968),
+            track_number: Some(7),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn tokenizes_quoted_and_escaped_words() {
+        assert_eq!(words("  play  3 "), ["play", "3"]);
+        assert_eq!(
+            words(r#"find artist "The Beatles" album "Say \"Hi\"""#),
+            ["find", "artist", "The Beatles", "album", r#"Say "Hi""#]
+        );
+        assert_eq!(
+            words(r#"add "C:\\Music\\a.mp3""#),
+            ["add", r"C:\Music\a.mp3"]
+        );
+        assert_eq!(words(r#"search any """#), ["search", "any", ""]);
+        assert!(words("").is_empty());
+    }
+
+    #[test]
+    fn rejects_an_unclosed_quote() {
+        let ack = tokenize(r#"find title "Hey"#).err().unwrap();
+        assert_eq!(ack.code, Ack::ARG);
+        assert_eq!(ack.line(0, ""), "ACK [2@0] {} Missing closing '\"'\n");
+    }
+
+    #[test]
+    fn parses_positions_and_ranges() {
+        assert_eq!(range("2", 5).ok(), Some(2..3));
+        assert_eq!(range("1:3", 5).ok(), Some(1..3));
+        assert_eq!(range("3:", 5).ok(), Some(3..5));
+        for bad in ["5", "3:3", "4:2", "0:6", "-1", "a:b", ""] {
+            assert!(range(bad, 5).is_err(), "{bad}");
+        }
+        assert_eq!(range("x", 5).err().unwrap().message, "Bad song index: x");
+    }
+
+    #[test]
+    fn parses_numbers_and_flags() {
+        let arg = |text: &str| Some(text.to_string());
+        assert_eq!(number::<u32>(arg("42").as_ref(), "song id").ok(), Some(42));
+        assert_eq!(number::<f64>(arg("1.5").as_ref(), "time").ok(), Some(1.5));
+        let ack = number::<u32>(arg("-1").as_ref(), "song id").err().unwrap();
+        assert_eq!(ack.message, "Integer or number expected: -1");
+        assert_eq!(
+            number::<u32>(None, "song id").err().unwrap().message,
+            "missing song id"
+        );
+        assert_eq!(flag(arg("0").as_ref()).ok(), Some(false));
+        assert_eq!(flag(arg("1").as_ref()).ok(), Some(true));
+        assert!(flag(arg("2").as_ref()).is_err());
+        assert!(flag(None).is_err());
+    }
+
+    #[test]
+    fn maps_uris_to_paths_below_the_root() {
+        let dir = std::env::temp_dir().join("music");
+        let root = Root(Some(dir.clone()));
+        let path = dir.join("Beatles").join("Hey Jude.mp3");
+        assert_eq!(root.path("Beatles/Hey Jude.mp3"), path);
+        assert_eq!(root.path("Beatles/Hey Jude.mp3/"), path);
+        assert_eq!(root.path(&path.to_string_lossy()), path);
+        let url = url::Url::from_file_path(&path).unwrap();
+        assert_eq!(root.path(url.as_str()), path);
+        assert_eq!(root.uri(&path.to_string_lossy()), "Beatles/Hey Jude.mp3");
+        assert_eq!(root.uri("http://radio/stream"), "http://radio/stream");
+        assert_eq!(Root(None).path("a/b.mp3"), Path::new("a/b.mp3"));
+    }
+
+    #[test]
+    fn names_cue_tracks_below_their_file() {
+        let dir = std::env::temp_dir().join("music");
+        let root = Root(Some(dir.clone()));
+        let mut track = Song {
+            file_path: dir.join("album.flac").to_string_lossy().into_owned(),
+            track_number: Some(3),
+            ..song()
+        };
+        assert_eq!(root.song_uri(&track), "album.flac");
+        track.start_offset = 200.0;
+        assert_eq!(root.song_uri(&track), "album.flac/track0003");
+    }
+
+    #[test]
+    fn matches_tags_exactly_or_as_substrings() {
+        let root = Root(None);
+        let song = song();
+        assert!(matches(&root, &song, "Artist", "The Beatles", true));
+        assert!(!matches(&root, &song, "artist", "beatles", true));
+        assert!(matches(&root, &song, "artist", "beatles", false));
+        assert!(matches(&root, &song, "any", "rock", false));
+        assert!(matches(&root, &song, "date", "1968", true));
+        assert!(matches(&root, &song, "track", "7", true));
+        assert!(!matches(&root, &song, "composer", "Lennon", false));
+    }
+
+    #[test]
+    fn groups_by_tag_values() {
+        let mut song = song();
+        assert_eq!(
+            tag_value(&song, "albumartist").as_deref(),
+            Some("The Beatles")
+        );
+        song.album_artist = Some("Various Artists".into());
+        assert_eq!(
+            tag_value(&song, "albumartist").as_deref(),
+            Some("Various Artists")
+        );
+        assert_eq!(tag_value(&song, "date").as_deref(), Some("1968"));
+        assert_eq!(tag_value(&song, "track"), None);
+        assert_eq!(tag_name("albumartist"), Some("AlbumArtist"));
+        assert_eq!(tag_name("composer"), None);
+    }
+}
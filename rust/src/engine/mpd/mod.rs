@@ -0,0 +1,515 @@
+mod commands;
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+
+use super::{AudioEngine, Command, EngineThread};
+use crate::{AudioEvent, SongId, StreamSink, TunesError};
+
+/// Protocol version announced to clients, whose commands they can rely on
+const PROTOCOL_VERSION: &str = "0.23.0";
+
+/// How often a client waiting in `idle` is checked for changes
+const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What `idle` tells clients changed, in the order reported
+#[derive(Clone, Copy, PartialEq)]
+enum Subsystem {
+    Database,
+    StoredPlaylist,
+    Playlist,
+    Player,
+    Mixer,
+    Output,
+    Options,
+}
+
+const SUBSYSTEMS: [Subsystem; 7] = [
+    Subsystem::Database,
+    Subsystem::StoredPlaylist,
+    Subsystem::Playlist,
+    Subsystem::Player,
+    Subsystem::Mixer,
+    Subsystem::Output,
+    Subsystem::Options,
+];
+
+impl Subsystem {
+    fn name(self) -> &'static str {
+        match self {
+            Subsystem::Database => "database",
+            Subsystem::StoredPlaylist => "stored_playlist",
+            Subsystem::Playlist => "playlist",
+            Subsystem::Player => "player",
+            Subsystem::Mixer => "mixer",
+            Subsystem::Output => "output",
+            Subsystem::Options => "options",
+        }
+    }
+
+    fn named(name: &str) -> Option<Subsystem> {
+        SUBSYSTEMS.into_iter().find(|s| s.name() == name)
+    }
+}
+
+/// State all connections of a server share
+struct State {
+    /// Times each of `SUBSYSTEMS` changed; the playlist's count is the queue version
+    changes: [AtomicU64; SUBSYSTEMS.len()],
+    /// Song ids handed out for queue entries, which MPD clients address songs by
+    ids: Mutex<HashMap<SongId, u32>>,
+    started: Instant,
+}
+
+impl State {
+    fn changed(&self, subsystem: Subsystem) {
+        self.changes[subsystem as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn change_count(&self, subsystem: Subsystem) -> u64 {
+        self.changes[subsystem as usize].load(Ordering::Relaxed)
+    }
+
+    /// The id MPD clients know `song` by, the same for as long as the server runs
+    fn id_of(&self, song: &SongId) -> u32 {
+        let mut ids = self.ids.lock().unwrap();
+        let next = ids.len() as u32 + 1;
+        *ids.entry(song.clone()).or_insert(next)
+    }
+
+    fn song_with_id(&self, id: u32) -> Option<SongId> {
+        let ids = self.ids.lock().unwrap();
+        ids.iter()
+            .find(|(_, &known)| known == id)
+            .map(|(song, _)| song.clone())
+    }
+}
+
+/// The running MPD server, owned by the engine thread; dropping it stops it
+///
+/// Its connections drive the engine through `AudioEngine`s of their own whose
+/// commands come here, so they never keep the engine alive on their own.
+pub(super) struct MpdServer {
+    port: u16,
+    stopped: Arc<AtomicBool>,
+    accepting: Option<JoinHandle<()>>,
+    /// Open connections, closed along with the server
+    connections: Arc<Mutex<Vec<(u64, TcpStream)>>>,
+    state: Arc<State>,
+    commands: Receiver<Command>,
+    /// Engine events, to tell idling clients what changed
+    events: Receiver<AudioEvent>,
+}
+
+impl Drop for MpdServer {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        // Wake the accept loop so it sees the flag.
+        let _ = TcpStream::connect(("127.0.0.1", self.port));
+        // Only once the loop is done is the port free to listen on again.
+        if let Some(accepting) = self.accepting.take() {
+            let _ = accepting.join();
+        }
+        for (_, stream) in self.connections.lock().unwrap().drain(..) {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+impl MpdServer {
+    /// Listen on `port` of every interface, 0 for any free one, for clients driving the
+    /// engine `engine` runs
+    fn start(engine: &EngineThread, port: u16) -> anyhow::Result<MpdServer> {
+        let listener = TcpListener::bind(("0.0.0.0", port))
+            .with_context(|| format!("failed to listen on port {port}"))?;
+        let port = listener.local_addr()?.port();
+        let (commands, rx) = mpsc::channel();
+        let (sink, events) = StreamSink::channel();
+        engine.shared.events.subscribe(sink);
+        let state = Arc::new(State {
+            changes: Default::default(),
+            ids: Mutex::new(HashMap::new()),
+            started: Instant::now(),
+        });
+        let stopped = Arc::new(AtomicBool::new(false));
+        let connections = Arc::new(Mutex::new(Vec::new()));
+        let remote = Remote {
+            sample_rate: engine.sample_rate,
+            commands,
+            shared: engine.shared.clone(),
+        };
+        let accepting = {
+            let (state, stopped, connections) =
+                (state.clone(), stopped.clone(), connections.clone());
+            thread::Builder::new()
+                .name("tunes4r-mpd".into())
+                .spawn(move || accept(listener, remote, state, stopped, connections))?
+        };
+        log::info!("MPD server listening on port {port}");
+        Ok(MpdServer {
+            port,
+            stopped,
+            accepting: Some(accepting),
+            connections,
+            state,
+            commands: rx,
+            events,
+        })
+    }
+}
+
+/// What each connection makes its `AudioEngine` from
+struct Remote {
+    sample_rate: u32,
+    commands: Sender<Command>,
+    shared: Arc<super::Shared>,
+}
+
+impl Remote {
+    fn engine(&self) -> AudioEngine {
+        AudioEngine {
+            sample_rate: self.sample_rate,
+            commands: self.commands.clone(),
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+fn accept(
+    listener: TcpListener,
+    remote: Remote,
+    state: Arc<State>,
+    stopped: Arc<AtomicBool>,
+    connections: Arc<Mutex<Vec<(u64, TcpStream)>>>,
+) {
+    for (number, stream) in (0..).zip(listener.incoming()) {
+        if stopped.load(Ordering::Relaxed) {
+            break;
+        }
+        let Ok(stream) = stream else {
+            continue;
+        };
+        let Ok(kept) = stream.try_clone() else {
+            continue;
+        };
+        connections.lock().unwrap().push((number, kept));
+        let (engine, state, connections) = (remote.engine(), state.clone(), connections.clone());
+        let spawned = thread::Builder::new()
+            .name("tunes4r-mpd-client".into())
+            .spawn(move || {
+                if let Err(e) = serve(stream, engine, state) {
+                    log::debug!("MPD client went away: {e:#}");
+                }
+                connections.lock().unwrap().retain(|(n, _)| *n != number);
+            });
+        if let Err(e) = spawned {
+            log::warn!("failed to serve an MPD client: {e}");
+        }
+    }
+}
+
+/// An error answer, as `ACK [code@index] {command} message`
+struct Ack {
+    code: u32,
+    message: String,
+}
+
+impl Ack {
+    const ARG: u32 = 2;
+    const PERMISSION: u32 = 4;
+    const UNKNOWN: u32 = 5;
+    const NO_EXIST: u32 = 50;
+    const SYSTEM: u32 = 52;
+    const EXIST: u32 = 56;
+
+    fn new(code: u32, message: impl Into<String>) -> Ack {
+        Ack {
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn arg(message: impl Into<String>) -> Ack {
+        Ack::new(Ack::ARG, message)
+    }
+
+    fn no_exist(message: impl Into<String>) -> Ack {
+        Ack::new(Ack::NO_EXIST, message)
+    }
+
+    fn line(&self, index: usize, command: &str) -> String {
+        format!(
+            "ACK [{}@{index}] {{{command}}} {}\n",
+            self.code, self.message
+        )
+    }
+}
+
+impl From<anyhow::Error> for Ack {
+    fn from(e: anyhow::Error) -> Ack {
+        Ack::new(Ack::SYSTEM, format!("{e:#}"))
+    }
+}
+
+impl From<TunesError> for Ack {
+    fn from(e: TunesError) -> Ack {
+        Ack::new(Ack::SYSTEM, e.to_string())
+    }
+}
+
+/// A client's place in the conversation
+struct Client {
+    engine: AudioEngine,
+    state: Arc<State>,
+    /// Change counts of `SUBSYSTEMS` last reported to the client by `idle`
+    seen: [u64; SUBSYSTEMS.len()],
+    /// Commands of a list being sent, and whether each gets a `list_OK`
+    list: Option<(bool, Vec<String>)>,
+}
+
+/// Talk to one client until it closes the connection or the server stops
+fn serve(stream: TcpStream, engine: AudioEngine, state: Arc<State>) -> anyhow::Result<()> {
+    // Lines arrive through a thread of their own, so `idle` can watch for `noidle`
+    // and for changes at once.
+    let (lines_tx, lines) = mpsc::channel();
+    let reader = BufReader::new(stream.try_clone()?);
+    thread::Builder::new()
+        .name("tunes4r-mpd-reader".into())
+        .spawn(move || {
+            for line in reader.lines() {
+                let Ok(line) = line else {
+                    break;
+                };
+                if lines_tx.send(line).is_err() {
+                    break;
+                }
+            }
+        })?;
+    let mut stream = stream;
+    writeln!(stream, "OK MPD {PROTOCOL_VERSION}")?;
+    let seen = SUBSYSTEMS.map(|s| state.change_count(s));
+    let mut client = Client {
+        engine,
+        state,
+        seen,
+        list: None,
+    };
+    while let Ok(line) = lines.recv() {
+        let answer = match client.receive(&line) {
+            Received::Answer(answer) => answer,
+            Received::Idle(subsystems) => match client.idle(&subsystems, &lines) {
+                Some(answer) => answer,
+                None => return Ok(()),
+            },
+            Received::Close => return Ok(()),
+        };
+        stream.write_all(answer.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// What a line from the client asks for
+enum Received {
+    /// Send this back; empty within a command list
+    Answer(String),
+    /// Wait for one of these subsystems to change
+    Idle(Vec<String>),
+    Close,
+}
+
+impl Client {
+    fn receive(&mut self, line: &str) -> Received {
+        let words = match tokenize(line) {
+            Ok(words) => words,
+            Err(ack) => return Received::Answer(ack.line(0, "")),
+        };
+        let Some(command) = words.first().map(String::as_str) else {
+            return Received::Answer(Ack::new(Ack::UNKNOWN, "No command given").line(0, ""));
+        };
+        if let Some((with_ok, commands)) = &mut self.list {
+            if command != "command_list_end" {
+                commands.push(line.to_string());
+                return Received::Answer(String::new());
+            }
+            let with_ok = *with_ok;
+            let commands = std::mem::take(commands);
+            self.list = None;
+            return Received::Answer(self.run_list(&commands, with_ok));
+        }
+        match command {
+            "command_list_begin" | "command_list_ok_begin" => {
+                self.list = Some((command == "command_list_ok_begin", Vec::new()));
+                Received::Answer(String::new())
+            }
+            "idle" => Received::Idle(words[1..].to_vec()),
+            "close" => Received::Close,
+            // Only meaningful during `idle`, and harmless after it ended.
+            "noidle" => Received::Answer(String::new()),
+            _ => Received::Answer(match self.run(command, &words[1..]) {
+                Ok(answer) => answer + "OK\n",
+                Err(ack) => ack.line(0, command),
+            }),
+        }
+    }
+
+    /// Run a command list, stopping at the first command that fails
+    fn run_list(&mut self, lines: &[String], with_ok: bool) -> String {
+        let mut answer = String::new();
+        for (index, line) in lines.iter().enumerate() {
+            let words = match tokenize(line) {
+                Ok(words) if !words.is_empty() => words,
+                Ok(_) => continue,
+                Err(ack) => return answer + &ack.line(index, ""),
+            };
+            match self.run(&words[0], &words[1..]) {
+                Ok(part) => answer.push_str(&part),
+                Err(ack) => return answer + &ack.line(index, &words[0]),
+            }
+            if with_ok {
+                answer.push_str("list_OK\n");
+            }
+        }
+        answer + "OK\n"
+    }
+
+    /// Wait until one of `names`, or any subsystem without any, has changed since the
+    /// client last heard, or it sends `noidle`; `None` once it's gone
+    fn idle(&mut self, names: &[String], lines: &Receiver<String>) -> Option<String> {
+        let mut wanted = Vec::new();
+        for name in names {
+            match Subsystem::named(name) {
+                Some(subsystem) => wanted.push(subsystem),
+                None => {
+                    let ack = Ack::arg(format!("Unrecognized idle event: {name}"));
+                    return Some(ack.line(0, "idle"));
+                }
+            }
+        }
+        if wanted.is_empty() {
+            wanted = SUBSYSTEMS.to_vec();
+        }
+        loop {
+            let mut answer = String::new();
+            for subsystem in SUBSYSTEMS.into_iter().filter(|s| wanted.contains(s)) {
+                let count = self.state.change_count(subsystem);
+                if count != self.seen[subsystem as usize] {
+                    self.seen[subsystem as usize] = count;
+                    answer.push_str(&format!("changed: {}\n", subsystem.name()));
+                }
+            }
+            if !answer.is_empty() {
+                return Some(answer + "OK\n");
+            }
+            match lines.recv_timeout(IDLE_POLL_INTERVAL) {
+                Ok(line) if line.trim() == "noidle" => return Some("OK\n".into()),
+                // Nothing but `noidle` is allowed while idle.
+                Ok(_) | Err(RecvTimeoutError::Disconnected) => return None,
+                Err(RecvTimeoutError::Timeout) => {}
+            }
+        }
+    }
+}
+
+/// Split a command line into words, honouring double quotes and backslash escapes
+fn tokenize(line: &str) -> Result<Vec<String>, Ack> {
+    let mut words = Vec::new();
+    let mut chars = line.trim().chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        let mut word = String::new();
+        if c == '"' {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => word.extend(chars.next()),
+                    Some(c) => word.push(c),
+                    None => return Err(Ack::arg("Missing closing '\"'")),
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                word.push(c);
+            }
+        }
+        words.push(word);
+    }
+    Ok(words)
+}
+
+impl AudioEngine {
+    /// Serve the MPD protocol on `port` of every interface, 0 for any free one, so MPD
+    /// clients can control playback and browse the library; returns the port listened on
+    ///
+    /// Replaces any server already running; `stop_mpd_server` stops it. Library
+    /// commands need the library open, and give paths relative to the folder every local
+    /// song is below. There's no password, so only use it on trusted networks.
+    pub fn start_mpd_server(&self, port: u16) -> Result<u16, TunesError> {
+        let (reply, started) = mpsc::channel();
+        self.send(Command::StartMpd { port, reply });
+        let started = started
+            .recv()
+            .map_err(|_| anyhow::anyhow!("audio thread is no longer running"))?;
+        Ok(started?)
+    }
+
+    pub fn stop_mpd_server(&self) {
+        self.send(Command::StopMpd);
+    }
+}
+
+impl EngineThread {
+    pub(super) fn start_mpd(&mut self, port: u16, reply: Sender<anyhow::Result<u16>>) {
+        // The old server has to let go of the port before asking for the same one.
+        self.mpd = None;
+        let started = MpdServer::start(self, port).map(|server| {
+            let port = server.port;
+            self.mpd = Some(server);
+            port
+        });
+        let _ = reply.send(started);
+    }
+
+    pub(super) fn stop_mpd(&mut self) {
+        self.mpd = None;
+    }
+
+    /// Carry out what clients asked for and note what changed for idling ones
+    pub(super) fn check_mpd(&mut self) {
+        let Some(mpd) = &self.mpd else {
+            return;
+        };
+        for event in mpd.events.try_iter() {
+            let subsystems: &[Subsystem] = match event {
+                AudioEvent::PlaybackStateChanged { .. }
+                | AudioEvent::TrackTransition { .. }
+                | AudioEvent::Seeked { .. }
+                | AudioEvent::ResumedFromSuspend { .. } => &[Subsystem::Player],
+                AudioEvent::VolumeChanged { .. } => &[Subsystem::Mixer],
+                // Shuffle and repeat changes come as queue changes too.
+                AudioEvent::QueueChanged { .. } => &[Subsystem::Playlist, Subsystem::Options],
+                AudioEvent::SettingsChanged { .. } => &[Subsystem::Options],
+                AudioEvent::DeviceChanged { .. } | AudioEvent::OutputFormatChanged { .. } => {
+                    &[Subsystem::Output]
+                }
+                _ => &[],
+            };
+            subsystems.iter().for_each(|&s| mpd.state.changed(s));
+        }
+        let commands: Vec<Command> = mpd.commands.try_iter().collect();
+        for command in commands {
+            self.handle(command);
+        }
+    }
+}
@@ -0,0 +1,535 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Mutex;
+
+use anyhow::Context;
+use zbus::fdo;
+use zbus::object_server::SignalEmitter;
+use zbus::zvariant::{ObjectPath, Value};
+use zbus::{interface, Connection};
+
+use super::{AudioEngine, Command, EngineThread};
+use crate::{
+    artwork, library, metadata, runtime, sources, AudioEvent, PlaybackState, RepeatMode,
+    ShuffleMode, Song, SongId, StorageKind, StreamSink, TunesError,
+};
+
+/// Bus name of the first instance; others add `.instance<pid>`, as the spec suggests
+const BUS_NAME: &str = "org.mpris.MediaPlayer2.tunes4r";
+
+const OBJECT_PATH: &str = "/org/mpris/MediaPlayer2";
+
+/// Track id for when nothing is loaded
+const NO_TRACK: &str = "/org/mpris/MediaPlayer2/TrackList/NoTrack";
+
+/// Name of the `.desktop` file the Linux build installs
+const DESKTOP_ENTRY: &str = "com.ocelot.tunes4r";
+
+/// The MPRIS player on the session bus, owned by the engine thread
+///
+/// Its interfaces drive the engine through an `AudioEngine` of their own whose
+/// commands come here, so they never keep the engine alive on their own.
+pub(super) struct Mpris {
+    connection: Connection,
+    commands: Receiver<Command>,
+    /// Engine events, to tell the bus what changed
+    events: Receiver<AudioEvent>,
+}
+
+/// What the bus is told changed after an engine event
+enum Change {
+    Status,
+    Metadata,
+    Volume,
+    Queue,
+    /// To this position, in microseconds
+    Seeked(i64),
+}
+
+impl AudioEngine {
+    /// Publish playback on the session D-Bus as an MPRIS player, so desktop media keys
+    /// and applets control the engine directly; Linux only
+    ///
+    /// Replaces any earlier registration; `disable_mpris` removes it.
+    pub fn enable_mpris(&self) -> Result<(), TunesError> {
+        let (commands, rx) = mpsc::channel();
+        let remote = AudioEngine {
+            sample_rate: self.sample_rate,
+            commands,
+            shared: self.shared.clone(),
+        };
+        let connection = runtime::block_on(connect(remote))?;
+        let (sink, events) = StreamSink::channel();
+        self.shared.events.subscribe(sink);
+        self.send(Command::SetMpris(Some(Mpris {
+            connection,
+            commands: rx,
+            events,
+        })));
+        Ok(())
+    }
+
+    pub fn disable_mpris(&self) {
+        self.send(Command::SetMpris(None));
+    }
+}
+
+/// Connect to the session bus and serve the player at the spec's path
+async fn connect(engine: AudioEngine) -> anyhow::Result<Connection> {
+    let connection = zbus::connection::Builder::session()?
+        .serve_at(OBJECT_PATH, Root)?
+        .serve_at(
+            OBJECT_PATH,
+            Player {
+                engine,
+                last_status: Mutex::new("Stopped"),
+                art: Mutex::new(None),
+            },
+        )?
+        .build()
+        .await
+        .context("failed to connect to the session D-Bus")?;
+    if connection.request_name(BUS_NAME).await.is_err() {
+        // Another instance has it already.
+        let name = format!("{BUS_NAME}.instance{}", std::process::id());
+        connection
+            .request_name(name)
+            .await
+            .context("failed to claim an MPRIS bus name")?;
+    }
+    log::info!("MPRIS player registered");
+    Ok(connection)
+}
+
+impl EngineThread {
+    pub(super) fn set_mpris(&mut self, mpris: Option<Mpris>) {
+        self.mpris = mpris;
+    }
+
+    /// Carry out what the bus asked for and tell it what changed since the last check
+    pub(super) fn check_mpris(&mut self) {
+        let Some(mpris) = &self.mpris else {
+            return;
+        };
+        let commands: Vec<Command> = mpris.commands.try_iter().collect();
+        let changes: Vec<Change> = mpris
+            .events
+            .try_iter()
+            .filter_map(|event| match event {
+                AudioEvent::PlaybackStateChanged { .. } => Some(Change::Status),
+                AudioEvent::TrackTransition { .. } => Some(Change::Metadata),
+                AudioEvent::VolumeChanged { .. } => Some(Change::Volume),
+                AudioEvent::QueueChanged { .. } => Some(Change::Queue),
+                AudioEvent::Seeked { position } => Some(Change::Seeked(micros(position))),
+                _ => None,
+            })
+            .collect();
+        if !changes.is_empty() {
+            let connection = mpris.connection.clone();
+            runtime::spawn(async move {
+                if let Err(e) = announce(&connection, changes).await {
+                    log::debug!("failed to signal MPRIS changes: {e}");
+                }
+            });
+        }
+        for command in commands {
+            self.handle(command);
+        }
+    }
+}
+
+/// Emit the property changes and signals for `changes`, in order
+async fn announce(connection: &Connection, changes: Vec<Change>) -> zbus::Result<()> {
+    let player = connection
+        .object_server()
+        .interface::<_, Player>(OBJECT_PATH)
+        .await?;
+    let emitter = player.signal_emitter();
+    for change in changes {
+        let player = player.get().await;
+        match change {
+            Change::Status => {
+                player.playback_status_changed(emitter).await?;
+                player.update_art().await;
+                player.metadata_changed(emitter).await?;
+                player.can_play_changed(emitter).await?;
+                player.can_pause_changed(emitter).await?;
+                player.can_seek_changed(emitter).await?;
+                player.can_go_previous_changed(emitter).await?;
+            }
+            Change::Metadata => {
+                player.update_art().await;
+                player.metadata_changed(emitter).await?;
+            }
+            Change::Volume => player.volume_changed(emitter).await?,
+            Change::Queue => {
+                player.can_go_next_changed(emitter).await?;
+                player.can_go_previous_changed(emitter).await?;
+                player.shuffle_changed(emitter).await?;
+                player.loop_status_changed(emitter).await?;
+            }
+            Change::Seeked(position) => Player::seeked(emitter, position).await?,
+        }
+    }
+    Ok(())
+}
+
+fn micros(secs: f64) -> i64 {
+    (secs * 1_000_000.0) as i64
+}
+
+/// D-Bus object path standing for `song`
+fn track_path(song: &Song) -> ObjectPath<'static> {
+    let mut hasher = DefaultHasher::new();
+    song.id.hash(&mut hasher);
+    let path = format!("/org/tunes4r/track/{:016x}", hasher.finish());
+    ObjectPath::try_from(path).expect("track ids are valid object paths")
+}
+
+/// `org.mpris.MediaPlayer2`: the application itself, which the engine can't raise or quit
+struct Root;
+
+#[interface(name = "org.mpris.MediaPlayer2")]
+impl Root {
+    fn raise(&self) {}
+
+    fn quit(&self) {}
+
+    #[zbus(property)]
+    fn can_quit(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn can_raise(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn has_track_list(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn identity(&self) -> &str {
+        "Tunes4R"
+    }
+
+    #[zbus(property)]
+    fn desktop_entry(&self) -> &str {
+        DESKTOP_ENTRY
+    }
+
+    #[zbus(property)]
+    fn supported_uri_schemes(&self) -> Vec<String> {
+        vec!["file".into(), "http".into(), "https".into()]
+    }
+
+    #[zbus(property)]
+    fn supported_mime_types(&self) -> Vec<String> {
+        [
+            "audio/mpeg",
+            "audio/flac",
+            "audio/ogg",
+            "audio/mp4",
+            "audio/aac",
+            "audio/wav",
+            "audio/aiff",
+        ]
+        .map(String::from)
+        .into()
+    }
+}
+
+/// `org.mpris.MediaPlayer2.Player`, answered from the engine's shared state
+struct Player {
+    engine: AudioEngine,
+    /// Status reported before a seek started, since seeking has no MPRIS status
+    last_status: Mutex<&'static str>,
+    /// Song id and `file://` URL of its cover art, once looked up
+    art: Mutex<Option<(SongId, Option<String>)>>,
+}
+
+impl Player {
+    fn song(&self) -> Option<Song> {
+        self.engine.shared.status.lock().unwrap().song.clone()
+    }
+
+    fn state(&self) -> PlaybackState {
+        self.engine.shared.status.lock().unwrap().state.clone()
+    }
+
+    fn length_secs(&self, song: &Song) -> f64 {
+        let duration = self.engine.shared.duration_secs();
+        duration.unwrap_or(song.duration as f64)
+    }
+
+    /// Look up the cover art of the current song off the runtime's threads
+    async fn update_art(&self) {
+        let Some(song) = self.song() else {
+            return;
+        };
+        let known = self.art.lock().unwrap().as_ref().map(|(id, _)| id.clone());
+        if known.as_ref() == Some(&song.id) {
+            return;
+        }
+        let path = song.file_path.clone();
+        let url = runtime::spawn_blocking(move || {
+            let cache_dir = library::cache_dir(StorageKind::Artwork)?;
+            match artwork::album_art_file(Path::new(&path), &cache_dir) {
+                Ok(file) => file.and_then(|file| url::Url::from_file_path(file).ok()),
+                Err(e) => {
+                    log::debug!("no MPRIS art for {path}: {e:#}");
+                    None
+                }
+            }
+        })
+        .await
+        .ok()
+        .flatten();
+        *self.art.lock().unwrap() = Some((song.id, url.map(String::from)));
+    }
+}
+
+#[interface(name = "org.mpris.MediaPlayer2.Player")]
+impl Player {
+    fn next(&self) {
+        self.engine.skip_next();
+    }
+
+    fn previous(&self) {
+        self.engine.skip_previous();
+    }
+
+    fn pause(&self) {
+        self.engine.pause();
+    }
+
+    fn play_pause(&self) {
+        match self.state() {
+            PlaybackState::Playing | PlaybackState::Buffering { .. } => self.engine.pause(),
+            _ => self.play(),
+        }
+    }
+
+    fn stop(&self) {
+        self.engine.stop();
+    }
+
+    /// Resume, or start the queue from its current entry when stopped
+    fn play(&self) {
+        match self.state() {
+            PlaybackState::Stopped => {
+                let index = {
+                    let queue = self.engine.shared.queue.lock().unwrap();
+                    let has_songs = !queue.songs().is_empty();
+                    queue.current_index().or(has_songs.then_some(0))
+                };
+                if let Some(index) = index {
+                    self.engine.play_queue_index(index as u32);
+                }
+            }
+            _ => self.engine.resume(),
+        }
+    }
+
+    /// Move by `offset` microseconds; past the end goes to the next track
+    fn seek(&self, offset: i64) {
+        let Some(song) = self.song() else {
+            return;
+        };
+        let position = self.engine.shared.position_secs() + offset as f64 / 1_000_000.0;
+        match position < self.length_secs(&song) {
+            true => self.engine.seek_to(position.max(0.0)),
+            false => self.engine.skip_next(),
+        }
+    }
+
+    fn set_position(&self, track_id: ObjectPath<'_>, position: i64) {
+        let Some(song) = self.song() else {
+            return;
+        };
+        let position = position as f64 / 1_000_000.0;
+        if track_id == track_path(&song) && (0.0..=self.length_secs(&song)).contains(&position) {
+            self.engine.seek_to(position);
+        }
+    }
+
+    fn open_uri(&self, uri: &str) -> fdo::Result<()> {
+        if uri.starts_with("http://") || uri.starts_with("https://") {
+            self.engine.play_url(uri.to_string());
+            return Ok(());
+        }
+        let path = url::Url::parse(uri)
+            .ok()
+            .filter(|url| url.scheme() == "file")
+            .and_then(|url| url.to_file_path().ok())
+            .ok_or_else(|| fdo::Error::InvalidArgs(format!("can't open {uri}")))?;
+        let mut song =
+            metadata::read_song(&path).map_err(|e| fdo::Error::Failed(format!("{e:#}")))?;
+        if let Err(e) = library::adopt_stored_id(&mut song) {
+            log::warn!("failed to look up {} in the library: {e:#}", path.display());
+        }
+        self.engine.play(song);
+        Ok(())
+    }
+
+    #[zbus(signal)]
+    async fn seeked(emitter: &SignalEmitter<'_>, position: i64) -> zbus::Result<()>;
+
+    #[zbus(property)]
+    fn playback_status(&self) -> &'static str {
+        let mut last = self.last_status.lock().unwrap();
+        *last = match self.state() {
+            PlaybackState::Playing | PlaybackState::Loading | PlaybackState::Buffering { .. } => {
+                "Playing"
+            }
+            PlaybackState::Paused => "Paused",
+            PlaybackState::Stopped => "Stopped",
+            PlaybackState::Seeking => *last,
+        };
+        *last
+    }
+
+    #[zbus(property)]
+    fn loop_status(&self) -> &'static str {
+        match self.engine.shared.queue.lock().unwrap().repeat() {
+            RepeatMode::Off => "None",
+            RepeatMode::One => "Track",
+            RepeatMode::All => "Playlist",
+        }
+    }
+
+    #[zbus(property)]
+    fn set_loop_status(&self, status: &str) -> fdo::Result<()> {
+        let mode = match status {
+            "None" => RepeatMode::Off,
+            "Track" => RepeatMode::One,
+            "Playlist" => RepeatMode::All,
+            _ => return Err(fdo::Error::InvalidArgs(format!("no loop status {status}"))),
+        };
+        self.engine.set_repeat(mode);
+        Ok(())
+    }
+
+    /// Speed changes stay with the app, so only normal speed is offered
+    #[zbus(property)]
+    fn rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn set_rate(&self, _rate: f64) {}
+
+    #[zbus(property)]
+    fn minimum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn maximum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn shuffle(&self) -> bool {
+        self.engine.shared.queue.lock().unwrap().shuffle() == ShuffleMode::On
+    }
+
+    #[zbus(property)]
+    fn set_shuffle(&self, shuffle: bool) {
+        let mode = if shuffle {
+            ShuffleMode::On
+        } else {
+            ShuffleMode::Off
+        };
+        self.engine.set_shuffle(mode);
+    }
+
+    #[zbus(property)]
+    fn metadata(&self) -> HashMap<&'static str, Value<'static>> {
+        let mut metadata = HashMap::new();
+        let Some(song) = self.song() else {
+            let no_track = ObjectPath::from_static_str_unchecked(NO_TRACK);
+            metadata.insert("mpris:trackid", Value::from(no_track));
+            return metadata;
+        };
+        metadata.insert("mpris:trackid", Value::from(track_path(&song)));
+        let length = micros(self.length_secs(&song));
+        metadata.insert("mpris:length", Value::from(length));
+        let art = self.art.lock().unwrap().clone();
+        if let Some((_, Some(url))) = art.filter(|(id, _)| *id == song.id) {
+            metadata.insert("mpris:artUrl", Value::from(url));
+        }
+        let path = Path::new(&song.file_path);
+        let url = match song.file_path.contains("://") {
+            _ if sources::is_remote(path) => None,
+            // Radio streams
+            true => Some(song.file_path.clone()),
+            false => url::Url::from_file_path(path).ok().map(String::from),
+        };
+        if let Some(url) = url {
+            metadata.insert("xesam:url", Value::from(url));
+        }
+        if let Some(genre) = song.genre {
+            metadata.insert("xesam:genre", Value::from(vec![genre]));
+        }
+        if let Some(stars) = song.rating {
+            metadata.insert("xesam:userRating", Value::from(stars as f64 / 5.0));
+        }
+        metadata.insert("xesam:title", Value::from(song.title));
+        metadata.insert("xesam:artist", Value::from(vec![song.artist]));
+        metadata.insert("xesam:album", Value::from(song.album));
+        metadata
+    }
+
+    #[zbus(property)]
+    fn volume(&self) -> f64 {
+        self.engine.get_volume() as f64
+    }
+
+    #[zbus(property)]
+    fn set_volume(&self, volume: f64) {
+        self.engine.set_volume(volume.clamp(0.0, 1.0) as f32);
+    }
+
+    /// In microseconds; players poll it, so changes aren't signalled
+    #[zbus(property(emits_changed_signal = "false"))]
+    fn position(&self) -> i64 {
+        micros(self.engine.shared.position_secs())
+    }
+
+    #[zbus(property)]
+    fn can_go_next(&self) -> bool {
+        let queue = self.engine.shared.queue.lock().unwrap();
+        queue.upcoming_index().is_some()
+    }
+
+    #[zbus(property)]
+    fn can_go_previous(&self) -> bool {
+        self.song().is_some()
+    }
+
+    #[zbus(property)]
+    fn can_play(&self) -> bool {
+        self.song().is_some() || !self.engine.shared.queue.lock().unwrap().songs().is_empty()
+    }
+
+    #[zbus(property)]
+    fn can_pause(&self) -> bool {
+        self.song().is_some()
+    }
+
+    #[zbus(property)]
+    fn can_seek(&self) -> bool {
+        self.song().is_some() && self.engine.shared.duration_secs().is_some()
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn can_control(&self) -> bool {
+        true
+    }
+}
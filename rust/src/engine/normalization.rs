@@ -0,0 +1,41 @@
+use super::{AudioEngine, Command, EngineThread};
+use crate::{loudness, NormalizationMode, Song};
+
+/// Default loudness target, matching the ReplayGain 2.0 reference level
+pub(crate) const DEFAULT_TARGET_LUFS: f32 = loudness::REPLAYGAIN_REFERENCE_LUFS;
+
+impl AudioEngine {
+    /// Normalize playback to `target_lufs` using track or album loudness
+    ///
+    /// Album mode falls back to the track loudness for songs without album loudness.
+    pub fn set_normalization(&self, mode: NormalizationMode, target_lufs: f32) {
+        self.send(Command::SetNormalization { mode, target_lufs });
+        self.shared.record_settings(|s| {
+            s.normalization = mode;
+            s.target_lufs = target_lufs;
+        });
+    }
+}
+
+impl EngineThread {
+    /// Linear normalization gain for `song` under the current settings
+    pub(super) fn normalization_gain(&self, song: &Song) -> f32 {
+        let loudness = match self.normalization {
+            NormalizationMode::Off => None,
+            NormalizationMode::Track => song.track_loudness,
+            NormalizationMode::Album => song.album_loudness.or(song.track_loudness),
+        };
+        loudness.map_or(1.0, |lufs| loudness::gain_for(lufs, self.target_lufs))
+    }
+
+    pub(super) fn set_normalization(&mut self, mode: NormalizationMode, target_lufs: f32) {
+        self.normalization = mode;
+        self.target_lufs = target_lufs;
+        let current = self.song().map_or(1.0, |s| self.normalization_gain(&s));
+        let next = self
+            .next_song
+            .as_ref()
+            .map_or(1.0, |s| self.normalization_gain(s));
+        self.player.lock().unwrap().set_gains(current, next);
+    }
+}
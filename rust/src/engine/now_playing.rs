@@ -0,0 +1,124 @@
+use std::path::Path;
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Weak};
+use std::thread;
+
+use super::{AudioEngine, Shared};
+use crate::{artwork, library, AudioEvent, NowPlaying, SongId, StorageKind, StreamSink};
+
+impl AudioEngine {
+    /// Stream what an OS media session shows (track, artwork, state and position) as
+    /// one value, sent at once and again whenever any of it changes
+    ///
+    /// Progress isn't sent as playback moves on; media sessions extrapolate from
+    /// `position_secs` themselves. Any number of streams may be open at once.
+    pub fn now_playing_stream(&self, sink: StreamSink<NowPlaying>) {
+        let (events_sink, events) = StreamSink::channel();
+        self.shared.events.subscribe(events_sink);
+        let feed = Feed {
+            shared: Arc::downgrade(&self.shared),
+            sink,
+            song_id: None,
+            artwork: None,
+            titled_song: None,
+            stream_title: None,
+        };
+        let spawned = thread::Builder::new()
+            .name("tunes4r-now-playing".into())
+            .spawn(move || feed.run(events));
+        if let Err(e) = spawned {
+            log::error!("failed to spawn now playing thread: {e}");
+        }
+    }
+}
+
+/// Worker thread behind one `now_playing_stream`
+struct Feed {
+    shared: Weak<Shared>,
+    sink: StreamSink<NowPlaying>,
+    /// Song `artwork` belongs to
+    song_id: Option<SongId>,
+    artwork: Option<Vec<u8>>,
+    /// Song `stream_title` belongs to
+    titled_song: Option<SongId>,
+    stream_title: Option<String>,
+}
+
+impl Feed {
+    /// Send updates until the stream closes, or the engine and its events are gone
+    fn run(mut self, events: Receiver<AudioEvent>) {
+        if !self.send() {
+            return;
+        }
+        for event in events {
+            let changed = match event {
+                AudioEvent::PlaybackStateChanged { song, .. } => {
+                    self.follow(song.as_ref().map(|s| &s.id));
+                    true
+                }
+                AudioEvent::TrackTransition { song, .. } => {
+                    self.follow(Some(&song.id));
+                    true
+                }
+                AudioEvent::Seeked { .. }
+                | AudioEvent::QueueChanged { .. }
+                | AudioEvent::CastChanged { .. } => true,
+                AudioEvent::StreamMetadataUpdated { title, .. } => {
+                    let changed = title.is_some() && title != self.stream_title;
+                    if changed {
+                        self.stream_title = title;
+                    }
+                    changed
+                }
+                _ => false,
+            };
+            if changed && !self.send() {
+                break;
+            }
+        }
+    }
+
+    /// Forget the stream title once another song plays
+    fn follow(&mut self, song_id: Option<&SongId>) {
+        if song_id != self.titled_song.as_ref() {
+            self.stream_title = None;
+            self.titled_song = song_id.cloned();
+        }
+    }
+
+    /// Send the engine's current state; false once the stream has closed
+    fn send(&mut self) -> bool {
+        let Some(shared) = self.shared.upgrade() else {
+            return false;
+        };
+        let (state, song) = {
+            let status = shared.status.lock().unwrap();
+            (status.state.clone(), status.song.clone())
+        };
+        let song_id = song.as_ref().map(|s| s.id.clone());
+        if song_id != self.song_id {
+            self.artwork = song.as_ref().and_then(|song| {
+                let cache_dir = library::cache_dir(StorageKind::Artwork)?;
+                let path = Path::new(&song.file_path);
+                artwork::album_art(path, &cache_dir)
+                    .inspect_err(|e| log::debug!("no artwork for {}: {e:#}", path.display()))
+                    .ok()
+                    .flatten()
+            });
+            self.song_id = song_id;
+        }
+        let can_skip_next = shared.queue.lock().unwrap().upcoming_index().is_some();
+        let fallback = song.as_ref().map_or(0.0, |s| s.duration as f64);
+        let now_playing = NowPlaying {
+            state,
+            artwork: self.artwork.clone(),
+            stream_title: self.stream_title.clone(),
+            position_secs: shared.position_secs(),
+            duration_secs: shared.duration_secs().unwrap_or(fallback),
+            can_skip_next,
+            can_skip_previous: song.is_some(),
+            song,
+        };
+        self.sink.add(now_playing).is_ok()
+    }
+}
@@ -0,0 +1,126 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rodio::cpal::traits::StreamTrait;
+use rodio::cpal::{
+    self, BufferSize, FromSample, SampleFormat, SizedSample, StreamConfig, SupportedBufferSize,
+    SupportedStreamConfig,
+};
+use rodio::source::UniformSourceIterator;
+use rodio::{DeviceTrait, Source};
+
+use super::metrics::Meters;
+use super::{AudioEngine, Command, EngineThread};
+
+/// Samples as the output stream asks for them
+type Samples = Box<dyn Iterator<Item = f32> + Send>;
+
+/// A running output stream, pulling from the pipeline
+pub(super) struct Output {
+    _stream: cpal::Stream,
+}
+
+impl Output {
+    /// Start playing `source`, an endless one for the pipeline, on `device` with `config`, in blocks of `buffer_frames`,
+    /// or the device's default for zero
+    ///
+    /// `latency` is kept up to date with the microseconds between a sample being
+    /// handed to the device and it being heard, and `meters` with how long each block
+    /// takes to produce.
+    pub fn open(
+        device: &cpal::Device,
+        config: &SupportedStreamConfig,
+        buffer_frames: u32,
+        source: impl Source<Item = f32> + Send + 'static,
+        latency: Arc<AtomicU32>,
+        meters: Arc<Meters>,
+    ) -> anyhow::Result<Output> {
+        let mut stream_config = config.config();
+        if buffer_frames > 0 {
+            let frames = match config.buffer_size() {
+                SupportedBufferSize::Range { min, max } => buffer_frames.clamp(*min, *max),
+                SupportedBufferSize::Unknown => buffer_frames,
+            };
+            stream_config.buffer_size = BufferSize::Fixed(frames);
+        }
+        let samples: Samples = if config.sample_rate().0 == source.sample_rate()
+            && config.channels() == source.channels()
+        {
+            Box::new(source)
+        } else {
+            // The device can't run at the pipeline's rate, so convert on the way out.
+            Box::new(UniformSourceIterator::<_, f32>::new(
+                source,
+                config.channels(),
+                config.sample_rate().0,
+            ))
+        };
+        let stream = match config.sample_format() {
+            SampleFormat::F32 => build::<f32>(device, &stream_config, samples, latency, meters),
+            SampleFormat::I32 => build::<i32>(device, &stream_config, samples, latency, meters),
+            SampleFormat::I16 => build::<i16>(device, &stream_config, samples, latency, meters),
+            SampleFormat::U16 => build::<u16>(device, &stream_config, samples, latency, meters),
+            format => anyhow::bail!("unsupported sample format {format}"),
+        }?;
+        stream.play()?;
+        Ok(Output { _stream: stream })
+    }
+}
+
+fn build<T>(
+    device: &cpal::Device,
+    config: &StreamConfig,
+    mut samples: Samples,
+    latency: Arc<AtomicU32>,
+    meters: Arc<Meters>,
+) -> Result<cpal::Stream, cpal::BuildStreamError>
+where
+    T: SizedSample + FromSample<f32>,
+{
+    let frame_rate = (config.sample_rate.0 * config.channels as u32) as f32;
+    device.build_output_stream::<T, _, _>(
+        config,
+        move |data, info| {
+            let started = Instant::now();
+            data.iter_mut()
+                .for_each(|d| *d = T::from_sample(samples.next().unwrap_or(0.0)));
+            // Backends that can't tell when the block will be heard get its own length.
+            let timestamp = info.timestamp();
+            let micros = match timestamp.playback.duration_since(&timestamp.callback) {
+                Some(delay) if !delay.is_zero() => delay.as_micros() as u32,
+                _ => (data.len() as f32 / frame_rate * 1e6) as u32,
+            };
+            latency.store(micros, Ordering::Relaxed);
+            let audio = Duration::from_secs_f32(data.len() as f32 / frame_rate);
+            meters.record_callback(started.elapsed(), audio);
+        },
+        |e| log::error!("output stream error: {e}"),
+        None,
+    )
+}
+
+impl AudioEngine {
+    /// Have the output device play in blocks of `frames`, zero for its default
+    ///
+    /// Larger blocks ride out hiccups on wireless devices at the cost of latency.
+    /// Sizes the device doesn't support are clamped to the nearest it does. Restarts
+    /// the output stream; playback continues where it was.
+    pub fn set_buffer_size(&self, frames: u32) {
+        self.send(Command::SetBufferSize(frames));
+    }
+
+    /// Time between audio leaving the engine and being heard, as last reported by the
+    /// output device; zero without any output
+    pub fn get_output_latency_ms(&self) -> f64 {
+        self.shared.output_latency.load(Ordering::Relaxed) as f64 / 1000.0
+    }
+}
+
+impl EngineThread {
+    pub(super) fn set_buffer_size(&mut self, frames: u32) {
+        self.buffer_frames = frames;
+        let device = self.output_device.clone();
+        self.open_output(device.as_deref());
+    }
+}
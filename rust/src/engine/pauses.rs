@@ -0,0 +1,106 @@
+use super::pipeline::CHANNELS;
+use super::{AudioEngine, Command, EngineThread};
+use crate::AudioEvent;
+
+/// Level in dBFS below which speech counts as paused
+const THRESHOLD_DB: f32 = -50.0;
+
+/// Pauses are shortened to this long unless configured otherwise
+pub(super) const DEFAULT_MIN_PAUSE_MS: u32 = 500;
+
+/// Shortens pauses in spoken word as tracks are decoded, by dropping every silent frame
+/// past the first `keep_frames` of a pause
+pub(crate) struct PauseSkipper {
+    sample_rate: u32,
+    enabled: bool,
+    keep_frames: u64,
+    threshold: f32,
+    /// Silent frames in a row so far
+    silent_run: u64,
+    /// Frames dropped from the current pause
+    dropped: u64,
+    saved_frames: u64,
+}
+
+impl PauseSkipper {
+    pub fn new(sample_rate: u32) -> Self {
+        let mut skipper = PauseSkipper {
+            sample_rate,
+            enabled: false,
+            keep_frames: 0,
+            threshold: 10f32.powf(THRESHOLD_DB / 20.0),
+            silent_run: 0,
+            dropped: 0,
+            saved_frames: 0,
+        };
+        skipper.set(false, DEFAULT_MIN_PAUSE_MS);
+        skipper
+    }
+
+    pub fn set(&mut self, enabled: bool, min_pause_ms: u32) {
+        if enabled && !self.enabled {
+            self.saved_frames = 0;
+        }
+        self.enabled = enabled;
+        self.keep_frames = min_pause_ms as u64 * self.sample_rate as u64 / 1000;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Time saved since pause skipping was turned on
+    pub fn saved_secs(&self) -> f64 {
+        self.saved_frames as f64 / self.sample_rate as f64
+    }
+
+    /// Drop the skipped frames among those of `block` from `from` on
+    ///
+    /// Returns true if a pause that was shortened ended in them.
+    pub fn process(&mut self, block: &mut Vec<f32>, from: usize) -> bool {
+        let channels = CHANNELS as usize;
+        let mut write = from;
+        let mut ended = false;
+        for read in (from..block.len()).step_by(channels) {
+            let frame = &block[read..read + channels];
+            if frame.iter().all(|s| s.abs() <= self.threshold) {
+                self.silent_run += 1;
+                if self.silent_run > self.keep_frames {
+                    self.dropped += 1;
+                    continue;
+                }
+            } else {
+                self.silent_run = 0;
+                if self.dropped > 0 {
+                    self.saved_frames += self.dropped;
+                    self.dropped = 0;
+                    ended = true;
+                }
+            }
+            block.copy_within(read..read + channels, write);
+            write += channels;
+        }
+        block.truncate(write);
+        ended
+    }
+}
+
+impl AudioEngine {
+    /// Shorten pauses in podcasts and audiobooks to `min_pause_ms` as they play
+    ///
+    /// Emits `SilenceSkipped` with the time saved so far after each shortened pause.
+    pub fn set_pause_skipping(&self, enabled: bool, min_pause_ms: u32) {
+        self.send(Command::SetPauseSkipping {
+            enabled,
+            min_pause_ms,
+        });
+    }
+}
+
+impl EngineThread {
+    pub(super) fn emit_pause_skipped(&self, total_saved_secs: f64) {
+        self.shared
+            .events
+            .emit(AudioEvent::SilenceSkipped { total_saved_secs });
+    }
+}
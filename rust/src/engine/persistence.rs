@@ -0,0 +1,151 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+
+use super::{pipeline, AudioEngine, Command, EngineThread, Shared};
+use crate::{RepeatMode, ShuffleMode, Song, TunesError};
+
+/// How often playback state is saved while a state file is set
+pub(super) const SAVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Where playback state is saved, and what was last written there
+#[derive(Default)]
+pub(super) struct StateFile {
+    path: Option<PathBuf>,
+    written: Option<String>,
+}
+
+/// What `restore_state` brings back
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SavedState {
+    queue: Vec<Song>,
+    current_index: Option<u32>,
+    song: Option<Song>,
+    position: f64,
+    volume: f32,
+    muted: bool,
+    shuffle: ShuffleMode,
+    repeat: RepeatMode,
+}
+
+impl AudioEngine {
+    /// Save playback state to `path` every few seconds from now on; `None` stops saving
+    ///
+    /// Call `restore_state` after this at startup to pick up where the last run left off.
+    pub fn set_state_file(&self, path: Option<String>) {
+        *self.shared.state_file.lock().unwrap() = StateFile {
+            path: path.map(PathBuf::from),
+            written: None,
+        };
+    }
+
+    /// Save the queue, the current song and position, volume, and shuffle and repeat
+    /// modes to the state file right away
+    pub fn save_state(&self) -> Result<(), TunesError> {
+        Ok(self.shared.save_state()?)
+    }
+
+    /// Load what the state file holds, with the current song paused at its position
+    ///
+    /// Returns false if nothing has been saved yet. A current song whose file is gone
+    /// is left out.
+    pub fn restore_state(&self) -> Result<bool, TunesError> {
+        let Some(saved) = self.shared.read_state()? else {
+            return Ok(false);
+        };
+        {
+            let mut queue = self.shared.queue.lock().unwrap();
+            queue.clear();
+            for song in saved.queue {
+                queue.add(song);
+            }
+            queue.set_current(saved.current_index.map(|i| i as usize));
+            queue.set_repeat(saved.repeat);
+            queue.set_shuffle(saved.shuffle);
+        }
+        self.queue_changed();
+        self.update_volume(|settings| {
+            settings.level = saved.volume.clamp(0.0, 1.0);
+            settings.muted = saved.muted;
+        });
+        if let Some(song) = saved.song.filter(|s| Path::new(&s.file_path).is_file()) {
+            self.send(Command::Restore {
+                song,
+                position: saved.position,
+            });
+        }
+        Ok(true)
+    }
+}
+
+impl Shared {
+    fn save_state(&self) -> anyhow::Result<()> {
+        let snapshot = self.snapshot();
+        let json = serde_json::to_string(&SavedState {
+            queue: snapshot.queue,
+            current_index: snapshot.current_index,
+            song: snapshot.song,
+            position: snapshot.current_time,
+            volume: snapshot.volume,
+            muted: snapshot.muted,
+            shuffle: snapshot.shuffle,
+            repeat: snapshot.repeat,
+        })?;
+        let mut file = self.state_file.lock().unwrap();
+        let Some(path) = file.path.clone() else {
+            anyhow::bail!("no state file; call set_state_file first");
+        };
+        if file.written.as_deref() == Some(json.as_str()) {
+            return Ok(());
+        }
+        // Written aside and renamed, so a crash mid-write leaves the previous state.
+        let partial = path.with_extension("tmp");
+        fs::write(&partial, &json)
+            .and_then(|()| fs::rename(&partial, &path))
+            .with_context(|| format!("failed to save playback state to {}", path.display()))?;
+        file.written = Some(json);
+        Ok(())
+    }
+
+    fn read_state(&self) -> anyhow::Result<Option<SavedState>> {
+        let mut file = self.state_file.lock().unwrap();
+        let Some(path) = file.path.clone() else {
+            anyhow::bail!("no state file; call set_state_file first");
+        };
+        let json = match fs::read_to_string(&path) {
+            Ok(json) => json,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+        };
+        let saved = serde_json::from_str(&json)
+            .with_context(|| format!("unreadable playback state in {}", path.display()))?;
+        file.written = Some(json);
+        Ok(Some(saved))
+    }
+}
+
+impl EngineThread {
+    /// Load `song` paused at `position`, as it was when the state was saved
+    pub(super) fn restore(&mut self, song: Song, position: f64) {
+        let sample_rate = self.sample_rate;
+        self.start_playback(song, Some(position), true, |song| {
+            pipeline::open_source(song, sample_rate)
+        });
+    }
+
+    pub(super) fn check_state_save(&mut self) {
+        if self.last_state_save.elapsed() < SAVE_INTERVAL {
+            return;
+        }
+        self.last_state_save = Instant::now();
+        if self.shared.state_file.lock().unwrap().path.is_none() {
+            return;
+        }
+        if let Err(e) = self.shared.save_state() {
+            log::warn!("{e:#}");
+        }
+    }
+}
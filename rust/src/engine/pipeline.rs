@@ -0,0 +1,947 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use rodio::Source;
+
+use super::announcements::Announcer;
+use super::beats::{Beat, BeatTracker};
+use super::channel_mode::ChannelMapper;
+use super::crossfade::Fade;
+use super::downmix::Downmix;
+use super::dsp_chain::{DspChain, Effect, Limiter};
+use super::dynamics::Compressor;
+use super::eq::Equalizer;
+use super::metrics::Meters;
+use super::pauses::PauseSkipper;
+use super::preamp::GainStage;
+use super::recording;
+use super::resampler::Resampler;
+use super::reverb::Reverb;
+use super::spectrum::{SpectrumAnalyzer, SpectrumFrame};
+use super::supervisor::panic_message;
+use super::timestretch::TimeStretch;
+use super::volume::VolumeRamp;
+use super::widener::StereoWidener;
+use super::zones::ZoneBuffer;
+use crate::decoder::SymphoniaSource;
+use crate::{cue, DspStage, FadeCurve, Song};
+
+/// Number of output channels the pipeline renders
+pub(crate) const CHANNELS: u16 = 2;
+
+/// Frames rendered per lock of the shared player state
+const BLOCK_FRAMES: usize = 512;
+
+/// Frames decoded ahead of time when a track is queued as up next
+pub(super) const PRIME_FRAMES: usize = 8192;
+
+pub(crate) type BoxedSource = Box<dyn Source<Item = f32> + Send>;
+
+/// Open a song's file and convert it to the pipeline's channel layout and sample rate
+///
+/// A CUE sheet track is cut to its span of the file, so positions, seeks and the end of
+/// the track all count from its own start.
+pub(crate) fn open_source(song: &Song, sample_rate: u32) -> anyhow::Result<BoxedSource> {
+    let source = uniform(SymphoniaSource::open(song.file_path.as_ref())?, sample_rate);
+    if !cue::is_track(song) {
+        return Ok(source);
+    }
+    let mut segment = Segment {
+        source,
+        start: Duration::from_secs_f64(song.start_offset),
+        length: song
+            .end_offset
+            .map(|end| Duration::from_secs_f64((end - song.start_offset).max(0.0))),
+        remaining: None,
+    };
+    segment
+        .try_seek(Duration::ZERO)
+        .map_err(|e| anyhow::anyhow!("cannot seek to the start of {}: {e}", song.title))?;
+    Ok(Box::new(segment))
+}
+
+/// Convert any source to the pipeline's channel layout and sample rate
+///
+/// Surround sources are mixed down rather than losing all but their front channels.
+pub(crate) fn uniform<S>(source: S, sample_rate: u32) -> BoxedSource
+where
+    S: Source<Item = f32> + Send + 'static,
+{
+    Box::new(Resampler::new(Downmix::new(source), sample_rate))
+}
+
+/// Decode the head of `source` now so switching to it never waits on the decoder
+pub(crate) fn prime(source: BoxedSource) -> BoxedSource {
+    prime_frames(source, PRIME_FRAMES)
+}
+
+/// Like `prime`, decoding the first `frames` frames
+pub(crate) fn prime_frames(mut source: BoxedSource, frames: usize) -> BoxedSource {
+    let head: Vec<f32> = source.by_ref().take(frames * CHANNELS as usize).collect();
+    Box::new(PrimedSource {
+        head: head.into_iter(),
+        rest: source,
+    })
+}
+
+/// Source whose first samples were decoded ahead of time
+struct PrimedSource {
+    head: std::vec::IntoIter<f32>,
+    rest: BoxedSource,
+}
+
+impl Iterator for PrimedSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        self.head.next().or_else(|| self.rest.next())
+    }
+}
+
+impl Source for PrimedSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.rest.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.rest.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.rest.total_duration()
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), rodio::source::SeekError> {
+        self.head = Vec::new().into_iter();
+        self.rest.try_seek(pos)
+    }
+}
+
+/// The part of a uniform source from `start` that lasts `length`
+struct Segment {
+    source: BoxedSource,
+    start: Duration,
+    length: Option<Duration>,
+    /// Samples left before `length` is reached
+    remaining: Option<u64>,
+}
+
+impl Iterator for Segment {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        match &mut self.remaining {
+            Some(0) => None,
+            Some(remaining) => {
+                *remaining -= 1;
+                self.source.next()
+            }
+            None => self.source.next(),
+        }
+    }
+}
+
+impl Source for Segment {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.source.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.length.or_else(|| {
+            let total = self.source.total_duration()?;
+            Some(total.saturating_sub(self.start))
+        })
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), rodio::source::SeekError> {
+        self.source.try_seek(self.start + pos)?;
+        self.remaining = self.length.map(|length| {
+            let left = length.saturating_sub(pos).as_secs_f64();
+            (left * self.sample_rate() as f64) as u64 * self.channels() as u64
+        });
+        Ok(())
+    }
+}
+
+/// Notifications sent from the audio callback to the engine thread
+///
+/// Delivered with `try_send` so the callback never blocks on a slow consumer.
+pub(crate) enum PipelineEvent {
+    TrackFinished,
+    /// The up-next track took over without a gap
+    TrackTransition,
+    Spectrum(SpectrumFrame),
+    Beat(Beat),
+    /// Playback jumped from the end of the loop region back to its start
+    LoopRestarted,
+    /// A pause was shortened; carries the time saved so far
+    PauseSkipped(f64),
+    /// An announcement clip with this id started or ended
+    ClipStarted(u32),
+    ClipFinished(u32),
+    /// Decoding or processing a block panicked, and the tracks were dropped
+    Panicked(String),
+}
+
+/// What `Player` does once a pause fade has faded out
+enum FadeOutThen {
+    Pause,
+    Unload,
+}
+
+/// A decoded track currently loaded into the pipeline
+struct Track {
+    source: BoxedSource,
+    frames_played: u64,
+    duration_secs: Option<f64>,
+    /// Frame the track is cut short at, skipping its trailing silence
+    end: Option<u64>,
+    /// Linear loudness normalization gain
+    gain: f32,
+}
+
+impl Track {
+    fn new(source: BoxedSource, gain: f32) -> Self {
+        Track {
+            duration_secs: source.total_duration().map(|d| d.as_secs_f64()),
+            source,
+            frames_played: 0,
+            end: None,
+            gain,
+        }
+    }
+
+    /// Cut the track short by `trailing_secs` of silence, or not at all for `None`
+    fn trim_end(&mut self, trailing_secs: Option<f64>, sample_rate: u32) {
+        self.end = trailing_secs
+            .filter(|&trailing| trailing > 0.0)
+            .zip(self.duration_secs)
+            .map(|(trailing, duration)| {
+                ((duration - trailing).max(0.0) * sample_rate as f64) as u64
+            });
+    }
+
+    /// Append up to `count` samples to `out`, returning how many were produced
+    fn pull(&mut self, out: &mut Vec<f32>, count: usize, normalize: bool) -> usize {
+        let before = out.len();
+        out.extend(self.source.by_ref().take(count));
+        if normalize && self.gain != 1.0 {
+            out[before..].iter_mut().for_each(|s| *s *= self.gain);
+        }
+        out.len() - before
+    }
+}
+
+/// Section of the current track that plays over and over, in track frames
+struct LoopRegion {
+    start: u64,
+    end: u64,
+    /// The track opened again and primed at `start`, to switch to at `end`
+    restart: Option<Track>,
+}
+
+/// State shared between the engine thread and the audio callback
+pub(crate) struct Player {
+    sample_rate: u32,
+    track: Option<Track>,
+    next: Option<Track>,
+    gapless: bool,
+    crossfade_frames: u64,
+    crossfade_curve: FadeCurve,
+    fade: Option<Fade<Track>>,
+    fade_buffer: Vec<f32>,
+    loop_region: Option<LoopRegion>,
+    paused: bool,
+    /// Length of the fades `pause`, `resume` and `stop` apply
+    pause_fade_frames: f32,
+    /// Set while fading out for `pause` or `stop`
+    fading_out: Option<FadeOutThen>,
+    /// The track ran out during a pause fade; it's handed over once playback resumes
+    ended_while_fading: bool,
+    /// Position of the current track at the first frame of the last rendered block;
+    /// negative when the track started partway into the block
+    block_origin: i64,
+    /// Frames of the last rendered block the output has consumed so far
+    consumed: Arc<AtomicUsize>,
+    /// Track frames each consumed output frame stands for
+    position_rate: f64,
+    stretch: TimeStretch,
+    stretch_buffer: Vec<f32>,
+    chain: DspChain,
+    /// Bypass every stage, whatever `chain` says
+    bit_perfect: bool,
+    pub eq: Equalizer,
+    pub reverb: Reverb,
+    pub widener: StereoWidener,
+    pub channel_map: ChannelMapper,
+    pub compressor: Compressor,
+    pub pauses: PauseSkipper,
+    /// Preamp and balance
+    pub gain: GainStage,
+    limiter: Limiter,
+    pub volume: VolumeRamp,
+    /// Separate from `volume` so the sleep timer's fade-out can't be undone by volume changes
+    pub sleep_fade: VolumeRamp,
+    pause_fade: VolumeRamp,
+    /// Lowers the output while another app briefly holds audio focus
+    pub duck: VolumeRamp,
+    /// Clips from `play_clip`, mixed over the music
+    pub announcer: Announcer,
+    spectrum: SpectrumAnalyzer,
+    pub beats: BeatTracker,
+    /// Where rendered blocks go while `start_recording` runs
+    pub recorder: Option<SyncSender<recording::Message>>,
+    /// Devices the output is mirrored to, from `set_zones`
+    pub zones: Vec<Arc<ZoneBuffer>>,
+    events: SyncSender<PipelineEvent>,
+    meters: Arc<Meters>,
+    /// Time the DSP stages have taken on the block being rendered
+    dsp_busy: Duration,
+}
+
+impl Player {
+    pub fn new(sample_rate: u32, events: SyncSender<PipelineEvent>, meters: Arc<Meters>) -> Self {
+        Player {
+            sample_rate,
+            track: None,
+            next: None,
+            gapless: true,
+            crossfade_frames: 0,
+            crossfade_curve: FadeCurve::EqualPower,
+            fade: None,
+            fade_buffer: Vec::with_capacity(BLOCK_FRAMES * CHANNELS as usize),
+            loop_region: None,
+            paused: false,
+            pause_fade_frames: 0.0,
+            fading_out: None,
+            ended_while_fading: false,
+            block_origin: 0,
+            consumed: Arc::new(AtomicUsize::new(0)),
+            position_rate: 1.0,
+            stretch: TimeStretch::new(sample_rate, CHANNELS as usize),
+            stretch_buffer: Vec::with_capacity(BLOCK_FRAMES * CHANNELS as usize),
+            chain: DspChain::default(),
+            bit_perfect: false,
+            eq: Equalizer::new(sample_rate, CHANNELS as usize),
+            reverb: Reverb::new(sample_rate),
+            widener: StereoWidener::new(sample_rate),
+            channel_map: ChannelMapper::new(),
+            compressor: Compressor::new(sample_rate),
+            pauses: PauseSkipper::new(sample_rate),
+            gain: GainStage::new(sample_rate),
+            limiter: Limiter,
+            volume: VolumeRamp::new(sample_rate),
+            sleep_fade: VolumeRamp::new(sample_rate),
+            pause_fade: VolumeRamp::new(sample_rate),
+            duck: VolumeRamp::new(sample_rate),
+            announcer: Announcer::new(sample_rate),
+            spectrum: SpectrumAnalyzer::new(sample_rate),
+            beats: BeatTracker::new(sample_rate),
+            recorder: None,
+            zones: Vec::new(),
+            events,
+            meters,
+            dsp_busy: Duration::ZERO,
+        }
+    }
+
+    pub fn load(&mut self, source: BoxedSource, gain: f32) {
+        self.track = Some(Track::new(source, gain));
+        self.fade = None;
+        self.loop_region = None;
+        self.paused = false;
+        self.fading_out = None;
+        self.ended_while_fading = false;
+        self.pause_fade.jump_to(1.0);
+        self.stretch.reset();
+        self.block_origin = -(self.consumed.load(Ordering::Relaxed) as i64);
+        self.announcer.track_changed();
+    }
+
+    /// Queue a (primed) source to take over sample-accurately when the current one ends
+    pub fn load_next(&mut self, source: Option<BoxedSource>, gain: f32) {
+        self.next = source.map(|source| Track::new(source, gain));
+    }
+
+    /// Cut the current track short by `trailing_secs` of silence, or play it to the end
+    /// for `None`
+    pub fn trim_end(&mut self, trailing_secs: Option<f64>) {
+        if let Some(track) = self.track.as_mut() {
+            track.trim_end(trailing_secs, self.sample_rate);
+        }
+    }
+
+    /// Count the up-next track from `start_secs`, where its source has been seeked to,
+    /// and cut it short by `trailing_secs`
+    pub fn trim_next(&mut self, start_secs: f64, trailing_secs: Option<f64>) {
+        if let Some(track) = self.next.as_mut() {
+            track.frames_played = (start_secs * self.sample_rate as f64) as u64;
+            track.trim_end(trailing_secs, self.sample_rate);
+        }
+    }
+
+    /// Update normalization gains of the current and up-next tracks
+    pub fn set_gains(&mut self, current: f32, next: f32) {
+        if let Some(track) = self.track.as_mut() {
+            track.gain = current;
+        }
+        if let Some(track) = self.next.as_mut() {
+            track.gain = next;
+        }
+    }
+
+    pub fn set_gapless(&mut self, gapless: bool) {
+        self.gapless = gapless;
+    }
+
+    pub fn set_crossfade(&mut self, duration_ms: u32, curve: FadeCurve) {
+        self.crossfade_frames = duration_ms as u64 * self.sample_rate as u64 / 1000;
+        self.crossfade_curve = curve;
+    }
+
+    pub fn unload(&mut self) {
+        self.track = None;
+        self.fade = None;
+        self.loop_region = None;
+    }
+
+    /// Loop the current track between `start` and `end` seconds, or stop looping
+    pub fn set_loop_region(&mut self, region: Option<(f64, f64)>) {
+        let frames = |secs: f64| (secs * self.sample_rate as f64) as u64;
+        self.loop_region = region.map(|(start, end)| LoopRegion {
+            start: frames(start),
+            end: frames(end),
+            restart: None,
+        });
+    }
+
+    pub fn loop_region(&self) -> Option<(f64, f64)> {
+        let secs = |frames: u64| frames as f64 / self.sample_rate as f64;
+        self.loop_region
+            .as_ref()
+            .map(|region| (secs(region.start), secs(region.end)))
+    }
+
+    /// Hand over a copy of the current track already positioned at the loop start
+    pub fn load_loop_restart(&mut self, source: BoxedSource) {
+        if let Some(region) = self.loop_region.as_mut() {
+            let mut track = Track::new(source, 1.0);
+            track.frames_played = region.start;
+            region.restart = Some(track);
+        }
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    /// Fade out over the pause fade, then pause
+    pub fn pause(&mut self) {
+        self.fade_out(FadeOutThen::Pause);
+    }
+
+    /// Unpause, fading back in over the pause fade
+    pub fn resume(&mut self) {
+        if std::mem::take(&mut self.paused) {
+            self.pause_fade.jump_to(0.0);
+        }
+        self.fading_out = None;
+        self.pause_fade.ramp_to(1.0, self.pause_fade_frames);
+        if std::mem::take(&mut self.ended_while_fading) {
+            self.end_track(0);
+        }
+    }
+
+    /// Fade out over the pause fade, then unload
+    pub fn stop(&mut self) {
+        self.fade_out(FadeOutThen::Unload);
+    }
+
+    pub fn set_pause_fade(&mut self, duration_ms: u32) {
+        self.pause_fade_frames = duration_ms as f32 * self.sample_rate as f32 / 1000.0;
+    }
+
+    fn fade_out(&mut self, then: FadeOutThen) {
+        if self.paused || self.track.is_none() || self.pause_fade_frames == 0.0 {
+            self.finish_fade_out(then);
+        } else {
+            self.fading_out = Some(then);
+            self.pause_fade.ramp_to(0.0, self.pause_fade_frames);
+        }
+    }
+
+    fn finish_fade_out(&mut self, then: FadeOutThen) {
+        match then {
+            FadeOutThen::Pause => self.paused = true,
+            FadeOutThen::Unload => self.unload(),
+        }
+    }
+
+    pub fn set_playback_rate(&mut self, rate: f32) {
+        self.stretch.set_tempo(rate);
+        if !self.stretch.is_active() {
+            self.stretch.reset();
+        }
+    }
+
+    pub fn set_chain(&mut self, chain: DspChain) {
+        let stretching = self.stage_enabled(DspStage::TimeStretch);
+        self.chain = chain;
+        if self.stage_enabled(DspStage::TimeStretch) != stretching {
+            self.stretch.reset();
+        }
+    }
+
+    pub fn set_bit_perfect(&mut self, bit_perfect: bool) {
+        let stretching = self.stage_enabled(DspStage::TimeStretch);
+        self.bit_perfect = bit_perfect;
+        if self.stage_enabled(DspStage::TimeStretch) != stretching {
+            self.stretch.reset();
+        }
+    }
+
+    fn stage_enabled(&self, stage: DspStage) -> bool {
+        !self.bit_perfect && self.chain.is_enabled(stage)
+    }
+
+    pub fn set_pitch_shift(&mut self, semitones: f32) {
+        self.stretch.set_pitch(semitones);
+        if !self.stretch.is_active() {
+            self.stretch.reset();
+        }
+    }
+
+    pub fn set_spectrum_config(&mut self, bands: u32, fps: u32, window: u32) {
+        self.spectrum.configure(bands, fps, window);
+    }
+
+    pub fn seek(&mut self, position_secs: f64) -> anyhow::Result<()> {
+        let Some(track) = self.track.as_mut() else {
+            anyhow::bail!("nothing is loaded");
+        };
+        let position_secs = position_secs.max(0.0);
+        self.fade = None;
+        self.stretch.reset();
+        track
+            .source
+            .try_seek(Duration::from_secs_f64(position_secs))
+            .map_err(|e| anyhow::anyhow!("{e}"))?;
+        track.frames_played = (position_secs * self.sample_rate as f64) as u64;
+        // The rest of the block already handed to the output now counts from here.
+        let consumed = self.consumed.load(Ordering::Relaxed) as f64 * self.position_rate;
+        self.block_origin = track.frames_played as i64 - consumed as i64;
+        Ok(())
+    }
+
+    /// Position of the loaded track in seconds, as far as the output has played it
+    ///
+    /// Frames decoded into the block the output is still consuming don't count yet.
+    pub fn position_secs(&self) -> f64 {
+        self.track.as_ref().map_or(0.0, |t| {
+            let consumed = self.consumed.load(Ordering::Relaxed) as f64 * self.position_rate;
+            let frames = (self.block_origin + consumed as i64).clamp(0, t.frames_played as i64);
+            frames as f64 / self.sample_rate as f64
+        })
+    }
+
+    /// Track position the last rendered block ends at
+    fn block_end_secs(&self) -> f64 {
+        self.track.as_ref().map_or(0.0, |t| {
+            let block = BLOCK_FRAMES as f64 * self.position_rate;
+            let frames = (self.block_origin + block as i64).clamp(0, t.frames_played as i64);
+            frames as f64 / self.sample_rate as f64
+        })
+    }
+
+    /// When the end of the block just rendered goes to the output device; the block
+    /// starts going out as soon as it's rendered
+    fn block_output_at(&self) -> SystemTime {
+        SystemTime::now() + Duration::from_secs_f64(BLOCK_FRAMES as f64 / self.sample_rate as f64)
+    }
+
+    /// Duration of the loaded track, if the decoder knows it
+    pub fn duration_secs(&self) -> Option<f64> {
+        self.track.as_ref().and_then(|t| t.duration_secs)
+    }
+
+    /// Hand over to the up-next track early once the current one is within the
+    /// crossfade window of its end
+    fn maybe_start_crossfade(&mut self) {
+        if self.crossfade_frames == 0
+            || self.fade.is_some()
+            || self.next.is_none()
+            || self.loop_region.is_some()
+        {
+            return;
+        }
+        let Some(track) = self.track.as_ref() else {
+            return;
+        };
+        let total_frames = match (track.end, track.duration_secs) {
+            (Some(end), _) => end,
+            (None, Some(duration_secs)) => (duration_secs * self.sample_rate as f64) as u64,
+            (None, None) => return,
+        };
+        let remaining = total_frames.saturating_sub(track.frames_played);
+        if remaining > self.crossfade_frames {
+            return;
+        }
+        let outgoing = std::mem::replace(&mut self.track, self.next.take());
+        self.fade = outgoing.map(|outgoing| Fade {
+            outgoing,
+            position: 0,
+            length: remaining.max(1) as usize,
+        });
+        self.announcer.track_changed();
+        let _ = self.events.try_send(PipelineEvent::TrackTransition);
+    }
+
+    /// Like `render_block`, but a panic on a corrupt file or in an effect drops the
+    /// tracks for a block of silence, rather than taking the output stream down
+    fn render(&mut self, out: &mut Vec<f32>) {
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| self.render_block(out))) {
+            self.unload();
+            self.next = None;
+            self.fading_out = None;
+            out.clear();
+            out.resize(BLOCK_FRAMES * CHANNELS as usize, 0.0);
+            let message = panic_message(&*payload);
+            let _ = self.events.try_send(PipelineEvent::Panicked(message));
+        }
+    }
+
+    /// Fill `out` with interleaved samples, padding with silence when idle
+    fn render_block(&mut self, out: &mut Vec<f32>) {
+        out.clear();
+        let wanted = BLOCK_FRAMES * CHANNELS as usize;
+        let block = Duration::from_secs_f64(BLOCK_FRAMES as f64 / self.sample_rate as f64);
+        self.dsp_busy = Duration::ZERO;
+        if self.paused {
+            self.meters.record_dsp(Duration::ZERO, block);
+            self.block_origin = self.track.as_ref().map_or(0, |t| t.frames_played as i64);
+            out.resize(wanted, 0.0);
+            let volume = self.volume.gain();
+            self.announcer.process(out, volume, &self.events);
+            return;
+        }
+        let (before_stretch, after_stretch) = DspChain::around_stretch();
+        let stretching = self.stretch.is_active() && self.stage_enabled(DspStage::TimeStretch);
+        let rendered = if stretching {
+            let mut rendered = false;
+            let mut input = std::mem::take(&mut self.stretch_buffer);
+            while self.stretch.available() < wanted {
+                if self.render_tracks(&mut input) {
+                    self.run_stages(before_stretch, &mut input);
+                    rendered = true;
+                }
+                self.stretch.push(&input);
+            }
+            self.stretch_buffer = input;
+            self.stretch.pull(out, wanted);
+            // Count this block from the track position the stretcher's output has reached.
+            self.position_rate = self.stretch.tempo();
+            let latency = self.stretch.latency_frames() + BLOCK_FRAMES as f64 * self.position_rate;
+            self.block_origin = self
+                .track
+                .as_ref()
+                .map_or(0, |t| t.frames_played as i64 - latency as i64);
+            rendered
+        } else {
+            self.position_rate = 1.0;
+            let rendered = self.render_tracks(out);
+            if rendered {
+                self.run_stages(before_stretch, out);
+            }
+            rendered
+        };
+
+        if rendered {
+            self.run_stages(after_stretch, out);
+            if let Some((frequencies, waveform)) = self.spectrum.push(out) {
+                let frame = SpectrumFrame {
+                    frequencies,
+                    waveform,
+                    position_secs: self.block_end_secs(),
+                    output_at: self.block_output_at(),
+                };
+                let _ = self.events.try_send(PipelineEvent::Spectrum(frame));
+            }
+            if let Some((confidence, bpm)) = self.beats.push(out) {
+                let beat = Beat {
+                    confidence,
+                    bpm,
+                    position_secs: self.block_end_secs(),
+                    output_at: self.block_output_at(),
+                };
+                let _ = self.events.try_send(PipelineEvent::Beat(beat));
+            }
+            self.record(out);
+        }
+        self.meters.record_dsp(self.dsp_busy, block);
+        // After the analyzer, so the visualizer doesn't shrink with the volume.
+        self.volume.process(out, CHANNELS as usize);
+        self.sleep_fade.process(out, CHANNELS as usize);
+        self.pause_fade.process(out, CHANNELS as usize);
+        self.duck.process(out, CHANNELS as usize);
+        // After the volume, which it's scaled by on its own, so the music can be
+        // ducked under it
+        let volume = self.volume.gain();
+        self.announcer.process(out, volume, &self.events);
+        for zone in &self.zones {
+            zone.push(out);
+        }
+        if self.pause_fade.gain() == 0.0 {
+            if let Some(then) = self.fading_out.take() {
+                self.finish_fade_out(then);
+            }
+        }
+    }
+
+    /// Hand a rendered block to the recording, if there is one
+    fn record(&mut self, block: &[f32]) {
+        let Some(recorder) = &self.recorder else {
+            return;
+        };
+        match recorder.try_send(recording::Message::Samples(block.to_vec())) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => log::warn!("recording fell behind, dropped a block"),
+            // It stopped on a write error.
+            Err(TrySendError::Disconnected(_)) => self.recorder = None,
+        }
+    }
+
+    /// Run the enabled block stages among `stages` over `block`
+    fn run_stages(&mut self, stages: &[DspStage], block: &mut [f32]) {
+        let started = Instant::now();
+        for &stage in stages {
+            if !self.stage_enabled(stage) {
+                continue;
+            }
+            let effect: &mut dyn Effect = match stage {
+                DspStage::Equalizer => &mut self.eq,
+                DspStage::Reverb => &mut self.reverb,
+                DspStage::StereoWidener => &mut self.widener,
+                DspStage::ChannelMode => &mut self.channel_map,
+                DspStage::Compressor => &mut self.compressor,
+                DspStage::Preamp => &mut self.gain,
+                DspStage::Limiter => &mut self.limiter,
+                // Applied to each track as it is decoded, or by `render` itself
+                DspStage::Resampler
+                | DspStage::PauseSkipping
+                | DspStage::Normalization
+                | DspStage::TimeStretch => continue,
+            };
+            effect.process(block, CHANNELS as usize);
+        }
+        self.dsp_busy += started.elapsed();
+    }
+
+    /// Continue from the current track into the next one, `offset` samples into the block
+    fn end_track(&mut self, offset: usize) {
+        let seamless = self.gapless || self.crossfade_frames > 0;
+        let next = if seamless { self.next.take() } else { None };
+        let event = match next {
+            Some(_) => {
+                self.announcer.track_changed();
+                PipelineEvent::TrackTransition
+            }
+            // `load` brings the next track in, if there is one.
+            None => PipelineEvent::TrackFinished,
+        };
+        self.track = next;
+        self.loop_region = None;
+        self.block_origin = -((offset / CHANNELS as usize) as i64);
+        let _ = self.events.try_send(event);
+    }
+
+    /// Fill `out` with one block from the loaded tracks, including any crossfade
+    ///
+    /// Returns false if there was nothing to play and the block is silence.
+    fn render_tracks(&mut self, out: &mut Vec<f32>) -> bool {
+        out.clear();
+        let wanted = BLOCK_FRAMES * CHANNELS as usize;
+        let normalize = self.stage_enabled(DspStage::Normalization);
+        let skip_pauses = self.pauses.is_enabled() && self.stage_enabled(DspStage::PauseSkipping);
+        self.maybe_start_crossfade();
+        self.block_origin = self.track.as_ref().map_or(0, |t| t.frames_played as i64);
+        while out.len() < wanted {
+            let Some(track) = self.track.as_mut() else {
+                break;
+            };
+            // Stop exactly at the end of the loop region, if the track is inside it.
+            let loop_end = self
+                .loop_region
+                .as_ref()
+                .map(|region| region.end)
+                .filter(|&end| track.frames_played < end);
+            // Or at the start of its trailing silence
+            let track_end = track.end.filter(|&end| track.frames_played < end);
+            let mut count = wanted - out.len();
+            if let Some(end) = loop_end.into_iter().chain(track_end).min() {
+                count = count.min((end - track.frames_played) as usize * CHANNELS as usize);
+            }
+            let produced = track.pull(out, count, normalize);
+            track.frames_played += (produced / CHANNELS as usize) as u64;
+            let dry = produced < count;
+            let cut = track_end.is_some_and(|end| track.frames_played >= end);
+            if skip_pauses && self.pauses.process(out, out.len() - produced) {
+                let saved = self.pauses.saved_secs();
+                let _ = self.events.try_send(PipelineEvent::PauseSkipped(saved));
+            }
+            if loop_end.is_some_and(|end| track.frames_played >= end) {
+                self.restart_loop(out.len());
+                continue;
+            }
+            if cut || dry {
+                if let Some(then) = self.fading_out.take() {
+                    // Playback was on its way out anyway; don't move on to the next track.
+                    self.ended_while_fading = matches!(then, FadeOutThen::Pause);
+                    self.finish_fade_out(then);
+                    break;
+                }
+                self.end_track(out.len());
+            }
+        }
+        let rendered = !out.is_empty() || self.fade.is_some();
+        out.resize(wanted, 0.0);
+
+        if let Some(fade) = self.fade.as_mut() {
+            self.fade_buffer.clear();
+            fade.outgoing.pull(&mut self.fade_buffer, wanted, normalize);
+            if !fade.mix(
+                self.crossfade_curve,
+                CHANNELS as usize,
+                out,
+                &self.fade_buffer,
+            ) {
+                self.fade = None;
+            }
+        }
+        rendered
+    }
+
+    /// Continue from the loop start, `rendered` samples into the block
+    fn restart_loop(&mut self, rendered: usize) {
+        let Some(region) = self.loop_region.as_mut() else {
+            return;
+        };
+        let start = region.start;
+        let gain = self.track.as_ref().map_or(1.0, |t| t.gain);
+        match region.restart.take() {
+            Some(mut restart) => {
+                restart.gain = gain;
+                self.track = Some(restart);
+            }
+            // Not prepared yet, so seek in place, which may hold up this block a little.
+            None => {
+                let Some(track) = self.track.as_mut() else {
+                    return;
+                };
+                let position = Duration::from_secs_f64(start as f64 / self.sample_rate as f64);
+                if track.source.try_seek(position).is_err() {
+                    self.loop_region = None;
+                    return;
+                }
+                track.frames_played = start;
+            }
+        }
+        self.block_origin = start as i64 - (rendered / CHANNELS as usize) as i64;
+        let _ = self.events.try_send(PipelineEvent::LoopRestarted);
+    }
+}
+
+/// Endless source handed to the output stream, rendering the player block by block
+pub(crate) struct PipelineSource {
+    player: Arc<Mutex<Player>>,
+    consumed: Arc<AtomicUsize>,
+    sample_rate: u32,
+    buffer: Vec<f32>,
+    cursor: usize,
+}
+
+impl PipelineSource {
+    pub fn new(player: Arc<Mutex<Player>>, sample_rate: u32) -> Self {
+        let consumed = player.lock().unwrap().consumed.clone();
+        PipelineSource {
+            player,
+            consumed,
+            sample_rate,
+            buffer: Vec::with_capacity(BLOCK_FRAMES * CHANNELS as usize),
+            cursor: 0,
+        }
+    }
+}
+
+impl Iterator for PipelineSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.cursor >= self.buffer.len() {
+            let mut player = self.player.lock().unwrap();
+            player.render(&mut self.buffer);
+            self.consumed.store(0, Ordering::Relaxed);
+            self.cursor = 0;
+        }
+        let sample = self.buffer[self.cursor];
+        self.cursor += 1;
+        if self.cursor.is_multiple_of(CHANNELS as usize) {
+            self.consumed
+                .store(self.cursor / CHANNELS as usize, Ordering::Relaxed);
+        }
+        Some(sample)
+    }
+}
+
+impl Source for PipelineSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        CHANNELS
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// Render `song` on its own through a player at `sample_rate` with default settings, as
+/// the output would be handed it, up to the end of the block it finishes in
+#[cfg(feature = "test-fixtures")]
+pub(crate) fn render(song: &Song, sample_rate: u32) -> anyhow::Result<Vec<f32>> {
+    let (events_tx, events) = std::sync::mpsc::sync_channel(64);
+    let mut player = Player::new(sample_rate, events_tx, Arc::default());
+    player.load(open_source(song, sample_rate)?, 1.0);
+    let mut rendered = Vec::new();
+    let mut block = Vec::with_capacity(BLOCK_FRAMES * CHANNELS as usize);
+    while player.track.is_some() {
+        player.render(&mut block);
+        rendered.extend_from_slice(&block);
+        for event in events.try_iter() {
+            if let PipelineEvent::Panicked(message) = event {
+                anyhow::bail!("rendering {} panicked: {message}", song.file_path);
+            }
+        }
+    }
+    Ok(rendered)
+}
@@ -0,0 +1,131 @@
+use super::{AudioEngine, Command};
+use crate::{AudioEvent, ChannelMode, Dynamics, ReverbSettings};
+
+/// Preamp range in dB either way
+const MAX_PREAMP_DB: f32 = 12.0;
+
+/// Length of the ramp applied to every preamp or balance change
+const RAMP_MS: u32 = 20;
+
+/// Effect settings as set by the user, mirrored on the FFI side like the volume
+#[derive(Clone, Copy)]
+pub(crate) struct DspSettings {
+    pub preamp_db: f32,
+    /// -1.0 is fully left, 1.0 fully right
+    pub balance: f32,
+    pub reverb: ReverbSettings,
+    pub stereo_width: f32,
+    pub channel_mode: ChannelMode,
+    pub dynamics: Dynamics,
+}
+
+impl Default for DspSettings {
+    fn default() -> Self {
+        DspSettings {
+            preamp_db: 0.0,
+            balance: 0.0,
+            reverb: ReverbSettings::default(),
+            stereo_width: 1.0,
+            channel_mode: ChannelMode::Stereo,
+            dynamics: Dynamics::Off,
+        }
+    }
+}
+
+impl DspSettings {
+    /// Linear gain of the left and right channel
+    fn gains(&self) -> [f32; 2] {
+        let preamp = 10f32.powf(self.preamp_db / 20.0);
+        [
+            preamp * (1.0 - self.balance).min(1.0),
+            preamp * (1.0 + self.balance).min(1.0),
+        ]
+    }
+}
+
+/// Post-EQ gain per channel, ramped like `VolumeRamp`
+pub(crate) struct GainStage {
+    current: [f32; 2],
+    target: [f32; 2],
+    step: [f32; 2],
+    ramp_frames: f32,
+}
+
+impl GainStage {
+    pub fn new(sample_rate: u32) -> Self {
+        GainStage {
+            current: [1.0; 2],
+            target: [1.0; 2],
+            step: [0.0; 2],
+            ramp_frames: (sample_rate * RAMP_MS / 1000).max(1) as f32,
+        }
+    }
+
+    pub fn set(&mut self, settings: DspSettings) {
+        self.target = settings.gains();
+        for channel in 0..2 {
+            self.step[channel] = (self.target[channel] - self.current[channel]) / self.ramp_frames;
+        }
+    }
+
+    /// Scale an interleaved stereo block in place; at unity it is left bit for bit
+    pub fn process(&mut self, block: &mut [f32], channels: usize) {
+        if self.current == [1.0; 2] && self.target == [1.0; 2] {
+            return;
+        }
+        for frame in block.chunks_exact_mut(channels) {
+            for (channel, sample) in frame.iter_mut().enumerate().take(2) {
+                let current = &mut self.current[channel];
+                let target = self.target[channel];
+                if *current != target {
+                    *current += self.step[channel];
+                    let step = self.step[channel];
+                    if (step > 0.0 && *current > target) || (step < 0.0 && *current < target) {
+                        *current = target;
+                    }
+                }
+                *sample *= *current;
+            }
+        }
+    }
+}
+
+impl AudioEngine {
+    /// Boost or cut everything after the equalizer by `gain_db` (clamped to +/-12)
+    ///
+    /// Boosts are soft clipped by the limiter rather than distorting. Emits
+    /// `DspSettingsChanged`.
+    pub fn set_preamp(&self, gain_db: f32) {
+        let settings = self.update_dsp(|settings| {
+            settings.preamp_db = gain_db.clamp(-MAX_PREAMP_DB, MAX_PREAMP_DB)
+        });
+        self.emit_gain(settings);
+    }
+
+    /// Shift output between the left (-1.0) and right (1.0) channel; 0.0 is centered
+    ///
+    /// The far side is attenuated while the near side stays as it is. Emits
+    /// `DspSettingsChanged`.
+    pub fn set_balance(&self, balance: f32) {
+        let settings = self.update_dsp(|settings| settings.balance = balance.clamp(-1.0, 1.0));
+        self.emit_gain(settings);
+    }
+
+    fn emit_gain(&self, settings: DspSettings) {
+        self.shared.events.emit(AudioEvent::DspSettingsChanged {
+            preamp_db: settings.preamp_db,
+            balance: settings.balance,
+        });
+    }
+
+    /// Change the effect settings and hand them to the engine thread
+    pub(super) fn update_dsp(&self, update: impl FnOnce(&mut DspSettings)) -> DspSettings {
+        let settings = {
+            let mut settings = self.shared.dsp.lock().unwrap();
+            update(&mut settings);
+            *settings
+        };
+        self.send(Command::SetDsp(settings));
+        settings
+    }
+}
@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use super::pipeline::{self, BoxedSource, CHANNELS};
+use super::{AudioEngine, Command, EngineThread};
+use crate::{Song, SongId};
+
+/// Queue entries kept ready by default
+pub(super) const DEFAULT_COUNT: u32 = 3;
+
+/// Memory the ready entries share by default
+pub(super) const DEFAULT_BUDGET_MB: u32 = 48;
+
+/// Upcoming queue entries opened and partly decoded off the audio thread, so playing
+/// one doesn't wait on a slow disk
+pub(super) struct Prebuffer {
+    ready: Arc<Mutex<HashMap<SongId, Ready>>>,
+    wanted: Option<Sender<Wanted>>,
+    count: usize,
+    budget_bytes: usize,
+}
+
+/// A song's source with its head decoded
+struct Ready {
+    file_path: String,
+    start_offset: f64,
+    source: BoxedSource,
+}
+
+impl Ready {
+    /// Whether this was opened for `song` as it is now, not for a file since moved
+    fn is_for(&self, song: &Song) -> bool {
+        self.file_path == song.file_path && self.start_offset == song.start_offset
+    }
+}
+
+/// Songs to have ready, nearest first, and how many frames of each to decode
+struct Wanted {
+    songs: Vec<Song>,
+    frames: usize,
+}
+
+impl Prebuffer {
+    pub(super) fn new(sample_rate: u32) -> Self {
+        let ready = Arc::new(Mutex::new(HashMap::new()));
+        let (tx, rx) = mpsc::channel();
+        let worker_ready = ready.clone();
+        let spawned = thread::Builder::new()
+            .name("tunes4r-prebuffer".into())
+            .spawn(move || run(rx, &worker_ready, sample_rate));
+        if let Err(e) = &spawned {
+            log::warn!("failed to spawn prebuffer thread: {e}");
+        }
+        Prebuffer {
+            ready,
+            wanted: spawned.ok().map(|_| tx),
+            count: DEFAULT_COUNT as usize,
+            budget_bytes: DEFAULT_BUDGET_MB as usize * 1024 * 1024,
+        }
+    }
+
+    /// Have the first entries of `upcoming` made ready, dropping any others
+    pub(super) fn update(&self, upcoming: Vec<Song>) {
+        let Some(wanted) = &self.wanted else {
+            return;
+        };
+        let mut songs = upcoming;
+        songs.truncate(self.count);
+        let bytes_per_frame = CHANNELS as usize * size_of::<f32>();
+        let frames = (self.budget_bytes / self.count.max(1) / bytes_per_frame)
+            .max(pipeline::PRIME_FRAMES);
+        let _ = wanted.send(Wanted { songs, frames });
+    }
+
+    /// The source made ready for `song`, if there is one, which then isn't kept any more
+    pub(super) fn take(&self, song: &Song) -> Option<BoxedSource> {
+        let mut ready = self.ready.lock().unwrap();
+        match ready.get(&song.id) {
+            Some(entry) if entry.is_for(song) => ready.remove(&song.id).map(|r| r.source),
+            _ => None,
+        }
+    }
+}
+
+/// Open what's wanted as the lists arrive, until the engine drops its sender
+fn run(wanted: Receiver<Wanted>, ready: &Mutex<HashMap<SongId, Ready>>, sample_rate: u32) {
+    let mut pending = None;
+    loop {
+        let mut next = match pending.take() {
+            Some(next) => next,
+            None => match wanted.recv() {
+                Ok(next) => next,
+                Err(_) => return,
+            },
+        };
+        // Only the latest list matters.
+        while let Ok(newer) = wanted.try_recv() {
+            next = newer;
+        }
+        ready.lock().unwrap().retain(|id, entry| {
+            next.songs
+                .iter()
+                .any(|song| &song.id == id && entry.is_for(song))
+        });
+        for song in &next.songs {
+            if ready.lock().unwrap().contains_key(&song.id) {
+                continue;
+            }
+            match pipeline::open_source(song, sample_rate) {
+                Ok(source) => {
+                    let entry = Ready {
+                        file_path: song.file_path.clone(),
+                        start_offset: song.start_offset,
+                        source: pipeline::prime_frames(source, next.frames),
+                    };
+                    ready.lock().unwrap().insert(song.id.clone(), entry);
+                }
+                Err(e) => log::debug!("failed to prebuffer {}: {e}", song.file_path),
+            }
+            // The queue changed while this one was opening, so start over with it.
+            match wanted.try_recv() {
+                Ok(newer) => {
+                    pending = Some(newer);
+                    break;
+                }
+                Err(TryRecvError::Disconnected) => return,
+                Err(TryRecvError::Empty) => {}
+            }
+        }
+    }
+}
+
+impl AudioEngine {
+    /// Keep the next `count` queue entries opened with their first seconds decoded,
+    /// sharing `memory_budget_mb` of memory, so skipping to one starts at once
+    ///
+    /// 3 entries and 48 MB by default; a count of zero turns it off. Entries are
+    /// opened again as the queue changes.
+    pub fn set_instant_play(&self, count: u32, memory_budget_mb: u32) {
+        self.send(Command::SetInstantPlay {
+            count,
+            memory_budget_mb,
+        });
+    }
+}
+
+impl EngineThread {
+    pub(super) fn set_instant_play(&mut self, count: u32, memory_budget_mb: u32) {
+        self.prebuffer.count = count as usize;
+        self.prebuffer.budget_bytes = memory_budget_mb as usize * 1024 * 1024;
+        self.update_prebuffer();
+    }
+
+    /// Make the upcoming queue entries ready, after the queue or its cursor changed
+    pub(super) fn update_prebuffer(&mut self) {
+        let upcoming = match self.prebuffer.count {
+            0 => Vec::new(),
+            count => {
+                let queue = self.shared.queue.lock().unwrap();
+                queue.upcoming(count).into_iter().cloned().collect()
+            }
+        };
+        self.prebuffer.update(upcoming);
+    }
+}
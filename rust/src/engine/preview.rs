@@ -0,0 +1,174 @@
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rodio::cpal::traits::HostTrait;
+use rodio::{cpal, DeviceTrait, Source};
+
+use super::devices::{find_device, negotiate};
+use super::output::Output;
+use super::pipeline::{self, BoxedSource, CHANNELS};
+use super::{AudioEngine, Command, EngineThread};
+use crate::{AudioEvent, Song, TunesError};
+
+/// Frames over which the preview follows a volume change, to avoid clicks
+const VOLUME_SMOOTHING_FRAMES: f32 = 1000.0;
+
+/// A song playing on an output of its own, next to main playback
+pub(super) struct Preview {
+    finished: Arc<AtomicBool>,
+    // Must stay alive for as long as the preview should be heard.
+    _output: Output,
+}
+
+/// The previewed song at the preview volume, with a flag raised once it has ended
+struct PreviewSource {
+    source: BoxedSource,
+    volume: Arc<AtomicU32>,
+    gain: f32,
+    channel: u16,
+    finished: Arc<AtomicBool>,
+}
+
+impl Iterator for PreviewSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let Some(sample) = self.source.next() else {
+            self.finished.store(true, Ordering::Relaxed);
+            return None;
+        };
+        if self.channel == 0 {
+            let target = f32::from_bits(self.volume.load(Ordering::Relaxed));
+            self.gain += (target - self.gain) / VOLUME_SMOOTHING_FRAMES;
+        }
+        self.channel = (self.channel + 1) % CHANNELS;
+        Some(sample * self.gain)
+    }
+}
+
+impl Source for PreviewSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        CHANNELS
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+impl AudioEngine {
+    /// Play `song` on the device with `device_id`, or the default device for `None`,
+    /// alongside main playback, for cueing the next track or a quick listen
+    ///
+    /// Main playback and its volume and effects are left alone. Replaces any preview
+    /// already playing; `PreviewChanged` reports it starting and ending.
+    pub fn preview_play(&self, song: Song, device_id: Option<String>) -> Result<(), TunesError> {
+        if let Some(id) = &device_id {
+            if find_device(id).is_none() {
+                return Err(TunesError::device(format!("no output device named {id:?}")));
+            }
+        }
+        self.send(Command::PreviewPlay { song, device_id });
+        Ok(())
+    }
+
+    pub fn preview_stop(&self) {
+        self.send(Command::PreviewStop);
+    }
+
+    /// Volume of previews from 0 to 1 (1 by default), apart from the main volume
+    pub fn set_preview_volume(&self, volume: f32) {
+        let volume = volume.clamp(0.0, 1.0);
+        self.shared
+            .preview_volume
+            .store(volume.to_bits(), Ordering::Relaxed);
+    }
+}
+
+impl EngineThread {
+    pub(super) fn preview_play(&mut self, song: Song, device_id: Option<String>) {
+        // Release the old stream first; some backends allow only one per device.
+        self.preview = None;
+        match self.open_preview(&song, device_id.as_deref()) {
+            Ok(preview) => {
+                self.preview = Some(preview);
+                self.shared
+                    .events
+                    .emit(AudioEvent::PreviewChanged { song: Some(song) });
+            }
+            Err(e) => {
+                let message = format!("couldn't preview {}: {e:#}", song.file_path);
+                log::warn!("{message}");
+                self.shared
+                    .events
+                    .emit(AudioEvent::PreviewFailed { message });
+                self.shared
+                    .events
+                    .emit(AudioEvent::PreviewChanged { song: None });
+            }
+        }
+    }
+
+    fn open_preview(&self, song: &Song, device_id: Option<&str>) -> anyhow::Result<Preview> {
+        let device = match device_id {
+            Some(name) => find_device(name)
+                .ok_or_else(|| anyhow::anyhow!("no output device named {name:?}"))?,
+            None => cpal::default_host()
+                .default_output_device()
+                .ok_or_else(|| anyhow::anyhow!("no audio output available"))?,
+        };
+        let config = match negotiate(&device, self.sample_rate) {
+            Some(config) => config,
+            None => device.default_output_config()?,
+        };
+        let finished = Arc::new(AtomicBool::new(false));
+        let source = PreviewSource {
+            source: pipeline::open_source(song, self.sample_rate)?,
+            volume: self.shared.preview_volume.clone(),
+            gain: f32::from_bits(self.shared.preview_volume.load(Ordering::Relaxed)),
+            channel: 0,
+            finished: finished.clone(),
+        };
+        let latency = Arc::new(AtomicU32::new(0));
+        let output = Output::open(
+            &device,
+            &config,
+            self.buffer_frames,
+            source,
+            latency,
+            Arc::default(),
+        )?;
+        Ok(Preview {
+            finished,
+            _output: output,
+        })
+    }
+
+    pub(super) fn preview_stop(&mut self) {
+        if self.preview.take().is_some() {
+            self.shared
+                .events
+                .emit(AudioEvent::PreviewChanged { song: None });
+        }
+    }
+
+    /// Let go of the preview's output once its song has played to the end
+    pub(super) fn check_preview(&mut self) {
+        let ended = self
+            .preview
+            .as_ref()
+            .is_some_and(|preview| preview.finished.load(Ordering::Relaxed));
+        if ended {
+            self.preview_stop();
+        }
+    }
+}
@@ -0,0 +1,539 @@
+use anyhow::{bail, ensure};
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+use super::{AudioEngine, Command, EngineThread};
+use crate::{AudioEvent, RepeatMode, ShuffleMode, Song, SongId, TunesError};
+
+/// Restarting the current track instead of going back happens past this position
+const RESTART_THRESHOLD_SECS: f64 = 3.0;
+
+/// Ordered list of songs with a cursor at the one currently playing
+///
+/// `current` is `None` before playback of the queue has started; `next_index` then
+/// points at the head. Playback follows `order`, a permutation of song indices that is
+/// the identity unless shuffle is on; it is kept across skips and edits so shuffle
+/// never repeats a song before the whole queue has played.
+pub(crate) struct Queue {
+    songs: Vec<Song>,
+    order: Vec<usize>,
+    current: Option<usize>,
+    shuffle: ShuffleMode,
+    repeat: RepeatMode,
+}
+
+impl Default for Queue {
+    fn default() -> Self {
+        Queue {
+            songs: Vec::new(),
+            order: Vec::new(),
+            current: None,
+            shuffle: ShuffleMode::Off,
+            repeat: RepeatMode::Off,
+        }
+    }
+}
+
+impl Queue {
+    pub fn songs(&self) -> &[Song] {
+        &self.songs
+    }
+
+    pub fn current_index(&self) -> Option<usize> {
+        self.current
+    }
+
+    pub fn set_current(&mut self, index: Option<usize>) {
+        self.current = index.filter(|&i| i < self.songs.len());
+    }
+
+    pub fn position_of(&self, song_id: &SongId) -> Option<usize> {
+        self.songs.iter().position(|s| s.id == *song_id)
+    }
+
+    pub fn set_shuffle(&mut self, mode: ShuffleMode) {
+        self.shuffle = mode;
+        self.order = (0..self.songs.len()).collect();
+        if mode == ShuffleMode::On {
+            // The current song stays first so the rest of the queue follows it.
+            let start = match self.current {
+                Some(current) => {
+                    self.order.swap(0, current);
+                    1
+                }
+                None => 0,
+            };
+            self.order[start..].shuffle(&mut rand::thread_rng());
+        }
+    }
+
+    pub fn set_repeat(&mut self, mode: RepeatMode) {
+        self.repeat = mode;
+    }
+
+    pub fn shuffle(&self) -> ShuffleMode {
+        self.shuffle
+    }
+
+    pub fn repeat(&self) -> RepeatMode {
+        self.repeat
+    }
+
+    fn order_position(&self) -> Option<usize> {
+        self.current
+            .and_then(|current| self.order.iter().position(|&i| i == current))
+    }
+
+    /// Entries left to play after the current one; `None` while repeating, when the
+    /// queue never runs out
+    pub fn remaining(&self) -> Option<usize> {
+        match self.repeat {
+            RepeatMode::Off => Some(self.order.len() - self.order_position().map_or(0, |p| p + 1)),
+            RepeatMode::One | RepeatMode::All => None,
+        }
+    }
+
+    /// The entry played last
+    pub fn last_in_order(&self) -> Option<&Song> {
+        self.order.last().map(|&index| &self.songs[index])
+    }
+
+    /// Entry to play on an explicit skip forward
+    pub fn next_index(&self) -> Option<usize> {
+        let candidate = self.order_position().map_or(0, |p| p + 1);
+        match self.order.get(candidate) {
+            Some(&index) => Some(index),
+            None if self.repeat == RepeatMode::All => self.order.first().copied(),
+            None => None,
+        }
+    }
+
+    /// Up to `count` entries skipping forward reaches one after the other
+    pub fn upcoming(&self, count: usize) -> Vec<&Song> {
+        let start = self.order_position().map_or(0, |p| p + 1);
+        let wrapped = match self.repeat {
+            RepeatMode::All => &self.order[..start],
+            RepeatMode::Off | RepeatMode::One => &[],
+        };
+        self.order[start..]
+            .iter()
+            .chain(wrapped)
+            .filter(|&&index| Some(index) != self.current)
+            .take(count)
+            .map(|&index| &self.songs[index])
+            .collect()
+    }
+
+    /// Entry to play once the current one finishes on its own
+    pub fn upcoming_index(&self) -> Option<usize> {
+        match self.repeat {
+            RepeatMode::One if self.current.is_some() => self.current,
+            _ => self.next_index(),
+        }
+    }
+
+    pub fn previous_index(&self) -> Option<usize> {
+        match self.order_position()?.checked_sub(1) {
+            Some(position) => Some(self.order[position]),
+            None if self.repeat == RepeatMode::All => self.order.last().copied(),
+            None => None,
+        }
+    }
+
+    /// Slot a new song index into the play order
+    fn place_in_order(&mut self, index: usize) {
+        if self.shuffle == ShuffleMode::Off {
+            self.order = (0..self.songs.len()).collect();
+            return;
+        }
+        let first_unplayed = self.order_position().map_or(0, |p| p + 1);
+        let position = rand::thread_rng().gen_range(first_unplayed..=self.order.len());
+        self.order.insert(position, index);
+    }
+
+    pub fn add(&mut self, song: Song) {
+        self.songs.push(song);
+        self.place_in_order(self.songs.len() - 1);
+    }
+
+    pub fn insert(&mut self, index: usize, song: Song) -> anyhow::Result<()> {
+        ensure!(
+            index <= self.songs.len(),
+            "queue index {index} out of range"
+        );
+        self.songs.insert(index, song);
+        for i in self.order.iter_mut().chain(self.current.as_mut()) {
+            if *i >= index {
+                *i += 1;
+            }
+        }
+        self.place_in_order(index);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, index: usize) -> anyhow::Result<Song> {
+        ensure!(index < self.songs.len(), "queue index {index} out of range");
+        // Removing the current entry leaves the cursor on its predecessor in play order.
+        if self.current == Some(index) {
+            self.current = self
+                .order_position()
+                .and_then(|p| p.checked_sub(1))
+                .map(|p| self.order[p]);
+        }
+        let song = self.songs.remove(index);
+        self.order.retain(|&i| i != index);
+        for i in self.order.iter_mut().chain(self.current.as_mut()) {
+            if *i > index {
+                *i -= 1;
+            }
+        }
+        Ok(song)
+    }
+
+    pub fn move_item(&mut self, from: usize, to: usize) -> anyhow::Result<()> {
+        let len = self.songs.len();
+        if from >= len || to >= len {
+            bail!("queue move {from} -> {to} out of range");
+        }
+        let song = self.songs.remove(from);
+        self.songs.insert(to, song);
+        let remap = |i: usize| {
+            if i == from {
+                to
+            } else if from < i && to >= i {
+                i - 1
+            } else if from > i && to <= i {
+                i + 1
+            } else {
+                i
+            }
+        };
+        self.current = self.current.map(remap);
+        match self.shuffle {
+            ShuffleMode::Off => self.order = (0..len).collect(),
+            ShuffleMode::On => self.order.iter_mut().for_each(|i| *i = remap(*i)),
+        }
+        Ok(())
+    }
+
+    pub fn clear(&mut self) {
+        self.songs.clear();
+        self.order.clear();
+        self.current = None;
+    }
+}
+
+impl AudioEngine {
+    pub fn queue_add(&self, song: Song) {
+        self.shared.queue.lock().unwrap().add(song);
+        self.queue_changed();
+    }
+
+    pub fn queue_insert(&self, index: u32, song: Song) -> Result<(), TunesError> {
+        Ok(self.edit_queue(|queue| queue.insert(index as usize, song))?)
+    }
+
+    pub fn queue_remove(&self, index: u32) -> Result<(), TunesError> {
+        Ok(self.edit_queue(|queue| queue.remove(index as usize).map(drop))?)
+    }
+
+    pub fn queue_move(&self, from: u32, to: u32) -> Result<(), TunesError> {
+        Ok(self.edit_queue(|queue| queue.move_item(from as usize, to as usize))?)
+    }
+
+    /// Empty the queue; the current song keeps playing to its end
+    pub fn queue_clear(&self) {
+        self.shared.queue.lock().unwrap().clear();
+        self.queue_changed();
+    }
+
+    pub fn get_queue(&self) -> Vec<Song> {
+        self.shared.queue.lock().unwrap().songs().to_vec()
+    }
+
+    /// Shuffle the play order once; it then stays fixed until shuffle is toggled again
+    pub fn set_shuffle(&self, mode: ShuffleMode) {
+        self.shared.queue.lock().unwrap().set_shuffle(mode);
+        self.queue_changed();
+    }
+
+    pub fn set_repeat(&self, mode: RepeatMode) {
+        self.shared.queue.lock().unwrap().set_repeat(mode);
+        self.queue_changed();
+    }
+
+    /// Start playing the queue entry at `index`
+    pub fn play_queue_index(&self, index: u32) {
+        self.send(Command::PlayQueueIndex(index as usize));
+    }
+
+    pub fn skip_next(&self) {
+        self.send(Command::SkipNext);
+    }
+
+    /// Go to the previous entry, or restart the current one if it has played a while
+    pub fn skip_previous(&self) {
+        self.send(Command::SkipPrevious);
+    }
+
+    fn edit_queue(
+        &self,
+        edit: impl FnOnce(&mut Queue) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        edit(&mut self.shared.queue.lock().unwrap())?;
+        self.queue_changed();
+        Ok(())
+    }
+
+    pub(super) fn queue_changed(&self) {
+        self.shared.emit_queue_changed();
+        self.send(Command::SyncQueue);
+    }
+}
+
+impl super::Shared {
+    pub(super) fn emit_queue_changed(&self) {
+        let event = {
+            let queue = self.queue.lock().unwrap();
+            AudioEvent::QueueChanged {
+                songs: queue.songs().to_vec(),
+                current_index: queue.current_index().map(|i| i as u32),
+            }
+        };
+        self.events.emit(event);
+    }
+}
+
+impl EngineThread {
+    /// Make the pipeline's up-next slot match the queue, topping it up first if
+    /// auto-DJ is on
+    pub(super) fn sync_next(&mut self) {
+        self.top_up_queue();
+        let next = {
+            let queue = self.shared.queue.lock().unwrap();
+            queue.upcoming_index().map(|i| queue.songs()[i].clone())
+        };
+        if next.as_ref().map(|s| &s.id) != self.next_song.as_ref().map(|s| &s.id) {
+            self.next_song = next;
+            self.preload_next();
+        }
+        self.update_prebuffer();
+    }
+
+    /// Move the queue cursor onto `song` after playback reached it
+    pub(super) fn advance_queue_to(&mut self, song: &Song) {
+        {
+            let mut queue = self.shared.queue.lock().unwrap();
+            let index = queue
+                .upcoming_index()
+                .filter(|&i| queue.songs()[i].id == song.id)
+                .or_else(|| queue.position_of(&song.id));
+            queue.set_current(index);
+        }
+        self.shared.emit_queue_changed();
+        self.sync_next();
+    }
+
+    pub(super) fn play_queue_index(&mut self, index: Option<usize>) {
+        let song = {
+            let mut queue = self.shared.queue.lock().unwrap();
+            let song = index.and_then(|i| queue.songs().get(i).cloned());
+            if song.is_some() {
+                queue.set_current(index);
+            }
+            song
+        };
+        match song {
+            Some(song) => {
+                self.shared.emit_queue_changed();
+                self.play(song, true);
+                self.sync_next();
+            }
+            None => self.stop(),
+        }
+    }
+
+    pub(super) fn skip_next(&mut self) {
+        let next = self.shared.queue.lock().unwrap().next_index();
+        self.play_queue_index(next);
+    }
+
+    pub(super) fn skip_previous(&mut self) {
+        let position = self.shared.position_secs();
+        let previous = self.shared.queue.lock().unwrap().previous_index();
+        match previous {
+            Some(index) if position <= RESTART_THRESHOLD_SECS => self.play_queue_index(Some(index)),
+            _ => self.seek(0.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn song(id: &str) -> Song {
+        Song {
+            id: SongId(id.to_string()),
+            ..Song::default()
+        }
+    }
+
+    /// A queue of `len` songs with ids "0", "1", ...
+    fn queue(len: usize) -> Queue {
+        let mut queue = Queue::default();
+        for i in 0..len {
+            queue.add(song(&i.to_string()));
+        }
+        queue
+    }
+
+    fn ids(songs: &[Song]) -> Vec<&str> {
+        songs.iter().map(|song| song.id.as_str()).collect()
+    }
+
+    fn current_id(queue: &Queue) -> Option<&str> {
+        queue.current_index().map(|i| queue.songs()[i].id.as_str())
+    }
+
+    /// Ids of every entry in play order
+    fn play_order(queue: &Queue) -> Vec<&str> {
+        let mut sorted = queue.order.clone();
+        sorted.sort_unstable();
+        assert_eq!(sorted, (0..queue.songs().len()).collect::<Vec<_>>());
+        queue
+            .order
+            .iter()
+            .map(|&i| queue.songs()[i].id.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn shuffle_without_a_current_entry_reorders_the_whole_queue() {
+        let mut queue = queue(20);
+        queue.set_shuffle(ShuffleMode::On);
+        assert_eq!(play_order(&queue).len(), 20);
+        assert_eq!(queue.remaining(), Some(20));
+        queue.set_shuffle(ShuffleMode::Off);
+        let expected: Vec<String> = (0..20).map(|i| i.to_string()).collect();
+        assert_eq!(play_order(&queue), expected);
+    }
+
+    #[test]
+    fn shuffle_keeps_the_current_entry_first() {
+        let mut queue = queue(20);
+        queue.set_current(Some(7));
+        queue.set_shuffle(ShuffleMode::On);
+        assert_eq!(play_order(&queue)[0], "7");
+        assert_eq!(current_id(&queue), Some("7"));
+        assert_eq!(queue.remaining(), Some(19));
+        assert_eq!(queue.previous_index(), None);
+        assert_ne!(queue.next_index(), Some(7));
+    }
+
+    #[test]
+    fn insert_shifts_the_current_entry() {
+        let mut queue = queue(3);
+        queue.set_current(Some(1));
+        queue.insert(0, song("new")).unwrap();
+        assert_eq!(ids(queue.songs()), ["new", "0", "1", "2"]);
+        assert_eq!(current_id(&queue), Some("1"));
+        assert_eq!(play_order(&queue), ["new", "0", "1", "2"]);
+        assert!(queue.insert(5, song("late")).is_err());
+    }
+
+    #[test]
+    fn insert_while_shuffled_places_the_song_after_the_current_one() {
+        let mut queue = queue(10);
+        queue.set_current(Some(4));
+        queue.set_shuffle(ShuffleMode::On);
+        queue.insert(2, song("new")).unwrap();
+        assert_eq!(current_id(&queue), Some("4"));
+        let order = play_order(&queue);
+        assert_eq!(order[0], "4");
+        assert!(order[1..].contains(&"new"));
+    }
+
+    #[test]
+    fn removing_the_current_entry_moves_the_cursor_to_its_predecessor() {
+        let mut queue = queue(5);
+        queue.set_current(Some(2));
+        assert_eq!(queue.remove(2).unwrap().id.as_str(), "2");
+        assert_eq!(ids(queue.songs()), ["0", "1", "3", "4"]);
+        assert_eq!(current_id(&queue), Some("1"));
+        assert_eq!(queue.next_index(), Some(2));
+
+        queue.set_current(Some(0));
+        queue.remove(0).unwrap();
+        assert_eq!(queue.current_index(), None);
+        assert_eq!(queue.next_index(), Some(0));
+        assert_eq!(ids(queue.songs()), ["1", "3", "4"]);
+        assert!(queue.remove(3).is_err());
+    }
+
+    #[test]
+    fn removing_another_entry_keeps_the_current_one() {
+        let mut queue = queue(5);
+        queue.set_current(Some(3));
+        queue.remove(1).unwrap();
+        assert_eq!(current_id(&queue), Some("3"));
+        queue.remove(3).unwrap();
+        assert_eq!(current_id(&queue), Some("3"));
+        assert_eq!(play_order(&queue), ["0", "2", "3"]);
+    }
+
+    #[test]
+    fn moving_forward_and_back_follows_the_current_entry() {
+        let mut queue = queue(5);
+        queue.set_current(Some(1));
+        queue.move_item(1, 3).unwrap();
+        assert_eq!(ids(queue.songs()), ["0", "2", "3", "1", "4"]);
+        assert_eq!(current_id(&queue), Some("1"));
+
+        queue.move_item(0, 4).unwrap();
+        assert_eq!(ids(queue.songs()), ["2", "3", "1", "4", "0"]);
+        assert_eq!(current_id(&queue), Some("1"));
+
+        queue.move_item(4, 0).unwrap();
+        assert_eq!(ids(queue.songs()), ["0", "2", "3", "1", "4"]);
+        assert_eq!(current_id(&queue), Some("1"));
+
+        queue.move_item(3, 0).unwrap();
+        assert_eq!(ids(queue.songs()), ["1", "0", "2", "3", "4"]);
+        assert_eq!(current_id(&queue), Some("1"));
+        assert_eq!(play_order(&queue), ["1", "0", "2", "3", "4"]);
+        assert!(queue.move_item(0, 5).is_err());
+    }
+
+    #[test]
+    fn moving_while_shuffled_keeps_the_play_order() {
+        let mut queue = queue(8);
+        queue.set_current(Some(2));
+        queue.set_shuffle(ShuffleMode::On);
+        let before: Vec<String> = play_order(&queue).into_iter().map(Into::into).collect();
+        queue.move_item(6, 1).unwrap();
+        queue.move_item(0, 7).unwrap();
+        assert_eq!(play_order(&queue), before);
+        assert_eq!(current_id(&queue), Some("2"));
+    }
+
+    #[test]
+    fn repeat_all_wraps_around_both_ends() {
+        let mut queue = queue(3);
+        queue.set_current(Some(2));
+        assert_eq!(queue.next_index(), None);
+        assert_eq!(queue.remaining(), Some(0));
+
+        queue.set_repeat(RepeatMode::All);
+        assert_eq!(queue.next_index(), Some(0));
+        assert_eq!(queue.upcoming_index(), Some(0));
+        assert_eq!(queue.remaining(), None);
+        let upcoming: Vec<&str> = queue.upcoming(5).iter().map(|s| s.id.as_str()).collect();
+        assert_eq!(upcoming, ["0", "1"]);
+
+        queue.set_current(Some(0));
+        assert_eq!(queue.previous_index(), Some(2));
+        queue.set_repeat(RepeatMode::Off);
+        assert_eq!(queue.previous_index(), None);
+    }
+}
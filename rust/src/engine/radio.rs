@@ -0,0 +1,92 @@
+use std::sync::{Arc, Mutex, OnceLock};
+
+use super::{pipeline, AudioEngine, Command, EngineThread};
+use crate::http_stream::{BufferLevel, HttpSource};
+use crate::{AudioEvent, PlaybackState, Song, SongId};
+
+impl AudioEngine {
+    /// Play an Icecast/Shoutcast station, an HLS playlist or any other HTTP(S) audio
+    ///
+    /// Titles and bitrates arrive as `StreamMetadataUpdated`; HLS switches between
+    /// bitrate variants as throughput allows. The state is `Buffering` while the stream
+    /// waits for data. A dropped connection is retried a few times before playback stops.
+    pub fn play_url(&self, url: String) {
+        self.send(Command::PlayUrl(url));
+    }
+}
+
+impl EngineThread {
+    pub(super) fn play_url(&mut self, url: String) {
+        let song = Song {
+            id: SongId(url.clone()),
+            title: url.clone(),
+            file_path: url.clone(),
+            ..Default::default()
+        };
+        let shared = self.shared.clone();
+        let sample_rate = self.sample_rate;
+        let mut buffer = None;
+        self.start_playback(song, None, false, |song| {
+            let level = Arc::new(OnceLock::<BufferLevel>::new());
+            let callback_level = level.clone();
+            let last_title = Mutex::new(None);
+            let source = HttpSource::open(&url, move |metadata| {
+                let mut last_title = last_title.lock().unwrap();
+                if let Some(title) = metadata
+                    .title
+                    .as_ref()
+                    .filter(|t| last_title.as_ref() != Some(*t))
+                {
+                    // Audio decoded before the new title is still waiting to be played.
+                    let ahead = callback_level.get().map_or(0.0, BufferLevel::ahead_secs);
+                    shared.split_recording(title.clone(), ahead);
+                    *last_title = Some(title.clone());
+                }
+                shared.events.emit(AudioEvent::StreamMetadataUpdated {
+                    title: metadata.title,
+                    bitrate: metadata.bitrate,
+                });
+            })?;
+            if let Some(name) = source.name() {
+                song.title = name.to_string();
+            }
+            let _ = level.set(source.buffer_level());
+            buffer = Some(source.buffer_level());
+            Ok(pipeline::uniform(source, sample_rate))
+        });
+        *self.shared.stream_buffer.lock().unwrap() = buffer;
+    }
+
+    /// Switch between `Playing` and `Buffering` as the stream's buffer runs dry and refills
+    pub(super) fn check_stream_buffer(&mut self) {
+        let panic = self
+            .shared
+            .stream_buffer
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(BufferLevel::take_panic);
+        if let Some(message) = panic {
+            self.skip_after_panic("stream", message);
+            return;
+        }
+        let Some(buffering) = self
+            .shared
+            .stream_buffer
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(BufferLevel::buffering)
+        else {
+            return;
+        };
+        let state = match (self.state(), buffering) {
+            (PlaybackState::Playing | PlaybackState::Buffering { .. }, Some(percent)) => {
+                PlaybackState::Buffering { percent }
+            }
+            (PlaybackState::Buffering { .. }, None) => PlaybackState::Playing,
+            _ => return,
+        };
+        self.set_state(state, self.song());
+    }
+}
@@ -0,0 +1,288 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Weak};
+use std::thread::{self, JoinHandle};
+
+use anyhow::Context;
+
+use super::pipeline::CHANNELS;
+use super::{AudioEngine, Shared};
+use crate::export::safe_name;
+use crate::flac::FlacWriter;
+use crate::{AudioEvent, RecordingFormat, TunesError};
+
+/// Blocks the output can get ahead of the writer before audio is dropped, a few seconds'
+/// worth
+const QUEUE_BLOCKS: usize = 512;
+
+/// What the output and the stream's metadata send the writer thread
+pub(super) enum Message {
+    /// Rendered samples, interleaved stereo
+    Samples(Vec<f32>),
+    /// The stream's title changed; the new title is heard once `delay_frames` more
+    /// frames have been written
+    Split { title: String, delay_frames: u64 },
+}
+
+/// A running recording, kept in `Shared::recording`
+pub(super) struct Recording {
+    messages: SyncSender<Message>,
+    writer: JoinHandle<anyhow::Result<()>>,
+    sample_rate: u32,
+}
+
+impl AudioEngine {
+    /// Record what's playing to `path`, after every DSP stage but before the volume
+    ///
+    /// Files are 16-bit at the engine's sample rate. While an internet radio station
+    /// plays, each new title from its metadata starts a new file next to `path`, named
+    /// after the title, at the point the new title is heard. Emits `RecordingChanged` for
+    /// each file; a write error stops the recording with `RecordingFailed`.
+    pub fn start_recording(&self, path: String, format: RecordingFormat) -> Result<(), TunesError> {
+        let mut recording = self.shared.recording.lock().unwrap();
+        if let Some(running) = recording.take() {
+            if !running.writer.is_finished() {
+                *recording = Some(running);
+                return Err(TunesError::invalid_state(
+                    "already recording; call stop_recording first",
+                ));
+            }
+            // It stopped on an error already reported as `RecordingFailed`.
+            let _ = running.writer.join();
+        }
+        let path = PathBuf::from(path);
+        let writer = Writer::create(&path, format, self.sample_rate)?;
+        let (messages, rx) = mpsc::sync_channel(QUEUE_BLOCKS);
+        let recorder = Recorder {
+            path: path.clone(),
+            format,
+            sample_rate: self.sample_rate,
+            writer: Some(writer),
+            frames: 0,
+            file_frames: 0,
+            files: 1,
+            title: None,
+            splits: VecDeque::new(),
+            shared: Arc::downgrade(&self.shared),
+        };
+        let writer = thread::Builder::new()
+            .name("tunes4r-recorder".into())
+            .spawn(move || recorder.run(rx))
+            .context("failed to spawn recording thread")?;
+        self.shared.player.lock().unwrap().recorder = Some(messages.clone());
+        *recording = Some(Recording {
+            messages,
+            writer,
+            sample_rate: self.sample_rate,
+        });
+        drop(recording);
+        log::info!("recording to {}", path.display());
+        self.shared.events.emit(AudioEvent::RecordingChanged {
+            path: Some(path.to_string_lossy().into_owned()),
+        });
+        Ok(())
+    }
+
+    /// Finish the current file and stop recording; does nothing if not recording
+    pub fn stop_recording(&self) -> Result<(), TunesError> {
+        let Some(recording) = self.shared.recording.lock().unwrap().take() else {
+            return Ok(());
+        };
+        self.shared.player.lock().unwrap().recorder = None;
+        // The writer finishes once both senders are gone.
+        drop(recording.messages);
+        let result = recording
+            .writer
+            .join()
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("recording thread panicked")));
+        self.shared
+            .events
+            .emit(AudioEvent::RecordingChanged { path: None });
+        Ok(result?)
+    }
+}
+
+impl Shared {
+    /// Start a new file when a stream's title changes, `delay_secs` from now
+    pub(super) fn split_recording(&self, title: String, delay_secs: f64) {
+        if let Some(recording) = self.recording.lock().unwrap().as_ref() {
+            let delay_frames = (delay_secs * recording.sample_rate as f64) as u64;
+            let _ = recording.messages.send(Message::Split {
+                title,
+                delay_frames,
+            });
+        }
+    }
+}
+
+enum Writer {
+    Wav(hound::WavWriter<BufWriter<File>>),
+    Flac(FlacWriter),
+}
+
+impl Writer {
+    fn create(path: &Path, format: RecordingFormat, sample_rate: u32) -> anyhow::Result<Self> {
+        let writer = match format {
+            RecordingFormat::Wav => {
+                let spec = hound::WavSpec {
+                    channels: CHANNELS,
+                    sample_rate,
+                    bits_per_sample: 16,
+                    sample_format: hound::SampleFormat::Int,
+                };
+                hound::WavWriter::create(path, spec).map(Writer::Wav)?
+            }
+            RecordingFormat::Flac => {
+                Writer::Flac(FlacWriter::create(path, CHANNELS, sample_rate, 16)?)
+            }
+        };
+        Ok(writer)
+    }
+
+    fn write(&mut self, samples: &[f32]) -> anyhow::Result<()> {
+        let samples = samples
+            .iter()
+            .map(|&s| (s.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16);
+        match self {
+            Writer::Wav(wav) => {
+                let mut wav = wav.get_i16_writer(samples.len() as u32);
+                for sample in samples {
+                    wav.write_sample(sample);
+                }
+                wav.flush()?;
+            }
+            Writer::Flac(flac) => flac.write(&samples.map(i32::from).collect::<Vec<_>>())?,
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> anyhow::Result<()> {
+        match self {
+            Writer::Wav(wav) => wav.finalize()?,
+            Writer::Flac(flac) => flac.finish()?,
+        }
+        Ok(())
+    }
+}
+
+/// Writer thread: files the output's samples, moving on to the next file at title changes
+struct Recorder {
+    /// The file the recording was started with; later ones are named after it
+    path: PathBuf,
+    format: RecordingFormat,
+    sample_rate: u32,
+    writer: Option<Writer>,
+    /// Frames recorded across all files
+    frames: u64,
+    file_frames: u64,
+    files: u32,
+    /// Stream title of the current file, if it started at a title change
+    title: Option<String>,
+    /// Titles waiting for the frame they start at
+    splits: VecDeque<(u64, String)>,
+    shared: Weak<Shared>,
+}
+
+impl Recorder {
+    fn run(mut self, rx: Receiver<Message>) -> anyhow::Result<()> {
+        let result = self.record(rx);
+        let result = match self.writer.take() {
+            Some(writer) => result.and_then(|_| writer.finish()),
+            None => result,
+        };
+        if let Err(e) = &result {
+            log::error!("recording to {} failed: {e:#}", self.path.display());
+            if let Some(shared) = self.shared.upgrade() {
+                let message = format!("{e:#}");
+                shared.events.emit(AudioEvent::RecordingFailed { message });
+            }
+        }
+        result
+    }
+
+    fn record(&mut self, rx: Receiver<Message>) -> anyhow::Result<()> {
+        for message in rx {
+            match message {
+                Message::Samples(samples) => {
+                    let mut block = samples.as_slice();
+                    while let Some((at, _)) = self.splits.front() {
+                        let until = at.saturating_sub(self.frames) as usize * CHANNELS as usize;
+                        if until > block.len() {
+                            break;
+                        }
+                        let (now, later) = block.split_at(until);
+                        self.write(now)?;
+                        block = later;
+                        let (_, title) = self.splits.pop_front().expect("checked above");
+                        self.split(title)?;
+                    }
+                    self.write(block)?;
+                }
+                Message::Split {
+                    title,
+                    delay_frames,
+                } => self.splits.push_back((self.frames + delay_frames, title)),
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, samples: &[f32]) -> anyhow::Result<()> {
+        if let Some(writer) = &mut self.writer {
+            writer.write(samples)?;
+        }
+        let frames = (samples.len() / CHANNELS as usize) as u64;
+        self.frames += frames;
+        self.file_frames += frames;
+        Ok(())
+    }
+
+    /// Finish the current file and continue in one named after `title`
+    fn split(&mut self, title: String) -> anyhow::Result<()> {
+        if self.title.as_ref() == Some(&title) {
+            return Ok(());
+        }
+        // Nothing was recorded under the old title, so the file so far is this title's.
+        if self.file_frames == 0 {
+            self.title = Some(title);
+            return Ok(());
+        }
+        if let Some(writer) = self.writer.take() {
+            writer.finish()?;
+        }
+        self.files += 1;
+        let path = self.numbered_path(&title);
+        self.writer = Some(Writer::create(&path, self.format, self.sample_rate)?);
+        self.file_frames = 0;
+        self.title = Some(title);
+        log::info!("recording continues in {}", path.display());
+        if let Some(shared) = self.shared.upgrade() {
+            shared.events.emit(AudioEvent::RecordingChanged {
+                path: Some(path.to_string_lossy().into_owned()),
+            });
+        }
+        Ok(())
+    }
+
+    /// "<stem> 02 - <title>.<extension>" next to the first file
+    fn numbered_path(&self, title: &str) -> PathBuf {
+        let stem = self
+            .path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "recording".into());
+        let extension = match self.format {
+            RecordingFormat::Wav => "wav",
+            RecordingFormat::Flac => "flac",
+        };
+        let name = format!(
+            "{stem} {:02} - {}.{extension}",
+            self.files,
+            safe_name(title)
+        );
+        self.path.with_file_name(name)
+    }
+}
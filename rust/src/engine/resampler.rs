@@ -0,0 +1,210 @@
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+use std::time::Duration;
+
+use rodio::source::SeekError;
+use rodio::Source;
+
+use super::pipeline::CHANNELS;
+
+/// Zero crossings of the sinc kernel on each side, at full bandwidth
+const ZERO_CROSSINGS: usize = 32;
+
+/// Kernel values tabulated per input frame; lookups interpolate between them
+const PHASES: usize = 256;
+
+/// Share of the lower of the two Nyquist frequencies that passes, leaving the filter
+/// room to roll off before aliasing sets in
+const BANDWIDTH: f64 = 0.95;
+
+/// Blackman-windowed sinc low-pass, tabulated for one side
+struct Kernel {
+    /// Input frames the kernel reaches on each side of the output position
+    half_width: usize,
+    table: Vec<f32>,
+}
+
+impl Kernel {
+    /// A kernel passing `cutoff` of the input's Nyquist frequency
+    fn new(cutoff: f64) -> Self {
+        // Lower cutoffs need proportionally more taps for the same steepness.
+        let half_width = (ZERO_CROSSINGS as f64 / cutoff).ceil() as usize;
+        let table = (0..=half_width * PHASES)
+            .map(|i| {
+                let x = i as f64 / PHASES as f64;
+                let sinc = if x == 0.0 {
+                    1.0
+                } else {
+                    (PI * cutoff * x).sin() / (PI * cutoff * x)
+                };
+                // Blackman window, centered on the output position
+                let t = 0.5 + x / (2.0 * half_width as f64);
+                let window = 0.42 - 0.5 * (2.0 * PI * t).cos() + 0.08 * (4.0 * PI * t).cos();
+                (cutoff * sinc * window) as f32
+            })
+            .collect();
+        Kernel { half_width, table }
+    }
+
+    /// Weight of an input frame `x` frames away from the output position
+    fn at(&self, x: f64) -> f32 {
+        let position = x.abs() * PHASES as f64;
+        let i = position as usize;
+        if i + 1 >= self.table.len() {
+            return 0.0;
+        }
+        let frac = (position - i as f64) as f32;
+        self.table[i] + (self.table[i + 1] - self.table[i]) * frac
+    }
+}
+
+/// Band-limited sample-rate conversion to the pipeline's rate, producing stereo
+///
+/// Mono input is played on both channels. Sources already at the pipeline's rate pass
+/// through untouched.
+pub(crate) struct Resampler<S> {
+    source: S,
+    sample_rate: u32,
+    input_rate: u32,
+    /// Input frames per output frame
+    step: f64,
+    /// `None` while the rates match
+    kernel: Option<Kernel>,
+    /// Buffered input frames, the first of which is input frame `first`
+    frames: VecDeque<[f32; 2]>,
+    first: i64,
+    /// Position of the next output frame, in input frames
+    position: f64,
+    /// Number of input frames, once the source has run dry
+    end: Option<i64>,
+    /// Right sample of the last output frame, still to be returned
+    right: Option<f32>,
+}
+
+impl<S: Source<Item = f32>> Resampler<S> {
+    pub fn new(source: S, sample_rate: u32) -> Self {
+        let mut resampler = Resampler {
+            input_rate: source.sample_rate(),
+            source,
+            sample_rate,
+            step: 1.0,
+            kernel: None,
+            frames: VecDeque::new(),
+            first: 0,
+            position: 0.0,
+            end: None,
+            right: None,
+        };
+        resampler.configure(resampler.input_rate);
+        resampler
+    }
+
+    pub fn source_mut(&mut self) -> &mut S {
+        &mut self.source
+    }
+
+    fn configure(&mut self, input_rate: u32) {
+        self.input_rate = input_rate;
+        self.step = input_rate as f64 / self.sample_rate as f64;
+        self.kernel = (input_rate != self.sample_rate).then(|| {
+            let ratio = (self.sample_rate as f64 / input_rate as f64).min(1.0);
+            Kernel::new(BANDWIDTH * ratio)
+        });
+        self.restart();
+    }
+
+    fn restart(&mut self) {
+        self.frames.clear();
+        self.first = 0;
+        self.position = 0.0;
+        self.end = None;
+        self.right = None;
+    }
+
+    fn read_frame(&mut self) -> Option<[f32; 2]> {
+        let channels = self.source.channels();
+        let left = self.source.next()?;
+        let right = match channels {
+            0 | 1 => left,
+            _ => self.source.next()?,
+        };
+        Some([left, right])
+    }
+
+    fn next_frame(&mut self) -> Option<[f32; 2]> {
+        let input_rate = self.source.sample_rate();
+        if input_rate != self.input_rate {
+            // A chained stream changing rate; the filter starts over from here.
+            self.configure(input_rate);
+        }
+        let Some(half_width) = self.kernel.as_ref().map(|k| k.half_width as i64) else {
+            return self.read_frame();
+        };
+        let center = self.position.floor() as i64;
+        // Buffer everything the kernel reaches, padding the end of the source with silence.
+        while self.end.is_none() && self.first + (self.frames.len() as i64) <= center + half_width {
+            match self.read_frame() {
+                Some(frame) => self.frames.push_back(frame),
+                None => self.end = Some(self.first + self.frames.len() as i64),
+            }
+        }
+        if self.end.is_some_and(|end| center >= end) {
+            return None;
+        }
+        while self.first < center - half_width + 1 && !self.frames.is_empty() {
+            self.frames.pop_front();
+            self.first += 1;
+        }
+
+        let kernel = self.kernel.as_ref()?;
+        let mut out = [0.0f32; 2];
+        for (i, frame) in self.frames.iter().enumerate() {
+            let j = self.first + i as i64;
+            if j > center + half_width {
+                break;
+            }
+            let weight = kernel.at(self.position - j as f64);
+            out[0] += frame[0] * weight;
+            out[1] += frame[1] * weight;
+        }
+        self.position += self.step;
+        Some(out)
+    }
+}
+
+impl<S: Source<Item = f32>> Iterator for Resampler<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if let Some(right) = self.right.take() {
+            return Some(right);
+        }
+        let [left, right] = self.next_frame()?;
+        self.right = Some(right);
+        Some(left)
+    }
+}
+
+impl<S: Source<Item = f32>> Source for Resampler<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        CHANNELS
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.source.total_duration()
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.source.try_seek(pos)?;
+        self.restart();
+        Ok(())
+    }
+}
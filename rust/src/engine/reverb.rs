@@ -0,0 +1,185 @@
+use super::dsp_chain::Effect;
+use super::AudioEngine;
+use crate::{AudioEvent, ReverbSettings};
+
+/// Comb and allpass delays in samples at `TUNING_RATE`, from Freeverb
+const COMB_TUNING: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+const ALLPASS_TUNING: [usize; 4] = [556, 441, 341, 225];
+
+/// Extra delay on the right channel, so the two sides decorrelate
+const STEREO_SPREAD: usize = 23;
+
+/// Sample rate the tunings are given for
+const TUNING_RATE: f32 = 44100.0;
+
+/// Scaling of the input and settings, from Freeverb
+const INPUT_GAIN: f32 = 0.015;
+const WET_SCALE: f32 = 3.0;
+const DAMPING_SCALE: f32 = 0.4;
+const ROOM_SCALE: f32 = 0.28;
+const ROOM_OFFSET: f32 = 0.7;
+const ALLPASS_FEEDBACK: f32 = 0.5;
+
+/// Length of the ramp applied to every wet level change
+const RAMP_MS: u32 = 20;
+
+/// Lowpass-feedback comb filter
+struct Comb {
+    buffer: Vec<f32>,
+    index: usize,
+    filtered: f32,
+}
+
+impl Comb {
+    fn new(len: usize) -> Self {
+        Comb {
+            buffer: vec![0.0; len.max(1)],
+            index: 0,
+            filtered: 0.0,
+        }
+    }
+
+    fn process(&mut self, input: f32, feedback: f32, damping: f32) -> f32 {
+        let output = self.buffer[self.index];
+        self.filtered = output * (1.0 - damping) + self.filtered * damping;
+        self.buffer[self.index] = input + self.filtered * feedback;
+        self.index = (self.index + 1) % self.buffer.len();
+        output
+    }
+}
+
+struct Allpass {
+    buffer: Vec<f32>,
+    index: usize,
+}
+
+impl Allpass {
+    fn new(len: usize) -> Self {
+        Allpass {
+            buffer: vec![0.0; len.max(1)],
+            index: 0,
+        }
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        let delayed = self.buffer[self.index];
+        self.buffer[self.index] = input + delayed * ALLPASS_FEEDBACK;
+        self.index = (self.index + 1) % self.buffer.len();
+        delayed - input
+    }
+}
+
+/// The filters of one output channel
+struct Tank {
+    combs: Vec<Comb>,
+    allpasses: Vec<Allpass>,
+}
+
+impl Tank {
+    fn new(scale: f32, spread: usize) -> Self {
+        let delay = |tuning: usize| ((tuning + spread) as f32 * scale) as usize;
+        Tank {
+            combs: COMB_TUNING.iter().map(|&t| Comb::new(delay(t))).collect(),
+            allpasses: ALLPASS_TUNING
+                .iter()
+                .map(|&t| Allpass::new(delay(t)))
+                .collect(),
+        }
+    }
+
+    fn process(&mut self, input: f32, feedback: f32, damping: f32) -> f32 {
+        let mut output = 0.0;
+        for comb in &mut self.combs {
+            output += comb.process(input, feedback, damping);
+        }
+        for allpass in &mut self.allpasses {
+            output = allpass.process(output);
+        }
+        output
+    }
+
+    fn clear(&mut self) {
+        for comb in &mut self.combs {
+            comb.buffer.fill(0.0);
+            comb.filtered = 0.0;
+        }
+        for allpass in &mut self.allpasses {
+            allpass.buffer.fill(0.0);
+        }
+    }
+}
+
+/// Freeverb-style stereo reverb, mixed into the dry signal by its wet level
+pub(crate) struct Reverb {
+    tanks: [Tank; 2],
+    feedback: f32,
+    damping: f32,
+    wet: f32,
+    target_wet: f32,
+    step: f32,
+}
+
+impl Reverb {
+    pub fn new(sample_rate: u32) -> Self {
+        let scale = sample_rate as f32 / TUNING_RATE;
+        let mut reverb = Reverb {
+            tanks: [Tank::new(scale, 0), Tank::new(scale, STEREO_SPREAD)],
+            feedback: 0.0,
+            damping: 0.0,
+            wet: 0.0,
+            target_wet: 0.0,
+            step: 1.0 / (sample_rate * RAMP_MS / 1000).max(1) as f32,
+        };
+        reverb.set(ReverbSettings::default());
+        reverb
+    }
+
+    pub fn set(&mut self, settings: ReverbSettings) {
+        self.feedback = settings.room_size * ROOM_SCALE + ROOM_OFFSET;
+        self.damping = settings.damping * DAMPING_SCALE;
+        self.target_wet = settings.wet;
+    }
+}
+
+impl Effect for Reverb {
+    /// Mix the reverb into an interleaved stereo block; without any wet level it is
+    /// left bit for bit
+    fn process(&mut self, block: &mut [f32], channels: usize) {
+        if self.wet == 0.0 && self.target_wet == 0.0 {
+            return;
+        }
+        for frame in block.chunks_exact_mut(channels) {
+            self.wet = match self.wet < self.target_wet {
+                true => (self.wet + self.step).min(self.target_wet),
+                false => (self.wet - self.step).max(self.target_wet),
+            };
+            let input = frame.iter().take(2).sum::<f32>() * INPUT_GAIN;
+            for (tank, sample) in self.tanks.iter_mut().zip(frame.iter_mut()) {
+                let reverb = tank.process(input, self.feedback, self.damping);
+                *sample = *sample * (1.0 - self.wet) + reverb * self.wet * WET_SCALE;
+            }
+        }
+        // Faded out; start the next time from silence rather than an old tail.
+        if self.wet == 0.0 {
+            self.tanks.iter_mut().for_each(Tank::clear);
+        }
+    }
+}
+
+impl AudioEngine {
+    /// Add reverb, with `room_size`, `damping` and `wet` each from 0.0 to 1.0
+    ///
+    /// A `wet` level of 0.0 turns it off. Emits `ReverbChanged`.
+    pub fn set_reverb(&self, room_size: f32, damping: f32, wet: f32) {
+        let settings = self.update_dsp(|settings| {
+            settings.reverb = ReverbSettings {
+                room_size: room_size.clamp(0.0, 1.0),
+                damping: damping.clamp(0.0, 1.0),
+                wet: wet.clamp(0.0, 1.0),
+            }
+        });
+        self.shared.events.emit(AudioEvent::ReverbChanged {
+            settings: settings.reverb,
+        });
+    }
+}
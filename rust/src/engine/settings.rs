@@ -0,0 +1,230 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::MutexGuard;
+
+use anyhow::Context;
+use serde_json::Value;
+
+use super::{devices, eq, normalization, spectrum, AudioEngine, Command, Shared};
+use crate::{AudioEvent, EngineSettings, FadeCurve, NormalizationMode, SettingsPatch, TunesError};
+
+/// Edits bringing the settings of each older file version up to the next; a file's
+/// version is one more than the number of upgrades it has had
+const UPGRADES: &[fn(&mut Value)] = &[];
+
+fn current_version() -> usize {
+    UPGRADES.len() + 1
+}
+
+/// Where settings are saved, and their values now
+#[derive(Default)]
+pub(super) struct SettingsFile {
+    path: Option<PathBuf>,
+    settings: EngineSettings,
+}
+
+/// Layout of a settings file
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Saved {
+    version: usize,
+    settings: Value,
+}
+
+impl Default for EngineSettings {
+    fn default() -> Self {
+        EngineSettings {
+            eq_enabled: false,
+            eq_gains: vec![0.0; eq::BAND_FREQUENCIES.len()],
+            gapless: true,
+            crossfade_ms: 0,
+            crossfade_curve: FadeCurve::EqualPower,
+            normalization: NormalizationMode::Off,
+            target_lufs: normalization::DEFAULT_TARGET_LUFS,
+            output_device: None,
+            visualizer_bands: spectrum::DEFAULT_BANDS,
+            visualizer_fps: spectrum::DEFAULT_FPS,
+            visualizer_window: 0,
+        }
+    }
+}
+
+impl SettingsPatch {
+    fn apply(self, settings: &mut EngineSettings) {
+        fn set<T>(field: &mut T, value: Option<T>) {
+            if let Some(value) = value {
+                *field = value;
+            }
+        }
+        set(&mut settings.eq_enabled, self.eq_enabled);
+        set(&mut settings.eq_gains, self.eq_gains);
+        set(&mut settings.gapless, self.gapless);
+        set(&mut settings.crossfade_ms, self.crossfade_ms);
+        set(&mut settings.crossfade_curve, self.crossfade_curve);
+        set(&mut settings.normalization, self.normalization);
+        set(&mut settings.target_lufs, self.target_lufs);
+        if let Some(id) = self.output_device {
+            settings.output_device = Some(id).filter(|id| !id.is_empty());
+        }
+        set(&mut settings.visualizer_bands, self.visualizer_bands);
+        set(&mut settings.visualizer_fps, self.visualizer_fps);
+        set(&mut settings.visualizer_window, self.visualizer_window);
+    }
+}
+
+/// Settings saved at `path`, brought up to the current version; `None` if nothing has
+/// been saved there yet
+fn read(path: &Path) -> anyhow::Result<Option<EngineSettings>> {
+    let json = match fs::read_to_string(path) {
+        Ok(json) => json,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+    };
+    let Saved {
+        version,
+        mut settings,
+    } = serde_json::from_str(&json)
+        .with_context(|| format!("unreadable settings in {}", path.display()))?;
+    if version > current_version() {
+        log::warn!(
+            "{} is from a newer version; reading the settings this one knows",
+            path.display()
+        );
+    }
+    for upgrade in UPGRADES.iter().skip(version.saturating_sub(1)) {
+        upgrade(&mut settings);
+    }
+    // Settings added since the file was written keep their defaults.
+    let mut settings: EngineSettings = serde_json::from_value(settings)
+        .with_context(|| format!("unreadable settings in {}", path.display()))?;
+    settings.eq_gains.resize(eq::BAND_FREQUENCIES.len(), 0.0);
+    Ok(Some(settings))
+}
+
+impl AudioEngine {
+    /// Keep engine settings in the file at `path` from now on, applying what it holds
+    ///
+    /// Returns the settings now in effect. Changes made with `update_settings` or the
+    /// individual setters are saved as they happen and reported as `SettingsChanged`.
+    pub fn set_settings_file(&self, path: String) -> Result<EngineSettings, TunesError> {
+        let path = PathBuf::from(path);
+        let loaded = read(&path)?;
+        let mut file = self.shared.settings.lock().unwrap();
+        file.path = Some(path);
+        let Some(loaded) = loaded else {
+            return Ok(file.settings.clone());
+        };
+        self.apply_settings(&file.settings, &loaded);
+        Ok(self.shared.store_settings(file, loaded)?)
+    }
+
+    pub fn get_settings(&self) -> EngineSettings {
+        self.shared.settings.lock().unwrap().settings.clone()
+    }
+
+    /// Change the settings `patch` has values for, keeping the others, and save them
+    pub fn update_settings(&self, patch: SettingsPatch) -> Result<EngineSettings, TunesError> {
+        if let Some(gains) = &patch.eq_gains {
+            if gains.len() != eq::BAND_FREQUENCIES.len() {
+                return Err(TunesError::invalid_state(format!(
+                    "expected {} equalizer gains, got {}",
+                    eq::BAND_FREQUENCIES.len(),
+                    gains.len()
+                )));
+            }
+        }
+        if let Some(id) = patch.output_device.as_deref().filter(|id| !id.is_empty()) {
+            if devices::find_device(id).is_none() {
+                return Err(TunesError::device(format!("no output device named {id:?}")));
+            }
+        }
+        let file = self.shared.settings.lock().unwrap();
+        let mut settings = file.settings.clone();
+        patch.apply(&mut settings);
+        self.apply_settings(&file.settings, &settings);
+        Ok(self.shared.store_settings(file, settings)?)
+    }
+
+    /// Send the engine thread whatever differs between `old` and `new`
+    fn apply_settings(&self, old: &EngineSettings, new: &EngineSettings) {
+        let changed = |same: fn(&EngineSettings, &EngineSettings) -> bool| !same(old, new);
+        if changed(|a, b| a.eq_enabled == b.eq_enabled) {
+            self.send(Command::SetEqEnabled(new.eq_enabled));
+        }
+        if changed(|a, b| a.eq_gains == b.eq_gains) {
+            if let Ok(gains) = new.eq_gains.as_slice().try_into() {
+                self.send(Command::SetEqGains(gains));
+            }
+        }
+        if changed(|a, b| a.gapless == b.gapless) {
+            self.send(Command::SetGapless(new.gapless));
+        }
+        if changed(|a, b| {
+            (a.crossfade_ms, a.crossfade_curve) == (b.crossfade_ms, b.crossfade_curve)
+        }) {
+            self.send(Command::SetCrossfade {
+                duration_ms: new.crossfade_ms,
+                curve: new.crossfade_curve,
+            });
+        }
+        if changed(|a, b| (a.normalization, a.target_lufs) == (b.normalization, b.target_lufs)) {
+            self.send(Command::SetNormalization {
+                mode: new.normalization,
+                target_lufs: new.target_lufs,
+            });
+        }
+        if changed(|a, b| a.output_device == b.output_device) {
+            self.send(Command::SetOutputDevice(new.output_device.clone()));
+        }
+        fn visualizer(s: &EngineSettings) -> (u32, u32, u32) {
+            (s.visualizer_bands, s.visualizer_fps, s.visualizer_window)
+        }
+        if changed(|a, b| visualizer(a) == visualizer(b)) {
+            self.send(Command::SetVisualizerConfig {
+                bands: new.visualizer_bands,
+                fps: new.visualizer_fps,
+                window: new.visualizer_window,
+            });
+        }
+    }
+}
+
+impl Shared {
+    /// Record a change one of the setters already sent to the engine thread
+    pub(super) fn record_settings(&self, edit: impl FnOnce(&mut EngineSettings)) {
+        let file = self.settings.lock().unwrap();
+        let mut settings = file.settings.clone();
+        edit(&mut settings);
+        if let Err(e) = self.store_settings(file, settings) {
+            log::warn!("{e:#}");
+        }
+    }
+
+    /// Make `settings` the current ones, announcing and saving them if they changed
+    fn store_settings(
+        &self,
+        mut file: MutexGuard<'_, SettingsFile>,
+        settings: EngineSettings,
+    ) -> anyhow::Result<EngineSettings> {
+        if settings == file.settings {
+            return Ok(settings);
+        }
+        file.settings = settings.clone();
+        self.events.emit(AudioEvent::SettingsChanged {
+            settings: settings.clone(),
+        });
+        let Some(path) = file.path.clone() else {
+            return Ok(settings);
+        };
+        let json = serde_json::to_string_pretty(&Saved {
+            version: current_version(),
+            settings: serde_json::to_value(&settings)?,
+        })?;
+        // Written aside and renamed, so a crash mid-write leaves the previous settings.
+        let partial = path.with_extension("tmp");
+        fs::write(&partial, json)
+            .and_then(|()| fs::rename(&partial, &path))
+            .with_context(|| format!("failed to save settings to {}", path.display()))?;
+        Ok(settings)
+    }
+}
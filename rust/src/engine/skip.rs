@@ -0,0 +1,42 @@
+use super::{AudioEngine, Command, EngineThread};
+
+/// A skip past the end stops this far before it, so the song still finishes normally
+const END_MARGIN_SECS: f64 = 0.5;
+
+impl AudioEngine {
+    /// Skip `delta_secs` forward, or back for a negative delta, from the current position
+    ///
+    /// Clamped to the song's start and end. Calls arriving faster than the engine can
+    /// seek add up into one seek, so holding down a skip key moves steadily.
+    pub fn seek_relative(&self, delta_secs: f64) {
+        let first = {
+            let mut pending = self.shared.pending_skip.lock().unwrap();
+            let first = pending.is_none();
+            *pending = Some(pending.unwrap_or(0.0) + delta_secs);
+            first
+        };
+        if first {
+            self.send(Command::SeekRelative);
+        }
+    }
+}
+
+impl EngineThread {
+    /// Seek by the skips added up since the last one
+    pub(super) fn seek_relative(&mut self) {
+        let Some(delta) = self.shared.pending_skip.lock().unwrap().take() else {
+            return;
+        };
+        let (position, duration) = (self.shared.position_secs(), self.shared.duration_secs());
+        let duration = duration.or_else(|| {
+            self.song()
+                .map(|song| song.duration as f64)
+                .filter(|&d| d > 0.0)
+        });
+        let mut target = (position + delta).max(0.0);
+        if let Some(duration) = duration {
+            target = target.min((duration - END_MARGIN_SECS).max(0.0));
+        }
+        self.seek(target);
+    }
+}
@@ -0,0 +1,133 @@
+use std::time::{Duration, Instant};
+
+use super::{AudioEngine, Command, EngineThread};
+use crate::{AudioEvent, PlaybackState, SleepTimerMode};
+
+/// How long playback fades out before a `StopAfterTime` timer pauses it
+const FADE_OUT: Duration = Duration::from_secs(10);
+
+/// Progress of an armed sleep timer
+pub(super) enum SleepTimer {
+    Counting {
+        deadline: Instant,
+        mode: SleepTimerMode,
+    },
+    /// Time is up and the output is fading; playback pauses at `until`
+    FadingOut { until: Instant },
+    /// Time is up; playback stops when the current track ends
+    AtTrackEnd,
+}
+
+impl AudioEngine {
+    /// Stop playback `duration_secs` from now, counted on the audio thread so it also
+    /// fires while the app is in the background
+    ///
+    /// `StopAfterTime` fades out and pauses; `StopAfterTrack` waits for the track playing
+    /// at that point to end. Either way `SleepTimerFired` is emitted. Replaces any
+    /// timer already set; zero cancels it.
+    pub fn set_sleep_timer(&self, duration_secs: u64, mode: SleepTimerMode) {
+        if duration_secs == 0 {
+            self.cancel_sleep_timer();
+        } else {
+            self.send(Command::SetSleepTimer {
+                duration: Duration::from_secs(duration_secs),
+                mode,
+            });
+        }
+    }
+
+    pub fn cancel_sleep_timer(&self) {
+        self.send(Command::CancelSleepTimer);
+    }
+}
+
+impl EngineThread {
+    pub(super) fn set_sleep_timer(&mut self, duration: Duration, mode: SleepTimerMode) {
+        self.cancel_sleep_timer();
+        self.sleep_timer = Some(SleepTimer::Counting {
+            deadline: Instant::now() + duration,
+            mode,
+        });
+    }
+
+    /// Disarm the timer, undoing a fade-out or held-back next track
+    pub(super) fn cancel_sleep_timer(&mut self) {
+        match self.sleep_timer.take() {
+            Some(SleepTimer::FadingOut { .. }) => {
+                self.player.lock().unwrap().sleep_fade.set_target(1.0);
+            }
+            Some(SleepTimer::AtTrackEnd) => self.preload_next(),
+            _ => {}
+        }
+    }
+
+    /// Whether the next track is held back because playback stops after this one
+    pub(super) fn stops_at_track_end(&self) -> bool {
+        matches!(self.sleep_timer, Some(SleepTimer::AtTrackEnd))
+    }
+
+    pub(super) fn check_sleep_timer(&mut self) {
+        let now = Instant::now();
+        match self.sleep_timer {
+            Some(SleepTimer::Counting { deadline, mode }) if now >= deadline => {
+                let playing = self.is_playing();
+                match mode {
+                    SleepTimerMode::StopAfterTime if playing => {
+                        self.sleep_timer = Some(SleepTimer::FadingOut {
+                            until: now + FADE_OUT,
+                        });
+                        let frames = FADE_OUT.as_secs_f32() * self.sample_rate as f32;
+                        self.player.lock().unwrap().sleep_fade.ramp_to(0.0, frames);
+                    }
+                    SleepTimerMode::StopAfterTrack
+                        if playing || self.state() == PlaybackState::Paused =>
+                    {
+                        self.sleep_timer = Some(SleepTimer::AtTrackEnd);
+                        self.player.lock().unwrap().load_next(None, 1.0);
+                    }
+                    // Nothing is playing, so there is nothing to stop.
+                    _ => self.fire_sleep_timer(mode),
+                }
+            }
+            Some(SleepTimer::FadingOut { until }) if now >= until => {
+                self.pause();
+                // Paused output is silent anyway, so this only shows once playback resumes.
+                self.player.lock().unwrap().sleep_fade.set_target(1.0);
+                self.fire_sleep_timer(SleepTimerMode::StopAfterTime);
+            }
+            _ => {}
+        }
+    }
+
+    /// Called when the current track ends; true if the timer stopped playback there
+    pub(super) fn sleep_timer_at_track_end(&mut self) -> bool {
+        if !self.stops_at_track_end() {
+            return false;
+        }
+        self.fire_sleep_timer(SleepTimerMode::StopAfterTrack);
+        true
+    }
+
+    /// The user paused, resumed or picked something else while the timer was fading out
+    pub(super) fn interrupt_sleep_fade(&mut self) {
+        if matches!(self.sleep_timer, Some(SleepTimer::FadingOut { .. })) {
+            self.cancel_sleep_timer();
+        }
+    }
+
+    /// Count `missed`, time the system slept without `Instant` counting it, toward the
+    /// timer, so it runs out by the wall clock on every platform
+    pub(super) fn sleep_timer_count_missed(&mut self, missed: Duration) {
+        if let Some(SleepTimer::Counting { deadline, .. }) = &mut self.sleep_timer {
+            *deadline = deadline.checked_sub(missed).unwrap_or_else(Instant::now);
+        }
+    }
+
+    fn fire_sleep_timer(&mut self, mode: SleepTimerMode) {
+        log::info!("sleep timer fired ({mode:?})");
+        self.sleep_timer = None;
+        self.shared
+            .events
+            .emit(AudioEvent::SleepTimerFired { mode });
+    }
+}
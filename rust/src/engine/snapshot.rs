@@ -0,0 +1,73 @@
+use super::{AudioEngine, Shared};
+use crate::{EngineSnapshot, LoopRegion};
+
+impl AudioEngine {
+    /// Everything the player UI shows, read in one call
+    ///
+    /// Lets a restarted Flutter isolate rebuild its state without waiting for events;
+    /// subscribe with `audio_event_stream` first so nothing is missed in between.
+    pub fn get_engine_state(&self) -> EngineSnapshot {
+        self.shared.snapshot()
+    }
+}
+
+impl Shared {
+    pub(super) fn snapshot(&self) -> EngineSnapshot {
+        let (state, song) = {
+            let status = self.status.lock().unwrap();
+            (status.state.clone(), status.song.clone())
+        };
+        let (queue, current_index, shuffle, repeat) = {
+            let queue = self.queue.lock().unwrap();
+            (
+                queue.songs().to_vec(),
+                queue.current_index().map(|i| i as u32),
+                queue.shuffle(),
+                queue.repeat(),
+            )
+        };
+        let (volume, muted) = {
+            let volume = self.volume.lock().unwrap();
+            (volume.level, volume.muted)
+        };
+        let fallback = song.as_ref().map_or(0.0, |s| s.duration as f64);
+        let current_time = self.position_secs();
+        let total_time = self.duration_secs().unwrap_or(fallback);
+        let (eq_enabled, eq_gains, loop_region) = {
+            let player = self.player.lock().unwrap();
+            (
+                player.eq.is_enabled(),
+                player.eq.gains().to_vec(),
+                player
+                    .loop_region()
+                    .map(|(start_secs, end_secs)| LoopRegion {
+                        start_secs,
+                        end_secs,
+                    }),
+            )
+        };
+        let dsp = *self.dsp.lock().unwrap();
+        EngineSnapshot {
+            state,
+            song,
+            current_time,
+            total_time,
+            queue,
+            current_index,
+            shuffle,
+            repeat,
+            volume,
+            muted,
+            eq_enabled,
+            eq_gains,
+            preamp_db: dsp.preamp_db,
+            balance: dsp.balance,
+            reverb: dsp.reverb,
+            stereo_width: dsp.stereo_width,
+            channel_mode: dsp.channel_mode,
+            dynamics: dsp.dynamics,
+            loop_region,
+            dsp_chain: self.dsp_chain.lock().unwrap().stages(),
+        }
+    }
+}
@@ -0,0 +1,190 @@
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+
+use super::pipeline::CHANNELS;
+use super::EngineThread;
+use crate::AudioEvent;
+
+/// Samples per FFT window unless `configure` says otherwise
+const DEFAULT_WINDOW: usize = 2048;
+
+/// Range of FFT window sizes; sizes are rounded up to a power of two
+const MIN_WINDOW: usize = 256;
+const MAX_WINDOW: usize = 16384;
+
+/// Lowest frequency covered by the first band
+const MIN_FREQUENCY: f32 = 20.0;
+
+/// Highest frequency covered by the last band (clamped to Nyquist)
+const MAX_FREQUENCY: f32 = 20_000.0;
+
+/// Level mapped to 0.0 in the normalized output
+const FLOOR_DB: f32 = -90.0;
+
+pub(crate) const DEFAULT_BANDS: u32 = 32;
+pub(crate) const DEFAULT_FPS: u32 = 30;
+
+/// One analyzed window of output, with when it's heard
+pub(crate) struct SpectrumFrame {
+    pub frequencies: Vec<f32>,
+    /// The window's mono samples, oldest first
+    pub waveform: Vec<f32>,
+    /// Track position at the end of the window
+    pub position_secs: f64,
+    /// When the end of the window is handed to the output device
+    pub output_at: SystemTime,
+}
+
+/// FFT tap over the rendered output, producing log-spaced band levels
+///
+/// Levels are normalized to 0.0..=1.0 over a `FLOOR_DB`..0 dBFS range.
+pub(crate) struct SpectrumAnalyzer {
+    sample_rate: u32,
+    planner: FftPlanner<f32>,
+    fft: Arc<dyn Fft<f32>>,
+    window: Vec<f32>,
+    history: Vec<f32>,
+    write_index: usize,
+    frames_since_output: usize,
+    hop_frames: usize,
+    band_edges: Vec<usize>,
+    scratch: Vec<Complex<f32>>,
+}
+
+impl SpectrumAnalyzer {
+    pub fn new(sample_rate: u32) -> Self {
+        let mut planner = FftPlanner::new();
+        let fft = planner.plan_fft_forward(DEFAULT_WINDOW);
+        let mut analyzer = SpectrumAnalyzer {
+            sample_rate,
+            planner,
+            fft,
+            window: Vec::new(),
+            history: Vec::new(),
+            write_index: 0,
+            frames_since_output: 0,
+            hop_frames: 0,
+            band_edges: Vec::new(),
+            scratch: Vec::new(),
+        };
+        analyzer.configure(DEFAULT_BANDS, DEFAULT_FPS, 0);
+        analyzer
+    }
+
+    /// Change the number of bands, output rate and FFT window size, zero for the
+    /// default window; zero bands or rate disables analysis
+    pub fn configure(&mut self, bands: u32, fps: u32, window: u32) {
+        let size = match window {
+            0 => DEFAULT_WINDOW,
+            window => (window as usize)
+                .clamp(MIN_WINDOW, MAX_WINDOW)
+                .next_power_of_two(),
+        };
+        if size != self.window.len() {
+            self.fft = self.planner.plan_fft_forward(size);
+            self.window = (0..size)
+                .map(|i| {
+                    let phase = 2.0 * std::f32::consts::PI * i as f32 / (size - 1) as f32;
+                    0.5 - 0.5 * phase.cos()
+                })
+                .collect();
+            self.history = vec![0.0; size];
+            self.write_index = 0;
+            self.scratch = vec![Complex::default(); size];
+        }
+        self.band_edges.clear();
+        self.hop_frames = 0;
+        if bands == 0 || fps == 0 {
+            return;
+        }
+        self.hop_frames = (self.sample_rate / fps).max(1) as usize;
+
+        let bin_hz = self.sample_rate as f32 / size as f32;
+        let max_frequency = MAX_FREQUENCY.min(self.sample_rate as f32 / 2.0);
+        let ratio = max_frequency / MIN_FREQUENCY;
+        self.band_edges = (0..=bands)
+            .map(|b| {
+                let frequency = MIN_FREQUENCY * ratio.powf(b as f32 / bands as f32);
+                ((frequency / bin_hz).round() as usize).clamp(1, size / 2)
+            })
+            .collect();
+    }
+
+    /// Feed interleaved output samples, returning band levels and the window they were
+    /// taken from when a frame is due
+    pub fn push(&mut self, samples: &[f32]) -> Option<(Vec<f32>, Vec<f32>)> {
+        if self.hop_frames == 0 {
+            return None;
+        }
+        let size = self.history.len();
+        for frame in samples.chunks_exact(CHANNELS as usize) {
+            self.history[self.write_index] = frame.iter().sum::<f32>() / CHANNELS as f32;
+            self.write_index = (self.write_index + 1) % size;
+        }
+        self.frames_since_output += samples.len() / CHANNELS as usize;
+        if self.frames_since_output < self.hop_frames {
+            return None;
+        }
+        self.frames_since_output = 0;
+        let waveform: Vec<f32> = (0..size)
+            .map(|i| self.history[(self.write_index + i) % size])
+            .collect();
+        Some((self.analyze(&waveform), waveform))
+    }
+
+    fn analyze(&mut self, waveform: &[f32]) -> Vec<f32> {
+        let size = waveform.len();
+        for ((bin, sample), weight) in self.scratch.iter_mut().zip(waveform).zip(&self.window) {
+            *bin = Complex::new(sample * weight, 0.0);
+        }
+        self.fft.process(&mut self.scratch);
+
+        // Hann window has a coherent gain of 0.5, so a full-scale sine peaks at N/4.
+        let scale = 4.0 / size as f32;
+        self.band_edges
+            .windows(2)
+            .map(|edge| {
+                let (lo, hi) = (edge[0], edge[1].max(edge[0] + 1));
+                let peak = self.scratch[lo..hi.min(size / 2 + 1)]
+                    .iter()
+                    .map(|c| c.norm() * scale)
+                    .fold(0.0f32, f32::max);
+                let db = 20.0 * peak.max(1e-9).log10();
+                ((db - FLOOR_DB) / -FLOOR_DB).clamp(0.0, 1.0)
+            })
+            .collect()
+    }
+}
+
+impl EngineThread {
+    /// Emit a frame as `SpectrumDataUpdated` and `WaveformFrame`, timed for when it's heard
+    pub(super) fn emit_spectrum(&self, frame: SpectrumFrame) {
+        let presentation_time_ms = self.presentation_time_ms(frame.output_at);
+        self.shared.events.emit(AudioEvent::SpectrumDataUpdated {
+            frequencies: frame.frequencies,
+            position_secs: frame.position_secs,
+            presentation_time_ms,
+        });
+        self.shared.events.emit(AudioEvent::WaveformFrame {
+            samples: frame.waveform,
+            position_secs: frame.position_secs,
+            presentation_time_ms,
+        });
+    }
+
+    /// Milliseconds since the Unix epoch when audio handed to the device at
+    /// `output_at` is heard
+    pub(super) fn presentation_time_ms(&self, output_at: SystemTime) -> f64 {
+        let latency = self.shared.output_latency.load(Ordering::Relaxed);
+        let heard_at = output_at + Duration::from_micros(latency.into());
+        heard_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64()
+            * 1000.0
+    }
+}
@@ -0,0 +1,82 @@
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::Receiver;
+
+use super::{Command, EngineThread, Shared};
+use crate::{AudioEvent, PlaybackState};
+
+/// What a panic was raised with, if it's text
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> String {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => payload
+            .downcast_ref::<String>()
+            .cloned()
+            .unwrap_or_else(|| "unknown panic".to_string()),
+    }
+}
+
+impl EngineThread {
+    /// Handle commands until the engine is dropped, starting over from a stopped
+    /// player whenever handling one panics
+    pub(super) fn supervise(mut self, commands: Receiver<Command>) {
+        loop {
+            match panic::catch_unwind(AssertUnwindSafe(|| self.step(&commands))) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(payload) => self.recover("engine", panic_message(&*payload)),
+            }
+        }
+    }
+
+    /// Stop playback and reopen the output after a panic in `context`
+    fn recover(&mut self, context: &str, message: String) {
+        log::error!("{context} panicked: {message}");
+        self.shared.clear_poison();
+        self.player.lock().unwrap().unload();
+        self.next_song = None;
+        *self.shared.stream_buffer.lock().unwrap() = None;
+        self.lyrics = None;
+        self.chapters = None;
+        self.play_tracker = None;
+        self.set_state(PlaybackState::Stopped, None);
+        let device = self.output_device.clone();
+        self.open_output(device.as_deref());
+        self.emit_engine_error(context, message);
+    }
+
+    /// Report a panic the pipeline already recovered from, moving on past the song the
+    /// panic left behind
+    pub(super) fn skip_after_panic(&mut self, context: &str, message: String) {
+        log::error!("{context} panicked: {message}");
+        self.emit_engine_error(context, message);
+        self.finish_track();
+    }
+
+    fn emit_engine_error(&self, context: &str, message: String) {
+        self.shared.events.emit(AudioEvent::EngineError {
+            context: context.to_string(),
+            message,
+        });
+    }
+}
+
+impl Shared {
+    /// Make the state a panicking thread held locked usable again
+    fn clear_poison(&self) {
+        self.status.clear_poison();
+        self.queue.clear_poison();
+        self.volume.clear_poison();
+        self.dsp.clear_poison();
+        self.dsp_chain.clear_poison();
+        self.pending_skip.clear_poison();
+        self.player.clear_poison();
+        self.state_file.clear_poison();
+        self.settings.clear_poison();
+        self.output_format.clear_poison();
+        self.stream_buffer.clear_poison();
+        self.zones.clear_poison();
+        self.recording.clear_poison();
+        self.cast.clear_poison();
+    }
+}
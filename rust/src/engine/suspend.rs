@@ -0,0 +1,84 @@
+use std::time::{Duration, Instant, SystemTime};
+
+use super::EngineThread;
+use crate::AudioEvent;
+
+/// A gap between two engine steps this long can only be the system having slept; the
+/// engine thread otherwise wakes every `POLL_INTERVAL`
+const SUSPEND_THRESHOLD: Duration = Duration::from_secs(5);
+
+/// Both clocks as the engine thread last saw them
+///
+/// `Instant` stops while the system is suspended on some platforms (Linux, Android,
+/// macOS, iOS) and keeps counting on others (Windows), so a suspend shows either as the
+/// wall clock running ahead of it or as a long gap on both.
+pub(super) struct SuspendWatch {
+    monotonic: Instant,
+    wall: SystemTime,
+}
+
+/// A suspend the system just woke from
+struct Suspend {
+    /// Time spent asleep, by the wall clock
+    slept: Duration,
+    /// Part of it `Instant` didn't count
+    missed: Duration,
+}
+
+impl SuspendWatch {
+    pub fn new() -> Self {
+        SuspendWatch {
+            monotonic: Instant::now(),
+            wall: SystemTime::now(),
+        }
+    }
+
+    /// The suspend since the last call, if there was one
+    fn check(&mut self) -> Option<Suspend> {
+        let monotonic = Instant::now();
+        let wall = SystemTime::now();
+        let ticked = monotonic - self.monotonic;
+        // A wall clock set back tells nothing; one set forward looks like a suspend
+        // but only costs an output restart.
+        let passed = wall.duration_since(self.wall).unwrap_or(ticked);
+        self.monotonic = monotonic;
+        self.wall = wall;
+        let missed = passed.saturating_sub(ticked);
+        (missed >= SUSPEND_THRESHOLD || ticked >= SUSPEND_THRESHOLD).then(|| Suspend {
+            slept: passed.max(ticked),
+            missed,
+        })
+    }
+}
+
+impl EngineThread {
+    /// Pick up where playback was after the system woke from a suspend
+    pub(super) fn check_suspend(&mut self) {
+        let Some(suspend) = self.suspend_watch.check() else {
+            return;
+        };
+        log::info!(
+            "resumed after {:.0}s suspended",
+            suspend.slept.as_secs_f64()
+        );
+        self.sleep_timer_count_missed(suspend.missed);
+        // The cast device's last report is from before the suspend; count on from now
+        // rather than across the suspend until it reports again.
+        if let Some(cast) = &mut *self.shared.cast.lock().unwrap() {
+            cast.updated = Instant::now();
+        }
+        // Streams rarely survive a suspend and don't reliably say when they didn't, so
+        // they're reopened either way, on the default device if the selected one went.
+        if !self.fail_over_if_gone() {
+            let device = self.output_device.clone();
+            self.open_output(device.as_deref());
+        }
+        self.reopen_zones();
+        self.last_device_check = Instant::now();
+        self.shared.events.emit(AudioEvent::ResumedFromSuspend {
+            slept_secs: suspend.slept.as_secs_f64(),
+            position_secs: self.shared.position_secs(),
+        });
+        self.emit_progress();
+    }
+}
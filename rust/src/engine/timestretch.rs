@@ -0,0 +1,277 @@
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+
+use super::{AudioEngine, Command};
+use crate::TunesError;
+
+/// Length of each overlap-added segment
+const SEGMENT_MS: u32 = 40;
+
+/// How far a segment may move from its nominal position to line up with the last one
+const SEARCH_MS: u32 = 12;
+
+/// Only every n-th candidate offset and frame is compared while searching, to bound cost
+const SEARCH_STRIDE: usize = 2;
+const COMPARE_STRIDE: usize = 4;
+
+pub(crate) const MIN_RATE: f32 = 0.5;
+pub(crate) const MAX_RATE: f32 = 3.0;
+pub(crate) const MAX_PITCH_SEMITONES: f32 = 12.0;
+
+/// WSOLA time-stretcher with a resampling stage for independent pitch shifting
+///
+/// Playing at `tempo` without a pitch change stretches time by `tempo`. Shifting pitch
+/// by a ratio `pitch` stretches by `tempo / pitch` and then resamples by `pitch`, which
+/// restores the tempo and moves every frequency by `pitch`.
+pub(crate) struct TimeStretch {
+    channels: usize,
+    segment: usize,
+    hop: usize,
+    search: usize,
+    window: Vec<f32>,
+    tempo: f64,
+    pitch: f64,
+    /// Interleaved input not yet fully consumed
+    input: Vec<f32>,
+    /// Nominal start of the next segment in `input`, in frames
+    position: f64,
+    /// Where the previous segment continues in `input`, which the next one should match
+    natural: Option<usize>,
+    /// Second half of the previous windowed segment, waiting for the next to overlap it
+    overlap: Vec<f32>,
+    /// Stretched frames waiting for the pitch resampler
+    stretched: Vec<f32>,
+    resample_position: f64,
+    output: VecDeque<f32>,
+}
+
+impl TimeStretch {
+    pub fn new(sample_rate: u32, channels: usize) -> Self {
+        let hop = (sample_rate * SEGMENT_MS / 2000).max(1) as usize;
+        let segment = hop * 2;
+        // Periodic Hann; windows half a segment apart sum to exactly one.
+        let window = (0..segment)
+            .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / segment as f32).cos())
+            .collect();
+        TimeStretch {
+            channels,
+            segment,
+            hop,
+            search: (sample_rate * SEARCH_MS / 1000) as usize,
+            window,
+            tempo: 1.0,
+            pitch: 1.0,
+            input: Vec::new(),
+            position: 0.0,
+            natural: None,
+            overlap: vec![0.0; hop * channels],
+            stretched: Vec::new(),
+            resample_position: 0.0,
+            output: VecDeque::new(),
+        }
+    }
+
+    /// Whether audio has to pass through the stretcher at all
+    pub fn is_active(&self) -> bool {
+        self.tempo != 1.0 || self.pitch != 1.0
+    }
+
+    pub fn tempo(&self) -> f64 {
+        self.tempo
+    }
+
+    pub fn set_tempo(&mut self, rate: f32) {
+        self.tempo = rate.clamp(MIN_RATE, MAX_RATE) as f64;
+    }
+
+    pub fn set_pitch(&mut self, semitones: f32) {
+        let semitones = semitones.clamp(-MAX_PITCH_SEMITONES, MAX_PITCH_SEMITONES);
+        self.pitch = 2f64.powf(semitones as f64 / 12.0);
+    }
+
+    /// Drop everything buffered, e.g. after a seek
+    pub fn reset(&mut self) {
+        self.input.clear();
+        self.position = 0.0;
+        self.natural = None;
+        self.overlap.fill(0.0);
+        self.stretched.clear();
+        self.resample_position = 0.0;
+        self.output.clear();
+    }
+
+    /// Samples ready to be pulled
+    pub fn available(&self) -> usize {
+        self.output.len()
+    }
+
+    /// Input frames that went in but haven't come out yet, for position reporting
+    pub fn latency_frames(&self) -> f64 {
+        let input = (self.input.len() / self.channels) as f64 - self.position;
+        let stretched = (self.stretched.len() / self.channels) as f64 * self.tempo / self.pitch;
+        let output = (self.output.len() / self.channels) as f64 * self.tempo;
+        input.max(0.0) + stretched + output
+    }
+
+    pub fn push(&mut self, block: &[f32]) {
+        self.input.extend_from_slice(block);
+        while self.can_step() {
+            self.step();
+        }
+        self.resample();
+    }
+
+    pub fn pull(&mut self, out: &mut Vec<f32>, count: usize) {
+        let count = count.min(self.output.len());
+        out.extend(self.output.drain(..count));
+    }
+
+    fn frames(&self) -> usize {
+        self.input.len() / self.channels
+    }
+
+    fn can_step(&self) -> bool {
+        let frames = self.frames();
+        // Rounded as `step` rounds it, so the latest candidate's segment is buffered.
+        let nominal_end = self.position.round() as usize + self.search + self.segment;
+        frames >= nominal_end && self.natural.is_none_or(|n| frames >= n + self.hop)
+    }
+
+    /// Channel sum of input frame `frame`
+    fn mono(&self, frame: usize) -> f32 {
+        self.input[frame * self.channels..(frame + 1) * self.channels]
+            .iter()
+            .sum()
+    }
+
+    /// Offset near `nominal` whose start best continues the waveform at `natural`
+    fn best_offset(&self, natural: usize, nominal: usize) -> usize {
+        let mut best = nominal;
+        let mut best_score = f32::MIN;
+        for candidate in
+            (nominal.saturating_sub(self.search)..=nominal + self.search).step_by(SEARCH_STRIDE)
+        {
+            let (mut correlation, mut energy) = (0.0, 0.0);
+            for i in (0..self.hop).step_by(COMPARE_STRIDE) {
+                let x = self.mono(candidate + i);
+                correlation += x * self.mono(natural + i);
+                energy += x * x;
+            }
+            let score = correlation / (energy + 1e-9).sqrt();
+            if score > best_score {
+                best_score = score;
+                best = candidate;
+            }
+        }
+        best
+    }
+
+    fn step(&mut self) {
+        let nominal = self.position.round() as usize;
+        let start = match self.natural {
+            Some(natural) => self.best_offset(natural, nominal),
+            None => nominal,
+        };
+        let ch = self.channels;
+        for i in 0..self.hop {
+            for c in 0..ch {
+                let head = self.input[(start + i) * ch + c] * self.window[i];
+                self.stretched.push(self.overlap[i * ch + c] + head);
+                self.overlap[i * ch + c] =
+                    self.input[(start + self.hop + i) * ch + c] * self.window[self.hop + i];
+            }
+        }
+        self.natural = Some(start + self.hop);
+        self.position += self.hop as f64 * self.tempo / self.pitch;
+
+        // Forget input no future segment can reach.
+        let keep_from = (self.position as usize)
+            .saturating_sub(self.search)
+            .min(start + self.hop);
+        if keep_from >= self.segment {
+            self.input.drain(..keep_from * ch);
+            self.position -= keep_from as f64;
+            self.natural = self.natural.map(|n| n - keep_from);
+        }
+    }
+
+    /// Move stretched frames to the output, resampling them by `pitch`
+    fn resample(&mut self) {
+        let ch = self.channels;
+        if self.pitch == 1.0 {
+            self.output.extend(self.stretched.drain(..));
+            return;
+        }
+        let frames = self.stretched.len() / ch;
+        while self.resample_position + 1.0 < frames as f64 {
+            let index = self.resample_position as usize;
+            let frac = (self.resample_position - index as f64) as f32;
+            for c in 0..ch {
+                let a = self.stretched[index * ch + c];
+                let b = self.stretched[(index + 1) * ch + c];
+                self.output.push_back(a + (b - a) * frac);
+            }
+            self.resample_position += self.pitch;
+        }
+        let consumed = (self.resample_position as usize).min(frames);
+        self.stretched.drain(..consumed * ch);
+        self.resample_position -= consumed as f64;
+    }
+}
+
+impl AudioEngine {
+    /// Play faster or slower without changing pitch; 1.0 is normal speed
+    pub fn set_playback_rate(&self, rate: f32) -> Result<(), TunesError> {
+        if !rate.is_finite() {
+            return Err(TunesError::invalid_state(format!(
+                "playback rate {rate} isn't a number"
+            )));
+        }
+        self.send(Command::SetPlaybackRate(rate));
+        Ok(())
+    }
+
+    /// Shift pitch by `semitones` (clamped to +/-12) without changing speed
+    pub fn set_pitch_shift(&self, semitones: f32) -> Result<(), TunesError> {
+        if !semitones.is_finite() {
+            return Err(TunesError::invalid_state(format!(
+                "pitch shift {semitones} isn't a number"
+            )));
+        }
+        self.send(Command::SetPitchShift(semitones));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A rising sawtooth, which the search always lines up at its latest candidate
+    fn sawtooth(frames: std::ops::Range<usize>, channels: usize) -> Vec<f32> {
+        frames
+            .flat_map(|frame| std::iter::repeat_n((frame % 4_000) as f32 / 4_000.0, channels))
+            .collect()
+    }
+
+    #[test]
+    fn stretches_at_fractional_tempos_without_overrunning_input() {
+        for tempo in [0.75, 0.6, 1.25, 2.5] {
+            let mut stretch = TimeStretch::new(44_100, 2);
+            stretch.set_tempo(tempo);
+            let mut out = Vec::new();
+            // Frame by frame, so a step runs the moment the buffered input allows it.
+            for frame in 0..44_100 {
+                stretch.push(&sawtooth(frame..frame + 1, 2));
+                stretch.pull(&mut out, usize::MAX);
+            }
+            let expected = 44_100.0 / tempo as f64;
+            let frames = out.len() as f64 / 2.0;
+            assert!(
+                (frames - expected).abs() < expected * 0.1,
+                "{tempo}: {frames} frames out"
+            );
+            assert!(out.iter().all(|sample| sample.is_finite()));
+        }
+    }
+}
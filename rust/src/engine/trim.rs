@@ -0,0 +1,100 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::pipeline::BoxedSource;
+use super::{AudioEngine, Command, EngineThread, Shared};
+use crate::{runtime, silence, Song, SongId, TrackSilence};
+
+/// Leading silence is only skipped while playback is still this close to the start
+const SKIP_WINDOW_SECS: f64 = 0.25;
+
+impl AudioEngine {
+    /// Skip silence at the start and end of songs, so tracks follow each other without
+    /// a gap
+    ///
+    /// Silence is measured the first time a song plays and kept in the library; see
+    /// `analyze_silence` to measure the whole library ahead of time.
+    pub fn set_skip_silence(&self, enabled: bool) {
+        self.send(Command::SetSkipSilence(enabled));
+    }
+}
+
+impl EngineThread {
+    pub(super) fn set_skip_silence(&mut self, enabled: bool) {
+        self.skip_silence = enabled;
+        match enabled {
+            true => {
+                if let Some(song) = self.song() {
+                    self.trim_silence(&song, false);
+                }
+            }
+            false => self.player.lock().unwrap().trim_end(None),
+        }
+        self.preload_next();
+    }
+
+    /// Trim the silence of the song just loaded, skipping its leading silence too if
+    /// it starts from the beginning
+    ///
+    /// Silence that hasn't been measured yet is measured off the audio thread and
+    /// applied once known, if the song is still playing.
+    pub(super) fn trim_silence(&mut self, song: &Song, from_start: bool) {
+        if !self.skip_silence || song.duration == 0 {
+            return;
+        }
+        if let Some(silence) = silence::stored(song) {
+            self.shared.apply_silence(&song.id, silence, from_start);
+            return;
+        }
+        let shared = self.shared.clone();
+        let song = song.clone();
+        runtime::spawn_blocking(move || match silence::silence_of(&song) {
+            Ok(silence) => shared.apply_silence(&song.id, silence, from_start),
+            Err(e) => log::debug!("no silence measured for {}: {e}", song.file_path),
+        });
+    }
+
+    /// Skip the stored leading silence of the up-next `song` in its freshly opened
+    /// source, returning how far in it now starts and its trailing silence
+    pub(super) fn trim_next_source(
+        &self,
+        song: &Song,
+        source: &mut BoxedSource,
+    ) -> Option<(f64, TrackSilence)> {
+        if !self.skip_silence || song.duration == 0 {
+            return None;
+        }
+        let silence = silence::stored(song)?;
+        let start = match source.try_seek(Duration::from_secs_f64(silence.leading_secs)) {
+            Ok(()) => silence.leading_secs,
+            Err(e) => {
+                log::debug!("failed to skip the silence of {}: {e}", song.file_path);
+                0.0
+            }
+        };
+        Some((start, silence))
+    }
+}
+
+impl Shared {
+    fn apply_silence(self: &Arc<Self>, song_id: &SongId, silence: TrackSilence, from_start: bool) {
+        let current = self
+            .status
+            .lock()
+            .unwrap()
+            .song
+            .as_ref()
+            .map(|s| s.id.clone());
+        if current.as_ref() != Some(song_id) {
+            return;
+        }
+        let mut player = self.player.lock().unwrap();
+        player.trim_end(Some(silence.trailing_secs));
+        let skip = silence.leading_secs > 0.0 && player.position_secs() < SKIP_WINDOW_SECS;
+        if from_start && skip {
+            if let Err(e) = player.seek(silence.leading_secs) {
+                log::debug!("failed to skip leading silence of {song_id}: {e}");
+            }
+        }
+    }
+}
@@ -0,0 +1,127 @@
+use super::{AudioEngine, Command};
+use crate::AudioEvent;
+
+/// Length of the ramp applied to every volume change
+const RAMP_MS: u32 = 20;
+
+/// User-facing volume settings, mirrored on the FFI side so reads don't hit the audio thread
+pub(crate) struct VolumeSettings {
+    pub level: f32,
+    pub muted: bool,
+}
+
+impl Default for VolumeSettings {
+    fn default() -> Self {
+        VolumeSettings {
+            level: 1.0,
+            muted: false,
+        }
+    }
+}
+
+impl VolumeSettings {
+    /// Linear gain the output should be scaled by
+    pub(super) fn gain(&self) -> f32 {
+        if self.muted {
+            0.0
+        } else {
+            self.level
+        }
+    }
+}
+
+/// Output gain stage that moves linearly to a new gain instead of jumping, avoiding clicks
+pub(crate) struct VolumeRamp {
+    current: f32,
+    target: f32,
+    step: f32,
+    ramp_frames: f32,
+}
+
+impl VolumeRamp {
+    pub fn new(sample_rate: u32) -> Self {
+        VolumeRamp {
+            current: 1.0,
+            target: 1.0,
+            step: 0.0,
+            ramp_frames: (sample_rate * RAMP_MS / 1000).max(1) as f32,
+        }
+    }
+
+    pub fn set_target(&mut self, gain: f32) {
+        self.ramp_to(gain, self.ramp_frames);
+    }
+
+    /// Move to `gain` over `frames` instead of the usual short ramp
+    pub fn ramp_to(&mut self, gain: f32, frames: f32) {
+        self.target = gain;
+        self.step = (gain - self.current) / frames.max(1.0);
+    }
+
+    /// Move to `gain` at once, for when nothing is playing to click
+    pub fn jump_to(&mut self, gain: f32) {
+        self.current = gain;
+        self.target = gain;
+    }
+
+    pub fn gain(&self) -> f32 {
+        self.current
+    }
+
+    /// Scale an interleaved block in place
+    pub fn process(&mut self, block: &mut [f32], channels: usize) {
+        if self.current == self.target {
+            if self.current != 1.0 {
+                block.iter_mut().for_each(|s| *s *= self.current);
+            }
+            return;
+        }
+        for frame in block.chunks_exact_mut(channels) {
+            if self.current != self.target {
+                self.current += self.step;
+                let overshot = (self.step > 0.0 && self.current > self.target)
+                    || (self.step < 0.0 && self.current < self.target);
+                if overshot {
+                    self.current = self.target;
+                }
+            }
+            frame.iter_mut().for_each(|s| *s *= self.current);
+        }
+    }
+}
+
+impl AudioEngine {
+    /// Set the output volume, from 0.0 (silent) to 1.0 (full scale)
+    pub fn set_volume(&self, volume: f32) {
+        self.update_volume(|settings| settings.level = volume.clamp(0.0, 1.0));
+    }
+
+    pub fn get_volume(&self) -> f32 {
+        self.shared.volume.lock().unwrap().level
+    }
+
+    /// Silence output without losing the volume level
+    pub fn set_muted(&self, muted: bool) {
+        self.update_volume(|settings| settings.muted = muted);
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.shared.volume.lock().unwrap().muted
+    }
+
+    pub(super) fn update_volume(&self, update: impl FnOnce(&mut VolumeSettings)) {
+        let (gain, event) = {
+            let mut settings = self.shared.volume.lock().unwrap();
+            update(&mut settings);
+            (
+                settings.gain(),
+                AudioEvent::VolumeChanged {
+                    volume: settings.level,
+                    muted: settings.muted,
+                },
+            )
+        };
+        self.send(Command::SetVolume(gain));
+        self.shared.events.emit(event);
+    }
+}
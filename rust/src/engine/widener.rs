@@ -0,0 +1,62 @@
+use super::dsp_chain::Effect;
+use super::AudioEngine;
+use crate::AudioEvent;
+
+/// Widest setting; 2.0 doubles the difference between the channels
+const MAX_WIDTH: f32 = 2.0;
+
+/// Length of the ramp applied to every width change
+const RAMP_MS: u32 = 20;
+
+/// Mid/side stereo width control; 0.0 is mono and 1.0 leaves the image as it is
+pub(crate) struct StereoWidener {
+    width: f32,
+    target: f32,
+    step: f32,
+}
+
+impl StereoWidener {
+    pub fn new(sample_rate: u32) -> Self {
+        StereoWidener {
+            width: 1.0,
+            target: 1.0,
+            step: 1.0 / (sample_rate * RAMP_MS / 1000).max(1) as f32,
+        }
+    }
+
+    pub fn set(&mut self, width: f32) {
+        self.target = width;
+    }
+}
+
+impl Effect for StereoWidener {
+    fn process(&mut self, block: &mut [f32], channels: usize) {
+        if self.width == 1.0 && self.target == 1.0 {
+            return;
+        }
+        for frame in block.chunks_exact_mut(channels) {
+            self.width = match self.width < self.target {
+                true => (self.width + self.step).min(self.target),
+                false => (self.width - self.step).max(self.target),
+            };
+            let (left, right) = (frame[0], frame[1]);
+            let mid = (left + right) * 0.5;
+            let side = (left - right) * 0.5 * self.width;
+            frame[0] = mid + side;
+            frame[1] = mid - side;
+        }
+    }
+}
+
+impl AudioEngine {
+    /// Narrow (below 1.0, down to mono at 0.0) or widen (up to 2.0) the stereo image
+    ///
+    /// Emits `StereoWidthChanged`.
+    pub fn set_stereo_width(&self, width: f32) {
+        let width = width.clamp(0.0, MAX_WIDTH);
+        self.update_dsp(|settings| settings.stereo_width = width);
+        self.shared
+            .events
+            .emit(AudioEvent::StereoWidthChanged { width });
+    }
+}
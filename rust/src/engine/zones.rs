@@ -0,0 +1,291 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rodio::{DeviceTrait, Source};
+
+use super::devices::{find_device, negotiate};
+use super::output::Output;
+use super::pipeline::CHANNELS;
+use super::{AudioEngine, Command, EngineThread};
+use crate::{AudioEvent, TunesError, ZoneConfig};
+
+/// How often the engine checks that zone devices are still present
+const DEVICE_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How far, in seconds, a zone may fall behind the main output before it skips ahead;
+/// the main output renders in bursts, so some slack is needed
+const MAX_LAG_SECS: f32 = 0.2;
+
+/// Frames a zone's output takes from its buffer at a time
+const CHUNK_FRAMES: usize = 256;
+
+/// Frames over which a zone follows a volume change, to avoid clicks
+const VOLUME_SMOOTHING_FRAMES: f32 = 1000.0;
+
+/// Blocks the main output renders, waiting to be played on a zone
+pub(super) struct ZoneBuffer {
+    samples: Mutex<VecDeque<f32>>,
+    /// Frames kept waiting, so the zone is heard in step with the main output, plus
+    /// its own delay
+    target_frames: AtomicU32,
+    /// Bits of the `f32` gain the zone plays at
+    volume: AtomicU32,
+    max_lag_frames: usize,
+}
+
+impl ZoneBuffer {
+    fn new(sample_rate: u32, volume: f32) -> Self {
+        ZoneBuffer {
+            samples: Mutex::new(VecDeque::new()),
+            target_frames: AtomicU32::new(0),
+            volume: AtomicU32::new(volume.to_bits()),
+            max_lag_frames: (sample_rate as f32 * MAX_LAG_SECS) as usize,
+        }
+    }
+
+    /// Queue a block the main output is about to play
+    pub fn push(&self, block: &[f32]) {
+        let mut samples = self.samples.lock().unwrap();
+        samples.extend(block);
+        // Far behind, e.g. after its device stalled; skip to where it should be.
+        let target = self.target_frames.load(Ordering::Relaxed) as usize * CHANNELS as usize;
+        if samples.len() > target + self.max_lag_frames * CHANNELS as usize {
+            let excess = samples.len() - target;
+            samples.drain(..excess);
+        }
+    }
+}
+
+/// Endless source handed to a zone's output stream
+struct ZoneSource {
+    buffer: Arc<ZoneBuffer>,
+    sample_rate: u32,
+    chunk: Vec<f32>,
+    cursor: usize,
+    /// Waiting for the buffer to fill up to its target again after running dry
+    priming: bool,
+    gain: f32,
+}
+
+impl ZoneSource {
+    fn refill(&mut self) {
+        self.chunk.clear();
+        self.cursor = 0;
+        let wanted = CHUNK_FRAMES * CHANNELS as usize;
+        let mut samples = self.buffer.samples.lock().unwrap();
+        let target = self.buffer.target_frames.load(Ordering::Relaxed) as usize;
+        if self.priming && samples.len() >= (target + CHUNK_FRAMES) * CHANNELS as usize {
+            self.priming = false;
+        }
+        if !self.priming && samples.len() >= wanted {
+            self.chunk.extend(samples.drain(..wanted));
+        } else {
+            self.priming = true;
+            self.chunk.resize(wanted, 0.0);
+        }
+    }
+}
+
+impl Iterator for ZoneSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.cursor >= self.chunk.len() {
+            self.refill();
+        }
+        if self.cursor.is_multiple_of(CHANNELS as usize) {
+            let target = f32::from_bits(self.buffer.volume.load(Ordering::Relaxed));
+            self.gain += (target - self.gain) / VOLUME_SMOOTHING_FRAMES;
+        }
+        let sample = self.chunk[self.cursor] * self.gain;
+        self.cursor += 1;
+        Some(sample)
+    }
+}
+
+impl Source for ZoneSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        CHANNELS
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// A device the main output is mirrored to
+pub(super) struct Zone {
+    config: ZoneConfig,
+    buffer: Arc<ZoneBuffer>,
+    /// Microseconds from a sample leaving the zone's buffer to it being heard
+    latency: Arc<AtomicU32>,
+    // Must stay alive for as long as the zone should be heard.
+    _output: Output,
+}
+
+impl AudioEngine {
+    /// Play what the main output plays on these devices too, for whole-home audio
+    ///
+    /// Each zone has its own volume, from 0 to 1, after the main volume, and a delay
+    /// to line it up with speakers further away; output latency is already made up
+    /// for. Zones already playing keep playing; an empty list stops mirroring. A zone
+    /// whose device fails or disappears is dropped with `ZoneFailed`.
+    pub fn set_zones(&self, zones: Vec<ZoneConfig>) -> Result<(), TunesError> {
+        for zone in &zones {
+            if find_device(&zone.device_id).is_none() {
+                return Err(TunesError::device(format!(
+                    "no output device named {:?}",
+                    zone.device_id
+                )));
+            }
+        }
+        self.send(Command::SetZones(zones));
+        Ok(())
+    }
+
+    /// Zones the main output is mirrored to
+    pub fn get_zones(&self) -> Vec<ZoneConfig> {
+        self.shared.zones.lock().unwrap().clone()
+    }
+}
+
+impl EngineThread {
+    pub(super) fn set_zones(&mut self, configs: Vec<ZoneConfig>) {
+        let mut old = std::mem::take(&mut self.zones);
+        for config in configs {
+            if self
+                .zones
+                .iter()
+                .any(|z| z.config.device_id == config.device_id)
+            {
+                continue;
+            }
+            let existing = old
+                .iter()
+                .position(|zone| zone.config.device_id == config.device_id);
+            match existing {
+                Some(index) => {
+                    let zone = old.swap_remove(index);
+                    zone.buffer
+                        .volume
+                        .store(config.volume.clamp(0.0, 1.0).to_bits(), Ordering::Relaxed);
+                    self.zones.push(Zone { config, ..zone });
+                }
+                None => match self.open_zone(&config) {
+                    Ok(zone) => self.zones.push(zone),
+                    Err(e) => self.zone_failed(config.device_id, format!("{e:#}")),
+                },
+            }
+        }
+        self.sync_zones();
+    }
+
+    fn open_zone(&self, config: &ZoneConfig) -> anyhow::Result<Zone> {
+        let device = find_device(&config.device_id)
+            .ok_or_else(|| anyhow::anyhow!("no output device named {:?}", config.device_id))?;
+        let stream_config = match negotiate(&device, self.sample_rate) {
+            Some(stream_config) => stream_config,
+            None => device.default_output_config()?,
+        };
+        let volume = config.volume.clamp(0.0, 1.0);
+        let buffer = Arc::new(ZoneBuffer::new(self.sample_rate, volume));
+        let source = ZoneSource {
+            buffer: buffer.clone(),
+            sample_rate: self.sample_rate,
+            chunk: Vec::new(),
+            cursor: 0,
+            priming: true,
+            gain: volume,
+        };
+        let latency = Arc::new(AtomicU32::new(0));
+        let output = Output::open(
+            &device,
+            &stream_config,
+            self.buffer_frames,
+            source,
+            latency.clone(),
+            Arc::default(),
+        )?;
+        log::info!("mirroring output to {:?}", config.device_id);
+        Ok(Zone {
+            config: config.clone(),
+            buffer,
+            latency,
+            _output: output,
+        })
+    }
+
+    fn zone_failed(&self, device_id: String, message: String) {
+        log::warn!("zone {device_id:?} failed: {message}");
+        self.shared
+            .events
+            .emit(AudioEvent::ZoneFailed { device_id, message });
+    }
+
+    /// Hand the player the zones' buffers and publish the zones
+    fn sync_zones(&mut self) {
+        self.update_zone_delays();
+        let buffers = self.zones.iter().map(|zone| zone.buffer.clone()).collect();
+        self.player.lock().unwrap().zones = buffers;
+        let zones: Vec<ZoneConfig> = self.zones.iter().map(|z| z.config.clone()).collect();
+        *self.shared.zones.lock().unwrap() = zones.clone();
+        self.shared.events.emit(AudioEvent::ZonesChanged { zones });
+    }
+
+    /// Keep each zone's buffer at the main output's latency less its own, plus its delay
+    fn update_zone_delays(&self) {
+        let main_us = self.shared.output_latency.load(Ordering::Relaxed) as i64;
+        for zone in &self.zones {
+            let zone_us = zone.latency.load(Ordering::Relaxed) as i64;
+            let delay_us = (main_us - zone_us + zone.config.delay_ms as i64 * 1000).max(0);
+            let frames = delay_us * self.sample_rate as i64 / 1_000_000;
+            zone.buffer
+                .target_frames
+                .store(frames as u32, Ordering::Relaxed);
+        }
+    }
+
+    pub(super) fn check_zones(&mut self) {
+        if self.zones.is_empty() {
+            return;
+        }
+        self.update_zone_delays();
+        if self.last_zone_check.elapsed() < DEVICE_CHECK_INTERVAL {
+            return;
+        }
+        self.last_zone_check = Instant::now();
+        let (present, gone): (Vec<Zone>, Vec<Zone>) = std::mem::take(&mut self.zones)
+            .into_iter()
+            .partition(|zone| find_device(&zone.config.device_id).is_some());
+        self.zones = present;
+        if gone.is_empty() {
+            return;
+        }
+        for zone in gone {
+            self.zone_failed(zone.config.device_id, "device disconnected".into());
+        }
+        self.sync_zones();
+    }
+
+    /// Open every zone's device afresh, as after a suspend; zones that fail are dropped
+    pub(super) fn reopen_zones(&mut self) {
+        if self.zones.is_empty() {
+            return;
+        }
+        let configs = std::mem::take(&mut self.zones)
+            .into_iter()
+            .map(|zone| zone.config)
+            .collect();
+        self.set_zones(configs);
+    }
+}
@@ -0,0 +1,96 @@
+use rodio::cpal;
+use symphonia::core::errors::Error as SymphoniaError;
+
+/// Error returned across the FFI boundary, sorted into what the app can tell the user
+///
+/// Messages carry the whole chain of context, e.g. "failed to fetch <url>: timed out".
+#[derive(Clone, Debug, thiserror::Error, serde::Serialize, serde::Deserialize)]
+pub enum TunesError {
+    /// A file or directory couldn't be read or written
+    #[error("{message}")]
+    Io { message: String },
+    /// Audio, tags or another format couldn't be understood
+    #[error("{message}")]
+    Decode { message: String },
+    /// The audio output or an output device failed
+    #[error("{message}")]
+    Device { message: String },
+    #[error("{message}")]
+    Database { message: String },
+    /// A server couldn't be reached or refused the request
+    #[error("{message}")]
+    Network { message: String },
+    /// The call doesn't fit the current state, like an unknown id or no open library
+    #[error("{message}")]
+    InvalidState { message: String },
+}
+
+impl TunesError {
+    pub(crate) fn device(message: impl Into<String>) -> Self {
+        TunesError::Device {
+            message: message.into(),
+        }
+    }
+
+    pub(crate) fn invalid_state(message: impl Into<String>) -> Self {
+        TunesError::InvalidState {
+            message: message.into(),
+        }
+    }
+
+    /// The same kind of error with another message
+    fn with_message(self, message: String) -> Self {
+        match self {
+            TunesError::Io { .. } => TunesError::Io { message },
+            TunesError::Decode { .. } => TunesError::Decode { message },
+            TunesError::Device { .. } => TunesError::Device { message },
+            TunesError::Database { .. } => TunesError::Database { message },
+            TunesError::Network { .. } => TunesError::Network { message },
+            TunesError::InvalidState { .. } => TunesError::InvalidState { message },
+        }
+    }
+}
+
+/// The kind of error `cause` is, with an empty message, if it is one we recognise
+fn kind_of(cause: &(dyn std::error::Error + 'static)) -> Option<TunesError> {
+    let message = String::new();
+    if let Some(e) = cause.downcast_ref::<TunesError>() {
+        Some(e.clone())
+    } else if cause.is::<rusqlite::Error>() {
+        Some(TunesError::Database { message })
+    } else if cause.is::<ureq::Error>() {
+        Some(TunesError::Network { message })
+    } else if cause.is::<SymphoniaError>()
+        || cause.is::<lofty::error::LoftyError>()
+        || cause.is::<id3::Error>()
+        || cause.is::<serde_json::Error>()
+    {
+        Some(TunesError::Decode { message })
+    } else if cause.is::<rodio::StreamError>()
+        || cause.is::<rodio::PlayError>()
+        || cause.is::<cpal::DevicesError>()
+        || cause.is::<cpal::BuildStreamError>()
+        || cause.is::<cpal::PlayStreamError>()
+    {
+        Some(TunesError::Device { message })
+    } else if cause.is::<std::io::Error>() || cause.is::<hound::Error>() {
+        Some(TunesError::Io { message })
+    } else {
+        None
+    }
+}
+
+impl From<anyhow::Error> for TunesError {
+    /// Sorted by the outermost recognised error in the chain, so a network error caused
+    /// by an I/O error is still a network error
+    fn from(error: anyhow::Error) -> Self {
+        let message = format!("{error:#}");
+        error
+            .chain()
+            .find_map(kind_of)
+            .unwrap_or(TunesError::InvalidState {
+                message: String::new(),
+            })
+            .with_message(message)
+    }
+}
@@ -0,0 +1,24 @@
+use std::sync::Mutex;
+
+use crate::{AudioEvent, StreamSink};
+
+/// Fan-out of engine events to every subscribed stream
+///
+/// Subscribers whose consumer has gone away are dropped on the next emit.
+#[derive(Default)]
+pub(crate) struct EventBus {
+    sinks: Mutex<Vec<StreamSink<AudioEvent>>>,
+}
+
+impl EventBus {
+    pub fn subscribe(&self, sink: StreamSink<AudioEvent>) {
+        self.sinks.lock().unwrap().push(sink);
+    }
+
+    pub fn emit(&self, event: AudioEvent) {
+        self.sinks
+            .lock()
+            .unwrap()
+            .retain(|sink| sink.add(event.clone()).is_ok());
+    }
+}
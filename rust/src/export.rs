@@ -0,0 +1,353 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+
+use anyhow::Context;
+use lofty::prelude::*;
+
+use crate::jobs::{self, Job};
+use crate::transcode::{self, Span};
+use crate::{
+    cue, decoder, library, metadata, ExportEvent, ExportOptions, JobKind, Song, StreamSink,
+    TranscodeFormat,
+};
+
+/// Layout used when `ExportOptions::pattern` is empty
+pub(crate) const DEFAULT_PATTERN: &str = "{artist}/{album}/{track} {title}";
+
+/// Longest file or folder name written, which FAT32 drives and car stereos cope with
+const MAX_NAME_CHARS: usize = 100;
+
+/// Emit `Progress` whenever this much more of the export is done
+const PROGRESS_STEP: f32 = 0.01;
+
+/// Queue exporting playlist `id` into `dest` as a background job, returning its id for
+/// `jobs::cancel`
+pub(crate) fn start(
+    id: i64,
+    dest: PathBuf,
+    options: ExportOptions,
+    sink: StreamSink<ExportEvent>,
+) -> u32 {
+    jobs::submit(JobKind::Export, move |job| {
+        let (event, result) = match export(id, &dest, &options, &sink, job) {
+            _ if job.is_cancelled() => (ExportEvent::Cancelled, Ok(())),
+            Ok(finished) => (finished, Ok(())),
+            Err(e) => {
+                let message = format!("{e:#}");
+                (ExportEvent::Failed { message }, Err(e))
+            }
+        };
+        let _ = sink.add(event);
+        result
+    })
+}
+
+/// `text` made safe as a file or folder name on any file system
+pub(crate) fn safe_name(text: &str) -> String {
+    let name: String = text
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .take(MAX_NAME_CHARS)
+        .collect();
+    // Windows drops trailing dots and spaces, which would make names collide.
+    name.trim().trim_end_matches('.').trim_end().to_string()
+}
+
+/// Reports progress through the sink, stopping the export if it's cancelled or the
+/// listener went away
+struct Progress<'a> {
+    sink: &'a StreamSink<ExportEvent>,
+    job: &'a Job,
+    total: u32,
+    reported: f32,
+}
+
+impl Progress<'_> {
+    /// Report `done` tracks exported and `current` of the next; false once the export
+    /// should stop
+    fn report(&mut self, done: u32, current: f32) -> bool {
+        let fraction = (done as f32 + current) / self.total.max(1) as f32;
+        if current == 0.0 || fraction - self.reported >= PROGRESS_STEP {
+            self.reported = fraction;
+            let event = ExportEvent::Progress {
+                done,
+                total: self.total,
+                fraction,
+            };
+            if self.sink.add(event).is_err() {
+                self.job.cancelled().store(true, Ordering::Relaxed);
+            }
+            self.job.progress(done as u64, Some(self.total as u64));
+        }
+        !self.job.is_cancelled()
+    }
+}
+
+fn export(
+    id: i64,
+    dest: &Path,
+    options: &ExportOptions,
+    sink: &StreamSink<ExportEvent>,
+    job: &Job,
+) -> anyhow::Result<ExportEvent> {
+    if let Some(format) = options.format {
+        transcode::check_supported(format)?;
+    }
+    let (playlist, songs) = library::with_library(|lib| {
+        let playlist = lib
+            .get_playlists()?
+            .into_iter()
+            .find(|p| p.id == id)
+            .with_context(|| format!("no playlist with id {id}"))?;
+        Ok((playlist, lib.get_playlist_songs(id)?))
+    })?;
+    fs::create_dir_all(dest).with_context(|| format!("failed to create {}", dest.display()))?;
+    let pattern = match options.pattern.trim() {
+        "" => DEFAULT_PATTERN,
+        pattern => pattern,
+    };
+
+    let mut progress = Progress {
+        sink,
+        job,
+        total: songs.len() as u32,
+        reported: 0.0,
+    };
+    let mut taken = HashSet::new();
+    let mut exported = Vec::new();
+    let (mut written, mut skipped, mut failed) = (0, 0, 0);
+    for (index, song) in songs.iter().enumerate() {
+        let done = index as u32;
+        if !progress.report(done, 0.0) {
+            anyhow::bail!("export cancelled");
+        }
+        let format = target_format(song, options.format);
+        let relative = relative_path(pattern, song, index, format);
+        let target = unique_path(dest.join(relative), &mut taken);
+        let result = match up_to_date(song, &target, format, options) {
+            true => {
+                skipped += 1;
+                Ok(())
+            }
+            false => export_song(song, &target, format, options, |current| {
+                progress.report(done, current)
+            })
+            .map(|()| written += 1),
+        };
+        if job.is_cancelled() {
+            anyhow::bail!("export cancelled");
+        }
+        match result {
+            Ok(()) => exported.push(Song {
+                file_path: target.to_string_lossy().into_owned(),
+                start_offset: 0.0,
+                end_offset: None,
+                ..song.clone()
+            }),
+            Err(e) => {
+                let message = format!("{e:#}");
+                log::warn!("couldn't export {}: {message}", song.file_path);
+                failed += 1;
+                let event = ExportEvent::TrackFailed {
+                    song: song.clone(),
+                    message,
+                };
+                if sink.add(event).is_err() {
+                    job.cancelled().store(true, Ordering::Relaxed);
+                    anyhow::bail!("export listener went away");
+                }
+            }
+        }
+    }
+
+    let name = match safe_name(&playlist.name) {
+        name if name.is_empty() => "playlist".to_string(),
+        name => name,
+    };
+    let playlist_path = dest.join(format!("{name}.m3u8"));
+    fs::write(&playlist_path, library::write_m3u(dest, &exported))
+        .with_context(|| format!("failed to write {}", playlist_path.display()))?;
+    log::info!(
+        "exported playlist {id} to {}: {written} written, {skipped} up to date, {failed} failed",
+        dest.display()
+    );
+    Ok(ExportEvent::Finished {
+        exported: written,
+        skipped,
+        failed,
+        playlist_path: playlist_path.to_string_lossy().into_owned(),
+    })
+}
+
+/// What `song` is converted to, or `None` to copy it as it is
+///
+/// Files already in the requested format are copied rather than encoded again. CUE
+/// sheet tracks have to be cut out of their image, losslessly unless a format is asked for.
+fn target_format(song: &Song, requested: Option<TranscodeFormat>) -> Option<TranscodeFormat> {
+    if cue::is_track(song) {
+        return Some(requested.unwrap_or(TranscodeFormat::Flac));
+    }
+    let requested = requested?;
+    let codec = decoder::probe(Path::new(&song.file_path)).map(|info| info.codec);
+    match (requested, codec.as_deref()) {
+        (TranscodeFormat::Mp3, Ok("mp3"))
+        | (TranscodeFormat::Flac, Ok("flac"))
+        | (TranscodeFormat::Opus, Ok("opus"))
+        | (TranscodeFormat::Aac, Ok("aac")) => None,
+        _ => Some(requested),
+    }
+}
+
+/// Where `song` goes below the destination, following `pattern`
+fn relative_path(
+    pattern: &str,
+    song: &Song,
+    index: usize,
+    format: Option<TranscodeFormat>,
+) -> PathBuf {
+    let position = format!("{:02}", index + 1);
+    let track = match cue::is_track(song) {
+        true => None,
+        false => song
+            .track_number
+            .or_else(|| track_number(Path::new(&song.file_path))),
+    };
+    let track = track.map_or(position.clone(), |n| format!("{n:02}"));
+    let fields = [
+        ("{artist}", song.artist.as_str()),
+        ("{album}", song.album.as_str()),
+        ("{title}", song.title.as_str()),
+        ("{track}", track.as_str()),
+        ("{index}", position.as_str()),
+    ];
+    let extension = match format {
+        Some(format) => transcode::extension(format).to_string(),
+        None => Path::new(&song.file_path)
+            .extension()
+            .map(|e| e.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+    };
+    fill_pattern(pattern, &fields, &position, &extension)
+}
+
+/// `pattern` with its placeholders replaced by `fields`, every name made safe, and
+/// `extension` added; `fallback` names the file if nothing else is left
+pub(crate) fn fill_pattern(
+    pattern: &str,
+    fields: &[(&str, &str)],
+    fallback: &str,
+    extension: &str,
+) -> PathBuf {
+    let mut path = PathBuf::new();
+    for component in pattern.split(['/', '\\']) {
+        let mut name = component.to_string();
+        for (placeholder, value) in fields {
+            name = name.replace(placeholder, &safe_name(value));
+        }
+        let name = safe_name(&name);
+        if !name.is_empty() && name != ".." {
+            path.push(name);
+        }
+    }
+    if path.as_os_str().is_empty() {
+        path.push(fallback);
+    }
+    let mut name = path.into_os_string();
+    if !extension.is_empty() {
+        name.push(".");
+        name.push(extension);
+    }
+    PathBuf::from(name)
+}
+
+/// Track number from the file's tags, if it has one
+pub(crate) fn track_number(path: &Path) -> Option<u32> {
+    let tagged = metadata::read_tagged(path).ok()?;
+    let tag = tagged.primary_tag().or_else(|| tagged.first_tag())?;
+    tag.track()
+}
+
+/// `path`, or "<path> (2)" and so on if an earlier track of this export already took it
+fn unique_path(path: PathBuf, taken: &mut HashSet<PathBuf>) -> PathBuf {
+    let mut candidate = path.clone();
+    let mut n = 2;
+    while !taken.insert(candidate.clone()) {
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let name = match path.extension() {
+            Some(extension) => format!("{stem} ({n}).{}", extension.to_string_lossy()),
+            None => format!("{stem} ({n})"),
+        };
+        candidate = path.with_file_name(name);
+        n += 1;
+    }
+    candidate
+}
+
+/// Whether `skip_existing` can leave `target` as it is: a copy of the same size or an
+/// earlier conversion
+fn up_to_date(
+    song: &Song,
+    target: &Path,
+    format: Option<TranscodeFormat>,
+    options: &ExportOptions,
+) -> bool {
+    if !options.skip_existing {
+        return false;
+    }
+    let Ok(existing) = fs::metadata(target) else {
+        return false;
+    };
+    match format {
+        Some(_) => true,
+        None => fs::metadata(&song.file_path).is_ok_and(|source| source.len() == existing.len()),
+    }
+}
+
+fn export_song(
+    song: &Song,
+    target: &Path,
+    format: Option<TranscodeFormat>,
+    options: &ExportOptions,
+    progress: impl FnMut(f32) -> bool,
+) -> anyhow::Result<()> {
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create {}", parent.display()))?;
+    }
+    let source = Path::new(&song.file_path);
+    let Some(format) = format else {
+        let partial = transcode::partial_path(target);
+        if let Err(e) = fs::copy(source, &partial) {
+            let _ = fs::remove_file(&partial);
+            return Err(e).with_context(|| format!("failed to copy {}", source.display()));
+        }
+        fs::rename(&partial, target)?;
+        return Ok(());
+    };
+
+    let is_track = cue::is_track(song);
+    let mut transcode_options = options.transcode.clone();
+    // A CUE track's tags are in its sheet, not its image.
+    transcode_options.copy_tags &= !is_track;
+    let span = Span {
+        start: song.start_offset,
+        end: song.end_offset,
+    };
+    transcode::convert(source, span, target, format, &transcode_options, progress)?;
+    if is_track && options.transcode.copy_tags {
+        let tagged = Song {
+            file_path: target.to_string_lossy().into_owned(),
+            start_offset: 0.0,
+            end_offset: None,
+            ..song.clone()
+        };
+        metadata::write_song(&tagged)?;
+    }
+    Ok(())
+}
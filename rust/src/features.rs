@@ -0,0 +1,151 @@
+use std::sync::Arc;
+
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+
+use crate::tempo::ENVELOPE_RATE;
+use crate::TrackFeatures;
+
+/// Windows quieter than this, in dBFS, are left out as silence
+const SILENCE_DB: f32 = -60.0;
+
+/// Loudness, in dBFS RMS, mapped to no energy and to full energy
+const QUIET_DB: f32 = -30.0;
+const LOUD_DB: f32 = -6.0;
+
+/// Onsets per second counted as fully busy
+const BUSY_ONSETS: f32 = 4.0;
+
+/// Spectral centroids, in Hz, mapped to dull and to bright
+const DULL_HZ: f32 = 500.0;
+const BRIGHT_HZ: f32 = 3000.0;
+
+/// Dynamic range, in dB, of heavily compressed and of very dynamic music
+const COMPRESSED_DB: f32 = 6.0;
+const DYNAMIC_DB: f32 = 30.0;
+
+/// Tempo most dance music is around, and how far either side of it, in octaves,
+/// still counts as danceable
+const DANCE_BPM: f32 = 120.0;
+const DANCE_OCTAVES: f32 = 0.4;
+
+/// Level and spectral centroid of decoded audio, gathered a window at a time
+pub(crate) struct FeatureMeter {
+    fft: Arc<dyn Fft<f32>>,
+    window: Vec<f32>,
+    buffer: Vec<f32>,
+    scratch: Vec<Complex<f32>>,
+    bin_hz: f32,
+    /// RMS level of every window that wasn't silent
+    levels: Vec<f32>,
+    /// Sum of the spectral centroids of those windows, weighted by level
+    centroid_sum: f32,
+}
+
+impl FeatureMeter {
+    pub fn new(sample_rate: u32) -> Self {
+        // Around 50 ms of audio per window, whatever the rate
+        let size = (sample_rate as usize / 20).next_power_of_two();
+        let window = (0..size)
+            .map(|i| {
+                let phase = 2.0 * std::f32::consts::PI * i as f32 / (size - 1) as f32;
+                0.5 - 0.5 * phase.cos()
+            })
+            .collect();
+        FeatureMeter {
+            fft: FftPlanner::new().plan_fft_forward(size),
+            window,
+            buffer: Vec::with_capacity(size),
+            scratch: vec![Complex::default(); size],
+            bin_hz: sample_rate as f32 / size as f32,
+            levels: Vec::new(),
+            centroid_sum: 0.0,
+        }
+    }
+
+    /// Feed one mono sample
+    pub fn push(&mut self, sample: f32) {
+        self.buffer.push(sample);
+        if self.buffer.len() < self.window.len() {
+            return;
+        }
+        let power = self.buffer.iter().map(|s| s * s).sum::<f32>() / self.buffer.len() as f32;
+        let level = power.sqrt();
+        if to_db(level) > SILENCE_DB {
+            for ((bin, sample), weight) in
+                self.scratch.iter_mut().zip(&self.buffer).zip(&self.window)
+            {
+                *bin = Complex::new(sample * weight, 0.0);
+            }
+            self.fft.process(&mut self.scratch);
+            let (mut weighted, mut total) = (0.0, 0.0);
+            for (i, bin) in self.scratch[1..self.scratch.len() / 2].iter().enumerate() {
+                let magnitude = bin.norm();
+                weighted += (i + 1) as f32 * self.bin_hz * magnitude;
+                total += magnitude;
+            }
+            if total > 0.0 {
+                self.centroid_sum += level * weighted / total;
+                self.levels.push(level);
+            }
+        }
+        self.buffer.clear();
+    }
+
+    /// Features of everything fed, given the tempo found and the onset envelope the
+    /// audio gave; `None` for silence
+    pub fn features(&self, bpm: Option<f32>, envelope: &[f32]) -> Option<TrackFeatures> {
+        if self.levels.is_empty() {
+            return None;
+        }
+        let count = self.levels.len() as f32;
+        let loudness_db = to_db((self.levels.iter().map(|l| l * l).sum::<f32>() / count).sqrt());
+        let brightness_hz = self.centroid_sum / self.levels.iter().sum::<f32>();
+        let mut levels_db: Vec<f32> = self.levels.iter().map(|&l| to_db(l)).collect();
+        levels_db.sort_by(f32::total_cmp);
+        let percentile = |p: f32| levels_db[((levels_db.len() - 1) as f32 * p) as usize];
+        let dynamic_range_db = percentile(0.95) - percentile(0.1);
+
+        let busy = scale(onset_density(envelope), 0.0, BUSY_ONSETS);
+        let energy = 0.45 * scale(loudness_db, QUIET_DB, LOUD_DB)
+            + 0.25 * busy
+            + 0.15 * scale(brightness_hz, DULL_HZ, BRIGHT_HZ)
+            + 0.15 * (1.0 - scale(dynamic_range_db, COMPRESSED_DB, DYNAMIC_DB));
+        let steady = bpm.map_or(0.0, |bpm| {
+            let octaves = (bpm / DANCE_BPM).log2() / DANCE_OCTAVES;
+            (-0.5 * octaves * octaves).exp()
+        });
+        Some(TrackFeatures {
+            energy,
+            danceability: 0.5 * steady + 0.5 * busy,
+            brightness_hz,
+            dynamic_range_db,
+        })
+    }
+}
+
+/// Onsets per second in an onset envelope: peaks standing out from the rest by more
+/// than its spread
+fn onset_density(envelope: &[f32]) -> f32 {
+    if envelope.len() < 3 {
+        return 0.0;
+    }
+    let count = envelope.len() as f32;
+    let mean = envelope.iter().sum::<f32>() / count;
+    let spread = (envelope.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / count).sqrt();
+    let threshold = mean + spread;
+    let onsets = envelope
+        .windows(3)
+        .filter(|w| w[1] > threshold && w[1] >= w[0] && w[1] > w[2])
+        .count();
+    onsets as f32 * ENVELOPE_RATE as f32 / count
+}
+
+fn to_db(level: f32) -> f32 {
+    20.0 * level.max(1e-9).log10()
+}
+
+/// Where `value` lies between `low` and `high`, from 0 to 1
+fn scale(value: f32, low: f32, high: f32) -> f32 {
+    ((value - low) / (high - low)).clamp(0.0, 1.0)
+}
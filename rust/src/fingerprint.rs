@@ -0,0 +1,423 @@
+use std::f64::consts::PI;
+use std::path::Path;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use rodio::Source;
+use rustfft::num_complex::Complex;
+use rustfft::FftPlanner;
+
+use crate::decoder::SymphoniaSource;
+
+/// Rate audio is resampled to before analysis
+const SAMPLE_RATE: u32 = 11025;
+const FRAME_SIZE: usize = 4096;
+/// Frames overlap by two thirds
+const HOP: usize = FRAME_SIZE / 3;
+
+/// Only the start of a file is fingerprinted, as AcoustID expects
+const MAX_SECONDS: usize = 120;
+
+const MIN_FREQ: f64 = 28.0;
+const MAX_FREQ: f64 = 3520.0;
+const BANDS: usize = 12;
+
+/// Smoothing applied across consecutive chroma vectors
+const CHROMA_FILTER: [f64; 5] = [0.25, 0.75, 1.0, 0.75, 0.25];
+
+/// Chroma vectors with a smaller norm are treated as silence
+const MIN_CHROMA_NORM: f64 = 0.01;
+
+/// Chromaprint's algorithm number for the classifiers below, stored in encoded prints
+const ALGORITHM: u8 = 1;
+
+/// Widest classifier filter, in chroma vectors
+const MAX_FILTER_WIDTH: usize = 16;
+
+/// Rectangle comparisons over the chroma image
+#[derive(Clone, Copy)]
+enum Filter {
+    /// Total of the whole rectangle
+    Whole,
+    /// Top half against bottom half
+    Halves,
+    /// Later half against earlier half
+    Sides,
+    /// Diagonal quarters against each other
+    Quarters,
+    /// Middle third of the bands against the outer two
+    BandThirds,
+    /// Middle third in time against the outer two
+    TimeThirds,
+}
+
+/// A filter over `height` bands from `band` and `width` vectors, quantized to two bits
+struct Classifier {
+    filter: Filter,
+    band: usize,
+    height: usize,
+    width: usize,
+    thresholds: [f64; 3],
+}
+
+const fn classifier(
+    filter: Filter,
+    band: usize,
+    height: usize,
+    width: usize,
+    thresholds: [f64; 3],
+) -> Classifier {
+    Classifier {
+        filter,
+        band,
+        height,
+        width,
+        thresholds,
+    }
+}
+
+/// Chromaprint's default classifiers, one per two bits of each sub-fingerprint
+const CLASSIFIERS: [Classifier; 16] = [
+    classifier(Filter::Whole, 4, 3, 15, [1.98215, 2.35817, 2.63523]),
+    classifier(
+        Filter::BandThirds,
+        4,
+        6,
+        15,
+        [-1.03809, -0.651211, -0.282167],
+    ),
+    classifier(Filter::Halves, 0, 4, 16, [-0.298702, 0.119262, 0.558497]),
+    classifier(Filter::Quarters, 8, 2, 12, [-0.105439, 0.0153946, 0.135898]),
+    classifier(Filter::Quarters, 4, 4, 8, [-0.142891, 0.0258736, 0.200632]),
+    classifier(
+        Filter::BandThirds,
+        0,
+        3,
+        5,
+        [-0.826319, -0.590612, -0.368214],
+    ),
+    classifier(Filter::Halves, 2, 2, 9, [-0.557409, -0.233035, 0.0534525]),
+    classifier(Filter::Sides, 7, 3, 4, [-0.0646826, 0.00620476, 0.0784847]),
+    classifier(Filter::Sides, 6, 2, 16, [-0.192387, -0.029699, 0.215855]),
+    classifier(Filter::Sides, 1, 3, 2, [-0.0397818, -0.00568076, 0.0292026]),
+    classifier(
+        Filter::TimeThirds,
+        10,
+        1,
+        15,
+        [-0.53823, -0.369934, -0.190235],
+    ),
+    classifier(Filter::Quarters, 6, 2, 10, [-0.124877, 0.0296483, 0.139239]),
+    classifier(Filter::Sides, 1, 1, 14, [-0.101475, 0.0225617, 0.231971]),
+    classifier(
+        Filter::Quarters,
+        5,
+        6,
+        4,
+        [-0.0799915, -0.00729616, 0.063262],
+    ),
+    classifier(Filter::Halves, 9, 2, 12, [-0.272556, 0.019424, 0.302559]),
+    classifier(
+        Filter::Quarters,
+        4,
+        2,
+        14,
+        [-0.164292, -0.0321188, 0.0846339],
+    ),
+];
+
+/// A Chromaprint-compatible fingerprint of the start of a file
+pub(crate) struct Fingerprint {
+    /// One 32-bit sub-fingerprint per chroma vector after the first few
+    pub raw: Vec<u32>,
+    /// Duration of the whole file in seconds
+    pub duration: f64,
+}
+
+impl Fingerprint {
+    pub fn compute(path: &Path) -> anyhow::Result<Self> {
+        let (samples, duration) = decode(path)?;
+        let raw = sub_fingerprints(&image(&samples));
+        anyhow::ensure!(
+            !raw.is_empty(),
+            "{} is too short to identify",
+            path.display()
+        );
+        Ok(Fingerprint { raw, duration })
+    }
+
+    /// The compressed, URL-safe base64 form AcoustID takes
+    pub fn encode(&self) -> String {
+        // Each sub-fingerprint is XORed with the previous one, and the positions of its
+        // set bits are stored as gaps: three bits each, with larger gaps continued in an
+        // exception list of five-bit values.
+        let mut gaps = Vec::new();
+        let mut previous = 0;
+        for &value in &self.raw {
+            let mut x = value ^ previous;
+            previous = value;
+            let (mut bit, mut last_bit) = (1, 0);
+            while x != 0 {
+                if x & 1 != 0 {
+                    gaps.push(bit - last_bit);
+                    last_bit = bit;
+                }
+                x >>= 1;
+                bit += 1;
+            }
+            gaps.push(0);
+        }
+
+        let len = self.raw.len() as u32;
+        let mut bytes = vec![ALGORITHM, (len >> 16) as u8, (len >> 8) as u8, len as u8];
+        let mut normal = BitWriter::default();
+        for &gap in &gaps {
+            normal.write(gap.min(7), 3);
+        }
+        bytes.extend(normal.finish());
+        let mut exceptions = BitWriter::default();
+        for &gap in gaps.iter().filter(|&&gap| gap >= 7) {
+            exceptions.write(gap - 7, 5);
+        }
+        bytes.extend(exceptions.finish());
+        URL_SAFE_NO_PAD.encode(bytes)
+    }
+}
+
+/// Packs values least significant bit first
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u32,
+    buffered: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, bits: u32) {
+        self.buffer |= value << self.buffered;
+        self.buffered += bits;
+        while self.buffered >= 8 {
+            self.bytes.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.buffered -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.buffered > 0 {
+            self.bytes.push(self.buffer as u8);
+        }
+        self.bytes
+    }
+}
+
+/// The first `MAX_SECONDS` of a file as mono samples at `SAMPLE_RATE`, plus the
+/// duration of the whole file
+fn decode(path: &Path) -> anyhow::Result<(Vec<f32>, f64)> {
+    let source = SymphoniaSource::open(path)?;
+    let channels = source.channels().max(1) as usize;
+    let rate = source.sample_rate();
+    let known_duration = source.total_duration().map(|d| d.as_secs_f64());
+    let max_frames = MAX_SECONDS * rate as usize;
+
+    let mut mono = Vec::with_capacity(max_frames);
+    let (mut sum, mut count, mut frames) = (0.0, 0, 0usize);
+    for sample in source {
+        sum += sample;
+        count += 1;
+        if count < channels {
+            continue;
+        }
+        if mono.len() < max_frames {
+            mono.push(sum / channels as f32);
+        } else if known_duration.is_some() {
+            break;
+        }
+        (sum, count) = (0.0, 0);
+        frames += 1;
+    }
+    let duration = known_duration.unwrap_or(frames as f64 / rate as f64);
+    Ok((resample(&mono, rate, SAMPLE_RATE), duration))
+}
+
+/// Windowed-sinc low-pass followed by linear interpolation
+fn resample(samples: &[f32], from: u32, to: u32) -> Vec<f32> {
+    if from == to {
+        return samples.to_vec();
+    }
+    let ratio = from as f64 / to as f64;
+    // Cut off a little below the new Nyquist frequency when downsampling.
+    let cutoff = 0.45 * (1.0 / ratio).min(1.0) * 2.0;
+    let half = 32isize;
+    let kernel: Vec<f64> = (-half..=half)
+        .map(|i| {
+            let x = i as f64;
+            let sinc = match i {
+                0 => cutoff,
+                _ => (PI * cutoff * x).sin() / (PI * x),
+            };
+            let blackman = 0.42
+                + 0.5 * (PI * x / half as f64).cos()
+                + 0.08 * (2.0 * PI * x / half as f64).cos();
+            sinc * blackman
+        })
+        .collect();
+    let filtered = |center: usize| -> f64 {
+        kernel
+            .iter()
+            .zip(center as isize - half..)
+            .filter(|(_, i)| *i >= 0 && (*i as usize) < samples.len())
+            .map(|(k, i)| k * samples[i as usize] as f64)
+            .sum()
+    };
+
+    let out_len = (samples.len() as f64 / ratio) as usize;
+    (0..out_len)
+        .map(|n| {
+            let position = n as f64 * ratio;
+            let index = position as usize;
+            let frac = position - index as f64;
+            let a = filtered(index);
+            let b = match index + 1 < samples.len() {
+                true => filtered(index + 1),
+                false => a,
+            };
+            (a + (b - a) * frac) as f32
+        })
+        .collect()
+}
+
+/// Filtered, normalized chroma vectors, one per FFT frame
+fn image(samples: &[f32]) -> Vec<[f64; BANDS]> {
+    let window: Vec<f64> = (0..FRAME_SIZE)
+        .map(|i| 0.54 - 0.46 * (2.0 * PI * i as f64 / (FRAME_SIZE - 1) as f64).cos())
+        .collect();
+    let bin = |freq: f64| (FRAME_SIZE as f64 * freq / SAMPLE_RATE as f64).round() as usize;
+    let (min_bin, max_bin) = (bin(MIN_FREQ).max(1), bin(MAX_FREQ).min(FRAME_SIZE / 2));
+    // Pitch class of each bin, counting octaves up from A0
+    let notes: Vec<usize> = (0..max_bin)
+        .map(|i| {
+            let freq = i as f64 * SAMPLE_RATE as f64 / FRAME_SIZE as f64;
+            let octave = (freq / 27.5).log2();
+            (BANDS as f64 * (octave - octave.floor())) as usize
+        })
+        .collect();
+
+    let fft = FftPlanner::new().plan_fft_forward(FRAME_SIZE);
+    let mut buffer = vec![Complex::default(); FRAME_SIZE];
+    let mut chroma = Vec::new();
+    let mut start = 0;
+    while start + FRAME_SIZE <= samples.len() {
+        for (i, slot) in buffer.iter_mut().enumerate() {
+            *slot = Complex::new((samples[start + i] as f64 * window[i]) as f32, 0.0);
+        }
+        fft.process(&mut buffer);
+        let mut bands = [0.0; BANDS];
+        for i in min_bin..max_bin {
+            bands[notes[i]] += buffer[i].norm_sqr() as f64;
+        }
+        chroma.push(bands);
+        start += HOP;
+    }
+
+    chroma
+        .windows(CHROMA_FILTER.len())
+        .map(|rows| {
+            let mut bands = [0.0; BANDS];
+            for (row, coefficient) in rows.iter().zip(CHROMA_FILTER) {
+                for (band, value) in bands.iter_mut().zip(row) {
+                    *band += value * coefficient;
+                }
+            }
+            let norm = bands.iter().map(|v| v * v).sum::<f64>().sqrt();
+            for band in &mut bands {
+                *band = match norm < MIN_CHROMA_NORM {
+                    true => 0.0,
+                    false => *band / norm,
+                };
+            }
+            bands
+        })
+        .collect()
+}
+
+/// Summed-area table over the chroma image, for constant-time rectangle sums
+struct Integral {
+    /// `(rows + 1) x (BANDS + 1)`, with a zero first row and column
+    sums: Vec<f64>,
+}
+
+impl Integral {
+    fn new(image: &[[f64; BANDS]]) -> Self {
+        let stride = BANDS + 1;
+        let mut sums = vec![0.0; (image.len() + 1) * stride];
+        for (r, row) in image.iter().enumerate() {
+            for (c, value) in row.iter().enumerate() {
+                sums[(r + 1) * stride + c + 1] =
+                    value + sums[r * stride + c + 1] + sums[(r + 1) * stride + c]
+                        - sums[r * stride + c];
+            }
+        }
+        Integral { sums }
+    }
+
+    /// Sum of rows `r1..r2` and bands `c1..c2`
+    fn area(&self, r1: usize, c1: usize, r2: usize, c2: usize) -> f64 {
+        let at = |r: usize, c: usize| self.sums[r * (BANDS + 1) + c];
+        at(r2, c2) - at(r1, c2) - at(r2, c1) + at(r1, c1)
+    }
+}
+
+impl Classifier {
+    /// Two-bit Gray-coded class of the rectangle starting at row `x`
+    fn classify(&self, image: &Integral, x: usize) -> u32 {
+        let (y, w, h) = (self.band, self.width, self.height);
+        let area = |x1, y1, x2, y2| image.area(x1, y1, x2, y2);
+        let (a, b) = match self.filter {
+            Filter::Whole => (area(x, y, x + w, y + h), 0.0),
+            Filter::Halves => (
+                area(x, y + h / 2, x + w, y + h),
+                area(x, y, x + w, y + h / 2),
+            ),
+            Filter::Sides => (
+                area(x + w / 2, y, x + w, y + h),
+                area(x, y, x + w / 2, y + h),
+            ),
+            Filter::Quarters => (
+                area(x, y + h / 2, x + w / 2, y + h) + area(x + w / 2, y, x + w, y + h / 2),
+                area(x, y, x + w / 2, y + h / 2) + area(x + w / 2, y + h / 2, x + w, y + h),
+            ),
+            Filter::BandThirds => (
+                area(x, y + h / 3, x + w, y + 2 * (h / 3)),
+                area(x, y, x + w, y + h / 3) + area(x, y + 2 * (h / 3), x + w, y + h),
+            ),
+            Filter::TimeThirds => (
+                area(x + w / 3, y, x + 2 * (w / 3), y + h),
+                area(x, y, x + w / 3, y + h) + area(x + 2 * (w / 3), y, x + w, y + h),
+            ),
+        };
+        let value = (1.0 + a).ln() - (1.0 + b).ln();
+        let [t0, t1, t2] = self.thresholds;
+        let class = match value {
+            v if v < t0 => 0,
+            v if v < t1 => 1,
+            v if v < t2 => 2,
+            _ => 3,
+        };
+        [0, 1, 3, 2][class]
+    }
+}
+
+fn sub_fingerprints(image: &[[f64; BANDS]]) -> Vec<u32> {
+    if image.len() < MAX_FILTER_WIDTH {
+        return Vec::new();
+    }
+    let integral = Integral::new(image);
+    (0..=image.len() - MAX_FILTER_WIDTH)
+        .map(|x| {
+            CLASSIFIERS.iter().fold(0, |bits, classifier| {
+                (bits << 2) | classifier.classify(&integral, x)
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,689 @@
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use md5::{Digest, Md5};
+
+/// Frames per FLAC frame; 4096 is what reference encoders use at CD rates
+const BLOCK_FRAMES: usize = 4096;
+
+const MAX_PARTITION_ORDER: u32 = 8;
+
+/// Where STREAMINFO starts, after "fLaC" and its block header
+const STREAMINFO_OFFSET: u64 = 8;
+
+/// Room left after STREAMINFO for tags added later, so they can be written in place
+const PADDING_BYTES: u32 = 4096;
+
+/// A minimal FLAC encoder for 16- or 24-bit audio of up to eight channels
+///
+/// Uses the fixed predictors and, for stereo, whichever decorrelation is smallest per
+/// frame, which gets most of the way to the reference encoder's default level without
+/// LPC. STREAMINFO is rewritten with the length and MD5 by `finish`.
+pub(crate) struct FlacWriter {
+    file: BufWriter<File>,
+    channels: usize,
+    sample_rate: u32,
+    bits_per_sample: u32,
+    /// Interleaved samples not yet making up a whole frame
+    pending: Vec<i32>,
+    frame_number: u32,
+    total_frames: u64,
+    frame_bytes: Option<(u32, u32)>,
+    md5: Md5,
+}
+
+impl FlacWriter {
+    pub fn create(
+        path: &Path,
+        channels: u16,
+        sample_rate: u32,
+        bits_per_sample: u32,
+    ) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            (1..=8).contains(&channels),
+            "FLAC holds one to eight channels, not {channels}"
+        );
+        anyhow::ensure!(
+            matches!(bits_per_sample, 16 | 24),
+            "FLAC is written at 16 or 24 bits, not {bits_per_sample}"
+        );
+        let mut writer = FlacWriter {
+            file: BufWriter::new(File::create(path)?),
+            channels: channels as usize,
+            sample_rate,
+            bits_per_sample,
+            pending: Vec::with_capacity(BLOCK_FRAMES * channels as usize),
+            frame_number: 0,
+            total_frames: 0,
+            frame_bytes: None,
+            md5: Md5::new(),
+        };
+        writer.file.write_all(b"fLaC")?;
+        // A STREAMINFO block, 34 bytes long
+        writer.file.write_all(&[0, 0, 0, 34])?;
+        let info = writer.stream_info([0; 16]);
+        writer.file.write_all(&info)?;
+        // Then the last metadata block, of type PADDING. Some tag writers insert their
+        // block after STREAMINFO without clearing its last-block flag, which hides the
+        // tags from readers when STREAMINFO is the only block.
+        let [_, size @ ..] = PADDING_BYTES.to_be_bytes();
+        writer.file.write_all(&[0x81])?;
+        writer.file.write_all(&size)?;
+        writer.file.write_all(&[0; PADDING_BYTES as usize])?;
+        Ok(writer)
+    }
+
+    /// Append interleaved samples at the writer's bit depth
+    pub fn write(&mut self, samples: &[i32]) -> anyhow::Result<()> {
+        let frame_len = self.channels * BLOCK_FRAMES;
+        for chunk in samples.chunks(frame_len) {
+            let room = frame_len - self.pending.len();
+            let (now, later) = chunk.split_at(room.min(chunk.len()));
+            self.pending.extend_from_slice(now);
+            if self.pending.len() == frame_len {
+                self.write_frame()?;
+            }
+            self.pending.extend_from_slice(later);
+        }
+        Ok(())
+    }
+
+    /// Write the last partial frame and fill in STREAMINFO
+    pub fn finish(mut self) -> anyhow::Result<()> {
+        if !self.pending.is_empty() {
+            self.write_frame()?;
+        }
+        let md5: [u8; 16] = self.md5.clone().finalize().into();
+        let info = self.stream_info(md5);
+        self.file.seek(SeekFrom::Start(STREAMINFO_OFFSET))?;
+        self.file.write_all(&info)?;
+        self.file.flush()?;
+        Ok(())
+    }
+
+    fn stream_info(&self, md5: [u8; 16]) -> [u8; 34] {
+        let mut bits = BitWriter::default();
+        bits.put(BLOCK_FRAMES as u64, 16);
+        bits.put(BLOCK_FRAMES as u64, 16);
+        let (min_frame, max_frame) = self.frame_bytes.unwrap_or((0, 0));
+        bits.put(min_frame as u64, 24);
+        bits.put(max_frame as u64, 24);
+        bits.put(self.sample_rate as u64, 20);
+        bits.put(self.channels as u64 - 1, 3);
+        bits.put(self.bits_per_sample as u64 - 1, 5);
+        bits.put(self.total_frames, 36);
+        for byte in md5 {
+            bits.put(byte as u64, 8);
+        }
+        bits.bytes.try_into().expect("STREAMINFO is 34 bytes")
+    }
+
+    fn write_frame(&mut self) -> anyhow::Result<()> {
+        let frames = self.pending.len() / self.channels;
+        let bytes_per_sample = self.bits_per_sample as usize / 8;
+        let mut channels = vec![Vec::with_capacity(frames); self.channels];
+        for frame in self.pending.chunks_exact(self.channels) {
+            for (channel, &sample) in channels.iter_mut().zip(frame) {
+                self.md5.update(&sample.to_le_bytes()[..bytes_per_sample]);
+                channel.push(sample as i64);
+            }
+        }
+        self.pending.clear();
+
+        let bps = self.bits_per_sample;
+        let coded: Vec<Coded> = channels
+            .into_iter()
+            .map(|samples| Coded::new(samples, bps))
+            .collect();
+        let (assignment, coded) = match <[Coded; 2]>::try_from(coded) {
+            Ok(stereo) => decorrelate(stereo),
+            Err(coded) => (coded.len() as u64 - 1, coded),
+        };
+
+        let mut bits = BitWriter::default();
+        bits.put(0b1111_1111_1111_1000, 16);
+        let block_code = match frames {
+            BLOCK_FRAMES => 0b1100,
+            _ => 0b0111,
+        };
+        bits.put(block_code, 4);
+        // Sample rate as in STREAMINFO
+        bits.put(0b0000, 4);
+        bits.put(assignment, 4);
+        let depth_code = match self.bits_per_sample {
+            16 => 0b100,
+            _ => 0b110,
+        };
+        bits.put(depth_code, 3);
+        bits.put(0, 1);
+        bits.put_utf8(self.frame_number as u64);
+        if block_code == 0b0111 {
+            bits.put(frames as u64 - 1, 16);
+        }
+        let crc = crc8(&bits.bytes);
+        bits.put(crc as u64, 8);
+        for channel in &coded {
+            channel
+                .subframe
+                .write(&channel.samples, channel.bps, &mut bits);
+        }
+        bits.align();
+        let crc = crc16(&bits.bytes);
+        bits.put(crc as u64, 16);
+
+        self.file.write_all(&bits.bytes)?;
+        let len = bits.bytes.len() as u32;
+        self.frame_bytes = Some(match self.frame_bytes {
+            Some((min, max)) => (min.min(len), max.max(len)),
+            None => (len, len),
+        });
+        self.frame_number += 1;
+        self.total_frames += frames as u64;
+        Ok(())
+    }
+}
+
+/// A channel's samples with the subframe chosen for them
+struct Coded {
+    subframe: Subframe,
+    samples: Vec<i64>,
+    bps: u32,
+}
+
+impl Coded {
+    fn new(samples: Vec<i64>, bps: u32) -> Coded {
+        Coded {
+            subframe: Subframe::choose(&samples, bps),
+            samples,
+            bps,
+        }
+    }
+}
+
+/// The smallest of coding stereo as independent channels, left/side, side/right or
+/// mid/side, with its channel assignment code
+fn decorrelate([left, right]: [Coded; 2]) -> (u64, Vec<Coded>) {
+    let pairs = left.samples.iter().zip(&right.samples);
+    let side = pairs.clone().map(|(l, r)| l - r).collect();
+    let mid = pairs.map(|(l, r)| (l + r) >> 1).collect();
+    let side = Coded::new(side, left.bps + 1);
+    let mid = Coded::new(mid, left.bps);
+    let bits = |a: &Coded, b: &Coded| a.subframe.bits + b.subframe.bits;
+    let costs = [
+        bits(&left, &right),
+        bits(&left, &side),
+        bits(&side, &right),
+        bits(&mid, &side),
+    ];
+    let best = (0..costs.len()).min_by_key(|&i| costs[i]).unwrap_or(0);
+    match best {
+        0 => (0b0001, vec![left, right]),
+        1 => (0b1000, vec![left, side]),
+        2 => (0b1001, vec![side, right]),
+        _ => (0b1010, vec![mid, side]),
+    }
+}
+
+/// Rice coding parameters for residuals of a given sample depth
+#[derive(Clone, Copy)]
+struct Rice {
+    /// 0 for 4-bit parameters, 1 for the 5-bit ones deeper audio needs
+    method: u64,
+    parameter_bits: u32,
+    /// All ones means an escaped partition, which isn't used here
+    max_parameter: u32,
+}
+
+impl Rice {
+    fn for_depth(bps: u32) -> Rice {
+        match bps {
+            ..=17 => Rice {
+                method: 0,
+                parameter_bits: 4,
+                max_parameter: 14,
+            },
+            _ => Rice {
+                method: 1,
+                parameter_bits: 5,
+                max_parameter: 30,
+            },
+        }
+    }
+}
+
+/// How one channel of a frame is coded, and what that costs in bits
+struct Subframe {
+    kind: SubframeKind,
+    bits: u64,
+}
+
+enum SubframeKind {
+    Constant,
+    Verbatim,
+    Fixed { order: usize, partition_order: u32 },
+}
+
+impl Subframe {
+    fn choose(samples: &[i64], bps: u32) -> Subframe {
+        let header = 8;
+        if samples.iter().all(|&s| s == samples[0]) {
+            return Subframe {
+                kind: SubframeKind::Constant,
+                bits: header + bps as u64,
+            };
+        }
+        let mut best = Subframe {
+            kind: SubframeKind::Verbatim,
+            bits: header + samples.len() as u64 * bps as u64,
+        };
+        for order in 0..=4.min(samples.len() - 1) {
+            let residual = fixed_residual(samples, order);
+            let (partition_order, residual_bits) =
+                best_partitioning(&residual, samples.len(), Rice::for_depth(bps));
+            let bits = header + (order as u64 * bps as u64) + residual_bits;
+            if bits < best.bits {
+                best = Subframe {
+                    kind: SubframeKind::Fixed {
+                        order,
+                        partition_order,
+                    },
+                    bits,
+                };
+            }
+        }
+        best
+    }
+
+    fn write(&self, samples: &[i64], bps: u32, bits: &mut BitWriter) {
+        match self.kind {
+            SubframeKind::Constant => {
+                bits.put(0b0000_0000, 8);
+                bits.put_signed(samples[0], bps);
+            }
+            SubframeKind::Verbatim => {
+                bits.put(0b0000_0010, 8);
+                for &sample in samples {
+                    bits.put_signed(sample, bps);
+                }
+            }
+            SubframeKind::Fixed {
+                order,
+                partition_order,
+            } => {
+                bits.put((0b1000 | order as u64) << 1, 8);
+                for &sample in &samples[..order] {
+                    bits.put_signed(sample, bps);
+                }
+                let residual = fixed_residual(samples, order);
+                let rice = Rice::for_depth(bps);
+                write_residual(&residual, samples.len(), partition_order, rice, bits);
+            }
+        }
+    }
+}
+
+/// What's left of `samples` after the fixed predictor of `order`, from sample `order` on
+fn fixed_residual(samples: &[i64], order: usize) -> Vec<i64> {
+    let s = samples;
+    (order..s.len())
+        .map(|i| match order {
+            0 => s[i],
+            1 => s[i] - s[i - 1],
+            2 => s[i] - 2 * s[i - 1] + s[i - 2],
+            3 => s[i] - 3 * s[i - 1] + 3 * s[i - 2] - s[i - 3],
+            _ => s[i] - 4 * s[i - 1] + 6 * s[i - 2] - 4 * s[i - 3] + s[i - 4],
+        })
+        .collect()
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+/// Rice parameter for a partition whose zigzagged residuals add up to `sum`, with its
+/// approximate cost in bits
+fn rice_parameter(sum: u64, count: usize, rice: Rice) -> (u32, u64) {
+    let count = count.max(1) as u64;
+    let mut best = (0, u64::MAX);
+    for k in 0..=rice.max_parameter {
+        let bits = count * (k as u64 + 1) + (sum >> k);
+        if bits < best.1 {
+            best = (k, bits);
+        }
+    }
+    best
+}
+
+/// Sums of zigzagged residuals per partition at `partition_order`
+fn partition_sums(residual: &[i64], block_len: usize, partition_order: u32) -> Vec<(u64, usize)> {
+    let partition_len = block_len >> partition_order;
+    let warmup = block_len - residual.len();
+    let mut start = 0;
+    (0..1usize << partition_order)
+        .map(|i| {
+            let len = if i == 0 {
+                partition_len - warmup
+            } else {
+                partition_len
+            };
+            let sum = residual[start..start + len]
+                .iter()
+                .map(|&r| zigzag(r))
+                .sum();
+            start += len;
+            (sum, len)
+        })
+        .collect()
+}
+
+/// Partition order with the smallest residual, and the residual's size in bits
+fn best_partitioning(residual: &[i64], block_len: usize, rice: Rice) -> (u32, u64) {
+    let warmup = block_len - residual.len();
+    let mut best = (0, u64::MAX);
+    for order in 0..=MAX_PARTITION_ORDER {
+        if !block_len.is_multiple_of(1 << order) || block_len >> order <= warmup {
+            break;
+        }
+        let bits: u64 = partition_sums(residual, block_len, order)
+            .into_iter()
+            .map(|(sum, len)| rice.parameter_bits as u64 + rice_parameter(sum, len, rice).1)
+            .sum();
+        let bits = bits + 6;
+        if bits < best.1 {
+            best = (order, bits);
+        }
+    }
+    best
+}
+
+fn write_residual(
+    residual: &[i64],
+    block_len: usize,
+    partition_order: u32,
+    rice: Rice,
+    bits: &mut BitWriter,
+) {
+    bits.put(rice.method, 2);
+    bits.put(partition_order as u64, 4);
+    let mut start = 0;
+    for (sum, len) in partition_sums(residual, block_len, partition_order) {
+        let (k, _) = rice_parameter(sum, len, rice);
+        bits.put(k as u64, rice.parameter_bits);
+        for &r in &residual[start..start + len] {
+            let value = zigzag(r);
+            bits.put_zeros(value >> k);
+            bits.put(1, 1);
+            bits.put(value & ((1 << k) - 1), k);
+        }
+        start += len;
+    }
+}
+
+/// Bits packed most significant first
+#[derive(Default)]
+pub(crate) struct BitWriter {
+    pub bytes: Vec<u8>,
+    acc: u64,
+    len: u32,
+}
+
+impl BitWriter {
+    /// The low `bits` of `value`, at most 32 at a time
+    pub fn put(&mut self, value: u64, bits: u32) {
+        if bits == 0 {
+            return;
+        }
+        if bits > 32 {
+            self.put(value >> 32, bits - 32);
+            self.put(value & 0xffff_ffff, 32);
+            return;
+        }
+        self.acc = (self.acc << bits) | (value & ((1 << bits) - 1));
+        self.len += bits;
+        while self.len >= 8 {
+            self.len -= 8;
+            self.bytes.push((self.acc >> self.len) as u8);
+        }
+        self.acc &= (1 << self.len) - 1;
+    }
+
+    fn put_signed(&mut self, value: i64, bits: u32) {
+        self.put(value as u64, bits);
+    }
+
+    fn put_zeros(&mut self, mut count: u64) {
+        while count > 0 {
+            let bits = count.min(32);
+            self.put(0, bits as u32);
+            count -= bits;
+        }
+    }
+
+    /// A frame number in FLAC's UTF-8-like variable length coding
+    fn put_utf8(&mut self, value: u64) {
+        if value < 0x80 {
+            self.put(value, 8);
+            return;
+        }
+        let continuation = match value {
+            v if v < 0x800 => 1,
+            v if v < 0x1_0000 => 2,
+            v if v < 0x20_0000 => 3,
+            v if v < 0x400_0000 => 4,
+            _ => 5,
+        };
+        let lead = (0xff00u64 >> (continuation + 1)) & 0xff;
+        self.put(lead | (value >> (6 * continuation)), 8);
+        for i in (0..continuation).rev() {
+            self.put(0x80 | ((value >> (6 * i)) & 0x3f), 8);
+        }
+    }
+
+    pub fn align(&mut self) {
+        if self.len > 0 {
+            self.put(0, 8 - self.len);
+        }
+    }
+}
+
+fn crc8(bytes: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in bytes {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = match crc & 0x80 {
+                0 => crc << 1,
+                _ => (crc << 1) ^ 0x07,
+            };
+        }
+    }
+    crc
+}
+
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &byte in bytes {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = match crc & 0x8000 {
+                0 => crc << 1,
+                _ => (crc << 1) ^ 0x8005,
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use symphonia::core::audio::SampleBuffer;
+    use symphonia::core::codecs::{DecoderOptions, CODEC_TYPE_FLAC};
+    use symphonia::core::formats::FormatOptions;
+    use symphonia::core::io::MediaSourceStream;
+    use symphonia::core::meta::MetadataOptions;
+    use symphonia::core::probe::Hint;
+
+    use super::*;
+
+    /// Interleaved samples the encoder has to keep exactly: a ramp, a square wave at
+    /// full scale, silence and noise, one per channel in turn
+    fn samples(channels: usize, frames: usize, bits: u32) -> Vec<i32> {
+        let max = (1i64 << (bits - 1)) - 1;
+        let min = -(1i64 << (bits - 1));
+        let mut noise = 0x1234_5678_u32;
+        let mut samples = Vec::with_capacity(channels * frames);
+        for frame in 0..frames {
+            for channel in 0..channels {
+                noise = noise.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                let sample = match channel % 4 {
+                    0 => (frame as i64 * 37) % (max + 1),
+                    1 if frame % 300 < 150 => max,
+                    1 => min,
+                    2 => 0,
+                    _ => (noise as i32 >> (32 - bits)) as i64,
+                };
+                samples.push(sample as i32);
+            }
+        }
+        samples
+    }
+
+    /// Encode `samples` and decode them back with symphonia
+    fn round_trip(samples: &[i32], channels: u16, bits: u32) -> (Vec<u8>, Vec<i32>) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.flac");
+        let mut writer = FlacWriter::create(&path, channels, 44_100, bits).unwrap();
+        // Uneven writes, so frames straddle them
+        for chunk in samples.chunks(1000 * channels as usize + 1) {
+            writer.write(chunk).unwrap();
+        }
+        writer.finish().unwrap();
+        let bytes = fs::read(&path).unwrap();
+
+        let stream =
+            MediaSourceStream::new(Box::new(fs::File::open(&path).unwrap()), Default::default());
+        let mut hint = Hint::new();
+        hint.with_extension("flac");
+        let mut format = symphonia::default::get_probe()
+            .format(
+                &hint,
+                stream,
+                &FormatOptions::default(),
+                &MetadataOptions::default(),
+            )
+            .unwrap()
+            .format;
+        let track = format.default_track().unwrap();
+        assert_eq!(track.codec_params.codec, CODEC_TYPE_FLAC);
+        assert_eq!(track.codec_params.sample_rate, Some(44_100));
+        assert_eq!(track.codec_params.bits_per_sample, Some(bits));
+        let mut decoder = symphonia::default::get_codecs()
+            .make(&track.codec_params, &DecoderOptions { verify: true })
+            .unwrap();
+        let mut decoded = Vec::new();
+        while let Ok(packet) = format.next_packet() {
+            let audio = decoder.decode(&packet).unwrap();
+            assert_eq!(audio.spec().channels.count(), channels as usize);
+            let mut buffer = SampleBuffer::<i32>::new(audio.capacity() as u64, *audio.spec());
+            buffer.copy_interleaved_ref(audio);
+            // symphonia scales samples up to 32 bits.
+            decoded.extend(buffer.samples().iter().map(|&s| s >> (32 - bits)));
+        }
+        assert!(decoder.finalize().verify_ok.unwrap_or(true));
+        (bytes, decoded)
+    }
+
+    /// STREAMINFO's total samples and MD5, read back out of an encoded file
+    fn stream_info(bytes: &[u8]) -> (u64, [u8; 16]) {
+        let info = &bytes[STREAMINFO_OFFSET as usize..STREAMINFO_OFFSET as usize + 34];
+        let packed = u64::from_be_bytes(info[10..18].try_into().unwrap());
+        (packed & ((1 << 36) - 1), info[18..].try_into().unwrap())
+    }
+
+    fn md5_of(samples: &[i32], bits: u32) -> [u8; 16] {
+        let mut md5 = Md5::new();
+        for sample in samples {
+            md5.update(&sample.to_le_bytes()[..bits as usize / 8]);
+        }
+        md5.finalize().into()
+    }
+
+    #[test]
+    fn stereo_16_bit_decodes_exactly() {
+        // Two whole frames and a partial one
+        let samples = samples(2, BLOCK_FRAMES * 2 + 123, 16);
+        let (bytes, decoded) = round_trip(&samples, 2, 16);
+        assert_eq!(decoded, samples);
+        let (total, md5) = stream_info(&bytes);
+        assert_eq!(total, BLOCK_FRAMES as u64 * 2 + 123);
+        assert_eq!(md5, md5_of(&samples, 16));
+    }
+
+    #[test]
+    fn other_layouts_and_24_bits_decode_exactly() {
+        for (channels, bits) in [(1, 16), (1, 24), (2, 24), (6, 16), (8, 24)] {
+            let samples = samples(channels, BLOCK_FRAMES + 7, bits);
+            let (bytes, decoded) = round_trip(&samples, channels as u16, bits);
+            assert_eq!(decoded, samples, "{channels} channels at {bits} bits");
+            assert_eq!(stream_info(&bytes).1, md5_of(&samples, bits));
+        }
+    }
+
+    #[test]
+    fn tiny_streams_decode_exactly() {
+        for frames in [1, 15, 16, 17] {
+            let samples = samples(2, frames, 16);
+            let (bytes, decoded) = round_trip(&samples, 2, 16);
+            assert_eq!(decoded, samples, "{frames} frames");
+            assert_eq!(stream_info(&bytes).0, frames as u64);
+        }
+    }
+
+    #[test]
+    fn an_empty_stream_is_only_metadata() {
+        // symphonia won't open a stream without frames, so only its metadata is checked.
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.flac");
+        FlacWriter::create(&path, 2, 44_100, 16)
+            .unwrap()
+            .finish()
+            .unwrap();
+        let bytes = fs::read(&path).unwrap();
+        assert_eq!(bytes.len(), 8 + 34 + 4 + PADDING_BYTES as usize);
+        assert_eq!(stream_info(&bytes), (0, md5_of(&[], 16)));
+    }
+
+    #[test]
+    fn the_first_metadata_block_is_not_the_last() {
+        let (bytes, _) = round_trip(&samples(2, 10, 16), 2, 16);
+        assert_eq!(&bytes[..4], b"fLaC");
+        assert_eq!(bytes[4], 0x00, "STREAMINFO without the last-block flag");
+        assert_eq!(bytes[8 + 34], 0x81, "then the last block, PADDING");
+    }
+
+    #[test]
+    fn unsupported_formats_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.flac");
+        assert!(FlacWriter::create(&path, 0, 44_100, 16).is_err());
+        assert!(FlacWriter::create(&path, 9, 44_100, 16).is_err());
+        assert!(FlacWriter::create(&path, 2, 44_100, 8).is_err());
+        assert!(FlacWriter::create(&path, 2, 44_100, 32).is_err());
+    }
+
+    #[test]
+    fn checksums_match_the_catalogued_checks() {
+        // CRC-8 and CRC-16/UMTS of "123456789"
+        assert_eq!(crc8(b"123456789"), 0xf4);
+        assert_eq!(crc16(b"123456789"), 0xfee8);
+    }
+
+    #[test]
+    fn zigzag_interleaves_signs() {
+        let coded: Vec<u64> = [0, -1, 1, -2, 2].into_iter().map(zigzag).collect();
+        assert_eq!(coded, [0, 1, 2, 3, 4]);
+    }
+}
@@ -0,0 +1,473 @@
+use std::io::{self, Cursor, Read};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context};
+use symphonia::core::io::ReadOnlySource;
+use symphonia::core::probe::Hint;
+use url::Url;
+
+use super::{backoff, header, url_extension, MetadataNotifier, MAX_RECONNECTS};
+use crate::decoder::SymphoniaSource;
+
+/// Segments downloaded ahead of the decoder
+const SEGMENT_QUEUE: usize = 3;
+
+/// Share of the measured throughput a variant's bandwidth may take up
+const BANDWIDTH_HEADROOM: f64 = 0.8;
+
+/// Weight of the latest segment download in the throughput estimate
+const THROUGHPUT_SMOOTHING: f64 = 0.3;
+
+/// Live playback starts this many segments before the end of the playlist
+const LIVE_START_SEGMENTS: usize = 3;
+
+const TS_PACKET_LEN: usize = 188;
+
+/// Whether a response is an HLS playlist rather than audio
+pub(super) fn is_playlist(url: &str, response: &ureq::Response) -> bool {
+    header(response, "content-type").is_some_and(|t| t.to_ascii_lowercase().contains("mpegurl"))
+        || url_extension(url).is_some_and(|e| e.eq_ignore_ascii_case("m3u8"))
+}
+
+/// One rendition listed in a master playlist
+struct Variant {
+    /// Peak bitrate in bit/s
+    bandwidth: u64,
+    codecs: Option<String>,
+    uri: Url,
+}
+
+struct Segment {
+    sequence: u64,
+    uri: Url,
+}
+
+struct MediaPlaylist {
+    target_duration: Duration,
+    segments: Vec<Segment>,
+    /// `#EXT-X-ENDLIST`: no segments will be added, so this isn't a live stream
+    ended: bool,
+}
+
+enum Playlist {
+    Master(Vec<Variant>),
+    Media(MediaPlaylist),
+}
+
+/// Value of `name` in a tag's `KEY=value` attribute list; quoted values may contain commas
+fn attribute<'a>(list: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = list;
+    while let Some((key, after)) = rest.split_once('=') {
+        let (value, next) = match after.strip_prefix('"') {
+            Some(quoted) => quoted.split_once('"').unwrap_or((quoted, "")),
+            None => after.split_once(',').unwrap_or((after, "")),
+        };
+        if key.trim() == name {
+            return Some(value);
+        }
+        rest = next.trim_start_matches(',');
+    }
+    None
+}
+
+fn parse(text: &str, base: &Url) -> anyhow::Result<Playlist> {
+    let mut lines = text
+        .trim_start_matches('\u{feff}')
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty());
+    if lines.next() != Some("#EXTM3U") {
+        bail!("not an HLS playlist");
+    }
+    let mut variants = Vec::new();
+    let mut pending_variant = None;
+    let mut segments = Vec::new();
+    let mut target_duration = None;
+    let mut sequence = 0;
+    let mut ended = false;
+    for line in lines {
+        if let Some(attributes) = line.strip_prefix("#EXT-X-STREAM-INF:") {
+            let bandwidth = attribute(attributes, "BANDWIDTH").and_then(|b| b.parse().ok());
+            let codecs = attribute(attributes, "CODECS").map(str::to_string);
+            pending_variant = Some((bandwidth.unwrap_or(0), codecs));
+        } else if let Some(value) = line.strip_prefix("#EXT-X-TARGETDURATION:") {
+            target_duration = value.parse::<f64>().ok();
+        } else if let Some(value) = line.strip_prefix("#EXT-X-MEDIA-SEQUENCE:") {
+            sequence = value.parse().context("invalid media sequence")?;
+        } else if line == "#EXT-X-ENDLIST" {
+            ended = true;
+        } else if let Some(attributes) = line.strip_prefix("#EXT-X-KEY:") {
+            if attribute(attributes, "METHOD").is_some_and(|m| m != "NONE") {
+                bail!("encrypted HLS streams aren't supported");
+            }
+        } else if line.starts_with("#EXT-X-MAP:") {
+            bail!("fragmented MP4 HLS streams aren't supported");
+        } else if !line.starts_with('#') {
+            let uri = base
+                .join(line)
+                .with_context(|| format!("invalid playlist entry {line}"))?;
+            match pending_variant.take() {
+                Some((bandwidth, codecs)) => variants.push(Variant {
+                    bandwidth,
+                    codecs,
+                    uri,
+                }),
+                None => {
+                    segments.push(Segment { sequence, uri });
+                    sequence += 1;
+                }
+            }
+        }
+    }
+    if !variants.is_empty() {
+        return Ok(Playlist::Master(variants));
+    }
+    // Plain M3U radio playlists share the MIME type but lack HLS tags.
+    let target_duration = target_duration.context("not an HLS playlist")?;
+    Ok(Playlist::Media(MediaPlaylist {
+        target_duration: Duration::from_secs_f64(target_duration),
+        segments,
+        ended,
+    }))
+}
+
+/// GET `url` into memory, retrying with backoff
+fn fetch(agent: &ureq::Agent, url: &Url) -> anyhow::Result<Vec<u8>> {
+    let mut failures = 0;
+    loop {
+        let result = agent
+            .get(url.as_str())
+            .call()
+            .map_err(anyhow::Error::from)
+            .and_then(|response| {
+                let mut bytes = Vec::new();
+                response.into_reader().read_to_end(&mut bytes)?;
+                Ok(bytes)
+            });
+        match result {
+            Ok(bytes) => return Ok(bytes),
+            Err(e) if failures < MAX_RECONNECTS => {
+                failures += 1;
+                let delay = backoff(failures);
+                log::warn!("fetching {url} failed, retrying in {delay:?}: {e:#}");
+                thread::sleep(delay);
+            }
+            Err(e) => return Err(e.context(format!("failed to fetch {url}"))),
+        }
+    }
+}
+
+fn load_media(agent: &ureq::Agent, url: &Url) -> anyhow::Result<MediaPlaylist> {
+    let text = String::from_utf8_lossy(&fetch(agent, url)?).into_owned();
+    match parse(&text, url)? {
+        Playlist::Media(playlist) => Ok(playlist),
+        Playlist::Master(_) => bail!("nested master playlist at {url}"),
+    }
+}
+
+/// Picks the variant to fetch from the measured download throughput
+struct VariantSelector {
+    /// Ordered by bandwidth
+    variants: Vec<Variant>,
+    current: usize,
+    /// Smoothed throughput in bit/s
+    throughput: Option<f64>,
+}
+
+impl VariantSelector {
+    fn new(mut variants: Vec<Variant>) -> Self {
+        variants.sort_by_key(|v| v.bandwidth);
+        // The decoder keeps running across switches, so stay within one codec.
+        let codecs = variants[0].codecs.clone();
+        variants.retain(|v| v.codecs == codecs);
+        // The lowest variant starts fastest; better ones follow once throughput is known.
+        VariantSelector {
+            variants,
+            current: 0,
+            throughput: None,
+        }
+    }
+
+    fn variant(&self) -> &Variant {
+        &self.variants[self.current]
+    }
+
+    fn record(&mut self, bytes: usize, elapsed: Duration) {
+        let sample = bytes as f64 * 8.0 / elapsed.as_secs_f64().max(0.001);
+        self.throughput = Some(match self.throughput {
+            Some(throughput) => throughput + (sample - throughput) * THROUGHPUT_SMOOTHING,
+            None => sample,
+        });
+    }
+
+    /// Highest variant the connection can sustain
+    fn best(&self) -> usize {
+        let Some(throughput) = self.throughput else {
+            return self.current;
+        };
+        self.variants
+            .iter()
+            .rposition(|v| v.bandwidth as f64 <= throughput * BANDWIDTH_HEADROOM)
+            .unwrap_or(0)
+    }
+}
+
+/// Reads the segment payloads the fetcher thread sends as one continuous stream
+struct SegmentReader {
+    // Only locked to make the reader `Sync`, which symphonia requires.
+    segments: Mutex<Receiver<Vec<u8>>>,
+    current: Cursor<Vec<u8>>,
+}
+
+impl Read for SegmentReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.current.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            match self.segments.get_mut().unwrap().recv() {
+                Ok(next) => self.current = Cursor::new(next),
+                Err(_) => return Ok(0),
+            }
+        }
+    }
+}
+
+/// Section of the PSI table starting in a TS packet payload, without its CRC
+fn psi_section(payload: &[u8]) -> Option<&[u8]> {
+    let pointer = *payload.first()? as usize;
+    let table = payload.get(1 + pointer..)?;
+    let length = (usize::from(*table.get(1)? & 0x0f) << 8) | usize::from(*table.get(2)?);
+    table.get(..(3 + length).checked_sub(4)?)
+}
+
+/// PID of the first program listed in a PAT section
+fn pat_program(table: &[u8]) -> Option<u16> {
+    table
+        .get(8..)?
+        .chunks_exact(4)
+        .find(|program| program[..2] != [0, 0])
+        .map(|program| (u16::from(program[2] & 0x1f) << 8) | u16::from(program[3]))
+}
+
+/// PID and container extension of the first audio stream listed in a PMT section
+fn pmt_audio_stream(table: &[u8]) -> Option<(u16, &'static str)> {
+    let info_len = (usize::from(*table.get(10)? & 0x0f) << 8) | usize::from(*table.get(11)?);
+    let mut streams = table.get(12 + info_len..)?;
+    while streams.len() >= 5 {
+        let pid = (u16::from(streams[1] & 0x1f) << 8) | u16::from(streams[2]);
+        let extension = match streams[0] {
+            0x0f => Some("aac"),
+            0x03 | 0x04 => Some("mp3"),
+            _ => None,
+        };
+        if let Some(extension) = extension {
+            return Some((pid, extension));
+        }
+        let es_info_len = (usize::from(streams[3] & 0x0f) << 8) | usize::from(streams[4]);
+        streams = streams.get(5 + es_info_len..)?;
+    }
+    None
+}
+
+/// The first audio elementary stream of an MPEG transport stream, and its format
+fn demux_ts(data: &[u8]) -> (Vec<u8>, Option<&'static str>) {
+    let mut pmt_pid = None;
+    let mut audio: Option<(u16, &'static str)> = None;
+    let mut out = Vec::with_capacity(data.len());
+    for packet in data.chunks_exact(TS_PACKET_LEN) {
+        if packet[0] != 0x47 {
+            continue;
+        }
+        let unit_start = packet[1] & 0x40 != 0;
+        let pid = (u16::from(packet[1] & 0x1f) << 8) | u16::from(packet[2]);
+        let control = (packet[3] >> 4) & 0x3;
+        if control & 0x1 == 0 {
+            continue;
+        }
+        let mut payload = &packet[4..];
+        if control & 0x2 != 0 {
+            let Some(rest) = payload.get(1 + payload[0] as usize..) else {
+                continue;
+            };
+            payload = rest;
+        }
+        if pid == 0 && unit_start {
+            pmt_pid = psi_section(payload).and_then(pat_program);
+        } else if Some(pid) == pmt_pid && unit_start && audio.is_none() {
+            audio = psi_section(payload).and_then(pmt_audio_stream);
+        } else if audio.is_some_and(|(audio_pid, _)| audio_pid == pid) {
+            if unit_start {
+                // Skip the PES header: 9 fixed bytes, then as many as it says follow.
+                let Some(rest) = payload.get(9 + *payload.get(8).unwrap_or(&0) as usize..) else {
+                    continue;
+                };
+                payload = rest;
+            }
+            out.extend_from_slice(payload);
+        }
+    }
+    (out, audio.map(|(_, extension)| extension))
+}
+
+/// A packed audio segment without the ID3 timestamp tag it starts with
+fn strip_id3(data: &[u8]) -> &[u8] {
+    if data.len() < 10 || &data[..3] != b"ID3" {
+        return data;
+    }
+    let size = data[6..10]
+        .iter()
+        .fold(0, |size, &b| (size << 7) | usize::from(b & 0x7f));
+    let footer = if data[5] & 0x10 != 0 { 10 } else { 0 };
+    data.get(10 + size + footer..).unwrap_or_default()
+}
+
+/// Audio bytes of a transport stream or packed audio segment, and the format if known
+fn audio_payload(data: &[u8]) -> (Vec<u8>, Option<&'static str>) {
+    let is_ts = data.first() == Some(&0x47) && data.get(TS_PACKET_LEN).is_none_or(|&b| b == 0x47);
+    if is_ts {
+        demux_ts(data)
+    } else {
+        (strip_id3(data).to_vec(), None)
+    }
+}
+
+/// Downloads segments of the selected variant and feeds them to the decoder
+struct Fetcher {
+    agent: ureq::Agent,
+    selector: VariantSelector,
+    notifier: MetadataNotifier,
+    segments: SyncSender<Vec<u8>>,
+}
+
+impl Fetcher {
+    fn report_bitrate(&self) {
+        let bandwidth = self.selector.variant().bandwidth;
+        let bitrate = (bandwidth > 0).then_some((bandwidth / 1000) as u32);
+        self.notifier.update(|m| m.bitrate = bitrate);
+    }
+
+    fn fetch_segment(
+        &mut self,
+        segment: &Segment,
+    ) -> anyhow::Result<(Vec<u8>, Option<&'static str>)> {
+        let started = Instant::now();
+        let data = fetch(&self.agent, &segment.uri)?;
+        self.selector.record(data.len(), started.elapsed());
+        Ok(audio_payload(&data))
+    }
+
+    /// Fetch from `next_sequence` on, reloading live playlists and switching variants as
+    /// throughput changes; returns when the stream ends or playback stopped
+    fn run(mut self, mut playlist: MediaPlaylist, mut next_sequence: u64) {
+        loop {
+            let mut fetched = false;
+            let mut switch_to = None;
+            let from = next_sequence;
+            for segment in playlist.segments.iter().filter(|s| s.sequence >= from) {
+                let payload = match self.fetch_segment(segment) {
+                    Ok((payload, _)) => payload,
+                    Err(e) => {
+                        log::error!("stopping HLS stream: {e:#}");
+                        return;
+                    }
+                };
+                next_sequence = segment.sequence + 1;
+                fetched = true;
+                if self.segments.send(payload).is_err() {
+                    return;
+                }
+                let best = self.selector.best();
+                if best != self.selector.current {
+                    switch_to = Some(best);
+                    break;
+                }
+            }
+            if let Some(index) = switch_to {
+                log::info!(
+                    "switching HLS variant {} -> {} bit/s",
+                    self.selector.variant().bandwidth,
+                    self.selector.variants[index].bandwidth
+                );
+                self.selector.current = index;
+                self.report_bitrate();
+            } else if playlist.ended {
+                return;
+            } else if !fetched {
+                thread::sleep(playlist.target_duration / 2);
+            }
+            playlist = match load_media(&self.agent, &self.selector.variant().uri) {
+                Ok(playlist) => playlist,
+                Err(e) => {
+                    log::error!("stopping HLS stream: {e:#}");
+                    return;
+                }
+            };
+        }
+    }
+}
+
+/// Pick a variant of the HLS playlist in `response`, fetch its first segment and return a
+/// decoder for the segment stream; a fetcher thread keeps it supplied
+pub(super) fn open(
+    agent: ureq::Agent,
+    response: ureq::Response,
+    notifier: MetadataNotifier,
+) -> anyhow::Result<SymphoniaSource> {
+    // Relative entries resolve against where any redirects ended up.
+    let base = Url::parse(response.get_url()).context("invalid stream URL")?;
+    let text = response.into_string()?;
+    let (selector, playlist) = match parse(&text, &base)? {
+        Playlist::Master(variants) => {
+            let selector = VariantSelector::new(variants);
+            let playlist = load_media(&agent, &selector.variant().uri)?;
+            (selector, playlist)
+        }
+        Playlist::Media(playlist) => {
+            let variant = Variant {
+                bandwidth: 0,
+                codecs: None,
+                uri: base,
+            };
+            (VariantSelector::new(vec![variant]), playlist)
+        }
+    };
+
+    let start = if playlist.ended {
+        0
+    } else {
+        playlist.segments.len().saturating_sub(LIVE_START_SEGMENTS)
+    };
+    let first = playlist
+        .segments
+        .get(start)
+        .context("HLS playlist has no segments")?;
+    let (segments, receiver) = mpsc::sync_channel(SEGMENT_QUEUE);
+    let mut fetcher = Fetcher {
+        agent,
+        selector,
+        notifier,
+        segments,
+    };
+    fetcher.report_bitrate();
+    let (payload, extension) = fetcher.fetch_segment(first)?;
+    let mut hint = Hint::new();
+    if let Some(extension) = extension.or_else(|| url_extension(first.uri.as_str())) {
+        hint.with_extension(extension);
+    }
+    let next_sequence = first.sequence + 1;
+    let _ = fetcher.segments.send(payload);
+    thread::Builder::new()
+        .name("tunes4r-hls".into())
+        .spawn(move || fetcher.run(playlist, next_sequence))?;
+
+    let reader = SegmentReader {
+        segments: Mutex::new(receiver),
+        current: Cursor::new(Vec::new()),
+    };
+    SymphoniaSource::from_stream(Box::new(ReadOnlySource::new(reader)), &hint)
+}
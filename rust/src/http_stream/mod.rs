@@ -0,0 +1,512 @@
+mod hls;
+
+use std::collections::VecDeque;
+use std::io::{self, Read};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Context;
+use rodio::source::SeekError;
+use rodio::Source;
+use symphonia::core::io::ReadOnlySource;
+use symphonia::core::probe::Hint;
+
+use crate::decoder::SymphoniaSource;
+use crate::engine::panic_message;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A station that sends nothing for this long is treated as disconnected
+const READ_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Decoded audio kept ahead of playback
+const BUFFER_SECS: usize = 10;
+
+/// Audio buffered before playback starts, and again after the buffer ran dry
+const PREBUFFER_SECS: usize = 2;
+
+/// Consecutive failed reconnects before the stream is given up
+const MAX_RECONNECTS: u32 = 5;
+
+const MAX_BACKOFF: Duration = Duration::from_secs(16);
+
+/// Frames moved between the network thread and the output at a time
+const CHUNK_FRAMES: usize = 2048;
+
+/// What a station announces about itself
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct StreamMetadata {
+    pub title: Option<String>,
+    /// In kbit/s
+    pub bitrate: Option<u32>,
+}
+
+/// Reports metadata to a callback whenever it changes
+#[derive(Clone)]
+struct MetadataNotifier {
+    last: Arc<Mutex<StreamMetadata>>,
+    callback: Arc<dyn Fn(StreamMetadata) + Send + Sync>,
+}
+
+impl MetadataNotifier {
+    fn update(&self, change: impl FnOnce(&mut StreamMetadata)) {
+        let mut last = self.last.lock().unwrap();
+        let mut metadata = last.clone();
+        change(&mut metadata);
+        if *last != metadata {
+            *last = metadata.clone();
+            (self.callback)(metadata);
+        }
+    }
+}
+
+/// Value of `StreamTitle` in an ICY metadata block
+fn stream_title(block: &str) -> Option<&str> {
+    const KEY: &str = "StreamTitle='";
+    let start = block.find(KEY)? + KEY.len();
+    let rest = block[start..].trim_end_matches('\0');
+    // Titles may contain quotes themselves, so only `';` ends the value.
+    Some(rest.split("';").next()?.trim_end_matches('\''))
+}
+
+/// Strips the metadata blocks an ICY server interleaves with the audio
+struct IcyReader {
+    inner: Box<dyn Read + Send + Sync>,
+    /// Audio bytes between metadata blocks; `None` if the server sends no metadata
+    metaint: Option<usize>,
+    until_metadata: usize,
+    notifier: MetadataNotifier,
+}
+
+impl IcyReader {
+    fn read_metadata(&mut self) -> io::Result<()> {
+        let mut len = [0u8];
+        self.inner.read_exact(&mut len)?;
+        if len[0] == 0 {
+            return Ok(());
+        }
+        let mut block = vec![0; len[0] as usize * 16];
+        self.inner.read_exact(&mut block)?;
+        // Stations without UTF-8 support send Latin-1.
+        let text = String::from_utf8(block)
+            .unwrap_or_else(|e| e.into_bytes().iter().map(|&b| b as char).collect());
+        if let Some(title) = stream_title(&text) {
+            let title = title.trim();
+            self.notifier
+                .update(|m| m.title = (!title.is_empty()).then(|| title.to_string()));
+        }
+        Ok(())
+    }
+}
+
+impl Read for IcyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(metaint) = self.metaint else {
+            return self.inner.read(buf);
+        };
+        if self.until_metadata == 0 {
+            self.read_metadata()?;
+            self.until_metadata = metaint;
+        }
+        let len = buf.len().min(self.until_metadata);
+        let read = self.inner.read(&mut buf[..len])?;
+        self.until_metadata -= read;
+        Ok(read)
+    }
+}
+
+/// Container extension for a stream's `Content-Type`
+fn mime_extension(content_type: &str) -> Option<&'static str> {
+    let mime = content_type.split(';').next()?.trim().to_ascii_lowercase();
+    Some(match mime.as_str() {
+        "audio/mpeg" | "audio/mp3" => "mp3",
+        "audio/aac" | "audio/aacp" | "audio/x-aac" => "aac",
+        "audio/ogg" | "application/ogg" | "audio/vorbis" => "ogg",
+        "audio/flac" | "audio/x-flac" => "flac",
+        "audio/wav" | "audio/x-wav" => "wav",
+        _ => return None,
+    })
+}
+
+/// File extension of the last path segment of `url`, if any
+pub(crate) fn url_extension(url: &str) -> Option<&str> {
+    let path = url.split(['?', '#']).next()?;
+    let (_, extension) = path.rsplit_once('/')?.1.rsplit_once('.')?;
+    Some(extension)
+}
+
+/// Decoder for a fresh connection, with what its headers told us
+struct Connected {
+    source: SymphoniaSource,
+    name: Option<String>,
+    /// No `Content-Length`, so the end of the response means the connection dropped
+    live: bool,
+}
+
+fn request(agent: &ureq::Agent, url: &str) -> anyhow::Result<ureq::Response> {
+    agent
+        .get(url)
+        .set("Icy-MetaData", "1")
+        .call()
+        .with_context(|| format!("failed to connect to {url}"))
+}
+
+/// Delay before retry number `failures`, doubling each time
+fn backoff(failures: u32) -> Duration {
+    (Duration::from_secs(1) * 2u32.pow(failures.saturating_sub(1).min(8))).min(MAX_BACKOFF)
+}
+
+/// Trimmed, non-empty value of a response header
+fn header(response: &ureq::Response, name: &str) -> Option<String> {
+    response
+        .header(name)
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(str::to_string)
+}
+
+/// Start decoding a response body, stripping ICY metadata if the server sends it
+fn decode_response(
+    response: ureq::Response,
+    url: &str,
+    notifier: &MetadataNotifier,
+) -> anyhow::Result<Connected> {
+    let metaint = header(&response, "icy-metaint").and_then(|v| v.parse().ok());
+    // Some servers list one bitrate per quality, e.g. "128,64".
+    let bitrate =
+        header(&response, "icy-br").and_then(|v| v.split(',').next()?.trim().parse().ok());
+    let name = header(&response, "icy-name");
+    let live = header(&response, "content-length").is_none();
+    let mut hint = Hint::new();
+    let content_type = header(&response, "content-type");
+    if let Some(extension) = content_type
+        .as_deref()
+        .and_then(mime_extension)
+        .or_else(|| url_extension(url))
+    {
+        hint.with_extension(extension);
+    }
+    if let Some(mime) = &content_type {
+        hint.mime_type(mime);
+    }
+
+    notifier.update(|m| m.bitrate = bitrate);
+    let reader = IcyReader {
+        inner: response.into_reader(),
+        until_metadata: metaint.unwrap_or(0),
+        metaint,
+        notifier: notifier.clone(),
+    };
+    let source = SymphoniaSource::from_stream(Box::new(ReadOnlySource::new(reader)), &hint)?;
+    Ok(Connected { source, name, live })
+}
+
+/// Decoded samples handed from the network thread to the output
+struct Buffer {
+    samples: Mutex<VecDeque<f32>>,
+    /// How full the prebuffer is while playback waits for it, 100 otherwise
+    level: AtomicU8,
+    /// The network thread gave up; nothing more will arrive
+    finished: AtomicBool,
+    /// The source was dropped and the network thread should exit
+    stopped: AtomicBool,
+    /// What the network thread panicked with, until `BufferLevel::take_panic`
+    panic: Mutex<Option<String>>,
+}
+
+impl Buffer {
+    fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+}
+
+/// Network thread: decode into the buffer, asking `next_source` for a new decoder each
+/// time the current one runs out
+///
+/// `next_source` is told whether the last decoder produced any audio.
+fn fill(
+    buffer: Arc<Buffer>,
+    mut source: SymphoniaSource,
+    mut next_source: impl FnMut(&Buffer, bool) -> Option<SymphoniaSource>,
+) {
+    let (channels, sample_rate) = (source.channels(), source.sample_rate());
+    let capacity = BUFFER_SECS * sample_rate as usize * channels as usize;
+    let chunk_len = CHUNK_FRAMES * channels as usize;
+    let mut chunk = Vec::with_capacity(chunk_len);
+    loop {
+        let mut received = false;
+        loop {
+            chunk.extend(source.by_ref().take(chunk_len));
+            if chunk.is_empty() {
+                break;
+            }
+            received = true;
+            while buffer.samples.lock().unwrap().len() >= capacity {
+                if buffer.is_stopped() {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+            buffer.samples.lock().unwrap().extend(chunk.drain(..));
+            if buffer.is_stopped() {
+                return;
+            }
+        }
+        match next_source(&buffer, received) {
+            Some(next) if next.channels() == channels && next.sample_rate() == sample_rate => {
+                source = next;
+            }
+            Some(_) => {
+                log::error!("stream changed format, stopping");
+                break;
+            }
+            None => break,
+        }
+    }
+    buffer.finished.store(true, Ordering::Relaxed);
+}
+
+/// Reconnect to a live stream with exponential backoff
+///
+/// Files served with a length just end; reconnecting would restart them.
+struct Reconnect {
+    agent: ureq::Agent,
+    url: String,
+    notifier: MetadataNotifier,
+    live: bool,
+    failures: u32,
+}
+
+impl Reconnect {
+    fn next_source(&mut self, buffer: &Buffer, received: bool) -> Option<SymphoniaSource> {
+        if !self.live {
+            return None;
+        }
+        if received {
+            self.failures = 0;
+        }
+        let url = &self.url;
+        loop {
+            self.failures += 1;
+            if self.failures > MAX_RECONNECTS {
+                log::error!("giving up on {url} after {MAX_RECONNECTS} reconnects");
+                return None;
+            }
+            let delay = backoff(self.failures);
+            log::warn!("stream {url} dropped, reconnecting in {delay:?}");
+            thread::sleep(delay);
+            if buffer.is_stopped() {
+                return None;
+            }
+            match request(&self.agent, url).and_then(|r| decode_response(r, url, &self.notifier)) {
+                Ok(connected) => {
+                    self.live = connected.live;
+                    return Some(connected.source);
+                }
+                Err(e) => log::warn!("reconnecting to {url} failed: {e:#}"),
+            }
+        }
+    }
+}
+
+fn spawn_fill(
+    buffer: Arc<Buffer>,
+    source: SymphoniaSource,
+    next_source: impl FnMut(&Buffer, bool) -> Option<SymphoniaSource> + Send + 'static,
+) -> io::Result<()> {
+    thread::Builder::new()
+        .name("tunes4r-stream".into())
+        .spawn(move || {
+            let filled = buffer.clone();
+            let result =
+                panic::catch_unwind(AssertUnwindSafe(|| fill(filled, source, next_source)));
+            // Playback ends as if the stream had, instead of waiting on it forever.
+            if let Err(payload) = result {
+                *buffer.panic.lock().unwrap() = Some(panic_message(&*payload));
+                buffer.finished.store(true, Ordering::Relaxed);
+            }
+        })?;
+    Ok(())
+}
+
+/// Whether playback of a network stream is waiting for its buffer, readable from any thread
+#[derive(Clone)]
+pub(crate) struct BufferLevel {
+    buffer: Arc<Buffer>,
+    samples_per_sec: f64,
+}
+
+impl BufferLevel {
+    /// How full the buffer is, in percent, while playback waits for it
+    pub fn buffering(&self) -> Option<u8> {
+        let level = self.buffer.level.load(Ordering::Relaxed);
+        (level < 100).then_some(level)
+    }
+
+    /// How far the network thread has decoded ahead of playback
+    pub fn ahead_secs(&self) -> f64 {
+        self.buffer.samples.lock().unwrap().len() as f64 / self.samples_per_sec
+    }
+
+    /// What the network thread panicked with, once
+    pub fn take_panic(&self) -> Option<String> {
+        self.buffer.panic.lock().unwrap().take()
+    }
+
+    /// Share of the buffer holding decoded audio, from 0 to 1
+    pub fn fill(&self) -> f32 {
+        (self.ahead_secs() / BUFFER_SECS as f64).min(1.0) as f32
+    }
+}
+
+/// Live HTTP(S) audio: an Icecast or Shoutcast station, a plain file or an HLS playlist
+///
+/// A network thread decodes into a buffer a few seconds ahead of playback, so a slow
+/// connection stalls into silence instead of blocking the audio callback. Legacy
+/// servers that answer with `ICY 200 OK` instead of an HTTP status line aren't supported.
+pub(crate) struct HttpSource {
+    buffer: Arc<Buffer>,
+    chunk: VecDeque<f32>,
+    channels: u16,
+    sample_rate: u32,
+    /// Playing silence until enough audio is buffered
+    buffering: bool,
+    name: Option<String>,
+}
+
+impl HttpSource {
+    /// Connect to `url`; `on_metadata` is called from the network thread whenever the
+    /// station's title or bitrate changes
+    pub fn open(
+        url: &str,
+        on_metadata: impl Fn(StreamMetadata) + Send + Sync + 'static,
+    ) -> anyhow::Result<Self> {
+        let agent = ureq::AgentBuilder::new()
+            .timeout_connect(CONNECT_TIMEOUT)
+            .timeout_read(READ_TIMEOUT)
+            .build();
+        let notifier = MetadataNotifier {
+            last: Arc::default(),
+            callback: Arc::new(on_metadata),
+        };
+        let buffer = Arc::new(Buffer {
+            samples: Mutex::new(VecDeque::new()),
+            level: AtomicU8::new(0),
+            finished: AtomicBool::new(false),
+            stopped: AtomicBool::new(false),
+            panic: Mutex::new(None),
+        });
+        let response = request(&agent, url)?;
+        let (spec, name) = if hls::is_playlist(url, &response) {
+            let source = hls::open(agent, response, notifier)?;
+            let spec = (source.channels(), source.sample_rate());
+            // The playlist fetcher retries on its own; its end is the end of the stream.
+            spawn_fill(buffer.clone(), source, |_, _| None)?;
+            (spec, None)
+        } else {
+            let connected = decode_response(response, url, &notifier)?;
+            let source = &connected.source;
+            let spec = (source.channels(), source.sample_rate());
+            let mut reconnect = Reconnect {
+                agent,
+                url: url.to_string(),
+                notifier,
+                live: connected.live,
+                failures: 0,
+            };
+            spawn_fill(buffer.clone(), connected.source, move |buffer, received| {
+                reconnect.next_source(buffer, received)
+            })?;
+            (spec, connected.name)
+        };
+        let (channels, sample_rate) = spec;
+        Ok(HttpSource {
+            buffer,
+            chunk: VecDeque::new(),
+            channels,
+            sample_rate,
+            buffering: true,
+            name,
+        })
+    }
+
+    pub fn buffer_level(&self) -> BufferLevel {
+        BufferLevel {
+            buffer: self.buffer.clone(),
+            samples_per_sec: self.sample_rate as f64 * self.channels as f64,
+        }
+    }
+
+    /// Station name from the `icy-name` header
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Move the next chunk out of the shared buffer, or a frame of silence while waiting
+    fn refill(&mut self) {
+        let mut samples = self.buffer.samples.lock().unwrap();
+        let finished = self.buffer.finished.load(Ordering::Relaxed);
+        if samples.is_empty() && !finished && !self.buffering {
+            log::debug!("stream buffer ran dry");
+            self.buffering = true;
+        }
+        let prebuffer = PREBUFFER_SECS * self.sample_rate as usize * self.channels as usize;
+        if self.buffering && samples.len() < prebuffer && !finished {
+            let level = (samples.len() * 100 / prebuffer) as u8;
+            self.buffer.level.store(level, Ordering::Relaxed);
+            self.chunk
+                .extend(std::iter::repeat_n(0.0, self.channels as usize));
+            return;
+        }
+        if self.buffering {
+            self.buffering = false;
+            self.buffer.level.store(100, Ordering::Relaxed);
+        }
+        let len = samples.len().min(CHUNK_FRAMES * self.channels as usize);
+        self.chunk.extend(samples.drain(..len));
+    }
+}
+
+impl Drop for HttpSource {
+    fn drop(&mut self) {
+        self.buffer.stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Iterator for HttpSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.chunk.is_empty() {
+            self.refill();
+        }
+        self.chunk.pop_front()
+    }
+}
+
+impl Source for HttpSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+
+    fn try_seek(&mut self, _pos: Duration) -> Result<(), SeekError> {
+        Err(SeekError::NotSupported {
+            underlying_source: std::any::type_name::<Self>(),
+        })
+    }
+}
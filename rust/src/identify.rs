@@ -0,0 +1,211 @@
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use serde_json::Value;
+
+use crate::fingerprint::Fingerprint;
+use crate::{library, metadata, IdentificationCandidate, Song};
+
+const ACOUSTID_URL: &str = "https://api.acoustid.org/v2/lookup";
+pub(crate) const MUSICBRAINZ_URL: &str = "https://musicbrainz.org/ws/2";
+
+/// MusicBrainz turns away requests without a descriptive user agent
+const USER_AGENT: &str = concat!("tunes4r/", env!("CARGO_PKG_VERSION"));
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Recordings whose length is further than this from the file's are less likely matches
+const DURATION_TOLERANCE_SECS: f64 = 10.0;
+
+static ACOUSTID_KEY: Mutex<Option<String>> = Mutex::new(None);
+
+pub(crate) fn set_acoustid_key(key: String) {
+    *ACOUSTID_KEY.lock().unwrap() = Some(key).filter(|key| !key.is_empty());
+}
+
+pub(crate) fn agent() -> ureq::Agent {
+    ureq::AgentBuilder::new()
+        .timeout_connect(CONNECT_TIMEOUT)
+        .timeout_read(READ_TIMEOUT)
+        .user_agent(USER_AGENT)
+        .build()
+}
+
+/// Fingerprint a file and look it up on AcoustID, best match first
+pub(crate) fn identify(path: &Path) -> anyhow::Result<Vec<IdentificationCandidate>> {
+    let key = ACOUSTID_KEY
+        .lock()
+        .unwrap()
+        .clone()
+        .context("no AcoustID API key; call set_acoustid_api_key first")?;
+    let fingerprint = Fingerprint::compute(path)?;
+    let result = agent().post(ACOUSTID_URL).send_form(&[
+        ("client", key.as_str()),
+        ("meta", "recordings releasegroups"),
+        (
+            "duration",
+            &(fingerprint.duration.round() as u64).to_string(),
+        ),
+        ("fingerprint", &fingerprint.encode()),
+    ]);
+    let body = match result {
+        // AcoustID explains errors in the body.
+        Ok(response) | Err(ureq::Error::Status(_, response)) => response.into_string()?,
+        Err(e) => return Err(e.into()),
+    };
+    let response: Value = serde_json::from_str(&body).context("unreadable AcoustID response")?;
+    if response["status"] != "ok" {
+        bail!(
+            "AcoustID lookup failed: {}",
+            response["error"]["message"]
+                .as_str()
+                .unwrap_or("unknown error")
+        );
+    }
+
+    let mut candidates: Vec<IdentificationCandidate> = Vec::new();
+    for result in response["results"].as_array().into_iter().flatten() {
+        let score = result["score"].as_f64().unwrap_or(0.0);
+        for recording in result["recordings"].as_array().into_iter().flatten() {
+            let Some(candidate) = candidate(recording, score, fingerprint.duration) else {
+                continue;
+            };
+            match candidates
+                .iter_mut()
+                .find(|c| c.recording_id == candidate.recording_id)
+            {
+                Some(existing) => {
+                    existing.confidence = existing.confidence.max(candidate.confidence)
+                }
+                None => candidates.push(candidate),
+            }
+        }
+    }
+    candidates.sort_by(|a, b| b.confidence.total_cmp(&a.confidence));
+    Ok(candidates)
+}
+
+/// A candidate from an AcoustID recording, unless it came without metadata
+fn candidate(recording: &Value, score: f64, duration: f64) -> Option<IdentificationCandidate> {
+    let recording_id = recording["id"].as_str()?.to_string();
+    let title = recording["title"].as_str()?.to_string();
+    let length = recording["duration"].as_f64();
+    let mut confidence = score;
+    if length.is_some_and(|length| (length - duration).abs() > DURATION_TOLERANCE_SECS) {
+        confidence /= 2.0;
+    }
+    let groups = recording["releasegroups"].as_array();
+    let album = groups
+        .and_then(|groups| groups.iter().find(|g| g["type"] == "Album"))
+        .or_else(|| groups.and_then(|groups| groups.first()))
+        .and_then(|group| group["title"].as_str())
+        .map(str::to_string);
+    Some(IdentificationCandidate {
+        recording_id,
+        title,
+        artist: artist_credit(&recording["artists"], "name"),
+        album,
+        duration: length.map(|length| length.round() as u64),
+        confidence: confidence.clamp(0.0, 1.0) as f32,
+    })
+}
+
+/// Artist names joined by their join phrases, e.g. "A feat. B"
+pub(crate) fn artist_credit(artists: &Value, name_key: &str) -> String {
+    let artists = artists.as_array().map(Vec::as_slice).unwrap_or_default();
+    let mut credit = String::new();
+    for (i, artist) in artists.iter().enumerate() {
+        credit.push_str(artist[name_key].as_str().unwrap_or_default());
+        match artist["joinphrase"].as_str() {
+            Some(join) => credit.push_str(join),
+            None if i + 1 < artists.len() => credit.push_str(", "),
+            None => {}
+        }
+    }
+    credit
+}
+
+/// Details MusicBrainz has on a recording beyond what AcoustID returns
+struct Recording {
+    title: String,
+    artist: String,
+    album: Option<String>,
+    genre: Option<String>,
+}
+
+fn musicbrainz_recording(id: &str) -> anyhow::Result<Recording> {
+    let body = agent()
+        .get(&format!("{MUSICBRAINZ_URL}/recording/{id}"))
+        .query("inc", "artist-credits releases genres")
+        .query("fmt", "json")
+        .call()?
+        .into_string()?;
+    let recording: Value =
+        serde_json::from_str(&body).context("unreadable MusicBrainz response")?;
+    let releases = recording["releases"].as_array();
+    let album = releases
+        .and_then(|releases| releases.iter().find(|r| r["status"] == "Official"))
+        .or_else(|| releases.and_then(|releases| releases.first()))
+        .and_then(|release| release["title"].as_str())
+        .map(str::to_string);
+    let genre = recording["genres"]
+        .as_array()
+        .and_then(|genres| {
+            genres
+                .iter()
+                .max_by_key(|g| g["count"].as_u64().unwrap_or(0))
+        })
+        .and_then(|genre| genre["name"].as_str())
+        .map(str::to_string);
+    Ok(Recording {
+        title: recording["title"].as_str().unwrap_or_default().to_string(),
+        artist: artist_credit(&recording["artist-credit"], "name"),
+        album,
+        genre,
+    })
+}
+
+/// Write a chosen candidate's metadata to the file's tags and library row
+///
+/// The album and genre come from MusicBrainz when it can be reached; otherwise the
+/// candidate is written as is.
+pub(crate) fn apply(path: &Path, candidate: &IdentificationCandidate) -> anyhow::Result<Song> {
+    let mut song = metadata::read_song(path)?;
+    if library::is_open() {
+        let stored = library::with_library(|lib| lib.get_file_songs(&song.file_path))?;
+        if let Some(stored) = stored.into_iter().next() {
+            song = stored;
+        }
+    }
+    song.title = candidate.title.clone();
+    song.artist = candidate.artist.clone();
+    song.album = candidate.album.clone().unwrap_or(song.album);
+    match musicbrainz_recording(&candidate.recording_id) {
+        Ok(recording) => {
+            if !recording.title.is_empty() {
+                song.title = recording.title;
+            }
+            if !recording.artist.is_empty() {
+                song.artist = recording.artist;
+            }
+            song.album = candidate
+                .album
+                .clone()
+                .or(recording.album)
+                .unwrap_or(song.album);
+            song.genre = recording.genre.or(song.genre);
+        }
+        Err(e) => log::warn!(
+            "MusicBrainz lookup of {} failed, writing AcoustID data only: {e}",
+            candidate.recording_id
+        ),
+    }
+
+    let updated = metadata::write_song(&song)?;
+    metadata::write_recording_id(path, &candidate.recording_id)?;
+    library::refresh_song(&updated)?;
+    Ok(updated)
+}
@@ -0,0 +1,160 @@
+use std::path::Path;
+
+use anyhow::Context;
+
+use super::{file_url_path, parse_date, read_text, walk_xml, Element};
+use super::{ForeignLibrary, ForeignPlaylist, ForeignTrack};
+
+/// A value in a property list
+enum Plist {
+    Dict(Vec<(String, Plist)>),
+    Array(Vec<Plist>),
+    /// Strings and dates, which iTunes writes in RFC 3339
+    Text(String),
+    Integer(i64),
+    Bool(bool),
+    /// Key of the next value in a dict, only seen while parsing
+    Key(String),
+    Other,
+}
+
+impl Plist {
+    fn get(&self, key: &str) -> Option<&Plist> {
+        match self {
+            Plist::Dict(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    fn text(&self, key: &str) -> Option<&str> {
+        match self.get(key)? {
+            Plist::Text(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    fn integer(&self, key: &str) -> Option<i64> {
+        match self.get(key)? {
+            Plist::Integer(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    fn flag(&self, key: &str) -> bool {
+        matches!(self.get(key), Some(Plist::Bool(true)))
+    }
+}
+
+/// Containers open while parsing, with a dict's key waiting for its value
+enum Frame {
+    Dict(Vec<(String, Plist)>, Option<String>),
+    Array(Vec<Plist>),
+    Value,
+}
+
+fn parse(xml: &str) -> anyhow::Result<Plist> {
+    let mut frames: Vec<Frame> = Vec::new();
+    let mut root = None;
+    walk_xml(xml, |element| {
+        let value = match element {
+            Element::Open("plist", _) | Element::Close("plist", _) => return Ok(()),
+            Element::Open(name, _) => {
+                frames.push(match name {
+                    "dict" => Frame::Dict(Vec::new(), None),
+                    "array" => Frame::Array(Vec::new()),
+                    _ => Frame::Value,
+                });
+                return Ok(());
+            }
+            Element::Close(name, text) => match (frames.pop(), name) {
+                (Some(Frame::Dict(entries, _)), _) => Plist::Dict(entries),
+                (Some(Frame::Array(items)), _) => Plist::Array(items),
+                (_, "key") => Plist::Key(text),
+                (_, "string" | "date") => Plist::Text(text),
+                (_, "integer") => text.trim().parse().map_or(Plist::Other, Plist::Integer),
+                (_, "true") => Plist::Bool(true),
+                (_, "false") => Plist::Bool(false),
+                _ => Plist::Other,
+            },
+        };
+        match frames.last_mut() {
+            Some(Frame::Dict(entries, key)) => match value {
+                Plist::Key(name) => *key = Some(name),
+                value => {
+                    if let Some(name) = key.take() {
+                        entries.push((name, value));
+                    }
+                }
+            },
+            Some(Frame::Array(items)) => items.push(value),
+            Some(Frame::Value) => {}
+            None => root = Some(value),
+        }
+        Ok(())
+    })?;
+    root.context("not a property list")
+}
+
+/// Tracks and playlists of an iTunes `Library.xml`, or the iTunes-compatible
+/// `iTunes Music Library.xml` MusicBee keeps when asked to
+///
+/// Smart playlists and the built-in ones, like "Music", are left out.
+pub(super) fn read(path: &Path) -> anyhow::Result<ForeignLibrary> {
+    let plist = parse(&read_text(path)?)
+        .with_context(|| format!("couldn't read the library in {}", path.display()))?;
+    let Some(Plist::Dict(entries)) = plist.get("Tracks") else {
+        anyhow::bail!("{} isn't an iTunes library", path.display());
+    };
+    let mut library = ForeignLibrary::default();
+    for (key, track) in entries {
+        if track.flag("Has Video") || track.text("Track Type") == Some("URL") {
+            continue;
+        }
+        // Computed ratings are the album's, not the track's own.
+        let rating = match track.flag("Rating Computed") {
+            true => None,
+            false => track.integer("Rating").filter(|&r| r > 0),
+        };
+        let text = |key: &str| track.text(key).unwrap_or_default().to_string();
+        library.tracks.push(ForeignTrack {
+            key: key.clone(),
+            path: track.text("Location").and_then(file_url_path),
+            title: text("Name"),
+            artist: text("Artist"),
+            album: text("Album"),
+            duration: track.integer("Total Time").map(|ms| ms as f64 / 1000.0),
+            play_count: track.integer("Play Count").unwrap_or(0).max(0) as u32,
+            last_played: track.text("Play Date UTC").and_then(parse_date),
+            // 20 per star, from 0 to 100
+            rating: rating.map(|r| ((r + 10) / 20).clamp(1, 5) as u8),
+            date_added: track.text("Date Added").and_then(parse_date),
+            loved: track.flag("Loved") || track.flag("Favorited"),
+        });
+    }
+    let playlists = match plist.get("Playlists") {
+        Some(Plist::Array(playlists)) => playlists.as_slice(),
+        _ => &[],
+    };
+    for playlist in playlists {
+        let built_in = playlist.flag("Master") || playlist.get("Distinguished Kind").is_some();
+        if built_in || playlist.flag("Folder") || playlist.get("Smart Info").is_some() {
+            continue;
+        }
+        let Some(name) = playlist.text("Name") else {
+            continue;
+        };
+        let items = match playlist.get("Playlist Items") {
+            Some(Plist::Array(items)) => items.as_slice(),
+            _ => &[],
+        };
+        library.playlists.push(ForeignPlaylist {
+            name: name.to_string(),
+            tracks: items
+                .iter()
+                .filter_map(|item| item.integer("Track ID"))
+                .map(|id| id.to_string())
+                .collect(),
+        });
+    }
+    Ok(library)
+}
@@ -0,0 +1,148 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::XmlVersion;
+
+use crate::{library, ImportReport, LibraryFormat};
+
+mod itunes;
+mod rhythmbox;
+
+/// A track as another player's library has it
+pub(crate) struct ForeignTrack {
+    /// What the other library's playlists refer to the track by
+    pub key: String,
+    pub path: Option<PathBuf>,
+    pub title: String,
+    pub artist: String,
+    pub album: String,
+    pub duration: Option<f64>,
+    pub play_count: u32,
+    pub last_played: Option<i64>,
+    /// 1 to 5 stars
+    pub rating: Option<u8>,
+    pub date_added: Option<i64>,
+    pub loved: bool,
+}
+
+pub(crate) struct ForeignPlaylist {
+    pub name: String,
+    /// Keys of its tracks, in order
+    pub tracks: Vec<String>,
+}
+
+#[derive(Default)]
+pub(crate) struct ForeignLibrary {
+    pub tracks: Vec<ForeignTrack>,
+    pub playlists: Vec<ForeignPlaylist>,
+}
+
+/// Match the tracks of the library file at `path` to library songs and, unless
+/// `dry_run`, bring over their plays, ratings, dates added and the playlists
+pub(crate) fn import(
+    format: LibraryFormat,
+    path: &Path,
+    dry_run: bool,
+) -> anyhow::Result<ImportReport> {
+    let foreign = match format {
+        LibraryFormat::ITunes | LibraryFormat::MusicBee => itunes::read(path)?,
+        LibraryFormat::Rhythmbox => rhythmbox::read(path)?,
+    };
+    log::info!(
+        "read {} tracks and {} playlists from {}",
+        foreign.tracks.len(),
+        foreign.playlists.len(),
+        path.display()
+    );
+    library::with_library(|lib| lib.import_foreign(&foreign, dry_run))
+}
+
+fn read_text(path: &Path) -> anyhow::Result<String> {
+    let bytes = fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Local path of a `file://` URL
+fn file_url_path(location: &str) -> Option<PathBuf> {
+    url::Url::parse(location).ok()?.to_file_path().ok()
+}
+
+/// Seconds since the epoch of an RFC 3339 date, as iTunes writes them
+fn parse_date(text: &str) -> Option<i64> {
+    chrono::DateTime::parse_from_rfc3339(text.trim())
+        .ok()
+        .map(|date| date.timestamp())
+}
+
+/// An element opening, with its attributes, or closing, with the text directly
+/// inside it
+enum Element<'a> {
+    Open(&'a str, &'a BytesStart<'a>),
+    Close(&'a str, String),
+}
+
+/// Walk `xml`, calling `on_element` as each element opens and closes
+fn walk_xml(
+    xml: &str,
+    mut on_element: impl FnMut(Element<'_>) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let mut reader = quick_xml::Reader::from_str(xml);
+    let mut open: Vec<(String, String)> = Vec::new();
+    loop {
+        match reader.read_event().context("malformed XML")? {
+            Event::Start(start) => {
+                let name = String::from_utf8_lossy(start.local_name().as_ref()).into_owned();
+                on_element(Element::Open(&name, &start))?;
+                open.push((name, String::new()));
+            }
+            Event::Empty(empty) => {
+                let name = String::from_utf8_lossy(empty.local_name().as_ref()).into_owned();
+                on_element(Element::Open(&name, &empty))?;
+                on_element(Element::Close(&name, String::new()))?;
+            }
+            Event::Text(text) => {
+                if let Some((_, current)) = open.last_mut() {
+                    current.push_str(&text.xml10_content()?);
+                }
+            }
+            Event::CData(data) => {
+                if let Some((_, current)) = open.last_mut() {
+                    current.push_str(&data.decode()?);
+                }
+            }
+            Event::GeneralRef(reference) => {
+                let Some((_, current)) = open.last_mut() else {
+                    continue;
+                };
+                let name = reference.decode()?;
+                match reference.resolve_char_ref()? {
+                    Some(c) => current.push(c),
+                    None => current.push_str(
+                        quick_xml::escape::resolve_predefined_entity(&name).unwrap_or(""),
+                    ),
+                }
+            }
+            Event::End(_) => {
+                if let Some((name, text)) = open.pop() {
+                    on_element(Element::Close(&name, text))?;
+                }
+            }
+            Event::Eof => return Ok(()),
+            _ => {}
+        }
+    }
+}
+
+/// Value of attribute `name` of `element`, if it has one
+fn attribute(element: &BytesStart<'_>, name: &str) -> anyhow::Result<Option<String>> {
+    let Some(attribute) = element.try_get_attribute(name)? else {
+        return Ok(None);
+    };
+    Ok(Some(
+        attribute
+            .normalized_value(XmlVersion::Implicit1_0)?
+            .into_owned(),
+    ))
+}
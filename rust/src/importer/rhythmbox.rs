@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use super::{attribute, file_url_path, read_text, walk_xml, Element};
+use super::{ForeignLibrary, ForeignPlaylist, ForeignTrack};
+
+/// Where Rhythmbox keeps its playlists, next to the database
+const PLAYLISTS_FILE: &str = "playlists.xml";
+
+/// Songs of a Rhythmbox `rhythmdb.xml`, with the static playlists from the
+/// `playlists.xml` beside it if there is one
+///
+/// Radio stations, podcasts, automatic playlists and the play queue are left out.
+pub(super) fn read(path: &Path) -> anyhow::Result<ForeignLibrary> {
+    let mut library = ForeignLibrary::default();
+    let mut song = false;
+    let mut fields: HashMap<String, String> = HashMap::new();
+    walk_xml(&read_text(path)?, |element| {
+        match element {
+            Element::Open("entry", entry) => {
+                song = attribute(entry, "type")?.as_deref() == Some("song");
+                fields.clear();
+            }
+            Element::Close("entry", _) if song => {
+                song = false;
+                if let Some(track) = track(&mut fields) {
+                    library.tracks.push(track);
+                }
+            }
+            Element::Close(name, text) if song => {
+                fields.insert(name.to_string(), text);
+            }
+            _ => {}
+        }
+        Ok(())
+    })?;
+    anyhow::ensure!(
+        !library.tracks.is_empty(),
+        "{} has no Rhythmbox songs",
+        path.display()
+    );
+
+    let playlists = path.with_file_name(PLAYLISTS_FILE);
+    if playlists.is_file() {
+        let mut current: Option<ForeignPlaylist> = None;
+        walk_xml(&read_text(&playlists)?, |element| {
+            match element {
+                Element::Open("playlist", playlist) => {
+                    let name = attribute(playlist, "name")?;
+                    current = match attribute(playlist, "type")?.as_deref() {
+                        Some("static") => name.map(|name| ForeignPlaylist {
+                            name,
+                            tracks: Vec::new(),
+                        }),
+                        _ => None,
+                    };
+                }
+                Element::Close("location", location) => {
+                    if let Some(playlist) = &mut current {
+                        playlist.tracks.push(location);
+                    }
+                }
+                Element::Close("playlist", _) => library.playlists.extend(current.take()),
+                _ => {}
+            }
+            Ok(())
+        })?;
+    }
+    Ok(library)
+}
+
+/// The song an entry's fields describe, keyed by its location, which playlists refer
+/// to it by
+fn track(fields: &mut HashMap<String, String>) -> Option<ForeignTrack> {
+    let location = fields.remove("location")?;
+    let mut text = |name: &str| fields.remove(name).unwrap_or_default();
+    let (title, artist, album) = (text("title"), text("artist"), text("album"));
+    let number = |name: &str| {
+        fields
+            .get(name)
+            .and_then(|value| value.trim().parse::<f64>().ok())
+    };
+    Some(ForeignTrack {
+        path: file_url_path(&location),
+        key: location,
+        title,
+        artist,
+        album,
+        duration: number("duration"),
+        play_count: number("play-count").unwrap_or(0.0) as u32,
+        last_played: number("last-played").map(|secs| secs as i64),
+        rating: number("rating")
+            .map(|stars| stars.round().min(5.0) as u8)
+            .filter(|&stars| stars > 0),
+        date_added: number("first-seen").map(|secs| secs as i64),
+        loved: false,
+    })
+}
@@ -0,0 +1,234 @@
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+
+use walkdir::WalkDir;
+
+use crate::jobs::{self, Job};
+use crate::{
+    artwork, library, scanner, sources, IntegrityEvent, IntegrityReport, JobKind, RepairOptions,
+    RepairSummary, Song, StorageKind, StreamSink,
+};
+
+/// Report progress every this many songs checked
+const PROGRESS_EVERY: usize = 100;
+
+/// Queue a check of the open library as a background job, repairing what `repair`
+/// asks for afterwards; returns the job's id for `jobs::cancel`
+pub(crate) fn start(repair: Option<RepairOptions>, sink: StreamSink<IntegrityEvent>) -> u32 {
+    jobs::submit(JobKind::Integrity, move |job| {
+        let progress = Progress { sink: &sink, job };
+        let outcome = check(&progress).and_then(|found| match repair {
+            Some(options) if !job.is_cancelled() => repair_found(found, &options, job)
+                .map(|summary| IntegrityEvent::Repaired { summary }),
+            _ => Ok(IntegrityEvent::Verified {
+                report: found.report(),
+            }),
+        });
+        let (event, result) = match outcome {
+            _ if job.is_cancelled() => (IntegrityEvent::Cancelled, Ok(())),
+            Ok(event) => (event, Ok(())),
+            Err(e) => {
+                let message = format!("{e:#}");
+                (IntegrityEvent::Failed { message }, Err(e))
+            }
+        };
+        let _ = sink.add(event);
+        result
+    })
+}
+
+/// Reports progress through the sink, stopping the check if it's cancelled or the
+/// listener went away
+struct Progress<'a> {
+    sink: &'a StreamSink<IntegrityEvent>,
+    job: &'a Job,
+}
+
+impl Progress<'_> {
+    /// Report `checked` songs out of `total` done; false once the check should stop
+    fn report(&self, checked: usize, total: usize) -> bool {
+        let event = IntegrityEvent::Progress {
+            checked: checked as u32,
+            total: total as u32,
+        };
+        if self.sink.add(event).is_err() {
+            self.job.cancelled().store(true, Ordering::Relaxed);
+        }
+        self.job.progress(checked as u64, Some(total as u64));
+        !self.job.is_cancelled()
+    }
+}
+
+/// What `check` found, with what repairing it takes
+#[derive(Default)]
+struct Found {
+    missing: Vec<Song>,
+    empty: Vec<Song>,
+    dangling_entries: u32,
+    stale_art: Vec<PathBuf>,
+}
+
+impl Found {
+    fn report(self) -> IntegrityReport {
+        IntegrityReport {
+            missing_files: self.missing,
+            empty_files: self.empty,
+            dangling_playlist_entries: self.dangling_entries,
+            stale_art: self.stale_art.len() as u32,
+        }
+    }
+}
+
+fn check(progress: &Progress) -> anyhow::Result<Found> {
+    let (songs, art_dir) = library::with_library(|lib| {
+        Ok((lib.get_every_song()?, lib.cache_dir(StorageKind::Artwork)))
+    })?;
+    let mut found = Found::default();
+    for (i, song) in songs.iter().enumerate() {
+        if i.is_multiple_of(PROGRESS_EVERY) && !progress.report(i, songs.len()) {
+            return Ok(found);
+        }
+        let path = Path::new(&song.file_path);
+        // Files of remote sources are checked against the source by rescanning it.
+        if sources::is_remote(path) {
+            continue;
+        }
+        match fs::metadata(path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => found.missing.push(song.clone()),
+            Ok(metadata) if metadata.len() == 0 => found.empty.push(song.clone()),
+            _ => {}
+        }
+    }
+    progress.report(songs.len(), songs.len());
+    found.dangling_entries = library::with_library(|lib| lib.count_dangling_playlist_entries())?;
+    found.stale_art = stale_art(&art_dir, &songs)?;
+    Ok(found)
+}
+
+/// Thumbnails in `art_dir` made for songs that aren't any of `songs`
+fn stale_art(art_dir: &Path, songs: &[Song]) -> anyhow::Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(art_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let current: HashSet<PathBuf> = songs
+        .iter()
+        .map(|song| artwork::cache_path(art_dir, Path::new(&song.file_path)))
+        .collect();
+    let mut stale = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_some_and(|e| e == "jpg") && !current.contains(&path) {
+            stale.push(path);
+        }
+    }
+    Ok(stale)
+}
+
+fn repair_found(found: Found, options: &RepairOptions, job: &Job) -> anyhow::Result<RepairSummary> {
+    let mut summary = RepairSummary::default();
+    let mut missing: Vec<String> = found.missing.into_iter().map(|s| s.file_path).collect();
+    // CUE sheet tracks of one image share its file.
+    missing.dedup();
+    if !options.relink_roots.is_empty() {
+        let candidates = files_by_name(&options.relink_roots, job);
+        missing.retain(|path| match relink(path, &candidates) {
+            Ok(true) => {
+                summary.relinked_files += 1;
+                false
+            }
+            Ok(false) => true,
+            Err(e) => {
+                log::warn!("failed to relink {path}: {e:#}");
+                true
+            }
+        });
+    }
+    if job.is_cancelled() {
+        return Ok(summary);
+    }
+    if options.remove_missing {
+        let mut broken = missing;
+        broken.extend(found.empty.into_iter().map(|s| s.file_path));
+        broken.dedup();
+        for path in broken {
+            let removed = library::with_library(|lib| lib.remove_path(&path))?;
+            summary.removed_songs += removed.len() as u32;
+        }
+    }
+    if options.remove_dangling_entries {
+        summary.removed_playlist_entries =
+            library::with_library(|lib| lib.remove_dangling_playlist_entries())?;
+    }
+    if options.remove_stale_art {
+        for path in &found.stale_art {
+            match fs::remove_file(path) {
+                Ok(()) => summary.removed_art += 1,
+                Err(e) => log::warn!("failed to remove {}: {e}", path.display()),
+            }
+        }
+    }
+    log::info!(
+        "library repaired: {} files relinked, {} songs, {} playlist entries and {} thumbnails removed",
+        summary.relinked_files,
+        summary.removed_songs,
+        summary.removed_playlist_entries,
+        summary.removed_art
+    );
+    Ok(summary)
+}
+
+/// Files under `roots` by lowercased file name
+fn files_by_name(roots: &[String], job: &Job) -> HashMap<OsString, Vec<PathBuf>> {
+    let mut files: HashMap<OsString, Vec<PathBuf>> = HashMap::new();
+    for root in roots {
+        for entry in WalkDir::new(root).follow_links(true).into_iter().flatten() {
+            if job.is_cancelled() {
+                return files;
+            }
+            if entry.file_type().is_file() {
+                let name = entry.file_name().to_ascii_lowercase();
+                files.entry(name).or_default().push(entry.into_path());
+            }
+        }
+    }
+    files
+}
+
+/// Point the songs of the missing file at `path` to a file of the same name among
+/// `candidates`: the identical one if its identity is known, or else the only one not
+/// in the library yet; false if there's no such file
+fn relink(path: &str, candidates: &HashMap<OsString, Vec<PathBuf>>) -> anyhow::Result<bool> {
+    let Some(name) = Path::new(path).file_name() else {
+        return Ok(false);
+    };
+    let Some(candidates) = candidates.get(&name.to_ascii_lowercase()) else {
+        return Ok(false);
+    };
+    let identity = library::with_library(|lib| lib.get_file_identity(path))?;
+    let mut free = Vec::new();
+    for candidate in candidates {
+        let candidate = candidate.to_string_lossy();
+        if library::with_library(|lib| lib.get_file_songs(&candidate))?.is_empty() {
+            free.push(candidate);
+        }
+    }
+    let target = match identity {
+        Some(identity) => free.into_iter().find(|candidate| {
+            scanner::identify(Path::new(&**candidate)).is_ok_and(|found| found == identity)
+        }),
+        None if free.len() == 1 => free.pop(),
+        None => None,
+    };
+    let Some(target) = target else {
+        return Ok(false);
+    };
+    library::with_library(|lib| lib.move_path(path, &target))?;
+    log::info!("relinked {path} to {target}");
+    Ok(true)
+}
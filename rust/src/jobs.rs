@@ -0,0 +1,214 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tokio::sync::Semaphore;
+
+use crate::{runtime, JobInfo, JobKind, JobProgress, StreamSink};
+
+/// Decoding-heavy jobs allowed to run at once, leaving cores for the UI and audio threads
+const MAX_CPU_JOBS: usize = 2;
+
+/// Network-bound jobs allowed to run at once
+const MAX_NETWORK_JOBS: usize = 4;
+
+static CPU_SLOTS: Semaphore = Semaphore::const_new(MAX_CPU_JOBS);
+static NETWORK_SLOTS: Semaphore = Semaphore::const_new(MAX_NETWORK_JOBS);
+
+/// Where job updates go besides each job's own stream, set by `watch_jobs`
+static LISTENER: Mutex<Option<StreamSink<JobProgress>>> = Mutex::new(None);
+
+struct Entry {
+    kind: JobKind,
+    cancelled: Arc<AtomicBool>,
+    running: bool,
+    done: u64,
+    total: Option<u64>,
+}
+
+fn jobs() -> &'static Mutex<HashMap<u32, Entry>> {
+    static JOBS: OnceLock<Mutex<HashMap<u32, Entry>>> = OnceLock::new();
+    JOBS.get_or_init(Default::default)
+}
+
+fn slots(kind: JobKind) -> &'static Semaphore {
+    match kind {
+        JobKind::Scan
+        | JobKind::Waveform
+        | JobKind::Duplicates
+        | JobKind::Silence
+        | JobKind::Analysis
+        | JobKind::Transcode
+        | JobKind::Export
+        | JobKind::Verify
+        | JobKind::Integrity
+        | JobKind::Organize
+        | JobKind::TagEdit
+        | JobKind::Rip => &CPU_SLOTS,
+        JobKind::Download => &NETWORK_SLOTS,
+    }
+}
+
+/// Whether jobs of `kind` work on the open library, so have to end before another is
+/// opened
+fn uses_library(kind: JobKind) -> bool {
+    !matches!(kind, JobKind::Transcode)
+}
+
+fn emit(event: JobProgress) {
+    let mut listener = LISTENER.lock().unwrap();
+    if listener
+        .as_ref()
+        .is_some_and(|sink| sink.add(event).is_err())
+    {
+        listener.take();
+    }
+}
+
+/// Send updates on every job to `sink`, replacing any earlier listener
+pub(crate) fn watch(sink: StreamSink<JobProgress>) {
+    *LISTENER.lock().unwrap() = Some(sink);
+}
+
+/// Queued and running jobs, oldest first
+pub(crate) fn list() -> Vec<JobInfo> {
+    let mut jobs: Vec<JobInfo> = jobs()
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(&job_id, entry)| JobInfo {
+            job_id,
+            kind: entry.kind,
+            running: entry.running,
+            done: entry.done,
+            total: entry.total,
+        })
+        .collect();
+    jobs.sort_by_key(|job| job.job_id);
+    jobs
+}
+
+/// A submitted job's view of itself
+pub(crate) struct Job {
+    id: u32,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Job {
+    /// Set by `cancel`, or by the job itself once nobody listens to it any more
+    pub(crate) fn cancelled(&self) -> &AtomicBool {
+        &self.cancelled
+    }
+
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Record how far along the job is, in whatever units suit it
+    pub(crate) fn progress(&self, done: u64, total: Option<u64>) {
+        if let Some(entry) = jobs().lock().unwrap().get_mut(&self.id) {
+            entry.done = done;
+            entry.total = total;
+        }
+        emit(JobProgress::Progress {
+            job_id: self.id,
+            done,
+            total,
+        });
+    }
+}
+
+/// Queue `work` to run on a blocking thread once its kind has a free slot, returning
+/// an id for `cancel`
+///
+/// A job cancelled while queued still runs, so it can report the cancel on its own
+/// stream; it should check `Job::is_cancelled` before doing anything costly.
+pub(crate) fn submit(
+    kind: JobKind,
+    work: impl FnOnce(&Job) -> anyhow::Result<()> + Send + 'static,
+) -> u32 {
+    static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+    let job_id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let cancelled = Arc::new(AtomicBool::new(false));
+    jobs().lock().unwrap().insert(
+        job_id,
+        Entry {
+            kind,
+            cancelled: cancelled.clone(),
+            running: false,
+            done: 0,
+            total: None,
+        },
+    );
+    emit(JobProgress::Queued { job_id, kind });
+
+    runtime::spawn(async move {
+        let _slot = slots(kind).acquire().await;
+        if let Some(entry) = jobs().lock().unwrap().get_mut(&job_id) {
+            entry.running = true;
+        }
+        emit(JobProgress::Started { job_id });
+        let job = Job {
+            id: job_id,
+            cancelled,
+        };
+        let result = tokio::task::spawn_blocking(move || {
+            let result = work(&job);
+            (result, job.is_cancelled())
+        })
+        .await;
+        jobs().lock().unwrap().remove(&job_id);
+        emit(match result {
+            Ok((_, true)) => JobProgress::Cancelled { job_id },
+            Ok((Ok(()), false)) => JobProgress::Finished { job_id },
+            Ok((Err(e), false)) => JobProgress::Failed {
+                job_id,
+                message: format!("{e:#}"),
+            },
+            Err(e) => {
+                log::error!("job {job_id} panicked: {e}");
+                JobProgress::Failed {
+                    job_id,
+                    message: "job panicked".to_string(),
+                }
+            }
+        });
+    });
+    job_id
+}
+
+/// Request cancellation of a queued or running job; returns false if there is none
+pub(crate) fn cancel(job_id: u32) -> bool {
+    match jobs().lock().unwrap().get(&job_id) {
+        Some(entry) => {
+            entry.cancelled.store(true, Ordering::Relaxed);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Cancel every job working on the open library and wait up to `timeout` for them to
+/// end; returns false if some are still running by then
+pub(crate) fn cancel_library_jobs(timeout: Duration) -> bool {
+    let busy = || {
+        let jobs = jobs().lock().unwrap();
+        let mut busy = jobs
+            .values()
+            .filter(|entry| uses_library(entry.kind))
+            .peekable();
+        let any = busy.peek().is_some();
+        busy.for_each(|entry| entry.cancelled.store(true, Ordering::Relaxed));
+        any
+    };
+    let deadline = Instant::now() + timeout;
+    while busy() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    true
+}
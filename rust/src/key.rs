@@ -0,0 +1,216 @@
+use std::sync::Arc;
+
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+
+/// Pitch class names, sharps or flats as DJ software usually spells them
+const NOTE_NAMES: [&str; 12] = [
+    "C", "C#", "D", "Eb", "E", "F", "F#", "G", "Ab", "A", "Bb", "B",
+];
+
+/// Frequencies the chromagram looks at; below, bass notes smear across bins, and
+/// above, overtones outweigh the notes played
+const MIN_FREQUENCY: f32 = 55.0;
+const MAX_FREQUENCY: f32 = 2000.0;
+
+/// Krumhansl-Kessler key profiles, from C
+const MAJOR_PROFILE: [f32; 12] = [
+    6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88,
+];
+const MINOR_PROFILE: [f32; 12] = [
+    6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17,
+];
+
+/// A musical key: its tonic's pitch class from C and its mode
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Key {
+    pub tonic: u8,
+    pub minor: bool,
+}
+
+impl Key {
+    /// Read standard ("C#m", "Bb", "A minor") or Camelot ("8A") notation
+    pub fn parse(text: &str) -> Option<Key> {
+        let text = text.trim();
+        if let Some(key) = Self::parse_camelot(text) {
+            return Some(key);
+        }
+        let mut chars = text.chars();
+        let letter = chars.next()?.to_ascii_uppercase();
+        let natural = match letter {
+            'C' => 0,
+            'D' => 2,
+            'E' => 4,
+            'F' => 5,
+            'G' => 7,
+            'A' => 9,
+            'B' => 11,
+            _ => return None,
+        };
+        let rest = chars.as_str();
+        let (accidental, mode) = if let Some(mode) = rest.strip_prefix(['#', '♯']) {
+            (1, mode)
+        } else if let Some(mode) = rest.strip_prefix(['b', '♭']) {
+            (11, mode)
+        } else {
+            (0, rest)
+        };
+        let minor = match mode.trim().to_ascii_lowercase().as_str() {
+            "" | "maj" | "major" => false,
+            "m" | "min" | "minor" => true,
+            _ => return None,
+        };
+        Some(Key {
+            tonic: (natural + accidental) % 12,
+            minor,
+        })
+    }
+
+    fn parse_camelot(text: &str) -> Option<Key> {
+        let letter = text.chars().last()?;
+        let number: u8 = text[..text.len() - letter.len_utf8()].parse().ok()?;
+        let letter = letter.to_ascii_uppercase();
+        if !(1..=12).contains(&number) || !matches!(letter, 'A' | 'B') {
+            return None;
+        }
+        // 8B is C major; each step round the wheel is a fifth up.
+        let tonic = ((number as u32 + 4) * 7 % 12) as u8;
+        Some(match letter {
+            'B' => Key {
+                tonic,
+                minor: false,
+            },
+            _ => Key {
+                tonic: (tonic + 9) % 12,
+                minor: true,
+            },
+        })
+    }
+
+    /// Standard notation, e.g. "C#m"
+    pub fn name(self) -> String {
+        let suffix = if self.minor { "m" } else { "" };
+        format!("{}{suffix}", NOTE_NAMES[self.tonic as usize])
+    }
+
+    /// Camelot wheel notation, e.g. "8A" for A minor
+    pub fn camelot(self) -> String {
+        let (major_tonic, letter) = match self.minor {
+            true => ((self.tonic + 3) % 12, 'A'),
+            false => (self.tonic, 'B'),
+        };
+        let number = (major_tonic as u32 * 7 + 7) % 12 + 1;
+        format!("{number}{letter}")
+    }
+
+    /// Keys that mix well with this one: itself, its neighbours on the Camelot wheel
+    /// and its relative major or minor
+    pub fn compatible(self) -> [Key; 4] {
+        let fifth = |steps: u8| Key {
+            tonic: (self.tonic + steps) % 12,
+            minor: self.minor,
+        };
+        let relative = match self.minor {
+            true => Key {
+                tonic: (self.tonic + 3) % 12,
+                minor: false,
+            },
+            false => Key {
+                tonic: (self.tonic + 9) % 12,
+                minor: true,
+            },
+        };
+        [self, fifth(7), fifth(5), relative]
+    }
+}
+
+/// Pitch class energy of decoded audio, gathered a window at a time
+pub(crate) struct Chromagram {
+    fft: Arc<dyn Fft<f32>>,
+    window: Vec<f32>,
+    buffer: Vec<f32>,
+    scratch: Vec<Complex<f32>>,
+    /// FFT bins looked at, with the pitch class each falls in
+    bins: Vec<(usize, usize)>,
+    chroma: [f32; 12],
+}
+
+impl Chromagram {
+    pub fn new(sample_rate: u32) -> Self {
+        // Around a tenth of a second, long enough to tell neighbouring bass notes apart
+        let size = (sample_rate as usize / 10).next_power_of_two();
+        let bin_hz = sample_rate as f32 / size as f32;
+        let bins = (1..size / 2)
+            .map(|bin| (bin, bin as f32 * bin_hz))
+            .filter(|&(_, frequency)| (MIN_FREQUENCY..=MAX_FREQUENCY).contains(&frequency))
+            .map(|(bin, frequency)| {
+                let semitones = (12.0 * (frequency / 440.0).log2()).round() as i32;
+                (bin, (semitones + 9).rem_euclid(12) as usize)
+            })
+            .collect();
+        let window = (0..size)
+            .map(|i| {
+                let phase = 2.0 * std::f32::consts::PI * i as f32 / (size - 1) as f32;
+                0.5 - 0.5 * phase.cos()
+            })
+            .collect();
+        Chromagram {
+            fft: FftPlanner::new().plan_fft_forward(size),
+            window,
+            buffer: Vec::with_capacity(size),
+            scratch: vec![Complex::default(); size],
+            bins,
+            chroma: [0.0; 12],
+        }
+    }
+
+    /// Feed one mono sample
+    pub fn push(&mut self, sample: f32) {
+        self.buffer.push(sample);
+        if self.buffer.len() < self.window.len() {
+            return;
+        }
+        for ((bin, sample), weight) in self.scratch.iter_mut().zip(&self.buffer).zip(&self.window) {
+            *bin = Complex::new(sample * weight, 0.0);
+        }
+        self.buffer.clear();
+        self.fft.process(&mut self.scratch);
+        for &(bin, pitch_class) in &self.bins {
+            self.chroma[pitch_class] += self.scratch[bin].norm();
+        }
+    }
+
+    /// The key whose profile the pitch classes heard match best; `None` for silence
+    pub fn key(&self) -> Option<Key> {
+        if self.chroma.iter().all(|&energy| energy == 0.0) {
+            return None;
+        }
+        (0..12u8)
+            .flat_map(|tonic| {
+                [(false, &MAJOR_PROFILE), (true, &MINOR_PROFILE)]
+                    .map(|(minor, profile)| (Key { tonic, minor }, profile))
+            })
+            .map(|(key, profile)| {
+                let rotated: Vec<f32> = (0..12)
+                    .map(|i| profile[(i + 12 - key.tonic as usize) % 12])
+                    .collect();
+                (key, correlation(&self.chroma, &rotated))
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(key, _)| key)
+    }
+}
+
+/// Pearson correlation of two equally long series
+fn correlation(a: &[f32], b: &[f32]) -> f32 {
+    let mean = |values: &[f32]| values.iter().sum::<f32>() / values.len() as f32;
+    let (mean_a, mean_b) = (mean(a), mean(b));
+    let (mut product, mut square_a, mut square_b) = (0.0, 0.0, 0.0);
+    for (x, y) in a.iter().zip(b) {
+        let (x, y) = (x - mean_a, y - mean_b);
+        product += x * y;
+        square_a += x * x;
+        square_b += y * y;
+    }
+    product / (square_a * square_b).sqrt().max(f32::EPSILON)
+}
@@ -1,65 +1,2575 @@
-use flutter_rust_bridge::{frb, StreamSink};
+use flutter_rust_bridge::frb;
 
-/// Core audio engine for Tunes4R
-pub struct AudioEngine {
-    // TODO: Implement with rodio, rustfft, etc.
-    pub sample_rate: u32,
-}
+#[cfg(feature = "aac")]
+mod aac;
+mod analysis;
+mod app;
+mod artwork;
+mod cast;
+mod chapters;
+mod collation;
+mod cue;
+mod decoder;
+mod drop_folder;
+mod duplicates;
+mod engine;
+mod error;
+mod events;
+mod export;
+mod features;
+mod fingerprint;
+mod flac;
+mod http_stream;
+mod identify;
+mod importer;
+mod integrity;
+mod jobs;
+mod key;
+mod library;
+mod logging;
+mod loudness;
+mod lyrics;
+mod metadata;
+mod offline;
+#[cfg(feature = "opus")]
+mod opus;
+mod podcasts;
+mod profiles;
+mod remote_server;
+mod ripper;
+mod runtime;
+mod scanner;
+mod scrobble;
+mod sources;
+mod silence;
+mod song_id;
+mod storage;
+mod stream;
+mod tag_edit;
+mod tempo;
+#[cfg(feature = "test-fixtures")]
+#[doc(hidden)]
+pub mod test_fixtures;
+mod transcode;
+mod waveform;
+mod watcher;
+
+pub use engine::AudioEngine;
+pub use error::TunesError;
+pub use stream::{SinkClosed, StreamSink};
+
+/// Identifies a song for as long as the library has it, across restarts, retagging
+/// and moves of its file
+///
+/// It's derived from the path and contents of the file the song was first stored from;
+/// songs stored by earlier versions under their path are given one when the library
+/// is opened.
+#[derive(
+    Clone, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord, serde::Serialize, serde::Deserialize,
+)]
+#[serde(transparent)]
+pub struct SongId(pub String);
 
 /// Domain model for a song
-#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
 pub struct Song {
-    pub id: String,
+    pub id: SongId,
     pub title: String,
     pub artist: String,
     pub album: String,
+    /// Artist the album is credited to, if tagged apart from the track artist
+    #[serde(default)]
+    pub album_artist: Option<String>,
+    /// Part of a compilation of several artists' tracks, as tagged or found by
+    /// `detect_compilations`; its album is credited to "Various Artists"
+    #[serde(default)]
+    pub compilation: bool,
+    /// Name the artist sorts under, as tagged or without a leading "The "; from the
+    /// library, always set
+    #[serde(default)]
+    pub artist_sort: Option<String>,
+    /// Title the album sorts under, like `artist_sort`
+    #[serde(default)]
+    pub album_sort: Option<String>,
+    /// Disc of a multi-disc set, from its tag or a "(Disc 2)" album title suffix
+    #[serde(default)]
+    pub disc_number: Option<u32>,
+    #[serde(default)]
+    pub track_number: Option<u32>,
+    pub genre: Option<String>,
+    /// Release year, if tagged
+    pub year: Option<u32>,
+    /// User rating from 1 to 5 stars; `None` if unrated
+    pub rating: Option<u8>,
+    pub favorite: bool,
+    /// Duration in whole seconds
     pub duration: u64,
     pub file_path: String,
+    /// Where the song starts in its file, in seconds; non-zero for CUE sheet tracks
+    pub start_offset: f64,
+    /// Where a CUE sheet track ends in its file; `None` plays to the end of the file
+    pub end_offset: Option<f64>,
+    /// Integrated loudness of the track in LUFS, if measured or tagged
+    pub track_loudness: Option<f32>,
+    /// Integrated loudness of the whole album in LUFS, if known
+    pub album_loudness: Option<f32>,
+    /// Tempo in beats per minute, if tagged or measured
+    pub bpm: Option<f32>,
+    /// Musical key in standard notation, e.g. "C#m", if tagged or measured
+    pub key: Option<String>,
+    /// Tagged with an explicit-content advisory
+    #[serde(default)]
+    pub explicit: bool,
 }
 
-/// Audio playback state
+/// Codec and stream properties of an audio file
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct AudioFormatInfo {
+    /// Short codec name, e.g. "mp3", "flac" or "aac"
+    pub codec: String,
+    pub sample_rate: u32,
+    pub channels: u16,
+    /// Bits per sample for lossless/PCM codecs; `None` for lossy ones
+    pub bit_depth: Option<u32>,
+    pub duration_secs: Option<f64>,
+}
+
+/// How the engine is keeping up, from `get_metrics` and `metrics_stream`
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct EngineMetrics {
+    /// Share of the network stream's buffer holding decoded audio, from 0 to 1;
+    /// `None` for local files, which are decoded as the output asks for them
+    pub buffer_fill: Option<f32>,
+    /// Seconds of audio the network stream has decoded ahead of playback
+    pub buffer_ahead_secs: Option<f64>,
+    /// Blocks the output device ran dry waiting for, since the engine started
+    pub underruns: u64,
+    /// Share of real time spent in the DSP stages, smoothed over a fraction of a second
+    pub dsp_load: f32,
+    /// Share of its deadline the output callback takes, decoding included; glitches
+    /// start past 1
+    pub callback_load: f32,
+    /// Resident memory of the app; `None` where the platform doesn't tell
+    pub memory_bytes: Option<u64>,
+}
+
+/// Configuration of the running output stream, from `get_output_format`
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct OutputFormat {
+    /// Rate the device runs at
+    pub sample_rate: u32,
+    pub channels: u16,
+    /// Device sample format, e.g. "f32" or "i16"
+    pub sample_format: String,
+    /// Rate the engine renders at; the output converts again if it differs from
+    /// `sample_rate`, as after switching to a device that can't run at it
+    pub engine_sample_rate: u32,
+    /// Whether the device is held exclusively, bypassing the system mixer
+    pub exclusive: bool,
+    /// Whether samples at `engine_sample_rate` reach the device unaltered, as
+    /// `set_exclusive_mode` asks for
+    pub bit_perfect: bool,
+    /// Frames the device plays per block, as `set_buffer_size` asks for; `None` for
+    /// the device's default
+    pub buffer_frames: Option<u32>,
+}
+
+/// An audio output device
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct AudioDevice {
+    pub id: String,
+    pub name: String,
+    pub is_default: bool,
+    /// Audio host the device is on, like "ALSA", "WASAPI", "JACK" or "ASIO"
+    #[serde(default)]
+    pub host: String,
+}
+
+/// A device `set_zones` mirrors the main output to
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ZoneConfig {
+    /// Id from `list_output_devices`
+    pub device_id: String,
+    /// From 0 to 1, after the main volume
+    pub volume: f32,
+    /// Extra delay to line the zone up with the others
+    pub delay_ms: u32,
+}
+
+/// How a cast device is controlled
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum CastProtocol {
+    /// UPnP AVTransport, as DLNA renderers, smart TVs and many receivers speak
+    Dlna,
+    /// Google Cast, playing through the default media receiver
+    Chromecast,
+}
+
+/// A renderer or Chromecast on the local network, from `discover_cast_devices`
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct CastDevice {
+    /// The device's UDN or Cast id, which stay the same across restarts
+    pub id: String,
+    pub name: String,
+    pub model: Option<String>,
+    pub protocol: CastProtocol,
+    /// URL of a renderer's device description, or a Chromecast's `address:port`
+    pub location: String,
+}
+
+/// How `start_remote_server` serves the library
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct RemoteServerOptions {
+    /// Port to listen on; 0 for any free one
+    pub port: u16,
+    /// Listen on every interface, so other devices on the network can connect, rather
+    /// than on this device only
+    pub lan: bool,
+    /// Token clients already paired with, to keep them paired across restarts; `None`
+    /// makes a new one
+    pub token: Option<String>,
+}
+
+/// A running remote server
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct RemoteServerInfo {
+    pub port: u16,
+    /// Sent by clients as `Authorization: Bearer <token>`, or as `?token=` where they
+    /// can't set headers, as in a stream URL
+    pub token: String,
+}
+
+/// An artist row from the library database
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Artist {
+    pub id: i64,
+    pub name: String,
+}
+
+/// An album from the library database with its songs
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Album {
+    pub id: i64,
+    pub title: String,
+    pub artist_id: i64,
+    pub artist: String,
+    /// Integrated loudness of the album in LUFS, if known
+    pub loudness: Option<f32>,
+    pub songs: Vec<Song>,
+}
+
+/// An album without its song list, as returned by searches
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct AlbumSummary {
+    pub id: i64,
+    pub title: String,
+    pub artist_id: i64,
+    pub artist: String,
+    /// Earliest year tagged on its songs
+    pub year: Option<u32>,
+    pub song_count: u32,
+    /// Total length in seconds
+    pub duration: u64,
+}
+
+/// An artist with how much of the library is theirs, for browsing
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ArtistSummary {
+    pub id: i64,
+    pub name: String,
+    pub album_count: u32,
+    pub song_count: u32,
+    pub play_count: u32,
+}
+
+/// A genre as tagged on library songs
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct GenreSummary {
+    pub name: String,
+    pub artist_count: u32,
+    pub album_count: u32,
+    pub song_count: u32,
+}
+
+/// Songs released in a decade, e.g. 1990 for 1990 to 1999
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct DecadeSummary {
+    pub decade: u32,
+    pub album_count: u32,
+    pub song_count: u32,
+}
+
+/// Order of browse lists; ties are broken by name
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum BrowseSort {
+    Name,
+    /// Oldest first, by the earliest year tagged; untagged last
+    Year,
+    /// Most recently added first
+    DateAdded,
+    /// Most plays across all songs first
+    MostPlayed,
+}
+
+/// A saved playlist; its songs are fetched with `get_playlist_songs`
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Playlist {
+    pub id: i64,
+    pub name: String,
+    /// Creation time in seconds since the Unix epoch
+    pub created_at: i64,
+    pub song_count: u32,
+}
+
+/// A library of its own, with separate songs, playlists and history, for one person
+/// sharing the app
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Profile {
+    pub id: String,
+    pub name: String,
+    /// Seconds since the Unix epoch
+    pub created_at: i64,
+}
+
+/// What a library snapshot holds, from `export_library_snapshot` and
+/// `import_library_snapshot`
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct SnapshotInfo {
+    pub format_version: u32,
+    /// Library schema the snapshot was taken at
+    pub schema_version: u32,
+    /// Seconds since the Unix epoch
+    pub created_at: i64,
+    /// Version of the app that took it
+    pub app_version: String,
+    pub songs: u32,
+    pub playlists: u32,
+}
+
+/// Another player whose library `import_library` reads
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum LibraryFormat {
+    /// `Library.xml`, from File > Library > Export Library
+    ITunes,
+    /// The iTunes-compatible `iTunes Music Library.xml` MusicBee can keep up to date
+    /// next to its own library
+    MusicBee,
+    /// `rhythmdb.xml`, with `playlists.xml` beside it, in `~/.local/share/rhythmbox`
+    Rhythmbox,
+}
+
+/// What `import_library` matched and brought over, or would have for a dry run
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ImportReport {
+    /// Tracks in the other library
+    pub tracks: u32,
+    /// Tracks matched to a library song at the same path
+    pub matched_by_path: u32,
+    /// Tracks matched by artist, title and length, e.g. after the files moved
+    pub matched_by_tags: u32,
+    pub unmatched: Vec<UnmatchedTrack>,
+    pub playlists: Vec<PlaylistImport>,
+    /// False for a dry run, which changes nothing
+    pub applied: bool,
+}
+
+/// A track of another library no song was found for, as that library has it
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct UnmatchedTrack {
+    pub title: String,
+    pub artist: String,
+    pub album: String,
+    pub path: Option<String>,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct PlaylistImport {
+    pub name: String,
+    pub tracks: u32,
+    /// Tracks matched to songs; the rest are left out of the playlist
+    pub matched: u32,
+    /// A playlist of that name exists already, so this one isn't imported
+    pub already_exists: bool,
+}
+
+/// A playlist whose songs are whatever currently matches its rules
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct SmartPlaylist {
+    pub id: i64,
+    pub name: String,
+    pub rules: SmartRules,
+    /// Creation time in seconds since the Unix epoch
+    pub created_at: i64,
+}
+
+/// Songs to keep out of a profile's library, e.g. for children; rules match ignoring
+/// case
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct ContentFilter {
+    /// Hide songs tagged with an explicit-content advisory (`ITUNESADVISORY`)
+    pub block_explicit: bool,
+    /// Hide songs of these genres
+    pub genres: Vec<String>,
+    /// Hide songs with any of these words in their title, artist or album
+    pub keywords: Vec<String>,
+}
+
+/// Which songs a smart playlist holds and in what order
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct SmartRules {
+    /// Whether a song has to match every rule or just one of them
+    pub match_all: bool,
+    pub rules: Vec<SmartRule>,
+    pub order: SmartOrder,
+    /// Keep only this many songs, after ordering
+    pub limit: Option<u32>,
+}
+
+/// One condition of a smart playlist
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub enum SmartRule {
+    Text { field: TextField, op: TextOp, value: String },
+    Number { field: NumberField, op: NumberOp, value: f64 },
+    /// Whether the date falls within (or outside) the last `days` days
+    Date { field: DateField, within: bool, days: u32 },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum TextField {
+    Title,
+    Artist,
+    Album,
+    Genre,
+}
+
+/// Text comparisons, all ignoring case
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum TextOp {
+    Is,
+    IsNot,
+    Contains,
+    DoesNotContain,
+    StartsWith,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum NumberField {
+    /// Stars from 1 to 5; unrated songs count as 0
+    Rating,
+    PlayCount,
+    /// Duration in seconds
+    Duration,
+    /// Tempo in beats per minute; songs without one never match
+    Bpm,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum NumberOp {
+    Equal,
+    NotEqual,
+    Greater,
+    GreaterOrEqual,
+    Less,
+    LessOrEqual,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum DateField {
+    /// Never-played songs are outside any range
+    LastPlayed,
+    DateAdded,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum SmartOrder {
+    Random,
+    Title,
+    Artist,
+    Album,
+    MostPlayed,
+    RecentlyPlayed,
+    RecentlyAdded,
+    HighestRated,
+    /// Slowest first, songs without a tempo last
+    Bpm,
+}
+
+/// A recording an unidentified file may be, from a fingerprint lookup
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct IdentificationCandidate {
+    /// MusicBrainz recording id
+    pub recording_id: String,
+    pub title: String,
+    pub artist: String,
+    pub album: Option<String>,
+    /// Length of the recording in seconds, if MusicBrainz knows it
+    pub duration: Option<u64>,
+    /// How likely the file is this recording, from 0 to 1
+    pub confidence: f32,
+}
+
+/// An online service that listens are reported to
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum ScrobbleService {
+    LastFm,
+    ListenBrainz,
+}
+
+/// A library song with how often and when it was last played
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct PlayedSong {
+    pub song: Song,
+    pub play_count: u32,
+    /// Last counted play in seconds since the Unix epoch
+    pub last_played: i64,
+}
+
+/// Listening totals of an artist or album
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ListeningStats {
+    /// Artist or album id
+    pub id: i64,
+    pub name: String,
+    pub play_count: u32,
+    /// Time listened, counting every play of a song as its full duration
+    pub listened_secs: u64,
+    pub last_played: i64,
+}
+
+/// One page of `get_songs_page`
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct SongPage {
+    pub songs: Vec<Song>,
+    /// Pass to `get_songs_page` for the next page; `None` after the last one
+    pub next_cursor: Option<String>,
+}
+
+/// Ranked matches for a library search, best first
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct SearchResults {
+    pub songs: Vec<Song>,
+    pub artists: Vec<Artist>,
+    pub albums: Vec<AlbumSummary>,
+}
+
+/// A subscribed podcast feed; its episodes are fetched with `get_podcast_episodes`
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Podcast {
+    pub id: i64,
+    pub feed_url: String,
+    pub title: String,
+    pub author: Option<String>,
+    pub description: String,
+    pub image_url: Option<String>,
+    /// Last successful feed refresh in seconds since the Unix epoch
+    pub last_refreshed: i64,
+    pub episode_count: u32,
+}
+
+/// An episode of a subscribed podcast
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct PodcastEpisode {
+    pub id: i64,
+    pub podcast_id: i64,
+    pub title: String,
+    /// Show notes as plain text, with the feed's HTML markup removed
+    pub show_notes: String,
+    pub audio_url: String,
+    /// Publication time in seconds since the Unix epoch
+    pub published_at: Option<i64>,
+    pub duration_secs: Option<u64>,
+    /// Size of the audio file in bytes, as announced by the feed
+    pub file_size: Option<u64>,
+    /// Local file of a finished download, playable like any library song
+    pub download_path: Option<String>,
+}
+
+/// Lyrics of a song; in `synced` lyrics every line has a start time
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct Lyrics {
+    pub synced: bool,
+    pub lines: Vec<LyricLine>,
+}
+
+/// One line of lyrics
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct LyricLine {
+    /// Start time in seconds, for synced lyrics
+    pub time: Option<f64>,
+    pub text: String,
+}
+
+/// A chapter of an audiobook or other long recording
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Chapter {
+    pub title: String,
+    /// Start and end within the file in seconds
+    pub start: f64,
+    pub end: f64,
+}
+
+/// Where a remote library source lives and how to sign in to it
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub enum SourceConfig {
+    /// A WebDAV folder, e.g. on Nextcloud, with basic auth if a username is given
+    WebDav {
+        url: String,
+        username: Option<String>,
+        password: Option<String>,
+    },
+    /// A bucket of an S3-compatible store, addressed path style, optionally only the
+    /// objects below `prefix`; an empty region signs for us-east-1
+    S3 {
+        endpoint: String,
+        region: String,
+        bucket: String,
+        prefix: String,
+        access_key_id: String,
+        secret_access_key: String,
+    },
+    /// A Subsonic-compatible media server such as Navidrome, whose tags and stars are
+    /// used as they are; songs above `max_bitrate_kbps`, or in a codec that can't be
+    /// decoded, are streamed converted to MP3
+    Subsonic {
+        url: String,
+        username: String,
+        password: String,
+        max_bitrate_kbps: Option<u32>,
+    },
+}
+
+/// A remote store the library indexes and streams songs from
+///
+/// Its songs have paths like `source://<id>/<path in the source>`, which everything
+/// that takes a song's path reads through the remote cache.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct LibrarySource {
+    pub id: i64,
+    pub name: String,
+    pub config: SourceConfig,
+    /// Creation time in seconds since the Unix epoch
+    pub created_at: i64,
+}
+
+/// Audio playback state
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
 pub enum PlaybackState {
     Stopped,
     Playing,
     Paused,
     Loading,
+    /// A seek is in progress; the previous state returns once it completes
+    Seeking,
+    /// A network stream is playing silence until `percent` of its buffer has filled
+    Buffering { percent: u8 },
+}
+
+/// Whether the queue plays in order or in a fixed shuffled order
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum ShuffleMode {
+    Off,
+    On,
+}
+
+/// What happens when a song or the end of the queue is reached
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum RepeatMode {
+    Off,
+    One,
+    All,
+}
+
+/// How `set_auto_dj` keeps the queue going
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct AutoDjParams {
+    /// Top the queue up once fewer songs than this are left to play
+    pub min_upcoming: u32,
+    /// Songs added each time
+    pub batch_size: u32,
+    /// Leave out songs played within this many hours
+    pub skip_played_within_hours: u32,
+    /// Largest tempo difference from the song before, in BPM; zero allows any
+    pub max_bpm_difference: f32,
+    /// Only add songs in a key that mixes harmonically with the song before
+    pub harmonic_only: bool,
+}
+
+/// Severity of a log record, most severe first
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+/// A log record kept for `get_recent_logs`
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct LogRecord {
+    /// Milliseconds since the Unix epoch
+    pub timestamp_ms: i64,
+    pub level: LogLevel,
+    /// Module the record came from
+    pub target: String,
+    pub message: String,
+}
+
+/// Engine configuration that `set_settings_file` keeps across launches
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct EngineSettings {
+    pub eq_enabled: bool,
+    /// Gain of each equalizer band in dB, in the order of `get_eq_bands`
+    pub eq_gains: Vec<f32>,
+    pub gapless: bool,
+    /// Zero turns crossfading off
+    pub crossfade_ms: u32,
+    pub crossfade_curve: FadeCurve,
+    pub normalization: NormalizationMode,
+    pub target_lufs: f32,
+    /// `None` follows the system default
+    pub output_device: Option<String>,
+    pub visualizer_bands: u32,
+    pub visualizer_fps: u32,
+    /// Samples per FFT window; zero for the default
+    pub visualizer_window: u32,
+}
+
+/// Changes for `update_settings`; settings left `None` keep their value
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct SettingsPatch {
+    pub eq_enabled: Option<bool>,
+    pub eq_gains: Option<Vec<f32>>,
+    pub gapless: Option<bool>,
+    pub crossfade_ms: Option<u32>,
+    pub crossfade_curve: Option<FadeCurve>,
+    pub normalization: Option<NormalizationMode>,
+    pub target_lufs: Option<f32>,
+    /// An empty id goes back to the system default
+    pub output_device: Option<String>,
+    pub visualizer_bands: Option<u32>,
+    pub visualizer_fps: Option<u32>,
+    pub visualizer_window: Option<u32>,
+}
+
+/// Which loudness value playback volume is normalized against
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum NormalizationMode {
+    Off,
+    Track,
+    Album,
+}
+
+/// Gain curve used when fading between tracks
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum FadeCurve {
+    Linear,
+    EqualPower,
+    SCurve,
+}
+
+/// A press of a headset button, media key or lock screen control, for
+/// `handle_media_command`
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum MediaCommand {
+    /// Pressed twice in quick succession skips to the next song, three times to the
+    /// previous one, as single-button headsets do
+    PlayPause,
+    Next,
+    Previous,
+    Stop,
+    SeekTo { position_secs: f64 },
+    /// Skip a few seconds ahead
+    FastForward,
+    /// Skip a few seconds back
+    Rewind,
+}
+
+/// When a sleep timer stops playback once its time is up
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum SleepTimerMode {
+    /// Fade out and pause right away
+    StopAfterTime,
+    /// Let the current track finish, then stop
+    StopAfterTrack,
+}
+
+/// A stage of the playback DSP chain
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum DspStage {
+    /// Conversion to the output's sample rate and channel layout
+    Resampler,
+    Equalizer,
+    /// Tempo and pitch changes
+    TimeStretch,
+    /// Loudness normalization gain
+    Normalization,
+    /// Preamp and balance
+    Preamp,
+    /// Soft clipping of peaks
+    Limiter,
+    Reverb,
+    StereoWidener,
+    /// Mono downmix or swapped channels
+    ChannelMode,
+    /// Shortening of pauses in spoken word
+    PauseSkipping,
+    /// Compression and peak limiting from `set_dynamics`
+    Compressor,
+}
+
+/// How the stereo channels reach the outputs, from `set_channel_mode`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum ChannelMode {
+    Stereo,
+    /// Both channels mixed into each output, for listening with one ear
+    Mono,
+    /// Left and right swapped
+    SwapLR,
+}
+
+/// File format for `start_recording`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum RecordingFormat {
+    Wav,
+    Flac,
+}
+
+/// Reverb from `set_reverb`, each setting from 0.0 to 1.0
+#[derive(Clone, Copy, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ReverbSettings {
+    pub room_size: f32,
+    pub damping: f32,
+    /// 0.0 is off
+    pub wet: f32,
+}
+
+/// A short clip for `play_clip` to mix over the music
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub enum AnnouncementClip {
+    /// An audio file in any format playback supports
+    File { path: String },
+    /// Interleaved samples, like speech from a TTS engine
+    Pcm {
+        samples: Vec<f32>,
+        sample_rate: u32,
+        channels: u16,
+    },
+}
+
+/// When `play_clip` plays a clip
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum ClipTiming {
+    /// After any clips already playing or waiting
+    Now,
+    /// Once the next track starts, over its beginning
+    NextTrack,
+}
+
+/// Dynamic range compression from `set_dynamics`: one of the presets, or settings of
+/// its own
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum Dynamics {
+    Off,
+    /// Gentle compression that brings loud scenes and quiet dialogue closer together,
+    /// for listening at low volume
+    NightMode,
+    /// Strong compression with a large boost, so speech in podcasts and audiobooks
+    /// stays clear in a noisy place
+    VoiceBoost,
+    /// Heavy compression to be heard over road noise
+    Car,
+    Custom { settings: DynamicsSettings },
+}
+
+/// How the compressor of `set_dynamics` works
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct DynamicsSettings {
+    /// Level above which the signal is turned down, in dBFS (-60 to 0)
+    pub threshold_db: f32,
+    /// How much of the level above the threshold is kept: 4.0 keeps a quarter (1 to 20)
+    pub ratio: f32,
+    /// How quickly it turns down a louder passage, in ms (0.1 to 200)
+    pub attack_ms: f32,
+    /// How quickly it comes back up after one, in ms (10 to 2000)
+    pub release_ms: f32,
+    /// Gain added after compression, in dB (0 to 24)
+    pub makeup_db: f32,
+    /// Peak level the limiter keeps the output under, in dBFS (-12 to 0)
+    pub ceiling_db: f32,
+}
+
+/// A stage of the DSP chain, from `get_dsp_chain`
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct DspStageInfo {
+    pub stage: DspStage,
+    pub enabled: bool,
+    /// Applied to each track on its own, so crossfading tracks keep their own settings
+    pub per_track: bool,
+}
+
+/// Section of the current song that `set_loop_region` repeats
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct LoopRegion {
+    pub start_secs: f64,
+    pub end_secs: f64,
+}
+
+/// What an OS media session shows, from `now_playing_stream`
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct NowPlaying {
+    pub state: PlaybackState,
+    pub song: Option<Song>,
+    /// Cover art thumbnail (JPEG) of `song`, if it has any and a library is open
+    pub artwork: Option<Vec<u8>>,
+    /// Title the radio station currently sends, if `song` is a stream
+    pub stream_title: Option<String>,
+    /// Position when this was sent, in seconds; it moves on at normal speed while
+    /// `Playing`
+    pub position_secs: f64,
+    pub duration_secs: f64,
+    pub can_skip_next: bool,
+    pub can_skip_previous: bool,
+}
+
+/// Engine state from `get_engine_state`, matching what the latest events reported
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct EngineSnapshot {
+    pub state: PlaybackState,
+    pub song: Option<Song>,
+    /// Position in the current song, in seconds
+    pub current_time: f64,
+    pub total_time: f64,
+    pub queue: Vec<Song>,
+    pub current_index: Option<u32>,
+    pub shuffle: ShuffleMode,
+    pub repeat: RepeatMode,
+    pub volume: f32,
+    pub muted: bool,
+    pub eq_enabled: bool,
+    /// Gain of each of `get_eq_bands`, in dB
+    pub eq_gains: Vec<f32>,
+    pub preamp_db: f32,
+    pub balance: f32,
+    pub loop_region: Option<LoopRegion>,
+    pub reverb: ReverbSettings,
+    pub stereo_width: f32,
+    pub channel_mode: ChannelMode,
+    pub dynamics: Dynamics,
+    pub dsp_chain: Vec<DspStageInfo>,
 }
 
 /// Event types for reactive UI updates
+// Events are handed across the bridge by value, so songs aren't boxed.
+#[allow(clippy::large_enum_variant)]
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub enum AudioEvent {
     PlaybackStateChanged { state: PlaybackState, song: Option<Song> },
-    SpectrumDataUpdated { frequencies: Vec<f32> },
+    /// Band levels of the output, meant to be shown once the wall clock reaches
+    /// `presentation_time_ms` since the Unix epoch, when `position_secs` of the track
+    /// is heard; output latency is already accounted for
+    SpectrumDataUpdated {
+        frequencies: Vec<f32>,
+        position_secs: f64,
+        presentation_time_ms: f64,
+    },
+    /// A beat in the output, `confidence` from 0 to 1 saying how clearly it stood out
+    /// and `bpm` the tempo of the last few seconds; timed like `SpectrumDataUpdated`
+    BeatDetected {
+        confidence: f32,
+        bpm: f32,
+        position_secs: f64,
+        presentation_time_ms: f64,
+    },
+    /// Mono output samples the matching `SpectrumDataUpdated` levels were taken from,
+    /// oldest first, timed the same way
+    WaveformFrame {
+        samples: Vec<f32>,
+        position_secs: f64,
+        presentation_time_ms: f64,
+    },
     ProgressUpdated { current_time: f64, total_time: f64 },
+    TrackTransition { previous: Option<Song>, song: Song },
+    QueueChanged { songs: Vec<Song>, current_index: Option<u32> },
+    /// Songs auto-DJ appended to the queue, as `QueueChanged` reports them too
+    AutoDjAdded { songs: Vec<Song> },
+    VolumeChanged { volume: f32, muted: bool },
+    /// From `set_preamp` and `set_balance`
+    DspSettingsChanged { preamp_db: f32, balance: f32 },
+    /// From `set_reverb`
+    ReverbChanged { settings: ReverbSettings },
+    /// From `set_stereo_width`
+    StereoWidthChanged { width: f32 },
+    /// From `set_channel_mode`
+    ChannelModeChanged { mode: ChannelMode },
+    /// From `set_dynamics`
+    DynamicsChanged { dynamics: Dynamics },
+    /// A clip from `play_clip` began to play, the music now ducked under it
+    ClipStarted { id: u32 },
+    /// A clip from `play_clip` played to its end or was stopped by `stop_clips`
+    ClipFinished { id: u32 },
+    /// With `set_pause_skipping` on, after each shortened pause
+    SilenceSkipped { total_saved_secs: f64 },
+    /// From `set_stage_enabled`
+    DspChainChanged { stages: Vec<DspStageInfo> },
+    /// From `set_loop_region`; `None` once cleared or another song loads
+    LoopRegionChanged { region: Option<LoopRegion> },
+    /// The file `start_recording` is writing to, which changes at each new stream title;
+    /// `None` after `stop_recording`
+    RecordingChanged { path: Option<String> },
+    /// Writing the recording failed and it stopped
+    RecordingFailed { message: String },
+    Seeked { position: f64 },
+    /// The system woke after sleeping about `slept_secs`; the output was reopened and
+    /// playback carries on from `position_secs`
+    ResumedFromSuspend { slept_secs: f64, position_secs: f64 },
+    DeviceChanged { device: AudioDevice },
+    /// The output stream was (re)opened or `set_exclusive_mode` changed
+    OutputFormatChanged { format: OutputFormat },
+    DeviceDisconnected { device: AudioDevice },
+    PlaybackInterrupted { active: bool },
+    SleepTimerFired { mode: SleepTimerMode },
+    StreamMetadataUpdated { title: Option<String>, bitrate: Option<u32> },
+    LyricLineChanged { index: u32, text: String },
+    ChapterChanged { index: u32, title: String },
+    /// From `cast_to` and `stop_casting`, or when the cast device stopped answering;
+    /// `None` once playing on this device again
+    CastChanged { device: Option<CastDevice> },
+    /// From `preview_play`; `None` once the preview ended or was stopped
+    PreviewChanged { song: Option<Song> },
+    /// A song couldn't be previewed
+    PreviewFailed { message: String },
+    /// From `set_zones`, or when a zone was dropped
+    ZonesChanged { zones: Vec<ZoneConfig> },
+    /// A zone's device couldn't be opened or went away, and it was dropped
+    ZoneFailed { device_id: String, message: String },
+    /// The engine settings changed, through `update_settings` or one of the setters
+    SettingsChanged { settings: EngineSettings },
+    /// Part of the engine panicked and was restarted: "playback" when decoding or
+    /// processing a song, which is skipped, "stream" for a network stream's decoder,
+    /// or "engine" itself, which stops playback
+    EngineError { context: String, message: String },
 }
 
-/// FFI API exposed to Flutter
-#[frb(sync)]
-pub fn create_audio_engine() -> AudioEngine {
-    AudioEngine { sample_rate: 44100 }
+/// A file scans couldn't read, kept out of the library until it reads again
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct QuarantinedFile {
+    pub id: i64,
+    /// The file, or the CUE sheet splitting it
+    pub path: String,
+    /// Why it couldn't be read, the last time it was tried
+    pub reason: String,
+    /// Seconds since the Unix epoch
+    pub failed_at: i64,
+    /// Times it was tried and failed
+    pub attempts: u32,
 }
 
-#[frb(sync)]
-pub fn get_next_free_id() -> u32 {
-    use std::sync::atomic::{AtomicU32, Ordering};
-    static COUNTER: AtomicU32 = AtomicU32::new(0);
-    COUNTER.fetch_add(1, Ordering::Relaxed)
+/// Progress of a library scan started with `scan_library`
+// Like `AudioEvent`, handed across the bridge by value.
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub enum ScanEvent {
+    Discovered { files_found: u32 },
+    Parsed { song: Song, files_parsed: u32, files_found: u32 },
+    Failed { path: String, message: String },
+    Finished { files_found: u32, files_parsed: u32, errors: u32, cancelled: bool },
 }
 
-#[frb(init)]
-pub fn init_app() {
-    // App initialization code
-    tracing_subscriber::fmt::init();
+/// Progress and result of `generate_waveform`
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub enum WaveformEvent {
+    Progress { fraction: f32 },
+    Finished { peaks: Vec<f32>, rms: Vec<f32> },
+    Failed { message: String },
+    Cancelled,
 }
 
-#[frb(stream)]
-pub fn tick_stream(sink: StreamSink<i32>) {
-    // TODO: Replace with actual audio events
-    tokio::spawn(async move {
-        for i in 0..100 {
-            sink.add(i).unwrap();
-            tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
-        }
-    });
+/// Progress and result of `download_episode`
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub enum DownloadEvent {
+    Progress { downloaded_bytes: u64, total_bytes: Option<u64> },
+    Finished { path: String },
+    Failed { message: String },
+    Cancelled,
+}
+
+/// A remote song or podcast episode that can be kept on the device to play offline
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum OfflineItem {
+    /// A song of a remote library source; local songs play offline anyway
+    Song { song_id: SongId },
+    Episode { episode_id: i64 },
+}
+
+/// Whether an offline item is on the device, from `get_download_state` and
+/// `watch_downloads`
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum DownloadState {
+    NotDownloaded,
+    /// Pinned, waiting for a free download slot
+    Queued,
+    Downloading {
+        downloaded_bytes: u64,
+        total_bytes: Option<u64>,
+    },
+    /// Unpinned copies are evicted, least recently played first, once offline copies
+    /// take more than the quota
+    Downloaded { pinned: bool, size_bytes: u64 },
+    /// The download stopped; the item is no longer pinned
+    Failed { message: String },
+}
+
+/// The download state of an item, as it changes
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct OfflineItemState {
+    pub item: OfflineItem,
+    pub state: DownloadState,
+}
+
+/// How `init_app` sets up the app, and how `create_audio_engine` sets up an engine
+///
+/// Fields left out keep what is in effect: the default device's sample rate, `Info`
+/// logging, the root sort order and no library. Each call only reads the fields it
+/// sets up.
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct EngineConfig {
+    /// Library database to open, as `open_library` does
+    pub library_path: Option<String>,
+    /// Where caches, downloads and temporary files go, as `init_storage` takes them
+    pub storage: Option<StorageConfig>,
+    pub log_level: Option<LogLevel>,
+    /// Rate the engine renders at
+    pub sample_rate: Option<u32>,
+    /// How library lists sort, as `set_sort_locale` takes it
+    pub locale: Option<String>,
+    /// File the engine keeps its settings in, as `set_settings_file` takes it
+    pub settings_path: Option<String>,
+    /// File the engine saves its playback state to, as `set_state_file` takes it
+    pub state_path: Option<String>,
+}
+
+/// Directories the platform gives the app for its files, for `init_storage`
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct StorageConfig {
+    /// For files that can be made again, like artwork thumbnails, waveforms and audio
+    /// fetched from remote sources; the OS may empty it
+    pub cache_dir: String,
+    /// For downloads that have to stay, offline copies and podcast episodes
+    pub data_dir: String,
+    /// For files only needed while they're worked on; `cache_dir` without one
+    pub temp_dir: Option<String>,
+}
+
+/// A kind of file the app keeps, for `get_storage_usage` and `clear_cache`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum StorageKind {
+    /// Cover art thumbnails
+    Artwork,
+    Waveforms,
+    /// Audio of remote sources, kept within `set_remote_cache_limit`
+    RemoteAudio,
+    /// Copies made by `pin_offline`
+    Offline,
+    /// Episodes from `download_episode`
+    Podcasts,
+    Temp,
+}
+
+/// Disk space taken by one kind of file
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct StorageUsage {
+    pub kind: StorageKind,
+    /// Directory the files are kept in
+    pub path: String,
+    pub bytes: u64,
+    pub files: u32,
+}
+
+/// How `find_duplicates` decides two songs are the same
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum DuplicateStrategy {
+    /// Same title and artist, ignoring case and punctuation, and about the same length
+    ByTags,
+    /// Audio that sounds the same, even in different formats or bitrates; slow, since
+    /// every file is decoded
+    ByAudioFingerprint,
+    /// Byte-for-byte identical files
+    ByFileHash,
+}
+
+/// Library songs found to be copies of each other, in library order
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct DuplicateGroup {
+    pub songs: Vec<Song>,
+}
+
+/// Progress and result of `find_duplicates`
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub enum DuplicateEvent {
+    Progress { checked: u32, total: u32 },
+    Finished { groups: Vec<DuplicateGroup> },
+    Failed { message: String },
+    Cancelled,
+}
+
+/// What `verify_library` found wrong with the open library
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct IntegrityReport {
+    /// Songs whose file is gone
+    pub missing_files: Vec<Song>,
+    /// Songs whose file is empty, as after a failed copy
+    pub empty_files: Vec<Song>,
+    /// Playlist entries pointing at songs the library doesn't have
+    pub dangling_playlist_entries: u32,
+    /// Cached album art thumbnails of songs no longer in the library
+    pub stale_art: u32,
+}
+
+/// What `repair_library` fixes of what `verify_library` finds
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct RepairOptions {
+    /// Folders to look for missing files in, by file name; a match is relinked when it
+    /// is identical to the file last scanned, or the only unscanned file of that name
+    pub relink_roots: Vec<String>,
+    /// Remove songs whose file is still missing after relinking, or empty
+    pub remove_missing: bool,
+    pub remove_dangling_entries: bool,
+    pub remove_stale_art: bool,
+}
+
+/// What `repair_library` did
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct RepairSummary {
+    /// Missing files whose songs now point at where they were found, keeping their
+    /// ratings, plays and playlist entries
+    pub relinked_files: u32,
+    pub removed_songs: u32,
+    pub removed_playlist_entries: u32,
+    pub removed_art: u32,
+}
+
+/// Progress and result of `verify_library` and `repair_library`
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub enum IntegrityEvent {
+    Progress { checked: u32, total: u32 },
+    /// From `verify_library`
+    Verified { report: IntegrityReport },
+    /// From `repair_library`
+    Repaired { summary: RepairSummary },
+    Failed { message: String },
+    Cancelled,
+}
+
+/// Silence at the start and end of a track, from `detect_silence`
+#[derive(Clone, Copy, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct TrackSilence {
+    pub leading_secs: f64,
+    pub trailing_secs: f64,
+}
+
+/// Progress and result of `analyze_silence`
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub enum SilenceEvent {
+    Progress { checked: u32, total: u32 },
+    /// `analyzed` songs were measured; unreadable ones are skipped
+    Finished { analyzed: u32 },
+    Failed { message: String },
+    Cancelled,
+}
+
+/// How a track sounds, from `analyze_tracks` or `get_track_features`
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct TrackFeatures {
+    /// Perceived intensity from 0 (calm) to 1, from loudness, how busy the track is,
+    /// its brightness and how compressed it sounds
+    pub energy: f32,
+    /// From 0 to 1, by how near the tempo is to dance tempos and how busy the track is
+    pub danceability: f32,
+    /// Average spectral centroid; higher sounds brighter
+    pub brightness_hz: f32,
+    /// Spread between the loud and quiet passages
+    pub dynamic_range_db: f32,
+}
+
+/// Which tracks `get_tracks_by_mood` picks
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum MoodFilter {
+    /// Calm tracks, calmest first
+    Chill,
+    /// Intense tracks, most intense first
+    Energetic,
+    /// Tracks within these ranges of `TrackFeatures`, most intense first
+    Custom {
+        min_energy: f32,
+        max_energy: f32,
+        min_danceability: f32,
+        max_danceability: f32,
+    },
+}
+
+/// Progress and result of `analyze_tracks`
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub enum AnalysisEvent {
+    Progress { checked: u32, total: u32 },
+    /// `analyzed` songs were measured; unreadable ones are skipped
+    Finished { analyzed: u32 },
+    Failed { message: String },
+    Cancelled,
+}
+
+/// Target format of `transcode`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum TranscodeFormat {
+    Mp3,
+    Flac,
+    /// In an Ogg file; needs the `opus` feature
+    Opus,
+    /// AAC-LC in an M4A file; needs the `aac` feature
+    Aac,
+}
+
+/// How `transcode` encodes
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct TranscodeOptions {
+    /// Constant bitrate for lossy formats, rounded to one the format allows; `None`
+    /// for high quality variable bitrate
+    pub bitrate_kbps: Option<u32>,
+    /// Copy the tags and cover art across
+    pub copy_tags: bool,
+}
+
+/// Progress and result of `transcode`
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub enum TranscodeEvent {
+    Progress { fraction: f32 },
+    Finished { path: String },
+    Failed { message: String },
+    Cancelled,
+}
+
+/// How `export_playlist_to_folder` lays out and encodes the tracks
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct ExportOptions {
+    /// Convert tracks to this format; `None` copies the files as they are. Files already
+    /// in the format are always copied.
+    pub format: Option<TranscodeFormat>,
+    pub transcode: TranscodeOptions,
+    /// Path of each track below the folder, without the extension, from `{artist}`,
+    /// `{album}`, `{title}`, `{track}` (its number, two digits) and `{index}` (its
+    /// position in the playlist); empty for "{artist}/{album}/{track} {title}"
+    pub pattern: String,
+    /// Leave files already exported, with the same size for copies, as they are
+    pub skip_existing: bool,
+}
+
+/// Progress and result of `export_playlist_to_folder`
+// Like `AudioEvent`, handed across the bridge by value.
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub enum ExportEvent {
+    /// `fraction` of the whole export, with `done` of `total` tracks finished
+    Progress { done: u32, total: u32, fraction: f32 },
+    /// The track is left out and the export carries on
+    TrackFailed { song: Song, message: String },
+    /// `exported` tracks were written and `skipped` already up to date;
+    /// `playlist_path` is the M3U listing them
+    Finished {
+        exported: u32,
+        skipped: u32,
+        failed: u32,
+        playlist_path: String,
+    },
+    Failed { message: String },
+    Cancelled,
+}
+
+/// An audio CD in a drive, from `read_cd`
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct CdDisc {
+    pub device: String,
+    /// MusicBrainz disc id, worked out from the track offsets
+    pub disc_id: String,
+    /// The rest is from MusicBrainz, `None` if it doesn't know the disc or couldn't be
+    /// reached; edit it before ripping to name and tag the tracks differently
+    pub release_id: Option<String>,
+    pub album: Option<String>,
+    pub artist: Option<String>,
+    pub year: Option<u32>,
+    /// The audio tracks; data tracks are left out
+    pub tracks: Vec<CdTrack>,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct CdTrack {
+    pub number: u32,
+    pub duration_secs: f64,
+    pub title: Option<String>,
+    /// The track's own artist, which differs from the album's on compilations
+    pub artist: Option<String>,
+}
+
+/// How `rip_cd` names and encodes the tracks
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct RipOptions {
+    pub format: TranscodeFormat,
+    pub transcode: TranscodeOptions,
+    /// Numbers of the tracks to rip; empty for all of them
+    pub tracks: Vec<u32>,
+    /// Path of each track below the folder, without the extension, from `{artist}`,
+    /// `{album}`, `{title}` and `{track}`; empty for "{artist}/{album}/{track} {title}"
+    pub pattern: String,
+    /// Add the ripped tracks to the open library
+    pub add_to_library: bool,
+}
+
+/// Progress and result of `rip_cd`
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub enum RipEvent {
+    /// `fraction` of the whole rip, with `done` of `total` tracks finished
+    Progress { done: u32, total: u32, fraction: f32 },
+    /// `rereads` stretches of the track took more than two reads to come out the same
+    /// twice; `unverified_secs` of it never did, or couldn't be read at all and were
+    /// left silent
+    TrackRipped {
+        number: u32,
+        path: String,
+        rereads: u32,
+        unverified_secs: f64,
+    },
+    Finished { ripped: u32 },
+    Failed { message: String },
+    Cancelled,
+}
+
+/// What a background job is for
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum JobKind {
+    Scan,
+    Waveform,
+    Duplicates,
+    Download,
+    Silence,
+    /// Measuring tempo, key and audio features
+    Analysis,
+    Transcode,
+    Export,
+    /// Checking offline copies for damage
+    Verify,
+    /// Reading and encoding tracks off an audio CD
+    Rip,
+    /// Checking the library for missing files and leftovers, and repairing it
+    Integrity,
+    /// Moving files from the drop folder into the library
+    Organize,
+    /// Writing tag edits to many files at once
+    TagEdit,
+}
+
+/// Lifecycle of background jobs, from `watch_jobs`
+///
+/// Each job also reports details and its result on the stream it was started with.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub enum JobProgress {
+    /// Waiting for a free slot; at most two decoding-heavy jobs run at once
+    Queued { job_id: u32, kind: JobKind },
+    Started { job_id: u32 },
+    /// Work done so far, in units of the job's kind: files for scans, songs for
+    /// duplicate searches, bytes for downloads, percent for waveforms and transcodes,
+    /// tracks for exports, copies for offline checks, sectors for CD rips, songs for
+    /// library checks, files for drop folder imports and files for tag edits
+    Progress { job_id: u32, done: u64, total: Option<u64> },
+    Finished { job_id: u32 },
+    Failed { job_id: u32, message: String },
+    Cancelled { job_id: u32 },
+}
+
+/// A queued or running background job
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct JobInfo {
+    pub job_id: u32,
+    pub kind: JobKind,
+    /// False while it waits for a slot
+    pub running: bool,
+    pub done: u64,
+    pub total: Option<u64>,
+}
+
+/// Library updates from `watch_library`
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub enum LibraryEvent {
+    /// Songs added, re-read or moved, and ids of songs no longer in the library; a
+    /// moved song keeps its id and shows up in `updated` with its new path
+    LibraryChanged { updated: Vec<Song>, removed: Vec<SongId> },
+    Failed { path: String, message: String },
+}
+
+/// Where `watch_drop_folder` takes files from and how it files them away
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct DropFolderOptions {
+    /// Folder watched for new files
+    pub folder: String,
+    /// Folder imported files are moved into; it can't be inside `folder`
+    pub library_root: String,
+    /// Path of each file below `library_root`, without the extension, from `{artist}`,
+    /// `{album}`, `{title}` and `{track}`; empty for "{artist}/{album}/{track} {title}"
+    pub pattern: String,
+    /// Look files without a title or artist up on AcoustID first, tagging them when it
+    /// has a confident match; needs `set_acoustid_api_key`
+    pub identify_untagged: bool,
+}
+
+/// Files imported by `watch_drop_folder`
+// Like `AudioEvent`, handed across the bridge by value.
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub enum DropFolderEvent {
+    /// The file at `source` was moved into the library as `song`, tagged from its
+    /// AcoustID match first if `retagged`
+    Imported {
+        source: String,
+        song: Song,
+        retagged: bool,
+    },
+    /// The file is left in the drop folder
+    Failed { path: String, message: String },
+}
+
+/// Tag fields `batch_edit_tags` sets; `None` leaves a field as it is, while empty text
+/// or a year of 0 clears it
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct TagChanges {
+    pub album: Option<String>,
+    pub artist: Option<String>,
+    pub genre: Option<String>,
+    pub year: Option<u32>,
+}
+
+/// Progress and result of `batch_edit_tags`
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub enum TagEditEvent {
+    /// Writing the tags of the file at `path`, after `done` others
+    Progress { done: u32, total: u32, path: String },
+    /// Every file was written; `songs` are the edited songs as now stored
+    Finished { songs: Vec<Song> },
+    /// A file couldn't be written, or the library couldn't store the edit, so the files
+    /// written got their tags back
+    Failed { message: String },
+    /// Cancelled before every file was written; those already written got their tags
+    /// back
+    Cancelled,
+}
+
+/// FFI API exposed to Flutter
+///
+/// Every fallible call returns a `TunesError` rather than panicking, so the app can show
+/// what went wrong and carry on.
+///
+/// The engine takes its sample rate and its settings and state files from `config`, so
+/// engines created side by side can each have their own.
+#[frb(sync)]
+pub fn create_audio_engine(config: EngineConfig) -> Result<AudioEngine, TunesError> {
+    Ok(AudioEngine::with_config(&config)?)
+}
+
+pub fn list_output_devices() -> Result<Vec<AudioDevice>, TunesError> {
+    Ok(engine::list_output_devices()?)
+}
+
+/// DLNA/UPnP renderers and Chromecasts answering on the local network within
+/// `timeout_ms`, for `cast_to`
+pub fn discover_cast_devices(timeout_ms: u32) -> Result<Vec<CastDevice>, TunesError> {
+    let timeout = std::time::Duration::from_millis(timeout_ms as u64);
+    Ok(cast::discover(timeout)?)
+}
+
+/// Serve the open library over HTTP, to this device only unless `options.lan`; returns
+/// the port it listens on and the token clients pair with
+///
+/// `/api/songs`, `/api/artists`, `/api/albums/<id>`, `/api/playlists` and
+/// `/api/search?q=` answer with the same JSON the matching functions here return.
+/// `/stream/<song id>` serves the song's file with range requests, or MP3 encoded on
+/// the fly given `?format=mp3`. Requests without the token are refused, and past a
+/// handful at once are turned away. Replaces any server already running.
+pub fn start_remote_server(options: RemoteServerOptions) -> Result<RemoteServerInfo, TunesError> {
+    Ok(remote_server::start(options)?)
+}
+
+#[frb(sync)]
+pub fn stop_remote_server() {
+    remote_server::stop();
+}
+
+/// Read title/artist/album/duration from the tags of an audio file
+///
+/// A file in the open library gets the id the library has it under.
+pub fn read_song_metadata(path: String) -> Result<Song, TunesError> {
+    let mut song = metadata::read_song(std::path::Path::new(&path))?;
+    library::adopt_stored_id(&mut song)?;
+    Ok(song)
+}
+
+/// Identify the codec and stream format of an audio file
+pub fn probe_file(path: String) -> Result<AudioFormatInfo, TunesError> {
+    Ok(decoder::probe(std::path::Path::new(&path))?)
+}
+
+/// Compute peak and RMS levels of `path` in `buckets` equal slices, for seek bars
+///
+/// Runs as a background job and finishes with `Finished`, `Failed` or `Cancelled` on
+/// `sink`. Returns the job id for `cancel_job`. Results are cached next to the library
+/// database, keyed by the file's contents.
+#[frb(sync)]
+pub fn generate_waveform(path: String, buckets: u32, sink: StreamSink<WaveformEvent>) -> u32 {
+    waveform::start(path.into(), buckets, sink)
+}
+
+/// Write a song's title, artist, album, album artist and genre back into its file's tags
+///
+/// If the song is in the open library, its row is updated to match.
+pub fn write_song_metadata(song: Song) -> Result<(), TunesError> {
+    let updated = metadata::write_song(&song)?;
+    Ok(library::refresh_song(&updated)?)
+}
+
+/// Set the album, artist, genre or year of many songs' files at once, on a background job
+///
+/// Either every file gets `changes` and the library is updated to match, or, should
+/// one fail, the library fail to store them or the job be cancelled, none keeps them.
+/// A cancel once every file is written comes too late. CUE sheet tracks are refused.
+/// Returns a job id that can be passed to `cancel_tag_edit`.
+#[frb(sync)]
+pub fn batch_edit_tags(
+    song_ids: Vec<SongId>,
+    changes: TagChanges,
+    sink: StreamSink<TagEditEvent>,
+) -> u32 {
+    tag_edit::start(song_ids, changes, sink)
+}
+
+#[frb(sync)]
+pub fn cancel_tag_edit(job_id: u32) -> bool {
+    jobs::cancel(job_id)
+}
+
+/// Replace the cover art embedded in the file at `path` with an encoded image (JPEG,
+/// PNG, ...), or remove it when `image` is `None`
+pub fn write_song_artwork(path: String, image: Option<Vec<u8>>) -> Result<(), TunesError> {
+    Ok(metadata::write_artwork(std::path::Path::new(&path), image.as_deref())?)
+}
+
+/// Set the AcoustID application key `identify_song` looks fingerprints up with
+#[frb(sync)]
+pub fn set_acoustid_api_key(key: String) {
+    identify::set_acoustid_key(key);
+}
+
+/// Fingerprint the start of a file and ask AcoustID which recordings it may be
+///
+/// Candidates come most likely first; the list is empty if nothing matched.
+pub fn identify_song(path: String) -> Result<Vec<IdentificationCandidate>, TunesError> {
+    Ok(identify::identify(std::path::Path::new(&path))?)
+}
+
+/// Write the metadata of the candidate the user picked into the file and library
+///
+/// MusicBrainz fills in the genre and the recording's usual album where it can. Returns
+/// the song as now tagged.
+pub fn apply_identification(
+    path: String,
+    candidate: IdentificationCandidate,
+) -> Result<Song, TunesError> {
+    Ok(identify::apply(std::path::Path::new(&path), &candidate)?)
+}
+
+/// Recursively scan `root` for audio files in the background
+///
+/// Returns a scan id that can be passed to `cancel_scan`.
+#[frb(sync)]
+pub fn scan_library(root: String, sink: StreamSink<ScanEvent>) -> u32 {
+    scanner::start(root.into(), sink)
+}
+
+#[frb(sync)]
+pub fn cancel_scan(scan_id: u32) -> bool {
+    jobs::cancel(scan_id)
+}
+
+/// Files scans and `watch_library` couldn't read, with why, so the app can show where
+/// songs went
+pub fn get_quarantined_files() -> Result<Vec<QuarantinedFile>, TunesError> {
+    Ok(library::with_library(|lib| lib.get_quarantined_files())?)
+}
+
+/// Read quarantined file `id` again, storing and returning its songs if it reads now
+///
+/// Fails with why, keeping it quarantined, if it still doesn't.
+pub fn retry_quarantined(id: i64) -> Result<Vec<Song>, TunesError> {
+    let path = library::with_library(|lib| lib.get_quarantined_path(id))?
+        .ok_or_else(|| TunesError::invalid_state(format!("no quarantined file with id {id}")))?;
+    Ok(watcher::retry(std::path::Path::new(&path))?)
+}
+
+/// Save a remote library source, returning its id; `scan_library_source` indexes it
+pub fn add_library_source(name: String, config: SourceConfig) -> Result<i64, TunesError> {
+    sources::check(&config)?;
+    Ok(library::with_library(|lib| lib.add_library_source(&name, &config))?)
+}
+
+/// Forget a remote library source and remove its songs, and their offline copies, from
+/// the library
+pub fn remove_library_source(id: i64) -> Result<(), TunesError> {
+    offline::remove_source(id)?;
+    library::with_library(|lib| lib.delete_library_source(id))?;
+    sources::forget(id);
+    Ok(())
+}
+
+pub fn get_library_sources() -> Result<Vec<LibrarySource>, TunesError> {
+    Ok(library::with_library(|lib| lib.get_library_sources())?)
+}
+
+/// Index the audio files of a remote library source in the background, reporting on
+/// `sink` like `scan_library`
+///
+/// Only the tags are fetched, so loudness isn't measured. Files already in the library
+/// are skipped unless `rescan` is set, and songs of files gone from the source are
+/// removed. Returns the job id for `cancel_job`.
+pub fn scan_library_source(
+    id: i64,
+    rescan: bool,
+    sink: StreamSink<ScanEvent>,
+) -> Result<u32, TunesError> {
+    if !library::is_open() {
+        return Err(TunesError::invalid_state(
+            "library database is not open; call open_library first",
+        ));
+    }
+    Ok(sources::scan(id, rescan, sink))
+}
+
+/// Limit the on-disk cache of audio fetched from remote sources to `max_bytes`,
+/// 1 GiB by default; the least recently used audio is evicted first
+#[frb(sync)]
+pub fn set_remote_cache_limit(max_bytes: u64) {
+    sources::set_max_bytes(max_bytes);
+}
+
+/// Look for duplicate songs in the library on a background task
+///
+/// Returns a job id that can be passed to `cancel_find_duplicates`. Songs sharing a
+/// CUE sheet image are skipped by the fingerprint and file hash strategies.
+#[frb(sync)]
+pub fn find_duplicates(strategy: DuplicateStrategy, sink: StreamSink<DuplicateEvent>) -> u32 {
+    duplicates::start(strategy, sink)
+}
+
+#[frb(sync)]
+pub fn cancel_find_duplicates(job_id: u32) -> bool {
+    jobs::cancel(job_id)
+}
+
+/// Check the open library in the background for songs whose file is missing or empty,
+/// playlist entries pointing nowhere and leftover album art, without changing anything
+///
+/// Returns a job id that can be passed to `cancel_library_check`.
+#[frb(sync)]
+pub fn verify_library(sink: StreamSink<IntegrityEvent>) -> u32 {
+    integrity::start(None, sink)
+}
+
+/// Check the open library like `verify_library`, then relink and prune what `options`
+/// ask for
+///
+/// Returns a job id that can be passed to `cancel_library_check`.
+#[frb(sync)]
+pub fn repair_library(options: RepairOptions, sink: StreamSink<IntegrityEvent>) -> u32 {
+    integrity::start(Some(options), sink)
+}
+
+#[frb(sync)]
+pub fn cancel_library_check(job_id: u32) -> bool {
+    jobs::cancel(job_id)
+}
+
+/// Treat stretches at the start or end of a track that stay below `threshold_db` dBFS
+/// for at least `min_duration_secs` as silence (-60 dB and half a second by default)
+///
+/// Silence measured under other settings is measured again when next needed.
+#[frb(sync)]
+pub fn set_silence_detection(threshold_db: f32, min_duration_secs: f32) {
+    silence::set_settings(threshold_db, min_duration_secs);
+}
+
+/// Silence at either end of `song`, measured once and then kept in the library
+pub fn detect_silence(song: Song) -> Result<TrackSilence, TunesError> {
+    Ok(silence::silence_of(&song)?)
+}
+
+/// Measure the silence of every library song that hasn't been yet, on a background job
+///
+/// Songs measured ahead of time have `set_skip_silence` trim them from the first play.
+/// Returns the job id for `cancel_job`.
+pub fn analyze_silence(sink: StreamSink<SilenceEvent>) -> Result<u32, TunesError> {
+    if !library::is_open() {
+        return Err(TunesError::invalid_state(
+            "library database is not open; call open_library first",
+        ));
+    }
+    Ok(silence::start(sink))
+}
+
+/// Measure the tempo, key and audio features of every library song missing any of
+/// them, on a background job
+///
+/// Scanning measures the tempo and key of new songs already, but only this measures
+/// their features. Returns the job id for `cancel_job`.
+pub fn analyze_tracks(sink: StreamSink<AnalysisEvent>) -> Result<u32, TunesError> {
+    if !library::is_open() {
+        return Err(TunesError::invalid_state(
+            "library database is not open; call open_library first",
+        ));
+    }
+    Ok(analysis::start(sink))
+}
+
+/// Tempo of a library song in beats per minute, from its tags or measured once and
+/// then kept in the library
+///
+/// Scanning measures songs without a tempo tag already, so this mostly returns at once.
+pub fn analyze_bpm(song_id: SongId) -> Result<f32, TunesError> {
+    let song = library_song(&song_id)?;
+    analysis::analysis_of(&song)?
+        .bpm
+        .ok_or_else(|| TunesError::Decode {
+            message: format!("{} has no steady beat", song.title),
+        })
+}
+
+/// Library songs that mix harmonically with `song_id`: those in its key, a fifth
+/// either side of it on the Camelot wheel, or its relative major or minor
+///
+/// Songs in the same key come first, then those closest in tempo. The song's key is
+/// measured if it isn't known yet.
+pub fn get_compatible_tracks(song_id: SongId) -> Result<Vec<Song>, TunesError> {
+    let song = library_song(&song_id)?;
+    let analysis = analysis::analysis_of(&song)?;
+    let Some(key) = analysis.key else {
+        return Err(TunesError::Decode {
+            message: format!("{} has no audible key", song.title),
+        });
+    };
+    Ok(library::with_library(|lib| {
+        lib.get_compatible_songs(&song, key, analysis.bpm)
+    })?)
+}
+
+/// Energy, danceability, brightness and dynamic range of a library song, measured
+/// once and then kept in the library
+pub fn get_track_features(song_id: SongId) -> Result<TrackFeatures, TunesError> {
+    let song = library_song(&song_id)?;
+    analysis::features_of(&song)?.ok_or_else(|| TunesError::Decode {
+        message: format!("{} is silent", song.title),
+    })
+}
+
+/// Library songs whose features match `filter`, for mood-based mixes
+///
+/// Only songs `analyze_tracks` or `get_track_features` has measured are picked.
+pub fn get_tracks_by_mood(filter: MoodFilter) -> Result<Vec<Song>, TunesError> {
+    Ok(library::with_library(|lib| lib.get_songs_by_mood(&filter))?)
+}
+
+/// Camelot wheel notation, e.g. "8A", of a key in standard or Camelot notation;
+/// `None` if it isn't a key
+#[frb(sync)]
+pub fn camelot_key(key: String) -> Option<String> {
+    key::Key::parse(&key).map(key::Key::camelot)
+}
+
+fn library_song(song_id: &SongId) -> Result<Song, TunesError> {
+    library::with_library(|lib| lib.get_song(song_id))?
+        .ok_or_else(|| TunesError::invalid_state(format!("no song with id {song_id}")))
+}
+
+/// Convert the audio file at `input` to `format` at `output`, on a background job
+///
+/// Sources with more than two channels are mixed down for the lossy formats; FLAC
+/// keeps them, and keeps 24-bit sources at 24 bits. Opus is always encoded at 48 kHz,
+/// and AAC at 48 kHz unless the source is at 32, 44.1 or 48 kHz. `output` only appears
+/// once the conversion is complete. Returns the job id for `cancel_job`.
+pub fn transcode(
+    input: String,
+    output: String,
+    format: TranscodeFormat,
+    options: TranscodeOptions,
+    sink: StreamSink<TranscodeEvent>,
+) -> Result<u32, TunesError> {
+    transcode::check_supported(format)?;
+    Ok(transcode::start(
+        input.into(),
+        output.into(),
+        format,
+        options,
+        sink,
+    ))
+}
+
+/// Copy or convert the songs of playlist `playlist_id` into the folder `dest`, on a
+/// background job
+///
+/// Tracks are named following `options.pattern`, and an M3U8 playlist named after the
+/// playlist is written next to them. CUE sheet tracks are cut out of their image, to
+/// FLAC unless a format is given. Returns the job id for `cancel_job`.
+pub fn export_playlist_to_folder(
+    playlist_id: i64,
+    dest: String,
+    options: ExportOptions,
+    sink: StreamSink<ExportEvent>,
+) -> Result<u32, TunesError> {
+    if !library::is_open() {
+        return Err(TunesError::invalid_state(
+            "library database is not open; call open_library first",
+        ));
+    }
+    if let Some(format) = options.format {
+        transcode::check_supported(format)?;
+    }
+    Ok(export::start(playlist_id, dest.into(), options, sink))
+}
+
+/// Read the table of contents of the audio CD in `device`, `/dev/cdrom` for `None`,
+/// and look the disc up on MusicBrainz
+///
+/// The disc still comes back, without names, when MusicBrainz doesn't know it or
+/// can't be reached. CDs can only be read on Linux so far.
+pub fn read_cd(device: Option<String>) -> Result<CdDisc, TunesError> {
+    let device = device.unwrap_or_else(|| ripper::DEFAULT_DEVICE.to_string());
+    Ok(ripper::read_disc(&device)?)
+}
+
+/// Rip tracks of `disc`, as returned by `read_cd`, into the folder `dest` on a
+/// background job
+///
+/// Sectors are read until two reads agree, so scratches slow the rip down rather than
+/// creeping into the files; `TrackRipped` reports how much of each track couldn't be
+/// checked. Tracks are tagged from `disc`. Returns the job id for `cancel_job`.
+pub fn rip_cd(
+    disc: CdDisc,
+    dest: String,
+    options: RipOptions,
+    sink: StreamSink<RipEvent>,
+) -> Result<u32, TunesError> {
+    transcode::check_supported(options.format)?;
+    Ok(ripper::start(disc, dest.into(), options, sink))
+}
+
+/// Report the lifecycle of every background job on `sink`, replacing any earlier
+/// listener
+///
+/// Scans, waveforms, duplicate searches and episode downloads all run as jobs and
+/// share one id space.
+#[frb(sync)]
+pub fn watch_jobs(sink: StreamSink<JobProgress>) {
+    jobs::watch(sink)
+}
+
+/// Background jobs that are queued or running, oldest first
+#[frb(sync)]
+pub fn get_jobs() -> Vec<JobInfo> {
+    jobs::list()
+}
+
+/// Cancel any background job; returns false if it already finished
+#[frb(sync)]
+pub fn cancel_job(job_id: u32) -> bool {
+    jobs::cancel(job_id)
+}
+
+/// Keep the open library up to date with changes under `roots` as they happen
+///
+/// New and changed files are read, deleted ones removed and moved ones keep their
+/// plays, bookmarks and playlist entries. A full `scan_library` is only needed for
+/// changes made while nothing was watching. Replaces any earlier watch.
+pub fn watch_library(roots: Vec<String>, sink: StreamSink<LibraryEvent>) -> Result<(), TunesError> {
+    Ok(watcher::start(roots.into_iter().map(Into::into).collect(), sink)?)
+}
+
+#[frb(sync)]
+pub fn stop_watching_library() {
+    watcher::stop();
+}
+
+/// Move audio files put in `options.folder` into the library as they arrive
+///
+/// Each file is tagged if asked for, moved below `options.library_root` following
+/// `options.pattern` and added to the open library. Files already there are imported
+/// straight away, and ones that can't be are left where they are. Replaces any earlier
+/// drop folder.
+pub fn watch_drop_folder(
+    options: DropFolderOptions,
+    sink: StreamSink<DropFolderEvent>,
+) -> Result<(), TunesError> {
+    Ok(drop_folder::start(options, sink)?)
+}
+
+#[frb(sync)]
+pub fn stop_watching_drop_folder() {
+    drop_folder::stop();
+}
+
+/// Open (creating if needed) the SQLite library database at `db_path`
+///
+/// While open, `scan_library` stores every parsed song in it. Replacing an open library
+/// first cancels its background jobs, failing if they don't end in time, and stops
+/// library and drop-folder watching.
+pub fn open_library(db_path: String) -> Result<(), TunesError> {
+    Ok(app::open_library(std::path::Path::new(&db_path))?)
+}
+
+/// Keep a library per profile in folders under `root`, opening the library of profile
+/// `id`, or of the one used last
+///
+/// A "Default" profile is made if there are none yet. Use instead of `open_library`.
+pub fn open_profiles(root: String, id: Option<String>) -> Result<Profile, TunesError> {
+    Ok(profiles::open(std::path::Path::new(&root), id.as_deref())?)
+}
+
+pub fn list_profiles() -> Result<Vec<Profile>, TunesError> {
+    Ok(profiles::list()?)
+}
+
+/// Add an empty profile named `name`, without switching to it
+pub fn create_profile(name: String) -> Result<Profile, TunesError> {
+    Ok(profiles::create(&name)?)
+}
+
+/// Close the open library and open the one of profile `id`
+///
+/// Library and drop-folder watching stop and background jobs working on the library
+/// are cancelled first; fails, leaving the open library as it was, if they don't end in time.
+/// Playback carries on.
+pub fn switch_profile(id: String) -> Result<Profile, TunesError> {
+    Ok(profiles::switch(&id)?)
+}
+
+/// Save the library, with its playlists, ratings, play counts and bookmarks, to a
+/// compressed snapshot at `path`, for moving to another device
+///
+/// Audio files, caches and downloads stay behind.
+pub fn export_library_snapshot(path: String) -> Result<SnapshotInfo, TunesError> {
+    Ok(library::with_library(|lib| {
+        lib.export_snapshot(std::path::Path::new(&path))
+    })?)
+}
+
+/// Replace the open library with the snapshot at `path`
+///
+/// Snapshots from older versions are brought up to date; ones from newer versions are
+/// refused. The replaced database is kept next to it with a `.bak` suffix.
+pub fn import_library_snapshot(path: String) -> Result<SnapshotInfo, TunesError> {
+    Ok(library::restore_snapshot(std::path::Path::new(&path))?)
+}
+
+pub fn get_all_songs() -> Result<Vec<Song>, TunesError> {
+    Ok(library::with_library(|lib| lib.get_all_songs())?)
+}
+
+/// Up to `limit` songs in the order of `get_all_songs`, starting after `cursor`
+///
+/// Start with no cursor and pass each page's `next_cursor` to get the next. Songs
+/// added or removed in between don't shift later pages.
+pub fn get_songs_page(cursor: Option<String>, limit: u32) -> Result<SongPage, TunesError> {
+    Ok(library::with_library(|lib| lib.get_songs_page(cursor.as_deref(), limit))?)
+}
+
+/// Stream every song in the order of `get_all_songs`, in batches of `page_size`
+/// (500 if zero)
+///
+/// The stream ends after the last batch, or early if the library can't be read.
+#[frb(sync)]
+pub fn stream_all_songs(page_size: u32, sink: StreamSink<Vec<Song>>) {
+    let page_size = match page_size {
+        0 => library::DEFAULT_PAGE_SIZE,
+        size => size,
+    };
+    library::stream_songs(page_size, sink)
+}
+
+/// Sort library lists the way speakers of `locale`, a BCP 47 tag like "sv-SE", expect
+///
+/// Names sort regardless of case, accents and character width, and hiragana with
+/// katakana. Letters an alphabet has of its own, like the Swedish "å" or the Spanish
+/// "ñ", go where that alphabet puts them.
+#[frb(sync)]
+pub fn set_sort_locale(locale: String) {
+    collation::set_locale(&locale);
+}
+
+/// Every artist with albums in the library, with album and song counts
+///
+/// Albums count towards their album artist, and compilations towards "Various Artists".
+pub fn get_artists(sort: BrowseSort) -> Result<Vec<ArtistSummary>, TunesError> {
+    Ok(library::with_library(|lib| lib.get_artists(sort))?)
+}
+
+/// Credit albums to "Various Artists" that look like compilations, for libraries where
+/// not every track of one is tagged as such: several artists on tracks sharing an
+/// album and folder and none with half of them, or some tracks tagged as one
+///
+/// Returns how many albums were regrouped.
+pub fn detect_compilations() -> Result<u32, TunesError> {
+    Ok(library::with_library(|lib| lib.detect_compilations())?)
+}
+
+pub fn get_albums_for_artist(
+    artist_id: i64,
+    sort: BrowseSort,
+) -> Result<Vec<AlbumSummary>, TunesError> {
+    Ok(library::with_library(|lib| lib.get_albums_for_artist(artist_id, sort))?)
+}
+
+/// Albums with at least one song tagged with `genre`, ignoring case
+pub fn get_albums_for_genre(
+    genre: String,
+    sort: BrowseSort,
+) -> Result<Vec<AlbumSummary>, TunesError> {
+    Ok(library::with_library(|lib| lib.get_albums_for_genre(&genre, sort))?)
+}
+
+/// Albums with songs from `decade`, as returned in `DecadeSummary::decade`
+pub fn get_albums_for_decade(
+    decade: u32,
+    sort: BrowseSort,
+) -> Result<Vec<AlbumSummary>, TunesError> {
+    Ok(library::with_library(|lib| lib.get_albums_for_decade(decade, sort))?)
+}
+
+/// Genres tagged in the library, merging spellings that differ only in case
+pub fn get_genres(sort: BrowseSort) -> Result<Vec<GenreSummary>, TunesError> {
+    Ok(library::with_library(|lib| lib.get_genres(sort))?)
+}
+
+/// Decades songs were released in; songs without a year are left out
+pub fn get_decades(sort: BrowseSort) -> Result<Vec<DecadeSummary>, TunesError> {
+    Ok(library::with_library(|lib| lib.get_decades(sort))?)
+}
+
+pub fn get_album(id: i64) -> Result<Option<Album>, TunesError> {
+    Ok(library::with_library(|lib| lib.get_album(id))?)
+}
+
+pub fn get_artist_songs(id: i64) -> Result<Vec<Song>, TunesError> {
+    Ok(library::with_library(|lib| lib.get_artist_songs(id))?)
+}
+
+/// Songs whose title, artist or album contains `query`
+pub fn search(query: String) -> Result<Vec<Song>, TunesError> {
+    Ok(library::with_library(|lib| lib.search(&query))?)
+}
+
+/// Full-text search where every word of `query` must prefix-match a title, artist,
+/// album or file name; each list in the result holds at most `limit` entries
+pub fn search_library(query: String, limit: u32) -> Result<SearchResults, TunesError> {
+    Ok(library::with_library(|lib| lib.search_library(&query, limit))?)
+}
+
+/// Hide the songs `rules` match from song lists, browsing, search, history, smart
+/// playlists and the auto-DJ of the open library, which keeps the rules
+///
+/// Playlists still list every song they hold, so their positions stay true. The
+/// default, empty rules turn the filter off. Advisory tags are read by the next scan.
+pub fn set_content_filter(rules: ContentFilter) -> Result<(), TunesError> {
+    Ok(library::with_library(|lib| lib.set_content_filter(&rules))?)
+}
+
+pub fn get_content_filter() -> Result<ContentFilter, TunesError> {
+    Ok(library::with_library(|lib| lib.get_content_filter())?)
+}
+
+/// Cover art thumbnail (JPEG) for a library song, from its tags or its folder
+///
+/// Thumbnails are cached in an `artwork` directory next to the library database.
+pub fn get_album_art(song_id: SongId) -> Result<Option<Vec<u8>>, TunesError> {
+    let (song, cache_dir) = library::with_library(|lib| {
+        Ok((lib.get_song(&song_id)?, lib.cache_dir(StorageKind::Artwork)))
+    })?;
+    let Some(song) = song else {
+        return Err(TunesError::invalid_state(format!("no song with id {song_id}")));
+    };
+    Ok(artwork::album_art(std::path::Path::new(&song.file_path), &cache_dir)?)
+}
+
+/// Lyrics of a library song, from a sidecar `.lrc` file or its tags; empty if it has none
+///
+/// While a song with synced lyrics plays, the engine emits `LyricLineChanged`.
+pub fn get_lyrics(song_id: SongId) -> Result<Lyrics, TunesError> {
+    let song = library::with_library(|lib| lib.get_song(&song_id))?;
+    let Some(song) = song else {
+        return Err(TunesError::invalid_state(format!("no song with id {song_id}")));
+    };
+    Ok(lyrics::read(std::path::Path::new(&song.file_path))?)
+}
+
+/// Chapter markers of a library song (MP4 `chpl` or ID3 `CHAP`); empty if it has none
+///
+/// While it plays, the engine emits `ChapterChanged` and `seek_to_chapter` jumps
+/// between them.
+pub fn get_chapters(song_id: SongId) -> Result<Vec<Chapter>, TunesError> {
+    let song = library::with_library(|lib| lib.get_song(&song_id))?;
+    let Some(song) = song else {
+        return Err(TunesError::invalid_state(format!("no song with id {song_id}")));
+    };
+    Ok(chapters::read(std::path::Path::new(&song.file_path))?)
+}
+
+/// Songs by when they were last played, most recent first
+///
+/// A play counts once enough of a song was heard; see `set_play_count_threshold`.
+pub fn get_recently_played(limit: u32) -> Result<Vec<PlayedSong>, TunesError> {
+    Ok(library::with_library(|lib| lib.get_recently_played(limit))?)
+}
+
+pub fn get_most_played(limit: u32) -> Result<Vec<PlayedSong>, TunesError> {
+    Ok(library::with_library(|lib| lib.get_most_played(limit))?)
+}
+
+/// Artists by how often their songs were played, most played first
+pub fn get_artist_listening_stats(limit: u32) -> Result<Vec<ListeningStats>, TunesError> {
+    Ok(library::with_library(|lib| lib.get_artist_listening_stats(limit))?)
+}
+
+/// Albums by how often their songs were played, most played first
+pub fn get_album_listening_stats(limit: u32) -> Result<Vec<ListeningStats>, TunesError> {
+    Ok(library::with_library(|lib| lib.get_album_listening_stats(limit))?)
+}
+
+pub fn create_playlist(name: String) -> Result<i64, TunesError> {
+    Ok(library::with_library(|lib| lib.create_playlist(&name))?)
+}
+
+pub fn rename_playlist(id: i64, name: String) -> Result<(), TunesError> {
+    Ok(library::with_library(|lib| lib.rename_playlist(id, &name))?)
+}
+
+pub fn delete_playlist(id: i64) -> Result<(), TunesError> {
+    Ok(library::with_library(|lib| lib.delete_playlist(id))?)
+}
+
+pub fn get_playlists() -> Result<Vec<Playlist>, TunesError> {
+    Ok(library::with_library(|lib| lib.get_playlists())?)
+}
+
+pub fn get_playlist_songs(id: i64) -> Result<Vec<Song>, TunesError> {
+    Ok(library::with_library(|lib| lib.get_playlist_songs(id))?)
+}
+
+/// Append library songs to the end of a playlist
+pub fn playlist_add_songs(id: i64, song_ids: Vec<SongId>) -> Result<(), TunesError> {
+    Ok(library::with_library(|lib| lib.playlist_add_songs(id, song_ids))?)
+}
+
+pub fn playlist_remove_song(id: i64, position: u32) -> Result<(), TunesError> {
+    Ok(library::with_library(|lib| lib.playlist_remove_song(id, position as usize))?)
+}
+
+pub fn playlist_move_song(id: i64, from: u32, to: u32) -> Result<(), TunesError> {
+    Ok(library::with_library(|lib| lib.playlist_move_song(id, from as usize, to as usize))?)
+}
+
+/// Save a smart playlist, returning its id
+pub fn create_smart_playlist(name: String, rules: SmartRules) -> Result<i64, TunesError> {
+    Ok(library::with_library(|lib| lib.create_smart_playlist(&name, &rules))?)
+}
+
+pub fn update_smart_playlist(id: i64, name: String, rules: SmartRules) -> Result<(), TunesError> {
+    Ok(library::with_library(|lib| lib.update_smart_playlist(id, &name, &rules))?)
+}
+
+pub fn delete_smart_playlist(id: i64) -> Result<(), TunesError> {
+    Ok(library::with_library(|lib| lib.delete_smart_playlist(id))?)
+}
+
+pub fn get_smart_playlists() -> Result<Vec<SmartPlaylist>, TunesError> {
+    Ok(library::with_library(|lib| lib.get_smart_playlists())?)
+}
+
+/// Songs currently matching a smart playlist's rules
+///
+/// Rules are evaluated on every call, so newly scanned, rated or played songs show up
+/// right away.
+pub fn evaluate_smart_playlist(id: i64) -> Result<Vec<Song>, TunesError> {
+    Ok(library::with_library(|lib| lib.evaluate_smart_playlist(id))?)
+}
+
+/// Rate a library song from 1 to 5 stars; 0 clears its rating
+///
+/// With `set_rating_tags_enabled`, the rating is also written to the file as an ID3
+/// POPM frame for MP3, or an FMPS_RATING field for Vorbis comment and APE tags.
+pub fn set_rating(song_id: SongId, stars: u8) -> Result<(), TunesError> {
+    let rating = (stars > 0).then_some(stars);
+    let song = library::with_library(|lib| {
+        lib.set_rating(&song_id, rating)?;
+        lib.get_song(&song_id)
+    })?;
+    match song {
+        Some(song) if metadata::writes_rating_tags() && !cue::is_track(&song) => {
+            Ok(metadata::write_rating(std::path::Path::new(&song.file_path), rating)?)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Whether `set_rating` also writes ratings into files' tags (off by default)
+#[frb(sync)]
+pub fn set_rating_tags_enabled(enabled: bool) {
+    metadata::set_rating_tags_enabled(enabled);
+}
+
+/// Mark or unmark a library song as a favorite, returning whether it now is one
+///
+/// Songs of a media server are starred or unstarred there too.
+pub fn toggle_favorite(song_id: SongId) -> Result<bool, TunesError> {
+    let (favorite, song) = library::with_library(|lib| {
+        let favorite = lib.toggle_favorite(&song_id)?;
+        Ok((favorite, lib.get_song(&song_id)?))
+    })?;
+    if let Some(song) = song {
+        sources::set_starred(&song, favorite);
+    }
+    Ok(favorite)
+}
+
+pub fn get_favorites() -> Result<Vec<Song>, TunesError> {
+    Ok(library::with_library(|lib| lib.get_favorites())?)
+}
+
+/// Songs rated at least `stars` stars, best rated first
+pub fn get_by_min_rating(stars: u8) -> Result<Vec<Song>, TunesError> {
+    Ok(library::with_library(|lib| lib.get_by_min_rating(stars))?)
+}
+
+/// Import an M3U/M3U8/PLS file as a new playlist, returning its id
+///
+/// Files it references that aren't in the library yet are added to it.
+pub fn import_playlist(path: String) -> Result<i64, TunesError> {
+    Ok(library::with_library(|lib| lib.import_playlist(std::path::Path::new(&path)))?)
+}
+
+/// Save a playlist as PLS (for a `.pls` path) or extended M3U
+pub fn export_playlist(id: i64, path: String) -> Result<(), TunesError> {
+    Ok(library::with_library(|lib| lib.export_playlist(id, std::path::Path::new(&path)))?)
+}
+
+/// Bring play counts, ratings, dates added and playlists over from another player's
+/// library file at `path`
+///
+/// Tracks are matched to songs by path, then by artist, title and length, so the
+/// music should be scanned in first. The higher play count and earlier date added are
+/// kept and only unrated songs are rated, so importing again changes nothing; smart
+/// playlists and ones whose name is taken are left out. A `dry_run` only reports what
+/// matches.
+pub fn import_library(
+    format: LibraryFormat,
+    path: String,
+    dry_run: bool,
+) -> Result<ImportReport, TunesError> {
+    Ok(importer::import(format, std::path::Path::new(&path), dry_run)?)
+}
+
+/// Subscribe to the RSS feed at `feed_url` and store its episodes
+///
+/// Subscribing to a feed again refreshes it instead.
+pub fn subscribe_podcast(feed_url: String) -> Result<Podcast, TunesError> {
+    Ok(podcasts::subscribe(&feed_url)?)
+}
+
+/// Unsubscribe, deleting the podcast's episodes and downloads
+pub fn unsubscribe_podcast(id: i64) -> Result<(), TunesError> {
+    Ok(podcasts::unsubscribe(id)?)
+}
+
+pub fn get_podcasts() -> Result<Vec<Podcast>, TunesError> {
+    Ok(library::with_library(|lib| lib.get_podcasts())?)
+}
+
+/// Re-fetch every subscribed feed, returning how many new episodes appeared
+///
+/// A feed that fails to load is logged and skipped.
+pub fn refresh_feeds() -> Result<u32, TunesError> {
+    Ok(podcasts::refresh_feeds()?)
+}
+
+/// Episodes of a podcast, newest first
+pub fn get_podcast_episodes(podcast_id: i64) -> Result<Vec<PodcastEpisode>, TunesError> {
+    Ok(library::with_library(|lib| lib.get_podcast_episodes(podcast_id))?)
+}
+
+/// Download an episode in the background, resuming an earlier partial download
+///
+/// Returns a download id that can be passed to `cancel_download`. Episodes are saved
+/// in a `podcasts` directory next to the library database.
+#[frb(sync)]
+pub fn download_episode(episode_id: i64, sink: StreamSink<DownloadEvent>) -> u32 {
+    podcasts::downloads::start(episode_id, sink)
+}
+
+/// Stop a running download, keeping what has arrived so far for a later resume
+#[frb(sync)]
+pub fn cancel_download(download_id: u32) -> bool {
+    jobs::cancel(download_id)
+}
+
+/// Delete an episode's downloaded file
+pub fn delete_episode_download(episode_id: i64) -> Result<(), TunesError> {
+    Ok(podcasts::downloads::delete(episode_id)?)
+}
+
+/// Keep a remote song or podcast episode on the device, downloading it in the
+/// background if needed
+///
+/// Pinned items are never evicted; a download that would take pinned items past the
+/// quota fails instead. Follow the download with `watch_downloads`.
+pub fn pin_offline(item: OfflineItem) -> Result<(), TunesError> {
+    Ok(offline::pin(item)?)
+}
+
+/// Let a pinned item's copy be evicted when space is needed, or stop its download
+pub fn unpin_offline(item: OfflineItem) -> Result<(), TunesError> {
+    Ok(offline::unpin(&item)?)
+}
+
+/// Delete an item's offline copy now, pinned or not
+pub fn remove_offline(item: OfflineItem) -> Result<(), TunesError> {
+    Ok(offline::remove(&item)?)
+}
+
+pub fn get_download_state(item: OfflineItem) -> Result<DownloadState, TunesError> {
+    Ok(offline::state(&item)?)
+}
+
+/// Every item that is downloaded or being downloaded
+pub fn get_offline_items() -> Result<Vec<OfflineItemState>, TunesError> {
+    Ok(offline::list()?)
+}
+
+/// Send every change of an item's download state to `sink`, replacing any earlier
+/// listener
+#[frb(sync)]
+pub fn watch_downloads(sink: StreamSink<OfflineItemState>) {
+    offline::watch(sink);
+}
+
+/// Let offline copies take `max_bytes` in all, 4 GiB by default, evicting unpinned
+/// ones now if they take more
+pub fn set_offline_quota(max_bytes: u64) -> Result<(), TunesError> {
+    Ok(offline::set_quota(max_bytes)?)
+}
+
+/// Check every offline copy against the digest taken when it was downloaded, in the
+/// background, returning the job id for `cancel_job`
+///
+/// Damaged copies are deleted and pinned ones downloaded again; changes arrive on
+/// `watch_downloads`. Copies whose file went missing are noticed without this.
+#[frb(sync)]
+pub fn verify_offline_items() -> u32 {
+    offline::verify()
+}
+
+/// Log in to Last.fm with the app's API account, returning the session key
+///
+/// Store the key and hand it to `set_lastfm_session` on the next start instead of
+/// logging in again. Scrobbling to Last.fm is enabled on success.
+pub fn lastfm_login(
+    api_key: String,
+    api_secret: String,
+    username: String,
+    password: String,
+) -> Result<String, TunesError> {
+    Ok(scrobble::lastfm_login(api_key, api_secret, &username, &password)?)
+}
+
+/// Restore a Last.fm session from an earlier `lastfm_login`
+#[frb(sync)]
+pub fn set_lastfm_session(api_key: String, api_secret: String, session_key: String) {
+    scrobble::set_lastfm_session(api_key, api_secret, session_key);
+}
+
+/// Check a ListenBrainz user token and start submitting listens with it
+///
+/// Returns the token's user name.
+pub fn listenbrainz_login(token: String) -> Result<String, TunesError> {
+    Ok(scrobble::listenbrainz_login(token)?)
+}
+
+/// Use a ListenBrainz token checked earlier, without going online
+#[frb(sync)]
+pub fn set_listenbrainz_token(token: String) {
+    scrobble::set_listenbrainz_token(token);
+}
+
+/// Pause or resume scrobbling to a service without forgetting its credentials
+///
+/// Listens are scrobbled once half a song or four minutes of it have been heard,
+/// whichever comes first; songs shorter than 30 seconds never are. Listens that
+/// can't be sent, e.g. while offline, are kept in the library and retried later.
+#[frb(sync)]
+pub fn set_scrobbling_enabled(service: ScrobbleService, enabled: bool) {
+    scrobble::set_enabled(service, enabled);
+}
+
+/// Forget a service's credentials and drop its unsent listens
+pub fn scrobble_logout(service: ScrobbleService) -> Result<(), TunesError> {
+    Ok(scrobble::logout(service)?)
+}
+
+/// Listens still waiting to be sent to a service
+pub fn get_pending_scrobbles(service: ScrobbleService) -> Result<u32, TunesError> {
+    Ok(library::with_library(|lib| lib.pending_scrobble_count(service))?)
+}
+
+/// Set the app up as `config` asks; call it first, before anything else
+///
+/// It can be called again, e.g. between integration tests, to start over with another
+/// library or other directories; without `library_path` the open library is closed.
+pub fn init_app(config: EngineConfig) -> Result<(), TunesError> {
+    Ok(app::init(config)?)
+}
+
+/// Keep caches, downloads and temporary files in the platform's directories from now on
+///
+/// Until this, or `init_app` with `storage`, is called they're kept next to the library
+/// database. Files already kept elsewhere stay where they are; downloads are still
+/// found there.
+pub fn init_storage(config: StorageConfig) -> Result<(), TunesError> {
+    Ok(storage::init(config)?)
+}
+
+/// Space taken by each kind of file, leaving out kinds without a directory yet, as
+/// before a library is opened or `init_storage` called
+pub fn get_storage_usage() -> Vec<StorageUsage> {
+    storage::usage()
+}
+
+/// Delete every file of `kind`, returning the bytes freed
+///
+/// Downloads are removed with `remove_offline` and `delete_episode_download` instead,
+/// which keep the library in step.
+pub fn clear_cache(kind: StorageKind) -> Result<u64, TunesError> {
+    Ok(storage::clear(kind)?)
+}
+
+/// The last `limit` log records at `level` or more severe, oldest first
+///
+/// Up to the latest 2000 records are kept, at the level `set_log_level` chose.
+#[frb(sync)]
+pub fn get_recent_logs(level: LogLevel, limit: u32) -> Vec<LogRecord> {
+    logging::recent(level, limit as usize)
+}
+
+/// Log records at `level` or more severe from now on; `Info` by default
+#[frb(sync)]
+pub fn set_log_level(level: LogLevel) {
+    logging::set_level(level);
 }
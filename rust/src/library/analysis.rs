@@ -0,0 +1,114 @@
+use rusqlite::{params, OptionalExtension};
+
+use super::Library;
+use crate::analysis::Analysis;
+use crate::key::Key;
+use crate::{MoodFilter, Song, SongId, TrackFeatures};
+
+/// Energy at most chill songs have, and at least energetic ones
+const CHILL_MAX_ENERGY: f32 = 0.4;
+const ENERGETIC_MIN_ENERGY: f32 = 0.6;
+
+impl Library {
+    /// Store the measured tempo, key and features of a song, keeping whatever wasn't
+    /// found
+    pub fn set_analysis(&self, song_id: &SongId, analysis: Analysis) -> anyhow::Result<()> {
+        self.conn.execute(
+            "UPDATE songs SET bpm = COALESCE(?1, bpm), musical_key = COALESCE(?2, musical_key)
+             WHERE id = ?3",
+            params![analysis.bpm, analysis.key.map(Key::name), song_id],
+        )?;
+        if let Some(features) = analysis.features {
+            self.conn.execute(
+                "INSERT INTO song_features (song_id, energy, danceability, brightness,
+                                            dynamic_range)
+                 VALUES (?1, ?2, ?3, ?4, ?5)
+                 ON CONFLICT (song_id) DO UPDATE SET
+                    energy = excluded.energy,
+                    danceability = excluded.danceability,
+                    brightness = excluded.brightness,
+                    dynamic_range = excluded.dynamic_range",
+                params![
+                    song_id,
+                    features.energy,
+                    features.danceability,
+                    features.brightness_hz,
+                    features.dynamic_range_db,
+                ],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Audio features measured for a song, if they have been
+    pub fn get_features(&self, song_id: &SongId) -> anyhow::Result<Option<TrackFeatures>> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT energy, danceability, brightness, dynamic_range FROM song_features
+                 WHERE song_id = ?1",
+                [song_id],
+                |row| {
+                    Ok(TrackFeatures {
+                        energy: row.get(0)?,
+                        danceability: row.get(1)?,
+                        brightness_hz: row.get(2)?,
+                        dynamic_range_db: row.get(3)?,
+                    })
+                },
+            )
+            .optional()?)
+    }
+
+    /// Songs still missing a tempo, a key or their features
+    pub fn get_songs_without_analysis(&self) -> anyhow::Result<Vec<Song>> {
+        self.query_songs(
+            "WHERE s.bpm IS NULL OR s.musical_key IS NULL
+                OR NOT EXISTS (SELECT 1 FROM song_features f WHERE f.song_id = s.id)
+             ORDER BY s.file_path, s.start_offset",
+            [],
+        )
+    }
+
+    /// Songs whose features match `filter`, in the order it asks for
+    pub fn get_songs_by_mood(&self, filter: &MoodFilter) -> anyhow::Result<Vec<Song>> {
+        let (energy, danceability, order) = match *filter {
+            MoodFilter::Chill => ((0.0, CHILL_MAX_ENERGY), (0.0, 1.0), "f.energy ASC"),
+            MoodFilter::Energetic => ((ENERGETIC_MIN_ENERGY, 1.0), (0.0, 1.0), "f.energy DESC"),
+            MoodFilter::Custom {
+                min_energy,
+                max_energy,
+                min_danceability,
+                max_danceability,
+            } => (
+                (min_energy, max_energy),
+                (min_danceability, max_danceability),
+                "f.energy DESC",
+            ),
+        };
+        self.query_songs(
+            &format!(
+                "JOIN song_features f ON f.song_id = s.id
+                 WHERE f.energy BETWEEN ?1 AND ?2 AND f.danceability BETWEEN ?3 AND ?4
+                 ORDER BY {order}, s.title"
+            ),
+            params![energy.0, energy.1, danceability.0, danceability.1],
+        )
+    }
+
+    /// Songs other than `song` in a key compatible with `key`, those in the same key
+    /// first, then those closest in tempo to `bpm`
+    pub fn get_compatible_songs(
+        &self,
+        song: &Song,
+        key: Key,
+        bpm: Option<f32>,
+    ) -> anyhow::Result<Vec<Song>> {
+        let [same, other_1, other_2, other_3] = key.compatible().map(Key::name);
+        self.query_songs(
+            "WHERE s.musical_key IN (?1, ?2, ?3, ?4) AND s.id != ?5
+             ORDER BY s.musical_key != ?1, s.bpm IS NULL, ABS(s.bpm - ?6), s.title",
+            params![same, other_1, other_2, other_3, song.id, bpm],
+        )
+    }
+}
@@ -0,0 +1,49 @@
+use rusqlite::Row;
+
+use super::{song_from_row, Library, SONG_COLUMNS, SONG_COLUMN_COUNT, SONG_JOINS, VISIBLE};
+use crate::{Song, TrackFeatures};
+
+/// A song auto-DJ might pick, with what it's picked by
+pub(crate) struct Candidate {
+    pub song: Song,
+    pub features: Option<TrackFeatures>,
+    pub play_count: u32,
+}
+
+fn candidate_from_row(row: &Row<'_>) -> rusqlite::Result<Candidate> {
+    let column = |offset| SONG_COLUMN_COUNT + offset;
+    let features = match row.get::<_, Option<f32>>(column(0))? {
+        Some(energy) => Some(TrackFeatures {
+            energy,
+            danceability: row.get(column(1))?,
+            brightness_hz: row.get(column(2))?,
+            dynamic_range_db: row.get(column(3))?,
+        }),
+        None => None,
+    };
+    Ok(Candidate {
+        song: song_from_row(row)?,
+        features,
+        play_count: row.get(column(4))?,
+    })
+}
+
+impl Library {
+    /// Every song not played since `played_before`, in seconds since the Unix epoch
+    pub fn get_auto_dj_candidates(&self, played_before: i64) -> anyhow::Result<Vec<Candidate>> {
+        let sql = format!(
+            "SELECT {SONG_COLUMNS}, f.energy, f.danceability, f.brightness, f.dynamic_range,
+                    COALESCE(p.play_count, 0)
+             FROM {SONG_JOINS}
+             LEFT JOIN song_features f ON f.song_id = s.id
+             LEFT JOIN song_plays p ON p.song_id = s.id
+             WHERE (p.last_played IS NULL OR p.last_played < ?1) AND {VISIBLE}"
+        );
+        let candidates = self
+            .conn
+            .prepare_cached(&sql)?
+            .query_map([played_before], candidate_from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(candidates)
+    }
+}
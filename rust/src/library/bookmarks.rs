@@ -0,0 +1,35 @@
+use rusqlite::{params, OptionalExtension};
+
+use super::{now_secs, Library};
+use crate::SongId;
+
+impl Library {
+    /// Saved playback position of a song in seconds, if it has one
+    pub fn get_bookmark(&self, song_id: &SongId) -> anyhow::Result<Option<f64>> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT position FROM bookmarks WHERE song_id = ?1",
+                [song_id],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    pub fn set_bookmark(&self, song_id: &SongId, position: f64) -> anyhow::Result<()> {
+        self.conn.execute(
+            "INSERT INTO bookmarks (song_id, position, updated_at) VALUES (?1, ?2, ?3)
+             ON CONFLICT (song_id) DO UPDATE SET
+                position = excluded.position,
+                updated_at = excluded.updated_at",
+            params![song_id, position, now_secs()],
+        )?;
+        Ok(())
+    }
+
+    pub fn clear_bookmark(&self, song_id: &SongId) -> anyhow::Result<()> {
+        self.conn
+            .execute("DELETE FROM bookmarks WHERE song_id = ?1", [song_id])?;
+        Ok(())
+    }
+}
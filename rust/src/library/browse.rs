@@ -0,0 +1,179 @@
+use rusqlite::{params, Row};
+
+use super::{Library, VISIBLE};
+use crate::{AlbumSummary, ArtistSummary, BrowseSort, DecadeSummary, GenreSummary};
+
+/// Year, song count and length of the album `al` over its songs the content filter
+/// doesn't hide, for `album_summary_from_row`
+pub(super) const ALBUM_TOTALS: &str =
+    "(SELECT MIN(year) FROM songs JOIN visible_songs USING (id) WHERE album_id = al.id),
+     (SELECT COUNT(*) FROM songs JOIN visible_songs USING (id) WHERE album_id = al.id),
+     (SELECT COALESCE(SUM(duration), 0) FROM songs JOIN visible_songs USING (id)
+      WHERE album_id = al.id)";
+
+/// Songs with their album and album artist, and `p` for their plays
+const BROWSE_JOINS: &str = "songs s
+     JOIN albums al ON al.id = s.album_id
+     JOIN artists ar ON ar.id = al.artist_id
+     LEFT JOIN song_plays p ON p.song_id = s.id";
+
+/// Reads `al.id, al.title, ar.id, ar.name` followed by `ALBUM_TOTALS`
+pub(super) fn album_summary_from_row(row: &Row<'_>) -> rusqlite::Result<AlbumSummary> {
+    Ok(AlbumSummary {
+        id: row.get(0)?,
+        title: row.get(1)?,
+        artist_id: row.get(2)?,
+        artist: row.get(3)?,
+        year: row.get(4)?,
+        song_count: row.get(5)?,
+        duration: row.get::<_, i64>(6)? as u64,
+    })
+}
+
+/// `ORDER BY` terms for a group of songs, falling back to the `name` expression
+fn order_by(sort: BrowseSort, name: &str) -> String {
+    match sort {
+        BrowseSort::Name => name.to_string(),
+        BrowseSort::Year => format!("MIN(s.year) IS NULL, MIN(s.year), {name}"),
+        BrowseSort::DateAdded => format!("MAX(s.date_added) DESC, {name}"),
+        BrowseSort::MostPlayed => format!("COALESCE(SUM(p.play_count), 0) DESC, {name}"),
+    }
+}
+
+impl Library {
+    /// Artists albums are credited to, so a compilation is listed once under "Various
+    /// Artists" rather than under each artist on it
+    pub fn get_artists(&self, sort: BrowseSort) -> anyhow::Result<Vec<ArtistSummary>> {
+        let sql = format!(
+            "SELECT ar.id, ar.name, COUNT(DISTINCT s.album_id), COUNT(*),
+                    COALESCE(SUM(p.play_count), 0)
+             FROM {BROWSE_JOINS}
+             WHERE {VISIBLE}
+             GROUP BY ar.id ORDER BY {}",
+            order_by(sort, "ar.sort_name COLLATE unicode")
+        );
+        let artists = self
+            .conn
+            .prepare_cached(&sql)?
+            .query_map([], |row| {
+                Ok(ArtistSummary {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    album_count: row.get(2)?,
+                    song_count: row.get(3)?,
+                    play_count: row.get(4)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(artists)
+    }
+
+    fn query_albums(
+        &self,
+        filter: &str,
+        sort: BrowseSort,
+        params: impl rusqlite::Params,
+    ) -> anyhow::Result<Vec<AlbumSummary>> {
+        let sql = format!(
+            "SELECT al.id, al.title, ar.id, ar.name, {ALBUM_TOTALS} FROM {BROWSE_JOINS}
+             WHERE ({filter}) AND {VISIBLE} GROUP BY al.id ORDER BY {}",
+            order_by(sort, "al.sort_title COLLATE unicode")
+        );
+        let albums = self
+            .conn
+            .prepare_cached(&sql)?
+            .query_map(params, album_summary_from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(albums)
+    }
+
+    pub fn get_albums_for_artist(
+        &self,
+        artist_id: i64,
+        sort: BrowseSort,
+    ) -> anyhow::Result<Vec<AlbumSummary>> {
+        self.query_albums("al.artist_id = ?1", sort, [artist_id])
+    }
+
+    pub fn get_albums_for_genre(
+        &self,
+        genre: &str,
+        sort: BrowseSort,
+    ) -> anyhow::Result<Vec<AlbumSummary>> {
+        self.query_albums("s.genre = ?1 COLLATE NOCASE", sort, [genre])
+    }
+
+    pub fn get_albums_for_decade(
+        &self,
+        decade: u32,
+        sort: BrowseSort,
+    ) -> anyhow::Result<Vec<AlbumSummary>> {
+        let decade = decade / 10 * 10;
+        self.query_albums(
+            "s.year >= ?1 AND s.year < ?2",
+            sort,
+            params![decade, decade + 10],
+        )
+    }
+
+    /// Songs, artists and albums in the library, and how long all the songs last in
+    /// seconds
+    pub fn get_totals(&self) -> anyhow::Result<(u32, u32, u32, u64)> {
+        let totals = self.conn.query_row(
+            &format!(
+                "SELECT COUNT(*), COUNT(DISTINCT s.artist_id), COUNT(DISTINCT s.album_id),
+                        COALESCE(SUM(s.duration), 0)
+                 FROM songs s WHERE {VISIBLE}"
+            ),
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+        )?;
+        Ok(totals)
+    }
+
+    pub fn get_genres(&self, sort: BrowseSort) -> anyhow::Result<Vec<GenreSummary>> {
+        let sql = format!(
+            "SELECT MIN(s.genre), COUNT(DISTINCT s.artist_id), COUNT(DISTINCT s.album_id),
+                    COUNT(*)
+             FROM songs s LEFT JOIN song_plays p ON p.song_id = s.id
+             WHERE s.genre <> '' AND {VISIBLE}
+             GROUP BY s.genre COLLATE NOCASE ORDER BY {}",
+            order_by(sort, "MIN(s.genre) COLLATE unicode")
+        );
+        let genres = self
+            .conn
+            .prepare_cached(&sql)?
+            .query_map([], |row| {
+                Ok(GenreSummary {
+                    name: row.get(0)?,
+                    artist_count: row.get(1)?,
+                    album_count: row.get(2)?,
+                    song_count: row.get(3)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(genres)
+    }
+
+    pub fn get_decades(&self, sort: BrowseSort) -> anyhow::Result<Vec<DecadeSummary>> {
+        let sql = format!(
+            "SELECT s.year / 10 * 10 AS decade, COUNT(DISTINCT s.album_id), COUNT(*)
+             FROM songs s LEFT JOIN song_plays p ON p.song_id = s.id
+             WHERE s.year > 0 AND {VISIBLE}
+             GROUP BY decade ORDER BY {}",
+            order_by(sort, "decade")
+        );
+        let decades = self
+            .conn
+            .prepare_cached(&sql)?
+            .query_map([], |row| {
+                Ok(DecadeSummary {
+                    decade: row.get(0)?,
+                    album_count: row.get(1)?,
+                    song_count: row.get(2)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(decades)
+    }
+}
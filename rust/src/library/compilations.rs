@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use rusqlite::params;
+
+use super::Library;
+use crate::metadata::{UNKNOWN_ALBUM, VARIOUS_ARTISTS};
+use crate::Song;
+
+/// Fewest tracks of an album before `detect_compilations` considers it
+const MIN_TRACKS: usize = 3;
+
+/// Fewest different track artists an untagged compilation has
+const MIN_ARTISTS: usize = 3;
+
+/// Whether the songs of one album in one folder look like a compilation its tags don't
+/// fully mark: some tracks are tagged as one, or no artist has half the tracks and no
+/// album artist says otherwise
+fn is_compilation(songs: &[&Song]) -> bool {
+    if songs.iter().any(|song| song.compilation) {
+        return true;
+    }
+    if songs.len() < MIN_TRACKS || songs.iter().any(|song| song.album_artist.is_some()) {
+        return false;
+    }
+    let mut tracks_by_artist: HashMap<String, usize> = HashMap::new();
+    for song in songs {
+        *tracks_by_artist
+            .entry(song.artist.to_lowercase())
+            .or_default() += 1;
+    }
+    let most = tracks_by_artist.values().copied().max().unwrap_or(0);
+    tracks_by_artist.len() >= MIN_ARTISTS && most * 2 < songs.len()
+}
+
+impl Library {
+    /// Find albums that are compilations without every track tagged as such, and credit
+    /// them to "Various Artists" so they group as one album
+    ///
+    /// Tracks count as one album when they share an album title and a folder. What's
+    /// found stays marked through rescans. Returns how many albums were regrouped.
+    pub fn detect_compilations(&mut self) -> anyhow::Result<u32> {
+        let songs = self.get_every_song()?;
+        let mut albums: HashMap<(String, &Path), Vec<&Song>> = HashMap::new();
+        for song in &songs {
+            if song.album == UNKNOWN_ALBUM {
+                continue;
+            }
+            let folder = Path::new(&song.file_path).parent().unwrap_or(Path::new(""));
+            albums
+                .entry((song.album.to_lowercase(), folder))
+                .or_default()
+                .push(song);
+        }
+
+        let various_artists = self.ensure_artist(VARIOUS_ARTISTS)?;
+        let mut regroup = Vec::new();
+        for tracks in albums.values() {
+            // Already grouped whole, as when every track is tagged or this ran before
+            if tracks.iter().all(|song| song.compilation) || !is_compilation(tracks) {
+                continue;
+            }
+            let album_id = self.ensure_album(&tracks[0].album, various_artists)?;
+            regroup.push((album_id, tracks));
+        }
+
+        let tx = self.conn.transaction()?;
+        {
+            let mut mark = tx.prepare_cached(
+                "UPDATE songs SET detected_compilation = 1, album_id = ?1 WHERE id = ?2",
+            )?;
+            for (album_id, tracks) in &regroup {
+                for song in tracks.iter() {
+                    mark.execute(params![album_id, song.id])?;
+                }
+            }
+        }
+        tx.commit()?;
+        log::info!("found {} compilations", regroup.len());
+        Ok(regroup.len() as u32)
+    }
+}
@@ -0,0 +1,50 @@
+use rusqlite::params;
+
+use super::Library;
+use crate::ContentFilter;
+
+impl Library {
+    /// Replace the content filter's rules, which `VISIBLE` applies
+    pub fn set_content_filter(&mut self, rules: &ContentFilter) -> anyhow::Result<()> {
+        let tx = self.conn.transaction()?;
+        tx.execute("DELETE FROM content_filter", [])?;
+        {
+            let mut insert = tx.prepare_cached(
+                "INSERT OR IGNORE INTO content_filter (kind, term) VALUES (?1, ?2)",
+            )?;
+            if rules.block_explicit {
+                insert.execute(params!["explicit", ""])?;
+            }
+            for (kind, terms) in [("genre", &rules.genres), ("keyword", &rules.keywords)] {
+                for term in terms {
+                    // SQLite's lower() only folds ASCII, so terms are folded the same way.
+                    let term = term.trim().to_ascii_lowercase();
+                    if !term.is_empty() {
+                        insert.execute(params![kind, term])?;
+                    }
+                }
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn get_content_filter(&self) -> anyhow::Result<ContentFilter> {
+        let mut rules = ContentFilter::default();
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT kind, term FROM content_filter ORDER BY kind, term")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+        for row in rows {
+            let (kind, term) = row?;
+            match kind.as_str() {
+                "explicit" => rules.block_explicit = true,
+                "genre" => rules.genres.push(term),
+                _ => rules.keywords.push(term),
+            }
+        }
+        Ok(rules)
+    }
+}
@@ -0,0 +1,41 @@
+use std::path::Path;
+
+use rusqlite::{params, OptionalExtension};
+
+use super::Library;
+
+impl Library {
+    /// Remember the size and sampled hash `scanner::identify` found for `file_path`
+    pub fn set_file_identity(&self, file_path: &str, size: u64, hash: &str) -> anyhow::Result<()> {
+        self.conn.execute(
+            "INSERT INTO file_identities (file_path, size, hash) VALUES (?1, ?2, ?3)
+             ON CONFLICT (file_path) DO UPDATE SET size = excluded.size, hash = excluded.hash",
+            params![file_path, size as i64, hash],
+        )?;
+        Ok(())
+    }
+
+    /// Size and sampled hash of `file_path` when it was last read
+    pub fn get_file_identity(&self, file_path: &str) -> anyhow::Result<Option<(u64, String)>> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT size, hash FROM file_identities WHERE file_path = ?1",
+                [file_path],
+                |row| Ok((row.get::<_, i64>(0)? as u64, row.get(1)?)),
+            )
+            .optional()?)
+    }
+
+    /// A file the library holds with this size and hash that is no longer where the
+    /// library has it
+    pub fn find_missing_file(&self, size: u64, hash: &str) -> anyhow::Result<Option<String>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT file_path FROM file_identities WHERE size = ?1 AND hash = ?2",
+        )?;
+        let paths = stmt
+            .query_map(params![size as i64, hash], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(paths.into_iter().find(|path| !Path::new(path).exists()))
+    }
+}
@@ -0,0 +1,86 @@
+use std::path::MAIN_SEPARATOR;
+
+use rusqlite::params;
+
+use super::{Library, VISIBLE};
+use crate::Song;
+
+/// Bounds of the paths below `dir`: every path starting with "<dir>/" sorts between
+/// "<dir>/" and "<dir>" with the character after the separator, so the lookup uses the
+/// path index
+fn bounds(dir: &str) -> (String, String) {
+    let dir = dir.trim_end_matches(MAIN_SEPARATOR);
+    let after = char::from_u32(MAIN_SEPARATOR as u32 + 1).expect("separators are ASCII");
+    (format!("{dir}{MAIN_SEPARATOR}"), format!("{dir}{after}"))
+}
+
+impl Library {
+    /// Deepest folder every local song is below, or `None` without any
+    pub fn get_music_root(&self) -> anyhow::Result<Option<String>> {
+        // The common start of the first and last path in order is that of all of them.
+        let (first, last): (Option<String>, Option<String>) = self.conn.query_row(
+            "SELECT MIN(file_path), MAX(file_path) FROM songs WHERE file_path NOT LIKE '%://%'",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        let (Some(first), Some(last)) = (first, last) else {
+            return Ok(None);
+        };
+        let common: String = first
+            .chars()
+            .zip(last.chars())
+            .take_while(|(a, b)| a == b)
+            .map(|(a, _)| a)
+            .collect();
+        let root = match common.rfind(MAIN_SEPARATOR) {
+            // Keep the separator of a root like "/" or "C:\".
+            Some(end) if !common[..end].contains(MAIN_SEPARATOR) => &common[..=end],
+            Some(end) => &common[..end],
+            None => "",
+        };
+        Ok(Some(root.to_string()))
+    }
+
+    /// Names of the folders directly in `dir`, and the songs of the files directly in
+    /// it, both by path
+    pub fn get_folder(&self, dir: &str) -> anyhow::Result<(Vec<String>, Vec<Song>)> {
+        let (start, end) = bounds(dir);
+        let mut folders: Vec<String> = Vec::new();
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "SELECT DISTINCT s.file_path FROM songs s
+             WHERE s.file_path > ?1 AND s.file_path < ?2 AND {VISIBLE}
+             ORDER BY s.file_path"
+        ))?;
+        let mut rows = stmt.query(params![start, end])?;
+        while let Some(row) = rows.next()? {
+            let path: String = row.get(0)?;
+            let Some((folder, _)) = path[start.len()..].split_once(MAIN_SEPARATOR) else {
+                continue;
+            };
+            if folders.last().map(String::as_str) != Some(folder) {
+                folders.push(folder.to_string());
+            }
+        }
+        let songs = self.query_songs(
+            &format!(
+                "WHERE s.file_path > ?1 AND s.file_path < ?2
+                    AND instr(substr(s.file_path, length(?1) + 1), ?3) = 0 AND {VISIBLE}
+                 ORDER BY s.file_path, s.start_offset"
+            ),
+            params![start, end, MAIN_SEPARATOR.to_string()],
+        )?;
+        Ok((folders, songs))
+    }
+
+    /// Songs of every file below `dir`, by path
+    pub fn get_songs_below(&self, dir: &str) -> anyhow::Result<Vec<Song>> {
+        let (start, end) = bounds(dir);
+        self.query_songs(
+            &format!(
+                "WHERE s.file_path > ?1 AND s.file_path < ?2 AND {VISIBLE}
+                 ORDER BY s.file_path, s.start_offset"
+            ),
+            params![start, end],
+        )
+    }
+}
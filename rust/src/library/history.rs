@@ -0,0 +1,103 @@
+use rusqlite::{params, Row};
+
+use super::{
+    now_secs, song_from_row, Library, SONG_COLUMNS, SONG_COLUMN_COUNT, SONG_JOINS, VISIBLE,
+};
+use crate::{ListeningStats, PlayedSong, SongId};
+
+fn played_song_from_row(row: &Row<'_>) -> rusqlite::Result<PlayedSong> {
+    Ok(PlayedSong {
+        song: song_from_row(row)?,
+        play_count: row.get(SONG_COLUMN_COUNT)?,
+        last_played: row.get(SONG_COLUMN_COUNT + 1)?,
+    })
+}
+
+fn stats_from_row(row: &Row<'_>) -> rusqlite::Result<ListeningStats> {
+    Ok(ListeningStats {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        play_count: row.get(2)?,
+        listened_secs: row.get::<_, i64>(3)? as u64,
+        last_played: row.get(4)?,
+    })
+}
+
+impl Library {
+    /// Count a play of a library song; songs outside the library are ignored
+    pub fn record_play(&mut self, song_id: &SongId) -> anyhow::Result<()> {
+        let now = now_secs();
+        let tx = self.conn.transaction()?;
+        let recorded = tx.execute(
+            "INSERT INTO play_history (song_id, played_at)
+             SELECT id, ?2 FROM songs WHERE id = ?1",
+            params![song_id, now],
+        )?;
+        if recorded == 1 {
+            tx.execute(
+                "INSERT INTO song_plays (song_id, play_count, last_played) VALUES (?1, 1, ?2)
+                 ON CONFLICT (song_id) DO UPDATE SET
+                    play_count = play_count + 1,
+                    last_played = excluded.last_played",
+                params![song_id, now],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn query_played_songs(&self, order: &str, limit: u32) -> anyhow::Result<Vec<PlayedSong>> {
+        let sql = format!(
+            "SELECT {SONG_COLUMNS}, p.play_count, p.last_played
+             FROM {SONG_JOINS} JOIN song_plays p ON p.song_id = s.id
+             WHERE {VISIBLE}
+             ORDER BY {order} LIMIT ?1"
+        );
+        let songs = self
+            .conn
+            .prepare_cached(&sql)?
+            .query_map([limit], played_song_from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(songs)
+    }
+
+    pub fn get_recently_played(&self, limit: u32) -> anyhow::Result<Vec<PlayedSong>> {
+        self.query_played_songs("p.last_played DESC", limit)
+    }
+
+    pub fn get_most_played(&self, limit: u32) -> anyhow::Result<Vec<PlayedSong>> {
+        self.query_played_songs("p.play_count DESC, p.last_played DESC", limit)
+    }
+
+    /// Plays per artist or album, most played first; `group` names the column of `s`
+    /// to group by and `name` the expression naming each group
+    fn listening_stats(
+        &self,
+        group: &str,
+        name: &str,
+        limit: u32,
+    ) -> anyhow::Result<Vec<ListeningStats>> {
+        let sql = format!(
+            "SELECT s.{group}, {name}, SUM(p.play_count), SUM(p.play_count * s.duration),
+                    MAX(p.last_played)
+             FROM {SONG_JOINS} JOIN song_plays p ON p.song_id = s.id
+             WHERE {VISIBLE}
+             GROUP BY s.{group} ORDER BY SUM(p.play_count) DESC, MAX(p.last_played) DESC
+             LIMIT ?1"
+        );
+        let stats = self
+            .conn
+            .prepare_cached(&sql)?
+            .query_map([limit], stats_from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(stats)
+    }
+
+    pub fn get_artist_listening_stats(&self, limit: u32) -> anyhow::Result<Vec<ListeningStats>> {
+        self.listening_stats("artist_id", "ar.name", limit)
+    }
+
+    pub fn get_album_listening_stats(&self, limit: u32) -> anyhow::Result<Vec<ListeningStats>> {
+        self.listening_stats("album_id", "al.title", limit)
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::{HashMap, HashSet};
+
+use rusqlite::params;
+
+use super::playlists::write_entries;
+use super::{now_secs, Library};
+use crate::importer::{ForeignLibrary, ForeignTrack};
+use crate::metadata::UNKNOWN_ARTIST;
+use crate::{ImportReport, PlaylistImport, Song, SongId, UnmatchedTrack};
+
+/// How far apart, in seconds, the lengths of a track and a song with its artist and
+/// title may be for them to count as the same recording
+const LENGTH_TOLERANCE_SECS: f64 = 3.0;
+
+/// Artist and title, as compared between libraries
+fn name_key(artist: &str, title: &str) -> (String, String) {
+    let artist = match artist.trim() {
+        "" => UNKNOWN_ARTIST,
+        artist => artist,
+    };
+    (artist.to_lowercase(), title.trim().to_lowercase())
+}
+
+/// The song `track` most likely is among those with its artist and title: one of the
+/// same length, preferably on the same album
+fn match_by_name<'a>(
+    by_name: &HashMap<(String, String), Vec<&'a Song>>,
+    track: &ForeignTrack,
+) -> Option<&'a Song> {
+    if track.title.trim().is_empty() {
+        return None;
+    }
+    let album = track.album.trim().to_lowercase();
+    let length_difference = |song: &Song| {
+        track
+            .duration
+            .map_or(0.0, |duration| (song.duration as f64 - duration).abs())
+    };
+    by_name
+        .get(&name_key(&track.artist, &track.title))?
+        .iter()
+        .filter(|song| length_difference(song) <= LENGTH_TOLERANCE_SECS)
+        .min_by(|a, b| {
+            let other_album = |song: &Song| song.album.to_lowercase() != album;
+            (other_album(a), length_difference(a))
+                .partial_cmp(&(other_album(b), length_difference(b)))
+                .unwrap_or(std::cmp::Ordering::Equal)
+        })
+        .copied()
+}
+
+impl Library {
+    /// Match the tracks of another player's library to songs and, unless `dry_run`,
+    /// merge in their plays, ratings, dates added and loved marks, and the playlists
+    /// whose names aren't taken yet
+    ///
+    /// Merging keeps the higher play count, the later last play and the earlier date
+    /// added, and only rates songs without a rating, so importing twice is harmless.
+    pub fn import_foreign(
+        &mut self,
+        foreign: &ForeignLibrary,
+        dry_run: bool,
+    ) -> anyhow::Result<ImportReport> {
+        let songs = self.get_all_songs()?;
+        let by_path: HashMap<&str, &Song> =
+            songs.iter().map(|s| (s.file_path.as_str(), s)).collect();
+        let mut by_name: HashMap<(String, String), Vec<&Song>> = HashMap::new();
+        for song in &songs {
+            by_name
+                .entry(name_key(&song.artist, &song.title))
+                .or_default()
+                .push(song);
+        }
+
+        let mut report = ImportReport {
+            tracks: foreign.tracks.len() as u32,
+            matched_by_path: 0,
+            matched_by_tags: 0,
+            unmatched: Vec::new(),
+            playlists: Vec::new(),
+            applied: !dry_run,
+        };
+        let mut matched: Vec<(&ForeignTrack, &str)> = Vec::new();
+        let mut ids: HashMap<&str, &str> = HashMap::new();
+        for track in &foreign.tracks {
+            let at_path = track
+                .path
+                .as_ref()
+                .and_then(|path| by_path.get(path.to_string_lossy().as_ref()).copied());
+            let song = match at_path {
+                Some(song) => {
+                    report.matched_by_path += 1;
+                    song
+                }
+                None => match match_by_name(&by_name, track) {
+                    Some(song) => {
+                        report.matched_by_tags += 1;
+                        song
+                    }
+                    None => {
+                        report.unmatched.push(UnmatchedTrack {
+                            title: track.title.clone(),
+                            artist: track.artist.clone(),
+                            album: track.album.clone(),
+                            path: track
+                                .path
+                                .as_ref()
+                                .map(|path| path.to_string_lossy().into_owned()),
+                        });
+                        continue;
+                    }
+                },
+            };
+            matched.push((track, song.id.as_str()));
+            ids.insert(track.key.as_str(), song.id.as_str());
+        }
+
+        let taken: HashSet<String> = self.get_playlists()?.into_iter().map(|p| p.name).collect();
+        let mut playlists = Vec::new();
+        for playlist in &foreign.playlists {
+            let song_ids: Vec<SongId> = playlist
+                .tracks
+                .iter()
+                .filter_map(|key| ids.get(key.as_str()))
+                .map(|id| SongId(id.to_string()))
+                .collect();
+            let already_exists = taken.contains(&playlist.name);
+            report.playlists.push(PlaylistImport {
+                name: playlist.name.clone(),
+                tracks: playlist.tracks.len() as u32,
+                matched: song_ids.len() as u32,
+                already_exists,
+            });
+            if !already_exists {
+                playlists.push((playlist.name.as_str(), song_ids));
+            }
+        }
+        if dry_run {
+            return Ok(report);
+        }
+
+        let tx = self.conn.transaction()?;
+        for (track, song_id) in matched {
+            if track.play_count > 0 {
+                let last_played = track.last_played.or(track.date_added).unwrap_or(0);
+                tx.execute(
+                    "INSERT INTO song_plays (song_id, play_count, last_played) VALUES (?1, ?2, ?3)
+                     ON CONFLICT (song_id) DO UPDATE SET
+                        play_count = MAX(play_count, excluded.play_count),
+                        last_played = MAX(last_played, excluded.last_played)",
+                    params![song_id, track.play_count, last_played],
+                )?;
+            }
+            if let Some(rating) = track.rating {
+                tx.execute(
+                    "UPDATE songs SET rating = ?1 WHERE id = ?2 AND rating IS NULL",
+                    params![rating, song_id],
+                )?;
+            }
+            if let Some(date_added) = track.date_added {
+                tx.execute(
+                    "UPDATE songs SET date_added = MIN(date_added, ?1) WHERE id = ?2",
+                    params![date_added, song_id],
+                )?;
+            }
+            if track.loved {
+                tx.execute("UPDATE songs SET favorite = 1 WHERE id = ?1", [song_id])?;
+            }
+        }
+        for (name, song_ids) in playlists {
+            tx.execute(
+                "INSERT INTO playlists (name, created_at) VALUES (?1, ?2)",
+                params![name, now_secs()],
+            )?;
+            write_entries(&tx, tx.last_insert_rowid(), &song_ids)?;
+        }
+        tx.commit()?;
+        Ok(report)
+    }
+}
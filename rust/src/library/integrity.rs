@@ -0,0 +1,56 @@
+use std::collections::HashSet;
+
+use super::Library;
+use crate::{Song, SongId};
+
+impl Library {
+    /// Every song, whatever the content filter hides, in file order
+    pub fn get_every_song(&self) -> anyhow::Result<Vec<Song>> {
+        self.query_songs("ORDER BY s.file_path, s.start_offset", [])
+    }
+
+    /// Ids that playlist entries point at but no song has
+    fn dangling_song_ids(&self) -> anyhow::Result<HashSet<SongId>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT DISTINCT song_id FROM playlist_songs
+             WHERE song_id NOT IN (SELECT id FROM songs)",
+        )?;
+        let ids = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(ids)
+    }
+
+    /// Playlist entries pointing at songs the library doesn't have
+    pub fn count_dangling_playlist_entries(&self) -> anyhow::Result<u32> {
+        Ok(self.conn.query_row(
+            "SELECT COUNT(*) FROM playlist_songs WHERE song_id NOT IN (SELECT id FROM songs)",
+            [],
+            |row| row.get(0),
+        )?)
+    }
+
+    /// Drop the playlist entries pointing at songs the library doesn't have, closing the
+    /// gaps they leave; returns how many were dropped
+    pub fn remove_dangling_playlist_entries(&mut self) -> anyhow::Result<u32> {
+        let dangling = self.dangling_song_ids()?;
+        let playlists: Vec<i64> = self
+            .conn
+            .prepare_cached(
+                "SELECT DISTINCT playlist_id FROM playlist_songs
+                 WHERE song_id NOT IN (SELECT id FROM songs)",
+            )?
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        let mut removed = 0;
+        for id in playlists {
+            self.edit_playlist(id, |entries| {
+                let before = entries.len();
+                entries.retain(|song_id| !dangling.contains(song_id));
+                removed += (before - entries.len()) as u32;
+                Ok(())
+            })?;
+        }
+        Ok(removed)
+    }
+}
@@ -0,0 +1,544 @@
+mod analysis;
+mod auto_dj;
+mod bookmarks;
+mod browse;
+mod compilations;
+mod content_filter;
+mod file_identities;
+mod folders;
+mod history;
+mod import;
+mod integrity;
+mod offline;
+mod pages;
+mod playlists;
+mod podcasts;
+mod quarantine;
+mod ratings;
+mod schema;
+mod scrobbles;
+mod search;
+mod silence;
+mod smart_playlists;
+mod snapshot;
+mod sources;
+
+pub(crate) use auto_dj::Candidate;
+pub(crate) use pages::{stream_songs, DEFAULT_PAGE_SIZE};
+pub(crate) use playlists::write_m3u;
+pub(crate) use snapshot::restore_snapshot;
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf, MAIN_SEPARATOR};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+
+use crate::{collation, metadata, storage, Album, Song, SongId, StorageKind};
+
+/// Columns selected by every song query, matching `song_from_row`
+pub(crate) const SONG_COLUMNS: &str = "s.id, s.title, ar.name, al.title, s.genre, s.year,
+     s.rating, s.favorite, s.duration, s.file_path, s.start_offset, s.end_offset, s.track_loudness, al.loudness,
+     s.bpm, s.musical_key, s.explicit, s.album_artist, s.compilation OR s.detected_compilation,
+     ar.sort_name, al.sort_title, s.disc_number, s.track_number";
+
+/// Number of columns in `SONG_COLUMNS`, where extra selected columns start
+pub(crate) const SONG_COLUMN_COUNT: usize = 23;
+
+/// Order of songs within an album: by disc, then track, with untracked songs last
+pub(crate) const TRACK_ORDER: &str = "COALESCE(s.disc_number, 1), s.track_number IS NULL,
+     COALESCE(s.track_number, 0), s.title COLLATE unicode";
+
+/// Order of song lists spanning albums: by artist and album sort names, then as
+/// `TRACK_ORDER`; no term is ever NULL, so rows compare against a page cursor
+pub(crate) const SONG_ORDER: &str = "ar.sort_name COLLATE unicode,
+     al.sort_title COLLATE unicode, COALESCE(s.disc_number, 1), s.track_number IS NULL,
+     COALESCE(s.track_number, 0), s.title COLLATE unicode";
+
+/// Joins needed by `SONG_COLUMNS`, with `s` as the songs alias
+pub(crate) const SONG_JOINS: &str = "songs s
+     JOIN artists ar ON ar.id = s.artist_id
+     JOIN albums al ON al.id = s.album_id";
+
+/// Condition on the song `s` that the content filter doesn't hide it
+pub(crate) const VISIBLE: &str = "s.id IN (SELECT id FROM visible_songs)";
+
+static LIBRARY: Mutex<Option<Library>> = Mutex::new(None);
+
+/// SQLite-backed song library
+pub(crate) struct Library {
+    conn: Connection,
+    /// Directory of the database file, which also holds derived caches
+    cache_root: PathBuf,
+}
+
+/// Make `library` the open one, replacing any open before
+pub(crate) fn install(library: Library) {
+    *LIBRARY.lock().unwrap() = Some(library);
+}
+
+/// Close the open library, if any
+pub(crate) fn close() {
+    *LIBRARY.lock().unwrap() = None;
+}
+
+pub(crate) fn is_open() -> bool {
+    LIBRARY.lock().unwrap().is_some()
+}
+
+/// Update the library row of a song whose tags were just rewritten, if it has one
+pub(crate) fn refresh_song(song: &Song) -> anyhow::Result<()> {
+    if !is_open() {
+        return Ok(());
+    }
+    with_library(
+        |lib| match lib.get_file_songs(&song.file_path)?.is_empty() {
+            false => lib.upsert_song(song).map(drop),
+            true => Ok(()),
+        },
+    )
+}
+
+/// Give a song just read from its file the id the open library has it under, if any
+pub(crate) fn adopt_stored_id(song: &mut Song) -> anyhow::Result<()> {
+    if !is_open() {
+        return Ok(());
+    }
+    let stored = with_library(|lib| lib.get_file_songs(&song.file_path))?;
+    if let Some(stored) = stored
+        .into_iter()
+        .find(|stored| stored.start_offset == song.start_offset)
+    {
+        song.id = stored.id;
+    }
+    Ok(())
+}
+
+/// Run `f` against the open library
+pub(crate) fn with_library<T>(
+    f: impl FnOnce(&mut Library) -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    let mut guard = LIBRARY.lock().unwrap();
+    let library = guard
+        .as_mut()
+        .context("library database is not open; call open_library first")?;
+    f(library)
+}
+
+/// Directory for files of `kind`, if `init_storage` was called or a library is open
+pub(crate) fn cache_dir(kind: StorageKind) -> Option<PathBuf> {
+    storage::configured(kind).or_else(|| {
+        LIBRARY
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|lib| lib.cache_dir(kind))
+    })
+}
+
+pub(crate) fn now_secs() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+pub(crate) fn song_from_row(row: &Row<'_>) -> rusqlite::Result<Song> {
+    Ok(Song {
+        id: row.get(0)?,
+        title: row.get(1)?,
+        artist: row.get(2)?,
+        album: row.get(3)?,
+        album_artist: row.get(17)?,
+        compilation: row.get(18)?,
+        artist_sort: row.get(19)?,
+        album_sort: row.get(20)?,
+        disc_number: row.get(21)?,
+        track_number: row.get(22)?,
+        genre: row.get(4)?,
+        year: row.get(5)?,
+        rating: row.get(6)?,
+        favorite: row.get(7)?,
+        duration: row.get::<_, i64>(8)? as u64,
+        file_path: row.get(9)?,
+        start_offset: row.get(10)?,
+        end_offset: row.get(11)?,
+        track_loudness: row.get(12)?,
+        album_loudness: row.get(13)?,
+        bpm: row.get(14)?,
+        key: row.get(15)?,
+        explicit: row.get(16)?,
+    })
+}
+
+impl Library {
+    /// Open (creating if needed) and migrate the database at `path`, without making it
+    /// the open library
+    pub(crate) fn open(path: &Path) -> anyhow::Result<Self> {
+        let mut conn = Connection::open(path)
+            .with_context(|| format!("failed to open library at {}", path.display()))?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.create_collation(collation::NAME, collation::compare)?;
+        schema::migrate(&mut conn)?;
+        conn.pragma_update(None, "foreign_keys", true)?;
+        let cache_root = path.parent().unwrap_or(Path::new("")).to_path_buf();
+        Ok(Library { conn, cache_root })
+    }
+
+    /// Directory for files of `kind`: where `init_storage` put them, or next to the
+    /// database
+    pub fn cache_dir(&self, kind: StorageKind) -> PathBuf {
+        storage::configured(kind).unwrap_or_else(|| self.cache_root.join(storage::folder(kind)))
+    }
+
+    fn ensure_artist(&self, name: &str) -> rusqlite::Result<i64> {
+        self.conn.execute(
+            "INSERT INTO artists (name, sort_name) VALUES (?1, ?2)
+             ON CONFLICT (name) DO NOTHING",
+            [name, metadata::sort_name(name)],
+        )?;
+        self.conn
+            .query_row("SELECT id FROM artists WHERE name = ?1", [name], |row| {
+                row.get(0)
+            })
+    }
+
+    fn ensure_album(&self, title: &str, artist_id: i64) -> rusqlite::Result<i64> {
+        self.conn.execute(
+            "INSERT INTO albums (title, artist_id, sort_title) VALUES (?1, ?2, ?3)
+             ON CONFLICT (title, artist_id) DO NOTHING",
+            params![title, artist_id, metadata::sort_name(title)],
+        )?;
+        self.conn.query_row(
+            "SELECT id FROM albums WHERE title = ?1 AND artist_id = ?2",
+            params![title, artist_id],
+            |row| row.get(0),
+        )
+    }
+
+    /// `upsert_song` for every one of `songs`, storing none of them if one fails
+    pub fn upsert_songs(&mut self, songs: &[Song]) -> anyhow::Result<()> {
+        // A savepoint rather than a transaction, as `upsert_song` needs the connection.
+        self.conn.execute_batch("SAVEPOINT upsert_songs")?;
+        let result = songs
+            .iter()
+            .try_for_each(|song| self.upsert_song(song).map(drop));
+        self.conn.execute_batch(match result {
+            Ok(()) => "RELEASE upsert_songs",
+            Err(_) => "ROLLBACK TO upsert_songs; RELEASE upsert_songs",
+        })?;
+        result
+    }
+
+    /// Insert or refresh a song, keeping its original date added and favorite mark
+    ///
+    /// A song already stored for the same track of the same file keeps its id, and its
+    /// album stays a compilation if `detect_compilations` found it to be one. Returns
+    /// the id the song is stored under.
+    pub fn upsert_song(&mut self, song: &Song) -> anyhow::Result<SongId> {
+        let id = self.stored_id(song)?;
+        let detected: bool = self
+            .conn
+            .query_row(
+                "SELECT detected_compilation FROM songs WHERE id = ?1",
+                [&id],
+                |row| row.get(0),
+            )
+            .optional()?
+            .unwrap_or(false);
+        let artist_id = self.ensure_artist(&song.artist)?;
+        let credited = match song.compilation || detected {
+            true => metadata::VARIOUS_ARTISTS,
+            false => song.album_artist.as_deref().unwrap_or(&song.artist),
+        };
+        let album_id = self.ensure_album(&song.album, self.ensure_artist(credited)?)?;
+        // Tagged sort names win over the ones made up when the artist or album was added.
+        if let Some(sort_name) = &song.artist_sort {
+            self.conn.execute(
+                "UPDATE artists SET sort_name = ?1 WHERE id = ?2",
+                params![sort_name, artist_id],
+            )?;
+        }
+        if let Some(sort_title) = &song.album_sort {
+            self.conn.execute(
+                "UPDATE albums SET sort_title = ?1 WHERE id = ?2",
+                params![sort_title, album_id],
+            )?;
+        }
+        if let Some(loudness) = song.album_loudness {
+            self.conn.execute(
+                "UPDATE albums SET loudness = ?1 WHERE id = ?2",
+                params![loudness, album_id],
+            )?;
+        }
+        self.conn.execute(
+            "INSERT INTO songs (id, title, artist_id, album_id, genre, year, rating,
+                                duration, file_path, start_offset, end_offset,
+                                track_loudness, bpm, musical_key, explicit, album_artist,
+                                compilation, disc_number, track_number, date_added)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16,
+                     ?17, ?18, ?19, ?20)
+             ON CONFLICT (id) DO UPDATE SET
+                title = excluded.title,
+                artist_id = excluded.artist_id,
+                album_id = excluded.album_id,
+                genre = excluded.genre,
+                year = excluded.year,
+                rating = COALESCE(excluded.rating, songs.rating),
+                duration = excluded.duration,
+                file_path = excluded.file_path,
+                start_offset = excluded.start_offset,
+                end_offset = excluded.end_offset,
+                track_loudness = COALESCE(excluded.track_loudness, songs.track_loudness),
+                bpm = COALESCE(excluded.bpm, songs.bpm),
+                musical_key = COALESCE(excluded.musical_key, songs.musical_key),
+                explicit = excluded.explicit,
+                album_artist = excluded.album_artist,
+                compilation = excluded.compilation,
+                disc_number = excluded.disc_number,
+                track_number = excluded.track_number",
+            params![
+                id,
+                song.title,
+                artist_id,
+                album_id,
+                song.genre,
+                song.year,
+                song.rating,
+                song.duration as i64,
+                song.file_path,
+                song.start_offset,
+                song.end_offset,
+                song.track_loudness,
+                song.bpm,
+                song.key,
+                song.explicit,
+                song.album_artist,
+                song.compilation,
+                song.disc_number,
+                song.track_number,
+                now_secs(),
+            ],
+        )?;
+        Ok(id)
+    }
+
+    /// Id to store `song` under: that of the song stored for the same track of its
+    /// file, or else its own, reissued until no other song has it
+    fn stored_id(&self, song: &Song) -> anyhow::Result<SongId> {
+        let stored = self
+            .conn
+            .query_row(
+                "SELECT id FROM songs WHERE file_path = ?1 AND start_offset = ?2",
+                params![song.file_path, song.start_offset],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if let Some(id) = stored {
+            return Ok(id);
+        }
+        let mut id = song.id.clone();
+        let mut attempt = 0;
+        while self
+            .conn
+            .query_row("SELECT 1 FROM songs WHERE id = ?1", [&id], |_| Ok(()))
+            .optional()?
+            .is_some()
+        {
+            attempt += 1;
+            id = song.id.reissued(attempt);
+        }
+        Ok(id)
+    }
+
+    /// Store `songs` as everything the library holds for `file_path`, e.g. when a CUE
+    /// sheet splits a file that used to be a single song
+    ///
+    /// Returns the songs with the ids they're stored under.
+    pub fn replace_file_songs(
+        &mut self,
+        file_path: &str,
+        songs: &[Song],
+    ) -> anyhow::Result<Vec<Song>> {
+        let stale: Vec<SongId> = self
+            .conn
+            .prepare_cached("SELECT id, start_offset FROM songs WHERE file_path = ?1")?
+            .query_map([file_path], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<Vec<(SongId, f64)>>>()?
+            .into_iter()
+            .filter(|(_, offset)| !songs.iter().any(|s| s.start_offset == *offset))
+            .map(|(id, _)| id)
+            .collect();
+        for id in stale {
+            self.conn.execute("DELETE FROM songs WHERE id = ?1", [id])?;
+        }
+        let mut stored = Vec::with_capacity(songs.len());
+        for song in songs {
+            let id = self.upsert_song(song)?;
+            // The file changed, so its silence may have too.
+            self.conn
+                .execute("DELETE FROM song_silence WHERE song_id = ?1", [&id])?;
+            stored.push(Song { id, ..song.clone() });
+        }
+        Ok(stored)
+    }
+
+    /// Songs stored for the file at `file_path`; several for a CUE sheet image
+    pub fn get_file_songs(&self, file_path: &str) -> anyhow::Result<Vec<Song>> {
+        self.query_songs(
+            "WHERE s.file_path = ?1 ORDER BY s.start_offset",
+            [file_path],
+        )
+    }
+
+    /// Ids and paths of the songs stored for the file at `path`, or for any file under
+    /// the directory at `path`
+    fn songs_under(&self, path: &str) -> anyhow::Result<Vec<(SongId, String)>> {
+        let directory = format!("{path}{MAIN_SEPARATOR}");
+        let songs = self
+            .conn
+            .prepare_cached(
+                "SELECT id, file_path FROM songs
+                 WHERE file_path = ?1 OR substr(file_path, 1, length(?2)) = ?2",
+            )?
+            .query_map(params![path, directory], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(songs)
+    }
+
+    /// Remove the songs of a deleted file or directory, returning their ids
+    pub fn remove_path(&mut self, path: &str) -> anyhow::Result<Vec<SongId>> {
+        let removed = self.songs_under(path)?;
+        let tx = self.conn.transaction()?;
+        for (id, file_path) in &removed {
+            tx.execute("DELETE FROM songs WHERE id = ?1", [id])?;
+            tx.execute(
+                "DELETE FROM file_identities WHERE file_path = ?1",
+                [file_path],
+            )?;
+        }
+        tx.commit()?;
+        Ok(removed.into_iter().map(|(id, _)| id).collect())
+    }
+
+    /// Point the songs of a moved file or directory at their new path, where they keep
+    /// their ids and with them their playlist entries, plays and bookmarks
+    ///
+    /// Returns the moved songs.
+    pub fn move_path(&mut self, from: &str, to: &str) -> anyhow::Result<Vec<Song>> {
+        let moved = self.songs_under(from)?;
+        let moved_ids: HashSet<&SongId> = moved.iter().map(|(id, _)| id).collect();
+        let new_path = |file_path: &str| format!("{to}{}", &file_path[from.len()..]);
+        let tx = self.conn.transaction()?;
+        // Whatever was at the destination has been overwritten.
+        for (_, file_path) in &moved {
+            let overwritten: Vec<SongId> = tx
+                .prepare_cached("SELECT id FROM songs WHERE file_path = ?1")?
+                .query_map([new_path(file_path)], |row| row.get(0))?
+                .collect::<rusqlite::Result<_>>()?;
+            for id in overwritten.iter().filter(|id| !moved_ids.contains(id)) {
+                tx.execute("DELETE FROM songs WHERE id = ?1", [id])?;
+            }
+        }
+        for (id, file_path) in &moved {
+            let new_path = new_path(file_path);
+            tx.execute(
+                "UPDATE songs SET file_path = ?1 WHERE id = ?2",
+                params![new_path, id],
+            )?;
+            tx.execute(
+                "UPDATE OR REPLACE file_identities SET file_path = ?1 WHERE file_path = ?2",
+                params![new_path, file_path],
+            )?;
+        }
+        tx.commit()?;
+
+        let mut songs = Vec::with_capacity(moved.len());
+        for (id, _) in &moved {
+            songs.extend(self.get_song(id)?);
+        }
+        Ok(songs)
+    }
+
+    fn query_songs(
+        &self,
+        filter: &str,
+        params: impl rusqlite::Params,
+    ) -> anyhow::Result<Vec<Song>> {
+        let sql = format!("SELECT {SONG_COLUMNS} FROM {SONG_JOINS} {filter}");
+        let mut stmt = self.conn.prepare_cached(&sql)?;
+        let songs = stmt
+            .query_map(params, song_from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(songs)
+    }
+
+    pub fn get_song(&self, id: &SongId) -> anyhow::Result<Option<Song>> {
+        Ok(self
+            .query_songs("WHERE s.id = ?1", [id])?
+            .into_iter()
+            .next())
+    }
+
+    pub fn get_all_songs(&self) -> anyhow::Result<Vec<Song>> {
+        self.query_songs(&format!("WHERE {VISIBLE} ORDER BY {SONG_ORDER}, s.id"), [])
+    }
+
+    pub fn get_artist_songs(&self, artist_id: i64) -> anyhow::Result<Vec<Song>> {
+        self.query_songs(
+            &format!("WHERE s.artist_id = ?1 AND {VISIBLE} ORDER BY al.sort_title COLLATE unicode, {TRACK_ORDER}"),
+            [artist_id],
+        )
+    }
+
+    pub fn get_album(&self, album_id: i64) -> anyhow::Result<Option<Album>> {
+        let album = self
+            .conn
+            .query_row(
+                "SELECT al.id, al.title, ar.id, ar.name, al.loudness
+                 FROM albums al JOIN artists ar ON ar.id = al.artist_id
+                 WHERE al.id = ?1",
+                [album_id],
+                |row| {
+                    Ok(Album {
+                        id: row.get(0)?,
+                        title: row.get(1)?,
+                        artist_id: row.get(2)?,
+                        artist: row.get(3)?,
+                        loudness: row.get(4)?,
+                        songs: Vec::new(),
+                    })
+                },
+            )
+            .optional()?;
+        let Some(mut album) = album else {
+            return Ok(None);
+        };
+        album.songs = self.query_songs(
+            &format!("WHERE s.album_id = ?1 AND {VISIBLE} ORDER BY {TRACK_ORDER}"),
+            [album_id],
+        )?;
+        Ok(Some(album))
+    }
+
+    /// Case-insensitive substring match on title, artist and album
+    pub fn search(&self, query: &str) -> anyhow::Result<Vec<Song>> {
+        let escaped = query
+            .trim()
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_");
+        let pattern = format!("%{escaped}%");
+        self.query_songs(
+            &format!(
+                "WHERE (s.title LIKE ?1 ESCAPE '\\' OR ar.name LIKE ?1 ESCAPE '\\'
+                    OR al.title LIKE ?1 ESCAPE '\\') AND {VISIBLE}
+                 ORDER BY {SONG_ORDER}"
+            ),
+            [pattern],
+        )
+    }
+}
@@ -0,0 +1,87 @@
+use rusqlite::{params, OptionalExtension, Row};
+
+use super::{now_secs, Library};
+use crate::offline::OfflineCopy;
+
+const COPY_COLUMNS: &str = "item, local_path, size, md5, pinned, used_at";
+
+fn copy_from_row(row: &Row<'_>) -> rusqlite::Result<OfflineCopy> {
+    Ok(OfflineCopy {
+        item: row.get(0)?,
+        local_path: row.get(1)?,
+        size: row.get::<_, i64>(2)? as u64,
+        md5: row.get(3)?,
+        pinned: row.get(4)?,
+        used_at: row.get(5)?,
+    })
+}
+
+impl Library {
+    pub fn put_offline_copy(&self, copy: &OfflineCopy) -> anyhow::Result<()> {
+        self.conn.execute(
+            "INSERT INTO offline_copies (item, local_path, size, md5, pinned, used_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+             ON CONFLICT (item) DO UPDATE SET
+                local_path = excluded.local_path,
+                size = excluded.size,
+                md5 = excluded.md5,
+                pinned = excluded.pinned,
+                used_at = excluded.used_at",
+            params![
+                copy.item,
+                copy.local_path,
+                copy.size as i64,
+                copy.md5,
+                copy.pinned,
+                copy.used_at,
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_offline_copy(&self, item: &str) -> anyhow::Result<Option<OfflineCopy>> {
+        Ok(self
+            .conn
+            .query_row(
+                &format!("SELECT {COPY_COLUMNS} FROM offline_copies WHERE item = ?1"),
+                [item],
+                copy_from_row,
+            )
+            .optional()?)
+    }
+
+    /// Every offline copy, least recently used first
+    pub fn get_offline_copies(&self) -> anyhow::Result<Vec<OfflineCopy>> {
+        Ok(self
+            .conn
+            .prepare_cached(&format!(
+                "SELECT {COPY_COLUMNS} FROM offline_copies ORDER BY used_at, item"
+            ))?
+            .query_map([], copy_from_row)?
+            .collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Pin or unpin a copy, returning whether there is one
+    pub fn set_offline_pinned(&self, item: &str, pinned: bool) -> anyhow::Result<bool> {
+        let changed = self.conn.execute(
+            "UPDATE offline_copies SET pinned = ?2 WHERE item = ?1",
+            params![item, pinned],
+        )?;
+        Ok(changed == 1)
+    }
+
+    /// Record that a copy was just used, for eviction
+    pub fn touch_offline_copy(&self, item: &str) -> anyhow::Result<()> {
+        self.conn.execute(
+            "UPDATE offline_copies SET used_at = ?2 WHERE item = ?1",
+            params![item, now_secs()],
+        )?;
+        Ok(())
+    }
+
+    pub fn delete_offline_copy(&self, item: &str) -> anyhow::Result<()> {
+        self.conn
+            .execute("DELETE FROM offline_copies WHERE item = ?1", [item])?;
+        Ok(())
+    }
+}
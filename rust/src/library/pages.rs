@@ -0,0 +1,83 @@
+use anyhow::Context;
+use rusqlite::params;
+
+use super::{with_library, Library, SONG_ORDER, VISIBLE};
+use crate::{runtime, Song, SongPage, StreamSink};
+
+/// Where a song falls in `SONG_ORDER`, with its id to break ties so pages never overlap
+type Position = (String, String, u32, bool, u32, String, String);
+
+/// Songs sent to a stream per batch when the caller doesn't say
+pub(crate) const DEFAULT_PAGE_SIZE: u32 = 500;
+
+/// Position after `song` in `SONG_ORDER`, as an opaque string for the caller to hand back
+fn cursor_after(song: &Song) -> String {
+    let position: Position = (
+        song.artist_sort.clone().unwrap_or_default(),
+        song.album_sort.clone().unwrap_or_default(),
+        song.disc_number.unwrap_or(1),
+        song.track_number.is_none(),
+        song.track_number.unwrap_or(0),
+        song.title.clone(),
+        song.id.0.clone(),
+    );
+    serde_json::to_string(&position).expect("positions always serialize")
+}
+
+impl Library {
+    /// Up to `limit` songs following `cursor`, or from the start without one
+    pub fn get_songs_page(&self, cursor: Option<&str>, limit: u32) -> anyhow::Result<SongPage> {
+        let limit = limit.max(1);
+        // One extra row says whether there is another page.
+        let mut songs = match cursor {
+            Some(cursor) => {
+                let (artist, album, disc, untracked, track, title, id): Position =
+                    serde_json::from_str(cursor).context("invalid song page cursor")?;
+                self.query_songs(
+                    &format!(
+                        "WHERE ({SONG_ORDER}, s.id) > (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+                            AND {VISIBLE}
+                         ORDER BY {SONG_ORDER}, s.id LIMIT ?8"
+                    ),
+                    params![artist, album, disc, untracked, track, title, id, limit + 1],
+                )?
+            }
+            None => self.query_songs(
+                &format!("WHERE {VISIBLE} ORDER BY {SONG_ORDER}, s.id LIMIT ?1"),
+                [limit + 1],
+            )?,
+        };
+        let next_cursor = match songs.len() > limit as usize {
+            true => {
+                songs.truncate(limit as usize);
+                songs.last().map(cursor_after)
+            }
+            false => None,
+        };
+        Ok(SongPage { songs, next_cursor })
+    }
+}
+
+/// Send the whole library to `sink` in the order of `get_all_songs`, `page_size`
+/// songs at a time, on a background task
+///
+/// The library is only locked while each page is read. The stream ends after the
+/// last page, on an error or once the listener goes away.
+pub(crate) fn stream_songs(page_size: u32, sink: StreamSink<Vec<Song>>) {
+    runtime::spawn_blocking(move || {
+        let mut cursor = None;
+        loop {
+            let page = match with_library(|lib| lib.get_songs_page(cursor.as_deref(), page_size)) {
+                Ok(page) => page,
+                Err(e) => {
+                    log::warn!("streaming songs failed: {e:#}");
+                    return;
+                }
+            };
+            if sink.add(page.songs).is_err() || page.next_cursor.is_none() {
+                return;
+            }
+            cursor = page.next_cursor;
+        }
+    });
+}
@@ -0,0 +1,314 @@
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{ensure, Context};
+use rusqlite::{params, OptionalExtension, Transaction};
+
+use super::{now_secs, Library};
+use crate::{metadata, scanner, Playlist, Song, SongId};
+
+/// On-disk playlist formats, picked from the file extension
+#[derive(Clone, Copy)]
+enum Format {
+    M3u,
+    Pls,
+}
+
+impl Format {
+    fn of(path: &Path) -> Format {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("pls") => Format::Pls,
+            _ => Format::M3u,
+        }
+    }
+}
+
+/// Entry paths of an M3U/M3U8 file, in order
+fn parse_m3u(text: &str) -> Vec<String> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Entry paths of a PLS file, ordered by their `FileN` number
+fn parse_pls(text: &str) -> Vec<String> {
+    let mut entries: Vec<(u32, String)> = text
+        .lines()
+        .filter_map(|line| {
+            let (key, value) = line.trim().split_once('=')?;
+            let number = key.trim().strip_prefix("File")?.parse().ok()?;
+            Some((number, value.trim().to_string()))
+        })
+        .collect();
+    entries.sort_by_key(|(number, _)| *number);
+    entries.into_iter().map(|(_, path)| path).collect()
+}
+
+/// Resolve a playlist entry against the playlist's directory, without touching the disk
+fn resolve_entry(base: &Path, entry: &str) -> PathBuf {
+    let entry = if cfg!(windows) {
+        entry.to_string()
+    } else {
+        entry.replace('\\', "/")
+    };
+    let mut resolved = PathBuf::new();
+    for component in base.join(entry).components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            other => resolved.push(other),
+        }
+    }
+    resolved
+}
+
+/// How `song` is written into a playlist saved in `base`: relative when it lives below it
+fn entry_path(base: &Path, song: &Song) -> String {
+    let path = Path::new(&song.file_path);
+    path.strip_prefix(base)
+        .unwrap_or(path)
+        .to_string_lossy()
+        .into_owned()
+}
+
+pub(crate) fn write_m3u(base: &Path, songs: &[Song]) -> String {
+    let mut out = String::from("#EXTM3U\n");
+    for song in songs {
+        out.push_str(&format!(
+            "#EXTINF:{},{} - {}\n{}\n",
+            song.duration,
+            song.artist,
+            song.title,
+            entry_path(base, song)
+        ));
+    }
+    out
+}
+
+fn write_pls(base: &Path, songs: &[Song]) -> String {
+    let mut out = String::from("[playlist]\n");
+    for (index, song) in songs.iter().enumerate() {
+        let n = index + 1;
+        out.push_str(&format!(
+            "File{n}={}\nTitle{n}={} - {}\nLength{n}={}\n",
+            entry_path(base, song),
+            song.artist,
+            song.title,
+            song.duration
+        ));
+    }
+    out.push_str(&format!("NumberOfEntries={}\nVersion=2\n", songs.len()));
+    out
+}
+
+/// Replace the entries of playlist `id` with `song_ids`, in order
+pub(super) fn write_entries(
+    tx: &Transaction<'_>,
+    id: i64,
+    song_ids: &[SongId],
+) -> rusqlite::Result<()> {
+    tx.execute("DELETE FROM playlist_songs WHERE playlist_id = ?1", [id])?;
+    let mut insert = tx.prepare_cached(
+        "INSERT INTO playlist_songs (playlist_id, position, song_id) VALUES (?1, ?2, ?3)",
+    )?;
+    for (position, song_id) in song_ids.iter().enumerate() {
+        insert.execute(params![id, position as i64, song_id])?;
+    }
+    Ok(())
+}
+
+impl Library {
+    pub fn create_playlist(&self, name: &str) -> anyhow::Result<i64> {
+        self.conn.execute(
+            "INSERT INTO playlists (name, created_at) VALUES (?1, ?2)",
+            params![name, now_secs()],
+        )?;
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    pub fn rename_playlist(&self, id: i64, name: &str) -> anyhow::Result<()> {
+        let changed = self.conn.execute(
+            "UPDATE playlists SET name = ?1 WHERE id = ?2",
+            params![name, id],
+        )?;
+        ensure!(changed == 1, "no playlist with id {id}");
+        Ok(())
+    }
+
+    pub fn delete_playlist(&self, id: i64) -> anyhow::Result<()> {
+        let changed = self
+            .conn
+            .execute("DELETE FROM playlists WHERE id = ?1", [id])?;
+        ensure!(changed == 1, "no playlist with id {id}");
+        Ok(())
+    }
+
+    pub fn get_playlists(&self) -> anyhow::Result<Vec<Playlist>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT p.id, p.name, p.created_at, COUNT(ps.song_id)
+             FROM playlists p LEFT JOIN playlist_songs ps ON ps.playlist_id = p.id
+             GROUP BY p.id ORDER BY p.name COLLATE unicode",
+        )?;
+        let playlists = stmt
+            .query_map([], |row| {
+                Ok(Playlist {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    created_at: row.get(2)?,
+                    song_count: row.get(3)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(playlists)
+    }
+
+    pub fn get_playlist_songs(&self, id: i64) -> anyhow::Result<Vec<Song>> {
+        self.query_songs(
+            "JOIN playlist_songs ps ON ps.song_id = s.id
+             WHERE ps.playlist_id = ?1 ORDER BY ps.position",
+            [id],
+        )
+    }
+
+    /// Apply `edit` to the song ids of playlist `id` and store the result
+    pub(super) fn edit_playlist(
+        &mut self,
+        id: i64,
+        edit: impl FnOnce(&mut Vec<SongId>) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let tx = self.conn.transaction()?;
+        let exists = tx
+            .query_row("SELECT 1 FROM playlists WHERE id = ?1", [id], |_| Ok(()))
+            .optional()?
+            .is_some();
+        ensure!(exists, "no playlist with id {id}");
+        let mut song_ids = tx
+            .prepare_cached(
+                "SELECT song_id FROM playlist_songs WHERE playlist_id = ?1 ORDER BY position",
+            )?
+            .query_map([id], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<SongId>>>()?;
+        edit(&mut song_ids)?;
+        write_entries(&tx, id, &song_ids)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn playlist_add_songs(&mut self, id: i64, song_ids: Vec<SongId>) -> anyhow::Result<()> {
+        self.edit_playlist(id, |entries| {
+            entries.extend(song_ids);
+            Ok(())
+        })
+    }
+
+    pub fn playlist_remove_song(&mut self, id: i64, position: usize) -> anyhow::Result<()> {
+        self.edit_playlist(id, |entries| {
+            ensure!(
+                position < entries.len(),
+                "playlist position {position} out of range"
+            );
+            entries.remove(position);
+            Ok(())
+        })
+    }
+
+    pub fn playlist_clear(&mut self, id: i64) -> anyhow::Result<()> {
+        self.edit_playlist(id, |entries| {
+            entries.clear();
+            Ok(())
+        })
+    }
+
+    pub fn playlist_move_song(&mut self, id: i64, from: usize, to: usize) -> anyhow::Result<()> {
+        self.edit_playlist(id, |entries| {
+            ensure!(
+                from < entries.len() && to < entries.len(),
+                "playlist move {from} -> {to} out of range"
+            );
+            let song = entries.remove(from);
+            entries.insert(to, song);
+            Ok(())
+        })
+    }
+
+    /// Library id of the song at `path`, adding the file to the library if needed
+    fn song_id_for_path(&mut self, path: &Path) -> anyhow::Result<Option<SongId>> {
+        let file_path = path.to_string_lossy();
+        let existing = self
+            .conn
+            .query_row(
+                "SELECT id FROM songs WHERE file_path = ?1",
+                [file_path.as_ref()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if existing.is_some() {
+            return Ok(existing);
+        }
+        if !path.is_file() || !scanner::is_supported(path) {
+            return Ok(None);
+        }
+        let song = metadata::read_song(path)?;
+        Ok(Some(self.upsert_song(&song)?))
+    }
+
+    /// Create a playlist from an M3U/M3U8/PLS file, named after the file
+    ///
+    /// Relative entries are resolved against the playlist's directory. Entries that
+    /// aren't playable local files are skipped.
+    pub fn import_playlist(&mut self, path: &Path) -> anyhow::Result<i64> {
+        let bytes = fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+        let text = String::from_utf8_lossy(&bytes);
+        let text = text.trim_start_matches('\u{feff}');
+        let entries = match Format::of(path) {
+            Format::M3u => parse_m3u(text),
+            Format::Pls => parse_pls(text),
+        };
+
+        let base = path.parent().unwrap_or(Path::new(""));
+        let mut song_ids = Vec::with_capacity(entries.len());
+        for entry in entries {
+            if entry.contains("://") {
+                log::warn!("skipping non-local playlist entry {entry}");
+                continue;
+            }
+            let resolved = resolve_entry(base, &entry);
+            match self.song_id_for_path(&resolved) {
+                Ok(Some(id)) => song_ids.push(id),
+                Ok(None) => log::warn!("skipping missing playlist entry {}", resolved.display()),
+                Err(e) => log::warn!("skipping playlist entry {}: {e}", resolved.display()),
+            }
+        }
+
+        let name = path
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let id = self.create_playlist(&name)?;
+        self.playlist_add_songs(id, song_ids)?;
+        Ok(id)
+    }
+
+    /// Write playlist `id` to `path` as PLS or extended M3U, depending on the extension
+    ///
+    /// Songs below the playlist's directory are written with relative paths.
+    pub fn export_playlist(&self, id: i64, path: &Path) -> anyhow::Result<()> {
+        let exists = self
+            .conn
+            .query_row("SELECT 1 FROM playlists WHERE id = ?1", [id], |_| Ok(()))
+            .optional()?
+            .is_some();
+        ensure!(exists, "no playlist with id {id}");
+        let songs = self.get_playlist_songs(id)?;
+        let base = path.parent().unwrap_or(Path::new(""));
+        let text = match Format::of(path) {
+            Format::M3u => write_m3u(base, &songs),
+            Format::Pls => write_pls(base, &songs),
+        };
+        fs::write(path, text).with_context(|| format!("failed to write {}", path.display()))
+    }
+}
@@ -0,0 +1,171 @@
+use anyhow::ensure;
+use rusqlite::{params, OptionalExtension, Row};
+
+use super::{now_secs, Library};
+use crate::podcasts::Feed;
+use crate::{Podcast, PodcastEpisode};
+
+const PODCAST_COLUMNS: &str = "p.id, p.feed_url, p.title, p.author, p.description, p.image_url,
+     p.last_refreshed, (SELECT COUNT(*) FROM podcast_episodes e WHERE e.podcast_id = p.id)";
+
+const EPISODE_COLUMNS: &str = "id, podcast_id, title, show_notes, audio_url, published_at,
+     duration, file_size, download_path";
+
+fn podcast_from_row(row: &Row<'_>) -> rusqlite::Result<Podcast> {
+    Ok(Podcast {
+        id: row.get(0)?,
+        feed_url: row.get(1)?,
+        title: row.get(2)?,
+        author: row.get(3)?,
+        description: row.get(4)?,
+        image_url: row.get(5)?,
+        last_refreshed: row.get(6)?,
+        episode_count: row.get(7)?,
+    })
+}
+
+fn episode_from_row(row: &Row<'_>) -> rusqlite::Result<PodcastEpisode> {
+    Ok(PodcastEpisode {
+        id: row.get(0)?,
+        podcast_id: row.get(1)?,
+        title: row.get(2)?,
+        show_notes: row.get(3)?,
+        audio_url: row.get(4)?,
+        published_at: row.get(5)?,
+        duration_secs: row.get::<_, Option<i64>>(6)?.map(|d| d as u64),
+        file_size: row.get::<_, Option<i64>>(7)?.map(|s| s as u64),
+        download_path: row.get(8)?,
+    })
+}
+
+impl Library {
+    /// Insert or refresh the podcast at `feed_url` and its episodes
+    ///
+    /// Returns the podcast id and how many episodes are new. Episodes that dropped out
+    /// of the feed are kept, as are downloads of the ones that changed.
+    pub fn store_feed(&mut self, feed_url: &str, feed: &Feed) -> anyhow::Result<(i64, u32)> {
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "INSERT INTO podcasts (feed_url, title, author, description, image_url, last_refreshed)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+             ON CONFLICT (feed_url) DO UPDATE SET
+                title = excluded.title,
+                author = excluded.author,
+                description = excluded.description,
+                image_url = excluded.image_url,
+                last_refreshed = excluded.last_refreshed",
+            params![
+                feed_url,
+                feed.title,
+                feed.author,
+                feed.description,
+                feed.image_url,
+                now_secs()
+            ],
+        )?;
+        let id: i64 = tx.query_row(
+            "SELECT id FROM podcasts WHERE feed_url = ?1",
+            [feed_url],
+            |row| row.get(0),
+        )?;
+        let count = |tx: &rusqlite::Transaction<'_>| -> rusqlite::Result<u32> {
+            tx.query_row(
+                "SELECT COUNT(*) FROM podcast_episodes WHERE podcast_id = ?1",
+                [id],
+                |row| row.get(0),
+            )
+        };
+        let before = count(&tx)?;
+        {
+            let mut stmt = tx.prepare_cached(
+                "INSERT INTO podcast_episodes (podcast_id, guid, title, show_notes, audio_url,
+                                               published_at, duration, file_size)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+                 ON CONFLICT (podcast_id, guid) DO UPDATE SET
+                    title = excluded.title,
+                    show_notes = excluded.show_notes,
+                    audio_url = excluded.audio_url,
+                    published_at = excluded.published_at,
+                    duration = excluded.duration,
+                    file_size = excluded.file_size",
+            )?;
+            for episode in &feed.episodes {
+                stmt.execute(params![
+                    id,
+                    episode.guid,
+                    episode.title,
+                    episode.show_notes,
+                    episode.audio_url,
+                    episode.published_at,
+                    episode.duration_secs.map(|d| d as i64),
+                    episode.file_size.map(|s| s as i64),
+                ])?;
+            }
+        }
+        let new_episodes = count(&tx)? - before;
+        tx.commit()?;
+        Ok((id, new_episodes))
+    }
+
+    pub fn get_podcasts(&self) -> anyhow::Result<Vec<Podcast>> {
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "SELECT {PODCAST_COLUMNS} FROM podcasts p ORDER BY p.title COLLATE unicode"
+        ))?;
+        let podcasts = stmt
+            .query_map([], podcast_from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(podcasts)
+    }
+
+    pub fn get_podcast(&self, id: i64) -> anyhow::Result<Option<Podcast>> {
+        Ok(self
+            .conn
+            .query_row(
+                &format!("SELECT {PODCAST_COLUMNS} FROM podcasts p WHERE p.id = ?1"),
+                [id],
+                podcast_from_row,
+            )
+            .optional()?)
+    }
+
+    pub fn delete_podcast(&self, id: i64) -> anyhow::Result<()> {
+        let changed = self
+            .conn
+            .execute("DELETE FROM podcasts WHERE id = ?1", [id])?;
+        ensure!(changed == 1, "no podcast with id {id}");
+        Ok(())
+    }
+
+    /// Episodes of a podcast, newest first; undated ones come last
+    pub fn get_podcast_episodes(&self, podcast_id: i64) -> anyhow::Result<Vec<PodcastEpisode>> {
+        let mut stmt = self.conn.prepare_cached(&format!(
+            "SELECT {EPISODE_COLUMNS} FROM podcast_episodes WHERE podcast_id = ?1
+             ORDER BY published_at IS NULL, published_at DESC, id DESC"
+        ))?;
+        let episodes = stmt
+            .query_map([podcast_id], episode_from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(episodes)
+    }
+
+    pub fn get_podcast_episode(&self, id: i64) -> anyhow::Result<Option<PodcastEpisode>> {
+        Ok(self
+            .conn
+            .query_row(
+                &format!("SELECT {EPISODE_COLUMNS} FROM podcast_episodes WHERE id = ?1"),
+                [id],
+                episode_from_row,
+            )
+            .optional()?)
+    }
+
+    /// Record where an episode was downloaded to, or that its download is gone
+    pub fn set_episode_download(&self, id: i64, path: Option<&str>) -> anyhow::Result<()> {
+        let changed = self.conn.execute(
+            "UPDATE podcast_episodes SET download_path = ?1 WHERE id = ?2",
+            params![path, id],
+        )?;
+        ensure!(changed == 1, "no podcast episode with id {id}");
+        Ok(())
+    }
+}
@@ -0,0 +1,53 @@
+use rusqlite::{params, OptionalExtension};
+
+use super::{now_secs, Library};
+use crate::QuarantinedFile;
+
+impl Library {
+    /// Keep `path` out for `reason`, counting another failed attempt if it already was
+    pub fn quarantine_file(&self, path: &str, reason: &str) -> anyhow::Result<()> {
+        self.conn.execute(
+            "INSERT INTO quarantine (path, reason, failed_at) VALUES (?1, ?2, ?3)
+             ON CONFLICT (path) DO UPDATE SET
+                 reason = excluded.reason,
+                 failed_at = excluded.failed_at,
+                 attempts = attempts + 1",
+            params![path, reason, now_secs()],
+        )?;
+        Ok(())
+    }
+
+    /// Let `path` out of quarantine, if it was in
+    pub fn release_file(&self, path: &str) -> anyhow::Result<()> {
+        self.conn
+            .execute("DELETE FROM quarantine WHERE path = ?1", [path])?;
+        Ok(())
+    }
+
+    pub fn get_quarantined_files(&self) -> anyhow::Result<Vec<QuarantinedFile>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT id, path, reason, failed_at, attempts FROM quarantine ORDER BY path",
+        )?;
+        let files = stmt
+            .query_map([], |row| {
+                Ok(QuarantinedFile {
+                    id: row.get(0)?,
+                    path: row.get(1)?,
+                    reason: row.get(2)?,
+                    failed_at: row.get(3)?,
+                    attempts: row.get(4)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(files)
+    }
+
+    pub fn get_quarantined_path(&self, id: i64) -> anyhow::Result<Option<String>> {
+        Ok(self
+            .conn
+            .query_row("SELECT path FROM quarantine WHERE id = ?1", [id], |row| {
+                row.get(0)
+            })
+            .optional()?)
+    }
+}
@@ -0,0 +1,56 @@
+use anyhow::ensure;
+use rusqlite::params;
+
+use super::{Library, SONG_ORDER, VISIBLE};
+use crate::{Song, SongId};
+
+impl Library {
+    /// Rate a song from 1 to 5 stars, or clear its rating with `None`
+    pub fn set_rating(&self, song_id: &SongId, rating: Option<u8>) -> anyhow::Result<()> {
+        ensure!(
+            rating.is_none_or(|r| (1..=5).contains(&r)),
+            "ratings go from 1 to 5 stars"
+        );
+        let changed = self.conn.execute(
+            "UPDATE songs SET rating = ?1 WHERE id = ?2",
+            params![rating, song_id],
+        )?;
+        ensure!(changed == 1, "no song with id {song_id}");
+        Ok(())
+    }
+
+    /// Flip a song's favorite mark, returning the new one
+    pub fn toggle_favorite(&self, song_id: &SongId) -> anyhow::Result<bool> {
+        let favorite = self
+            .conn
+            .query_row(
+                "UPDATE songs SET favorite = NOT favorite WHERE id = ?1 RETURNING favorite",
+                [song_id],
+                |row| row.get(0),
+            )
+            .map_err(|e| match e {
+                rusqlite::Error::QueryReturnedNoRows => {
+                    anyhow::anyhow!("no song with id {song_id}")
+                }
+                e => e.into(),
+            })?;
+        Ok(favorite)
+    }
+
+    pub fn get_favorites(&self) -> anyhow::Result<Vec<Song>> {
+        self.query_songs(
+            &format!("WHERE s.favorite AND {VISIBLE} ORDER BY {SONG_ORDER}"),
+            [],
+        )
+    }
+
+    pub fn get_by_min_rating(&self, stars: u8) -> anyhow::Result<Vec<Song>> {
+        self.query_songs(
+            &format!(
+                "WHERE s.rating >= ?1 AND {VISIBLE}
+                 ORDER BY s.rating DESC, {SONG_ORDER}"
+            ),
+            [stars.max(1)],
+        )
+    }
+}
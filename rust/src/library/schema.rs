@@ -0,0 +1,461 @@
+use std::collections::HashSet;
+use std::path::Path;
+
+use rusqlite::{params, Connection, Transaction};
+
+use crate::SongId;
+
+/// Schema migrations, applied in order; `PRAGMA user_version` records how many ran
+const MIGRATIONS: &[&str] = &[
+    // 1: core library tables
+    "CREATE TABLE artists (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL UNIQUE
+    );
+    CREATE TABLE albums (
+        id INTEGER PRIMARY KEY,
+        title TEXT NOT NULL,
+        artist_id INTEGER NOT NULL REFERENCES artists(id),
+        loudness REAL,
+        UNIQUE (title, artist_id)
+    );
+    CREATE TABLE songs (
+        id TEXT PRIMARY KEY,
+        title TEXT NOT NULL,
+        artist_id INTEGER NOT NULL REFERENCES artists(id),
+        album_id INTEGER NOT NULL REFERENCES albums(id),
+        duration INTEGER NOT NULL,
+        file_path TEXT NOT NULL UNIQUE,
+        track_loudness REAL,
+        date_added INTEGER NOT NULL
+    );
+    CREATE INDEX songs_artist ON songs(artist_id);
+    CREATE INDEX songs_album ON songs(album_id);
+    CREATE TABLE playlists (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL,
+        created_at INTEGER NOT NULL
+    );
+    CREATE TABLE playlist_songs (
+        playlist_id INTEGER NOT NULL REFERENCES playlists(id) ON DELETE CASCADE,
+        position INTEGER NOT NULL,
+        song_id TEXT NOT NULL REFERENCES songs(id) ON DELETE CASCADE,
+        PRIMARY KEY (playlist_id, position)
+    );",
+    // 2: full-text index over title, artist, album and file name, kept in sync by triggers;
+    // the file name is the path with everything up to its last separator trimmed off
+    "CREATE VIRTUAL TABLE songs_fts USING fts5(
+        title, artist, album, file_name,
+        tokenize = 'unicode61 remove_diacritics 2',
+        prefix = '2 3'
+    );
+    INSERT INTO songs_fts (rowid, title, artist, album, file_name)
+        SELECT s.rowid, s.title, ar.name, al.title, replace(replace(s.file_path, '\\', '/'), rtrim(replace(s.file_path, '\\', '/'), replace(replace(s.file_path, '\\', '/'), '/', '')), '')
+        FROM songs s
+        JOIN artists ar ON ar.id = s.artist_id
+        JOIN albums al ON al.id = s.album_id;
+    CREATE TRIGGER songs_fts_insert AFTER INSERT ON songs BEGIN
+        INSERT INTO songs_fts (rowid, title, artist, album, file_name) VALUES (
+            new.rowid,
+            new.title,
+            (SELECT name FROM artists WHERE id = new.artist_id),
+            (SELECT title FROM albums WHERE id = new.album_id),
+            replace(replace(new.file_path, '\\', '/'), rtrim(replace(new.file_path, '\\', '/'), replace(replace(new.file_path, '\\', '/'), '/', '')), '')
+        );
+    END;
+    CREATE TRIGGER songs_fts_update AFTER UPDATE ON songs BEGIN
+        DELETE FROM songs_fts WHERE rowid = old.rowid;
+        INSERT INTO songs_fts (rowid, title, artist, album, file_name) VALUES (
+            new.rowid,
+            new.title,
+            (SELECT name FROM artists WHERE id = new.artist_id),
+            (SELECT title FROM albums WHERE id = new.album_id),
+            replace(replace(new.file_path, '\\', '/'), rtrim(replace(new.file_path, '\\', '/'), replace(replace(new.file_path, '\\', '/'), '/', '')), '')
+        );
+    END;
+    CREATE TRIGGER songs_fts_delete AFTER DELETE ON songs BEGIN
+        DELETE FROM songs_fts WHERE rowid = old.rowid;
+    END;",
+    // 3: podcast subscriptions and their episodes
+    "CREATE TABLE podcasts (
+        id INTEGER PRIMARY KEY,
+        feed_url TEXT NOT NULL UNIQUE,
+        title TEXT NOT NULL,
+        author TEXT,
+        description TEXT NOT NULL,
+        image_url TEXT,
+        last_refreshed INTEGER NOT NULL
+    );
+    CREATE TABLE podcast_episodes (
+        id INTEGER PRIMARY KEY,
+        podcast_id INTEGER NOT NULL REFERENCES podcasts(id) ON DELETE CASCADE,
+        guid TEXT NOT NULL,
+        title TEXT NOT NULL,
+        show_notes TEXT NOT NULL,
+        audio_url TEXT NOT NULL,
+        published_at INTEGER,
+        duration INTEGER,
+        file_size INTEGER,
+        download_path TEXT,
+        UNIQUE (podcast_id, guid)
+    );
+    CREATE INDEX podcast_episodes_published ON podcast_episodes(podcast_id, published_at);",
+    // 4: CUE sheet tracks share a file, so songs get offsets into it and file paths
+    // stop being unique; the table is rebuilt (keeping rowids, which songs_fts uses)
+    // and the FTS triggers dropped along with it are recreated
+    "CREATE TABLE songs_new (
+        id TEXT PRIMARY KEY,
+        title TEXT NOT NULL,
+        artist_id INTEGER NOT NULL REFERENCES artists(id),
+        album_id INTEGER NOT NULL REFERENCES albums(id),
+        duration INTEGER NOT NULL,
+        file_path TEXT NOT NULL,
+        start_offset REAL NOT NULL DEFAULT 0,
+        end_offset REAL,
+        track_loudness REAL,
+        date_added INTEGER NOT NULL
+    );
+    INSERT INTO songs_new (rowid, id, title, artist_id, album_id, duration, file_path,
+                           track_loudness, date_added)
+        SELECT rowid, id, title, artist_id, album_id, duration, file_path,
+               track_loudness, date_added
+        FROM songs;
+    DROP TABLE songs;
+    ALTER TABLE songs_new RENAME TO songs;
+    CREATE INDEX songs_artist ON songs(artist_id);
+    CREATE INDEX songs_album ON songs(album_id);
+    CREATE INDEX songs_file ON songs(file_path);
+    CREATE TRIGGER songs_fts_insert AFTER INSERT ON songs BEGIN
+        INSERT INTO songs_fts (rowid, title, artist, album, file_name) VALUES (
+            new.rowid,
+            new.title,
+            (SELECT name FROM artists WHERE id = new.artist_id),
+            (SELECT title FROM albums WHERE id = new.album_id),
+            replace(replace(new.file_path, '\\', '/'), rtrim(replace(new.file_path, '\\', '/'), replace(replace(new.file_path, '\\', '/'), '/', '')), '')
+        );
+    END;
+    CREATE TRIGGER songs_fts_update AFTER UPDATE ON songs BEGIN
+        DELETE FROM songs_fts WHERE rowid = old.rowid;
+        INSERT INTO songs_fts (rowid, title, artist, album, file_name) VALUES (
+            new.rowid,
+            new.title,
+            (SELECT name FROM artists WHERE id = new.artist_id),
+            (SELECT title FROM albums WHERE id = new.album_id),
+            replace(replace(new.file_path, '\\', '/'), rtrim(replace(new.file_path, '\\', '/'), replace(replace(new.file_path, '\\', '/'), '/', '')), '')
+        );
+    END;
+    CREATE TRIGGER songs_fts_delete AFTER DELETE ON songs BEGIN
+        DELETE FROM songs_fts WHERE rowid = old.rowid;
+    END;",
+    // 5: saved playback positions; not tied to songs so downloaded episodes get them too
+    "CREATE TABLE bookmarks (
+        song_id TEXT PRIMARY KEY,
+        position REAL NOT NULL,
+        updated_at INTEGER NOT NULL
+    );",
+    // 6: play counts, kept apart from songs so counting a play doesn't reindex it,
+    // and one history row per counted play
+    "CREATE TABLE song_plays (
+        song_id TEXT PRIMARY KEY REFERENCES songs(id) ON DELETE CASCADE,
+        play_count INTEGER NOT NULL,
+        last_played INTEGER NOT NULL
+    );
+    CREATE TABLE play_history (
+        id INTEGER PRIMARY KEY,
+        song_id TEXT NOT NULL REFERENCES songs(id) ON DELETE CASCADE,
+        played_at INTEGER NOT NULL
+    );
+    CREATE INDEX play_history_song ON play_history(song_id);
+    CREATE INDEX play_history_played ON play_history(played_at);",
+    // 7: genre and rating for smart playlist rules, and the smart playlists themselves,
+    // whose rules are stored as JSON
+    "ALTER TABLE songs ADD COLUMN genre TEXT;
+    ALTER TABLE songs ADD COLUMN rating INTEGER;
+    CREATE TABLE smart_playlists (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL,
+        rules TEXT NOT NULL,
+        created_at INTEGER NOT NULL
+    );",
+    // 8: listens waiting to be submitted to a scrobbling service
+    "CREATE TABLE scrobble_queue (
+        id INTEGER PRIMARY KEY,
+        service TEXT NOT NULL,
+        artist TEXT NOT NULL,
+        track TEXT NOT NULL,
+        album TEXT,
+        duration INTEGER NOT NULL,
+        listened_at INTEGER NOT NULL
+    );
+    CREATE INDEX scrobble_queue_service ON scrobble_queue(service);",
+    // 9: favorite marks
+    "ALTER TABLE songs ADD COLUMN favorite INTEGER NOT NULL DEFAULT 0;",
+    // 10: release years for browsing by decade
+    "ALTER TABLE songs ADD COLUMN year INTEGER;",
+    // 11: silence at either end of songs, with the settings it was measured under
+    "CREATE TABLE song_silence (
+        song_id TEXT PRIMARY KEY REFERENCES songs(id) ON DELETE CASCADE,
+        threshold_db REAL NOT NULL,
+        min_duration REAL NOT NULL,
+        leading REAL NOT NULL,
+        trailing REAL NOT NULL
+    );",
+    // 12: remote backends songs are indexed from, with their settings as JSON
+    "CREATE TABLE library_sources (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL,
+        config TEXT NOT NULL,
+        created_at INTEGER NOT NULL
+    );",
+    // 13: copies kept for playing offline, keyed by the remote file's library path or
+    // `episode:<id>`
+    "CREATE TABLE offline_copies (
+        item TEXT PRIMARY KEY,
+        local_path TEXT NOT NULL,
+        size INTEGER NOT NULL,
+        md5 TEXT NOT NULL,
+        pinned INTEGER NOT NULL,
+        used_at INTEGER NOT NULL
+    );",
+    // 14: tempo, tagged or measured
+    "ALTER TABLE songs ADD COLUMN bpm REAL;",
+    // 15: musical key in standard notation, e.g. "C#m"; `key` is an SQL keyword
+    "ALTER TABLE songs ADD COLUMN musical_key TEXT;",
+    // 16: audio features, for picking songs by mood
+    "CREATE TABLE song_features (
+        song_id TEXT PRIMARY KEY REFERENCES songs(id) ON DELETE CASCADE,
+        energy REAL NOT NULL,
+        danceability REAL NOT NULL,
+        brightness REAL NOT NULL,
+        dynamic_range REAL NOT NULL
+    );",
+    // 17: parental advisory marks and the content filter's rules, with lowercase
+    // terms; `visible_songs` holds the songs no rule hides
+    "ALTER TABLE songs ADD COLUMN explicit INTEGER NOT NULL DEFAULT 0;
+    CREATE TABLE content_filter (
+        kind TEXT NOT NULL CHECK (kind IN ('explicit', 'genre', 'keyword')),
+        term TEXT NOT NULL,
+        PRIMARY KEY (kind, term)
+    );
+    CREATE VIEW visible_songs AS
+        SELECT s.id FROM songs s WHERE NOT EXISTS (
+            SELECT 1 FROM content_filter f
+            WHERE (f.kind = 'explicit' AND s.explicit)
+               OR (f.kind = 'genre' AND lower(s.genre) = f.term)
+               OR (f.kind = 'keyword' AND (
+                   instr(lower(s.title), f.term) > 0
+                   OR instr((SELECT lower(name) FROM artists WHERE id = s.artist_id), f.term) > 0
+                   OR instr((SELECT lower(title) FROM albums WHERE id = s.album_id), f.term) > 0))
+        );",
+    // 18: files scans couldn't read, with why, until they read again; an image split
+    // by a CUE sheet is kept under the sheet's path
+    "CREATE TABLE quarantine (
+        id INTEGER PRIMARY KEY,
+        path TEXT NOT NULL UNIQUE,
+        reason TEXT NOT NULL,
+        failed_at INTEGER NOT NULL,
+        attempts INTEGER NOT NULL DEFAULT 1
+    );",
+    // 19: size and sampled hash of single-song files, to recognise one moved while
+    // nothing watched
+    "CREATE TABLE file_identities (
+        file_path TEXT PRIMARY KEY,
+        size INTEGER NOT NULL,
+        hash TEXT NOT NULL
+    );
+    CREATE INDEX file_identities_size ON file_identities (size, hash);",
+    // 20: songs keep their ids through moves, so tracks of files are told apart by path
+    // and offset instead, and the library can hold only one song for each; rows
+    // earlier versions duplicated are dropped, after handing what refers to them to the
+    // first of their copies. Foreign keys are off, so nothing cascades.
+    "CREATE TEMP TABLE song_duplicates AS
+        SELECT s.id AS duplicate, kept.id AS kept
+        FROM songs s
+        JOIN (SELECT MIN(rowid) AS first, file_path, start_offset FROM songs
+              GROUP BY file_path, start_offset) f USING (file_path, start_offset)
+        JOIN songs kept ON kept.rowid = f.first
+        WHERE s.rowid != f.first;
+    UPDATE playlist_songs
+        SET song_id = (SELECT kept FROM song_duplicates WHERE duplicate = song_id)
+        WHERE song_id IN (SELECT duplicate FROM song_duplicates);
+    UPDATE play_history
+        SET song_id = (SELECT kept FROM song_duplicates WHERE duplicate = song_id)
+        WHERE song_id IN (SELECT duplicate FROM song_duplicates);
+    INSERT INTO song_plays (song_id, play_count, last_played)
+        SELECT d.kept, p.play_count, p.last_played
+        FROM song_plays p JOIN song_duplicates d ON d.duplicate = p.song_id
+        WHERE true
+        ON CONFLICT (song_id) DO UPDATE SET
+            play_count = play_count + excluded.play_count,
+            last_played = max(last_played, excluded.last_played);
+    UPDATE OR IGNORE bookmarks
+        SET song_id = (SELECT kept FROM song_duplicates WHERE duplicate = song_id)
+        WHERE song_id IN (SELECT duplicate FROM song_duplicates);
+    UPDATE OR IGNORE song_silence
+        SET song_id = (SELECT kept FROM song_duplicates WHERE duplicate = song_id)
+        WHERE song_id IN (SELECT duplicate FROM song_duplicates);
+    UPDATE OR IGNORE song_features
+        SET song_id = (SELECT kept FROM song_duplicates WHERE duplicate = song_id)
+        WHERE song_id IN (SELECT duplicate FROM song_duplicates);
+    DELETE FROM song_plays WHERE song_id IN (SELECT duplicate FROM song_duplicates);
+    DELETE FROM bookmarks WHERE song_id IN (SELECT duplicate FROM song_duplicates);
+    DELETE FROM songs WHERE id IN (SELECT duplicate FROM song_duplicates);
+    DROP TABLE song_duplicates;
+    DELETE FROM playlist_songs WHERE song_id NOT IN (SELECT id FROM songs);
+    DELETE FROM song_plays WHERE song_id NOT IN (SELECT id FROM songs);
+    DELETE FROM play_history WHERE song_id NOT IN (SELECT id FROM songs);
+    DELETE FROM song_silence WHERE song_id NOT IN (SELECT id FROM songs);
+    DELETE FROM song_features WHERE song_id NOT IN (SELECT id FROM songs);
+    DROP INDEX songs_file;
+    CREATE UNIQUE INDEX songs_file ON songs (file_path, start_offset);",
+    // 21: album artist and compilation flag as tagged, and whether `detect_compilations`
+    // found the song on a compilation its tags don't mark as one
+    "ALTER TABLE songs ADD COLUMN album_artist TEXT;
+    ALTER TABLE songs ADD COLUMN compilation INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE songs ADD COLUMN detected_compilation INTEGER NOT NULL DEFAULT 0;",
+    // 22: disc and track numbers, and the names artists and albums sort under; those
+    // already stored sort without a leading "The ", as `metadata::sort_name` has it
+    "ALTER TABLE songs ADD COLUMN disc_number INTEGER;
+    ALTER TABLE songs ADD COLUMN track_number INTEGER;
+    ALTER TABLE artists ADD COLUMN sort_name TEXT;
+    ALTER TABLE albums ADD COLUMN sort_title TEXT;
+    UPDATE artists SET sort_name = CASE WHEN name LIKE 'the _%' THEN substr(name, 5)
+        ELSE name END;
+    UPDATE albums SET sort_title = CASE WHEN title LIKE 'the _%' THEN substr(title, 5)
+        ELSE title END;",
+    // 23: songs stored under their path, as before songs got ids of their own, get
+    // hashed ids instead, in `rehash_song_ids`
+    "",
+];
+
+/// A migration step written in Rust
+type CodeMigration = fn(&Transaction) -> rusqlite::Result<()>;
+
+/// Migrations needing more than SQL, by their number, run after its SQL
+const CODE_MIGRATIONS: &[(usize, CodeMigration)] = &[(23, rehash_song_ids)];
+
+/// Tables referring to songs by id; foreign keys are off while migrating, so rows are
+/// moved to a new id by hand
+const SONG_ID_TABLES: &[&str] = &[
+    "playlist_songs",
+    "song_plays",
+    "play_history",
+    "bookmarks",
+    "song_silence",
+    "song_features",
+];
+
+/// Schema version of a fully migrated database
+pub(super) fn version() -> usize {
+    MIGRATIONS.len()
+}
+
+/// Bring the database up to the latest schema
+///
+/// Foreign keys are turned off, so rebuilding a table doesn't cascade; the bundled
+/// SQLite starts with them on. Turn them back on after.
+pub(super) fn migrate(conn: &mut Connection) -> rusqlite::Result<()> {
+    // Has no effect inside a transaction.
+    conn.pragma_update(None, "foreign_keys", false)?;
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    let tx = conn.transaction()?;
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        log::info!("applying library migration {}", index + 1);
+        tx.execute_batch(migration)?;
+        for (_, migrate) in CODE_MIGRATIONS.iter().filter(|(n, _)| *n == index + 1) {
+            migrate(&tx)?;
+        }
+    }
+    tx.pragma_update(None, "user_version", MIGRATIONS.len())?;
+    tx.commit()
+}
+
+/// Give every song still stored under its path (`<path>#<track>` for CUE sheet tracks)
+/// the id a song first stored now would get, moving everything referring to it along
+fn rehash_song_ids(tx: &Transaction) -> rusqlite::Result<()> {
+    let songs: Vec<(SongId, String)> = tx
+        .prepare("SELECT id, file_path FROM songs")?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+    let mut taken: HashSet<SongId> = songs.iter().map(|(id, _)| id.clone()).collect();
+    let mut rehashed = 0;
+    for (old, file_path) in songs.iter().filter(|(id, _)| !id.is_hashed()) {
+        let track = old
+            .as_str()
+            .strip_prefix(file_path.as_str())
+            .and_then(|rest| rest.strip_prefix('#'))
+            .and_then(|number| number.parse().ok());
+        let hashed = SongId::for_file(Path::new(file_path), track);
+        let mut id = hashed.clone();
+        let mut attempt = 0;
+        while taken.contains(&id) {
+            attempt += 1;
+            id = hashed.reissued(attempt);
+        }
+        tx.execute("UPDATE songs SET id = ?2 WHERE id = ?1", params![old, id])?;
+        for table in SONG_ID_TABLES {
+            tx.execute(
+                &format!("UPDATE {table} SET song_id = ?2 WHERE song_id = ?1"),
+                params![old, id],
+            )?;
+        }
+        taken.insert(id);
+        rehashed += 1;
+    }
+    log::info!("gave {rehashed} songs hashed ids");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn path_ids_are_rehashed_along_with_what_refers_to_them() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        for migration in &MIGRATIONS[..22] {
+            conn.execute_batch(migration).unwrap();
+        }
+        conn.pragma_update(None, "user_version", 22).unwrap();
+        conn.execute_batch(
+            "INSERT INTO artists (id, name) VALUES (1, 'Artist');
+            INSERT INTO albums (id, title, artist_id) VALUES (1, 'Album', 1);
+            INSERT INTO songs (id, title, artist_id, album_id, duration, file_path,
+                               start_offset, date_added)
+                VALUES ('/music/a.mp3', 'A', 1, 1, 60, '/music/a.mp3', 0, 0),
+                       ('/music/b.flac#02', 'B', 1, 1, 60, '/music/b.flac', 30, 0),
+                       ('0123456789abcdef', 'C', 1, 1, 60, '/music/c.mp3', 0, 0);
+            INSERT INTO playlists (id, name, created_at) VALUES (1, 'Mix', 0);
+            INSERT INTO playlist_songs VALUES (1, 0, '/music/b.flac#02'),
+                                              (1, 1, '0123456789abcdef');
+            INSERT INTO song_plays VALUES ('/music/a.mp3', 3, 0);
+            INSERT INTO play_history (song_id, played_at) VALUES ('/music/a.mp3', 0);
+            INSERT INTO bookmarks VALUES ('/music/b.flac#02', 12.5, 0);",
+        )
+        .unwrap();
+
+        migrate(&mut conn).unwrap();
+
+        let id = |path: &str, track| SongId::for_file(Path::new(path), track);
+        let ids: Vec<SongId> = conn
+            .prepare("SELECT id FROM songs ORDER BY title")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        let (a, b) = (id("/music/a.mp3", None), id("/music/b.flac", Some(2)));
+        assert_eq!(
+            ids,
+            [a.clone(), b.clone(), SongId("0123456789abcdef".into())]
+        );
+        let song_of = |sql: &str| -> SongId { conn.query_row(sql, [], |row| row.get(0)).unwrap() };
+        assert_eq!(
+            song_of("SELECT song_id FROM playlist_songs WHERE position = 0"),
+            b
+        );
+        assert_eq!(song_of("SELECT song_id FROM song_plays"), a);
+        assert_eq!(song_of("SELECT song_id FROM play_history"), a);
+        assert_eq!(song_of("SELECT song_id FROM bookmarks"), b);
+    }
+}
@@ -0,0 +1,85 @@
+use rusqlite::params;
+
+use super::Library;
+use crate::scrobble::Listen;
+use crate::ScrobbleService;
+
+fn service_key(service: ScrobbleService) -> &'static str {
+    match service {
+        ScrobbleService::LastFm => "lastfm",
+        ScrobbleService::ListenBrainz => "listenbrainz",
+    }
+}
+
+impl Library {
+    pub fn queue_scrobble(&self, service: ScrobbleService, listen: &Listen) -> anyhow::Result<()> {
+        self.conn.execute(
+            "INSERT INTO scrobble_queue (service, artist, track, album, duration, listened_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                service_key(service),
+                listen.artist,
+                listen.track,
+                listen.album,
+                listen.duration,
+                listen.listened_at,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// The oldest `limit` queued listens for a service, with their queue ids
+    pub fn queued_scrobbles(
+        &self,
+        service: ScrobbleService,
+        limit: usize,
+    ) -> anyhow::Result<Vec<(i64, Listen)>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT id, artist, track, album, duration, listened_at FROM scrobble_queue
+             WHERE service = ?1 ORDER BY listened_at, id LIMIT ?2",
+        )?;
+        let listens = stmt
+            .query_map(params![service_key(service), limit as i64], |row| {
+                Ok((
+                    row.get(0)?,
+                    Listen {
+                        artist: row.get(1)?,
+                        track: row.get(2)?,
+                        album: row.get(3)?,
+                        duration: row.get(4)?,
+                        listened_at: row.get(5)?,
+                    },
+                ))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(listens)
+    }
+
+    pub fn remove_scrobbles(&mut self, ids: &[i64]) -> anyhow::Result<()> {
+        let tx = self.conn.transaction()?;
+        {
+            let mut stmt = tx.prepare_cached("DELETE FROM scrobble_queue WHERE id = ?1")?;
+            for id in ids {
+                stmt.execute([id])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn clear_scrobbles(&self, service: ScrobbleService) -> anyhow::Result<()> {
+        self.conn.execute(
+            "DELETE FROM scrobble_queue WHERE service = ?1",
+            [service_key(service)],
+        )?;
+        Ok(())
+    }
+
+    pub fn pending_scrobble_count(&self, service: ScrobbleService) -> anyhow::Result<u32> {
+        Ok(self.conn.query_row(
+            "SELECT COUNT(*) FROM scrobble_queue WHERE service = ?1",
+            [service_key(service)],
+            |row| row.get(0),
+        )?)
+    }
+}
@@ -0,0 +1,89 @@
+use super::browse::{album_summary_from_row, ALBUM_TOTALS};
+use super::{song_from_row, Library, SONG_COLUMNS, SONG_JOINS, VISIBLE};
+use crate::{Artist, SearchResults};
+
+/// Matching index rows as `m(rowid, score)`, lower scores ranking higher; the `bm25`
+/// weights follow the column order: title, artist, album, file name
+///
+/// Materialized so SQLite doesn't flatten `bm25` into the grouped queries, where it
+/// can't be evaluated.
+const MATCHES: &str = "WITH m AS MATERIALIZED (
+        SELECT rowid, bm25(songs_fts, 10.0, 5.0, 5.0, 1.0) AS score
+        FROM songs_fts WHERE songs_fts MATCH ?1
+    )";
+
+/// Turn free text into an FTS5 query where every word must match as a prefix
+///
+/// Words are quoted so FTS5 operators and punctuation in user input are taken literally.
+fn match_expression(query: &str) -> Option<String> {
+    let terms: Vec<String> = query
+        .split_whitespace()
+        .map(|word| word.replace('"', ""))
+        .filter(|word| !word.is_empty())
+        .map(|word| format!("\"{word}\"*"))
+        .collect();
+    (!terms.is_empty()).then(|| terms.join(" "))
+}
+
+impl Library {
+    /// Ranked prefix search over songs, plus the artists and albums whose names match
+    pub fn search_library(&self, query: &str, limit: u32) -> anyhow::Result<SearchResults> {
+        let Some(terms) = match_expression(query) else {
+            return Ok(SearchResults::default());
+        };
+
+        let sql = format!(
+            "{MATCHES} SELECT {SONG_COLUMNS} FROM {SONG_JOINS} JOIN m ON m.rowid = s.rowid
+             WHERE {VISIBLE} ORDER BY m.score LIMIT ?2"
+        );
+        let songs = self
+            .conn
+            .prepare_cached(&sql)?
+            .query_map(rusqlite::params![terms, limit], song_from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+
+        let sql = format!(
+            "{MATCHES} SELECT ar.id, ar.name FROM m
+             JOIN songs s ON s.rowid = m.rowid
+             JOIN artists ar ON ar.id = s.artist_id
+             WHERE {VISIBLE}
+             GROUP BY ar.id ORDER BY min(m.score) LIMIT ?2"
+        );
+        let artists = self
+            .conn
+            .prepare_cached(&sql)?
+            .query_map(
+                rusqlite::params![format!("{{artist}} : ({terms})"), limit],
+                |row| {
+                    Ok(Artist {
+                        id: row.get(0)?,
+                        name: row.get(1)?,
+                    })
+                },
+            )?
+            .collect::<rusqlite::Result<_>>()?;
+
+        let sql = format!(
+            "{MATCHES} SELECT al.id, al.title, ar.id, ar.name, {ALBUM_TOTALS} FROM m
+             JOIN songs s ON s.rowid = m.rowid
+             JOIN albums al ON al.id = s.album_id
+             JOIN artists ar ON ar.id = al.artist_id
+             WHERE {VISIBLE}
+             GROUP BY al.id ORDER BY min(m.score) LIMIT ?2"
+        );
+        let albums = self
+            .conn
+            .prepare_cached(&sql)?
+            .query_map(
+                rusqlite::params![format!("{{album}} : ({terms})"), limit],
+                album_summary_from_row,
+            )?
+            .collect::<rusqlite::Result<_>>()?;
+
+        Ok(SearchResults {
+            songs,
+            artists,
+            albums,
+        })
+    }
+}
@@ -0,0 +1,69 @@
+use rusqlite::{params, OptionalExtension};
+
+use super::Library;
+use crate::silence::SilenceSettings;
+use crate::{Song, SongId, TrackSilence};
+
+impl Library {
+    /// Silence measured at either end of a song under `settings`, if it has been
+    pub fn get_silence(
+        &self,
+        song_id: &SongId,
+        settings: SilenceSettings,
+    ) -> anyhow::Result<Option<TrackSilence>> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT leading, trailing FROM song_silence
+                 WHERE song_id = ?1 AND threshold_db = ?2 AND min_duration = ?3",
+                params![song_id, settings.threshold_db, settings.min_duration_secs],
+                |row| {
+                    Ok(TrackSilence {
+                        leading_secs: row.get(0)?,
+                        trailing_secs: row.get(1)?,
+                    })
+                },
+            )
+            .optional()?)
+    }
+
+    pub fn set_silence(
+        &self,
+        song_id: &SongId,
+        settings: SilenceSettings,
+        silence: TrackSilence,
+    ) -> anyhow::Result<()> {
+        self.conn.execute(
+            "INSERT INTO song_silence (song_id, threshold_db, min_duration, leading, trailing)
+             VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT (song_id) DO UPDATE SET
+                threshold_db = excluded.threshold_db,
+                min_duration = excluded.min_duration,
+                leading = excluded.leading,
+                trailing = excluded.trailing",
+            params![
+                song_id,
+                settings.threshold_db,
+                settings.min_duration_secs,
+                silence.leading_secs,
+                silence.trailing_secs,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Songs whose silence hasn't been measured under `settings`
+    pub fn get_songs_without_silence(
+        &self,
+        settings: SilenceSettings,
+    ) -> anyhow::Result<Vec<Song>> {
+        self.query_songs(
+            "WHERE NOT EXISTS (
+                SELECT 1 FROM song_silence x
+                WHERE x.song_id = s.id AND x.threshold_db = ?1 AND x.min_duration = ?2
+             )
+             ORDER BY s.file_path, s.start_offset",
+            params![settings.threshold_db, settings.min_duration_secs],
+        )
+    }
+}
@@ -0,0 +1,195 @@
+use anyhow::{ensure, Context};
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter, OptionalExtension};
+
+use super::{now_secs, song_from_row, Library, SONG_COLUMNS, SONG_JOINS, SONG_ORDER, VISIBLE};
+use crate::{
+    DateField, NumberField, NumberOp, SmartOrder, SmartPlaylist, SmartRule, SmartRules, Song,
+    TextField, TextOp,
+};
+
+const SECS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// Escape `%`, `_` and the escape character itself for a `LIKE ... ESCAPE '\'` pattern
+fn like_escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+/// SQL for one rule, pushing its parameters onto `values`
+///
+/// Expressions refer to the `SONG_JOINS` aliases plus `p` for the song's plays.
+fn condition(rule: &SmartRule, values: &mut Vec<Value>) -> String {
+    match rule {
+        SmartRule::Text { field, op, value } => {
+            let column = match field {
+                TextField::Title => "s.title",
+                TextField::Artist => "ar.name",
+                TextField::Album => "al.title",
+                TextField::Genre => "COALESCE(s.genre, '')",
+            };
+            let (sql, pattern) = match op {
+                TextOp::Is => ("= ? COLLATE NOCASE", value.clone()),
+                TextOp::IsNot => ("<> ? COLLATE NOCASE", value.clone()),
+                TextOp::Contains => ("LIKE ? ESCAPE '\\'", format!("%{}%", like_escape(value))),
+                TextOp::DoesNotContain => (
+                    "NOT LIKE ? ESCAPE '\\'",
+                    format!("%{}%", like_escape(value)),
+                ),
+                TextOp::StartsWith => ("LIKE ? ESCAPE '\\'", format!("{}%", like_escape(value))),
+            };
+            values.push(Value::Text(pattern));
+            format!("{column} {sql}")
+        }
+        SmartRule::Number { field, op, value } => {
+            let column = match field {
+                NumberField::Rating => "COALESCE(s.rating, 0)",
+                NumberField::PlayCount => "COALESCE(p.play_count, 0)",
+                NumberField::Duration => "s.duration",
+                NumberField::Bpm => "s.bpm",
+            };
+            let op = match op {
+                NumberOp::Equal => "=",
+                NumberOp::NotEqual => "<>",
+                NumberOp::Greater => ">",
+                NumberOp::GreaterOrEqual => ">=",
+                NumberOp::Less => "<",
+                NumberOp::LessOrEqual => "<=",
+            };
+            values.push(Value::Real(*value));
+            format!("{column} {op} ?")
+        }
+        SmartRule::Date {
+            field,
+            within,
+            days,
+        } => {
+            let column = match field {
+                DateField::LastPlayed => "p.last_played",
+                DateField::DateAdded => "s.date_added",
+            };
+            values.push(Value::Integer(now_secs() - *days as i64 * SECS_PER_DAY));
+            match within {
+                true => format!("{column} >= ?"),
+                false => format!("({column} IS NULL OR {column} < ?)"),
+            }
+        }
+    }
+}
+
+fn order_by(order: SmartOrder) -> &'static str {
+    match order {
+        SmartOrder::Random => "random()",
+        SmartOrder::Title => "s.title COLLATE unicode",
+        SmartOrder::Artist => SONG_ORDER,
+        SmartOrder::Album => "al.sort_title COLLATE unicode, s.title COLLATE unicode",
+        SmartOrder::MostPlayed => "COALESCE(p.play_count, 0) DESC, p.last_played DESC",
+        SmartOrder::RecentlyPlayed => "p.last_played IS NULL, p.last_played DESC",
+        SmartOrder::RecentlyAdded => "s.date_added DESC",
+        SmartOrder::HighestRated => "COALESCE(s.rating, 0) DESC, s.title",
+        SmartOrder::Bpm => "s.bpm IS NULL, s.bpm, s.title",
+    }
+}
+
+impl Library {
+    pub fn create_smart_playlist(&self, name: &str, rules: &SmartRules) -> anyhow::Result<i64> {
+        self.conn.execute(
+            "INSERT INTO smart_playlists (name, rules, created_at) VALUES (?1, ?2, ?3)",
+            params![name, serde_json::to_string(rules)?, now_secs()],
+        )?;
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    pub fn update_smart_playlist(
+        &self,
+        id: i64,
+        name: &str,
+        rules: &SmartRules,
+    ) -> anyhow::Result<()> {
+        let changed = self.conn.execute(
+            "UPDATE smart_playlists SET name = ?1, rules = ?2 WHERE id = ?3",
+            params![name, serde_json::to_string(rules)?, id],
+        )?;
+        ensure!(changed == 1, "no smart playlist with id {id}");
+        Ok(())
+    }
+
+    pub fn delete_smart_playlist(&self, id: i64) -> anyhow::Result<()> {
+        let changed = self
+            .conn
+            .execute("DELETE FROM smart_playlists WHERE id = ?1", [id])?;
+        ensure!(changed == 1, "no smart playlist with id {id}");
+        Ok(())
+    }
+
+    pub fn get_smart_playlists(&self) -> anyhow::Result<Vec<SmartPlaylist>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT id, name, rules, created_at FROM smart_playlists ORDER BY name COLLATE unicode",
+        )?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get(3)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        rows.into_iter()
+            .map(|(id, name, rules, created_at)| {
+                Ok(SmartPlaylist {
+                    id,
+                    name,
+                    rules: serde_json::from_str(&rules)
+                        .with_context(|| format!("smart playlist {id} has unreadable rules"))?,
+                    created_at,
+                })
+            })
+            .collect()
+    }
+
+    /// Songs matching the rules of smart playlist `id` right now
+    pub fn evaluate_smart_playlist(&self, id: i64) -> anyhow::Result<Vec<Song>> {
+        let rules: String = self
+            .conn
+            .query_row(
+                "SELECT rules FROM smart_playlists WHERE id = ?1",
+                [id],
+                |row| row.get(0),
+            )
+            .optional()?
+            .with_context(|| format!("no smart playlist with id {id}"))?;
+        let rules: SmartRules = serde_json::from_str(&rules)
+            .with_context(|| format!("smart playlist {id} has unreadable rules"))?;
+        self.query_smart_rules(&rules)
+    }
+
+    fn query_smart_rules(&self, rules: &SmartRules) -> anyhow::Result<Vec<Song>> {
+        let mut values = Vec::new();
+        let conditions: Vec<String> = rules
+            .rules
+            .iter()
+            .map(|rule| condition(rule, &mut values))
+            .collect();
+        let filter = match conditions.is_empty() {
+            true => "1".to_string(),
+            false => conditions.join(if rules.match_all { " AND " } else { " OR " }),
+        };
+        let limit = rules.limit.map_or(-1, i64::from);
+        values.push(Value::Integer(limit));
+        let sql = format!(
+            "SELECT {SONG_COLUMNS} FROM {SONG_JOINS}
+             LEFT JOIN song_plays p ON p.song_id = s.id
+             WHERE ({filter}) AND {VISIBLE} ORDER BY {} LIMIT ?",
+            order_by(rules.order)
+        );
+        let songs = self
+            .conn
+            .prepare(&sql)?
+            .query_map(params_from_iter(values), song_from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(songs)
+    }
+}
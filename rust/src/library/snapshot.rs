@@ -0,0 +1,165 @@
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{ensure, Context};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use rusqlite::Connection;
+
+use super::{now_secs, schema, Library, LIBRARY};
+use crate::transcode::partial_path;
+use crate::SnapshotInfo;
+
+/// First line of every snapshot
+const MAGIC: &str = "tunes4r library snapshot";
+
+/// Layout of snapshots: the magic line, a line of `SnapshotInfo` JSON and then the
+/// database file, all gzipped
+const FORMAT_VERSION: u32 = 1;
+
+/// `path` with `suffix` added to its file name
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(OsStr::new(suffix));
+    PathBuf::from(name)
+}
+
+fn count(conn: &Connection, table: &str) -> rusqlite::Result<u32> {
+    conn.query_row(&format!("SELECT COUNT(*) FROM {table}"), [], |row| {
+        row.get(0)
+    })
+}
+
+impl Library {
+    /// Write a snapshot of the database to `path`, without what only makes sense on
+    /// this device: downloaded episodes and offline copies
+    pub fn export_snapshot(&self, path: &Path) -> anyhow::Result<SnapshotInfo> {
+        let copy = with_suffix(&partial_path(path), ".db");
+        let _ = fs::remove_file(&copy);
+        let result = self.write_snapshot(&copy, path);
+        let _ = fs::remove_file(&copy);
+        result
+    }
+
+    fn write_snapshot(&self, copy: &Path, path: &Path) -> anyhow::Result<SnapshotInfo> {
+        self.conn
+            .execute("VACUUM INTO ?1", [copy.to_string_lossy()])
+            .context("failed to copy the library database")?;
+        let info = {
+            let conn = Connection::open(copy)?;
+            conn.execute_batch(
+                "DELETE FROM offline_copies;
+                 UPDATE podcast_episodes SET download_path = NULL;",
+            )?;
+            SnapshotInfo {
+                format_version: FORMAT_VERSION,
+                schema_version: schema::version() as u32,
+                created_at: now_secs(),
+                app_version: env!("CARGO_PKG_VERSION").to_string(),
+                songs: count(&conn, "songs")?,
+                playlists: count(&conn, "playlists")?,
+            }
+        };
+        let partial = partial_path(path);
+        if let Err(e) = write_archive(&partial, &info, copy) {
+            let _ = fs::remove_file(&partial);
+            return Err(e.context(format!("failed to write {}", path.display())));
+        }
+        fs::rename(&partial, path)
+            .with_context(|| format!("failed to move {} into place", path.display()))?;
+        log::info!(
+            "saved a snapshot of {} songs to {}",
+            info.songs,
+            path.display()
+        );
+        Ok(info)
+    }
+}
+
+fn write_archive(path: &Path, info: &SnapshotInfo, database: &Path) -> anyhow::Result<()> {
+    let file = BufWriter::new(File::create(path)?);
+    let mut out = GzEncoder::new(file, Compression::default());
+    writeln!(out, "{MAGIC}")?;
+    writeln!(out, "{}", serde_json::to_string(info)?)?;
+    io::copy(&mut File::open(database)?, &mut out)?;
+    out.finish()?.flush()?;
+    Ok(())
+}
+
+/// Replace the open library with the snapshot at `path`, bringing a snapshot from an
+/// older version up to the current schema
+///
+/// The replaced database is kept next to it with a `.bak` suffix.
+pub(crate) fn restore_snapshot(path: &Path) -> anyhow::Result<SnapshotInfo> {
+    let file = File::open(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let mut reader = BufReader::new(GzDecoder::new(BufReader::new(file)));
+    let mut line = String::new();
+    let not_snapshot = || format!("{} isn't a library snapshot", path.display());
+    reader.read_line(&mut line).with_context(not_snapshot)?;
+    ensure!(line.trim_end() == MAGIC, not_snapshot());
+    line.clear();
+    reader.read_line(&mut line)?;
+    let info: SnapshotInfo =
+        serde_json::from_str(&line).context("unreadable library snapshot header")?;
+    ensure!(
+        info.format_version <= FORMAT_VERSION && info.schema_version as usize <= schema::version(),
+        "the snapshot is from a newer version ({}); update the app to restore it",
+        info.app_version
+    );
+
+    let mut guard = LIBRARY.lock().unwrap();
+    let current = guard
+        .as_ref()
+        .context("library database is not open; call open_library first")?;
+    let database = PathBuf::from(
+        current
+            .conn
+            .path()
+            .filter(|path| !path.is_empty())
+            .context("the library database isn't a file")?,
+    );
+    let restored = with_suffix(&database, ".restore");
+    for suffix in ["-wal", "-shm"] {
+        let _ = fs::remove_file(with_suffix(&restored, suffix));
+    }
+    let unpacked = File::create(&restored)
+        .map_err(anyhow::Error::from)
+        .and_then(|mut file| Ok(io::copy(&mut reader, &mut file)?))
+        .context("failed to unpack the snapshot")
+        // Opening it applies any migrations newer than the snapshot.
+        .and_then(|_| {
+            Library::open(&restored)
+                .map(drop)
+                .context("the snapshot's database is damaged")
+        });
+    if let Err(e) = unpacked {
+        let _ = fs::remove_file(&restored);
+        return Err(e);
+    }
+
+    // Fold the write-ahead log in, so the kept copy is complete on its own.
+    current
+        .conn
+        .query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+    guard.take();
+    let backup = with_suffix(&database, ".bak");
+    let swapped = fs::rename(&database, &backup).and_then(|()| {
+        fs::rename(&restored, &database).inspect_err(|_| {
+            let _ = fs::rename(&backup, &database);
+        })
+    });
+    for suffix in ["-wal", "-shm"] {
+        let _ = fs::remove_file(with_suffix(&database, suffix));
+    }
+    *guard = Some(Library::open(&database)?);
+    swapped.context("failed to replace the library database")?;
+    log::info!(
+        "restored {} songs from the snapshot {}",
+        info.songs,
+        path.display()
+    );
+    Ok(info)
+}
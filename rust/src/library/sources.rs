@@ -0,0 +1,97 @@
+use std::collections::HashSet;
+
+use anyhow::{ensure, Context};
+use rusqlite::{params, OptionalExtension, Row};
+
+use super::{now_secs, Library};
+use crate::{sources, LibrarySource, SourceConfig};
+
+fn source_from_row(row: &Row<'_>) -> rusqlite::Result<(i64, String, String, i64)> {
+    Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+}
+
+fn parse_source(
+    (id, name, config, created_at): (i64, String, String, i64),
+) -> anyhow::Result<LibrarySource> {
+    Ok(LibrarySource {
+        id,
+        name,
+        config: serde_json::from_str(&config)
+            .with_context(|| format!("library source {id} has unreadable settings"))?,
+        created_at,
+    })
+}
+
+impl Library {
+    pub fn add_library_source(&self, name: &str, config: &SourceConfig) -> anyhow::Result<i64> {
+        self.conn.execute(
+            "INSERT INTO library_sources (name, config, created_at) VALUES (?1, ?2, ?3)",
+            params![name, serde_json::to_string(config)?, now_secs()],
+        )?;
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    /// Forget source `id` along with the songs indexed from it
+    pub fn delete_library_source(&mut self, id: i64) -> anyhow::Result<()> {
+        let tx = self.conn.transaction()?;
+        let changed = tx.execute("DELETE FROM library_sources WHERE id = ?1", [id])?;
+        ensure!(changed == 1, "no library source with id {id}");
+        tx.execute(
+            "DELETE FROM songs WHERE substr(file_path, 1, length(?1)) = ?1",
+            [sources::uri(id, "")],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn get_library_sources(&self) -> anyhow::Result<Vec<LibrarySource>> {
+        self.conn
+            .prepare_cached(
+                "SELECT id, name, config, created_at FROM library_sources ORDER BY name",
+            )?
+            .query_map([], source_from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?
+            .into_iter()
+            .map(parse_source)
+            .collect()
+    }
+
+    pub fn get_library_source(&self, id: i64) -> anyhow::Result<Option<LibrarySource>> {
+        self.conn
+            .query_row(
+                "SELECT id, name, config, created_at FROM library_sources WHERE id = ?1",
+                [id],
+                source_from_row,
+            )
+            .optional()?
+            .map(parse_source)
+            .transpose()
+    }
+
+    /// Mark or unmark the songs of files as favorites, as a media server has them
+    pub fn set_file_favorites(&mut self, favorites: &[(&str, bool)]) -> anyhow::Result<()> {
+        let tx = self.conn.transaction()?;
+        {
+            let mut update =
+                tx.prepare_cached("UPDATE songs SET favorite = ?2 WHERE file_path = ?1")?;
+            for (file_path, favorite) in favorites {
+                update.execute(params![file_path, favorite])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Library paths of the files indexed from source `id`
+    pub fn get_source_file_paths(&self, id: i64) -> anyhow::Result<HashSet<String>> {
+        let paths = self
+            .conn
+            .prepare_cached(
+                "SELECT DISTINCT file_path FROM songs
+                 WHERE substr(file_path, 1, length(?1)) = ?1",
+            )?
+            .query_map([sources::uri(id, "")], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(paths)
+    }
+}
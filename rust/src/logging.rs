@@ -0,0 +1,146 @@
+use std::collections::VecDeque;
+use std::fmt::{self, Write as _};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Mutex, Once};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_log::NormalizeEvent;
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{filter, Layer};
+
+use crate::{LogLevel, LogRecord};
+
+/// Records kept for `recent`; the oldest are dropped once there are more
+const CAPACITY: usize = 2000;
+
+static RECORDS: Mutex<VecDeque<LogRecord>> = Mutex::new(VecDeque::new());
+
+/// Most verbose level logged, as a `LogLevel`
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+static INIT: Once = Once::new();
+
+impl From<Level> for LogLevel {
+    fn from(level: Level) -> Self {
+        match level {
+            Level::ERROR => LogLevel::Error,
+            Level::WARN => LogLevel::Warn,
+            Level::INFO => LogLevel::Info,
+            Level::DEBUG => LogLevel::Debug,
+            Level::TRACE => LogLevel::Trace,
+        }
+    }
+}
+
+impl LogLevel {
+    fn as_log_filter(self) -> log::LevelFilter {
+        match self {
+            LogLevel::Error => log::LevelFilter::Error,
+            LogLevel::Warn => log::LevelFilter::Warn,
+            LogLevel::Info => log::LevelFilter::Info,
+            LogLevel::Debug => log::LevelFilter::Debug,
+            LogLevel::Trace => log::LevelFilter::Trace,
+        }
+    }
+}
+
+fn enabled(level: Level) -> bool {
+    LogLevel::from(level) as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+/// Print log records to stderr and keep the latest for `recent`, with `log` macros
+/// routed through `tracing`; only the first call does anything
+pub(crate) fn init() {
+    INIT.call_once(install);
+}
+
+fn install() {
+    let result = tracing_subscriber::registry()
+        // Stdout is left to the app, like the JSON tunes4r-cli answers with.
+        .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
+        .with(Recorder)
+        .with(filter::filter_fn(|metadata| enabled(*metadata.level())))
+        .try_init();
+    match result {
+        Ok(()) => log::set_max_level(level().as_log_filter()),
+        // Another subscriber was installed first, e.g. by a test harness.
+        Err(e) => eprintln!("logging already set up: {e}"),
+    }
+}
+
+pub(crate) fn level() -> LogLevel {
+    match LEVEL.load(Ordering::Relaxed) {
+        0 => LogLevel::Error,
+        1 => LogLevel::Warn,
+        2 => LogLevel::Info,
+        3 => LogLevel::Debug,
+        _ => LogLevel::Trace,
+    }
+}
+
+pub(crate) fn set_level(level: LogLevel) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+    log::set_max_level(level.as_log_filter());
+}
+
+/// The last `limit` records at `level` or more severe, oldest first
+pub(crate) fn recent(level: LogLevel, limit: usize) -> Vec<LogRecord> {
+    let records = RECORDS.lock().unwrap();
+    let mut recent: Vec<LogRecord> = records
+        .iter()
+        .rev()
+        .filter(|record| record.level as u8 <= level as u8)
+        .take(limit)
+        .cloned()
+        .collect();
+    recent.reverse();
+    recent
+}
+
+/// Layer keeping the latest records in `RECORDS`
+struct Recorder;
+
+impl<S: Subscriber> Layer<S> for Recorder {
+    fn on_event(&self, event: &Event<'_>, _: Context<'_, S>) {
+        // Records from `log` carry their real target and level in fields of their own.
+        let normalized = event.normalized_metadata();
+        let metadata = normalized.as_ref().unwrap_or_else(|| event.metadata());
+        let mut message = Message::default();
+        event.record(&mut message);
+        let record = LogRecord {
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis() as i64),
+            level: (*metadata.level()).into(),
+            target: metadata.target().to_string(),
+            message: message.0,
+        };
+        let mut records = RECORDS.lock().unwrap();
+        if records.len() == CAPACITY {
+            records.pop_front();
+        }
+        records.push_back(record);
+    }
+}
+
+/// An event's message followed by its other fields as `name=value`
+#[derive(Default)]
+struct Message(String);
+
+impl Visit for Message {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name().starts_with("log.") {
+            return;
+        }
+        if !self.0.is_empty() {
+            self.0.push(' ');
+        }
+        let _ = match field.name() {
+            "message" => write!(self.0, "{value:?}"),
+            name => write!(self.0, "{name}={value:?}"),
+        };
+    }
+}
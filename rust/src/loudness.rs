@@ -0,0 +1,79 @@
+use std::path::Path;
+
+use ebur128::{EbuR128, Mode};
+use rodio::Source;
+
+use crate::decoder::SymphoniaSource;
+
+/// Reference level that ReplayGain 2.0 gains are relative to, in LUFS
+pub(crate) const REPLAYGAIN_REFERENCE_LUFS: f32 = -18.0;
+
+/// Never boost a quiet track by more than this many dB
+const MAX_BOOST_DB: f32 = 12.0;
+
+/// Frames handed to the meter per call
+const CHUNK_FRAMES: usize = 4096;
+
+/// Decode a whole file and measure its EBU R128 integrated loudness in LUFS
+pub(crate) fn measure(path: &Path) -> anyhow::Result<f32> {
+    let decoder = SymphoniaSource::open(path)?;
+    let mut meter = LoudnessMeter::new(decoder.channels(), decoder.sample_rate())?;
+    for sample in decoder {
+        meter.push(sample)?;
+    }
+    meter.loudness()
+}
+
+/// EBU R128 integrated loudness of interleaved samples pushed one at a time
+pub(crate) struct LoudnessMeter {
+    meter: EbuR128,
+    chunk: Vec<f32>,
+    channels: usize,
+}
+
+impl LoudnessMeter {
+    pub(crate) fn new(channels: u16, sample_rate: u32) -> anyhow::Result<Self> {
+        let channels = channels.max(1) as usize;
+        Ok(LoudnessMeter {
+            meter: EbuR128::new(channels as u32, sample_rate, Mode::I)?,
+            chunk: Vec::with_capacity(CHUNK_FRAMES * channels),
+            channels,
+        })
+    }
+
+    pub(crate) fn push(&mut self, sample: f32) -> anyhow::Result<()> {
+        self.chunk.push(sample);
+        if self.chunk.len() == self.chunk.capacity() {
+            self.meter.add_frames_f32(&self.chunk)?;
+            self.chunk.clear();
+        }
+        Ok(())
+    }
+
+    /// Loudness in LUFS of everything pushed, leaving out a trailing partial frame
+    pub(crate) fn loudness(mut self) -> anyhow::Result<f32> {
+        let whole_frames = self.chunk.len() / self.channels * self.channels;
+        self.meter.add_frames_f32(&self.chunk[..whole_frames])?;
+        Ok(self.meter.loudness_global()? as f32)
+    }
+}
+
+/// Parse a ReplayGain gain tag such as "-6.54 dB" into the loudness it implies
+pub(crate) fn from_replaygain(value: &str) -> Option<f32> {
+    let gain: f32 = value
+        .trim()
+        .trim_end_matches(|c: char| c.is_alphabetic())
+        .trim()
+        .parse()
+        .ok()?;
+    Some(REPLAYGAIN_REFERENCE_LUFS - gain)
+}
+
+/// Linear gain that brings `loudness_lufs` to `target_lufs`
+pub(crate) fn gain_for(loudness_lufs: f32, target_lufs: f32) -> f32 {
+    if !loudness_lufs.is_finite() {
+        return 1.0;
+    }
+    let gain_db = (target_lufs - loudness_lufs).min(MAX_BOOST_DB);
+    10f32.powf(gain_db / 20.0)
+}
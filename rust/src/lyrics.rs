@@ -0,0 +1,144 @@
+use std::fs;
+use std::path::Path;
+
+use id3::frame::{SynchronisedLyricsType, TimestampFormat};
+use lofty::prelude::*;
+
+use crate::{metadata, LyricLine, Lyrics};
+
+/// Seconds of an LRC timestamp such as `01:23.45`
+fn parse_timestamp(text: &str) -> Option<f64> {
+    let (minutes, seconds) = text.trim().split_once(':')?;
+    let minutes: u32 = minutes.parse().ok()?;
+    // Some files use `mm:ss:xx` for hundredths.
+    let seconds: f64 = seconds.replacen(':', ".", 1).parse().ok()?;
+    Some(minutes as f64 * 60.0 + seconds)
+}
+
+/// Drop the per-word `<mm:ss.xx>` timestamps of enhanced LRC
+fn strip_word_timestamps(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('<') {
+        out.push_str(&rest[..start]);
+        match rest[start + 1..].split_once('>') {
+            Some((inside, after)) if parse_timestamp(inside).is_some() => rest = after,
+            _ => {
+                out.push('<');
+                rest = &rest[start + 1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out.trim().to_string()
+}
+
+/// Timed lines of LRC text, sorted by time; empty if it has no timestamps
+///
+/// A line may carry several timestamps, e.g. for a repeated chorus, and an `[offset:]`
+/// tag shifts every line.
+fn parse_lrc(text: &str) -> Vec<LyricLine> {
+    let mut lines = Vec::new();
+    let mut offset_ms = 0.0;
+    for line in text.lines() {
+        let mut rest = line.trim();
+        let mut times = Vec::new();
+        while let Some((inside, after)) = rest.strip_prefix('[').and_then(|r| r.split_once(']')) {
+            if let Some(time) = parse_timestamp(inside) {
+                times.push(time);
+            } else if let Some(offset) = inside.strip_prefix("offset:") {
+                offset_ms = offset.trim().parse().unwrap_or(0.0);
+            }
+            rest = after;
+        }
+        let text = strip_word_timestamps(rest);
+        lines.extend(times.into_iter().map(|time| LyricLine {
+            time: Some(time),
+            text: text.clone(),
+        }));
+    }
+    // A positive offset makes lines appear sooner.
+    for line in &mut lines {
+        line.time = line.time.map(|t| (t - offset_ms / 1000.0).max(0.0));
+    }
+    lines.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap());
+    lines
+}
+
+/// Lyrics from text that is either LRC or plain, one line per line
+fn from_text(text: &str) -> Lyrics {
+    let text = text.trim_start_matches('\u{feff}');
+    let synced = parse_lrc(text);
+    if !synced.is_empty() {
+        return Lyrics {
+            synced: true,
+            lines: synced,
+        };
+    }
+    Lyrics {
+        synced: false,
+        lines: text
+            .trim()
+            .lines()
+            .map(|line| LyricLine {
+                time: None,
+                text: line.trim().to_string(),
+            })
+            .collect(),
+    }
+}
+
+/// Lines of an ID3 SYLT frame with millisecond timestamps
+///
+/// Frames that time single syllables mark line starts with a leading newline; those
+/// syllables are joined back into lines.
+fn sylt_lines(path: &Path) -> Option<Vec<LyricLine>> {
+    let tag = id3::Tag::read_from_path(path).ok()?;
+    let sylt = tag
+        .synchronised_lyrics()
+        .filter(|s| s.timestamp_format == TimestampFormat::Ms)
+        .max_by_key(|s| s.content_type == SynchronisedLyricsType::Lyrics)?;
+    let by_syllable = sylt
+        .content
+        .iter()
+        .any(|(_, text)| text.starts_with(['\n', '\r']));
+    let mut lines: Vec<LyricLine> = Vec::new();
+    for (time_ms, text) in &sylt.content {
+        match lines.last_mut() {
+            Some(line) if by_syllable && !text.starts_with(['\n', '\r']) => {
+                line.text.push_str(text);
+            }
+            _ => lines.push(LyricLine {
+                time: Some(*time_ms as f64 / 1000.0),
+                text: text.clone(),
+            }),
+        }
+    }
+    for line in &mut lines {
+        line.text = line.text.trim().to_string();
+    }
+    (!lines.is_empty()).then_some(lines)
+}
+
+/// Lyrics of the audio file at `path`, empty if it has none
+///
+/// A sidecar `.lrc` file next to it wins over embedded lyrics; among those, a synced
+/// SYLT frame wins over unsynced USLT/`LYRICS` tags, which may themselves hold LRC.
+pub(crate) fn read(path: &Path) -> anyhow::Result<Lyrics> {
+    if let Ok(bytes) = fs::read(path.with_extension("lrc")) {
+        return Ok(from_text(&String::from_utf8_lossy(&bytes)));
+    }
+    if let Some(lines) = sylt_lines(path) {
+        return Ok(Lyrics {
+            synced: true,
+            lines,
+        });
+    }
+    let tagged_file = metadata::read_tagged(path)?;
+    let text = tagged_file
+        .primary_tag()
+        .into_iter()
+        .chain(tagged_file.tags())
+        .find_map(|tag| tag.get_string(&ItemKey::Lyrics));
+    Ok(text.map(from_text).unwrap_or_default())
+}
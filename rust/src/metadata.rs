@@ -0,0 +1,377 @@
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use lofty::config::WriteOptions;
+use lofty::file::{FileType, TaggedFile};
+use lofty::picture::{Picture, PictureType};
+use lofty::prelude::*;
+use lofty::probe::Probe;
+use lofty::tag::{ItemValue, Tag, TagItem, TagType};
+
+use crate::key::Key;
+use crate::sources::{self, RemoteFile};
+use crate::{cue, loudness, offline, Song, SongId, TagChanges};
+
+pub(crate) const UNKNOWN_ARTIST: &str = "Unknown Artist";
+pub(crate) const UNKNOWN_ALBUM: &str = "Unknown Album";
+/// Artist compilations are credited to
+pub(crate) const VARIOUS_ARTISTS: &str = "Various Artists";
+
+/// Who ratings in ID3 POPM frames are attributed to
+const POPM_USER: &str = "tunes4r";
+
+static WRITE_RATING_TAGS: AtomicBool = AtomicBool::new(false);
+
+/// Tags and properties of the audio file at `path`, which may be in a remote source
+pub(crate) fn read_tagged(path: &Path) -> anyhow::Result<TaggedFile> {
+    if let Some(local) = offline::local_copy(path) {
+        return Ok(lofty::read_from_path(local)?);
+    }
+    if !sources::is_remote(path) {
+        return Ok(lofty::read_from_path(path)?);
+    }
+    let file = RemoteFile::open_for_tags(path)?;
+    let mut probe = Probe::new(file);
+    if let Some(file_type) = path.extension().and_then(FileType::from_ext) {
+        probe = probe.set_file_type(file_type);
+    }
+    Ok(probe.read()?)
+}
+
+/// Read tags and stream properties from an audio file into a `Song`
+///
+/// Missing fields fall back to the same placeholders the Dart side uses, and the title
+/// falls back to the file name.
+pub(crate) fn read_song(path: &Path) -> anyhow::Result<Song> {
+    let tagged_file = read_tagged(path)?;
+    let tag = tagged_file
+        .primary_tag()
+        .or_else(|| tagged_file.first_tag());
+
+    let text = |value: Option<std::borrow::Cow<'_, str>>| {
+        value
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+    };
+    let title = tag
+        .and_then(|t| text(t.title()))
+        .unwrap_or_else(|| file_stem(path));
+    let artist = tag
+        .and_then(|t| text(t.artist()))
+        .unwrap_or_else(|| UNKNOWN_ARTIST.to_string());
+    let album = tag
+        .and_then(|t| text(t.album()))
+        .unwrap_or_else(|| UNKNOWN_ALBUM.to_string());
+    // Discs tagged as albums of their own, like "Symphonies (Disc 2)", join their set.
+    let (album, titled_disc) = match split_disc_suffix(&album) {
+        Some((title, disc)) => (title.to_string(), Some(disc)),
+        None => (album, None),
+    };
+    let sort_order = |key| tag.and_then(|t| text(t.get_string(&key).map(Into::into)));
+
+    let album_artist = tag.and_then(|t| text(t.get_string(&ItemKey::AlbumArtist).map(Into::into)));
+    // iTunes' `cpil` flag and its equivalents, or a compilation credited as such
+    let compilation = tag
+        .and_then(|t| t.get_string(&ItemKey::FlagCompilation))
+        .is_some_and(|flag| matches!(flag.trim(), "1" | "true"))
+        || album_artist
+            .as_deref()
+            .is_some_and(|a| a.eq_ignore_ascii_case(VARIOUS_ARTISTS));
+    let genre = tag.and_then(|t| text(t.genre()));
+
+    let replaygain = |key| {
+        tag.and_then(|t| t.get_string(&key))
+            .and_then(loudness::from_replaygain)
+    };
+
+    let file_path = path.to_string_lossy().into_owned();
+    Ok(Song {
+        id: SongId::for_file(path, None),
+        title,
+        artist,
+        album,
+        album_artist,
+        compilation,
+        artist_sort: sort_order(ItemKey::TrackArtistSortOrder),
+        album_sort: sort_order(ItemKey::AlbumTitleSortOrder),
+        disc_number: tag.and_then(|t| t.disk()).or(titled_disc),
+        track_number: tag.and_then(|t| t.track()),
+        genre,
+        year: tag.and_then(|t| t.year()),
+        rating: None,
+        favorite: false,
+        duration: tagged_file.properties().duration().as_secs(),
+        file_path,
+        start_offset: 0.0,
+        end_offset: None,
+        track_loudness: replaygain(ItemKey::ReplayGainTrackGain),
+        album_loudness: replaygain(ItemKey::ReplayGainAlbumGain),
+        bpm: [ItemKey::Bpm, ItemKey::IntegerBpm]
+            .into_iter()
+            .find_map(|key| tag?.get_string(&key)?.trim().parse::<f32>().ok())
+            .filter(|&bpm| bpm > 0.0),
+        key: tag
+            .and_then(|tag| tag.get_string(&ItemKey::InitialKey))
+            .and_then(Key::parse)
+            .map(Key::name),
+        explicit: tag
+            .and_then(|tag| tag.get_string(&ItemKey::ParentalAdvisory))
+            .is_some_and(is_explicit),
+    })
+}
+
+/// Name `name` sorts under when untagged: without a leading "The ", as in
+/// "Beatles" for "The Beatles"
+pub(crate) fn sort_name(name: &str) -> &str {
+    match name.get(..4) {
+        Some(the) if the.eq_ignore_ascii_case("the ") && name.len() > 4 => &name[4..],
+        _ => name,
+    }
+}
+
+/// `album` without a "(Disc 2)", "[CD 2]" or "- Disc 2" suffix, and the disc it names
+fn split_disc_suffix(album: &str) -> Option<(&str, u32)> {
+    let album = album.trim_end();
+    let (inner, opening) = match album.strip_suffix(')') {
+        Some(inner) => (inner, Some('(')),
+        None => match album.strip_suffix(']') {
+            Some(inner) => (inner, Some('[')),
+            None => (album, None),
+        },
+    };
+    let digits = inner.trim_end_matches(|c: char| c.is_ascii_digit());
+    let disc = inner[digits.len()..].parse().ok()?;
+    let digits = digits.trim_end();
+    let word = ["disc", "disk", "cd"]
+        .into_iter()
+        .find(|word| digits.to_ascii_lowercase().ends_with(word))?;
+    let before = &digits[..digits.len() - word.len()];
+    let title = match opening {
+        Some(opening) => before.trim_end().strip_suffix(opening)?,
+        None if before.ends_with(char::is_whitespace) => {
+            before.trim_end().trim_end_matches(['-', ',', ':'])
+        }
+        None => return None,
+    };
+    let title = title.trim_end();
+    (!title.is_empty()).then_some((title, disc))
+}
+
+/// Whether a parental advisory value, as iTunes writes it in `ITUNESADVISORY` and the
+/// `rtng` atom, marks explicit content: 1, or 4 from older versions
+fn is_explicit(advisory: &str) -> bool {
+    matches!(advisory.trim(), "1" | "4") || advisory.trim().eq_ignore_ascii_case("explicit")
+}
+
+/// The tag edits are written to, created in the format's preferred kind if missing:
+/// ID3v2.4 for MP3, Vorbis comments for FLAC/Ogg/Opus and MP4 atoms for M4A
+pub(crate) fn writable_tag(tagged_file: &mut TaggedFile) -> &mut Tag {
+    if tagged_file.primary_tag().is_none() {
+        tagged_file.insert_tag(Tag::new(tagged_file.primary_tag_type()));
+    }
+    tagged_file.primary_tag_mut().unwrap()
+}
+
+/// Write the title, artist, album, album artist and genre of `song` into its file's tags
+///
+/// A title that's just the file name, like the one `read_song` falls back to, and
+/// placeholder artist and album names clear the field rather than being written out.
+/// Returns the song as read back from the file, keeping measured loudness that isn't
+/// tagged.
+pub(crate) fn write_song(song: &Song) -> anyhow::Result<Song> {
+    anyhow::ensure!(
+        !cue::is_track(song),
+        "{} is a CUE sheet track; edit the sheet instead",
+        song.title
+    );
+    let path = Path::new(&song.file_path);
+    let mut tagged_file = lofty::read_from_path(path)?;
+    let tag = writable_tag(&mut tagged_file);
+    let stem = file_stem(path);
+    let fields = [
+        (ItemKey::TrackTitle, song.title.trim(), stem.as_str()),
+        (ItemKey::TrackArtist, song.artist.trim(), UNKNOWN_ARTIST),
+        (ItemKey::AlbumTitle, song.album.trim(), UNKNOWN_ALBUM),
+        (
+            ItemKey::AlbumArtist,
+            song.album_artist.as_deref().unwrap_or("").trim(),
+            "",
+        ),
+        (
+            ItemKey::Genre,
+            song.genre.as_deref().unwrap_or("").trim(),
+            "",
+        ),
+    ];
+    for (key, value, placeholder) in fields {
+        if value.is_empty() || value == placeholder {
+            tag.remove_key(&key);
+        } else {
+            tag.insert_text(key, value.to_string());
+        }
+    }
+    tag.save_to_path(path, WriteOptions::default())?;
+
+    let mut updated = read_song(path)?;
+    updated.id = song.id.clone();
+    updated.track_loudness = updated.track_loudness.or(song.track_loudness);
+    updated.album_loudness = updated.album_loudness.or(song.album_loudness);
+    updated.bpm = updated.bpm.or(song.bpm);
+    updated.key = updated.key.or_else(|| song.key.clone());
+    Ok(updated)
+}
+
+/// Apply the fields `changes` has values for to the tags of the file at `path`
+///
+/// Empty values and placeholder names clear the field, as does a year of 0.
+pub(crate) fn write_tag_changes(path: &Path, changes: &TagChanges) -> anyhow::Result<()> {
+    let mut tagged_file = lofty::read_from_path(path)?;
+    let tag = writable_tag(&mut tagged_file);
+    let fields = [
+        (ItemKey::AlbumTitle, &changes.album, UNKNOWN_ALBUM),
+        (ItemKey::TrackArtist, &changes.artist, UNKNOWN_ARTIST),
+        (ItemKey::Genre, &changes.genre, ""),
+    ];
+    for (key, value, placeholder) in fields {
+        match value.as_deref().map(str::trim) {
+            None => {}
+            Some(value) if value.is_empty() || value == placeholder => tag.remove_key(&key),
+            Some(value) => {
+                tag.insert_text(key, value.to_string());
+            }
+        }
+    }
+    match changes.year {
+        None => {}
+        Some(0) => tag.remove_year(),
+        Some(year) => tag.set_year(year),
+    }
+    tag.save_to_path(path, WriteOptions::default())?;
+    Ok(())
+}
+
+/// The tag of a file edits go to, as it was before them
+pub(crate) struct SavedTag {
+    tag_type: TagType,
+    tag: Option<Tag>,
+}
+
+pub(crate) fn save_tag(path: &Path) -> anyhow::Result<SavedTag> {
+    let tagged_file = lofty::read_from_path(path)?;
+    Ok(SavedTag {
+        tag_type: tagged_file.primary_tag_type(),
+        tag: tagged_file.primary_tag().cloned(),
+    })
+}
+
+/// Put back a tag `save_tag` kept, removing the one edits created if there was none
+pub(crate) fn restore_tag(path: &Path, saved: &SavedTag) -> anyhow::Result<()> {
+    match &saved.tag {
+        Some(tag) => tag.save_to_path(path, WriteOptions::default())?,
+        None => saved.tag_type.remove_from_path(path)?,
+    }
+    Ok(())
+}
+
+/// Tag the file at `path` with the MusicBrainz recording it was identified as
+pub(crate) fn write_recording_id(path: &Path, recording_id: &str) -> anyhow::Result<()> {
+    let mut tagged_file = lofty::read_from_path(path)?;
+    let tag = writable_tag(&mut tagged_file);
+    tag.insert_text(ItemKey::MusicBrainzRecordingId, recording_id.to_string());
+    tag.save_to_path(path, WriteOptions::default())?;
+    Ok(())
+}
+
+pub(crate) fn set_rating_tags_enabled(enabled: bool) {
+    WRITE_RATING_TAGS.store(enabled, Ordering::Relaxed);
+}
+
+pub(crate) fn writes_rating_tags() -> bool {
+    WRITE_RATING_TAGS.load(Ordering::Relaxed)
+}
+
+/// Write a 1 to 5 star rating into the file at `path`, or remove it for `None`
+///
+/// MP3s get an ID3 POPM frame, on the scale Windows Media Player and most other
+/// players read; Vorbis comments and APE tags an FMPS_RATING from 0 to 1. Other tag
+/// formats have no common rating field and are left alone.
+pub(crate) fn write_rating(path: &Path, rating: Option<u8>) -> anyhow::Result<()> {
+    let mut tagged_file = lofty::read_from_path(path)?;
+    if tagged_file.primary_tag_type() == TagType::Id3v2 {
+        return write_popm(path, rating);
+    }
+    let tag = writable_tag(&mut tagged_file);
+    if !matches!(tag.tag_type(), TagType::VorbisComments | TagType::Ape) {
+        log::debug!("{} has no rating field to write", path.display());
+        return Ok(());
+    }
+    let key = ItemKey::Unknown("FMPS_RATING".to_string());
+    match rating {
+        Some(stars) => {
+            let value = format!("{:.1}", stars as f32 / 5.0);
+            tag.insert_unchecked(TagItem::new(key, ItemValue::Text(value)));
+        }
+        None => tag.remove_key(&key),
+    }
+    tag.save_to_path(path, WriteOptions::default())?;
+    Ok(())
+}
+
+fn write_popm(path: &Path, rating: Option<u8>) -> anyhow::Result<()> {
+    use id3::frame::{Content, Frame, Popularimeter};
+    use id3::{ErrorKind, TagLike, Version};
+
+    let mut tag = match id3::Tag::read_from_path(path) {
+        Ok(tag) => tag,
+        Err(e) if matches!(e.kind, ErrorKind::NoTag) => id3::Tag::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let others: Vec<Frame> = tag
+        .frames()
+        .filter(|frame| {
+            frame.id() == "POPM"
+                && frame
+                    .content()
+                    .popularimeter()
+                    .is_some_and(|popm| popm.user != POPM_USER)
+        })
+        .cloned()
+        .collect();
+    tag.remove("POPM");
+    for frame in others {
+        tag.add_frame(frame);
+    }
+    if let Some(stars) = rating {
+        let rating = [1, 64, 128, 196, 255][stars.clamp(1, 5) as usize - 1];
+        tag.add_frame(Frame::with_content(
+            "POPM",
+            Content::Popularimeter(Popularimeter {
+                user: POPM_USER.to_string(),
+                rating,
+                counter: 0,
+            }),
+        ));
+    }
+    tag.write_to_path(path, Version::Id3v24)?;
+    Ok(())
+}
+
+/// Replace the front cover embedded in the file at `path`, or remove it for `None`
+pub(crate) fn write_artwork(path: &Path, image: Option<&[u8]>) -> anyhow::Result<()> {
+    let mut tagged_file = lofty::read_from_path(path)?;
+    let tag = writable_tag(&mut tagged_file);
+    tag.remove_picture_type(PictureType::CoverFront);
+    if let Some(mut image) = image {
+        let mut picture = Picture::from_reader(&mut image)?;
+        picture.set_pic_type(PictureType::CoverFront);
+        tag.push_picture(picture);
+    }
+    tag.save_to_path(path, WriteOptions::default())?;
+    Ok(())
+}
+
+fn file_stem(path: &Path) -> String {
+    path.file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
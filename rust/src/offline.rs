@@ -0,0 +1,552 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use anyhow::{ensure, Context};
+use md5::{Digest, Md5};
+use symphonia::core::io::MediaSource;
+
+use crate::jobs::{self, Job};
+use crate::library::{self, now_secs, Library};
+use crate::podcasts::downloads;
+use crate::sources::{self, RemoteFile};
+use crate::{
+    transcode, DownloadState, JobKind, OfflineItem, OfflineItemState, StorageKind, StreamSink,
+};
+
+/// Most bytes of offline copies kept unless `set_quota` says otherwise
+const DEFAULT_QUOTA_BYTES: u64 = 4 * 1024 * 1024 * 1024;
+
+/// Report `Downloading` every this many bytes
+const PROGRESS_EVERY: u64 = 256 * 1024;
+
+/// Key prefix of podcast episodes; other keys are library paths of remote files
+const EPISODE_PREFIX: &str = "episode:";
+
+static QUOTA_BYTES: AtomicU64 = AtomicU64::new(DEFAULT_QUOTA_BYTES);
+
+/// Where state changes go, set by `watch`
+static LISTENER: Mutex<Option<StreamSink<OfflineItemState>>> = Mutex::new(None);
+
+/// Downloads queued or running, by key
+static DOWNLOADS: Mutex<Option<HashMap<String, Download>>> = Mutex::new(None);
+
+struct Download {
+    job_id: u32,
+    /// `Queued` or the latest `Downloading`
+    state: DownloadState,
+}
+
+/// A whole copy of a remote song's file or of a podcast episode, kept to play offline
+pub(crate) struct OfflineCopy {
+    /// Library path of the remote file, or `episode:<id>`
+    pub item: String,
+    pub local_path: String,
+    pub size: u64,
+    /// Hex digest of the file as downloaded, which `verify` checks it against
+    pub md5: String,
+    /// Pinned copies are never evicted
+    pub pinned: bool,
+    /// Seconds since the Unix epoch
+    pub used_at: i64,
+}
+
+/// Key of what `item` downloads: the file of a song, or an episode
+fn key(lib: &Library, item: &OfflineItem) -> anyhow::Result<String> {
+    match item {
+        OfflineItem::Song { song_id } => {
+            let song = lib
+                .get_song(song_id)?
+                .with_context(|| format!("no song with id {song_id}"))?;
+            ensure!(
+                sources::is_remote(Path::new(&song.file_path)),
+                "{} is a local file, which plays offline anyway",
+                song.file_path
+            );
+            Ok(song.file_path)
+        }
+        OfflineItem::Episode { episode_id } => {
+            lib.get_podcast_episode(*episode_id)?
+                .with_context(|| format!("no podcast episode with id {episode_id}"))?;
+            Ok(format!("{EPISODE_PREFIX}{episode_id}"))
+        }
+    }
+}
+
+fn episode_id(key: &str) -> Option<i64> {
+    key.strip_prefix(EPISODE_PREFIX)?.parse().ok()
+}
+
+/// The items a key stands for: every song of a remote file, or an episode
+fn items(lib: &Library, key: &str) -> anyhow::Result<Vec<OfflineItem>> {
+    if let Some(episode_id) = episode_id(key) {
+        return Ok(vec![OfflineItem::Episode { episode_id }]);
+    }
+    Ok(lib
+        .get_file_songs(key)?
+        .into_iter()
+        .map(|song| OfflineItem::Song { song_id: song.id })
+        .collect())
+}
+
+fn emit(item: &OfflineItem, state: DownloadState) {
+    let mut listener = LISTENER.lock().unwrap();
+    let event = OfflineItemState {
+        item: item.clone(),
+        state,
+    };
+    if listener
+        .as_ref()
+        .is_some_and(|sink| sink.add(event).is_err())
+    {
+        listener.take();
+    }
+}
+
+fn emit_key(lib: &Library, key: &str, state: DownloadState) -> anyhow::Result<()> {
+    for item in items(lib, key)? {
+        emit(&item, state.clone());
+    }
+    Ok(())
+}
+
+/// Send every change of an item's download state to `sink`, replacing any earlier
+/// listener
+pub(crate) fn watch(sink: StreamSink<OfflineItemState>) {
+    *LISTENER.lock().unwrap() = Some(sink);
+}
+
+/// Whether the copy is still there as it was downloaded, going by its size; `verify`
+/// looks at the contents
+fn is_intact(copy: &OfflineCopy) -> bool {
+    fs::metadata(&copy.local_path).is_ok_and(|m| m.is_file() && m.len() == copy.size)
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Delete a copy and its record
+fn discard(lib: &Library, copy: &OfflineCopy) -> anyhow::Result<()> {
+    remove_if_exists(Path::new(&copy.local_path))
+        .with_context(|| format!("failed to delete {}", copy.local_path))?;
+    if let Some(episode_id) = episode_id(&copy.item) {
+        lib.set_episode_download(episode_id, None)?;
+    }
+    lib.delete_offline_copy(&copy.item)
+}
+
+fn state_of(lib: &Library, key: &str) -> anyhow::Result<DownloadState> {
+    if let Some(download) = DOWNLOADS.lock().unwrap().as_ref().and_then(|d| d.get(key)) {
+        return Ok(download.state.clone());
+    }
+    Ok(match lib.get_offline_copy(key)? {
+        Some(copy) if is_intact(&copy) => DownloadState::Downloaded {
+            pinned: copy.pinned,
+            size_bytes: copy.size,
+        },
+        Some(copy) => {
+            log::warn!("offline copy of {key} is gone or truncated; dropping it");
+            discard(lib, &copy)?;
+            DownloadState::NotDownloaded
+        }
+        None => DownloadState::NotDownloaded,
+    })
+}
+
+pub(crate) fn state(item: &OfflineItem) -> anyhow::Result<DownloadState> {
+    library::with_library(|lib| {
+        let key = key(lib, item)?;
+        state_of(lib, &key)
+    })
+}
+
+/// Every item downloaded, queued or downloading
+pub(crate) fn list() -> anyhow::Result<Vec<OfflineItemState>> {
+    library::with_library(|lib| {
+        let mut keys: Vec<String> = lib
+            .get_offline_copies()?
+            .into_iter()
+            .map(|copy| copy.item)
+            .collect();
+        if let Some(downloads) = DOWNLOADS.lock().unwrap().as_ref() {
+            let queued: Vec<String> = downloads
+                .keys()
+                .filter(|key| !keys.contains(key))
+                .cloned()
+                .collect();
+            keys.extend(queued);
+        }
+        let mut states = Vec::new();
+        for key in keys {
+            let state = state_of(lib, &key)?;
+            if state == DownloadState::NotDownloaded {
+                continue;
+            }
+            for item in items(lib, &key)? {
+                states.push(OfflineItemState {
+                    item,
+                    state: state.clone(),
+                });
+            }
+        }
+        Ok(states)
+    })
+}
+
+/// Keep `item` offline, downloading it in the background unless it already is
+///
+/// A pin whose download fails or is cancelled is dropped.
+pub(crate) fn pin(item: OfflineItem) -> anyhow::Result<()> {
+    let (key, downloaded) = library::with_library(|lib| {
+        let key = key(lib, &item)?;
+        let downloaded = matches!(state_of(lib, &key)?, DownloadState::Downloaded { .. });
+        if downloaded {
+            lib.set_offline_pinned(&key, true)?;
+        }
+        Ok((key, downloaded))
+    })?;
+    if downloaded {
+        let state = state(&item)?;
+        emit(&item, state);
+        return Ok(());
+    }
+
+    // Held while submitting, so the job can't finish before it's recorded.
+    let mut downloads = DOWNLOADS.lock().unwrap();
+    let downloads = downloads.get_or_insert_with(HashMap::new);
+    if downloads.contains_key(&key) {
+        return Ok(());
+    }
+    emit(&item, DownloadState::Queued);
+    let job_id = jobs::submit(JobKind::Download, {
+        let key = key.clone();
+        move |job| run(&item, &key, job)
+    });
+    downloads.insert(
+        key,
+        Download {
+            job_id,
+            state: DownloadState::Queued,
+        },
+    );
+    Ok(())
+}
+
+/// Let `item` be evicted when space is needed, stopping its download if it's still
+/// being fetched
+pub(crate) fn unpin(item: &OfflineItem) -> anyhow::Result<()> {
+    let state = library::with_library(|lib| {
+        let key = key(lib, item)?;
+        cancel(&key);
+        if lib.set_offline_pinned(&key, false)? {
+            evict(lib)?;
+        }
+        state_of(lib, &key)
+    })?;
+    // Evicting and cancelling report for themselves.
+    if matches!(state, DownloadState::Downloaded { .. }) {
+        emit(item, state);
+    }
+    Ok(())
+}
+
+/// Delete the offline copy of `item` now, pinned or not
+pub(crate) fn remove(item: &OfflineItem) -> anyhow::Result<()> {
+    let key = library::with_library(|lib| {
+        let key = key(lib, item)?;
+        cancel(&key);
+        if let Some(copy) = lib.get_offline_copy(&key)? {
+            discard(lib, &copy)?;
+        }
+        Ok(key)
+    })?;
+    // Also drops a partial download, which is kept for resuming otherwise.
+    if let Some(episode_id) = episode_id(&key) {
+        downloads::delete(episode_id)?;
+    }
+    emit(item, DownloadState::NotDownloaded);
+    Ok(())
+}
+
+/// Delete the copies of songs from remote source `id`, which is being removed
+pub(crate) fn remove_source(id: i64) -> anyhow::Result<()> {
+    let prefix = sources::uri(id, "");
+    library::with_library(|lib| {
+        for copy in lib.get_offline_copies()? {
+            if copy.item.starts_with(&prefix) {
+                cancel(&copy.item);
+                discard(lib, &copy)?;
+            }
+        }
+        Ok(())
+    })
+}
+
+fn cancel(key: &str) {
+    if let Some(download) = DOWNLOADS.lock().unwrap().as_ref().and_then(|d| d.get(key)) {
+        jobs::cancel(download.job_id);
+    }
+}
+
+/// Allow offline copies `max_bytes` in all, evicting unpinned ones now if they take
+/// more; pinned copies are never evicted, so new pins fail once they alone fill it
+pub(crate) fn set_quota(max_bytes: u64) -> anyhow::Result<()> {
+    QUOTA_BYTES.store(max_bytes, Ordering::Relaxed);
+    if library::is_open() {
+        library::with_library(evict)?;
+    }
+    Ok(())
+}
+
+/// Delete unpinned copies, least recently used first, until all fit the quota
+fn evict(lib: &mut Library) -> anyhow::Result<()> {
+    let quota = QUOTA_BYTES.load(Ordering::Relaxed);
+    let copies = lib.get_offline_copies()?;
+    let mut total: u64 = copies.iter().map(|copy| copy.size).sum();
+    for copy in copies.iter().filter(|copy| !copy.pinned) {
+        if total <= quota {
+            break;
+        }
+        discard(lib, copy)?;
+        total -= copy.size;
+        log::info!("evicted offline copy of {}", copy.item);
+        emit_key(lib, &copy.item, DownloadState::NotDownloaded)?;
+    }
+    Ok(())
+}
+
+/// Offline copy to read instead of the remote file at `path`, if there's an intact one
+pub(crate) fn local_copy(path: &Path) -> Option<PathBuf> {
+    if !sources::is_remote(path) || !library::is_open() {
+        return None;
+    }
+    let key = path.to_str()?;
+    let copy = library::with_library(|lib| {
+        let Some(copy) = lib.get_offline_copy(key)? else {
+            return Ok(None);
+        };
+        if !is_intact(&copy) {
+            log::warn!("offline copy of {key} is gone or truncated; streaming instead");
+            discard(lib, &copy)?;
+            emit_key(lib, key, DownloadState::NotDownloaded)?;
+            return Ok(None);
+        }
+        lib.touch_offline_copy(key)?;
+        Ok(Some(PathBuf::from(copy.local_path)))
+    });
+    copy.unwrap_or_else(|e| {
+        log::warn!("couldn't look up the offline copy of {key}: {e:#}");
+        None
+    })
+}
+
+/// Body of a download job: fetch, check the quota, record
+fn run(item: &OfflineItem, key: &str, job: &Job) -> anyhow::Result<()> {
+    let result = download(item, key, job);
+    if let Some(downloads) = DOWNLOADS.lock().unwrap().as_mut() {
+        downloads.remove(key);
+    }
+    match result {
+        Ok(Some(size_bytes)) => {
+            emit(
+                item,
+                DownloadState::Downloaded {
+                    pinned: true,
+                    size_bytes,
+                },
+            );
+            library::with_library(evict)
+        }
+        Ok(None) => {
+            emit(item, DownloadState::NotDownloaded);
+            Ok(())
+        }
+        Err(e) => {
+            log::warn!("offline download of {key} failed: {e:#}");
+            let message = format!("{e:#}");
+            emit(item, DownloadState::Failed { message });
+            Err(e)
+        }
+    }
+}
+
+/// Download `item` and record the pinned copy, returning its size; `None` if cancelled
+fn download(item: &OfflineItem, key: &str, job: &Job) -> anyhow::Result<Option<u64>> {
+    let mut reported = 0;
+    let mut progress = |downloaded_bytes: u64, total_bytes: Option<u64>| {
+        if downloaded_bytes != 0 && downloaded_bytes - reported < PROGRESS_EVERY {
+            return;
+        }
+        reported = downloaded_bytes;
+        let state = DownloadState::Downloading {
+            downloaded_bytes,
+            total_bytes,
+        };
+        if let Some(download) = DOWNLOADS
+            .lock()
+            .unwrap()
+            .as_mut()
+            .and_then(|d| d.get_mut(key))
+        {
+            download.state = state.clone();
+        }
+        emit(item, state);
+    };
+    let downloaded = match episode_id(key) {
+        Some(episode_id) => downloads::download(episode_id, job, &mut progress)?
+            .map(|path| {
+                let md5 = hash_file(Path::new(&path))?;
+                anyhow::Ok((PathBuf::from(path), md5))
+            })
+            .transpose()?,
+        None => download_file(key, job, &mut progress)?,
+    };
+    let Some((local_path, md5)) = downloaded else {
+        return Ok(None);
+    };
+
+    let copy = OfflineCopy {
+        item: key.to_string(),
+        size: fs::metadata(&local_path)?.len(),
+        local_path: local_path.to_string_lossy().into_owned(),
+        md5,
+        pinned: true,
+        used_at: now_secs(),
+    };
+    library::with_library(|lib| {
+        let pinned: u64 = lib
+            .get_offline_copies()?
+            .iter()
+            .filter(|other| other.pinned && other.item != key)
+            .map(|other| other.size)
+            .sum();
+        let quota = QUOTA_BYTES.load(Ordering::Relaxed);
+        if pinned + copy.size > quota {
+            discard(lib, &copy)?;
+            anyhow::bail!(
+                "not enough offline storage: pinned items would take {} of the {quota} bytes allowed",
+                pinned + copy.size
+            );
+        }
+        lib.put_offline_copy(&copy)
+    })?;
+    Ok(Some(copy.size))
+}
+
+/// Copy the remote file at `uri` into the offline folder, returning where it went and
+/// its digest; `None` if cancelled
+fn download_file(
+    uri: &str,
+    job: &Job,
+    mut progress: impl FnMut(u64, Option<u64>),
+) -> anyhow::Result<Option<(PathBuf, String)>> {
+    let dir = library::cache_dir(StorageKind::Offline)
+        .context("library database is not open; call open_library first")?;
+    fs::create_dir_all(&dir).with_context(|| format!("failed to create {}", dir.display()))?;
+    let mut name = format!("{:x}", Md5::digest(uri.as_bytes()));
+    if let Some(extension) = Path::new(uri).extension() {
+        name.push('.');
+        name.push_str(&extension.to_string_lossy());
+    }
+    let path = dir.join(name);
+    let partial = transcode::partial_path(&path);
+
+    let mut remote = RemoteFile::open_for_download(Path::new(uri))?;
+    let total = remote.byte_len();
+    let mut copy = || -> anyhow::Result<Option<String>> {
+        let mut file = File::create(&partial)
+            .with_context(|| format!("failed to create {}", partial.display()))?;
+        let mut hasher = Md5::new();
+        let mut buf = vec![0; 64 * 1024];
+        let mut downloaded = 0;
+        progress(0, total);
+        loop {
+            if job.is_cancelled() {
+                return Ok(None);
+            }
+            let n = remote.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            file.write_all(&buf[..n])
+                .with_context(|| format!("failed to write {}", partial.display()))?;
+            downloaded += n as u64;
+            job.progress(downloaded, total);
+            progress(downloaded, total);
+        }
+        file.sync_all()?;
+        Ok(Some(format!("{:x}", hasher.finalize())))
+    };
+    match copy() {
+        Ok(Some(md5)) => {
+            fs::rename(&partial, &path)
+                .with_context(|| format!("failed to move download to {}", path.display()))?;
+            Ok(Some((path, md5)))
+        }
+        result => {
+            let _ = fs::remove_file(&partial);
+            result.map(|_| None)
+        }
+    }
+}
+
+fn hash_file(path: &Path) -> anyhow::Result<String> {
+    let mut file =
+        File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let mut hasher = Md5::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Queue checking every offline copy against its digest as a background job, returning
+/// its id for `jobs::cancel`
+///
+/// Damaged or missing copies are deleted; pinned ones are downloaded again.
+pub(crate) fn verify() -> u32 {
+    jobs::submit(JobKind::Verify, |job| {
+        let copies = library::with_library(|lib| lib.get_offline_copies())?;
+        let total = copies.len() as u64;
+        let mut damaged = 0;
+        for (done, copy) in copies.into_iter().enumerate() {
+            if job.is_cancelled() {
+                break;
+            }
+            job.progress(done as u64, Some(total));
+            let intact = match hash_file(Path::new(&copy.local_path)) {
+                Ok(md5) => md5 == copy.md5,
+                Err(e) => {
+                    log::debug!("{e:#}");
+                    false
+                }
+            };
+            if intact {
+                continue;
+            }
+            damaged += 1;
+            log::warn!("offline copy of {} is damaged; deleting it", copy.item);
+            let items = library::with_library(|lib| {
+                discard(lib, &copy)?;
+                let items = items(lib, &copy.item)?;
+                for item in &items {
+                    emit(item, DownloadState::NotDownloaded);
+                }
+                Ok(items)
+            })?;
+            if copy.pinned {
+                for item in items.into_iter().take(1) {
+                    pin(item)?;
+                }
+            }
+        }
+        log::info!("checked {total} offline copies, {damaged} damaged");
+        Ok(())
+    })
+}
@@ -0,0 +1,530 @@
+use std::ffi::{c_char, c_int, CStr};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::ptr::NonNull;
+
+use symphonia::core::audio::{AsAudioBufferRef, AudioBuffer, AudioBufferRef, Signal, SignalSpec};
+use symphonia::core::codecs::{
+    CodecDescriptor, CodecParameters, Decoder, DecoderOptions, FinalizeResult, CODEC_TYPE_OPUS,
+};
+use symphonia::core::errors::{decode_error, unsupported_error, Result as SymphoniaResult};
+use symphonia::core::formats::Packet;
+use symphonia::core::support_codec;
+
+use crate::TranscodeOptions;
+
+/// Rate Opus is encoded and decoded at, which other rates are resampled to
+pub(crate) const SAMPLE_RATE: u32 = 48_000;
+
+/// Frames of the longest packet, 120 ms
+const MAX_PACKET_FRAMES: usize = 5760;
+
+/// Frames of each packet, 20 ms
+const PACKET_FRAMES: usize = 960;
+
+/// Largest packet libopus is asked for, as its documentation recommends
+const MAX_PACKET: usize = 4000;
+
+const DEFAULT_KBPS_PER_CHANNEL: u32 = 64;
+const MIN_KBPS: u32 = 6;
+const MAX_KBPS: u32 = 510;
+
+/// Packets put on a page at most, a second's worth
+const PAGE_PACKETS: usize = 50;
+
+/// Lacing values an Ogg page holds at most
+const PAGE_SEGMENTS: usize = 255;
+
+const VENDOR: &str = concat!("tunes4r ", env!("CARGO_PKG_VERSION"));
+
+// From libopus's opus_defines.h
+const OPUS_APPLICATION_AUDIO: c_int = 2049;
+const OPUS_SET_BITRATE_REQUEST: c_int = 4002;
+const OPUS_SET_COMPLEXITY_REQUEST: c_int = 4010;
+const OPUS_GET_LOOKAHEAD_REQUEST: c_int = 4027;
+const OPUS_RESET_STATE: c_int = 4028;
+const OPUS_SET_GAIN_REQUEST: c_int = 4034;
+
+#[repr(C)]
+struct OpusEncoder {
+    _private: [u8; 0],
+}
+
+#[repr(C)]
+struct OpusMSDecoder {
+    _private: [u8; 0],
+}
+
+#[link(name = "opus")]
+extern "C" {
+    fn opus_encoder_create(
+        rate: i32,
+        channels: c_int,
+        application: c_int,
+        error: *mut c_int,
+    ) -> *mut OpusEncoder;
+    fn opus_encode_float(
+        encoder: *mut OpusEncoder,
+        pcm: *const f32,
+        frames: c_int,
+        data: *mut u8,
+        max_bytes: i32,
+    ) -> i32;
+    fn opus_encoder_ctl(encoder: *mut OpusEncoder, request: c_int, ...) -> c_int;
+    fn opus_encoder_destroy(encoder: *mut OpusEncoder);
+    fn opus_multistream_decoder_create(
+        rate: i32,
+        channels: c_int,
+        streams: c_int,
+        coupled_streams: c_int,
+        mapping: *const u8,
+        error: *mut c_int,
+    ) -> *mut OpusMSDecoder;
+    fn opus_multistream_decode_float(
+        decoder: *mut OpusMSDecoder,
+        data: *const u8,
+        len: i32,
+        pcm: *mut f32,
+        frames: c_int,
+        decode_fec: c_int,
+    ) -> c_int;
+    fn opus_multistream_decoder_ctl(decoder: *mut OpusMSDecoder, request: c_int, ...) -> c_int;
+    fn opus_multistream_decoder_destroy(decoder: *mut OpusMSDecoder);
+    fn opus_strerror(error: c_int) -> *const c_char;
+}
+
+/// `code` if libopus succeeded, or its description of the error
+fn check(code: c_int) -> anyhow::Result<c_int> {
+    if code >= 0 {
+        return Ok(code);
+    }
+    // SAFETY: opus_strerror returns a static string for any code.
+    let message = unsafe { CStr::from_ptr(opus_strerror(code)) };
+    anyhow::bail!("Opus encoder failed: {}", message.to_string_lossy())
+}
+
+/// An owned libopus encoder
+struct Encoder(NonNull<OpusEncoder>);
+
+// SAFETY: an encoder's state is only touched through `&mut`, from whichever thread.
+unsafe impl Send for Encoder {}
+
+impl Encoder {
+    fn new(channels: usize) -> anyhow::Result<Self> {
+        let mut error = 0;
+        // SAFETY: the arguments are in range, and `error` outlives the call.
+        let encoder = unsafe {
+            opus_encoder_create(
+                SAMPLE_RATE as i32,
+                channels as c_int,
+                OPUS_APPLICATION_AUDIO,
+                &mut error,
+            )
+        };
+        check(error)?;
+        let encoder = NonNull::new(encoder).ok_or_else(|| anyhow::anyhow!("out of memory"))?;
+        Ok(Encoder(encoder))
+    }
+
+    fn set(&mut self, request: c_int, value: i32) -> anyhow::Result<()> {
+        // SAFETY: `request` is a setter taking one opus_int32.
+        check(unsafe { opus_encoder_ctl(self.0.as_ptr(), request, value) })?;
+        Ok(())
+    }
+
+    /// Frames the encoder delays its input by
+    fn lookahead(&mut self) -> anyhow::Result<u32> {
+        let mut frames: i32 = 0;
+        // SAFETY: the lookahead getter writes one opus_int32 through the pointer.
+        check(unsafe {
+            opus_encoder_ctl(
+                self.0.as_ptr(),
+                OPUS_GET_LOOKAHEAD_REQUEST,
+                &mut frames as *mut i32,
+            )
+        })?;
+        Ok(frames as u32)
+    }
+
+    /// Encode one packet's interleaved `samples` into `out`
+    fn encode(&mut self, samples: &[f32], frames: usize, out: &mut Vec<u8>) -> anyhow::Result<()> {
+        out.resize(MAX_PACKET, 0);
+        // SAFETY: `samples` holds `frames` frames of the encoder's channels, and `out`
+        // is as long as promised.
+        let len = check(unsafe {
+            opus_encode_float(
+                self.0.as_ptr(),
+                samples.as_ptr(),
+                frames as c_int,
+                out.as_mut_ptr(),
+                MAX_PACKET as i32,
+            )
+        })?;
+        out.truncate(len as usize);
+        Ok(())
+    }
+}
+
+impl Drop for Encoder {
+    fn drop(&mut self) {
+        // SAFETY: the encoder came from opus_encoder_create and isn't used after this.
+        unsafe { opus_encoder_destroy(self.0.as_ptr()) }
+    }
+}
+
+/// Opus through libopus, at a variable bitrate, in an Ogg file
+pub(crate) struct OpusWriter {
+    encoder: Encoder,
+    ogg: OggWriter,
+    channels: usize,
+    /// Frames the decoder drops from the start, the encoder's delay
+    pre_skip: u64,
+    /// Input not yet making up a whole packet
+    pending: Vec<f32>,
+    packet: Vec<u8>,
+    frames_in: u64,
+    frames_encoded: u64,
+}
+
+impl OpusWriter {
+    pub fn create(
+        path: &Path,
+        channels: u16,
+        sample_rate: u32,
+        options: &TranscodeOptions,
+    ) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            (1..=2).contains(&channels),
+            "Opus is written with one or two channels, not {channels}"
+        );
+        anyhow::ensure!(
+            sample_rate == SAMPLE_RATE,
+            "Opus is written at {SAMPLE_RATE} Hz, not {sample_rate} Hz"
+        );
+        let channels = channels as usize;
+        let mut encoder = Encoder::new(channels)?;
+        let kbps = options
+            .bitrate_kbps
+            .unwrap_or(DEFAULT_KBPS_PER_CHANNEL * channels as u32)
+            .clamp(MIN_KBPS, MAX_KBPS);
+        encoder.set(OPUS_SET_BITRATE_REQUEST, kbps as i32 * 1000)?;
+        encoder.set(OPUS_SET_COMPLEXITY_REQUEST, 10)?;
+        let pre_skip = encoder.lookahead()?;
+
+        let mut ogg = OggWriter::create(path)?;
+        let mut head = b"OpusHead".to_vec();
+        head.push(1);
+        head.push(channels as u8);
+        head.extend_from_slice(&(pre_skip as u16).to_le_bytes());
+        head.extend_from_slice(&sample_rate.to_le_bytes());
+        // No output gain, and mono or stereo without a mapping table
+        head.extend_from_slice(&[0, 0, 0]);
+        ogg.write_page(&[head], 0, BEGINNING)?;
+        let mut tags = b"OpusTags".to_vec();
+        tags.extend_from_slice(&(VENDOR.len() as u32).to_le_bytes());
+        tags.extend_from_slice(VENDOR.as_bytes());
+        tags.extend_from_slice(&0u32.to_le_bytes());
+        ogg.write_page(&[tags], 0, 0)?;
+
+        Ok(OpusWriter {
+            encoder,
+            ogg,
+            channels,
+            pre_skip: pre_skip as u64,
+            pending: Vec::with_capacity(PACKET_FRAMES * channels),
+            packet: Vec::with_capacity(MAX_PACKET),
+            frames_in: 0,
+            frames_encoded: 0,
+        })
+    }
+
+    /// Encode interleaved samples
+    pub fn write(&mut self, samples: &[f32]) -> anyhow::Result<()> {
+        self.frames_in += (samples.len() / self.channels) as u64;
+        let packet_len = PACKET_FRAMES * self.channels;
+        for chunk in samples.chunks(packet_len) {
+            let taken = (packet_len - self.pending.len()).min(chunk.len());
+            self.pending.extend_from_slice(&chunk[..taken]);
+            if self.pending.len() == packet_len {
+                self.encode_pending()?;
+            }
+            self.pending.extend_from_slice(&chunk[taken..]);
+        }
+        Ok(())
+    }
+
+    /// Encode what's left, flushing out the encoder's delay, and end the stream
+    pub fn finish(mut self) -> anyhow::Result<()> {
+        let end = self.pre_skip + self.frames_in;
+        while self.frames_encoded < end {
+            self.pending.resize(PACKET_FRAMES * self.channels, 0.0);
+            self.encode_pending()?;
+        }
+        self.ogg.finish(end)
+    }
+
+    fn encode_pending(&mut self) -> anyhow::Result<()> {
+        self.encoder
+            .encode(&self.pending, PACKET_FRAMES, &mut self.packet)?;
+        self.pending.clear();
+        self.frames_encoded += PACKET_FRAMES as u64;
+        self.ogg.write_packet(&self.packet, self.frames_encoded)?;
+        Ok(())
+    }
+}
+
+/// Opus through libopus, for symphonia to decode Ogg Opus files with
+///
+/// symphonia's Ogg reader passes `OpusHead` as the extra data and trims the pre-skip
+/// and end padding through each packet's trim.
+pub(crate) struct OpusDecoder {
+    decoder: NonNull<OpusMSDecoder>,
+    params: CodecParameters,
+    /// Plane of each channel libopus decodes, which come in Vorbis order
+    planes: Vec<usize>,
+    pcm: Vec<f32>,
+    buffer: AudioBuffer<f32>,
+}
+
+// SAFETY: the decoder's state is only touched through `&mut self`.
+unsafe impl Send for OpusDecoder {}
+unsafe impl Sync for OpusDecoder {}
+
+impl OpusDecoder {
+    fn decode_packet(&mut self, packet: &Packet) -> SymphoniaResult<()> {
+        let channels = self.planes.len();
+        self.pcm.resize(MAX_PACKET_FRAMES * channels, 0.0);
+        let data = packet.buf();
+        // SAFETY: `pcm` holds `MAX_PACKET_FRAMES` frames of every channel.
+        let frames = unsafe {
+            opus_multistream_decode_float(
+                self.decoder.as_ptr(),
+                data.as_ptr(),
+                data.len() as i32,
+                self.pcm.as_mut_ptr(),
+                MAX_PACKET_FRAMES as c_int,
+                0,
+            )
+        };
+        if frames < 0 {
+            return decode_error("opus: invalid packet");
+        }
+        self.buffer.clear();
+        self.buffer.render_reserved(Some(frames as usize));
+        for (channel, &plane) in self.planes.iter().enumerate() {
+            let samples = self.pcm.iter().skip(channel).step_by(channels);
+            for (out, &sample) in self.buffer.chan_mut(plane).iter_mut().zip(samples) {
+                *out = sample;
+            }
+        }
+        self.buffer
+            .trim(packet.trim_start() as usize, packet.trim_end() as usize);
+        Ok(())
+    }
+}
+
+impl Decoder for OpusDecoder {
+    fn try_new(params: &CodecParameters, _: &DecoderOptions) -> SymphoniaResult<Self> {
+        if params.codec != CODEC_TYPE_OPUS {
+            return unsupported_error("opus: invalid codec type");
+        }
+        let Some(head) = params.extra_data.as_deref().filter(|head| head.len() >= 19) else {
+            return unsupported_error("opus: missing OpusHead");
+        };
+        let (Some(layout), Some(planes)) = (params.channels, vorbis_planes(head[9])) else {
+            return unsupported_error("opus: unsupported channel layout");
+        };
+        let channels = planes.len();
+        let gain = i16::from_le_bytes([head[16], head[17]]);
+        // Family 0 is one stream, coupled if stereo; others carry a table.
+        let (streams, coupled, mapping) = match head[18] {
+            0 if channels <= 2 => (1, channels - 1, [0, 1][..channels].to_vec()),
+            0 => return unsupported_error("opus: too many channels for one stream"),
+            _ if head.len() >= 21 + channels => {
+                let table = head[21..21 + channels].to_vec();
+                (head[19] as usize, head[20] as usize, table)
+            }
+            _ => return unsupported_error("opus: truncated channel mapping"),
+        };
+
+        let mut error = 0;
+        // SAFETY: `mapping` holds an entry for every channel, and `error` outlives the call.
+        let decoder = unsafe {
+            opus_multistream_decoder_create(
+                SAMPLE_RATE as i32,
+                channels as c_int,
+                streams as c_int,
+                coupled as c_int,
+                mapping.as_ptr(),
+                &mut error,
+            )
+        };
+        let Some(decoder) = NonNull::new(decoder).filter(|_| error >= 0) else {
+            return unsupported_error("opus: libopus won't decode this stream");
+        };
+        // SAFETY: the gain setter takes one opus_int32.
+        unsafe {
+            opus_multistream_decoder_ctl(decoder.as_ptr(), OPUS_SET_GAIN_REQUEST, gain as i32)
+        };
+        let spec = SignalSpec::new(SAMPLE_RATE, layout);
+        Ok(OpusDecoder {
+            decoder,
+            params: params.clone(),
+            planes,
+            pcm: Vec::new(),
+            buffer: AudioBuffer::new(MAX_PACKET_FRAMES as u64, spec),
+        })
+    }
+
+    fn supported_codecs() -> &'static [CodecDescriptor] {
+        &[support_codec!(CODEC_TYPE_OPUS, "opus", "Opus")]
+    }
+
+    fn reset(&mut self) {
+        // SAFETY: resetting takes no argument.
+        unsafe { opus_multistream_decoder_ctl(self.decoder.as_ptr(), OPUS_RESET_STATE) };
+    }
+
+    fn codec_params(&self) -> &CodecParameters {
+        &self.params
+    }
+
+    fn decode(&mut self, packet: &Packet) -> SymphoniaResult<AudioBufferRef<'_>> {
+        if let Err(e) = self.decode_packet(packet) {
+            self.buffer.clear();
+            return Err(e);
+        }
+        Ok(self.buffer.as_audio_buffer_ref())
+    }
+
+    fn finalize(&mut self) -> FinalizeResult {
+        FinalizeResult::default()
+    }
+
+    fn last_decoded(&self) -> AudioBufferRef<'_> {
+        self.buffer.as_audio_buffer_ref()
+    }
+}
+
+impl Drop for OpusDecoder {
+    fn drop(&mut self) {
+        // SAFETY: the decoder came from opus_multistream_decoder_create and isn't used
+        // after this.
+        unsafe { opus_multistream_decoder_destroy(self.decoder.as_ptr()) }
+    }
+}
+
+/// Plane of each of `channels` channels in Vorbis order, in the order of symphonia's
+/// channel bits the Ogg reader lays them out in
+fn vorbis_planes(channels: u8) -> Option<Vec<usize>> {
+    let planes: &[usize] = match channels {
+        1 => &[0],
+        2 => &[0, 1],
+        // Left, centre, right
+        3 => &[0, 2, 1],
+        4 => &[0, 1, 2, 3],
+        5 => &[0, 2, 1, 3, 4],
+        // ..., rear left and right, LFE
+        6 => &[0, 2, 1, 4, 5, 3],
+        // ..., side left and right, rear centre, LFE
+        7 => &[0, 2, 1, 5, 6, 4, 3],
+        // ..., side left and right, rear left and right, LFE
+        8 => &[0, 2, 1, 6, 7, 4, 5, 3],
+        _ => return None,
+    };
+    Some(planes.to_vec())
+}
+
+/// Page flags
+const BEGINNING: u8 = 0x02;
+const END: u8 = 0x04;
+
+/// An Ogg file of one logical stream, written a page at a time
+struct OggWriter {
+    file: BufWriter<File>,
+    serial: u32,
+    sequence: u32,
+    /// Packets of the next page, with the granule position after the last of them
+    packets: Vec<Vec<u8>>,
+    granule: u64,
+}
+
+impl OggWriter {
+    fn create(path: &Path) -> anyhow::Result<Self> {
+        Ok(OggWriter {
+            file: BufWriter::new(File::create(path)?),
+            serial: rand::random(),
+            sequence: 0,
+            packets: Vec::new(),
+            granule: 0,
+        })
+    }
+
+    /// Queue `packet`, after which `granule` frames are decoded; the page before it is
+    /// written out once it's full
+    ///
+    /// The last page is only written by `finish`, which marks it as the end.
+    fn write_packet(&mut self, packet: &[u8], granule: u64) -> anyhow::Result<()> {
+        let segments: usize = self.packets.iter().map(|p| lacing(p)).sum();
+        if self.packets.len() == PAGE_PACKETS || segments + lacing(packet) > PAGE_SEGMENTS {
+            let packets = std::mem::take(&mut self.packets);
+            self.write_page(&packets, self.granule, 0)?;
+        }
+        self.packets.push(packet.to_vec());
+        self.granule = granule;
+        Ok(())
+    }
+
+    /// Write the last page, which ends `end` frames in
+    fn finish(mut self, end: u64) -> anyhow::Result<()> {
+        let packets = std::mem::take(&mut self.packets);
+        self.write_page(&packets, end, END)?;
+        self.file.flush()?;
+        Ok(())
+    }
+
+    fn write_page(&mut self, packets: &[Vec<u8>], granule: u64, flags: u8) -> anyhow::Result<()> {
+        let mut page = b"OggS".to_vec();
+        page.push(0);
+        page.push(flags);
+        page.extend_from_slice(&granule.to_le_bytes());
+        page.extend_from_slice(&self.serial.to_le_bytes());
+        page.extend_from_slice(&self.sequence.to_le_bytes());
+        // The checksum, filled in once the page is complete
+        page.extend_from_slice(&[0; 4]);
+        let segments: usize = packets.iter().map(|p| lacing(p)).sum();
+        page.push(segments as u8);
+        for packet in packets {
+            page.extend(std::iter::repeat_n(255, packet.len() / 255));
+            page.push((packet.len() % 255) as u8);
+        }
+        for packet in packets {
+            page.extend_from_slice(packet);
+        }
+        let crc = crc32(&page);
+        page[22..26].copy_from_slice(&crc.to_le_bytes());
+        self.file.write_all(&page)?;
+        self.sequence += 1;
+        Ok(())
+    }
+}
+
+/// Lacing values `packet` takes in a page's segment table
+fn lacing(packet: &[u8]) -> usize {
+    packet.len() / 255 + 1
+}
+
+/// Ogg's CRC-32: polynomial 0x04c11db7, not reflected, starting from zero
+fn crc32(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |mut crc: u32, &byte| {
+        crc ^= (byte as u32) << 24;
+        for _ in 0..8 {
+            crc = match crc & 0x8000_0000 {
+                0 => crc << 1,
+                _ => (crc << 1) ^ 0x04c1_1db7,
+            };
+        }
+        crc
+    })
+}
@@ -0,0 +1,196 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+
+use anyhow::{ensure, Context};
+
+use super::agent;
+use crate::jobs::{self, Job};
+use crate::library::{self, Library};
+use crate::{
+    http_stream, scanner, DownloadEvent, JobKind, PodcastEpisode, StorageKind, StreamSink,
+};
+
+/// Emit a `Progress` event every this many bytes
+const PROGRESS_EVERY: u64 = 256 * 1024;
+
+/// Directory holding the downloaded episodes of podcast `podcast_id`
+pub(super) fn podcast_dir(lib: &Library, podcast_id: i64) -> PathBuf {
+    lib.cache_dir(StorageKind::Podcasts)
+        .join(podcast_id.to_string())
+}
+
+/// Where `episode` is saved, keeping the extension of its URL when it is a known one
+fn episode_path(dir: &Path, episode: &PodcastEpisode) -> PathBuf {
+    let extension = http_stream::url_extension(&episode.audio_url)
+        .map(str::to_ascii_lowercase)
+        .filter(|e| scanner::SUPPORTED_EXTENSIONS.contains(&e.as_str()))
+        .unwrap_or_else(|| "mp3".to_string());
+    dir.join(format!("{}.{extension}", episode.id))
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// The `.part` file a download is written to until it completes
+fn partial_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".part");
+    path.with_file_name(name)
+}
+
+/// Total size from a `Content-Range: bytes start-end/total` header
+fn content_range_total(response: &ureq::Response) -> Option<u64> {
+    response
+        .header("Content-Range")?
+        .rsplit_once('/')?
+        .1
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Queue an episode download as a background job, returning its id for `jobs::cancel`
+pub(crate) fn start(episode_id: i64, sink: StreamSink<DownloadEvent>) -> u32 {
+    jobs::submit(JobKind::Download, move |job| {
+        let progress = |downloaded_bytes, total_bytes| {
+            let event = DownloadEvent::Progress {
+                downloaded_bytes,
+                total_bytes,
+            };
+            // A closed sink means nobody is listening any more, which is as good as a cancel.
+            if sink.add(event).is_err() {
+                job.cancelled().store(true, Ordering::Relaxed);
+            }
+        };
+        let (event, result) = match download(episode_id, job, progress) {
+            Ok(Some(path)) => (DownloadEvent::Finished { path }, Ok(())),
+            Ok(None) => (DownloadEvent::Cancelled, Ok(())),
+            Err(e) => {
+                log::warn!("download of episode {episode_id} failed: {e:#}");
+                let message = format!("{e:#}");
+                (DownloadEvent::Failed { message }, Err(e))
+            }
+        };
+        let _ = sink.add(event);
+        result
+    })
+}
+
+/// Delete an episode's download, finished or partial
+pub(crate) fn delete(episode_id: i64) -> anyhow::Result<()> {
+    library::with_library(|lib| {
+        let episode = lib
+            .get_podcast_episode(episode_id)?
+            .with_context(|| format!("no podcast episode with id {episode_id}"))?;
+        let path = episode_path(&podcast_dir(lib, episode.podcast_id), &episode);
+        remove_if_exists(&partial_path(&path))?;
+        if let Some(download) = &episode.download_path {
+            remove_if_exists(Path::new(download))?;
+        }
+        lib.set_episode_download(episode_id, None)
+    })
+}
+
+/// Download into the `.part` file, resuming where an earlier attempt left off, and
+/// move it into place once complete; `None` if cancelled
+///
+/// `progress` is called with the bytes downloaded and the expected total every so often.
+pub(crate) fn download(
+    episode_id: i64,
+    job: &Job,
+    mut progress: impl FnMut(u64, Option<u64>),
+) -> anyhow::Result<Option<String>> {
+    let (episode, dir) = library::with_library(|lib| {
+        let episode = lib
+            .get_podcast_episode(episode_id)?
+            .with_context(|| format!("no podcast episode with id {episode_id}"))?;
+        let dir = podcast_dir(lib, episode.podcast_id);
+        Ok((episode, dir))
+    })?;
+    if let Some(path) = episode
+        .download_path
+        .as_ref()
+        .filter(|p| Path::new(p).is_file())
+    {
+        return Ok(Some(path.clone()));
+    }
+    fs::create_dir_all(&dir).with_context(|| format!("failed to create {}", dir.display()))?;
+    let path = episode_path(&dir, &episode);
+    let partial = partial_path(&path);
+
+    let agent = agent();
+    let resume_from = fs::metadata(&partial).map_or(0, |m| m.len());
+    let request = agent.get(&episode.audio_url);
+    let response = match resume_from {
+        0 => request.call(),
+        _ => match request
+            .set("Range", &format!("bytes={resume_from}-"))
+            .call()
+        {
+            // The partial file doesn't fit what the server has any more.
+            Err(ureq::Error::Status(416, _)) => {
+                remove_if_exists(&partial)?;
+                agent.get(&episode.audio_url).call()
+            }
+            result => result,
+        },
+    }
+    .with_context(|| format!("failed to fetch {}", episode.audio_url))?;
+
+    // Servers that ignore the range send everything again with a 200.
+    let (mut file, mut downloaded, total) = if response.status() == 206 {
+        let file = OpenOptions::new().append(true).open(&partial)?;
+        (file, resume_from, content_range_total(&response))
+    } else {
+        let total = response
+            .header("Content-Length")
+            .and_then(|length| length.trim().parse().ok());
+        (File::create(&partial)?, 0, total)
+    };
+    // Feeds often announce placeholder sizes, so theirs only serves for progress.
+    let expected = total.or(episode.file_size);
+
+    let mut reader = response.into_reader();
+    let mut buf = vec![0; 64 * 1024];
+    let mut reported = downloaded;
+    progress(downloaded, expected);
+    loop {
+        if job.is_cancelled() {
+            return Ok(None);
+        }
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e).context("download interrupted; it resumes when retried"),
+        };
+        file.write_all(&buf[..n])
+            .with_context(|| format!("failed to write {}", partial.display()))?;
+        downloaded += n as u64;
+        if downloaded - reported >= PROGRESS_EVERY {
+            reported = downloaded;
+            job.progress(downloaded, expected);
+            progress(downloaded, expected);
+        }
+    }
+    file.sync_all()?;
+    drop(file);
+    if let Some(total) = total {
+        ensure!(
+            downloaded >= total,
+            "connection closed after {downloaded} of {total} bytes; it resumes when retried"
+        );
+    }
+
+    fs::rename(&partial, &path)
+        .with_context(|| format!("failed to move download to {}", path.display()))?;
+    let path = path.to_string_lossy().into_owned();
+    library::with_library(|lib| lib.set_episode_download(episode_id, Some(&path)))?;
+    Ok(Some(path))
+}
@@ -0,0 +1,161 @@
+pub(crate) mod downloads;
+mod show_notes;
+
+use std::fs;
+use std::io::{BufReader, Read};
+use std::time::Duration;
+
+use anyhow::Context;
+use rss::Channel;
+
+use crate::{library, Podcast};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Feeds larger than this are cut off rather than parsed
+const MAX_FEED_BYTES: u64 = 32 * 1024 * 1024;
+
+/// A podcast feed as fetched, before it is stored in the library
+pub(crate) struct Feed {
+    pub title: String,
+    pub author: Option<String>,
+    pub description: String,
+    pub image_url: Option<String>,
+    pub episodes: Vec<FeedEpisode>,
+}
+
+pub(crate) struct FeedEpisode {
+    /// The item's GUID, or its audio URL if the feed gives none
+    pub guid: String,
+    pub title: String,
+    pub show_notes: String,
+    pub audio_url: String,
+    pub published_at: Option<i64>,
+    pub duration_secs: Option<u64>,
+    pub file_size: Option<u64>,
+}
+
+fn agent() -> ureq::Agent {
+    ureq::AgentBuilder::new()
+        .timeout_connect(CONNECT_TIMEOUT)
+        .timeout_read(READ_TIMEOUT)
+        .build()
+}
+
+/// Seconds since the Unix epoch of an RFC 2822 date, or RFC 3339 as some feeds use
+fn parse_date(text: &str) -> Option<i64> {
+    let text = text.trim();
+    chrono::DateTime::parse_from_rfc2822(text)
+        .or_else(|_| chrono::DateTime::parse_from_rfc3339(text))
+        .ok()
+        .map(|date| date.timestamp())
+}
+
+/// `itunes:duration` as seconds, which may be `SS`, `MM:SS` or `HH:MM:SS`
+fn parse_duration(text: &str) -> Option<u64> {
+    text.trim().split(':').try_fold(0, |total, part| {
+        let value: f64 = part.trim().parse().ok()?;
+        Some(total * 60 + value as u64)
+    })
+}
+
+/// Feed contents of `channel`; items without an audio enclosure are skipped
+fn parse(channel: &Channel) -> Feed {
+    let itunes = channel.itunes_ext();
+    let episodes = channel
+        .items()
+        .iter()
+        .filter_map(|item| {
+            let enclosure = item.enclosure()?;
+            let audio_url = enclosure.url().trim().to_string();
+            let itunes = item.itunes_ext();
+            let notes = item
+                .content()
+                .or(item.description())
+                .or(itunes.and_then(|i| i.summary()))
+                .unwrap_or_default();
+            Some(FeedEpisode {
+                guid: item
+                    .guid()
+                    .map(|g| g.value().trim().to_string())
+                    .filter(|g| !g.is_empty())
+                    .unwrap_or_else(|| audio_url.clone()),
+                title: item.title().unwrap_or_default().trim().to_string(),
+                show_notes: show_notes::to_plain_text(notes),
+                published_at: item.pub_date().and_then(parse_date),
+                duration_secs: itunes.and_then(|i| i.duration()).and_then(parse_duration),
+                file_size: enclosure
+                    .length()
+                    .trim()
+                    .parse()
+                    .ok()
+                    .filter(|&size| size > 0),
+                audio_url,
+            })
+        })
+        .collect();
+    Feed {
+        title: channel.title().trim().to_string(),
+        author: itunes.and_then(|i| i.author()).map(str::to_string),
+        description: show_notes::to_plain_text(channel.description()),
+        image_url: itunes
+            .and_then(|i| i.image())
+            .or(channel.image().map(|image| image.url()))
+            .map(str::to_string),
+        episodes,
+    }
+}
+
+fn fetch(feed_url: &str) -> anyhow::Result<Feed> {
+    let response = agent()
+        .get(feed_url)
+        .call()
+        .with_context(|| format!("failed to fetch {feed_url}"))?;
+    let reader = BufReader::new(response.into_reader().take(MAX_FEED_BYTES));
+    let channel =
+        Channel::read_from(reader).with_context(|| format!("{feed_url} is not an RSS feed"))?;
+    Ok(parse(&channel))
+}
+
+/// Fetch the feed at `feed_url` and store it with its episodes
+pub(crate) fn subscribe(feed_url: &str) -> anyhow::Result<Podcast> {
+    let feed = fetch(feed_url)?;
+    library::with_library(|lib| {
+        let (id, _) = lib.store_feed(feed_url, &feed)?;
+        lib.get_podcast(id)?
+            .with_context(|| format!("podcast {id} disappeared while subscribing"))
+    })
+}
+
+pub(crate) fn unsubscribe(id: i64) -> anyhow::Result<()> {
+    let dir = library::with_library(|lib| {
+        lib.delete_podcast(id)?;
+        Ok(downloads::podcast_dir(lib, id))
+    })?;
+    if let Err(e) = fs::remove_dir_all(&dir) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            log::warn!("failed to delete downloads in {}: {e}", dir.display());
+        }
+    }
+    Ok(())
+}
+
+/// Re-fetch every subscription, returning the number of new episodes
+///
+/// The library is only locked to store each feed, not while fetching it.
+pub(crate) fn refresh_feeds() -> anyhow::Result<u32> {
+    let podcasts = library::with_library(|lib| lib.get_podcasts())?;
+    let mut new_episodes = 0;
+    for podcast in podcasts {
+        match fetch(&podcast.feed_url) {
+            Ok(feed) => {
+                let (_, new) =
+                    library::with_library(|lib| lib.store_feed(&podcast.feed_url, &feed))?;
+                new_episodes += new;
+            }
+            Err(e) => log::warn!("failed to refresh {}: {e:#}", podcast.feed_url),
+        }
+    }
+    Ok(new_episodes)
+}
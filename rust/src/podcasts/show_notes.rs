@@ -0,0 +1,167 @@
+/// Elements that start and end a paragraph of their own
+const BLOCK_ELEMENTS: &[&str] = &[
+    "p",
+    "div",
+    "ul",
+    "ol",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "blockquote",
+    "pre",
+    "table",
+    "tr",
+    "hr",
+    "section",
+    "article",
+];
+
+/// Elements whose content is never shown
+const HIDDEN_ELEMENTS: &[&str] = &["script", "style", "head", "title"];
+
+/// Builds plain text with HTML whitespace rules: runs of whitespace collapse to one
+/// space, and line breaks only come from markup
+#[derive(Default)]
+struct Writer {
+    out: String,
+    space: bool,
+    breaks: usize,
+}
+
+impl Writer {
+    fn text(&mut self, text: &str) {
+        for c in decode_entities(text).chars() {
+            if c.is_whitespace() {
+                self.space = true;
+            } else {
+                self.flush();
+                self.out.push(c);
+            }
+        }
+    }
+
+    /// Start a new line (`1`) or paragraph (`2`) before the next text
+    fn line_break(&mut self, breaks: usize) {
+        self.breaks = self.breaks.max(breaks);
+    }
+
+    fn flush(&mut self) {
+        if !self.out.is_empty() {
+            if self.breaks > 0 {
+                self.out.push_str(&"\n".repeat(self.breaks));
+            } else if self.space {
+                self.out.push(' ');
+            }
+        }
+        self.space = false;
+        self.breaks = 0;
+    }
+}
+
+/// Decode the named entities show notes commonly use, plus numeric ones
+fn decode_entities(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let decoded = rest[1..]
+            .find(';')
+            .filter(|&end| end <= 10)
+            .and_then(|end| Some((decode_entity(&rest[1..end + 1])?, end + 2)));
+        match decoded {
+            Some((c, len)) => {
+                out.push(c);
+                rest = &rest[len..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+fn decode_entity(name: &str) -> Option<char> {
+    if let Some(number) = name.strip_prefix('#') {
+        let code = match number.strip_prefix(['x', 'X']) {
+            Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+            None => number.parse().ok()?,
+        };
+        return char::from_u32(code);
+    }
+    Some(match name {
+        "amp" => '&',
+        "lt" => '<',
+        "gt" => '>',
+        "quot" => '"',
+        "apos" => '\'',
+        "nbsp" => ' ',
+        "ndash" => '\u{2013}',
+        "mdash" => '\u{2014}',
+        "hellip" => '\u{2026}',
+        "lsquo" => '\u{2018}',
+        "rsquo" => '\u{2019}',
+        "ldquo" => '\u{201c}',
+        "rdquo" => '\u{201d}',
+        "copy" => '\u{a9}',
+        _ => return None,
+    })
+}
+
+/// Show notes as plain text: tags are dropped, block elements and `<br>` become line
+/// breaks, list items get a bullet and entities are decoded
+///
+/// Notes without any markup keep their own line breaks.
+pub(super) fn to_plain_text(html: &str) -> String {
+    if !html.contains('<') {
+        return decode_entities(html.trim());
+    }
+    let mut writer = Writer::default();
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        writer.text(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            rest = comment.find("-->").map_or("", |end| &comment[end + 3..]);
+            continue;
+        }
+        let Some(end) = rest.find('>') else {
+            break;
+        };
+        let tag = &rest[1..end];
+        rest = &rest[end + 1..];
+        let closing = tag.starts_with('/');
+        let name = tag
+            .trim_start_matches('/')
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        match name.as_str() {
+            "br" => writer.breaks = (writer.breaks + 1).min(2),
+            "li" if !closing => {
+                writer.line_break(1);
+                writer.flush();
+                writer.out.push('\u{2022}');
+                writer.space = true;
+            }
+            name if HIDDEN_ELEMENTS.contains(&name) && !closing => {
+                let close = format!("</{name}");
+                rest = rest
+                    .to_ascii_lowercase()
+                    .find(&close)
+                    .map_or("", |i| &rest[i..]);
+            }
+            name if BLOCK_ELEMENTS.contains(&name) => writer.line_break(2),
+            _ => {}
+        }
+    }
+    writer.text(rest);
+    writer.out
+}
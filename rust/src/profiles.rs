@@ -0,0 +1,173 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{ensure, Context};
+
+use crate::library::{self, Library};
+use crate::transcode::partial_path;
+use crate::{app, Profile};
+
+/// File listing the profiles, in the folder holding them
+const REGISTRY_FILE: &str = "profiles.json";
+
+/// Database of a profile, in the profile's own folder beside its caches
+const DATABASE_FILE: &str = "library.db";
+
+/// Name of the profile made when there are none yet
+const DEFAULT_NAME: &str = "Default";
+
+/// Folder holding the profiles, set by `open`; held while switching so switches
+/// happen one at a time
+static ROOT: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+struct Registry {
+    profiles: Vec<Profile>,
+    /// Id of the profile opened last
+    current: Option<String>,
+}
+
+impl Registry {
+    fn load(root: &Path) -> anyhow::Result<Self> {
+        let path = root.join(REGISTRY_FILE);
+        match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .with_context(|| format!("{} is damaged", path.display())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => {
+                Err(anyhow::Error::from(e).context(format!("failed to read {}", path.display())))
+            }
+        }
+    }
+
+    fn save(&self, root: &Path) -> anyhow::Result<()> {
+        let path = root.join(REGISTRY_FILE);
+        let partial = partial_path(&path);
+        fs::write(&partial, serde_json::to_vec_pretty(self)?)
+            .and_then(|()| fs::rename(&partial, &path))
+            .with_context(|| format!("failed to write {}", path.display()))
+    }
+
+    /// Add a profile named `name`, with a folder of its own under `root`
+    fn add(&mut self, root: &Path, name: &str) -> anyhow::Result<Profile> {
+        let name = name.trim();
+        ensure!(!name.is_empty(), "a profile needs a name");
+        ensure!(
+            !self
+                .profiles
+                .iter()
+                .any(|profile| profile.name.to_lowercase() == name.to_lowercase()),
+            "there's already a profile named {name}"
+        );
+        // Never reuse a folder, which may hold what's left of a removed profile.
+        let base = slug(name);
+        let id = (1..)
+            .map(|n| match n {
+                1 => base.clone(),
+                n => format!("{base}-{n}"),
+            })
+            .find(|id| {
+                !self.profiles.iter().any(|profile| &profile.id == id) && !root.join(id).exists()
+            })
+            .unwrap();
+        fs::create_dir_all(root.join(&id))
+            .with_context(|| format!("failed to make a folder for the profile {name}"))?;
+        let profile = Profile {
+            id,
+            name: name.to_string(),
+            created_at: library::now_secs(),
+        };
+        self.profiles.push(profile.clone());
+        Ok(profile)
+    }
+}
+
+/// Lowercase letters and digits of `name`, with a dash for each run of anything else
+fn slug(name: &str) -> String {
+    let mut slug = String::new();
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    let slug = slug.trim_end_matches('-');
+    match slug.is_empty() {
+        true => "profile".to_string(),
+        false => slug.to_string(),
+    }
+}
+
+fn root(root: &Option<PathBuf>) -> anyhow::Result<&Path> {
+    root.as_deref()
+        .context("profiles aren't open; call open_profiles first")
+}
+
+/// Use the profiles kept under `root`, making a default one if there are none, and
+/// open the library of profile `id`, or of the one used last
+pub(crate) fn open(root: &Path, id: Option<&str>) -> anyhow::Result<Profile> {
+    let mut current = ROOT.lock().unwrap();
+    fs::create_dir_all(root).with_context(|| format!("failed to make {}", root.display()))?;
+    let mut registry = Registry::load(root)?;
+    if registry.profiles.is_empty() {
+        registry.add(root, DEFAULT_NAME)?;
+    }
+    let id = match id {
+        Some(id) => id.to_string(),
+        None => registry
+            .current
+            .clone()
+            .filter(|id| registry.profiles.iter().any(|profile| &profile.id == id))
+            .unwrap_or_else(|| registry.profiles[0].id.clone()),
+    };
+    let profile = activate(root, &mut registry, &id)?;
+    *current = Some(root.to_path_buf());
+    Ok(profile)
+}
+
+pub(crate) fn list() -> anyhow::Result<Vec<Profile>> {
+    Ok(Registry::load(root(&ROOT.lock().unwrap())?)?.profiles)
+}
+
+pub(crate) fn create(name: &str) -> anyhow::Result<Profile> {
+    let current = ROOT.lock().unwrap();
+    let root = root(&current)?;
+    let mut registry = Registry::load(root)?;
+    let profile = registry.add(root, name)?;
+    registry.save(root)?;
+    log::info!("made the profile {}", profile.name);
+    Ok(profile)
+}
+
+pub(crate) fn switch(id: &str) -> anyhow::Result<Profile> {
+    let current = ROOT.lock().unwrap();
+    let root = root(&current)?;
+    let mut registry = Registry::load(root)?;
+    activate(root, &mut registry, id)
+}
+
+/// Close the open library, after stopping everything working on it, and open the
+/// library of profile `id` instead
+///
+/// Everything that can fail happens before the open library is let go, so a failed
+/// switch leaves it open; jobs cancelled and watches stopped on the way stay so.
+fn activate(root: &Path, registry: &mut Registry, id: &str) -> anyhow::Result<Profile> {
+    let profile = registry
+        .profiles
+        .iter()
+        .find(|profile| profile.id == id)
+        .cloned()
+        .with_context(|| format!("no profile with id {id}"))?;
+    let opened = Library::open(&root.join(&profile.id).join(DATABASE_FILE))?;
+    if library::is_open() {
+        app::stop_library_work()?;
+    }
+    registry.current = Some(profile.id.clone());
+    registry.save(root)?;
+    library::install(opened);
+    log::info!("opened the profile {}", profile.name);
+    Ok(profile)
+}
@@ -0,0 +1,390 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use anyhow::Context;
+use percent_encoding::percent_decode_str;
+use serde::Serialize;
+
+use crate::cast::{self, Request};
+use crate::transcode::{self, Span};
+use crate::{
+    artwork, cue, library, BrowseSort, RemoteServerInfo, RemoteServerOptions, SongId, StorageKind,
+    TranscodeOptions,
+};
+
+/// Songs per page of `/api/songs` when the request doesn't say
+const DEFAULT_PAGE_LIMIT: u32 = 100;
+
+/// Matches per list of `/api/search` when the request doesn't say
+const DEFAULT_SEARCH_LIMIT: u32 = 20;
+
+/// Requests answered at once; more are turned away until one ends
+const MAX_CONNECTIONS: usize = 16;
+
+/// Shortest pairing token accepted from `RemoteServerOptions`
+const MIN_TOKEN_LEN: usize = 16;
+
+/// The running server; dropping it stops it
+static SERVER: Mutex<Option<Server>> = Mutex::new(None);
+
+struct Server {
+    port: u16,
+    stopped: Arc<AtomicBool>,
+    accepting: Option<JoinHandle<()>>,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        // Wake the accept loop so it sees the flag.
+        let _ = TcpStream::connect(("127.0.0.1", self.port));
+        // Only once the loop is done is the port free to listen on again.
+        if let Some(accepting) = self.accepting.take() {
+            let _ = accepting.join();
+        }
+    }
+}
+
+/// What a request gets back, short of a stream
+enum Response {
+    Json(String),
+    Jpeg(Vec<u8>),
+    /// Status line and message
+    Error(&'static str, String),
+}
+
+impl Response {
+    fn json(value: &impl Serialize) -> anyhow::Result<Response> {
+        Ok(Response::Json(serde_json::to_string(value)?))
+    }
+
+    fn not_found(what: &str) -> Response {
+        Response::Error("404 Not Found", format!("no {what}"))
+    }
+
+    fn bad_request(message: impl Into<String>) -> Response {
+        Response::Error("400 Bad Request", message.into())
+    }
+}
+
+/// A request being answered, counted in `connections` until it's dropped
+struct Connection(Arc<AtomicUsize>);
+
+impl Connection {
+    /// `None` once `MAX_CONNECTIONS` requests are being answered
+    fn open(connections: &Arc<AtomicUsize>) -> Option<Connection> {
+        connections
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |open| {
+                (open < MAX_CONNECTIONS).then_some(open + 1)
+            })
+            .ok()
+            .map(|_| Connection(connections.clone()))
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Serve the open library as `options` asks, replacing any server already running;
+/// returns the port listened on and the token clients pair with
+pub(crate) fn start(options: RemoteServerOptions) -> anyhow::Result<RemoteServerInfo> {
+    anyhow::ensure!(
+        library::is_open(),
+        "library database is not open; call open_library first"
+    );
+    let token = match options.token {
+        Some(token) => {
+            anyhow::ensure!(
+                token.len() >= MIN_TOKEN_LEN && token.bytes().all(|b| b.is_ascii_graphic()),
+                "a pairing token needs at least {MIN_TOKEN_LEN} characters, without spaces"
+            );
+            token
+        }
+        None => format!("{:032x}", rand::random::<u128>()),
+    };
+    let address = match options.lan {
+        true => Ipv4Addr::UNSPECIFIED,
+        false => Ipv4Addr::LOCALHOST,
+    };
+    // The old server has to let go of the port before asking for the same one.
+    stop();
+    let port = options.port;
+    let listener = TcpListener::bind((address, port))
+        .with_context(|| format!("failed to listen on port {port}"))?;
+    let port = listener.local_addr()?.port();
+    let stopped = Arc::new(AtomicBool::new(false));
+    let thread_stopped = stopped.clone();
+    let thread_token: Arc<str> = token.clone().into();
+    let accepting = thread::Builder::new()
+        .name("tunes4r-remote-server".into())
+        .spawn(move || {
+            let connections = Arc::new(AtomicUsize::new(0));
+            for stream in listener.incoming() {
+                if thread_stopped.load(Ordering::Relaxed) {
+                    break;
+                }
+                let Ok(mut stream) = stream else {
+                    continue;
+                };
+                let Some(connection) = Connection::open(&connections) else {
+                    let _ = cast::reply(&mut stream, "503 Service Unavailable");
+                    continue;
+                };
+                let token = thread_token.clone();
+                let spawned = thread::Builder::new()
+                    .name("tunes4r-remote-request".into())
+                    .spawn(move || {
+                        let _connection = connection;
+                        if let Err(e) = respond(stream, &token) {
+                            log::debug!("remote request failed: {e:#}");
+                        }
+                    });
+                if let Err(e) = spawned {
+                    log::warn!("failed to spawn a remote request thread: {e}");
+                }
+            }
+        })
+        .context("failed to spawn remote server thread")?;
+    *SERVER.lock().unwrap() = Some(Server {
+        port,
+        stopped,
+        accepting: Some(accepting),
+    });
+    log::info!("serving the library on {address}:{port}");
+    Ok(RemoteServerInfo { port, token })
+}
+
+pub(crate) fn stop() {
+    SERVER.lock().unwrap().take();
+}
+
+/// Whether `request` carries `token`, as a bearer token or in its query
+fn is_paired(request: &Request, query: &HashMap<String, String>, token: &str) -> bool {
+    let given = request
+        .authorization
+        .as_deref()
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .or(query.get("token").map(String::as_str));
+    // Compared in full whatever differs, so timing gives nothing away.
+    given.is_some_and(|given| {
+        given.len() == token.len()
+            && given
+                .bytes()
+                .zip(token.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    })
+}
+
+fn respond(mut stream: TcpStream, token: &str) -> anyhow::Result<()> {
+    let request = cast::read_request(&stream)?;
+    if !matches!(request.method.as_str(), "GET" | "HEAD") {
+        return cast::reply(&mut stream, "405 Method Not Allowed");
+    }
+    let Ok(url) = url::Url::parse(&format!("http://localhost{}", request.target)) else {
+        return send(
+            &mut stream,
+            &request,
+            Response::bad_request("malformed request"),
+        );
+    };
+    let path: Vec<String> = url
+        .path_segments()
+        .into_iter()
+        .flatten()
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| percent_decode_str(segment).decode_utf8_lossy().into_owned())
+        .collect();
+    let query: HashMap<String, String> = url.query_pairs().into_owned().collect();
+    if !is_paired(&request, &query, token) {
+        let message = "pair with the token start_remote_server returned".to_string();
+        return send(
+            &mut stream,
+            &request,
+            Response::Error("401 Unauthorized", message),
+        );
+    }
+    let path: Vec<&str> = path.iter().map(String::as_str).collect();
+
+    if let ["stream", song_id] = path[..] {
+        let song_id = SongId(song_id.to_string());
+        return stream_song(&mut stream, &request, &song_id, &query);
+    }
+    let response = match api(&path, &query) {
+        Ok(response) => response,
+        Err(e) => Response::Error("500 Internal Server Error", format!("{e:#}")),
+    };
+    send(&mut stream, &request, response)
+}
+
+/// Answer a request below `/api`
+fn api(path: &[&str], query: &HashMap<String, String>) -> anyhow::Result<Response> {
+    let number = |name: &str, default: u32| match query.get(name) {
+        Some(value) => value.parse().ok(),
+        None => Some(default),
+    };
+    let Some(sort) = query
+        .get("sort")
+        .map_or(Some(BrowseSort::Name), |s| parse_sort(s))
+    else {
+        return Ok(Response::bad_request(
+            "sort must be name, year, date_added or most_played",
+        ));
+    };
+    let id = |value: &str| value.parse::<i64>().ok();
+
+    library::with_library(|lib| match path {
+        ["api", "songs"] => {
+            let Some(limit) = number("limit", DEFAULT_PAGE_LIMIT) else {
+                return Ok(Response::bad_request("limit must be a number"));
+            };
+            let cursor = query.get("cursor").map(String::as_str);
+            Response::json(&lib.get_songs_page(cursor, limit)?)
+        }
+        ["api", "songs", song_id] => match lib.get_song(&SongId(song_id.to_string()))? {
+            Some(song) => Response::json(&song),
+            None => Ok(Response::not_found("such song")),
+        },
+        ["api", "songs", song_id, "art"] => {
+            let Some(song) = lib.get_song(&SongId(song_id.to_string()))? else {
+                return Ok(Response::not_found("such song"));
+            };
+            let art = artwork::album_art(
+                Path::new(&song.file_path),
+                &lib.cache_dir(StorageKind::Artwork),
+            )?;
+            Ok(art.map_or_else(|| Response::not_found("cover art"), Response::Jpeg))
+        }
+        ["api", "artists"] => Response::json(&lib.get_artists(sort)?),
+        ["api", "artists", artist_id, "albums"] => match id(artist_id) {
+            Some(artist_id) => Response::json(&lib.get_albums_for_artist(artist_id, sort)?),
+            None => Ok(Response::not_found("such artist")),
+        },
+        ["api", "artists", artist_id, "songs"] => match id(artist_id) {
+            Some(artist_id) => Response::json(&lib.get_artist_songs(artist_id)?),
+            None => Ok(Response::not_found("such artist")),
+        },
+        ["api", "albums", album_id] => {
+            let album = match id(album_id) {
+                Some(album_id) => lib.get_album(album_id)?,
+                None => None,
+            };
+            match album {
+                Some(album) => Response::json(&album),
+                None => Ok(Response::not_found("such album")),
+            }
+        }
+        ["api", "playlists"] => Response::json(&lib.get_playlists()?),
+        ["api", "playlists", playlist_id, "songs"] => match id(playlist_id) {
+            Some(playlist_id) => Response::json(&lib.get_playlist_songs(playlist_id)?),
+            None => Ok(Response::not_found("such playlist")),
+        },
+        ["api", "search"] => {
+            let Some(limit) = number("limit", DEFAULT_SEARCH_LIMIT) else {
+                return Ok(Response::bad_request("limit must be a number"));
+            };
+            let text = query.get("q").map_or("", String::as_str);
+            Response::json(&lib.search_library(text, limit)?)
+        }
+        _ => Ok(Response::not_found("such page")),
+    })
+}
+
+fn parse_sort(value: &str) -> Option<BrowseSort> {
+    match value {
+        "name" => Some(BrowseSort::Name),
+        "year" => Some(BrowseSort::Year),
+        "date_added" => Some(BrowseSort::DateAdded),
+        "most_played" => Some(BrowseSort::MostPlayed),
+        _ => None,
+    }
+}
+
+fn send(stream: &mut TcpStream, request: &Request, response: Response) -> anyhow::Result<()> {
+    let (status, content_type, body) = match response {
+        Response::Json(json) => ("200 OK", "application/json", json.into_bytes()),
+        Response::Jpeg(image) => ("200 OK", "image/jpeg", image),
+        Response::Error(status, message) => {
+            let body = serde_json::json!({ "error": message }).to_string();
+            (status, "application/json", body.into_bytes())
+        }
+    };
+    let head = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        body.len()
+    );
+    stream.write_all(head.as_bytes())?;
+    if request.method == "GET" {
+        stream.write_all(&body)?;
+    }
+    Ok(stream.flush()?)
+}
+
+/// Answer `/stream/<song id>` with the song's file, or with MP3 encoded as it's sent
+/// when the query asks for `format=mp3`, optionally at `bitrate` kbps
+///
+/// CUE sheet tracks are always encoded, being only part of their file.
+fn stream_song(
+    stream: &mut TcpStream,
+    request: &Request,
+    song_id: &SongId,
+    query: &HashMap<String, String>,
+) -> anyhow::Result<()> {
+    let song = match library::with_library(|lib| lib.get_song(song_id)) {
+        Ok(Some(song)) => song,
+        Ok(None) => return send(stream, request, Response::not_found("such song")),
+        Err(e) => {
+            let message = format!("{e:#}");
+            return send(
+                stream,
+                request,
+                Response::Error("500 Internal Server Error", message),
+            );
+        }
+    };
+    let options = TranscodeOptions {
+        bitrate_kbps: match query.get("bitrate").map(|b| b.parse()) {
+            Some(Ok(kbps)) => Some(kbps),
+            Some(Err(_)) => {
+                return send(
+                    stream,
+                    request,
+                    Response::bad_request("bitrate must be a number"),
+                );
+            }
+            None => None,
+        },
+        copy_tags: false,
+    };
+    let path = Path::new(&song.file_path);
+    match query.get("format").map(String::as_str) {
+        None if !cue::is_track(&song) => return cast::send_file(stream, request, path),
+        None | Some("mp3") => {}
+        Some(_) => {
+            return send(stream, request, Response::bad_request("format must be mp3"));
+        }
+    }
+
+    let head = "HTTP/1.1 200 OK\r\nContent-Type: audio/mpeg\r\nAccept-Ranges: none\r\n\
+                Connection: close\r\n\r\n";
+    stream.write_all(head.as_bytes())?;
+    if request.method != "GET" {
+        return Ok(());
+    }
+    let span = Span {
+        start: song.start_offset,
+        end: song.end_offset,
+    };
+    // The response has no length, so it ends when the connection closes.
+    transcode::stream_mp3(path, span, &options, stream)
+        .with_context(|| format!("couldn't stream {}", path.display()))
+}
@@ -0,0 +1,127 @@
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use anyhow::Context;
+
+use super::{Toc, TocTrack, SECTOR_BYTES};
+
+// From linux/cdrom.h
+const CDROMREADTOCHDR: libc::c_ulong = 0x5305;
+const CDROMREADTOCENTRY: libc::c_ulong = 0x5306;
+const CDROMREADAUDIO: libc::c_ulong = 0x530e;
+const CDROM_LBA: u8 = 0x01;
+const CDROM_LEADOUT: u8 = 0xaa;
+const CDROM_DATA_TRACK: u8 = 0x04;
+
+#[repr(C)]
+#[derive(Default)]
+struct TocHeader {
+    first_track: u8,
+    last_track: u8,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct TocEntry {
+    track: u8,
+    /// The address type in the low four bits and the control flags in the high ones
+    adr_ctrl: u8,
+    format: u8,
+    /// `CDROM_LBA` was asked for, so this is the sector number
+    lba: libc::c_int,
+    data_mode: u8,
+}
+
+#[repr(C)]
+struct ReadAudio {
+    lba: libc::c_int,
+    format: u8,
+    frames: libc::c_int,
+    buffer: *mut u8,
+}
+
+/// An optical drive opened for reading its table of contents and audio sectors
+pub(super) struct Drive {
+    fd: libc::c_int,
+}
+
+impl Drive {
+    pub fn open(device: &Path) -> anyhow::Result<Self> {
+        let path = CString::new(device.as_os_str().as_bytes())
+            .with_context(|| format!("invalid device path {}", device.display()))?;
+        // Without O_NONBLOCK the open fails while the tray is open or the disc spins up.
+        let fd = unsafe { libc::open(path.as_ptr(), libc::O_RDONLY | libc::O_NONBLOCK) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error())
+                .with_context(|| format!("couldn't open {}", device.display()));
+        }
+        Ok(Drive { fd })
+    }
+
+    /// The tracks on the disc in order, with where each starts
+    pub fn toc(&self) -> anyhow::Result<Toc> {
+        let mut header = TocHeader::default();
+        self.ioctl(CDROMREADTOCHDR, &mut header)
+            .context("no readable CD in the drive")?;
+        let mut tracks = Vec::new();
+        for number in header.first_track..=header.last_track {
+            let entry = self.toc_entry(number)?;
+            let ctrl = match cfg!(target_endian = "little") {
+                true => entry.adr_ctrl >> 4,
+                false => entry.adr_ctrl & 0x0f,
+            };
+            tracks.push(TocTrack {
+                number: number as u32,
+                start: entry.lba as u32,
+                audio: ctrl & CDROM_DATA_TRACK == 0,
+            });
+        }
+        let leadout = self.toc_entry(CDROM_LEADOUT)?.lba as u32;
+        Ok(Toc { tracks, leadout })
+    }
+
+    fn toc_entry(&self, track: u8) -> anyhow::Result<TocEntry> {
+        let mut entry = TocEntry {
+            track,
+            format: CDROM_LBA,
+            ..Default::default()
+        };
+        self.ioctl(CDROMREADTOCENTRY, &mut entry)
+            .with_context(|| format!("couldn't read the table of contents entry {track}"))?;
+        Ok(entry)
+    }
+
+    /// Read audio sectors from `lba` on into `buffer`, a whole number of sectors long;
+    /// false if the disc couldn't be read there, as at a scratch
+    pub fn read_audio(&self, lba: u32, buffer: &mut [u8]) -> anyhow::Result<bool> {
+        debug_assert!(buffer.len().is_multiple_of(SECTOR_BYTES));
+        let mut request = ReadAudio {
+            lba: lba as libc::c_int,
+            format: CDROM_LBA,
+            frames: (buffer.len() / SECTOR_BYTES) as libc::c_int,
+            buffer: buffer.as_mut_ptr(),
+        };
+        match self.ioctl(CDROMREADAUDIO, &mut request) {
+            Ok(()) => Ok(true),
+            Err(e) if e.raw_os_error() == Some(libc::EIO) => Ok(false),
+            Err(e) => Err(e).with_context(|| format!("couldn't read sector {lba}")),
+        }
+    }
+
+    fn ioctl<T>(&self, request: libc::c_ulong, argument: &mut T) -> io::Result<()> {
+        // Every request used here fills in or reads the one struct it's given.
+        let result = unsafe { libc::ioctl(self.fd, request as _, argument as *mut T) };
+        match result {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for Drive {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
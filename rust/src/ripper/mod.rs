@@ -0,0 +1,478 @@
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+
+use anyhow::Context;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use lofty::config::WriteOptions;
+use lofty::prelude::*;
+use ring::digest;
+use serde_json::Value;
+
+use crate::export::{self, DEFAULT_PATTERN};
+use crate::identify::{self, MUSICBRAINZ_URL};
+use crate::jobs::{self, Job};
+use crate::transcode::{self, EncodedFile};
+use crate::{library, metadata};
+use crate::{CdDisc, CdTrack, JobKind, RipEvent, RipOptions, StreamSink, TranscodeFormat};
+
+#[cfg(target_os = "linux")]
+mod drive;
+
+#[cfg(target_os = "linux")]
+use drive::Drive;
+
+/// Drive read when none is named
+pub(crate) const DEFAULT_DEVICE: &str = "/dev/cdrom";
+
+/// Bytes in an audio sector: 1/75 of a second of 16-bit stereo at 44.1 kHz
+const SECTOR_BYTES: usize = 2352;
+const SECTORS_PER_SECOND: u32 = 75;
+const CD_SAMPLE_RATE: u32 = 44_100;
+const CD_CHANNELS: u16 = 2;
+
+/// Sectors asked of the drive at a time
+const READ_SECTORS: u32 = 16;
+
+/// Reads of a stretch of sectors tried before settling for one no other read agreed
+/// with
+const MAX_READS: usize = 20;
+
+/// The two seconds before the first track, which disc ids count offsets from
+const LEAD_IN_SECTORS: u32 = 150;
+
+/// Gap an enhanced CD leaves between its audio and the data session after it
+const SESSION_GAP_SECTORS: u32 = 11_400;
+
+/// Emit `Progress` whenever this much more of the rip is done
+const PROGRESS_STEP: f32 = 0.01;
+
+/// A track in the disc's table of contents
+struct TocTrack {
+    number: u32,
+    /// First sector
+    start: u32,
+    audio: bool,
+}
+
+struct Toc {
+    tracks: Vec<TocTrack>,
+    /// The sector after the last track
+    leadout: u32,
+}
+
+impl Toc {
+    /// Number, first sector and the sector after the last of each audio track
+    fn audio_tracks(&self) -> Vec<(u32, u32, u32)> {
+        let mut spans = Vec::new();
+        for (i, track) in self.tracks.iter().enumerate() {
+            if !track.audio {
+                continue;
+            }
+            let end = match self.tracks.get(i + 1) {
+                Some(next) if !next.audio => next.start.saturating_sub(SESSION_GAP_SECTORS),
+                Some(next) => next.start,
+                None => self.leadout,
+            };
+            spans.push((track.number, track.start, end.max(track.start)));
+        }
+        spans
+    }
+
+    /// The MusicBrainz disc id, from the audio tracks' offsets; `None` without any
+    fn disc_id(&self) -> Option<String> {
+        let spans = self.audio_tracks();
+        let (first, _, _) = *spans.first()?;
+        let (last, _, leadout) = *spans.last()?;
+        let mut offsets = [0u32; 100];
+        offsets[0] = leadout + LEAD_IN_SECTORS;
+        for &(number, start, _) in &spans {
+            if let Some(offset) = offsets.get_mut(number as usize) {
+                *offset = start + LEAD_IN_SECTORS;
+            }
+        }
+        let mut text = format!("{first:02X}{last:02X}");
+        for offset in offsets {
+            let _ = write!(text, "{offset:08X}");
+        }
+        let hash = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, text.as_bytes());
+        // MusicBrainz's own base64 alphabet, safe in URLs
+        let id = STANDARD
+            .encode(hash)
+            .replace('+', ".")
+            .replace('/', "_")
+            .replace('=', "-");
+        Some(id)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+enum Drive {}
+
+#[cfg(not(target_os = "linux"))]
+impl Drive {
+    fn open(_device: &Path) -> anyhow::Result<Self> {
+        anyhow::bail!("reading CDs is only supported on Linux so far")
+    }
+
+    fn toc(&self) -> anyhow::Result<Toc> {
+        match *self {}
+    }
+
+    fn read_audio(&self, _lba: u32, _buffer: &mut [u8]) -> anyhow::Result<bool> {
+        match *self {}
+    }
+}
+
+/// The audio CD in `device`, with its tracks named from MusicBrainz if it knows the
+/// disc and can be reached
+pub(crate) fn read_disc(device: &str) -> anyhow::Result<CdDisc> {
+    let toc = Drive::open(Path::new(device))?.toc()?;
+    let disc_id = toc
+        .disc_id()
+        .with_context(|| format!("the disc in {device} has no audio tracks"))?;
+    let tracks = toc
+        .audio_tracks()
+        .into_iter()
+        .map(|(number, start, end)| CdTrack {
+            number,
+            duration_secs: (end - start) as f64 / SECTORS_PER_SECOND as f64,
+            title: None,
+            artist: None,
+        })
+        .collect();
+    let mut disc = CdDisc {
+        device: device.to_string(),
+        disc_id,
+        release_id: None,
+        album: None,
+        artist: None,
+        year: None,
+        tracks,
+    };
+    if let Err(e) = look_up(&mut disc) {
+        log::warn!("couldn't look CD {} up on MusicBrainz: {e:#}", disc.disc_id);
+    }
+    Ok(disc)
+}
+
+/// Fill in `disc` from the MusicBrainz release it belongs to, if there is one
+fn look_up(disc: &mut CdDisc) -> anyhow::Result<()> {
+    let result = identify::agent()
+        .get(&format!("{MUSICBRAINZ_URL}/discid/{}", disc.disc_id))
+        .query("inc", "artist-credits recordings")
+        .query("fmt", "json")
+        .call();
+    let body = match result {
+        Ok(response) => response.into_string()?,
+        Err(ureq::Error::Status(404, _)) => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let response: Value = serde_json::from_str(&body).context("unreadable MusicBrainz response")?;
+    let releases = response["releases"].as_array().map(Vec::as_slice);
+    let releases = releases.unwrap_or_default();
+    // Several pressings can share a disc id; prefer an official one.
+    let Some(release) = releases
+        .iter()
+        .find(|r| r["status"] == "Official")
+        .or(releases.first())
+    else {
+        return Ok(());
+    };
+    let on_disc = |medium: &&Value| {
+        medium["discs"]
+            .as_array()
+            .is_some_and(|discs| discs.iter().any(|d| d["id"] == disc.disc_id.as_str()))
+    };
+    let media = release["media"].as_array().map(Vec::as_slice);
+    let Some(medium) = media.unwrap_or_default().iter().find(on_disc) else {
+        return Ok(());
+    };
+    let text = |value: &Value| value.as_str().map(str::to_string);
+    let credit =
+        |value: &Value| Some(identify::artist_credit(value, "name")).filter(|a| !a.is_empty());
+    disc.release_id = text(&release["id"]);
+    disc.album = text(&release["title"]);
+    disc.artist = credit(&release["artist-credit"]);
+    disc.year = release["date"]
+        .as_str()
+        .and_then(|date| date.get(..4))
+        .and_then(|year| year.parse().ok());
+    let tracks = medium["tracks"].as_array().map(Vec::as_slice);
+    for (track, found) in disc.tracks.iter_mut().zip(tracks.unwrap_or_default()) {
+        track.title = text(&found["title"]);
+        track.artist = credit(&found["artist-credit"]);
+    }
+    Ok(())
+}
+
+/// Queue ripping `disc` into `dest` as a background job, returning its id for
+/// `jobs::cancel`
+pub(crate) fn start(
+    disc: CdDisc,
+    dest: PathBuf,
+    options: RipOptions,
+    sink: StreamSink<RipEvent>,
+) -> u32 {
+    jobs::submit(JobKind::Rip, move |job| {
+        let (event, result) = match rip(&disc, &dest, &options, &sink, job) {
+            _ if job.is_cancelled() => (RipEvent::Cancelled, Ok(())),
+            Ok(finished) => (finished, Ok(())),
+            Err(e) => {
+                let message = format!("{e:#}");
+                (RipEvent::Failed { message }, Err(e))
+            }
+        };
+        let _ = sink.add(event);
+        result
+    })
+}
+
+/// Reports progress through the sink, stopping the rip if it's cancelled or the
+/// listener went away
+struct Progress<'a> {
+    sink: &'a StreamSink<RipEvent>,
+    job: &'a Job,
+    tracks: u32,
+    /// Sectors of every track being ripped
+    sectors: u32,
+    reported: f32,
+}
+
+impl Progress<'_> {
+    /// Report `done` tracks ripped and `read` sectors read in all; false once the rip
+    /// should stop
+    fn report(&mut self, done: u32, read: u32) -> bool {
+        let fraction = read as f32 / self.sectors.max(1) as f32;
+        if fraction == 0.0 || fraction - self.reported >= PROGRESS_STEP {
+            self.reported = fraction;
+            let event = RipEvent::Progress {
+                done,
+                total: self.tracks,
+                fraction,
+            };
+            if self.sink.add(event).is_err() {
+                self.job.cancelled().store(true, Ordering::Relaxed);
+            }
+            self.job.progress(read as u64, Some(self.sectors as u64));
+        }
+        !self.job.is_cancelled()
+    }
+}
+
+fn rip(
+    disc: &CdDisc,
+    dest: &Path,
+    options: &RipOptions,
+    sink: &StreamSink<RipEvent>,
+    job: &Job,
+) -> anyhow::Result<RipEvent> {
+    transcode::check_supported(options.format)?;
+    let drive = Drive::open(Path::new(&disc.device))?;
+    let toc = drive.toc()?;
+    anyhow::ensure!(
+        toc.disc_id().as_deref() == Some(disc.disc_id.as_str()),
+        "the disc in {} isn't the one read before",
+        disc.device
+    );
+    let spans = toc.audio_tracks();
+    let tracks: Vec<(&CdTrack, u32, u32)> = disc
+        .tracks
+        .iter()
+        .filter(|track| options.tracks.is_empty() || options.tracks.contains(&track.number))
+        .filter_map(|track| {
+            let &(_, start, end) = spans.iter().find(|(n, _, _)| *n == track.number)?;
+            Some((track, start, end))
+        })
+        .collect();
+    anyhow::ensure!(
+        !tracks.is_empty(),
+        "none of the tracks asked for are on the disc"
+    );
+    fs::create_dir_all(dest).with_context(|| format!("failed to create {}", dest.display()))?;
+    let pattern = match options.pattern.trim() {
+        "" => DEFAULT_PATTERN,
+        pattern => pattern,
+    };
+
+    let mut progress = Progress {
+        sink,
+        job,
+        tracks: tracks.len() as u32,
+        sectors: tracks.iter().map(|(_, start, end)| end - start).sum(),
+        reported: 0.0,
+    };
+    let mut reader = VerifiedReader {
+        drive: &drive,
+        rereads: 0,
+        unverified: 0,
+    };
+    let mut read = 0;
+    let mut ripped = Vec::new();
+    for (index, &(track, start, end)) in tracks.iter().enumerate() {
+        let done = index as u32;
+        if !progress.report(done, read) {
+            anyhow::bail!("rip cancelled");
+        }
+        let path = dest.join(track_path(pattern, disc, track, options.format));
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("failed to create {}", parent.display()))?;
+        }
+        let mut file = EncodedFile::create(
+            &path,
+            options.format,
+            &options.transcode,
+            CD_CHANNELS,
+            CD_SAMPLE_RATE,
+            16,
+        )?;
+        reader.rereads = 0;
+        reader.unverified = 0;
+        let mut samples = Vec::new();
+        let mut lba = start;
+        while lba < end {
+            let count = (end - lba).min(READ_SECTORS);
+            let sectors = reader
+                .read(lba, count)
+                .with_context(|| format!("couldn't read track {}", track.number))?;
+            samples.clear();
+            samples.extend(
+                sectors
+                    .chunks_exact(2)
+                    .map(|b| i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.0),
+            );
+            file.write(&samples)?;
+            lba += count;
+            if !progress.report(done, read + lba - start) {
+                anyhow::bail!("rip cancelled");
+            }
+        }
+        file.finish()?;
+        read += end - start;
+        if let Err(e) = write_tags(&path, disc, track) {
+            log::warn!("couldn't tag {}: {e:#}", path.display());
+        }
+        log::info!("ripped track {} to {}", track.number, path.display());
+        let _ = sink.add(RipEvent::TrackRipped {
+            number: track.number,
+            path: path.to_string_lossy().into_owned(),
+            rereads: reader.rereads,
+            unverified_secs: reader.unverified as f64 / SECTORS_PER_SECOND as f64,
+        });
+        ripped.push(path);
+    }
+    if options.add_to_library && library::is_open() {
+        for path in &ripped {
+            let added = metadata::read_song(path)
+                .and_then(|song| library::with_library(|lib| lib.upsert_song(&song)));
+            if let Err(e) = added {
+                log::warn!("couldn't add {} to the library: {e:#}", path.display());
+            }
+        }
+    }
+    Ok(RipEvent::Finished {
+        ripped: ripped.len() as u32,
+    })
+}
+
+/// Reads sectors until two reads agree, getting past scratches and drives that return
+/// slightly different data each time, as cdparanoia does
+struct VerifiedReader<'a> {
+    drive: &'a Drive,
+    /// Stretches read more than twice before two reads agreed
+    rereads: u32,
+    /// Sectors no two reads agreed on, or that couldn't be read at all and were left
+    /// silent
+    unverified: u32,
+}
+
+impl VerifiedReader<'_> {
+    fn read(&mut self, lba: u32, count: u32) -> anyhow::Result<Vec<u8>> {
+        let mut reads: Vec<Vec<u8>> = Vec::new();
+        for attempt in 0..MAX_READS {
+            let mut buffer = vec![0; count as usize * SECTOR_BYTES];
+            if !self.drive.read_audio(lba, &mut buffer)? {
+                continue;
+            }
+            if reads.contains(&buffer) {
+                if attempt >= 2 {
+                    self.rereads += 1;
+                }
+                return Ok(buffer);
+            }
+            reads.push(buffer);
+        }
+        if reads.is_empty() && count > 1 {
+            // Save what can be read around the bad spot.
+            let mut buffer = Vec::with_capacity(count as usize * SECTOR_BYTES);
+            for sector in lba..lba + count {
+                buffer.extend(self.read(sector, 1)?);
+            }
+            return Ok(buffer);
+        }
+        log::warn!(
+            "sectors {lba} to {} never read the same twice",
+            lba + count - 1
+        );
+        self.unverified += count;
+        Ok(reads
+            .pop()
+            .unwrap_or_else(|| vec![0; count as usize * SECTOR_BYTES]))
+    }
+}
+
+/// Where `track` goes below the destination, following `pattern`
+fn track_path(pattern: &str, disc: &CdDisc, track: &CdTrack, format: TranscodeFormat) -> PathBuf {
+    let number = format!("{:02}", track.number);
+    let title = track
+        .title
+        .clone()
+        .unwrap_or_else(|| format!("Track {number}"));
+    // The album artist, so a compilation's tracks stay together
+    let artist = disc.artist.as_ref().or(track.artist.as_ref());
+    let fields = [
+        (
+            "{artist}",
+            artist.map_or(metadata::UNKNOWN_ARTIST, String::as_str),
+        ),
+        (
+            "{album}",
+            disc.album.as_deref().unwrap_or(metadata::UNKNOWN_ALBUM),
+        ),
+        ("{title}", title.as_str()),
+        ("{track}", number.as_str()),
+        ("{index}", number.as_str()),
+    ];
+    export::fill_pattern(pattern, &fields, &number, transcode::extension(format))
+}
+
+/// Tag a ripped track with what's known about it and its disc
+fn write_tags(path: &Path, disc: &CdDisc, track: &CdTrack) -> anyhow::Result<()> {
+    let mut tagged_file = lofty::read_from_path(path)?;
+    let tag = metadata::writable_tag(&mut tagged_file);
+    if let Some(title) = &track.title {
+        tag.set_title(title.clone());
+    }
+    if let Some(artist) = track.artist.as_ref().or(disc.artist.as_ref()) {
+        tag.set_artist(artist.clone());
+    }
+    if let Some(album) = &disc.album {
+        tag.set_album(album.clone());
+    }
+    if let Some(artist) = &disc.artist {
+        tag.insert_text(ItemKey::AlbumArtist, artist.clone());
+    }
+    if let Some(year) = disc.year {
+        tag.set_year(year);
+    }
+    if let Some(id) = &disc.release_id {
+        tag.insert_text(ItemKey::MusicBrainzReleaseId, id.clone());
+    }
+    tag.set_track(track.number);
+    tag.set_track_total(disc.tracks.len() as u32);
+    tag.save_to_path(path, WriteOptions::default())?;
+    Ok(())
+}
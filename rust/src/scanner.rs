@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+
+use md5::{Digest, Md5};
+use walkdir::WalkDir;
+
+use crate::analysis::{self, Analysis};
+use crate::cue::{self, CueFile, CueSheet};
+use crate::jobs::{self, Job};
+use crate::{library, loudness, metadata, JobKind, ScanEvent, Song, StreamSink, TrackFeatures};
+
+/// File extensions the scanner treats as audio; Opus files need the `opus` feature
+pub(crate) const SUPPORTED_EXTENSIONS: &[&str] = &[
+    "mp3",
+    "flac",
+    "ogg",
+    "oga",
+    #[cfg(feature = "opus")]
+    "opus",
+    "m4a",
+    "m4b",
+    "aac",
+    "mp4",
+    "wav",
+    "aif",
+    "aiff",
+];
+
+/// Emit a `Discovered` event every this many files during the walk
+const DISCOVERY_REPORT_EVERY: u32 = 100;
+
+/// Bytes `identify` hashes from each of the start, middle and end of a file
+const IDENTITY_SAMPLE_BYTES: u64 = 64 * 1024;
+
+pub(crate) fn is_supported(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| SUPPORTED_EXTENSIONS.contains(&e.to_ascii_lowercase().as_str()))
+}
+
+/// Queue a scan of `root` as a background job, returning its id for `jobs::cancel`
+pub(crate) fn start(root: PathBuf, sink: StreamSink<ScanEvent>) -> u32 {
+    jobs::submit(JobKind::Scan, move |job| {
+        scan(&root, &sink, job);
+        Ok(())
+    })
+}
+
+pub(crate) fn is_cue_sheet(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("cue"))
+}
+
+/// Quarantine `path` with the `error` reading it failed with, or let it out now that it
+/// read without one
+pub(crate) fn note_read(path: &str, error: Option<&anyhow::Error>) {
+    if !library::is_open() {
+        return;
+    }
+    let noted = library::with_library(|lib| match error {
+        None => lib.release_file(path),
+        Some(e) => lib.quarantine_file(path, &format!("{e:#}")),
+    });
+    if let Err(e) = noted {
+        log::warn!("failed to update the quarantine for {path}: {e:#}");
+    }
+}
+
+/// Size of the file at `path` and an MD5 hash of samples of it, which tell it apart from
+/// other files without reading all of it; returns (size, hash)
+pub(crate) fn identify(path: &Path) -> io::Result<(u64, String)> {
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let last = size.saturating_sub(IDENTITY_SAMPLE_BYTES);
+    let mut hasher = Md5::new();
+    let mut sample = Vec::with_capacity(IDENTITY_SAMPLE_BYTES as usize);
+    for offset in [0, last / 2, last] {
+        sample.clear();
+        file.seek(SeekFrom::Start(offset))?;
+        (&mut file)
+            .take(IDENTITY_SAMPLE_BYTES)
+            .read_to_end(&mut sample)?;
+        hasher.update(&sample);
+    }
+    let hash = hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    Ok((size, hash))
+}
+
+/// A song read from its file, with the audio features measured while reading it
+pub(crate) struct ReadSong {
+    pub song: Song,
+    pub features: Option<TrackFeatures>,
+}
+
+/// Store `songs` as everything the library holds for the file at `path`, with the
+/// features measured for them
+///
+/// A single-song file the library has nothing for yet first takes over the songs of an
+/// identical file that's gone from where the library has it, as after a move nothing
+/// saw, keeping their ids and with them their ratings, plays and playlist entries.
+/// Returns the songs with the ids they're stored under.
+pub(crate) fn store_file_songs(path: &Path, songs: &[ReadSong]) -> anyhow::Result<Vec<Song>> {
+    let file_path = path.to_string_lossy();
+    // Images split by a CUE sheet are found through their sheet instead.
+    let identity = match songs.iter().any(|read| cue::is_track(&read.song)) {
+        true => None,
+        false => identify(path)
+            .inspect_err(|e| log::warn!("failed to identify {file_path}: {e}"))
+            .ok(),
+    };
+    library::with_library(|lib| {
+        if let Some((size, hash)) = &identity {
+            if lib.get_file_songs(&file_path)?.is_empty() {
+                if let Some(from) = lib.find_missing_file(*size, hash)? {
+                    log::info!("{from} was moved to {file_path}");
+                    lib.move_path(&from, &file_path)?;
+                }
+            }
+        }
+        let tracks: Vec<Song> = songs.iter().map(|read| read.song.clone()).collect();
+        let stored = lib.replace_file_songs(&file_path, &tracks)?;
+        if let Some((size, hash)) = &identity {
+            lib.set_file_identity(&file_path, *size, hash)?;
+        }
+        for (song, read) in stored.iter().zip(songs) {
+            let features = read.features;
+            if features.is_some() {
+                let analysis = Analysis {
+                    features,
+                    ..Analysis::default()
+                };
+                lib.set_analysis(&song.id, analysis)?;
+            }
+        }
+        Ok(stored)
+    })
+}
+
+/// Tags of a single-song file, measuring its loudness, tempo and key if no tag has them
+pub(crate) fn read_file(path: &Path) -> anyhow::Result<ReadSong> {
+    let mut song = metadata::read_song(path)?;
+    let features = analysis::complete(&mut song, true);
+    Ok(ReadSong { song, features })
+}
+
+/// Tracks a CUE sheet cuts from `file`; the whole image is measured as their album
+pub(crate) fn read_cue_tracks(sheet: &CueSheet, file: &CueFile) -> anyhow::Result<Vec<ReadSong>> {
+    let mut songs = cue::songs(sheet, file)?;
+    if songs.iter().all(|song| song.album_loudness.is_none()) {
+        match loudness::measure(&file.path) {
+            Ok(lufs) => songs
+                .iter_mut()
+                .for_each(|song| song.album_loudness = Some(lufs)),
+            Err(e) => log::debug!("loudness of {} unavailable: {e}", file.path.display()),
+        }
+    }
+    Ok(songs
+        .into_iter()
+        .map(|mut song| {
+            let features = analysis::complete(&mut song, false);
+            ReadSong { song, features }
+        })
+        .collect())
+}
+
+fn scan(root: &Path, sink: &StreamSink<ScanEvent>, job: &Job) {
+    // A closed sink means nobody is listening any more, which is as good as a cancel.
+    let emit = |event| {
+        if sink.add(event).is_err() {
+            job.cancelled().store(true, Ordering::Relaxed);
+        }
+    };
+    let is_cancelled = || job.is_cancelled();
+
+    let mut files = Vec::new();
+    let mut sheets = Vec::new();
+    let mut errors = 0;
+    for entry in WalkDir::new(root).follow_links(true) {
+        if is_cancelled() {
+            break;
+        }
+        match entry {
+            Ok(entry) if entry.file_type().is_file() && is_supported(entry.path()) => {
+                files.push(entry.into_path());
+                if (files.len() as u32).is_multiple_of(DISCOVERY_REPORT_EVERY) {
+                    emit(ScanEvent::Discovered {
+                        files_found: files.len() as u32,
+                    });
+                }
+            }
+            Ok(entry) if entry.file_type().is_file() && is_cue_sheet(entry.path()) => {
+                sheets.push(entry.into_path());
+            }
+            Ok(_) => {}
+            Err(e) => {
+                errors += 1;
+                emit(ScanEvent::Failed {
+                    path: e
+                        .path()
+                        .map(|p| p.to_string_lossy().into_owned())
+                        .unwrap_or_default(),
+                    message: e.to_string(),
+                });
+            }
+        }
+    }
+
+    let files_found = files.len() as u32;
+    if !is_cancelled() {
+        emit(ScanEvent::Discovered { files_found });
+    }
+
+    // Images split by a CUE sheet, mapped to the sheet and its entry for them
+    let mut images = HashMap::new();
+    let sheets: Vec<(String, CueSheet)> = sheets
+        .into_iter()
+        .filter_map(|path| match cue::read(&path) {
+            Ok(sheet) => Some((path.to_string_lossy().into_owned(), sheet)),
+            Err(e) => {
+                note_read(&path.to_string_lossy(), Some(&e));
+                errors += 1;
+                emit(ScanEvent::Failed {
+                    path: path.to_string_lossy().into_owned(),
+                    message: format!("{e:#}"),
+                });
+                None
+            }
+        })
+        .collect();
+    for (sheet_index, (_, sheet)) in sheets.iter().enumerate() {
+        for (file_index, file) in sheet.files.iter().enumerate() {
+            if !file.tracks.is_empty() {
+                images.insert(file.path.clone(), (sheet_index, file_index));
+            }
+        }
+    }
+
+    let mut files_parsed = 0;
+    for (i, path) in files.into_iter().enumerate() {
+        if is_cancelled() {
+            break;
+        }
+        job.progress(i as u64, Some(files_found as u64));
+        let file_path = path.to_string_lossy();
+        let (read_path, songs) = match images.get(&path) {
+            Some(&(sheet, file)) => {
+                let (sheet_path, sheet) = &sheets[sheet];
+                (
+                    sheet_path.as_str(),
+                    read_cue_tracks(sheet, &sheet.files[file]),
+                )
+            }
+            None => (&*file_path, read_file(&path).map(|song| vec![song])),
+        };
+        note_read(read_path, songs.as_ref().err());
+        match songs {
+            Ok(read) => {
+                let mut stored = None;
+                if library::is_open() {
+                    match store_file_songs(&path, &read) {
+                        Ok(songs) => stored = Some(songs),
+                        Err(e) => log::warn!("failed to store {}: {e}", path.display()),
+                    }
+                }
+                let songs =
+                    stored.unwrap_or_else(|| read.into_iter().map(|read| read.song).collect());
+                files_parsed += 1;
+                for song in songs {
+                    emit(ScanEvent::Parsed {
+                        song,
+                        files_parsed,
+                        files_found,
+                    });
+                }
+            }
+            Err(e) => {
+                errors += 1;
+                emit(ScanEvent::Failed {
+                    path: path.to_string_lossy().into_owned(),
+                    message: e.to_string(),
+                });
+            }
+        }
+    }
+
+    let _ = sink.add(ScanEvent::Finished {
+        files_found,
+        files_parsed,
+        errors,
+        cancelled: is_cancelled(),
+    });
+}
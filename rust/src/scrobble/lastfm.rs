@@ -0,0 +1,127 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Context};
+use md5::{Digest, Md5};
+use serde_json::Value;
+
+use super::{Failure, Listen};
+
+const API_URL: &str = "https://ws.audioscrobbler.com/2.0/";
+
+/// Most listens one `track.scrobble` call takes
+pub(super) const MAX_BATCH: usize = 50;
+
+/// Error codes worth retrying: invalid session, service offline, temporarily
+/// unavailable and rate limit exceeded
+const RETRY_CODES: [i64; 4] = [9, 11, 16, 29];
+
+#[derive(Clone)]
+pub(super) struct Session {
+    pub api_key: String,
+    pub api_secret: String,
+    pub key: String,
+}
+
+impl Session {
+    /// Get a session key with the mobile authentication flow
+    pub fn login(
+        agent: &ureq::Agent,
+        api_key: String,
+        api_secret: String,
+        username: &str,
+        password: &str,
+    ) -> anyhow::Result<Self> {
+        let params = BTreeMap::from([
+            ("method".to_string(), "auth.getMobileSession".to_string()),
+            ("api_key".to_string(), api_key.clone()),
+            ("username".to_string(), username.to_string()),
+            ("password".to_string(), password.to_string()),
+        ]);
+        let response = call(agent, &api_secret, params).map_err(Failure::into_error)?;
+        let key = response["session"]["key"]
+            .as_str()
+            .context("Last.fm sent no session key")?
+            .to_string();
+        Ok(Session {
+            api_key,
+            api_secret,
+            key,
+        })
+    }
+
+    pub fn now_playing(&self, agent: &ureq::Agent, listen: &Listen) -> Result<(), Failure> {
+        let mut params = self.params("track.updateNowPlaying");
+        params.insert("artist".into(), listen.artist.clone());
+        params.insert("track".into(), listen.track.clone());
+        params.insert("duration".into(), listen.duration.to_string());
+        if let Some(album) = &listen.album {
+            params.insert("album".into(), album.clone());
+        }
+        call(agent, &self.api_secret, params).map(drop)
+    }
+
+    pub fn scrobble(&self, agent: &ureq::Agent, listens: &[Listen]) -> Result<(), Failure> {
+        let mut params = self.params("track.scrobble");
+        for (i, listen) in listens.iter().enumerate() {
+            params.insert(format!("artist[{i}]"), listen.artist.clone());
+            params.insert(format!("track[{i}]"), listen.track.clone());
+            params.insert(format!("timestamp[{i}]"), listen.listened_at.to_string());
+            params.insert(format!("duration[{i}]"), listen.duration.to_string());
+            if let Some(album) = &listen.album {
+                params.insert(format!("album[{i}]"), album.clone());
+            }
+        }
+        let response = call(agent, &self.api_secret, params)?;
+        let ignored = response["scrobbles"]["@attr"]["ignored"]
+            .as_i64()
+            .unwrap_or(0);
+        if ignored > 0 {
+            log::info!("Last.fm ignored {ignored} of {} scrobbles", listens.len());
+        }
+        Ok(())
+    }
+
+    fn params(&self, method: &str) -> BTreeMap<String, String> {
+        BTreeMap::from([
+            ("method".to_string(), method.to_string()),
+            ("api_key".to_string(), self.api_key.clone()),
+            ("sk".to_string(), self.key.clone()),
+        ])
+    }
+}
+
+/// Sign and post an API call, returning its JSON response
+fn call(
+    agent: &ureq::Agent,
+    api_secret: &str,
+    mut params: BTreeMap<String, String>,
+) -> Result<Value, Failure> {
+    // The signature covers every parameter in name order, followed by the secret.
+    let mut signed: String = params.iter().map(|(k, v)| format!("{k}{v}")).collect();
+    signed.push_str(api_secret);
+    params.insert("api_sig".into(), format!("{:x}", Md5::digest(signed)));
+    params.insert("format".into(), "json".into());
+    let form: Vec<(&str, &str)> = params
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.as_str()))
+        .collect();
+    let body = match agent.post(API_URL).send_form(&form) {
+        // Errors come with an error status, but the body still says which.
+        Ok(response) | Err(ureq::Error::Status(_, response)) => response.into_string(),
+        Err(e) => return Err(Failure::Retry(e.into())),
+    }
+    .map_err(|e| Failure::Retry(e.into()))?;
+    let response: Value = serde_json::from_str(&body)
+        .map_err(|e| Failure::Retry(anyhow!("unreadable Last.fm response: {e}")))?;
+    if let Some(code) = response["error"].as_i64() {
+        let e = anyhow!(
+            "Last.fm error {code}: {}",
+            response["message"].as_str().unwrap_or_default()
+        );
+        return Err(match RETRY_CODES.contains(&code) {
+            true => Failure::Retry(e),
+            false => Failure::Rejected(e),
+        });
+    }
+    Ok(response)
+}
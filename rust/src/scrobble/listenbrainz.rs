@@ -0,0 +1,103 @@
+use anyhow::{anyhow, ensure};
+use serde_json::{json, Value};
+
+use super::{Failure, Listen};
+
+const API_URL: &str = "https://api.listenbrainz.org/1";
+
+/// Most listens sent in one import
+pub(super) const MAX_BATCH: usize = 100;
+
+/// A ListenBrainz user token
+#[derive(Clone)]
+pub(super) struct Token(pub String);
+
+impl Token {
+    /// Check the token, returning its user name
+    pub fn validate(&self, agent: &ureq::Agent) -> anyhow::Result<String> {
+        let body = agent
+            .get(&format!("{API_URL}/validate-token"))
+            .set("Authorization", &self.header())
+            .call()?
+            .into_string()?;
+        let response: Value = serde_json::from_str(&body)?;
+        ensure!(
+            response["valid"].as_bool() == Some(true),
+            "ListenBrainz doesn't accept this token"
+        );
+        Ok(response["user_name"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string())
+    }
+
+    pub fn now_playing(&self, agent: &ureq::Agent, listen: &Listen) -> Result<(), Failure> {
+        self.submit_listens(agent, "playing_now", vec![payload(listen, false)])
+    }
+
+    pub fn submit(&self, agent: &ureq::Agent, listens: &[Listen]) -> Result<(), Failure> {
+        let listen_type = match listens.len() {
+            1 => "single",
+            _ => "import",
+        };
+        let payload = listens.iter().map(|listen| payload(listen, true)).collect();
+        self.submit_listens(agent, listen_type, payload)
+    }
+
+    fn header(&self) -> String {
+        format!("Token {}", self.0)
+    }
+
+    fn submit_listens(
+        &self,
+        agent: &ureq::Agent,
+        listen_type: &str,
+        payload: Vec<Value>,
+    ) -> Result<(), Failure> {
+        let body = json!({ "listen_type": listen_type, "payload": payload });
+        let result = agent
+            .post(&format!("{API_URL}/submit-listens"))
+            .set("Authorization", &self.header())
+            .set("Content-Type", "application/json")
+            .send_string(&body.to_string());
+        match result {
+            Ok(_) => Ok(()),
+            Err(ureq::Error::Status(code, response)) => {
+                let message = response
+                    .into_string()
+                    .ok()
+                    .and_then(|body| serde_json::from_str::<Value>(&body).ok())
+                    .and_then(|body| body["error"].as_str().map(str::to_string))
+                    .unwrap_or_default();
+                let e = anyhow!("ListenBrainz error {code}: {message}");
+                // A revoked token, rate limiting or server trouble can all clear up.
+                Err(match code {
+                    401 | 429 | 500.. => Failure::Retry(e),
+                    _ => Failure::Rejected(e),
+                })
+            }
+            Err(e) => Err(Failure::Retry(e.into())),
+        }
+    }
+}
+
+fn payload(listen: &Listen, with_time: bool) -> Value {
+    let mut payload = json!({
+        "track_metadata": {
+            "artist_name": listen.artist,
+            "track_name": listen.track,
+            "additional_info": {
+                "duration_ms": listen.duration * 1000,
+                "submission_client": "tunes4r",
+                "submission_client_version": env!("CARGO_PKG_VERSION"),
+            },
+        },
+    });
+    if let Some(album) = &listen.album {
+        payload["track_metadata"]["release_name"] = album.as_str().into();
+    }
+    if with_time {
+        payload["listened_at"] = listen.listened_at.into();
+    }
+    payload
+}
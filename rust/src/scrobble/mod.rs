@@ -0,0 +1,323 @@
+mod lastfm;
+mod listenbrainz;
+
+use std::slice;
+use std::sync::{Mutex, Once};
+use std::thread;
+use std::time::Duration;
+
+use crate::library::{self, now_secs};
+use crate::metadata::{UNKNOWN_ALBUM, UNKNOWN_ARTIST};
+use crate::{runtime, sources, ScrobbleService, Song};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often listens that couldn't be sent are tried again
+const RETRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Songs shorter than this are never scrobbled
+const MIN_DURATION_SECS: u64 = 30;
+
+/// A song is scrobbled once half of it or this many seconds have been heard
+const MAX_LISTEN_SECS: f64 = 240.0;
+
+/// A heard song, as the scrobbling services want it
+#[derive(Clone, Debug)]
+pub(crate) struct Listen {
+    pub artist: String,
+    pub track: String,
+    pub album: Option<String>,
+    /// Duration in whole seconds
+    pub duration: u64,
+    /// When the song started playing, in seconds since the Unix epoch
+    pub listened_at: i64,
+}
+
+impl Listen {
+    /// `None` for songs too short or too sparsely tagged for the services to take
+    fn from_song(song: &Song, listened_at: i64) -> Option<Self> {
+        if song.duration < MIN_DURATION_SECS
+            || song.artist.is_empty()
+            || song.artist == UNKNOWN_ARTIST
+            || song.title.is_empty()
+        {
+            return None;
+        }
+        Some(Listen {
+            artist: song.artist.clone(),
+            track: song.title.clone(),
+            album: (!song.album.is_empty() && song.album != UNKNOWN_ALBUM)
+                .then(|| song.album.clone()),
+            duration: song.duration,
+            listened_at,
+        })
+    }
+}
+
+/// Why a service didn't take a request
+pub(super) enum Failure {
+    /// Offline, server trouble or rejected credentials; worth trying again later
+    Retry(anyhow::Error),
+    /// The service refused the request for good
+    Rejected(anyhow::Error),
+}
+
+impl Failure {
+    fn into_error(self) -> anyhow::Error {
+        match self {
+            Failure::Retry(e) | Failure::Rejected(e) => e,
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Client {
+    LastFm(lastfm::Session),
+    ListenBrainz(listenbrainz::Token),
+}
+
+impl Client {
+    fn service(&self) -> ScrobbleService {
+        match self {
+            Client::LastFm(_) => ScrobbleService::LastFm,
+            Client::ListenBrainz(_) => ScrobbleService::ListenBrainz,
+        }
+    }
+
+    fn max_batch(&self) -> usize {
+        match self {
+            Client::LastFm(_) => lastfm::MAX_BATCH,
+            Client::ListenBrainz(_) => listenbrainz::MAX_BATCH,
+        }
+    }
+
+    fn now_playing(&self, agent: &ureq::Agent, listen: &Listen) -> Result<(), Failure> {
+        match self {
+            Client::LastFm(session) => session.now_playing(agent, listen),
+            Client::ListenBrainz(token) => token.now_playing(agent, listen),
+        }
+    }
+
+    fn submit(&self, agent: &ureq::Agent, listens: &[Listen]) -> Result<(), Failure> {
+        match self {
+            Client::LastFm(session) => session.scrobble(agent, listens),
+            Client::ListenBrainz(token) => token.submit(agent, listens),
+        }
+    }
+}
+
+struct Account {
+    client: Client,
+    enabled: bool,
+}
+
+static ACCOUNTS: Mutex<Vec<Account>> = Mutex::new(Vec::new());
+
+fn agent() -> ureq::Agent {
+    ureq::AgentBuilder::new()
+        .timeout_connect(CONNECT_TIMEOUT)
+        .timeout_read(READ_TIMEOUT)
+        .build()
+}
+
+/// Start scrobbling with `client`, replacing any account for the same service
+fn set_account(client: Client) {
+    let mut accounts = ACCOUNTS.lock().unwrap();
+    accounts.retain(|account| account.client.service() != client.service());
+    accounts.push(Account {
+        client,
+        enabled: true,
+    });
+    drop(accounts);
+    start_retrying();
+    // Listens queued in an earlier session can go out right away.
+    runtime::spawn_blocking(flush);
+}
+
+fn enabled_clients() -> Vec<Client> {
+    ACCOUNTS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|account| account.enabled)
+        .map(|account| account.client.clone())
+        .collect()
+}
+
+pub(crate) fn lastfm_login(
+    api_key: String,
+    api_secret: String,
+    username: &str,
+    password: &str,
+) -> anyhow::Result<String> {
+    let session = lastfm::Session::login(&agent(), api_key, api_secret, username, password)?;
+    let key = session.key.clone();
+    set_account(Client::LastFm(session));
+    Ok(key)
+}
+
+pub(crate) fn set_lastfm_session(api_key: String, api_secret: String, key: String) {
+    set_account(Client::LastFm(lastfm::Session {
+        api_key,
+        api_secret,
+        key,
+    }));
+}
+
+pub(crate) fn listenbrainz_login(token: String) -> anyhow::Result<String> {
+    let token = listenbrainz::Token(token);
+    let user_name = token.validate(&agent())?;
+    set_account(Client::ListenBrainz(token));
+    Ok(user_name)
+}
+
+pub(crate) fn set_listenbrainz_token(token: String) {
+    set_account(Client::ListenBrainz(listenbrainz::Token(token)));
+}
+
+pub(crate) fn set_enabled(service: ScrobbleService, enabled: bool) {
+    let mut accounts = ACCOUNTS.lock().unwrap();
+    for account in accounts.iter_mut() {
+        if account.client.service() == service {
+            account.enabled = enabled;
+        }
+    }
+    drop(accounts);
+    if enabled {
+        runtime::spawn_blocking(flush);
+    }
+}
+
+pub(crate) fn logout(service: ScrobbleService) -> anyhow::Result<()> {
+    ACCOUNTS
+        .lock()
+        .unwrap()
+        .retain(|account| account.client.service() != service);
+    if library::is_open() {
+        library::with_library(|lib| lib.clear_scrobbles(service))?;
+    }
+    Ok(())
+}
+
+/// Seconds of a song that have to be heard before it is scrobbled, if it ever is
+pub(crate) fn listen_threshold(song: &Song) -> Option<f64> {
+    (song.duration >= MIN_DURATION_SECS).then(|| (song.duration as f64 / 2.0).min(MAX_LISTEN_SECS))
+}
+
+/// Tell the enabled services, and the media server a song comes from, that it just started
+pub(crate) fn now_playing(song: &Song) {
+    sources::scrobble(song, None);
+    let clients = enabled_clients();
+    let Some(listen) = Listen::from_song(song, now_secs()) else {
+        return;
+    };
+    if clients.is_empty() {
+        return;
+    }
+    runtime::spawn_blocking(move || {
+        let agent = agent();
+        for client in clients {
+            if let Err(e) = client.now_playing(&agent, &listen) {
+                log::debug!(
+                    "failed to update now playing on {:?}: {}",
+                    client.service(),
+                    e.into_error()
+                );
+            }
+        }
+    });
+}
+
+/// Scrobble a song that started playing at `listened_at` to the enabled services
+///
+/// Listens go through the queue in the library so they survive being offline. Songs
+/// of a media server are reported to it as well, just the once.
+pub(crate) fn scrobble(song: &Song, listened_at: i64) {
+    sources::scrobble(song, Some(listened_at));
+    let clients = enabled_clients();
+    let Some(listen) = Listen::from_song(song, listened_at) else {
+        return;
+    };
+    if clients.is_empty() {
+        return;
+    }
+    runtime::spawn_blocking(move || {
+        if !library::is_open() {
+            // Without the library there's nowhere to queue, so this is the only try.
+            let agent = agent();
+            for client in clients {
+                if let Err(e) = client.submit(&agent, slice::from_ref(&listen)) {
+                    log::warn!(
+                        "failed to scrobble to {:?}: {}",
+                        client.service(),
+                        e.into_error()
+                    );
+                }
+            }
+            return;
+        }
+        for client in &clients {
+            if let Err(e) =
+                library::with_library(|lib| lib.queue_scrobble(client.service(), &listen))
+            {
+                log::warn!("failed to queue scrobble for {:?}: {e}", client.service());
+            }
+        }
+        flush();
+    });
+}
+
+/// Keep retrying queued listens in the background, so they go out once back online
+fn start_retrying() {
+    static STARTED: Once = Once::new();
+    STARTED.call_once(|| {
+        let spawned = thread::Builder::new()
+            .name("tunes4r-scrobble".into())
+            .spawn(|| loop {
+                thread::sleep(RETRY_INTERVAL);
+                flush();
+            });
+        if let Err(e) = spawned {
+            log::error!("failed to start scrobble retry thread: {e}");
+        }
+    });
+}
+
+/// Send queued listens to every enabled service, oldest first
+fn flush() {
+    static FLUSHING: Mutex<()> = Mutex::new(());
+    // Another flush is already sending; whatever it misses goes out next time.
+    let Ok(_flushing) = FLUSHING.try_lock() else {
+        return;
+    };
+    if !library::is_open() {
+        return;
+    }
+    let agent = agent();
+    for client in enabled_clients() {
+        if let Err(e) = flush_client(&agent, &client) {
+            log::info!("listens for {:?} stay queued: {e}", client.service());
+        }
+    }
+}
+
+fn flush_client(agent: &ureq::Agent, client: &Client) -> anyhow::Result<()> {
+    let service = client.service();
+    loop {
+        let queued =
+            library::with_library(|lib| lib.queued_scrobbles(service, client.max_batch()))?;
+        if queued.is_empty() {
+            return Ok(());
+        }
+        let (ids, listens): (Vec<i64>, Vec<Listen>) = queued.into_iter().unzip();
+        match client.submit(agent, &listens) {
+            Ok(()) => {}
+            Err(Failure::Retry(e)) => return Err(e),
+            Err(Failure::Rejected(e)) => {
+                log::warn!("{service:?} rejected {} listens: {e}", ids.len());
+            }
+        }
+        library::with_library(|lib| lib.remove_scrobbles(&ids))?;
+    }
+}
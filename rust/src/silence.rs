@@ -0,0 +1,165 @@
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use rodio::Source;
+
+use crate::decoder::SymphoniaSource;
+use crate::jobs::{self, Job};
+use crate::{cue, library, JobKind, SilenceEvent, Song, StreamSink, TrackSilence};
+
+/// How often, in frames, measuring checks whether it was cancelled
+const CANCEL_CHECK_FRAMES: u64 = 1 << 16;
+
+/// What counts as silence, set by `set_silence_detection`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct SilenceSettings {
+    /// Level in dBFS no sample may exceed
+    pub threshold_db: f32,
+    /// Shorter stretches of silence are left alone
+    pub min_duration_secs: f32,
+}
+
+static SETTINGS: Mutex<SilenceSettings> = Mutex::new(SilenceSettings {
+    threshold_db: -60.0,
+    min_duration_secs: 0.5,
+});
+
+pub(crate) fn set_settings(threshold_db: f32, min_duration_secs: f32) {
+    *SETTINGS.lock().unwrap() = SilenceSettings {
+        threshold_db: threshold_db.min(0.0),
+        min_duration_secs: min_duration_secs.max(0.0),
+    };
+}
+
+pub(crate) fn settings() -> SilenceSettings {
+    *SETTINGS.lock().unwrap()
+}
+
+/// Silence of `song` as stored in the library under the current settings, if it has
+/// been measured
+pub(crate) fn stored(song: &Song) -> Option<TrackSilence> {
+    if !library::is_open() {
+        return None;
+    }
+    library::with_library(|lib| lib.get_silence(&song.id, settings()))
+        .inspect_err(|e| log::warn!("failed to read silence of {}: {e}", song.id))
+        .ok()?
+}
+
+/// Silence of `song`, measured and stored in the library unless it already was
+pub(crate) fn silence_of(song: &Song) -> anyhow::Result<TrackSilence> {
+    if let Some(silence) = stored(song) {
+        return Ok(silence);
+    }
+    let settings = settings();
+    let silence = detect(song, settings, || false)?;
+    if library::is_open() {
+        library::with_library(|lib| lib.set_silence(&song.id, settings, silence))?;
+    }
+    Ok(silence)
+}
+
+/// Decode `song` and measure the silence at either end of it
+///
+/// A song that is silent throughout isn't trimmed at all. Stops early, reporting no
+/// silence, once `cancelled` returns true.
+fn detect(
+    song: &Song,
+    settings: SilenceSettings,
+    cancelled: impl Fn() -> bool,
+) -> anyhow::Result<TrackSilence> {
+    let mut source = SymphoniaSource::open(song.file_path.as_ref())?;
+    let channels = source.channels().max(1) as usize;
+    let sample_rate = source.sample_rate() as f64;
+    if cue::is_track(song) {
+        source
+            .try_seek(Duration::from_secs_f64(song.start_offset))
+            .map_err(|e| anyhow::anyhow!("cannot seek to the start of {}: {e}", song.title))?;
+    }
+    let length = song
+        .end_offset
+        .map(|end| ((end - song.start_offset).max(0.0) * sample_rate) as u64);
+    let threshold = 10f32.powf(settings.threshold_db / 20.0);
+
+    let mut frames = 0u64;
+    let mut first_loud = None;
+    let mut last_loud = 0;
+    loop {
+        if length.is_some_and(|length| frames >= length) {
+            break;
+        }
+        let mut loud = false;
+        let mut read = 0;
+        for sample in source.by_ref().take(channels) {
+            loud |= sample.abs() > threshold;
+            read += 1;
+        }
+        if read < channels {
+            break;
+        }
+        if loud {
+            first_loud.get_or_insert(frames);
+            last_loud = frames;
+        }
+        frames += 1;
+        if frames.is_multiple_of(CANCEL_CHECK_FRAMES) && cancelled() {
+            return Ok(TrackSilence::default());
+        }
+    }
+
+    let Some(first_loud) = first_loud else {
+        return Ok(TrackSilence::default());
+    };
+    let trim = |silent_frames: u64| {
+        let secs = silent_frames as f64 / sample_rate;
+        match secs >= settings.min_duration_secs as f64 {
+            true => secs,
+            false => 0.0,
+        }
+    };
+    Ok(TrackSilence {
+        leading_secs: trim(first_loud),
+        trailing_secs: trim(frames - last_loud - 1),
+    })
+}
+
+/// Queue measuring every library song not yet measured under the current settings as
+/// a background job, returning its id for `jobs::cancel`
+pub(crate) fn start(sink: StreamSink<SilenceEvent>) -> u32 {
+    jobs::submit(JobKind::Silence, move |job| {
+        let (event, result) = match analyze(&sink, job) {
+            _ if job.is_cancelled() => (SilenceEvent::Cancelled, Ok(())),
+            Ok(analyzed) => (SilenceEvent::Finished { analyzed }, Ok(())),
+            Err(e) => {
+                let message = format!("{e:#}");
+                (SilenceEvent::Failed { message }, Err(e))
+            }
+        };
+        let _ = sink.add(event);
+        result
+    })
+}
+
+fn analyze(sink: &StreamSink<SilenceEvent>, job: &Job) -> anyhow::Result<u32> {
+    let settings = settings();
+    let songs = library::with_library(|lib| lib.get_songs_without_silence(settings))?;
+    let total = songs.len() as u32;
+    let mut analyzed = 0;
+    for (i, song) in songs.iter().enumerate() {
+        match detect(song, settings, || job.is_cancelled()) {
+            _ if job.is_cancelled() => break,
+            Ok(silence) => {
+                library::with_library(|lib| lib.set_silence(&song.id, settings, silence))?;
+                analyzed += 1;
+            }
+            Err(e) => log::debug!("no silence measured for {}: {e}", song.file_path),
+        }
+        let checked = i as u32 + 1;
+        if sink.add(SilenceEvent::Progress { checked, total }).is_err() {
+            job.cancelled().store(true, Ordering::Relaxed);
+        }
+        job.progress(checked as u64, Some(total as u64));
+    }
+    Ok(analyzed)
+}
@@ -0,0 +1,83 @@
+use std::fmt;
+use std::path::Path;
+
+use md5::{Digest, Md5};
+use rusqlite::types::{FromSql, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+
+use crate::{scanner, sources, SongId};
+
+impl SongId {
+    /// Id for a song first seen at `path`, track `track` of a CUE sheet image or the
+    /// whole file if `None`, from the path and a sampled hash of the file
+    ///
+    /// The library keeps the id a song was stored under through retagging and moves,
+    /// so the id `Song`s read from files carry is only used for ones it doesn't have.
+    pub(crate) fn for_file(path: &Path, track: Option<u32>) -> SongId {
+        // Hashing a remote file would download parts of it.
+        let content = match sources::is_remote(path) {
+            true => None,
+            false => scanner::identify(path).ok().map(|(_, hash)| hash),
+        };
+        let mut hasher = Md5::new();
+        hasher.update(content.unwrap_or_default());
+        hasher.update([0]);
+        hasher.update(path.to_string_lossy().as_bytes());
+        if let Some(track) = track {
+            hasher.update(format!("#{track}"));
+        }
+        SongId::from_digest(hasher)
+    }
+
+    /// Another id for the song, for when another song already has this one; each
+    /// `attempt` gives a different one
+    pub(crate) fn reissued(&self, attempt: u32) -> SongId {
+        let mut hasher = Md5::new();
+        hasher.update(self.0.as_bytes());
+        hasher.update(attempt.to_le_bytes());
+        SongId::from_digest(hasher)
+    }
+
+    /// The first half of the digest is plenty; the library makes sure ids are unique.
+    fn from_digest(hasher: Md5) -> SongId {
+        let digest = hasher.finalize();
+        SongId(digest[..8].iter().map(|b| format!("{b:02x}")).collect())
+    }
+
+    /// Whether the id is one made by `for_file` or `reissued`, rather than the path
+    /// versions before them stored songs under
+    pub(crate) fn is_hashed(&self) -> bool {
+        self.0.len() == 16
+            && self
+                .0
+                .bytes()
+                .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for SongId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<String> for SongId {
+    fn from(id: String) -> Self {
+        SongId(id)
+    }
+}
+
+impl ToSql for SongId {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        self.0.to_sql()
+    }
+}
+
+impl FromSql for SongId {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        String::column_result(value).map(SongId)
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use md5::{Digest, Md5};
+use walkdir::WalkDir;
+
+use crate::{library, StorageKind};
+
+/// Most bytes of remote audio kept on disk unless `set_max_bytes` says otherwise
+const DEFAULT_MAX_BYTES: u64 = 1024 * 1024 * 1024;
+
+static MAX_BYTES: AtomicU64 = AtomicU64::new(DEFAULT_MAX_BYTES);
+
+/// Cache of the open library, replaced when another library is opened
+static CACHE: Mutex<Option<Arc<BlockCache>>> = Mutex::new(None);
+
+/// Limit the block cache to `max_bytes`, evicting the least recently used blocks now
+/// if it's over
+pub(crate) fn set_max_bytes(max_bytes: u64) {
+    MAX_BYTES.store(max_bytes, Ordering::Relaxed);
+    if let Some(cache) = CACHE.lock().unwrap().as_ref() {
+        cache.index(|index| index.evict(max_bytes));
+    }
+}
+
+/// Delete every block of the open library's cache
+pub(crate) fn clear() {
+    if let Some(cache) = cache() {
+        cache.index(|index| index.evict(0));
+    }
+}
+
+/// The block cache next to the open library's database, if a library is open
+pub(super) fn cache() -> Option<Arc<BlockCache>> {
+    let dir = library::cache_dir(StorageKind::RemoteAudio)?;
+    let mut cache = CACHE.lock().unwrap();
+    match cache.as_ref() {
+        Some(current) if current.dir == dir => Some(current.clone()),
+        _ => Some(cache.insert(Arc::new(BlockCache::new(dir))).clone()),
+    }
+}
+
+/// Cache key of one version of the file at `uri`
+pub(super) fn key(uri: &str, size: u64, version: Option<&str>) -> String {
+    let mut hasher = Md5::new();
+    hasher.update(uri.as_bytes());
+    hasher.update(size.to_le_bytes());
+    hasher.update(version.unwrap_or("").as_bytes());
+    hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Blocks of remote files on disk, one file per block under a folder per file version,
+/// evicted least recently used first
+///
+/// Modification times record use, so the order survives restarts.
+pub(super) struct BlockCache {
+    dir: PathBuf,
+    index: Mutex<Index>,
+}
+
+#[derive(Default)]
+struct Index {
+    /// Read from disk on first use
+    loaded: bool,
+    blocks: HashMap<PathBuf, Block>,
+    total: u64,
+}
+
+struct Block {
+    len: u64,
+    used: SystemTime,
+}
+
+impl BlockCache {
+    fn new(dir: PathBuf) -> Self {
+        BlockCache {
+            dir,
+            index: Mutex::default(),
+        }
+    }
+
+    fn path(&self, key: &str, index: u64) -> PathBuf {
+        self.dir.join(key).join(index.to_string())
+    }
+
+    pub fn contains(&self, key: &str, index: u64) -> bool {
+        let path = self.path(key, index);
+        self.index(|index| index.blocks.contains_key(&path))
+    }
+
+    pub fn get(&self, key: &str, index: u64) -> Option<Vec<u8>> {
+        let path = self.path(key, index);
+        if !self.index(|index| index.blocks.contains_key(&path)) {
+            return None;
+        }
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(e) => {
+                log::debug!("dropping unreadable cache block {}: {e}", path.display());
+                self.index(|index| index.remove(&path));
+                return None;
+            }
+        };
+        let now = SystemTime::now();
+        if let Err(e) = File::options()
+            .write(true)
+            .open(&path)
+            .and_then(|file| file.set_modified(now))
+        {
+            log::debug!("couldn't mark {} as used: {e}", path.display());
+        }
+        self.index(|index| {
+            if let Some(block) = index.blocks.get_mut(&path) {
+                block.used = now;
+            }
+        });
+        Some(data)
+    }
+
+    /// Store a block, evicting older ones if the cache grows past its limit
+    ///
+    /// The cache is best effort: a failed write is logged and the block fetched again
+    /// next time.
+    pub fn put(&self, key: &str, index: u64, data: &[u8]) {
+        let path = self.path(key, index);
+        let partial = path.with_extension("part");
+        let written = path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::write(&partial, data))
+            .and_then(|_| fs::rename(&partial, &path));
+        if let Err(e) = written {
+            log::warn!("couldn't cache {}: {e}", path.display());
+            let _ = fs::remove_file(&partial);
+            return;
+        }
+        self.index(|index| {
+            index.remove(&path);
+            index.total += data.len() as u64;
+            index.blocks.insert(
+                path,
+                Block {
+                    len: data.len() as u64,
+                    used: SystemTime::now(),
+                },
+            );
+            index.evict(MAX_BYTES.load(Ordering::Relaxed));
+        });
+    }
+
+    fn index<T>(&self, f: impl FnOnce(&mut Index) -> T) -> T {
+        let mut index = self.index.lock().unwrap();
+        if !index.loaded {
+            index.load(&self.dir);
+        }
+        f(&mut index)
+    }
+}
+
+impl Index {
+    fn load(&mut self, dir: &Path) {
+        self.loaded = true;
+        for entry in WalkDir::new(dir).min_depth(2).max_depth(2) {
+            let Ok(entry) = entry else { continue };
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            if !metadata.is_file() {
+                continue;
+            }
+            if entry.path().extension().is_some_and(|e| e == "part") {
+                let _ = fs::remove_file(entry.path());
+                continue;
+            }
+            self.total += metadata.len();
+            self.blocks.insert(
+                entry.into_path(),
+                Block {
+                    len: metadata.len(),
+                    used: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                },
+            );
+        }
+        log::debug!(
+            "remote block cache in {} holds {} bytes",
+            dir.display(),
+            self.total
+        );
+    }
+
+    fn remove(&mut self, path: &Path) {
+        if let Some(block) = self.blocks.remove(path) {
+            self.total -= block.len;
+        }
+    }
+
+    /// Delete least recently used blocks until the cache is a tenth below `max_bytes`,
+    /// so it isn't trimmed again with every block stored
+    fn evict(&mut self, max_bytes: u64) {
+        if self.total <= max_bytes {
+            return;
+        }
+        let target = max_bytes - max_bytes / 10;
+        let mut blocks: Vec<(SystemTime, PathBuf)> = self
+            .blocks
+            .iter()
+            .map(|(path, block)| (block.used, path.clone()))
+            .collect();
+        blocks.sort();
+        for (_, path) in blocks {
+            if self.total <= target {
+                break;
+            }
+            if let Err(e) = fs::remove_file(&path) {
+                log::debug!("couldn't evict {}: {e}", path.display());
+            }
+            // Only succeeds once the last block of the file version is gone.
+            if let Some(folder) = path.parent() {
+                let _ = fs::remove_dir(folder);
+            }
+            self.remove(&path);
+        }
+    }
+}
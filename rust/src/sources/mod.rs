@@ -0,0 +1,589 @@
+mod cache;
+mod s3;
+mod subsonic;
+mod webdav;
+
+pub(crate) use cache::{clear as clear_cache, set_max_bytes};
+
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Context;
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC};
+use quick_xml::events::Event;
+use symphonia::core::io::MediaSource;
+
+use self::cache::BlockCache;
+use crate::jobs::{self, Job};
+use crate::{
+    library, metadata, runtime, scanner, JobKind, ScanEvent, Song, SongId, SourceConfig, StreamSink,
+};
+
+/// Prefix of the paths songs from remote sources are stored under:
+/// `source://<source id>/<path below the source's root>`
+const SCHEME: &str = "source://";
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Bytes fetched and cached at a time while decoding
+const BLOCK_SIZE: u64 = 256 * 1024;
+
+/// Bytes fetched at a time while reading tags, which only need the ends of a file
+const TAG_BLOCK_SIZE: u64 = 64 * 1024;
+
+/// Blocks fetched ahead of the decoder, so playback doesn't wait on the network
+const READ_AHEAD_BLOCKS: u64 = 8;
+
+/// Characters left as they are in URL paths: RFC 3986's unreserved ones, as S3 signing
+/// requires
+pub(super) const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// A remote file's size and whatever identifies its current contents
+pub(super) struct Stat {
+    pub size: u64,
+    /// ETag or modification date, so an edited file isn't served from the cache
+    pub version: Option<String>,
+}
+
+/// A file a backend lists
+pub(super) struct Listed {
+    /// `/`-separated, relative to the source's root
+    pub path: String,
+    /// Tags and favorite mark as the server reports them, for backends that index their
+    /// files themselves; other files have their tags read
+    pub song: Option<Song>,
+}
+
+/// A remote store the library can index and stream from
+///
+/// The provided methods are for media servers, which know who is listening; for plain
+/// file stores they do nothing.
+pub(super) trait Backend: Send + Sync {
+    /// Every audio file of the source
+    fn list(&self) -> anyhow::Result<Vec<Listed>>;
+
+    fn stat(&self, path: &str) -> anyhow::Result<Stat>;
+
+    /// Bytes `range` of the file at `path`
+    fn read(&self, path: &str, range: Range<u64>) -> anyhow::Result<Vec<u8>>;
+
+    /// Check the server is reachable and takes the credentials
+    fn ping(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Mark the file at `path` as a favorite on the server, or clear the mark
+    fn set_starred(&self, _path: &str, _starred: bool) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Report the file at `path` as now playing, or as heard once `listened_at` is given
+    fn scrobble(&self, _path: &str, _listened_at: Option<i64>) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Backends of the sources used so far, by source id
+static BACKENDS: Mutex<Option<HashMap<i64, Arc<dyn Backend>>>> = Mutex::new(None);
+
+fn agent() -> ureq::Agent {
+    ureq::AgentBuilder::new()
+        .timeout_connect(CONNECT_TIMEOUT)
+        .timeout_read(READ_TIMEOUT)
+        .build()
+}
+
+fn connect(config: &SourceConfig) -> anyhow::Result<Arc<dyn Backend>> {
+    Ok(match config {
+        SourceConfig::WebDav { .. } => Arc::new(webdav::WebDav::new(agent(), config)?),
+        SourceConfig::S3 { .. } => Arc::new(s3::S3::new(agent(), config)?),
+        SourceConfig::Subsonic { .. } => Arc::new(subsonic::Subsonic::new(agent(), config)?),
+    })
+}
+
+/// Fail early for settings no backend could work with, such as a malformed URL, or
+/// that a media server turns down
+pub(crate) fn check(config: &SourceConfig) -> anyhow::Result<()> {
+    connect(config)?.ping()
+}
+
+fn backend(id: i64) -> anyhow::Result<Arc<dyn Backend>> {
+    if let Some(backend) = BACKENDS.lock().unwrap().as_ref().and_then(|b| b.get(&id)) {
+        return Ok(backend.clone());
+    }
+    let source = library::with_library(|lib| lib.get_library_source(id))?
+        .with_context(|| format!("no library source with id {id}"))?;
+    let backend = connect(&source.config)?;
+    BACKENDS
+        .lock()
+        .unwrap()
+        .get_or_insert_with(HashMap::new)
+        .insert(id, backend.clone());
+    Ok(backend)
+}
+
+/// Drop the backend of a source that was removed
+pub(crate) fn forget(id: i64) {
+    if let Some(backends) = BACKENDS.lock().unwrap().as_mut() {
+        backends.remove(&id);
+    }
+}
+
+/// Drop the backends of every source, whose ids only mean something in the library
+/// that was open
+pub(crate) fn forget_all() {
+    BACKENDS.lock().unwrap().take();
+}
+
+/// Tell the server a song of one of its sources was starred or unstarred, in the
+/// background; other songs are left alone
+pub(crate) fn set_starred(song: &Song, starred: bool) {
+    let Some((id, path)) = parse(Path::new(&song.file_path)) else {
+        return;
+    };
+    let path = path.to_string();
+    runtime::spawn_blocking(move || {
+        if let Err(e) = backend(id).and_then(|backend| backend.set_starred(&path, starred)) {
+            log::warn!("couldn't update the star of {path} in library source {id}: {e:#}");
+        }
+    });
+}
+
+/// Report a song of one of the sources to its server as now playing, or as heard once
+/// `listened_at` is given, in the background
+pub(crate) fn scrobble(song: &Song, listened_at: Option<i64>) {
+    let Some((id, path)) = parse(Path::new(&song.file_path)) else {
+        return;
+    };
+    let path = path.to_string();
+    runtime::spawn_blocking(move || {
+        if let Err(e) = backend(id).and_then(|backend| backend.scrobble(&path, listened_at)) {
+            log::debug!("couldn't scrobble {path} to library source {id}: {e:#}");
+        }
+    });
+}
+
+/// Library path of the file at `path` in source `id`
+pub(crate) fn uri(id: i64, path: &str) -> String {
+    format!("{SCHEME}{id}/{path}")
+}
+
+pub(crate) fn is_remote(path: &Path) -> bool {
+    path.to_str().is_some_and(|p| p.starts_with(SCHEME))
+}
+
+/// Source id and path within it of a library path made by `uri`
+fn parse(path: &Path) -> Option<(i64, &str)> {
+    let (id, path) = path.to_str()?.strip_prefix(SCHEME)?.split_once('/')?;
+    Some((id.parse().ok()?, path))
+}
+
+/// Walk `xml`, calling `on_end` as each element closes with the local names of the
+/// elements open at that point, innermost last, and the text directly inside it
+pub(crate) fn parse_xml(xml: &str, mut on_end: impl FnMut(&[String], &str)) -> anyhow::Result<()> {
+    let mut reader = quick_xml::Reader::from_str(xml);
+    let mut open: Vec<String> = Vec::new();
+    let mut texts: Vec<String> = Vec::new();
+    loop {
+        match reader.read_event().context("malformed XML response")? {
+            Event::Start(start) => {
+                open.push(String::from_utf8_lossy(start.local_name().as_ref()).into_owned());
+                texts.push(String::new());
+            }
+            Event::Empty(empty) => {
+                open.push(String::from_utf8_lossy(empty.local_name().as_ref()).into_owned());
+                on_end(&open, "");
+                open.pop();
+            }
+            Event::Text(text) => {
+                if let Some(current) = texts.last_mut() {
+                    current.push_str(&text.xml10_content()?);
+                }
+            }
+            Event::CData(data) => {
+                if let Some(current) = texts.last_mut() {
+                    current.push_str(&data.decode()?);
+                }
+            }
+            Event::GeneralRef(reference) => {
+                let Some(current) = texts.last_mut() else {
+                    continue;
+                };
+                let name = reference.decode()?;
+                match reference.resolve_char_ref()? {
+                    Some(c) => current.push(c),
+                    None => current.push_str(
+                        quick_xml::escape::resolve_predefined_entity(&name).unwrap_or(""),
+                    ),
+                }
+            }
+            Event::End(_) => {
+                let text = texts.pop().unwrap_or_default();
+                on_end(&open, text.trim());
+                open.pop();
+            }
+            Event::Eof => return Ok(()),
+            _ => {}
+        }
+    }
+}
+
+/// Bytes `range` of the response to `request`, whether or not the server honours the
+/// `Range` header
+pub(super) fn read_range(request: ureq::Request, range: Range<u64>) -> anyhow::Result<Vec<u8>> {
+    let url = request.url().to_string();
+    let response = request
+        .set("Range", &format!("bytes={}-{}", range.start, range.end - 1))
+        .call()
+        .with_context(|| format!("failed to fetch {url}"))?;
+    // 200 instead of 206 Partial Content: the server ignored the range and sends it all.
+    let whole = response.status() == 200;
+    let mut reader = response.into_reader();
+    if whole {
+        io::copy(&mut reader.by_ref().take(range.start), &mut io::sink())?;
+    }
+    let mut bytes = Vec::with_capacity((range.end - range.start) as usize);
+    reader
+        .take(range.end - range.start)
+        .read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// A file of a remote source, read through the block cache
+///
+/// Implements `Read` and `Seek` for tag readers and `MediaSource` for the decoder.
+pub(crate) struct RemoteFile {
+    backend: Arc<dyn Backend>,
+    path: String,
+    len: u64,
+    pos: u64,
+    block_size: u64,
+    /// With the key of this version of the file; `None` reads around the cache
+    cache: Option<(Arc<BlockCache>, String)>,
+    block: Option<(u64, Arc<Vec<u8>>)>,
+    read_ahead: Option<ReadAhead>,
+}
+
+/// Asks a helper thread to fetch blocks into the cache before they're read
+struct ReadAhead {
+    requests: SyncSender<u64>,
+    /// Blocks already asked for
+    requested: Range<u64>,
+}
+
+impl RemoteFile {
+    /// Open a file for decoding, fetching and caching it sequentially ahead of the reader
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let mut file = Self::open_with(path, BLOCK_SIZE)?;
+        if let Some((cache, key)) = &file.cache {
+            let (requests, rx) = mpsc::sync_channel(READ_AHEAD_BLOCKS as usize);
+            let backend = file.backend.clone();
+            let (cache, key) = (cache.clone(), key.clone());
+            let (path, len) = (file.path.clone(), file.len);
+            thread::Builder::new()
+                .name("tunes4r-read-ahead".into())
+                .spawn(move || read_ahead(rx, backend, &path, len, &cache, &key))
+                .context("failed to spawn read-ahead thread")?;
+            file.read_ahead = Some(ReadAhead {
+                requests,
+                requested: 0..0,
+            });
+        }
+        Ok(file)
+    }
+
+    /// Open a file to read its tags, in small blocks that bypass the cache
+    pub fn open_for_tags(path: &Path) -> anyhow::Result<Self> {
+        let mut file = Self::open_with(path, TAG_BLOCK_SIZE)?;
+        file.cache = None;
+        Ok(file)
+    }
+
+    /// Open a file to copy it whole, in decoding-sized blocks that bypass the cache
+    pub fn open_for_download(path: &Path) -> anyhow::Result<Self> {
+        let mut file = Self::open_with(path, BLOCK_SIZE)?;
+        file.cache = None;
+        Ok(file)
+    }
+
+    fn open_with(uri: &Path, block_size: u64) -> anyhow::Result<Self> {
+        let (id, path) =
+            parse(uri).with_context(|| format!("not a remote file: {}", uri.display()))?;
+        let backend = backend(id)?;
+        let stat = backend
+            .stat(path)
+            .with_context(|| format!("failed to look up {}", uri.display()))?;
+        let cache = cache::cache().map(|cache| {
+            let key = cache::key(&uri.to_string_lossy(), stat.size, stat.version.as_deref());
+            (cache, key)
+        });
+        Ok(RemoteFile {
+            backend,
+            path: path.to_string(),
+            len: stat.size,
+            pos: 0,
+            block_size,
+            cache,
+            block: None,
+            read_ahead: None,
+        })
+    }
+
+    /// Block `index`, from the cache if it's there
+    fn block(&mut self, index: u64) -> io::Result<Arc<Vec<u8>>> {
+        if let Some((current, data)) = &self.block {
+            if *current == index {
+                return Ok(data.clone());
+            }
+        }
+        if let Some(read_ahead) = &mut self.read_ahead {
+            read_ahead.request(index);
+        }
+        let cached = self
+            .cache
+            .as_ref()
+            .and_then(|(cache, key)| cache.get(key, index));
+        let data = match cached {
+            Some(data) => data,
+            None => {
+                let data = fetch(&*self.backend, &self.path, self.len, self.block_size, index)
+                    .map_err(io::Error::other)?;
+                if let Some((cache, key)) = &self.cache {
+                    cache.put(key, index, &data);
+                }
+                data
+            }
+        };
+        let data = Arc::new(data);
+        self.block = Some((index, data.clone()));
+        Ok(data)
+    }
+}
+
+impl ReadAhead {
+    /// Ask for the blocks following `index` that haven't been asked for yet
+    fn request(&mut self, index: u64) {
+        let wanted = index + 1..index + 1 + READ_AHEAD_BLOCKS;
+        if !self.requested.contains(&wanted.start) {
+            self.requested = wanted.start..wanted.start;
+        }
+        while self.requested.end < wanted.end {
+            // A full queue means the helper is behind anyway; reads fetch for themselves.
+            if self.requests.try_send(self.requested.end).is_err() {
+                break;
+            }
+            self.requested.end += 1;
+        }
+    }
+}
+
+/// Block `index` of the file at `path`, `len` bytes long
+fn fetch(
+    backend: &dyn Backend,
+    path: &str,
+    len: u64,
+    block_size: u64,
+    index: u64,
+) -> anyhow::Result<Vec<u8>> {
+    let start = index * block_size;
+    let end = (start + block_size).min(len);
+    let data = backend.read(path, start..end)?;
+    anyhow::ensure!(
+        data.len() as u64 == end - start,
+        "{path} ended early: got {} of {} bytes at {start}",
+        data.len(),
+        end - start
+    );
+    Ok(data)
+}
+
+/// Helper thread of `ReadAhead`: fetch requested blocks the cache doesn't have, until
+/// the file is dropped
+fn read_ahead(
+    requests: Receiver<u64>,
+    backend: Arc<dyn Backend>,
+    path: &str,
+    len: u64,
+    cache: &BlockCache,
+    key: &str,
+) {
+    for index in requests {
+        if index * BLOCK_SIZE >= len || cache.contains(key, index) {
+            continue;
+        }
+        match fetch(&*backend, path, len, BLOCK_SIZE, index) {
+            Ok(data) => cache.put(key, index, &data),
+            Err(e) => log::debug!("read-ahead of {path} failed: {e:#}"),
+        }
+    }
+}
+
+impl Read for RemoteFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let index = self.pos / self.block_size;
+        let block = self.block(index)?;
+        let offset = (self.pos - index * self.block_size) as usize;
+        let len = buf.len().min(block.len().saturating_sub(offset));
+        buf[..len].copy_from_slice(&block[offset..offset + len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Seek for RemoteFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        self.pos = target.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek before the start of the file",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+impl MediaSource for RemoteFile {
+    fn is_seekable(&self) -> bool {
+        true
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        Some(self.len)
+    }
+}
+
+/// Queue indexing the files of source `id` as a background job, returning its id for
+/// `jobs::cancel`
+///
+/// Files already in the library are left as they are unless `rescan` is set; songs of
+/// files that are gone from the source are removed.
+pub(crate) fn scan(id: i64, rescan: bool, sink: StreamSink<ScanEvent>) -> u32 {
+    jobs::submit(JobKind::Scan, move |job| {
+        let result = scan_source(id, rescan, &sink, job);
+        if let Err(e) = &result {
+            let _ = sink.add(ScanEvent::Failed {
+                path: uri(id, ""),
+                message: format!("{e:#}"),
+            });
+        }
+        result.map(drop)
+    })
+}
+
+fn scan_source(
+    id: i64,
+    rescan: bool,
+    sink: &StreamSink<ScanEvent>,
+    job: &Job,
+) -> anyhow::Result<()> {
+    let emit = |event| {
+        if sink.add(event).is_err() {
+            job.cancelled().store(true, Ordering::Relaxed);
+        }
+    };
+    let files: Vec<(String, Option<Song>)> = backend(id)?
+        .list()
+        .with_context(|| format!("failed to list library source {id}"))?
+        .into_iter()
+        .filter(|listed| scanner::is_supported(Path::new(&listed.path)))
+        .map(|listed| (uri(id, &listed.path), listed.song))
+        .collect();
+    let files_found = files.len() as u32;
+    emit(ScanEvent::Discovered { files_found });
+
+    let known = library::with_library(|lib| lib.get_source_file_paths(id))?;
+    let listed: HashSet<&str> = files.iter().map(|(file, _)| file.as_str()).collect();
+    let gone: Vec<&String> = known
+        .iter()
+        .filter(|p| !listed.contains(p.as_str()))
+        .collect();
+    if !gone.is_empty() {
+        library::with_library(|lib| {
+            for path in &gone {
+                lib.remove_path(path)?;
+            }
+            Ok(())
+        })?;
+        log::info!("removed {} songs gone from library source {id}", gone.len());
+    }
+
+    let (mut files_parsed, mut errors) = (0, 0);
+    for (i, (file, listed)) in files.iter().enumerate() {
+        if job.is_cancelled() {
+            break;
+        }
+        job.progress(i as u64, Some(files_found as u64));
+        if !rescan && known.contains(file) {
+            files_parsed += 1;
+            continue;
+        }
+        // Reading a remote file whole to measure its loudness would download the library.
+        let song = match listed {
+            Some(song) => Ok(Song {
+                id: SongId::for_file(Path::new(file), None),
+                file_path: file.clone(),
+                ..song.clone()
+            }),
+            None => metadata::read_song(Path::new(file)),
+        };
+        let song = song.and_then(|song| {
+            let mut stored = library::with_library(|lib| {
+                lib.replace_file_songs(file, std::slice::from_ref(&song))
+            })?;
+            Ok(stored.remove(0))
+        });
+        match song {
+            Ok(song) => {
+                files_parsed += 1;
+                emit(ScanEvent::Parsed {
+                    song,
+                    files_parsed,
+                    files_found,
+                });
+            }
+            Err(e) => {
+                errors += 1;
+                emit(ScanEvent::Failed {
+                    path: file.clone(),
+                    message: format!("{e:#}"),
+                });
+            }
+        }
+    }
+    // Stars set on the server, from other clients too, apply to known and new songs alike.
+    let favorites: Vec<(&str, bool)> = files
+        .iter()
+        .filter_map(|(file, song)| Some((file.as_str(), song.as_ref()?.favorite)))
+        .collect();
+    if !favorites.is_empty() {
+        library::with_library(|lib| lib.set_file_favorites(&favorites))?;
+    }
+
+    let _ = sink.add(ScanEvent::Finished {
+        files_found,
+        files_parsed,
+        errors,
+        cancelled: job.is_cancelled(),
+    });
+    Ok(())
+}
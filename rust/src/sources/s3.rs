@@ -0,0 +1,335 @@
+use std::ops::Range;
+use std::time::SystemTime;
+
+use anyhow::{bail, Context};
+use percent_encoding::utf8_percent_encode;
+use ring::{digest, hmac};
+use url::Url;
+
+use super::{parse_xml, read_range, Backend, Listed, Stat, PATH_SEGMENT};
+use crate::SourceConfig;
+
+/// Region signed for when none is configured, which S3-compatible stores accept
+const DEFAULT_REGION: &str = "us-east-1";
+
+/// Objects of an S3-compatible bucket, optionally only those below a prefix
+///
+/// Buckets are addressed path style (`<endpoint>/<bucket>/<key>`), which MinIO, Garage
+/// and the other self-hosted stores need and AWS still serves.
+pub(super) struct S3 {
+    agent: ureq::Agent,
+    /// Scheme, host and port, without a trailing `/`
+    endpoint: String,
+    host: String,
+    region: String,
+    bucket: String,
+    /// Empty, or ending with `/`
+    prefix: String,
+    access_key_id: String,
+    secret_access_key: String,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn sign(key: &[u8], data: &str) -> hmac::Tag {
+    hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, key), data.as_bytes())
+}
+
+/// `text` encoded the way SigV4 canonical requests need it
+fn encode(text: &str) -> String {
+    utf8_percent_encode(text, PATH_SEGMENT).to_string()
+}
+
+/// Unencoded query pairs as a SigV4 canonical query string, sorted and encoded
+fn canonical_query(query: &[(&str, &str)]) -> String {
+    let mut query: Vec<(String, String)> =
+        query.iter().map(|(k, v)| (encode(k), encode(v))).collect();
+    query.sort();
+    query
+        .iter()
+        .map(|(k, v)| format!("{k}={v}"))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// A SigV4 canonical request, with `headers` lowercase and sorted by name; returns it
+/// and the signed headers list
+fn canonical_request(
+    method: &str,
+    path: &str,
+    query: &str,
+    headers: &[(&str, &str)],
+    payload: &str,
+) -> (String, String) {
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{name}:{}\n", value.trim()))
+        .collect();
+    let signed_headers = headers
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical =
+        format!("{method}\n{path}\n{query}\n{canonical_headers}\n{signed_headers}\n{payload}");
+    (canonical, signed_headers)
+}
+
+/// The credential scope and SigV4 signature of `canonical` for `service` in `region`,
+/// at `timestamp` (`YYYYMMDDTHHMMSSZ`)
+fn signature(
+    secret_access_key: &str,
+    region: &str,
+    service: &str,
+    timestamp: &str,
+    canonical: &str,
+) -> (String, String) {
+    let date = &timestamp[..8];
+    let scope = format!("{date}/{region}/{service}/aws4_request");
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{timestamp}\n{scope}\n{}",
+        hex(digest::digest(&digest::SHA256, canonical.as_bytes()).as_ref())
+    );
+    let mut key = sign(format!("AWS4{secret_access_key}").as_bytes(), date);
+    for part in [region, service, "aws4_request"] {
+        key = sign(key.as_ref(), part);
+    }
+    (scope, hex(sign(key.as_ref(), &string_to_sign).as_ref()))
+}
+
+impl S3 {
+    pub fn new(agent: ureq::Agent, config: &SourceConfig) -> anyhow::Result<Self> {
+        let SourceConfig::S3 {
+            endpoint,
+            region,
+            bucket,
+            prefix,
+            access_key_id,
+            secret_access_key,
+        } = config
+        else {
+            bail!("not an S3 source");
+        };
+        let url = Url::parse(endpoint.trim()).with_context(|| format!("invalid URL {endpoint}"))?;
+        anyhow::ensure!(
+            matches!(url.scheme(), "http" | "https"),
+            "S3 endpoints need an http or https URL, not {endpoint}"
+        );
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{host}:{port}"),
+            (Some(host), None) => host.to_string(),
+            (None, _) => bail!("S3 endpoint {endpoint} has no host"),
+        };
+        anyhow::ensure!(!bucket.trim().is_empty(), "S3 sources need a bucket");
+        let prefix = prefix.trim().trim_matches('/');
+        Ok(S3 {
+            agent,
+            endpoint: format!("{}://{host}", url.scheme()),
+            host,
+            region: match region.trim() {
+                "" => DEFAULT_REGION.to_string(),
+                region => region.to_string(),
+            },
+            bucket: bucket.trim().to_string(),
+            prefix: match prefix {
+                "" => String::new(),
+                prefix => format!("{prefix}/"),
+            },
+            access_key_id: access_key_id.trim().to_string(),
+            secret_access_key: secret_access_key.trim().to_string(),
+        })
+    }
+
+    /// A request signed with AWS Signature Version 4
+    ///
+    /// `key` is the object, or `None` for the bucket; `query` pairs are unencoded.
+    fn request(&self, method: &str, key: Option<&str>, query: &[(&str, &str)]) -> ureq::Request {
+        let mut path = format!("/{}", encode(&self.bucket));
+        if let Some(key) = key {
+            for segment in key.split('/') {
+                path.push('/');
+                path.push_str(&encode(segment));
+            }
+        }
+        let query = canonical_query(query);
+
+        let now = chrono::DateTime::<chrono::Utc>::from(SystemTime::now());
+        let timestamp = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let payload = "UNSIGNED-PAYLOAD";
+        let headers = [
+            ("host", self.host.as_str()),
+            ("x-amz-content-sha256", payload),
+            ("x-amz-date", &timestamp),
+        ];
+        let (canonical, signed_headers) =
+            canonical_request(method, &path, &query, &headers, payload);
+        let (scope, signature) = signature(
+            &self.secret_access_key,
+            &self.region,
+            "s3",
+            &timestamp,
+            &canonical,
+        );
+
+        let url = match query.is_empty() {
+            true => format!("{}{path}", self.endpoint),
+            false => format!("{}{path}?{query}", self.endpoint),
+        };
+        self.agent
+            .request(method, &url)
+            .set("x-amz-content-sha256", payload)
+            .set("x-amz-date", &timestamp)
+            .set(
+                "Authorization",
+                &format!(
+                    "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}",
+                    self.access_key_id
+                ),
+            )
+    }
+}
+
+impl Backend for S3 {
+    fn list(&self) -> anyhow::Result<Vec<Listed>> {
+        let mut keys = Vec::new();
+        let mut token: Option<String> = None;
+        loop {
+            let mut query = vec![("list-type", "2"), ("prefix", self.prefix.as_str())];
+            if let Some(token) = &token {
+                query.push(("continuation-token", token));
+            }
+            let body = self
+                .request("GET", None, &query)
+                .call()
+                .with_context(|| format!("failed to list bucket {}", self.bucket))?
+                .into_string()?;
+            let (mut truncated, mut next) = (false, None);
+            parse_xml(&body, |open, text| match open {
+                [.., parent, tag] if parent == "Contents" && tag == "Key" => {
+                    // Keys ending with `/` are folder placeholders some tools create.
+                    if let Some(key) = text.strip_prefix(self.prefix.as_str()) {
+                        if !key.is_empty() && !key.ends_with('/') {
+                            keys.push(Listed {
+                                path: key.to_string(),
+                                song: None,
+                            });
+                        }
+                    }
+                }
+                [.., tag] if tag == "IsTruncated" => truncated = text == "true",
+                [.., tag] if tag == "NextContinuationToken" => next = Some(text.to_string()),
+                _ => {}
+            })?;
+            match next {
+                Some(next) if truncated => token = Some(next),
+                _ => return Ok(keys),
+            }
+        }
+    }
+
+    fn stat(&self, path: &str) -> anyhow::Result<Stat> {
+        let key = format!("{}{path}", self.prefix);
+        let response = self
+            .request("HEAD", Some(&key), &[])
+            .call()
+            .with_context(|| format!("failed to look up {key} in bucket {}", self.bucket))?;
+        let size = response
+            .header("Content-Length")
+            .and_then(|len| len.trim().parse().ok())
+            .with_context(|| format!("{key} has no Content-Length"))?;
+        let version = response
+            .header("ETag")
+            .or_else(|| response.header("Last-Modified"))
+            .map(str::to_string);
+        Ok(Stat { size, version })
+    }
+
+    fn read(&self, path: &str, range: Range<u64>) -> anyhow::Result<Vec<u8>> {
+        let key = format!("{}{path}", self.prefix);
+        read_range(self.request("GET", Some(&key), &[]), range)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    #[test]
+    fn signs_aws_get_vanilla() {
+        let timestamp = "20150830T123600Z";
+        let headers = [("host", "example.amazonaws.com"), ("x-amz-date", timestamp)];
+        let (canonical, signed_headers) = canonical_request("GET", "/", "", &headers, EMPTY_SHA256);
+        assert_eq!(signed_headers, "host;x-amz-date");
+        let (scope, signature) = signature(
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            "us-east-1",
+            "service",
+            timestamp,
+            &canonical,
+        );
+        assert_eq!(scope, "20150830/us-east-1/service/aws4_request");
+        assert_eq!(
+            signature,
+            "5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31"
+        );
+    }
+
+    #[test]
+    fn signs_aws_s3_get_object() {
+        let timestamp = "20130524T000000Z";
+        let headers = [
+            ("host", "examplebucket.s3.amazonaws.com"),
+            ("range", "bytes=0-9"),
+            ("x-amz-content-sha256", EMPTY_SHA256),
+            ("x-amz-date", timestamp),
+        ];
+        let (canonical, _) = canonical_request("GET", "/test.txt", "", &headers, EMPTY_SHA256);
+        let (_, signature) = signature(
+            "wJalrXUtnFEMI/K7MDENG/bPxRfiCYEXAMPLEKEY",
+            "us-east-1",
+            "s3",
+            timestamp,
+            &canonical,
+        );
+        assert_eq!(
+            signature,
+            "f0e8bdb87c964420e857bd35b5d6ed310bd44f0170aba48dd91039c6036bdb41"
+        );
+    }
+
+    #[test]
+    fn signs_aws_s3_list_objects() {
+        let timestamp = "20130524T000000Z";
+        let headers = [
+            ("host", "examplebucket.s3.amazonaws.com"),
+            ("x-amz-content-sha256", EMPTY_SHA256),
+            ("x-amz-date", timestamp),
+        ];
+        let query = canonical_query(&[("prefix", "J"), ("max-keys", "2")]);
+        assert_eq!(query, "max-keys=2&prefix=J");
+        let (canonical, _) = canonical_request("GET", "/", &query, &headers, EMPTY_SHA256);
+        let (_, signature) = signature(
+            "wJalrXUtnFEMI/K7MDENG/bPxRfiCYEXAMPLEKEY",
+            "us-east-1",
+            "s3",
+            timestamp,
+            &canonical,
+        );
+        assert_eq!(
+            signature,
+            "34b48302e7b5fa45bde8084f4b7868a86f0a534bc59db6670ed5711ef69dc6f7"
+        );
+    }
+
+    #[test]
+    fn encodes_query_values_for_signing() {
+        assert_eq!(
+            canonical_query(&[("prefix", "My Music/ä"), ("list-type", "2")]),
+            "list-type=2&prefix=My%20Music%2F%C3%A4"
+        );
+    }
+}
@@ -0,0 +1,446 @@
+use std::collections::VecDeque;
+use std::io::Read;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Context};
+use md5::{Digest, Md5};
+use percent_encoding::{percent_decode_str, utf8_percent_encode};
+use rand::Rng;
+use serde::Deserialize;
+use url::Url;
+
+use super::{read_range, Backend, Listed, Stat, PATH_SEGMENT};
+use crate::metadata::{UNKNOWN_ALBUM, UNKNOWN_ARTIST, VARIOUS_ARTISTS};
+use crate::{loudness, scanner, Song, SongId, SourceConfig};
+
+/// API version requests claim; 1.13 added token authentication
+const API_VERSION: &str = "1.16.1";
+
+const CLIENT_NAME: &str = "tunes4r";
+
+/// Albums asked for per page of `getAlbumList2`, the most servers hand out
+const ALBUM_PAGE_SIZE: usize = 500;
+
+/// Folder of the paths of songs the server converts before sending; being a prefix,
+/// it can't be confused with a song id
+const TRANSCODED: &str = "transcoded/";
+
+/// Format asked for when the server has to convert a song
+const TRANSCODE_FORMAT: &str = "mp3";
+
+/// Bit rate asked for when converting a song only because its codec can't be decoded
+const TRANSCODE_BITRATE_KBPS: u32 = 320;
+
+/// Converted streams kept in memory, so reading a song's tags and then decoding it
+/// converts it once
+const KEPT_STREAMS: usize = 2;
+
+/// Error code of servers that check passwords elsewhere, e.g. against LDAP, and so only
+/// take them in plain
+const TOKEN_AUTH_UNSUPPORTED: u32 = 41;
+
+/// Songs of a Subsonic-compatible media server such as Navidrome, Gonic or Airsonic
+///
+/// Songs are listed with the tags and stars the server has indexed. Those the decoder
+/// can't play, or above `max_bitrate_kbps`, are streamed converted to MP3; since
+/// servers can't serve ranges of a conversion, those are fetched whole.
+pub(super) struct Subsonic {
+    agent: ureq::Agent,
+    /// Ends with `rest/`, so API methods join below it
+    api: Url,
+    username: String,
+    password: String,
+    max_bitrate_kbps: Option<u32>,
+    /// Set once the server turned down token authentication
+    plain_password: AtomicBool,
+    /// The latest converted streams, by path
+    streams: Mutex<VecDeque<(String, Arc<Vec<u8>>)>>,
+}
+
+#[derive(Deserialize)]
+struct Envelope {
+    #[serde(rename = "subsonic-response")]
+    response: Response,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Response {
+    status: String,
+    error: Option<ApiError>,
+    album_list2: Option<AlbumList>,
+    album: Option<Album>,
+    song: Option<ApiSong>,
+}
+
+#[derive(Deserialize)]
+struct ApiError {
+    code: u32,
+    #[serde(default)]
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct AlbumList {
+    #[serde(default)]
+    album: Vec<AlbumId>,
+}
+
+#[derive(Deserialize)]
+struct AlbumId {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct Album {
+    #[serde(default)]
+    song: Vec<ApiSong>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ApiSong {
+    id: String,
+    #[serde(default)]
+    title: String,
+    artist: Option<String>,
+    album: Option<String>,
+    /// OpenSubsonic's album artists, joined for display
+    display_album_artist: Option<String>,
+    track: Option<u32>,
+    disc_number: Option<u32>,
+    genre: Option<String>,
+    year: Option<u32>,
+    duration: Option<u64>,
+    suffix: Option<String>,
+    bit_rate: Option<u32>,
+    size: Option<u64>,
+    created: Option<String>,
+    /// When the song was starred, if it is
+    starred: Option<String>,
+    user_rating: Option<u8>,
+    /// OpenSubsonic's ReplayGain values
+    replay_gain: Option<ReplayGain>,
+    /// OpenSubsonic's tempo; zero if unknown
+    bpm: Option<u32>,
+    /// OpenSubsonic's advisory: "explicit", "clean" or empty
+    explicit_status: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ReplayGain {
+    track_gain: Option<f32>,
+    album_gain: Option<f32>,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Server song id of a path made by `Subsonic::path`, and whether the song is converted
+fn song_id(path: &str) -> anyhow::Result<(String, bool)> {
+    let (path, transcoded) = match path.strip_prefix(TRANSCODED) {
+        Some(path) => (path, true),
+        None => (path, false),
+    };
+    let (id, _) = path
+        .rsplit_once('.')
+        .with_context(|| format!("not a Subsonic song path: {path}"))?;
+    Ok((
+        percent_decode_str(id).decode_utf8()?.into_owned(),
+        transcoded,
+    ))
+}
+
+/// Text the server left out or blank, as tags read from files would be
+fn text(value: Option<String>) -> Option<String> {
+    value
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+impl Subsonic {
+    pub fn new(agent: ureq::Agent, config: &SourceConfig) -> anyhow::Result<Self> {
+        let SourceConfig::Subsonic {
+            url,
+            username,
+            password,
+            max_bitrate_kbps,
+        } = config
+        else {
+            bail!("not a Subsonic source");
+        };
+        let mut api = Url::parse(url.trim()).with_context(|| format!("invalid URL {url}"))?;
+        anyhow::ensure!(
+            matches!(api.scheme(), "http" | "https"),
+            "Subsonic servers need an http or https URL, not {url}"
+        );
+        // The API lives under `/rest`, whether or not the URL given already ends there.
+        let path = api.path().trim_end_matches('/').to_string();
+        let path = path.strip_suffix("/rest").unwrap_or(&path);
+        api.set_path(&format!("{path}/rest/"));
+        anyhow::ensure!(
+            !username.trim().is_empty(),
+            "Subsonic sources need a username"
+        );
+        Ok(Subsonic {
+            agent,
+            api,
+            username: username.trim().to_string(),
+            password: password.clone(),
+            max_bitrate_kbps: max_bitrate_kbps.filter(|&kbps| kbps > 0),
+            plain_password: AtomicBool::new(false),
+            streams: Mutex::default(),
+        })
+    }
+
+    /// URL of API `method` with the credentials and `query`
+    fn url(&self, method: &str, query: &[(&str, &str)]) -> anyhow::Result<Url> {
+        let mut url = self.api.join(&format!("{method}.view"))?;
+        {
+            let mut pairs = url.query_pairs_mut();
+            pairs
+                .append_pair("u", &self.username)
+                .append_pair("v", API_VERSION)
+                .append_pair("c", CLIENT_NAME)
+                .append_pair("f", "json");
+            if self.plain_password.load(Ordering::Relaxed) {
+                pairs.append_pair("p", &format!("enc:{}", hex(self.password.as_bytes())));
+            } else {
+                let salt = hex(&rand::thread_rng().gen::<[u8; 8]>());
+                let token = Md5::digest(format!("{}{salt}", self.password).as_bytes());
+                pairs.append_pair("t", &hex(&token)).append_pair("s", &salt);
+            }
+            for (key, value) in query {
+                pairs.append_pair(key, value);
+            }
+        }
+        Ok(url)
+    }
+
+    /// Call API `method`, failing with the server's message if it reports an error
+    fn call(&self, method: &str, query: &[(&str, &str)]) -> anyhow::Result<Response> {
+        let body = self
+            .agent
+            .request_url("GET", &self.url(method, query)?)
+            .call()
+            .with_context(|| format!("Subsonic request {method} failed"))?
+            .into_string()?;
+        let response = serde_json::from_str::<Envelope>(&body)
+            .with_context(|| format!("unexpected response to Subsonic request {method}"))?
+            .response;
+        if response.status == "ok" {
+            return Ok(response);
+        }
+        match response.error {
+            Some(error)
+                if error.code == TOKEN_AUTH_UNSUPPORTED
+                    && !self.plain_password.swap(true, Ordering::Relaxed) =>
+            {
+                log::info!("Subsonic server at {} takes plain passwords only", self.api);
+                self.call(method, query)
+            }
+            Some(error) => bail!(
+                "Subsonic request {method} failed: {} (error {})",
+                error.message,
+                error.code
+            ),
+            None => bail!("Subsonic request {method} failed"),
+        }
+    }
+
+    /// Path a song is listed under: its id with the extension it's streamed in
+    fn path(&self, song: &ApiSong) -> String {
+        let id = utf8_percent_encode(&song.id, PATH_SEGMENT);
+        let suffix = song.suffix.as_deref().unwrap_or("").to_ascii_lowercase();
+        let decodable = scanner::is_supported(Path::new(&format!("song.{suffix}")));
+        let within_limit = match (self.max_bitrate_kbps, song.bit_rate) {
+            (Some(max), Some(bit_rate)) => bit_rate <= max,
+            _ => true,
+        };
+        match decodable && within_limit {
+            true => format!("{id}.{suffix}"),
+            false => format!("{TRANSCODED}{id}.{TRANSCODE_FORMAT}"),
+        }
+    }
+
+    /// The converted stream of song `id`, fetched whole unless it was just fetched
+    fn transcoded(&self, path: &str, id: &str) -> anyhow::Result<Arc<Vec<u8>>> {
+        let mut streams = self.streams.lock().unwrap();
+        if let Some((_, data)) = streams.iter().find(|(p, _)| p == path) {
+            return Ok(data.clone());
+        }
+        let bitrate = self
+            .max_bitrate_kbps
+            .unwrap_or(TRANSCODE_BITRATE_KBPS)
+            .to_string();
+        let url = self.url(
+            "stream",
+            &[
+                ("id", id),
+                ("format", TRANSCODE_FORMAT),
+                ("maxBitRate", &bitrate),
+            ],
+        )?;
+        let response = self
+            .agent
+            .request_url("GET", &url)
+            .call()
+            .with_context(|| format!("failed to stream Subsonic song {id}"))?;
+        // Errors come back as a normal response rather than audio.
+        if response.content_type().contains("json") {
+            let body = response.into_string()?;
+            match serde_json::from_str::<Envelope>(&body).map(|e| e.response.error) {
+                Ok(Some(error)) => bail!("failed to stream Subsonic song {id}: {}", error.message),
+                _ => bail!("failed to stream Subsonic song {id}"),
+            }
+        }
+        let mut data = Vec::new();
+        response.into_reader().read_to_end(&mut data)?;
+        let data = Arc::new(data);
+        if streams.len() >= KEPT_STREAMS {
+            streams.pop_front();
+        }
+        streams.push_back((path.to_string(), data.clone()));
+        Ok(data)
+    }
+}
+
+impl Backend for Subsonic {
+    fn list(&self) -> anyhow::Result<Vec<Listed>> {
+        let mut albums = Vec::new();
+        loop {
+            let (size, offset) = (ALBUM_PAGE_SIZE.to_string(), albums.len().to_string());
+            let page = self
+                .call(
+                    "getAlbumList2",
+                    &[
+                        ("type", "alphabeticalByName"),
+                        ("size", &size),
+                        ("offset", &offset),
+                    ],
+                )?
+                .album_list2
+                .map(|list| list.album)
+                .unwrap_or_default();
+            let full = page.len() == ALBUM_PAGE_SIZE;
+            albums.extend(page);
+            if !full {
+                break;
+            }
+        }
+
+        let mut listed = Vec::new();
+        for album in albums {
+            let songs = match self.call("getAlbum", &[("id", &album.id)]) {
+                Ok(response) => response.album.map(|a| a.song).unwrap_or_default(),
+                // One album the server can't read shouldn't hide the rest.
+                Err(e) => {
+                    log::warn!("skipping Subsonic album {}: {e:#}", album.id);
+                    continue;
+                }
+            };
+            for song in songs {
+                let path = self.path(&song);
+                let gain = |gain: Option<f32>| {
+                    gain.and_then(|g| loudness::from_replaygain(&g.to_string()))
+                };
+                let replay_gain = song.replay_gain.as_ref();
+                listed.push(Listed {
+                    song: Some(Song {
+                        id: SongId::default(),
+                        title: text(Some(song.title)).unwrap_or_else(|| song.id.clone()),
+                        artist: text(song.artist).unwrap_or_else(|| UNKNOWN_ARTIST.to_string()),
+                        album: text(song.album).unwrap_or_else(|| UNKNOWN_ALBUM.to_string()),
+                        compilation: song
+                            .display_album_artist
+                            .as_deref()
+                            .is_some_and(|a| a.eq_ignore_ascii_case(VARIOUS_ARTISTS)),
+                        album_artist: text(song.display_album_artist),
+                        artist_sort: None,
+                        album_sort: None,
+                        disc_number: song.disc_number.filter(|&disc| disc > 0),
+                        track_number: song.track.filter(|&track| track > 0),
+                        genre: text(song.genre),
+                        year: song.year.filter(|&year| year > 0),
+                        rating: song.user_rating.filter(|stars| (1..=5).contains(stars)),
+                        favorite: song.starred.is_some(),
+                        duration: song.duration.unwrap_or(0),
+                        file_path: String::new(),
+                        start_offset: 0.0,
+                        end_offset: None,
+                        track_loudness: gain(replay_gain.and_then(|g| g.track_gain)),
+                        album_loudness: gain(replay_gain.and_then(|g| g.album_gain)),
+                        bpm: song.bpm.filter(|&bpm| bpm > 0).map(|bpm| bpm as f32),
+                        key: None,
+                        explicit: song.explicit_status.as_deref() == Some("explicit"),
+                    }),
+                    path,
+                });
+            }
+        }
+        Ok(listed)
+    }
+
+    fn stat(&self, path: &str) -> anyhow::Result<Stat> {
+        let (id, transcoded) = song_id(path)?;
+        if transcoded {
+            let size = self.transcoded(path, &id)?.len() as u64;
+            return Ok(Stat {
+                size,
+                version: self.max_bitrate_kbps.map(|kbps| kbps.to_string()),
+            });
+        }
+        let song = self
+            .call("getSong", &[("id", &id)])?
+            .song
+            .with_context(|| format!("no Subsonic song {id}"))?;
+        Ok(Stat {
+            size: song
+                .size
+                .with_context(|| format!("Subsonic song {id} has no size"))?,
+            version: song.created,
+        })
+    }
+
+    fn read(&self, path: &str, range: Range<u64>) -> anyhow::Result<Vec<u8>> {
+        let (id, transcoded) = song_id(path)?;
+        if transcoded {
+            let data = self.transcoded(path, &id)?;
+            let end = (range.end as usize).min(data.len());
+            let start = (range.start as usize).min(end);
+            return Ok(data[start..end].to_vec());
+        }
+        let url = self.url("stream", &[("id", &id), ("format", "raw")])?;
+        read_range(self.agent.request_url("GET", &url), range)
+    }
+
+    fn ping(&self) -> anyhow::Result<()> {
+        self.call("ping", &[]).map(drop)
+    }
+
+    fn set_starred(&self, path: &str, starred: bool) -> anyhow::Result<()> {
+        let (id, _) = song_id(path)?;
+        let method = if starred { "star" } else { "unstar" };
+        self.call(method, &[("id", &id)]).map(drop)
+    }
+
+    fn scrobble(&self, path: &str, listened_at: Option<i64>) -> anyhow::Result<()> {
+        let (id, _) = song_id(path)?;
+        match listened_at {
+            Some(time) => {
+                let time = (time * 1000).to_string();
+                self.call(
+                    "scrobble",
+                    &[("id", &id), ("submission", "true"), ("time", &time)],
+                )
+            }
+            None => self.call("scrobble", &[("id", &id), ("submission", "false")]),
+        }
+        .map(drop)
+    }
+}
@@ -0,0 +1,157 @@
+use std::collections::{HashSet, VecDeque};
+use std::ops::Range;
+
+use anyhow::{bail, Context};
+use base64::Engine;
+use percent_encoding::{percent_decode_str, utf8_percent_encode};
+use url::Url;
+
+use super::{parse_xml, read_range, Backend, Listed, Stat, PATH_SEGMENT};
+use crate::SourceConfig;
+
+/// Properties asked for when listing a collection
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propfind xmlns:d="DAV:"><d:prop><d:resourcetype/></d:prop></d:propfind>"#;
+
+/// A WebDAV collection and everything below it, e.g. a Nextcloud folder
+pub(super) struct WebDav {
+    agent: ureq::Agent,
+    /// Always ends with `/`, so relative paths join below it
+    root: Url,
+    /// `Authorization` header value for basic auth
+    auth: Option<String>,
+}
+
+impl WebDav {
+    pub fn new(agent: ureq::Agent, config: &SourceConfig) -> anyhow::Result<Self> {
+        let SourceConfig::WebDav {
+            url,
+            username,
+            password,
+        } = config
+        else {
+            bail!("not a WebDAV source");
+        };
+        let mut root = Url::parse(url.trim()).with_context(|| format!("invalid URL {url}"))?;
+        anyhow::ensure!(
+            matches!(root.scheme(), "http" | "https"),
+            "WebDAV sources need an http or https URL, not {url}"
+        );
+        if !root.path().ends_with('/') {
+            root.set_path(&format!("{}/", root.path()));
+        }
+        let auth = username.as_ref().map(|username| {
+            let credentials = format!("{username}:{}", password.as_deref().unwrap_or(""));
+            let encoded = base64::engine::general_purpose::STANDARD.encode(credentials);
+            format!("Basic {encoded}")
+        });
+        Ok(WebDav { agent, root, auth })
+    }
+
+    fn request(&self, method: &str, url: &Url) -> ureq::Request {
+        let request = self.agent.request_url(method, url);
+        match &self.auth {
+            Some(auth) => request.set("Authorization", auth),
+            None => request,
+        }
+    }
+
+    /// URL of `path` below the root, a file or, ending with `/`, a collection
+    fn url(&self, path: &str) -> anyhow::Result<Url> {
+        let encoded: Vec<String> = path
+            .split('/')
+            .map(|segment| utf8_percent_encode(segment, PATH_SEGMENT).to_string())
+            .collect();
+        Ok(self.root.join(&encoded.join("/"))?)
+    }
+
+    /// Decoded path of `url` relative to the root, if it's below it
+    fn relative(&self, url: &Url) -> Option<String> {
+        let root = percent_decode_str(self.root.path()).decode_utf8().ok()?;
+        let path = percent_decode_str(url.path()).decode_utf8().ok()?;
+        path.strip_prefix(root.as_ref()).map(str::to_string)
+    }
+
+    /// Members of the collection at `path`: files, and collections ending with `/`
+    fn members(&self, path: &str) -> anyhow::Result<Vec<String>> {
+        let url = self.url(path)?;
+        let body = self
+            .request("PROPFIND", &url)
+            .set("Depth", "1")
+            .set("Content-Type", "application/xml; charset=utf-8")
+            .send_string(PROPFIND_BODY)
+            .with_context(|| format!("failed to list {url}"))?
+            .into_string()?;
+
+        let mut members = Vec::new();
+        let (mut href, mut collection) = (None, false);
+        parse_xml(&body, |open, text| match open {
+            [.., parent, tag] if parent == "response" && tag == "href" => {
+                href = Some(text.to_string());
+            }
+            [.., tag] if tag == "collection" => collection = true,
+            [.., tag] if tag == "response" => {
+                let member = href.take().and_then(|href| url.join(&href).ok());
+                if let Some(relative) = member.and_then(|m| self.relative(&m)) {
+                    let relative = relative.trim_end_matches('/');
+                    // The collection itself is listed along with its members.
+                    if relative != path.trim_end_matches('/') {
+                        members.push(match collection {
+                            true => format!("{relative}/"),
+                            false => relative.to_string(),
+                        });
+                    }
+                }
+                collection = false;
+            }
+            _ => {}
+        })?;
+        Ok(members)
+    }
+}
+
+impl Backend for WebDav {
+    fn list(&self) -> anyhow::Result<Vec<Listed>> {
+        // Depth one at a time, as many servers refuse `Depth: infinity`.
+        let mut pending = VecDeque::from([String::new()]);
+        let mut seen = HashSet::new();
+        let mut files = Vec::new();
+        while let Some(collection) = pending.pop_front() {
+            if !seen.insert(collection.clone()) {
+                continue;
+            }
+            for member in self.members(&collection)? {
+                if member.ends_with('/') {
+                    pending.push_back(member);
+                } else {
+                    files.push(Listed {
+                        path: member,
+                        song: None,
+                    });
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    fn stat(&self, path: &str) -> anyhow::Result<Stat> {
+        let url = self.url(path)?;
+        let response = self
+            .request("HEAD", &url)
+            .call()
+            .with_context(|| format!("failed to look up {url}"))?;
+        let size = response
+            .header("Content-Length")
+            .and_then(|len| len.trim().parse().ok())
+            .with_context(|| format!("{url} has no Content-Length"))?;
+        let version = response
+            .header("ETag")
+            .or_else(|| response.header("Last-Modified"))
+            .map(str::to_string);
+        Ok(Stat { size, version })
+    }
+
+    fn read(&self, path: &str, range: Range<u64>) -> anyhow::Result<Vec<u8>> {
+        read_range(self.request("GET", &self.url(path)?), range)
+    }
+}
@@ -0,0 +1,135 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::Context;
+use walkdir::WalkDir;
+
+use crate::{library, sources, StorageConfig, StorageKind, StorageUsage, TunesError};
+
+/// Every kind, in the order `usage` reports them
+const KINDS: [StorageKind; 6] = [
+    StorageKind::Artwork,
+    StorageKind::Waveforms,
+    StorageKind::RemoteAudio,
+    StorageKind::Offline,
+    StorageKind::Podcasts,
+    StorageKind::Temp,
+];
+
+/// Directories from `init_storage`; without them files go next to the library database
+static ROOTS: Mutex<Option<Roots>> = Mutex::new(None);
+
+struct Roots {
+    cache: PathBuf,
+    data: PathBuf,
+    temp: PathBuf,
+}
+
+/// Name of the folder files of `kind` are kept in, below its root
+pub(crate) fn folder(kind: StorageKind) -> &'static str {
+    match kind {
+        StorageKind::Artwork => "artwork",
+        StorageKind::Waveforms => "waveforms",
+        StorageKind::RemoteAudio => "remote",
+        StorageKind::Offline => "offline",
+        StorageKind::Podcasts => "podcasts",
+        StorageKind::Temp => "tmp",
+    }
+}
+
+/// Keep files below the directories in `config` from now on, creating them as needed
+pub(crate) fn init(config: StorageConfig) -> anyhow::Result<()> {
+    let root = |dir: &str, what: &str| -> anyhow::Result<PathBuf> {
+        let dir = PathBuf::from(dir);
+        anyhow::ensure!(
+            dir.is_absolute(),
+            TunesError::invalid_state(format!("the {what} directory has to be an absolute path"))
+        );
+        fs::create_dir_all(&dir).with_context(|| format!("failed to create {}", dir.display()))?;
+        Ok(dir)
+    };
+    let cache = root(&config.cache_dir, "cache")?;
+    let data = root(&config.data_dir, "data")?;
+    let temp = match &config.temp_dir {
+        Some(dir) => root(dir, "temp")?,
+        None => cache.clone(),
+    };
+    log::info!(
+        "keeping caches in {}, downloads in {}",
+        cache.display(),
+        data.display()
+    );
+    *ROOTS.lock().unwrap() = Some(Roots { cache, data, temp });
+    Ok(())
+}
+
+/// Directory for files of `kind` below the directories from `init_storage`, if it was
+/// called
+pub(crate) fn configured(kind: StorageKind) -> Option<PathBuf> {
+    let roots = ROOTS.lock().unwrap();
+    let roots = roots.as_ref()?;
+    let root = match kind {
+        StorageKind::Artwork | StorageKind::Waveforms | StorageKind::RemoteAudio => &roots.cache,
+        StorageKind::Offline | StorageKind::Podcasts => &roots.data,
+        StorageKind::Temp => &roots.temp,
+    };
+    Some(root.join(folder(kind)))
+}
+
+pub(crate) fn usage() -> Vec<StorageUsage> {
+    KINDS
+        .into_iter()
+        .filter_map(|kind| {
+            let dir = library::cache_dir(kind)?;
+            let (bytes, files) = measure(&dir);
+            Some(StorageUsage {
+                kind,
+                path: dir.to_string_lossy().into_owned(),
+                bytes,
+                files,
+            })
+        })
+        .collect()
+}
+
+/// Bytes and number of the files below `dir`
+fn measure(dir: &Path) -> (u64, u32) {
+    WalkDir::new(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.metadata().ok())
+        .filter(|metadata| metadata.is_file())
+        .fold((0, 0), |(bytes, files), metadata| {
+            (bytes + metadata.len(), files + 1)
+        })
+}
+
+/// Delete every file of `kind`, returning the bytes freed
+pub(crate) fn clear(kind: StorageKind) -> anyhow::Result<u64> {
+    if matches!(kind, StorageKind::Offline | StorageKind::Podcasts) {
+        return Err(TunesError::invalid_state(
+            "downloads are removed with remove_offline or delete_episode_download",
+        )
+        .into());
+    }
+    let Some(dir) = library::cache_dir(kind) else {
+        return Ok(0);
+    };
+    let (before, _) = measure(&dir);
+    match kind {
+        // The block cache keeps an index of what it holds, so it has to do this itself.
+        StorageKind::RemoteAudio => sources::clear_cache(),
+        _ => match fs::remove_dir_all(&dir) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                // Whatever couldn't be deleted, like a file in use, is left behind.
+                log::warn!("couldn't clear all of {}: {e}", dir.display());
+            }
+            _ => {}
+        },
+    }
+    let (after, _) = measure(&dir);
+    log::info!("cleared {} bytes of {kind:?}", before.saturating_sub(after));
+    Ok(before.saturating_sub(after))
+}
@@ -0,0 +1,44 @@
+use std::fmt;
+use std::sync::mpsc::{self, Receiver, Sender};
+
+/// Sending half of a Rust -> Dart stream
+///
+/// Mirrors the surface of flutter_rust_bridge's generated `StreamSink` (`add` returns a
+/// `Result`), so engine code can push values without caring whether the consumer is a
+/// Dart isolate or a plain Rust receiver.
+pub struct StreamSink<T> {
+    tx: Sender<T>,
+}
+
+impl<T> StreamSink<T> {
+    /// Create a sink together with the receiver that observes it
+    pub fn channel() -> (Self, Receiver<T>) {
+        let (tx, rx) = mpsc::channel();
+        (StreamSink { tx }, rx)
+    }
+
+    /// Push a value to the consumer, failing once the consumer has gone away
+    pub fn add(&self, value: T) -> Result<(), SinkClosed> {
+        self.tx.send(value).map_err(|_| SinkClosed)
+    }
+}
+
+impl<T> Clone for StreamSink<T> {
+    fn clone(&self) -> Self {
+        StreamSink {
+            tx: self.tx.clone(),
+        }
+    }
+}
+
+/// The receiving side of a `StreamSink` was dropped
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SinkClosed;
+
+impl fmt::Display for SinkClosed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("stream consumer is closed")
+    }
+}
+
+impl std::error::Error for SinkClosed {}
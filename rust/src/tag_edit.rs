@@ -0,0 +1,139 @@
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+
+use anyhow::Context;
+
+use crate::jobs::{self, Job};
+use crate::metadata::{self, SavedTag, UNKNOWN_ALBUM, UNKNOWN_ARTIST};
+use crate::{cue, library, JobKind, Song, SongId, StreamSink, TagChanges, TagEditEvent};
+
+/// Queue applying `changes` to the files of `song_ids` as a background job, returning
+/// its id for `jobs::cancel`
+pub(crate) fn start(
+    song_ids: Vec<SongId>,
+    changes: TagChanges,
+    sink: StreamSink<TagEditEvent>,
+) -> u32 {
+    jobs::submit(JobKind::TagEdit, move |job| {
+        // A cancel that comes once every file is written is too late to stop the edit.
+        let (event, result) = match edit(&song_ids, &changes, &sink, job) {
+            Ok(songs) => (TagEditEvent::Finished { songs }, Ok(())),
+            Err(_) if job.is_cancelled() => (TagEditEvent::Cancelled, Ok(())),
+            Err(e) => {
+                let message = format!("{e:#}");
+                (TagEditEvent::Failed { message }, Err(e))
+            }
+        };
+        let _ = sink.add(event);
+        result
+    })
+}
+
+/// Write `changes` to every file, then store the edited songs
+///
+/// Should a file fail to save, the library fail to store the edit, or the job be
+/// cancelled before every file is written, the files already written get their tags
+/// back as they were and the library is left alone.
+fn edit(
+    song_ids: &[SongId],
+    changes: &TagChanges,
+    sink: &StreamSink<TagEditEvent>,
+    job: &Job,
+) -> anyhow::Result<Vec<Song>> {
+    let songs = library::with_library(|lib| {
+        let mut songs = Vec::with_capacity(song_ids.len());
+        let mut files = HashSet::new();
+        for song_id in song_ids {
+            let song = lib
+                .get_song(song_id)?
+                .with_context(|| format!("no song with id {song_id}"))?;
+            anyhow::ensure!(
+                !cue::is_track(&song),
+                "{} is a CUE sheet track; edit the sheet instead",
+                song.title
+            );
+            if files.insert(song.file_path.clone()) {
+                songs.push(song);
+            }
+        }
+        Ok(songs)
+    })?;
+    // Kept before anything is written, so a file that can't be read fails the edit
+    // while there's nothing to undo.
+    let saved = songs
+        .iter()
+        .map(|song| {
+            metadata::save_tag(Path::new(&song.file_path))
+                .with_context(|| format!("failed to read the tags of {}", song.file_path))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let total = songs.len() as u32;
+    for (done, song) in songs.iter().enumerate() {
+        let event = TagEditEvent::Progress {
+            done: done as u32,
+            total,
+            path: song.file_path.clone(),
+        };
+        if sink.add(event).is_err() {
+            job.cancelled().store(true, Ordering::Relaxed);
+        }
+        job.progress(done as u64, Some(total as u64));
+        if job.is_cancelled() {
+            roll_back(&songs[..done], &saved);
+            anyhow::bail!("tag edit cancelled");
+        }
+        let written = metadata::write_tag_changes(Path::new(&song.file_path), changes)
+            .with_context(|| format!("failed to tag {}", song.file_path));
+        if let Err(e) = written {
+            // The file may have been left half written.
+            roll_back(&songs[..=done], &saved);
+            return Err(e);
+        }
+    }
+
+    let edited: Vec<Song> = songs
+        .into_iter()
+        .map(|mut song| {
+            apply(changes, &mut song);
+            song
+        })
+        .collect();
+    if let Err(e) = library::with_library(|lib| lib.upsert_songs(&edited)) {
+        roll_back(&edited, &saved);
+        return Err(e.context("failed to store the edited tags"));
+    }
+    log::info!("edited the tags of {total} files");
+    Ok(edited)
+}
+
+/// Put back the tags of `written`, the first of the files `saved` was kept for
+fn roll_back(written: &[Song], saved: &[SavedTag]) {
+    for (song, saved) in written.iter().zip(saved) {
+        if let Err(e) = metadata::restore_tag(Path::new(&song.file_path), saved) {
+            log::warn!("failed to restore the tags of {}: {e:#}", song.file_path);
+        }
+    }
+}
+
+/// `song` as `read_song` would read it after `changes` were written to its file
+fn apply(changes: &TagChanges, song: &mut Song) {
+    let text = |value: &str| {
+        Some(value.trim())
+            .filter(|v| !v.is_empty())
+            .map(str::to_string)
+    };
+    if let Some(album) = &changes.album {
+        song.album = text(album).unwrap_or_else(|| UNKNOWN_ALBUM.to_string());
+    }
+    if let Some(artist) = &changes.artist {
+        song.artist = text(artist).unwrap_or_else(|| UNKNOWN_ARTIST.to_string());
+    }
+    if let Some(genre) = &changes.genre {
+        song.genre = text(genre);
+    }
+    if let Some(year) = changes.year {
+        song.year = Some(year).filter(|&year| year != 0);
+    }
+}
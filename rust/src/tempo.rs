@@ -0,0 +1,122 @@
+use std::sync::Arc;
+
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+
+/// Onset strength values per second of audio
+pub(crate) const ENVELOPE_RATE: u32 = 100;
+
+/// Tempo range estimates are kept to
+const MIN_BPM: f32 = 60.0;
+const MAX_BPM: f32 = 200.0;
+
+/// Tempo most music is felt at; estimates lean towards it, favouring the beat over
+/// half or double time
+const PREFERRED_BPM: f32 = 120.0;
+
+/// How far, in octaves, the lean towards `PREFERRED_BPM` reaches
+const PREFERENCE_OCTAVES: f32 = 1.0;
+
+/// Spectral flux onset detector, turning audio into an onset strength envelope at
+/// `ENVELOPE_RATE` values per second
+pub(crate) struct OnsetDetector {
+    hop: usize,
+    fft: Arc<dyn Fft<f32>>,
+    window: Vec<f32>,
+    history: Vec<f32>,
+    write_index: usize,
+    since_hop: usize,
+    scratch: Vec<Complex<f32>>,
+    previous: Vec<f32>,
+}
+
+impl OnsetDetector {
+    pub fn new(sample_rate: u32) -> Self {
+        // Around 25 ms of audio per window, whatever the rate
+        let size = (sample_rate as usize / 40).next_power_of_two();
+        let window = (0..size)
+            .map(|i| {
+                let phase = 2.0 * std::f32::consts::PI * i as f32 / (size - 1) as f32;
+                0.5 - 0.5 * phase.cos()
+            })
+            .collect();
+        OnsetDetector {
+            hop: (sample_rate / ENVELOPE_RATE).max(1) as usize,
+            fft: FftPlanner::new().plan_fft_forward(size),
+            window,
+            history: vec![0.0; size],
+            write_index: 0,
+            since_hop: 0,
+            scratch: vec![Complex::default(); size],
+            previous: vec![0.0; size / 2],
+        }
+    }
+
+    /// Feed one mono sample, returning the onset strength when a hop is complete
+    pub fn push(&mut self, sample: f32) -> Option<f32> {
+        let size = self.history.len();
+        self.history[self.write_index] = sample;
+        self.write_index = (self.write_index + 1) % size;
+        self.since_hop += 1;
+        if self.since_hop < self.hop {
+            return None;
+        }
+        self.since_hop = 0;
+        for (i, bin) in self.scratch.iter_mut().enumerate() {
+            let sample = self.history[(self.write_index + i) % size];
+            *bin = Complex::new(sample * self.window[i], 0.0);
+        }
+        self.fft.process(&mut self.scratch);
+        // Rises in log magnitude, so quiet and loud passages weigh alike
+        let mut flux = 0.0;
+        for (bin, previous) in self.scratch.iter().zip(&mut self.previous) {
+            let level = (1.0 + 100.0 * bin.norm()).ln();
+            flux += (level - *previous).max(0.0);
+            *previous = level;
+        }
+        Some(flux / self.previous.len() as f32)
+    }
+}
+
+/// Tempo of an onset envelope in beats per minute; `None` without any steady beat
+pub(crate) fn estimate(envelope: &[f32]) -> Option<f32> {
+    let mean = envelope.iter().sum::<f32>() / envelope.len().max(1) as f32;
+    let centered: Vec<f32> = envelope.iter().map(|value| value - mean).collect();
+    let lag_of = |bpm: f32| 60.0 * ENVELOPE_RATE as f32 / bpm;
+    let min_lag = lag_of(MAX_BPM).floor() as usize;
+    let max_lag = lag_of(MIN_BPM).ceil() as usize;
+    if centered.len() < max_lag * 2 {
+        return None;
+    }
+    let correlation = |lag: usize| -> f32 {
+        centered[lag..]
+            .iter()
+            .zip(&centered)
+            .map(|(a, b)| a * b)
+            .sum()
+    };
+    if correlation(0) <= 0.0 {
+        return None;
+    }
+    let scores: Vec<f32> = (min_lag - 1..=max_lag + 1).map(correlation).collect();
+    let weight = |lag: f32| {
+        let octaves = (lag_of(PREFERRED_BPM) / lag).log2() / PREFERENCE_OCTAVES;
+        (-0.5 * octaves * octaves).exp()
+    };
+    let (best, _) = (1..scores.len() - 1)
+        .map(|i| (i, scores[i] * weight((min_lag - 1 + i) as f32)))
+        .filter(|&(i, _)| scores[i] >= scores[i - 1] && scores[i] >= scores[i + 1])
+        .max_by(|a, b| a.1.total_cmp(&b.1))?;
+    if scores[best] <= 0.0 {
+        return None;
+    }
+    // Between lags, where the peak actually is
+    let (before, peak, after) = (scores[best - 1], scores[best], scores[best + 1]);
+    let curvature = before - 2.0 * peak + after;
+    let offset = match curvature {
+        c if c < 0.0 => (0.5 * (before - after) / c).clamp(-0.5, 0.5),
+        _ => 0.0,
+    };
+    let lag = (min_lag - 1 + best) as f32 + offset;
+    Some(lag_of(lag))
+}
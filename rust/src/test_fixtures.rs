@@ -0,0 +1,223 @@
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::Context;
+use md5::{Digest, Md5};
+use rodio::Source;
+
+use crate::decoder::SymphoniaSource;
+use crate::transcode::EncodedFile;
+use crate::{engine, Song, TranscodeFormat, TranscodeOptions};
+
+/// Rate fixtures are written at
+pub const SAMPLE_RATE: u32 = 44_100;
+pub const CHANNELS: u16 = 2;
+/// Length of a fixture, half a second
+pub const FRAMES: usize = SAMPLE_RATE as usize / 2;
+
+/// A format and sample encoding fixtures are written in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Codec {
+    WavS16,
+    WavS24,
+    WavF32,
+    AiffS16,
+    Flac16,
+    Flac24,
+    Mp3,
+}
+
+impl Codec {
+    pub const ALL: [Codec; 7] = [
+        Codec::WavS16,
+        Codec::WavS24,
+        Codec::WavF32,
+        Codec::AiffS16,
+        Codec::Flac16,
+        Codec::Flac24,
+        Codec::Mp3,
+    ];
+
+    /// Name of the codec in golden files
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::WavS16 => "wav-s16",
+            Codec::WavS24 => "wav-s24",
+            Codec::WavF32 => "wav-f32",
+            Codec::AiffS16 => "aiff-s16",
+            Codec::Flac16 => "flac-16",
+            Codec::Flac24 => "flac-24",
+            Codec::Mp3 => "mp3",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Codec::WavS16 | Codec::WavS24 | Codec::WavF32 => "wav",
+            Codec::AiffS16 => "aiff",
+            Codec::Flac16 | Codec::Flac24 => "flac",
+            Codec::Mp3 => "mp3",
+        }
+    }
+
+    /// Whether decoding gives back exactly the samples of `signal`
+    pub fn is_lossless(self) -> bool {
+        self != Codec::Mp3
+    }
+}
+
+/// Samples every fixture holds: a chord on the left, a sweep on the right and a little
+/// noise on both, all on the 16-bit grid so every lossless codec holds them exactly
+pub fn signal() -> Vec<f32> {
+    let mut noise = 0x2545_f491_u32;
+    let mut samples = Vec::with_capacity(FRAMES * CHANNELS as usize);
+    for frame in 0..FRAMES {
+        let t = frame as f32 / SAMPLE_RATE as f32;
+        let tau = std::f32::consts::TAU;
+        let chord = [220.0, 277.2, 329.6]
+            .iter()
+            .map(|freq| (tau * freq * t).sin() * 0.2)
+            .sum::<f32>();
+        let sweep = (tau * (200.0 + 1800.0 * t) * t).sin() * 0.5;
+        for tone in [chord, sweep] {
+            noise = noise.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            let dither = (noise >> 8) as f32 / (1 << 24) as f32 - 0.5;
+            samples.push(dequantize(quantize(tone + dither * 0.02)));
+        }
+    }
+    samples
+}
+
+/// A sample as 16-bit PCM, the way the FLAC encoder rounds it
+pub fn quantize(sample: f32) -> i16 {
+    (sample * 32_768.0).round().clamp(-32_768.0, 32_767.0) as i16
+}
+
+fn dequantize(sample: i16) -> f32 {
+    sample as f32 / 32_768.0
+}
+
+/// Write `signal` to `path` as `codec`
+pub fn write_fixture(codec: Codec, path: &Path) -> anyhow::Result<()> {
+    let samples = signal();
+    match codec {
+        Codec::WavS16 | Codec::WavS24 | Codec::WavF32 => {
+            let (bits_per_sample, sample_format) = match codec {
+                Codec::WavS16 => (16, hound::SampleFormat::Int),
+                Codec::WavS24 => (24, hound::SampleFormat::Int),
+                _ => (32, hound::SampleFormat::Float),
+            };
+            let spec = hound::WavSpec {
+                channels: CHANNELS,
+                sample_rate: SAMPLE_RATE,
+                bits_per_sample,
+                sample_format,
+            };
+            let mut wav = hound::WavWriter::create(path, spec)?;
+            for &sample in &samples {
+                match codec {
+                    Codec::WavS16 => wav.write_sample(quantize(sample))?,
+                    Codec::WavS24 => wav.write_sample((sample * 8_388_608.0).round() as i32)?,
+                    _ => wav.write_sample(sample)?,
+                }
+            }
+            wav.finalize()?;
+        }
+        Codec::AiffS16 => write_aiff(path, &samples)?,
+        Codec::Flac16 | Codec::Flac24 | Codec::Mp3 => {
+            let (format, bits) = match codec {
+                Codec::Flac16 => (TranscodeFormat::Flac, 16),
+                Codec::Flac24 => (TranscodeFormat::Flac, 24),
+                _ => (TranscodeFormat::Mp3, 16),
+            };
+            let options = TranscodeOptions {
+                bitrate_kbps: Some(192),
+                copy_tags: false,
+            };
+            let mut file =
+                EncodedFile::create(path, format, &options, CHANNELS, SAMPLE_RATE, bits)?;
+            file.write(&samples)?;
+            file.finish()?;
+        }
+    }
+    Ok(())
+}
+
+/// 16-bit AIFF, which nothing else in the crate writes
+fn write_aiff(path: &Path, samples: &[f32]) -> anyhow::Result<()> {
+    let data_len = samples.len() as u32 * 2;
+    let mut out = Vec::with_capacity(54 + data_len as usize);
+    out.extend_from_slice(b"FORM");
+    out.extend_from_slice(&(4 + 26 + 16 + data_len).to_be_bytes());
+    out.extend_from_slice(b"AIFF");
+    out.extend_from_slice(b"COMM");
+    out.extend_from_slice(&18u32.to_be_bytes());
+    out.extend_from_slice(&CHANNELS.to_be_bytes());
+    out.extend_from_slice(&(FRAMES as u32).to_be_bytes());
+    out.extend_from_slice(&16u16.to_be_bytes());
+    // The rate is an 80-bit extended float: a biased exponent, then a mantissa with an
+    // explicit leading one.
+    let exponent = 31 - SAMPLE_RATE.leading_zeros();
+    out.extend_from_slice(&(16_383 + exponent as u16).to_be_bytes());
+    out.extend_from_slice(&((SAMPLE_RATE as u64) << (63 - exponent)).to_be_bytes());
+    out.extend_from_slice(b"SSND");
+    out.extend_from_slice(&(8 + data_len).to_be_bytes());
+    out.extend_from_slice(&[0; 8]);
+    for &sample in samples {
+        out.extend_from_slice(&quantize(sample).to_be_bytes());
+    }
+    fs::File::create(path)
+        .and_then(|mut file| file.write_all(&out))
+        .with_context(|| format!("failed to write {}", path.display()))
+}
+
+/// Everything a file decodes to
+pub struct Decoded {
+    pub sample_rate: u32,
+    pub channels: u16,
+    /// Interleaved
+    pub samples: Vec<f32>,
+}
+
+impl Decoded {
+    pub fn frames(&self) -> usize {
+        self.samples.len() / self.channels.max(1) as usize
+    }
+}
+
+/// Decode all of `path` as the engine's decoder does, without resampling or mixing
+pub fn decode(path: &Path) -> anyhow::Result<Decoded> {
+    let source = SymphoniaSource::open(path)?;
+    let (sample_rate, channels) = (source.sample_rate(), source.channels());
+    Ok(Decoded {
+        sample_rate,
+        channels,
+        samples: source.collect(),
+    })
+}
+
+/// Play `path` through the pipeline at `sample_rate` with default settings and no
+/// output device, returning the interleaved stereo it renders
+pub fn render(path: &Path, sample_rate: u32) -> anyhow::Result<Vec<f32>> {
+    let song = Song {
+        title: path.display().to_string(),
+        file_path: path.to_string_lossy().into_owned(),
+        ..Song::default()
+    };
+    engine::render(&song, sample_rate)
+}
+
+/// Hex MD5 of samples as little-endian 16-bit PCM, so rounding far below what can be
+/// heard doesn't change it
+pub fn checksum(samples: &[f32]) -> String {
+    let mut hasher = Md5::new();
+    for &sample in samples {
+        hasher.update(quantize(sample).to_le_bytes());
+    }
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
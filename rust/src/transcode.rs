@@ -0,0 +1,631 @@
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context};
+use lofty::config::WriteOptions;
+use lofty::prelude::*;
+use mp3lame_encoder::{Bitrate, FlushGap, InterleavedPcm, MonoPcm, Quality, VbrMode};
+use rodio::Source;
+
+#[cfg(feature = "aac")]
+use crate::aac::{self, AacWriter};
+use crate::decoder::{self, SymphoniaSource};
+use crate::engine::{Downmix, Resampler};
+use crate::flac::FlacWriter;
+#[cfg(feature = "opus")]
+use crate::opus::{self, OpusWriter};
+use crate::{jobs, metadata};
+use crate::{JobKind, StreamSink, TranscodeEvent, TranscodeFormat, TranscodeOptions};
+
+/// Frames encoded at a time
+const CHUNK_FRAMES: usize = 4096;
+
+/// Emit `Progress` whenever this much more of the file has been converted
+const PROGRESS_STEP: f32 = 0.01;
+
+/// Bitrates MP3 allows, in kbps; requests in between are rounded to the nearest
+const MP3_BITRATES: [(u32, Bitrate); 16] = [
+    (8, Bitrate::Kbps8),
+    (16, Bitrate::Kbps16),
+    (24, Bitrate::Kbps24),
+    (32, Bitrate::Kbps32),
+    (40, Bitrate::Kbps40),
+    (48, Bitrate::Kbps48),
+    (64, Bitrate::Kbps64),
+    (80, Bitrate::Kbps80),
+    (96, Bitrate::Kbps96),
+    (112, Bitrate::Kbps112),
+    (128, Bitrate::Kbps128),
+    (160, Bitrate::Kbps160),
+    (192, Bitrate::Kbps192),
+    (224, Bitrate::Kbps224),
+    (256, Bitrate::Kbps256),
+    (320, Bitrate::Kbps320),
+];
+
+/// Input frames kept ahead of a `Resampling`'s output, more than its filter reaches
+const RESAMPLER_LOOKAHEAD: usize = 1024;
+
+/// Fail early for formats this build has no encoder for
+pub(crate) fn check_supported(format: TranscodeFormat) -> anyhow::Result<()> {
+    let missing = match format {
+        TranscodeFormat::Mp3 | TranscodeFormat::Flac => None,
+        TranscodeFormat::Opus => (!cfg!(feature = "opus")).then_some("opus"),
+        TranscodeFormat::Aac => (!cfg!(feature = "aac")).then_some("aac"),
+    };
+    match missing {
+        Some(feature) => Err(anyhow!(
+            "no {format:?} encoder in this build; it needs the `{feature}` feature"
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Extension of files in `format`
+pub(crate) fn extension(format: TranscodeFormat) -> &'static str {
+    match format {
+        TranscodeFormat::Mp3 => "mp3",
+        TranscodeFormat::Flac => "flac",
+        TranscodeFormat::Opus => "opus",
+        TranscodeFormat::Aac => "m4a",
+    }
+}
+
+/// Rate `format` is encoded at, for samples at `sample_rate`
+fn encoded_rate(format: TranscodeFormat, sample_rate: u32) -> u32 {
+    match format {
+        #[cfg(feature = "aac")]
+        TranscodeFormat::Aac if !aac::codes_rate(sample_rate) => aac::RESAMPLE_RATE,
+        #[cfg(feature = "opus")]
+        TranscodeFormat::Opus => opus::SAMPLE_RATE,
+        _ => sample_rate,
+    }
+}
+
+/// Queue converting `input` to `output` as a background job, returning its id for
+/// `jobs::cancel`
+pub(crate) fn start(
+    input: PathBuf,
+    output: PathBuf,
+    format: TranscodeFormat,
+    options: TranscodeOptions,
+    sink: StreamSink<TranscodeEvent>,
+) -> u32 {
+    jobs::submit(JobKind::Transcode, move |job| {
+        let mut reported = 0.0;
+        let progress = |fraction: f32| {
+            if fraction - reported >= PROGRESS_STEP {
+                reported = fraction;
+                job.progress((fraction * 100.0) as u64, Some(100));
+                if sink.add(TranscodeEvent::Progress { fraction }).is_err() {
+                    job.cancelled().store(true, Ordering::Relaxed);
+                }
+            }
+            !job.is_cancelled()
+        };
+        let result = convert(&input, Span::default(), &output, format, &options, progress);
+        let (event, result) = match result {
+            _ if job.is_cancelled() => (TranscodeEvent::Cancelled, Ok(())),
+            Ok(()) => {
+                let path = output.to_string_lossy().into_owned();
+                (TranscodeEvent::Finished { path }, Ok(()))
+            }
+            Err(e) => {
+                let message = format!("{e:#}");
+                (TranscodeEvent::Failed { message }, Err(e))
+            }
+        };
+        let _ = sink.add(event);
+        result
+    })
+}
+
+/// The file a conversion or copy is written to until it completes, so a failed or
+/// cancelled one never leaves a truncated file at `output`
+pub(crate) fn partial_path(output: &Path) -> PathBuf {
+    let mut name = output.as_os_str().to_owned();
+    name.push(".part");
+    PathBuf::from(name)
+}
+
+/// Part of a file to convert, in seconds, to cut CUE sheet tracks out of their image
+#[derive(Clone, Copy, Default)]
+pub(crate) struct Span {
+    pub start: f64,
+    /// `None` converts to the end of the file
+    pub end: Option<f64>,
+}
+
+/// Convert `span` of `input` to `output`, telling `progress` the fraction done after
+/// each chunk; it returns false to stop
+pub(crate) fn convert(
+    input: &Path,
+    span: Span,
+    output: &Path,
+    format: TranscodeFormat,
+    options: &TranscodeOptions,
+    progress: impl FnMut(f32) -> bool,
+) -> anyhow::Result<()> {
+    check_supported(format)?;
+    anyhow::ensure!(
+        input != output,
+        "can't convert {} into itself",
+        input.display()
+    );
+    let (source, length) = open_span(input, span)?;
+    let end = span.end.or(length);
+    let expected_secs = end.map(|end| (end - span.start).max(0.0));
+
+    match format {
+        TranscodeFormat::Mp3 | TranscodeFormat::Aac | TranscodeFormat::Opus => {
+            let source = Downmix::new(source);
+            let (channels, rate) = (source.channels(), source.sample_rate());
+            let file = EncodedFile::create(output, format, options, channels, rate, 16)?;
+            encode(source, file, expected_secs, progress)?;
+        }
+        TranscodeFormat::Flac => {
+            // Deeper sources keep their precision; symphonia decodes them exactly to f32.
+            let deep = decoder::probe(input)?
+                .bit_depth
+                .is_some_and(|bits| bits > 16);
+            let bits = if deep { 24 } else { 16 };
+            let (channels, rate) = (source.channels(), source.sample_rate());
+            let file = EncodedFile::create(output, format, options, channels, rate, bits)?;
+            encode(source, file, expected_secs, progress)?;
+        }
+    }
+    if options.copy_tags {
+        if let Err(e) = copy_tags(input, output) {
+            log::warn!("couldn't copy tags to {}: {e:#}", output.display());
+        }
+    }
+    log::info!("converted {} to {}", input.display(), output.display());
+    Ok(())
+}
+
+/// Encode `span` of `input` to MP3 into `out` as it decodes, for players to start on
+/// before the encoding is done
+///
+/// The stream has no VBR length header, since it can't be gone back to fill it in.
+pub(crate) fn stream_mp3(
+    input: &Path,
+    span: Span,
+    options: &TranscodeOptions,
+    out: impl Write,
+) -> anyhow::Result<()> {
+    let (source, _) = open_span(input, span)?;
+    let mut source = Downmix::new(source);
+    let mut writer = Mp3Writer::new(out, source.channels(), source.sample_rate(), options, false)?;
+    let channels = source.channels().max(1) as usize;
+    let mut chunk = Vec::with_capacity(CHUNK_FRAMES * channels);
+    loop {
+        chunk.clear();
+        chunk.extend(source.by_ref().take(CHUNK_FRAMES * channels));
+        if chunk.is_empty() {
+            break;
+        }
+        writer.write(&chunk)?;
+    }
+    writer.flush()?;
+    Ok(writer.file.flush()?)
+}
+
+/// Decoded `span` of `input`, with the length of the whole file if known
+fn open_span(
+    input: &Path,
+    span: Span,
+) -> anyhow::Result<(Box<dyn Source<Item = f32> + Send>, Option<f64>)> {
+    let mut file = SymphoniaSource::open(input)?;
+    let length = file.total_duration().map(|d| d.as_secs_f64());
+    if span.start > 0.0 {
+        file.try_seek(Duration::from_secs_f64(span.start))
+            .map_err(|e| anyhow!("couldn't seek in {}: {e}", input.display()))?;
+    }
+    let source: Box<dyn Source<Item = f32> + Send> = match span.end {
+        Some(end) => Box::new(file.take_duration(Duration::from_secs_f64(end - span.start))),
+        None => Box::new(file),
+    };
+    Ok((source, length))
+}
+
+enum Encoder {
+    Mp3(Mp3Writer),
+    /// With its bit depth
+    Flac(FlacWriter, u32),
+    #[cfg(feature = "aac")]
+    Aac(Box<AacWriter>),
+    #[cfg(feature = "opus")]
+    Opus(OpusWriter),
+}
+
+impl Encoder {
+    fn write(&mut self, samples: &[f32]) -> anyhow::Result<()> {
+        match self {
+            Encoder::Mp3(mp3) => mp3.write(samples),
+            Encoder::Flac(flac, bits) => {
+                let scale = (1i64 << (*bits - 1)) as f32;
+                let samples: Vec<i32> = samples
+                    .iter()
+                    .map(|&s| (s * scale).round().clamp(-scale, scale - 1.0) as i32)
+                    .collect();
+                flac.write(&samples)
+            }
+            #[cfg(feature = "aac")]
+            Encoder::Aac(aac) => aac.write(samples),
+            #[cfg(feature = "opus")]
+            Encoder::Opus(opus) => opus.write(samples),
+        }
+    }
+
+    fn finish(self) -> anyhow::Result<()> {
+        match self {
+            Encoder::Mp3(mp3) => mp3.finish(),
+            Encoder::Flac(flac, _) => flac.finish(),
+            #[cfg(feature = "aac")]
+            Encoder::Aac(aac) => aac.finish(),
+            #[cfg(feature = "opus")]
+            Encoder::Opus(opus) => opus.finish(),
+        }
+    }
+}
+
+/// A file being encoded a chunk of samples at a time
+///
+/// It's written to `partial_path` and only moved into place by `finish`; dropping it
+/// unfinished deletes what was written.
+pub(crate) struct EncodedFile {
+    /// `None` once finished
+    encoder: Option<Encoder>,
+    /// For formats not taking the samples' rate
+    resampling: Option<Resampling>,
+    partial: PathBuf,
+    output: PathBuf,
+}
+
+impl EncodedFile {
+    /// `bits` is the bit depth FLAC is written at; MP3, AAC and Opus take up to two
+    /// channels
+    pub fn create(
+        output: &Path,
+        format: TranscodeFormat,
+        options: &TranscodeOptions,
+        channels: u16,
+        sample_rate: u32,
+        bits: u32,
+    ) -> anyhow::Result<Self> {
+        check_supported(format)?;
+        let encoded_rate = encoded_rate(format, sample_rate);
+        let resampling = (encoded_rate != sample_rate)
+            .then(|| Resampling::new(channels, sample_rate, encoded_rate));
+        let (channels, sample_rate) = match &resampling {
+            Some(resampling) => (resampling.channels(), encoded_rate),
+            None => (channels, sample_rate),
+        };
+        let partial = partial_path(output);
+        let encoder = match format {
+            TranscodeFormat::Mp3 => {
+                Mp3Writer::create(&partial, channels, sample_rate, options).map(Encoder::Mp3)
+            }
+            TranscodeFormat::Flac => FlacWriter::create(&partial, channels, sample_rate, bits)
+                .map(|writer| Encoder::Flac(writer, bits)),
+            #[cfg(feature = "aac")]
+            TranscodeFormat::Aac => AacWriter::create(&partial, channels, sample_rate, options)
+                .map(|writer| Encoder::Aac(Box::new(writer))),
+            #[cfg(feature = "opus")]
+            TranscodeFormat::Opus => {
+                OpusWriter::create(&partial, channels, sample_rate, options).map(Encoder::Opus)
+            }
+            #[cfg(not(all(feature = "aac", feature = "opus")))]
+            _ => unreachable!("check_supported turns {format:?} down"),
+        };
+        let encoder = encoder.inspect_err(|_| {
+            let _ = fs::remove_file(&partial);
+        })?;
+        Ok(EncodedFile {
+            encoder: Some(encoder),
+            resampling,
+            partial,
+            output: output.to_path_buf(),
+        })
+    }
+
+    /// Encode interleaved samples
+    pub fn write(&mut self, samples: &[f32]) -> anyhow::Result<()> {
+        let encoder = self
+            .encoder
+            .as_mut()
+            .expect("encoded file is already finished");
+        match &mut self.resampling {
+            Some(resampling) => encoder.write(resampling.push(samples)),
+            None => encoder.write(samples),
+        }
+    }
+
+    /// Write out the rest and move the file into place
+    pub fn finish(mut self) -> anyhow::Result<()> {
+        let mut encoder = self
+            .encoder
+            .take()
+            .expect("encoded file is already finished");
+        let rest = match &mut self.resampling {
+            Some(resampling) => encoder.write(resampling.finish()),
+            None => Ok(()),
+        };
+        let result = rest.and_then(|()| encoder.finish()).and_then(|()| {
+            fs::rename(&self.partial, &self.output)
+                .with_context(|| format!("failed to move {} into place", self.output.display()))
+        });
+        if result.is_err() {
+            let _ = fs::remove_file(&self.partial);
+        }
+        result
+    }
+}
+
+impl Drop for EncodedFile {
+    fn drop(&mut self) {
+        // The encoder holds the file open, which must be closed before it can go.
+        if let Some(encoder) = self.encoder.take() {
+            drop(encoder);
+            let _ = fs::remove_file(&self.partial);
+        }
+    }
+}
+
+/// Samples pushed through a `Resampler` as they're encoded
+struct Resampling {
+    resampler: Resampler<Pushed>,
+    out: Vec<f32>,
+}
+
+impl Resampling {
+    fn new(channels: u16, sample_rate: u32, encoded_rate: u32) -> Self {
+        let pushed = Pushed {
+            samples: VecDeque::new(),
+            channels,
+            sample_rate,
+        };
+        Resampling {
+            resampler: Resampler::new(pushed, encoded_rate),
+            out: Vec::new(),
+        }
+    }
+
+    /// Channels of the output
+    fn channels(&self) -> u16 {
+        self.resampler.channels()
+    }
+
+    /// Take interleaved samples, returning the output they complete
+    fn push(&mut self, samples: &[f32]) -> &[f32] {
+        let (channels, out_channels) = (self.resampler.source_mut().channels, self.channels());
+        self.resampler.source_mut().samples.extend(samples);
+        self.out.clear();
+        // Never letting the input run dry, which would end it.
+        let lookahead = RESAMPLER_LOOKAHEAD * channels as usize;
+        while self.resampler.source_mut().samples.len() >= lookahead {
+            let frame = self.resampler.by_ref().take(out_channels as usize);
+            self.out.extend(frame);
+        }
+        &self.out
+    }
+
+    /// The rest of the output
+    fn finish(&mut self) -> &[f32] {
+        self.out.clear();
+        self.out.extend(self.resampler.by_ref());
+        &self.out
+    }
+}
+
+/// Input of a `Resampling`
+struct Pushed {
+    samples: VecDeque<f32>,
+    channels: u16,
+    sample_rate: u32,
+}
+
+impl Iterator for Pushed {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        self.samples.pop_front()
+    }
+}
+
+impl Source for Pushed {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// Decode `source` to the end into `file`, reporting progress on the way
+fn encode(
+    mut source: impl Source<Item = f32>,
+    mut file: EncodedFile,
+    expected_secs: Option<f64>,
+    mut progress: impl FnMut(f32) -> bool,
+) -> anyhow::Result<()> {
+    let channels = source.channels().max(1) as usize;
+    let expected_samples =
+        expected_secs.map(|secs| secs * source.sample_rate() as f64 * channels as f64);
+    let mut chunk = Vec::with_capacity(CHUNK_FRAMES * channels);
+    let mut converted = 0u64;
+    loop {
+        chunk.clear();
+        chunk.extend(source.by_ref().take(CHUNK_FRAMES * channels));
+        if chunk.is_empty() {
+            break;
+        }
+        file.write(&chunk)?;
+        converted += chunk.len() as u64;
+        let fraction = match expected_samples {
+            Some(expected) if expected > 0.0 => (converted as f64 / expected).min(1.0) as f32,
+            _ => 0.0,
+        };
+        if !progress(fraction) {
+            anyhow::bail!("conversion cancelled");
+        }
+    }
+    file.finish()
+}
+
+/// MP3 through LAME, at a constant bitrate or its high quality VBR preset
+struct Mp3Writer<W: Write = BufWriter<File>> {
+    lame: mp3lame_encoder::Encoder,
+    file: W,
+    channels: usize,
+    buffer: Vec<u8>,
+}
+
+impl Mp3Writer {
+    fn create(
+        path: &Path,
+        channels: u16,
+        sample_rate: u32,
+        options: &TranscodeOptions,
+    ) -> anyhow::Result<Self> {
+        let file = BufWriter::new(File::create(path)?);
+        Mp3Writer::new(file, channels, sample_rate, options, true)
+    }
+
+    /// Flush the last frames and fill in the header VBR players read the length from
+    fn finish(mut self) -> anyhow::Result<()> {
+        self.flush()?;
+        if self.lame.is_lame_tag_written() {
+            self.buffer.clear();
+            self.buffer.reserve(self.lame.lame_tag_size());
+            if self.lame.lame_tag_encode_to_vec(&mut self.buffer).is_some() {
+                self.file.seek(SeekFrom::Start(0))?;
+                self.file.write_all(&self.buffer)?;
+            }
+        }
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+impl<W: Write> Mp3Writer<W> {
+    /// `vbr_tag` leaves room for the header `finish` fills in
+    fn new(
+        file: W,
+        channels: u16,
+        sample_rate: u32,
+        options: &TranscodeOptions,
+        vbr_tag: bool,
+    ) -> anyhow::Result<Self> {
+        let lame_error = |e| anyhow!("MP3 encoder rejected the settings: {e}");
+        let mut builder =
+            mp3lame_encoder::Builder::new().context("couldn't set up the MP3 encoder")?;
+        builder
+            .set_num_channels(channels as u8)
+            .map_err(lame_error)?;
+        builder.set_sample_rate(sample_rate).map_err(lame_error)?;
+        match options.bitrate_kbps {
+            Some(kbps) => {
+                let (_, bitrate) = MP3_BITRATES
+                    .into_iter()
+                    .min_by_key(|(rate, _)| rate.abs_diff(kbps))
+                    .expect("bitrates aren't empty");
+                builder.set_brate(bitrate).map_err(lame_error)?;
+            }
+            // LAME's -V 2, transparent for most listeners at around 190 kbps
+            None => {
+                builder.set_vbr_mode(VbrMode::Mtrh).map_err(lame_error)?;
+                builder
+                    .set_vbr_quality(Quality::NearBest)
+                    .map_err(lame_error)?;
+            }
+        }
+        builder.set_quality(Quality::NearBest).map_err(lame_error)?;
+        builder.set_to_write_vbr_tag(vbr_tag).map_err(lame_error)?;
+        Ok(Mp3Writer {
+            lame: builder.build().map_err(lame_error)?,
+            file,
+            channels: channels as usize,
+            buffer: Vec::new(),
+        })
+    }
+
+    fn write(&mut self, samples: &[f32]) -> anyhow::Result<()> {
+        let frames = samples.len() / self.channels;
+        self.buffer.clear();
+        self.buffer
+            .reserve(mp3lame_encoder::max_required_buffer_size(frames));
+        let encoded = match self.channels {
+            1 => self.lame.encode_to_vec(MonoPcm(samples), &mut self.buffer),
+            _ => self
+                .lame
+                .encode_to_vec(InterleavedPcm(samples), &mut self.buffer),
+        };
+        encoded.map_err(|e| anyhow!("MP3 encoding failed: {e}"))?;
+        self.file.write_all(&self.buffer)?;
+        Ok(())
+    }
+
+    /// Write out the frames LAME still holds
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.buffer.clear();
+        self.buffer
+            .reserve(mp3lame_encoder::max_required_buffer_size(0));
+        self.lame
+            .flush_to_vec::<FlushGap>(&mut self.buffer)
+            .map_err(|e| anyhow!("MP3 encoding failed: {e}"))?;
+        self.file.write_all(&self.buffer)?;
+        Ok(())
+    }
+}
+
+/// Copy the tags of `input`, cover art included, to `output` in the tag format it uses
+fn copy_tags(input: &Path, output: &Path) -> anyhow::Result<()> {
+    let source = metadata::read_tagged(input)?;
+    let Some(tag) = source.primary_tag().or_else(|| source.first_tag()) else {
+        return Ok(());
+    };
+    let target = lofty::read_from_path(output)?;
+    let mut tag = tag.clone();
+    tag.re_map(target.primary_tag_type());
+    tag.save_to_path(output, WriteOptions::default())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rodio::buffer::SamplesBuffer;
+
+    use super::*;
+
+    #[test]
+    fn resampling_pushed_samples_matches_resampling_the_whole_source() {
+        let samples: Vec<f32> = (0..96_000 * 2)
+            .map(|i| ((i * 7919) % 2001) as f32 / 1000.0 - 1.0)
+            .collect();
+        let expected: Vec<f32> =
+            Resampler::new(SamplesBuffer::new(2, 96_000, samples.clone()), 48_000).collect();
+
+        let mut resampling = Resampling::new(2, 96_000, 48_000);
+        let mut resampled = Vec::new();
+        // Uneven pushes, some shorter than the lookahead
+        for chunk in samples.chunks(2 * 777) {
+            resampled.extend_from_slice(resampling.push(chunk));
+        }
+        resampled.extend_from_slice(resampling.finish());
+        assert_eq!(resampled, expected);
+    }
+}
@@ -0,0 +1,263 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use notify::event::{AccessKind, AccessMode, ModifyKind, RenameMode};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use walkdir::WalkDir;
+
+use crate::{cue, library, scanner, LibraryEvent, Song, StreamSink};
+
+/// Changes are applied once the watched folders have been quiet this long, so a file
+/// being copied is read once it is complete
+pub(crate) const SETTLE_TIME: Duration = Duration::from_secs(2);
+
+/// The running watcher and its id; dropping it ends its worker thread
+static WATCHER: Mutex<Option<(u32, RecommendedWatcher)>> = Mutex::new(None);
+
+/// What happened to a path since the last batch was applied
+enum Change {
+    /// Created, written or moved in from elsewhere; read it (again)
+    Updated,
+    Removed,
+    /// Renamed within the watched folders to this path
+    MovedTo(PathBuf),
+}
+
+/// Watch `roots` recursively and keep the open library in step with them
+///
+/// Replaces any earlier watch. Each settled batch of changes is reported as one
+/// `LibraryChanged` event.
+pub(crate) fn start(roots: Vec<PathBuf>, sink: StreamSink<LibraryEvent>) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        library::is_open(),
+        "library database is not open; call open_library first"
+    );
+    static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+    let watch_id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    for root in &roots {
+        watcher.watch(root, RecursiveMode::Recursive)?;
+    }
+    thread::Builder::new()
+        .name("tunes4r-watcher".into())
+        .spawn(move || {
+            run(rx, sink);
+            // Nobody is listening any more, so stop watching, unless already replaced.
+            let mut watcher = WATCHER.lock().unwrap();
+            if watcher.as_ref().is_some_and(|(id, _)| *id == watch_id) {
+                watcher.take();
+            }
+        })?;
+    *WATCHER.lock().unwrap() = Some((watch_id, watcher));
+    log::info!("watching {} library folders", roots.len());
+    Ok(())
+}
+
+pub(crate) fn stop() {
+    WATCHER.lock().unwrap().take();
+}
+
+/// Apply changes as they settle, until the watcher is dropped or the sink closes
+fn run(rx: Receiver<notify::Result<Event>>, sink: StreamSink<LibraryEvent>) {
+    let mut pending: HashMap<PathBuf, Change> = HashMap::new();
+    loop {
+        let received = match pending.is_empty() {
+            true => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            false => rx.recv_timeout(SETTLE_TIME),
+        };
+        match received {
+            Ok(Ok(event)) => collect(event, &mut pending),
+            Ok(Err(e)) => {
+                let message = e.to_string();
+                let path = e.paths.first().map(|p| p.to_string_lossy().into_owned());
+                let event = LibraryEvent::Failed {
+                    path: path.unwrap_or_default(),
+                    message,
+                };
+                if sink.add(event).is_err() {
+                    break;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                let batch = std::mem::take(&mut pending);
+                if !apply(batch, &sink) {
+                    break;
+                }
+            }
+            // The watcher was stopped or replaced.
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
+
+fn collect(event: Event, pending: &mut HashMap<PathBuf, Change>) {
+    let mut paths = event.paths.into_iter();
+    match event.kind {
+        EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
+            if let (Some(from), Some(to)) = (paths.next(), paths.next()) {
+                pending.insert(from, Change::MovedTo(to));
+            }
+        }
+        EventKind::Modify(ModifyKind::Name(RenameMode::From)) | EventKind::Remove(_) => {
+            for path in paths {
+                pending.insert(path, Change::Removed);
+            }
+        }
+        // Platforms that don't say which side of a rename a path is on
+        EventKind::Modify(ModifyKind::Name(_)) => {
+            for path in paths {
+                let change = match path.exists() {
+                    true => Change::Updated,
+                    false => Change::Removed,
+                };
+                pending.insert(path, change);
+            }
+        }
+        EventKind::Create(_)
+        | EventKind::Modify(ModifyKind::Data(_) | ModifyKind::Any)
+        | EventKind::Access(AccessKind::Close(AccessMode::Write)) => {
+            for path in paths {
+                pending.insert(path, Change::Updated);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Apply a batch of changes to the library and report them; false once the listener
+/// has gone away
+fn apply(batch: HashMap<PathBuf, Change>, sink: &StreamSink<LibraryEvent>) -> bool {
+    let mut updated = Vec::new();
+    let mut removed = Vec::new();
+    let mut failures = Vec::new();
+    // Moves first, so a file moved and then rewritten is read at its new path, and
+    // removals last, so a file reappearing elsewhere can take over its songs.
+    let mut batch: Vec<_> = batch.into_iter().collect();
+    batch.sort_by_key(|(_, change)| match change {
+        Change::MovedTo(_) => 0,
+        Change::Updated => 1,
+        Change::Removed => 2,
+    });
+    for (path, change) in batch {
+        let key = path.to_string_lossy().into_owned();
+        let result = match change {
+            Change::MovedTo(to) => {
+                library::with_library(|lib| lib.move_path(&key, &to.to_string_lossy())).map(
+                    |songs| {
+                        // Renamed from something that wasn't in the library, like a
+                        // partial download
+                        if songs.is_empty() {
+                            read_path(&to, &mut updated, &mut failures);
+                        }
+                        updated.extend(songs);
+                    },
+                )
+            }
+            Change::Removed => {
+                library::with_library(|lib| lib.remove_path(&key)).map(|ids| removed.extend(ids))
+            }
+            Change::Updated => {
+                read_path(&path, &mut updated, &mut failures);
+                Ok(())
+            }
+        };
+        if let Err(e) = result {
+            failures.push((key, format!("{e:#}")));
+        }
+    }
+
+    // A song that came back or moved in this batch isn't gone.
+    removed.retain(|id| !updated.iter().any(|song: &Song| &song.id == id));
+    for (path, message) in failures {
+        log::warn!("failed to update library for {path}: {message}");
+        if sink.add(LibraryEvent::Failed { path, message }).is_err() {
+            return false;
+        }
+    }
+    if updated.is_empty() && removed.is_empty() {
+        return true;
+    }
+    log::info!(
+        "library folders changed: {} songs updated, {} removed",
+        updated.len(),
+        removed.len()
+    );
+    sink.add(LibraryEvent::LibraryChanged { updated, removed })
+        .is_ok()
+}
+
+/// Read and store a new or changed file, CUE sheet or directory of them
+fn read_path(path: &Path, updated: &mut Vec<Song>, failures: &mut Vec<(String, String)>) {
+    if path.is_dir() {
+        for entry in WalkDir::new(path).follow_links(true).into_iter().flatten() {
+            if entry.file_type().is_file() {
+                read_path(entry.path(), updated, failures);
+            }
+        }
+        return;
+    }
+    if !path.is_file() {
+        // Gone again already, like most temporary files.
+        return;
+    }
+    let file_path = path.to_string_lossy().into_owned();
+    let result = if scanner::is_cue_sheet(path) {
+        read_cue_sheet(path)
+    } else if scanner::is_supported(path) {
+        read_audio_file(path, &file_path)
+    } else {
+        return;
+    };
+    scanner::note_read(&file_path, result.as_ref().err());
+    match result {
+        Ok(songs) => updated.extend(songs),
+        Err(e) => failures.push((file_path, format!("{e:#}"))),
+    }
+}
+
+/// Read a quarantined file or CUE sheet again, storing its songs if it reads now
+///
+/// A file that's gone is just let out of quarantine.
+pub(crate) fn retry(path: &Path) -> anyhow::Result<Vec<Song>> {
+    let file_path = path.to_string_lossy().into_owned();
+    if !path.is_file() {
+        library::with_library(|lib| lib.release_file(&file_path))?;
+        return Ok(Vec::new());
+    }
+    let result = match scanner::is_cue_sheet(path) {
+        true => read_cue_sheet(path),
+        false => read_audio_file(path, &file_path),
+    };
+    scanner::note_read(&file_path, result.as_ref().err());
+    result
+}
+
+fn read_cue_sheet(path: &Path) -> anyhow::Result<Vec<Song>> {
+    let sheet = cue::read(path)?;
+    let mut songs = Vec::new();
+    for file in sheet.files.iter().filter(|f| !f.tracks.is_empty()) {
+        if !file.path.is_file() {
+            continue;
+        }
+        let tracks = scanner::read_cue_tracks(&sheet, file)?;
+        songs.extend(scanner::store_file_songs(&file.path, &tracks)?);
+    }
+    Ok(songs)
+}
+
+/// Read and store a single-song file
+fn read_audio_file(path: &Path, file_path: &str) -> anyhow::Result<Vec<Song>> {
+    // An image split by a CUE sheet is re-read when its sheet changes instead.
+    let stored = library::with_library(|lib| lib.get_file_songs(file_path))?;
+    if stored.iter().any(cue::is_track) {
+        return Ok(Vec::new());
+    }
+    let read = scanner::read_file(path)?;
+    scanner::store_file_songs(path, std::slice::from_ref(&read))
+}
@@ -0,0 +1,161 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{self, File};
+use std::hash::Hasher;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+
+use rodio::Source;
+
+use crate::decoder::SymphoniaSource;
+use crate::jobs::{self, Job};
+use crate::{library, JobKind, StorageKind, StreamSink, WaveformEvent};
+
+/// Frames reduced into one fine-grained window before bucketing
+const WINDOW_FRAMES: usize = 1024;
+
+/// Emit `Progress` whenever this much more of the file has been decoded
+const PROGRESS_STEP: f32 = 0.05;
+
+/// Queue computing the waveform of `path` as a background job, returning its id for
+/// `jobs::cancel`
+pub(crate) fn start(path: PathBuf, buckets: u32, sink: StreamSink<WaveformEvent>) -> u32 {
+    jobs::submit(JobKind::Waveform, move |job| {
+        let (event, result) = match generate(&path, buckets.max(1) as usize, &sink, job) {
+            _ if job.is_cancelled() => (WaveformEvent::Cancelled, Ok(())),
+            Ok((peaks, rms)) => (WaveformEvent::Finished { peaks, rms }, Ok(())),
+            Err(e) => {
+                let message = e.to_string();
+                (WaveformEvent::Failed { message }, Err(e))
+            }
+        };
+        let _ = sink.add(event);
+        result
+    })
+}
+
+/// Peak and RMS level per bucket, served from the cache when the file is unchanged
+fn generate(
+    path: &Path,
+    buckets: usize,
+    sink: &StreamSink<WaveformEvent>,
+    job: &Job,
+) -> anyhow::Result<(Vec<f32>, Vec<f32>)> {
+    let cached = match library::cache_dir(StorageKind::Waveforms) {
+        Some(dir) => Some(dir.join(format!("{:016x}-{buckets}.bin", content_hash(path)?))),
+        None => None,
+    };
+    if let Some(levels) = cached.as_deref().and_then(|c| read_cache(c, buckets)) {
+        return Ok(levels);
+    }
+
+    let levels = compute(path, buckets, sink, job)?;
+    if let Some(cached) = cached {
+        if let Err(e) = write_cache(&cached, &levels) {
+            log::warn!("failed to cache waveform at {}: {e}", cached.display());
+        }
+    }
+    Ok(levels)
+}
+
+pub(crate) fn content_hash(path: &Path) -> io::Result<u64> {
+    let mut file = File::open(path)?;
+    let mut hasher = DefaultHasher::new();
+    let mut chunk = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut chunk)?;
+        if read == 0 {
+            return Ok(hasher.finish());
+        }
+        hasher.write(&chunk[..read]);
+    }
+}
+
+fn compute(
+    path: &Path,
+    buckets: usize,
+    sink: &StreamSink<WaveformEvent>,
+    job: &Job,
+) -> anyhow::Result<(Vec<f32>, Vec<f32>)> {
+    let source = SymphoniaSource::open(path)?;
+    let channels = source.channels().max(1) as usize;
+    let expected_samples = source
+        .total_duration()
+        .map(|d| d.as_secs_f64() * source.sample_rate() as f64 * channels as f64);
+
+    // (peak, sum of squares, samples) per window, so the file is decoded only once
+    // even when its length isn't known up front.
+    let mut windows: Vec<(f32, f64, usize)> = Vec::new();
+    let mut window = (0.0f32, 0.0f64, 0usize);
+    let mut decoded = 0u64;
+    let mut reported = 0.0;
+    for sample in source {
+        window.0 = window.0.max(sample.abs());
+        window.1 += (sample * sample) as f64;
+        window.2 += 1;
+        if window.2 == WINDOW_FRAMES * channels {
+            windows.push(std::mem::take(&mut window));
+            if job.is_cancelled() {
+                anyhow::bail!("waveform cancelled");
+            }
+        }
+        decoded += 1;
+        if let Some(expected) = expected_samples {
+            let fraction = (decoded as f64 / expected).min(1.0) as f32;
+            if fraction - reported >= PROGRESS_STEP {
+                reported = fraction;
+                job.progress((fraction * 100.0) as u64, Some(100));
+                if sink.add(WaveformEvent::Progress { fraction }).is_err() {
+                    job.cancelled().store(true, Ordering::Relaxed);
+                    anyhow::bail!("waveform listener went away");
+                }
+            }
+        }
+    }
+    if window.2 > 0 {
+        windows.push(window);
+    }
+
+    let mut peaks = vec![0.0; buckets];
+    let mut rms = vec![0.0; buckets];
+    if windows.is_empty() {
+        return Ok((peaks, rms));
+    }
+    for bucket in 0..buckets {
+        let start = bucket * windows.len() / buckets;
+        let end = ((bucket + 1) * windows.len() / buckets).max(start + 1);
+        let slice = &windows[start..end];
+        let (sum, count) = slice
+            .iter()
+            .fold((0.0, 0), |(sum, count), w| (sum + w.1, count + w.2));
+        peaks[bucket] = slice.iter().fold(0.0, |peak: f32, w| peak.max(w.0));
+        rms[bucket] = (sum / count.max(1) as f64).sqrt() as f32;
+    }
+    Ok((peaks, rms))
+}
+
+/// Cache files hold the peaks then the RMS levels, as little-endian `f32`s
+fn read_cache(path: &Path, buckets: usize) -> Option<(Vec<f32>, Vec<f32>)> {
+    let bytes = fs::read(path).ok()?;
+    if bytes.len() != buckets * 2 * 4 {
+        return None;
+    }
+    let mut values = bytes
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+    let peaks = values.by_ref().take(buckets).collect();
+    let rms = values.collect();
+    Some((peaks, rms))
+}
+
+fn write_cache(path: &Path, (peaks, rms): &(Vec<f32>, Vec<f32>)) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let bytes: Vec<u8> = peaks
+        .iter()
+        .chain(rms)
+        .flat_map(|v| v.to_le_bytes())
+        .collect();
+    fs::write(path, bytes)
+}
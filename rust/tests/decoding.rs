@@ -0,0 +1,87 @@
+// Every fixture codec decoded, and played through the pipeline, against checksums in
+// `tests/fixtures/decoded.golden`
+//
+// Run with `TUNES4R_BLESS=1` to rewrite the golden file after a deliberate change to
+// the decoder or the pipeline, and review the diff.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use tunes4r::test_fixtures::{self, Codec};
+
+/// A rate fixtures aren't written at, so rendering them goes through the resampler
+const RENDER_RATE: u32 = 48_000;
+
+fn golden_path() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/decoded.golden")
+}
+
+/// One line of the golden file: what, its rate, channels, frames and checksum
+fn line(name: &str, rate: u32, channels: u16, samples: &[f32]) -> String {
+    let frames = samples.len() / channels as usize;
+    let checksum = test_fixtures::checksum(samples);
+    format!("{name} {rate} {channels} {frames} {checksum}")
+}
+
+#[test]
+fn decoded_audio_matches_golden_checksums() {
+    let dir = tempfile::tempdir().unwrap();
+    let signal = test_fixtures::signal();
+    let mut lines = Vec::new();
+    for codec in Codec::ALL {
+        let path = dir
+            .path()
+            .join(format!("{}.{}", codec.name(), codec.extension()));
+        test_fixtures::write_fixture(codec, &path).unwrap();
+
+        let decoded = test_fixtures::decode(&path).unwrap();
+        assert_eq!(decoded.sample_rate, test_fixtures::SAMPLE_RATE, "{codec:?}");
+        assert_eq!(decoded.channels, test_fixtures::CHANNELS, "{codec:?}");
+        if codec.is_lossless() {
+            assert_eq!(decoded.frames(), test_fixtures::FRAMES, "{codec:?}");
+            let mismatch = decoded
+                .samples
+                .iter()
+                .zip(&signal)
+                .position(|(&a, &b)| test_fixtures::quantize(a) != test_fixtures::quantize(b));
+            assert_eq!(mismatch, None, "{codec:?} decoded to other samples");
+        }
+        lines.push(line(
+            codec.name(),
+            decoded.sample_rate,
+            decoded.channels,
+            &decoded.samples,
+        ));
+
+        let rendered = test_fixtures::render(&path, RENDER_RATE).unwrap();
+        assert!(
+            rendered.iter().any(|sample| sample.abs() > 0.1),
+            "{codec:?} rendered silence"
+        );
+        lines.push(line(
+            &format!("{}@render", codec.name()),
+            RENDER_RATE,
+            2,
+            &rendered,
+        ));
+    }
+
+    let actual = lines.join("\n") + "\n";
+    let path = golden_path();
+    if std::env::var_os("TUNES4R_BLESS").is_some() {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, &actual).unwrap();
+        return;
+    }
+    let expected = fs::read_to_string(&path).unwrap_or_default();
+    let changed: Vec<_> = actual
+        .lines()
+        .filter(|line| !expected.lines().any(|expected| expected == *line))
+        .collect();
+    assert!(
+        changed.is_empty(),
+        "decoding changed; rerun with TUNES4R_BLESS=1 if that was intended:\n{}",
+        changed.join("\n")
+    );
+    assert_eq!(expected.lines().count(), actual.lines().count());
+}
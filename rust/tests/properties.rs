@@ -0,0 +1,389 @@
+// Property tests for the tag reader and writer and the playlist and library importers:
+// generated input has to round trip, and mangled input must fail cleanly, never panic
+//
+// Cases are drawn from a seeded generator, so a failure prints the seed that
+// reproduces it. `TUNES4R_PROPTEST_SEED` picks the seed and `TUNES4R_PROPTEST_CASES`
+// how many cases each property runs.
+
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tempfile::TempDir;
+use tunes4r::test_fixtures::{self, Codec};
+use tunes4r::{EngineConfig, LibraryFormat, LogLevel, Song};
+
+/// The library is process-wide, so tests that use one take turns
+static LIBRARY: Mutex<()> = Mutex::new(());
+
+/// Run `property` on `default_cases` generated cases, or as many as the environment
+/// asks for, reporting the seed of the first that fails
+fn check(name: &str, default_cases: u64, mut property: impl FnMut(&mut StdRng)) {
+    let env = |key: &str| std::env::var(key).ok().and_then(|value| value.parse().ok());
+    let base: u64 = env("TUNES4R_PROPTEST_SEED").unwrap_or(0x7475_6e65);
+    let cases = env("TUNES4R_PROPTEST_CASES").unwrap_or(default_cases);
+    for case in 0..cases {
+        let seed = base.wrapping_add(case);
+        let mut rng = StdRng::seed_from_u64(seed);
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| property(&mut rng))) {
+            eprintln!(
+                "{name} failed on case {case}; rerun it with TUNES4R_PROPTEST_SEED={seed} \
+                 TUNES4R_PROPTEST_CASES=1"
+            );
+            panic::resume_unwind(payload);
+        }
+    }
+}
+
+/// Letters, marks, symbols and emoji from several scripts, but no whitespace or
+/// characters with a meaning in tags, paths or playlists
+const ALPHABET: &[char] = &[
+    'a', 'Z', 'q', '7', '-', '_', '.', ',', '!', '&', '\'', '"', '<', '>', 'é', 'ß', 'ø', 'Ł', 'Ω',
+    'ж', 'Я', 'ש', 'ع', 'ह', 'ก', '日', '本', '語', '한', 'ん', '♪', '★', '€', '\u{301}', '😀',
+    '🎸', '𝄞',
+];
+
+/// A non-empty name of a few words that starts with a letter, so no format reads it
+/// as a number or a placeholder
+fn name(rng: &mut StdRng) -> String {
+    let words = rng.gen_range(1..=4);
+    let mut name = String::from(['A', 'ä', 'Ж', '曲'][rng.gen_range(0..4)]);
+    for word in 0..words {
+        if word > 0 {
+            name.push(' ');
+        }
+        for _ in 0..rng.gen_range(1..=8) {
+            name.push(ALPHABET[rng.gen_range(0..ALPHABET.len())]);
+        }
+    }
+    name
+}
+
+/// A name usable as a file name on every platform
+fn file_name(rng: &mut StdRng) -> String {
+    name(rng)
+        .chars()
+        .filter(|c| !"<>\"'.,!&".contains(*c))
+        .collect()
+}
+
+/// `bytes` with some flipped, overwritten, dropped or repeated, or cut short
+fn mangle(rng: &mut StdRng, mut bytes: Vec<u8>) -> Vec<u8> {
+    for _ in 0..rng.gen_range(1..=16) {
+        if bytes.is_empty() {
+            break;
+        }
+        let at = rng.gen_range(0..bytes.len());
+        match rng.gen_range(0..5) {
+            0 => bytes[at] ^= 1 << rng.gen_range(0..8),
+            1 => bytes[at] = rng.gen(),
+            2 => {
+                bytes.remove(at);
+            }
+            3 => {
+                let end = (at + rng.gen_range(1..64)).min(bytes.len());
+                let copy = bytes[at..end].to_vec();
+                bytes.splice(at..at, copy);
+            }
+            _ => bytes.truncate(at),
+        }
+    }
+    bytes
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Open a fresh library in `dir`
+fn open_library(dir: &Path) {
+    tunes4r::init_app(EngineConfig {
+        library_path: Some(dir.join("library.db").to_string_lossy().into_owned()),
+        log_level: Some(LogLevel::Error),
+        ..EngineConfig::default()
+    })
+    .unwrap();
+}
+
+fn fixture(codec: Codec, dir: &Path, name: &str) -> PathBuf {
+    let path = dir.join(format!("{name}.{}", codec.extension()));
+    test_fixtures::write_fixture(codec, &path).unwrap();
+    path
+}
+
+fn path_string(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+#[test]
+fn tags_round_trip() {
+    let _library = LIBRARY.lock().unwrap_or_else(|e| e.into_inner());
+    let dir = TempDir::new().unwrap();
+    let files: Vec<_> = [Codec::Flac16, Codec::Mp3, Codec::WavS16]
+        .into_iter()
+        .map(|codec| fixture(codec, dir.path(), codec.name()))
+        .collect();
+    check("tags_round_trip", 48, |rng| {
+        let path = &files[rng.gen_range(0..files.len())];
+        let song = Song {
+            title: name(rng),
+            artist: name(rng),
+            album: name(rng),
+            album_artist: rng.gen_bool(0.5).then(|| name(rng)),
+            genre: rng.gen_bool(0.5).then(|| name(rng)),
+            file_path: path_string(path),
+            ..Song::default()
+        };
+        tunes4r::write_song_metadata(song.clone()).unwrap();
+        let read = tunes4r::read_song_metadata(path_string(path)).unwrap();
+        assert_eq!(read.title, song.title, "{}", path.display());
+        assert_eq!(read.artist, song.artist, "{}", path.display());
+        assert_eq!(read.album, song.album, "{}", path.display());
+        assert_eq!(read.album_artist, song.album_artist, "{}", path.display());
+        assert_eq!(read.genre, song.genre, "{}", path.display());
+    });
+}
+
+#[test]
+fn mangled_audio_files_fail_cleanly() {
+    let _library = LIBRARY.lock().unwrap_or_else(|e| e.into_inner());
+    let dir = TempDir::new().unwrap();
+    let fixtures: Vec<_> = Codec::ALL
+        .into_iter()
+        .map(|codec| {
+            (
+                codec,
+                fs::read(fixture(codec, dir.path(), codec.name())).unwrap(),
+            )
+        })
+        .collect();
+    check("mangled_audio_files_fail_cleanly", 64, |rng| {
+        let (codec, bytes) = &fixtures[rng.gen_range(0..fixtures.len())];
+        let path = dir.path().join(format!("mangled.{}", codec.extension()));
+        fs::write(&path, mangle(rng, bytes.clone())).unwrap();
+        let _ = tunes4r::read_song_metadata(path_string(&path));
+        let _ = tunes4r::probe_file(path_string(&path));
+        let _ = test_fixtures::decode(&path);
+    });
+}
+
+#[test]
+fn playlists_round_trip() {
+    let _library = LIBRARY.lock().unwrap_or_else(|e| e.into_inner());
+    let dir = TempDir::new().unwrap();
+    open_library(dir.path());
+    let music = dir.path().join("music");
+    fs::create_dir_all(music.join("nested")).unwrap();
+    check("playlists_round_trip", 24, |rng| {
+        let songs: Vec<PathBuf> = (0..rng.gen_range(1..6))
+            .map(|_| {
+                let folder = if rng.gen_bool(0.5) {
+                    music.clone()
+                } else {
+                    music.join("nested")
+                };
+                let path = folder.join(format!("{}.flac", file_name(rng)));
+                if !path.exists() {
+                    test_fixtures::write_fixture(Codec::Flac16, &path).unwrap();
+                }
+                path
+            })
+            .collect();
+        // Entries are written the ways other players do: absolute, relative, or
+        // relative through a parent directory.
+        let mut m3u = String::from("#EXTM3U\n");
+        for song in &songs {
+            let relative = song.strip_prefix(&music).unwrap();
+            let entry = match rng.gen_range(0..3) {
+                0 => path_string(song),
+                1 => path_string(relative),
+                _ => format!("nested/../{}", path_string(relative)),
+            };
+            m3u.push_str(&format!("#EXTINF:1,{}\n{entry}\n", name(rng)));
+        }
+        let source = music.join(format!("{}.m3u8", file_name(rng)));
+        fs::write(&source, m3u).unwrap();
+
+        let id = tunes4r::import_playlist(path_string(&source)).unwrap();
+        let paths = |id| -> Vec<String> {
+            tunes4r::get_playlist_songs(id)
+                .unwrap()
+                .into_iter()
+                .map(|song| song.file_path)
+                .collect()
+        };
+        let imported = paths(id);
+        let expected: Vec<_> = songs.iter().map(|song| path_string(song)).collect();
+        assert_eq!(imported, expected);
+
+        let folder = if rng.gen_bool(0.5) {
+            &music
+        } else {
+            dir.path()
+        };
+        let extension = ["m3u", "m3u8", "pls"][rng.gen_range(0..3)];
+        let exported = folder.join(format!("{}.{extension}", file_name(rng)));
+        tunes4r::export_playlist(id, path_string(&exported)).unwrap();
+        let reimported = tunes4r::import_playlist(path_string(&exported)).unwrap();
+        assert_eq!(paths(reimported), expected, "{}", exported.display());
+    });
+}
+
+#[test]
+fn garbage_playlists_fail_cleanly() {
+    let _library = LIBRARY.lock().unwrap_or_else(|e| e.into_inner());
+    let dir = TempDir::new().unwrap();
+    open_library(dir.path());
+    let song = fixture(Codec::WavS16, dir.path(), "song");
+    let lines = [
+        "#EXTM3U".to_string(),
+        "[playlist]".to_string(),
+        "#EXTINF:-1,".to_string(),
+        "NumberOfEntries=3".to_string(),
+        "File1=song.wav".to_string(),
+        "File999999999999=song.wav".to_string(),
+        "File=".to_string(),
+        "=".to_string(),
+        "song.wav".to_string(),
+        path_string(&song),
+        "../../../../../../song.wav".to_string(),
+        "http://example.invalid/stream".to_string(),
+        "\u{feff}\u{0}\t\r".to_string(),
+    ];
+    check("garbage_playlists_fail_cleanly", 64, |rng| {
+        let mut text = String::new();
+        for _ in 0..rng.gen_range(0..12) {
+            match rng.gen_range(0..3) {
+                0 => text.push_str(&name(rng)),
+                _ => text.push_str(&lines[rng.gen_range(0..lines.len())]),
+            }
+            text.push(['\n', '\r', ' '][rng.gen_range(0..3)]);
+        }
+        let bytes = mangle(rng, text.into_bytes());
+        let extension = ["m3u", "m3u8", "pls"][rng.gen_range(0..3)];
+        let path = dir.path().join(format!("garbage.{extension}"));
+        fs::write(&path, bytes).unwrap();
+        let _ = tunes4r::import_playlist(path_string(&path));
+    });
+}
+
+/// A track of a generated foreign library
+struct Track {
+    title: String,
+    artist: String,
+    album: String,
+}
+
+fn rhythmdb(tracks: &[Track]) -> String {
+    let mut xml = String::from("<?xml version=\"1.0\"?>\n<rhythmdb version=\"2.0\">\n");
+    xml.push_str("  <entry type=\"iradio\"><title>Radio</title><location>http://example.invalid/</location></entry>\n");
+    for (i, track) in tracks.iter().enumerate() {
+        xml.push_str(&format!(
+            "  <entry type=\"song\"><title>{}</title><artist>{}</artist><album>{}</album>\
+             <duration>{}</duration><location>file:///nowhere/{i}.mp3</location></entry>\n",
+            escape_xml(&track.title),
+            escape_xml(&track.artist),
+            escape_xml(&track.album),
+            100 + i,
+        ));
+    }
+    xml.push_str("</rhythmdb>\n");
+    xml
+}
+
+fn itunes_plist(tracks: &[Track]) -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<plist version=\"1.0\">\n<dict>\n\
+         <key>Tracks</key>\n<dict>\n",
+    );
+    for (i, track) in tracks.iter().enumerate() {
+        xml.push_str(&format!(
+            "<key>{i}</key><dict><key>Track ID</key><integer>{i}</integer>\
+             <key>Name</key><string>{}</string><key>Artist</key><string>{}</string>\
+             <key>Album</key><string>{}</string><key>Total Time</key><integer>{}</integer>\
+             <key>Location</key><string>file:///nowhere/{i}.mp3</string></dict>\n",
+            escape_xml(&track.title),
+            escape_xml(&track.artist),
+            escape_xml(&track.album),
+            (100 + i) * 1000,
+        ));
+    }
+    xml.push_str("</dict>\n<key>Playlists</key>\n<array/>\n</dict>\n</plist>\n");
+    xml
+}
+
+#[test]
+fn foreign_libraries_round_trip() {
+    let _library = LIBRARY.lock().unwrap_or_else(|e| e.into_inner());
+    let dir = TempDir::new().unwrap();
+    open_library(dir.path());
+    check("foreign_libraries_round_trip", 32, |rng| {
+        let tracks: Vec<_> = (0..rng.gen_range(1..8))
+            .map(|_| Track {
+                title: name(rng),
+                artist: name(rng),
+                album: name(rng),
+            })
+            .collect();
+        let (format, xml) = match rng.gen_bool(0.5) {
+            true => (LibraryFormat::Rhythmbox, rhythmdb(&tracks)),
+            false => (LibraryFormat::ITunes, itunes_plist(&tracks)),
+        };
+        let path = dir.path().join("library.xml");
+        fs::write(&path, xml).unwrap();
+        let report = tunes4r::import_library(format, path_string(&path), true).unwrap();
+        assert_eq!(report.tracks as usize, tracks.len());
+        assert!(!report.applied);
+        let mut unmatched: Vec<_> = report
+            .unmatched
+            .iter()
+            .map(|track| (&track.title, &track.artist, &track.album))
+            .collect();
+        let mut expected: Vec<_> = tracks
+            .iter()
+            .map(|track| (&track.title, &track.artist, &track.album))
+            .collect();
+        unmatched.sort();
+        expected.sort();
+        assert_eq!(unmatched, expected);
+    });
+}
+
+#[test]
+fn garbage_foreign_libraries_fail_cleanly() {
+    let _library = LIBRARY.lock().unwrap_or_else(|e| e.into_inner());
+    let dir = TempDir::new().unwrap();
+    open_library(dir.path());
+    check("garbage_foreign_libraries_fail_cleanly", 64, |rng| {
+        let tracks: Vec<_> = (0..rng.gen_range(1..4))
+            .map(|_| Track {
+                title: name(rng),
+                artist: name(rng),
+                album: name(rng),
+            })
+            .collect();
+        let (format, xml) = match rng.gen_range(0..3) {
+            0 => (LibraryFormat::Rhythmbox, rhythmdb(&tracks)),
+            1 => (LibraryFormat::ITunes, itunes_plist(&tracks)),
+            _ => (LibraryFormat::MusicBee, itunes_plist(&tracks)),
+        };
+        let path = dir.path().join("rhythmdb.xml");
+        fs::write(&path, mangle(rng, xml.into_bytes())).unwrap();
+        if format == LibraryFormat::Rhythmbox && rng.gen_bool(0.5) {
+            let playlists = format!(
+                "<rhythmdb-playlists><playlist name=\"{}\" type=\"static\">\
+                 <location>file:///nowhere/0.mp3</location></playlist></rhythmdb-playlists>",
+                escape_xml(&name(rng))
+            );
+            let playlists = mangle(rng, playlists.into_bytes());
+            fs::write(dir.path().join("playlists.xml"), playlists).unwrap();
+        }
+        let _ = tunes4r::import_library(format, path_string(&path), rng.gen());
+    });
+}